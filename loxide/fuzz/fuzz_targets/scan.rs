@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// Scans the whole input, like the REPL does to decide whether it's complete
+fuzz_target!(|src: &str| {
+    loxide::compile::is_incomplete(src);
+});
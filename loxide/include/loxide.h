@@ -0,0 +1,67 @@
+/* C API of loxide, see src/ffi.rs. Link against the cdylib built by `cargo build --release`. */
+
+#ifndef LOXIDE_H
+#define LOXIDE_H
+
+#include <stdbool.h>
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define LOXIDE_OK 0
+#define LOXIDE_COMPILE_ERROR 1
+#define LOXIDE_RUNTIME_ERROR 2
+
+typedef struct LoxideVm LoxideVm;
+
+typedef enum LoxideValueKind {
+    LOXIDE_NIL = 0,
+    LOXIDE_BOOL = 1,
+    LOXIDE_NUMBER = 2,
+    LOXIDE_STRING = 3,
+    /* Any other object, `string` is how `print` shows it */
+    LOXIDE_OBJECT = 4,
+} LoxideValueKind;
+
+/* Strings aren't NUL-terminated and are only valid until the next call on the VM */
+typedef struct LoxideValue {
+    LoxideValueKind kind;
+    bool boolean;
+    double number;
+    const char *string;
+    size_t len;
+} LoxideValue;
+
+/* Returns 0 after storing the result, anything else raises a runtime error with the string in
+ * `result` as the message */
+typedef int (*LoxideNativeFn)(void *user_data, const LoxideValue *args, size_t arg_count,
+                              LoxideValue *result);
+
+LoxideVm *loxide_new(void);
+void loxide_free(LoxideVm *vm);
+
+/* Stores the value of a trailing expression without a semicolon (or nil) in `result` if it
+ * isn't NULL */
+int loxide_eval(LoxideVm *vm, const char *source, LoxideValue *result);
+const char *loxide_error(const LoxideVm *vm);
+
+bool loxide_get_global(LoxideVm *vm, const char *name, LoxideValue *result);
+
+/* `arity` -1 takes any number of arguments */
+bool loxide_register_native(LoxideVm *vm, const char *name, int arity, LoxideNativeFn native,
+                            void *user_data);
+
+/* Native modules, loaded with `import native "name";`, export a `LoxideModuleInit` called
+ * `loxide_module_init`. It registers the module's natives with `register_native`, passing on
+ * `module`, and returns 0, anything else fails the import */
+typedef bool (*LoxideRegisterFn)(void *module, const char *name, int arity, LoxideNativeFn native,
+                                 void *user_data);
+typedef int (*LoxideModuleInit)(void *module, LoxideRegisterFn register_native);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,210 @@
+use std::{
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    time::Instant,
+};
+
+use crate::cli::{Bench, BenchFormat};
+
+/// Times of one benchmark, in seconds
+#[derive(Debug, PartialEq)]
+struct BenchResult {
+    name: String,
+    times: Vec<f64>,
+}
+
+impl BenchResult {
+    fn mean(&self) -> f64 {
+        self.times.iter().sum::<f64>() / self.times.len() as f64
+    }
+
+    fn stddev(&self) -> f64 {
+        let mean = self.mean();
+        let variance = self
+            .times
+            .iter()
+            .map(|time| (time - mean).powi(2))
+            .sum::<f64>()
+            / self.times.len() as f64;
+        variance.sqrt()
+    }
+
+    fn min(&self) -> f64 {
+        self.times.iter().copied().fold(f64::INFINITY, f64::min)
+    }
+
+    fn max(&self) -> f64 {
+        self.times.iter().copied().fold(0.0, f64::max)
+    }
+}
+
+/// `loxide bench`: run every `.lox` file in the directory as a separate process, so the times
+/// include startup and compiling like they do for other interpreters. Progress goes to stderr,
+/// the results to `out`
+pub fn run(bench: &Bench, optimize: bool, mut out: impl Write) -> io::Result<()> {
+    let (interpreter, args) = match &bench.interpreter {
+        Some(interpreter) => (interpreter.clone(), vec![]),
+        None => {
+            let args = if optimize { vec!["--opt"] } else { vec![] };
+            (std::env::current_exe()?, args)
+        }
+    };
+
+    let mut results = vec![];
+    for script in scripts(&bench.dir)? {
+        let name = script.file_stem().unwrap().to_string_lossy().into_owned();
+        let run = || -> io::Result<f64> {
+            let start = Instant::now();
+            let status = Command::new(&interpreter)
+                .args(&args)
+                .arg(&script)
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .status()?;
+            let time = start.elapsed().as_secs_f64();
+            if !status.success() {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("{} failed with {status}", script.display()),
+                ));
+            }
+            Ok(time)
+        };
+
+        for _ in 0..bench.warmup {
+            run()?;
+        }
+        let times = (0..bench.runs).map(|_| run()).collect::<io::Result<_>>()?;
+        let result = BenchResult { name, times };
+        eprintln!(
+            "{:<20} {:>8.3}s ± {:.3}s",
+            result.name,
+            result.mean(),
+            result.stddev()
+        );
+        results.push(result);
+    }
+
+    match bench.format {
+        BenchFormat::Json => write_json(&interpreter, &results, &mut out),
+        BenchFormat::Csv => write_csv(&results, &mut out),
+    }
+}
+
+/// The `.lox` files in `dir`, sorted by name
+fn scripts(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut scripts = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path
+            .extension()
+            .map_or(false, |extension| extension == "lox")
+        {
+            scripts.push(path);
+        }
+    }
+    scripts.sort();
+    Ok(scripts)
+}
+
+fn write_json(interpreter: &Path, results: &[BenchResult], out: &mut impl Write) -> io::Result<()> {
+    writeln!(out, "{{")?;
+    let interpreter = json_string(&interpreter.to_string_lossy());
+    writeln!(out, "  \"interpreter\": {interpreter},")?;
+    writeln!(out, "  \"benchmarks\": [")?;
+    for (i, result) in results.iter().enumerate() {
+        let times: Vec<_> = result
+            .times
+            .iter()
+            .map(|time| format!("{time:.6}"))
+            .collect();
+        writeln!(
+            out,
+            "    {{\"name\": {}, \"mean\": {:.6}, \"stddev\": {:.6}, \"min\": {:.6}, \"max\": {:.6}, \"times\": [{}]}}{}",
+            json_string(&result.name),
+            result.mean(),
+            result.stddev(),
+            result.min(),
+            result.max(),
+            times.join(", "),
+            if i + 1 < results.len() { "," } else { "" }
+        )?;
+    }
+    writeln!(out, "  ]")?;
+    writeln!(out, "}}")
+}
+
+fn write_csv(results: &[BenchResult], out: &mut impl Write) -> io::Result<()> {
+    writeln!(out, "name,mean,stddev,min,max")?;
+    for result in results {
+        writeln!(
+            out,
+            "{},{:.6},{:.6},{:.6},{:.6}",
+            result.name,
+            result.mean(),
+            result.stddev(),
+            result.min(),
+            result.max()
+        )?;
+    }
+    Ok(())
+}
+
+fn json_string(string: &str) -> String {
+    let mut json = String::from('"');
+    for c in string.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[cfg(test)]
+mod test {
+    use super::{write_csv, write_json, BenchResult};
+
+    #[test]
+    fn reports() {
+        let results = [
+            BenchResult {
+                name: "fib".to_string(),
+                times: vec![1.0, 2.0, 3.0],
+            },
+            BenchResult {
+                name: "zoo".to_string(),
+                times: vec![0.5],
+            },
+        ];
+        assert_eq!(results[0].mean(), 2.0);
+        assert_eq!((results[0].min(), results[0].max()), (1.0, 3.0));
+
+        let mut csv = vec![];
+        write_csv(&results, &mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "name,mean,stddev,min,max\n\
+             fib,2.000000,0.816497,1.000000,3.000000\n\
+             zoo,0.500000,0.000000,0.500000,0.500000\n"
+        );
+
+        let mut json = vec![];
+        write_json("dir/\"zlox\"".as_ref(), &results, &mut json).unwrap();
+        assert_eq!(
+            String::from_utf8(json).unwrap(),
+            r#"{
+  "interpreter": "dir/\"zlox\"",
+  "benchmarks": [
+    {"name": "fib", "mean": 2.000000, "stddev": 0.816497, "min": 1.000000, "max": 3.000000, "times": [1.000000, 2.000000, 3.000000]},
+    {"name": "zoo", "mean": 0.500000, "stddev": 0.000000, "min": 0.500000, "max": 0.500000, "times": [0.500000]}
+  ]
+}
+"#
+        );
+    }
+}
@@ -1,7 +1,16 @@
+pub mod debug_info;
+pub mod disassemble;
+pub mod optimize;
+pub mod serialize;
+pub mod stack;
+
 use std::ops::Deref;
 
+use self::debug_info::DebugInfo;
 use crate::{
     compile::Upvalue,
+    mem::Gc,
+    obj::{ObjClass, ObjClosure},
     value::{Value, ValueArray},
 };
 
@@ -44,8 +53,82 @@ pub enum Opcode {
     Inherit,
     GetSuper,
     SuperInvoke,
+    Modulo,
+    FloorDivide,
+    Power,
+    Dup,
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitNot,
+    ShiftLeft,
+    ShiftRight,
+    BuildList,
+    GetIndex,
+    SetIndex,
+    BuildMap,
+    Slice,
+    ToString,
+    CloseLocal,
+    GetIter,
+    ForIter,
+    Throw,
+    PushCatch,
+    PushFinally,
+    PopHandler,
+    EndFinally,
+    Assert,
+    /// `Constant` with a 24 bit index, for chunks with more than 256 constants
+    ConstantLong,
+    /// Superinstructions, only emitted by the optimizer (see [`optimize`]). `GetLocal a;
+    /// GetLocal b; Add`
+    AddLocals,
+    /// `Constant; Call`
+    CallConstant,
+    /// `GetLocal; Less; JumpIfFalse`
+    LessLocalJumpIfFalse,
+    /// Push the module at the path in the constant, running the file first if it wasn't
+    /// imported before
+    Import,
+    /// The end of a module's script: push the module, whose exports are the globals the script
+    /// defined, see [`ObjModule`](crate::obj::ObjModule)
+    EndModule,
+    /// Push the module of the shared library named by the constant, loading it first if it
+    /// wasn't imported before, see [`crate::module::native`]
+    ImportNative,
+    /// Pop the value of a field initializer into the class below it, like `Method`
+    Field,
+    /// Pop a `get` accessor into the class below it, like `Method`
+    Getter,
+    /// Pop a `set` accessor into the class below it, like `Method`
+    Setter,
+    /// Pop a `static` method into the class below it, like `Method`
+    StaticMethod,
+    /// Pop the initial value of a `static var` into the class below it, like `Method`
+    StaticField,
+    /// Replace the operand's count of values by their concatenation, for `+` chains that start
+    /// with a string. Values that aren't strings are converted like `Add` does
+    Concat,
+    /// `Call` in a `return`, the callee reuses the caller's frame if it's a closure, see
+    /// [`VM::tail_calls`](crate::VM::tail_calls)
+    TailCall,
+    /// `Call` with named arguments: the operands are the number of positional arguments and
+    /// of named ones, which follow them as name and value pairs
+    CallNamed,
+    /// Suspend the generator running the frame, the value goes to whatever resumed it
+    Yield,
+    /// Replace the channel on top of the stack with the next value sent to it, letting the other
+    /// tasks run until there is one
+    Await,
 }
 
+/// Completions of a `try` statement, passed to its `finally` block along with a value
+pub const FINALLY_NORMAL: f64 = 0.0;
+/// The value is the exception to rethrow
+pub const FINALLY_THROW: f64 = 1.0;
+/// The value is the return value
+pub const FINALLY_RETURN: f64 = 2.0;
+
 impl Opcode {
     #[inline]
     pub fn from_u8(val: u8) -> Option<Self> {
@@ -88,15 +171,86 @@ impl Opcode {
             34 => Some(Inherit),
             35 => Some(GetSuper),
             36 => Some(SuperInvoke),
+            37 => Some(Modulo),
+            38 => Some(FloorDivide),
+            39 => Some(Power),
+            40 => Some(Dup),
+            41 => Some(BitAnd),
+            42 => Some(BitOr),
+            43 => Some(BitXor),
+            44 => Some(BitNot),
+            45 => Some(ShiftLeft),
+            46 => Some(ShiftRight),
+            47 => Some(BuildList),
+            48 => Some(GetIndex),
+            49 => Some(SetIndex),
+            50 => Some(BuildMap),
+            51 => Some(Slice),
+            52 => Some(ToString),
+            53 => Some(CloseLocal),
+            54 => Some(GetIter),
+            55 => Some(ForIter),
+            56 => Some(Throw),
+            57 => Some(PushCatch),
+            58 => Some(PushFinally),
+            59 => Some(PopHandler),
+            60 => Some(EndFinally),
+            61 => Some(Assert),
+            62 => Some(ConstantLong),
+            63 => Some(AddLocals),
+            64 => Some(CallConstant),
+            65 => Some(LessLocalJumpIfFalse),
+            66 => Some(Import),
+            67 => Some(EndModule),
+            68 => Some(ImportNative),
+            69 => Some(Field),
+            70 => Some(Getter),
+            71 => Some(Setter),
+            72 => Some(StaticMethod),
+            73 => Some(StaticField),
+            74 => Some(Concat),
+            75 => Some(TailCall),
+            76 => Some(CallNamed),
+            77 => Some(Yield),
+            78 => Some(Await),
             _ => None,
         }
     }
 }
 
+/// Most constants an instruction can refer to, [`Opcode::ConstantLong`] takes a 24 bit index
+pub const MAX_CONSTANTS: usize = 1 << 24;
+
 pub struct Chunk {
     pub code: Vec<u8>,
     pub constants: ValueArray,
-    pub lines: Vec<u32>,
+    /// Run-length encoded source lines, see [`Chunk::get_line`]
+    pub lines: Vec<LineStart>,
+    /// Slot in [`Globals`](crate::globals::Globals) of the global named by each constant,
+    /// filled in by the VM the first time an instruction uses it
+    pub global_slots: Vec<Option<u32>>,
+    /// Inline cache of `GetProperty` and `Invoke` for each property name constant, filled in by
+    /// the VM
+    pub method_caches: Vec<Option<MethodCache>>,
+    /// [`stack::max_height`] of the chunk, filled in by the VM the first time it's called
+    pub max_stack: Option<u32>,
+    pub debug: DebugInfo,
+}
+
+/// The last class a method was looked up on and the method it found. A call site that keeps
+/// seeing the same class skips the class's method table, one that sees another class looks the
+/// method up again and replaces the entry
+#[derive(Copy, Clone, Debug)]
+pub struct MethodCache {
+    pub(crate) class: Gc<ObjClass>,
+    pub(crate) method: Gc<ObjClosure>,
+}
+
+/// The instructions starting at `offset` up to the next `LineStart` were compiled from `line`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct LineStart {
+    pub offset: u32,
+    pub line: u32,
 }
 
 impl Chunk {
@@ -105,6 +259,10 @@ impl Chunk {
             code: vec![],
             constants: vec![],
             lines: vec![],
+            global_slots: vec![],
+            method_caches: vec![],
+            max_stack: None,
+            debug: DebugInfo::default(),
         }
     }
 
@@ -122,19 +280,37 @@ impl Chunk {
         })
     }
 
-    pub fn write(&mut self, op: u8, line: u32) {
+    /// Append a byte compiled from the token at `line` and `column`
+    pub fn write(&mut self, op: u8, line: u32, column: u32) {
+        self.debug.add_column(self.code.len(), column);
+        if self.lines.last().map(|start| start.line) != Some(line) {
+            self.lines.push(LineStart {
+                offset: self.code.len() as u32,
+                line,
+            });
+        }
         self.code.push(op);
-        self.lines.push(line);
     }
 
-    pub fn add_constant(&mut self, value: Value) -> u8 {
-        let index = self.constants.len();
+    /// Source line of the byte at `offset`
+    pub fn get_line(&self, offset: usize) -> u32 {
+        let run = self
+            .lines
+            .partition_point(|start| start.offset as usize <= offset);
+        self.lines[run.saturating_sub(1)].line
+    }
+
+    /// Returns the index of the new constant, which may be too big to fit in the instruction
+    pub(crate) fn add_constant(&mut self, value: Value) -> usize {
         self.constants.push(value);
-        index.try_into().unwrap()
+        self.constants.len() - 1
     }
 
     /// Dissamble instruction and increment offset to the start of
-    /// the next one
+    /// the next one.
+    ///
+    /// Returns `None` if the instruction is cut off or refers to a constant that doesn't exist,
+    /// which only happens for bytecode that wasn't produced by the compiler
     pub fn disassemble_instruction(&self, offset: &mut usize) -> Option<Instruction> {
         let instr = self.code[*offset];
         let op = Opcode::from_u8(instr);
@@ -154,9 +330,30 @@ impl Chunk {
                 | Opcode::Subtract
                 | Opcode::Multiply
                 | Opcode::Divide
+                | Opcode::Modulo
+                | Opcode::FloorDivide
+                | Opcode::Power
+                | Opcode::Dup
+                | Opcode::BitAnd
+                | Opcode::BitOr
+                | Opcode::BitXor
+                | Opcode::BitNot
+                | Opcode::ShiftLeft
+                | Opcode::ShiftRight
+                | Opcode::GetIndex
+                | Opcode::SetIndex
+                | Opcode::Slice
+                | Opcode::ToString
+                | Opcode::GetIter
+                | Opcode::Throw
+                | Opcode::PopHandler
+                | Opcode::EndFinally
                 | Opcode::Negate
                 | Opcode::Return
-                | Opcode::Inherit,
+                | Opcode::Yield
+                | Opcode::Await
+                | Opcode::Inherit
+                | Opcode::EndModule,
             ) => {
                 *offset += 1;
                 Some(Instruction::Simple(op.unwrap()))
@@ -170,43 +367,92 @@ impl Chunk {
                 | Opcode::DefineGlobal
                 | Opcode::GetGlobal
                 | Opcode::SetGlobal
-                | Opcode::GetSuper,
+                | Opcode::GetSuper
+                | Opcode::Assert
+                | Opcode::Import
+                | Opcode::ImportNative
+                | Opcode::Field
+                | Opcode::Getter
+                | Opcode::Setter
+                | Opcode::StaticMethod
+                | Opcode::StaticField,
             ) => {
-                let constant_idx = self.code[*offset + 1];
-                let constant = self.constants[constant_idx as usize];
+                let constant_idx = *self.code.get(*offset + 1)?;
+                let constant = *self.constants.get(constant_idx as usize)?;
                 *offset += 2;
                 Some(Instruction::Constant(op.unwrap(), constant))
             }
+            Some(Opcode::ConstantLong) => {
+                let constant_idx = read_u24(self.code.get(*offset + 1..*offset + 4)?);
+                let constant = *self.constants.get(constant_idx)?;
+                *offset += 4;
+                Some(Instruction::Constant(Opcode::ConstantLong, constant))
+            }
             Some(
                 Opcode::GetUpvalue
                 | Opcode::SetUpvalue
                 | Opcode::GetLocal
                 | Opcode::SetLocal
-                | Opcode::Call,
+                | Opcode::Call
+                | Opcode::TailCall
+                | Opcode::BuildList
+                | Opcode::Concat
+                | Opcode::BuildMap
+                | Opcode::CloseLocal,
             ) => {
-                let slot = self.code[*offset + 1];
+                let slot = *self.code.get(*offset + 1)?;
                 *offset += 2;
                 Some(Instruction::Byte(op.unwrap(), slot))
             }
-            Some(Opcode::Jump | Opcode::JumpIfFalse | Opcode::Loop) => {
-                let byte1 = self.code[*offset + 1];
-                let byte2 = self.code[*offset + 2];
+            Some(
+                Opcode::Jump
+                | Opcode::JumpIfFalse
+                | Opcode::Loop
+                | Opcode::PushCatch
+                | Opcode::PushFinally,
+            ) => {
+                let byte1 = *self.code.get(*offset + 1)?;
+                let byte2 = *self.code.get(*offset + 2)?;
                 *offset += 3;
                 let val = ((byte1 as u16) << 8) | (byte2 as u16);
                 Some(Instruction::Jump(op.unwrap(), val))
             }
+            Some(Opcode::AddLocals) => {
+                let a = *self.code.get(*offset + 1)?;
+                let b = *self.code.get(*offset + 2)?;
+                *offset += 3;
+                Some(Instruction::Locals(Opcode::AddLocals, a, b))
+            }
+            Some(Opcode::ForIter | Opcode::LessLocalJumpIfFalse) => {
+                let slot = *self.code.get(*offset + 1)?;
+                let byte1 = *self.code.get(*offset + 2)?;
+                let byte2 = *self.code.get(*offset + 3)?;
+                *offset += 4;
+                let jump = ((byte1 as u16) << 8) | (byte2 as u16);
+                Some(Instruction::SlotJump(op.unwrap(), slot, jump))
+            }
+            Some(Opcode::CallConstant) => {
+                let constant = *self.code.get(*offset + 1)?;
+                let arg_count = *self.code.get(*offset + 2)?;
+                let constant = *self.constants.get(constant as usize)?;
+                *offset += 3;
+                Some(Instruction::CallConstant {
+                    constant,
+                    arg_count,
+                })
+            }
             Some(Opcode::Closure) => {
                 *offset += 1;
-                let constant_idx = self.code[*offset];
+                let constant_idx = *self.code.get(*offset)?;
                 *offset += 1;
-                let value = self.constants[constant_idx as usize];
+                let value = *self.constants.get(constant_idx as usize)?;
                 let mut upvalues = vec![];
 
-                let function = value.as_fn().unwrap();
+                let function = value.as_fn()?;
                 for _i in 0..function.upvalue_count as usize {
-                    let is_local = self.code[*offset] == 1;
+                    let is_local = *self.code.get(*offset)? == 1;
                     *offset += 1;
-                    let index = self.code[*offset];
+                    let index = *self.code.get(*offset)?;
                     *offset += 1;
                     upvalues.push(Upvalue { index, is_local })
                 }
@@ -216,10 +462,16 @@ impl Chunk {
                     upvalues,
                 })
             }
+            Some(Opcode::CallNamed) => {
+                let arg_count = *self.code.get(*offset + 1)?;
+                let named = *self.code.get(*offset + 2)?;
+                *offset += 3;
+                Some(Instruction::CallNamed { arg_count, named })
+            }
             Some(Opcode::Invoke | Opcode::SuperInvoke) => {
-                let method = self.code[*offset + 1];
-                let arg_count = self.code[*offset + 2];
-                let method = self.constants[method as usize];
+                let method = *self.code.get(*offset + 1)?;
+                let arg_count = *self.code.get(*offset + 2)?;
+                let method = *self.constants.get(method as usize)?;
                 *offset += 3;
                 Some(Instruction::Invoke { method, arg_count })
             }
@@ -228,6 +480,11 @@ impl Chunk {
     }
 }
 
+/// Big endian, like jump offsets
+pub fn read_u24(bytes: &[u8]) -> usize {
+    (bytes[0] as usize) << 16 | (bytes[1] as usize) << 8 | bytes[2] as usize
+}
+
 impl Deref for Chunk {
     type Target = Vec<u8>;
 
@@ -253,6 +510,11 @@ pub enum Instruction {
     Constant(Opcode, Value),
     Byte(Opcode, u8),
     Jump(Opcode, u16),
+    /// Two local slots
+    Locals(Opcode, u8, u8),
+    /// A local slot and a forward jump. For `ForIter` the slot of the collection, the cursor is
+    /// in the next one, and the jump out of the loop
+    SlotJump(Opcode, u8, u16),
     Closure {
         function: Value,
         upvalues: Vec<Upvalue>,
@@ -261,6 +523,14 @@ pub enum Instruction {
         method: Value,
         arg_count: u8,
     },
+    CallConstant {
+        constant: Value,
+        arg_count: u8,
+    },
+    CallNamed {
+        arg_count: u8,
+        named: u8,
+    },
 }
 
 impl std::fmt::Debug for Instruction {
@@ -274,6 +544,15 @@ impl std::fmt::Debug for Instruction {
             }
             Instruction::Byte(op, val) => f.debug_tuple("Byte").field(op).field(val).finish(),
             Instruction::Jump(op, offset) => f.debug_tuple("Jump").field(op).field(offset).finish(),
+            Instruction::Locals(op, a, b) => {
+                f.debug_tuple("Locals").field(op).field(a).field(b).finish()
+            }
+            Instruction::SlotJump(op, slot, jump) => f
+                .debug_tuple("SlotJump")
+                .field(op)
+                .field(slot)
+                .field(jump)
+                .finish(),
             Instruction::Closure { function, upvalues } => f
                 .debug_struct("Closure")
                 .field("function", &function)
@@ -284,6 +563,19 @@ impl std::fmt::Debug for Instruction {
                 .field(method)
                 .field(arg_count)
                 .finish(),
+            Instruction::CallConstant {
+                constant,
+                arg_count,
+            } => f
+                .debug_tuple("CallConstant")
+                .field(constant)
+                .field(arg_count)
+                .finish(),
+            Instruction::CallNamed { arg_count, named } => f
+                .debug_tuple("CallNamed")
+                .field(arg_count)
+                .field(named)
+                .finish(),
         }
     }
 }
@@ -323,7 +615,7 @@ impl<'a> Iterator for ChunkIterDebug<'a> {
             return None;
         }
 
-        let line = self.0.chunk.lines[self.0.offset];
+        let line = self.0.chunk.get_line(self.0.offset);
         let inner = self.0.disassemble_instruction();
 
         inner.map(|inner| InstructionDebug { inner, line })
@@ -0,0 +1,87 @@
+//! Debug info of a chunk: the source columns of its instructions and the names of its locals.
+//!
+//! The VM doesn't need any of this to run a script, it's kept next to the bytecode for stack
+//! traces and the debugger. The line of each instruction is in [`Chunk::lines`](super::Chunk)
+
+/// The instructions starting at `offset` up to the next `ColumnStart` were compiled from a token
+/// starting at `column`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ColumnStart {
+    pub offset: u32,
+    pub column: u32,
+}
+
+/// A local variable that's in `slot` of its call while the instructions in `start..end` run
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LocalInfo {
+    pub name: String,
+    pub slot: u8,
+    pub start: u32,
+    /// [`LocalInfo::OPEN`] while the compiler is still in the local's scope
+    pub end: u32,
+}
+
+impl LocalInfo {
+    pub const OPEN: u32 = u32::MAX;
+
+    pub fn is_live(&self, offset: usize) -> bool {
+        (self.start as usize..self.end as usize).contains(&offset)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DebugInfo {
+    /// Run-length encoded source columns, see [`DebugInfo::get_column`]
+    pub columns: Vec<ColumnStart>,
+    /// In the order the locals were declared
+    pub locals: Vec<LocalInfo>,
+}
+
+impl DebugInfo {
+    /// Record the column of the byte at `offset`, bytes have to be added in order
+    pub fn add_column(&mut self, offset: usize, column: u32) {
+        if self.columns.last().map(|start| start.column) != Some(column) {
+            self.columns.push(ColumnStart {
+                offset: offset as u32,
+                column,
+            });
+        }
+    }
+
+    /// 1-based source column of the byte at `offset`, 0 if it's unknown
+    pub fn get_column(&self, offset: usize) -> u32 {
+        let run = self
+            .columns
+            .partition_point(|start| start.offset as usize <= offset);
+        match run {
+            0 => 0,
+            run => self.columns[run - 1].column,
+        }
+    }
+
+    /// A local declared at `offset` in `slot`
+    pub fn add_local(&mut self, name: &str, slot: u8, offset: usize) {
+        self.locals.push(LocalInfo {
+            name: name.to_string(),
+            slot,
+            start: offset as u32,
+            end: LocalInfo::OPEN,
+        });
+    }
+
+    /// End the scope of the open locals in `slot` and above at `offset`
+    pub fn close_locals(&mut self, slot: u8, offset: usize) {
+        for local in &mut self.locals {
+            if local.end == LocalInfo::OPEN && local.slot >= slot {
+                local.end = offset as u32;
+            }
+        }
+    }
+
+    /// The locals that are in scope at `offset`
+    pub fn locals_at(&self, offset: usize) -> impl Iterator<Item = &LocalInfo> {
+        self.locals
+            .iter()
+            .filter(move |local| local.is_live(offset))
+    }
+}
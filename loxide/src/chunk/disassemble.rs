@@ -0,0 +1,150 @@
+//! Readable listings of compiled bytecode, like clox's `debug.c`
+
+use std::fmt::Write;
+
+use super::{read_u24, Chunk, Instruction, Opcode};
+use crate::{
+    obj::ObjFunction,
+    value::{display::ValueWrapper, Value},
+};
+
+/// Disassemble `function`'s chunk followed by the chunks of all the functions nested in it
+pub(crate) fn disassemble_function(function: &ObjFunction) -> String {
+    let mut out = String::new();
+    write_function(&mut out, function);
+    out
+}
+
+fn write_function(out: &mut String, function: &ObjFunction) {
+    let name = match &function.name {
+        Some(name) => name.as_str(),
+        None => "<script>",
+    };
+    out.push_str(&disassemble_chunk(&function.chunk, name));
+
+    for constant in function.chunk.constants.iter() {
+        if let Some(nested) = constant.as_fn() {
+            out.push('\n');
+            write_function(out, nested.as_ref());
+        }
+    }
+}
+
+/// One line per instruction with its offset, source line (`|` when it's the same as the
+/// previous instruction's), opcode and operands
+pub(crate) fn disassemble_chunk(chunk: &Chunk, name: &str) -> String {
+    let mut out = format!("== {name} ==\n");
+    let mut offset = 0;
+    while offset < chunk.len() {
+        offset = write_instruction(&mut out, chunk, offset);
+    }
+    out
+}
+
+/// Disassemble the instruction at `offset` on a single line, without a trailing newline
+pub(crate) fn disassemble_instruction(chunk: &Chunk, offset: usize) -> String {
+    let mut out = String::new();
+    write_instruction(&mut out, chunk, offset);
+    out.pop();
+    out
+}
+
+/// Returns the offset of the next instruction
+fn write_instruction(out: &mut String, chunk: &Chunk, offset: usize) -> usize {
+    let _ = write!(out, "{offset:04} ");
+    let line = chunk.get_line(offset);
+    if offset > 0 && chunk.get_line(offset - 1) == line {
+        out.push_str("   | ");
+    } else {
+        let _ = write!(out, "{line:4} ");
+    }
+
+    let mut next = offset;
+    let Some(instruction) = Opcode::from_u8(chunk.code[offset])
+        .and_then(|_| chunk.disassemble_instruction(&mut next))
+    else {
+        let _ = writeln!(out, "Unknown opcode {}", chunk.code[offset]);
+        return offset + 1;
+    };
+
+    // The operand right after the opcode, constant indices and slots
+    let operand = match instruction {
+        Instruction::Constant(Opcode::ConstantLong, _) => read_u24(&chunk.code[offset + 1..]),
+        _ => chunk.code.get(offset + 1).copied().unwrap_or_default() as usize,
+    };
+    let _ = match instruction {
+        Instruction::Simple(op) => writeln!(out, "{op:?}"),
+        Instruction::Constant(op, value) => {
+            writeln!(
+                out,
+                "{:<16} {operand:4} {}",
+                format!("{op:?}"),
+                format_value(value)
+            )
+        }
+        Instruction::Byte(op, byte) => writeln!(out, "{:<16} {byte:4}", format!("{op:?}")),
+        Instruction::Jump(op, jump) => {
+            let target = if op == Opcode::Loop {
+                next as isize - jump as isize
+            } else {
+                (next + jump as usize) as isize
+            };
+            writeln!(out, "{:<16} {offset:4} -> {target}", format!("{op:?}"))
+        }
+        Instruction::Locals(op, a, b) => writeln!(out, "{:<16} {a:4} {b:4}", format!("{op:?}")),
+        Instruction::SlotJump(op, slot, jump) => {
+            let target = next + jump as usize;
+            writeln!(out, "{:<16} {slot:4} -> {target}", format!("{op:?}"))
+        }
+        Instruction::Closure { function, upvalues } => {
+            let _ = writeln!(
+                out,
+                "{:<16} {operand:4} {}",
+                "Closure",
+                format_value(function)
+            );
+            let mut upvalue_offset = offset + 2;
+            for upvalue in upvalues {
+                let kind = if upvalue.is_local { "local" } else { "upvalue" };
+                let _ = writeln!(
+                    out,
+                    "{upvalue_offset:04}    |                     {kind} {}",
+                    upvalue.index
+                );
+                upvalue_offset += 2;
+            }
+            Ok(())
+        }
+        Instruction::Invoke { method, arg_count } => {
+            let op = Opcode::from_u8(chunk.code[offset]).unwrap();
+            writeln!(
+                out,
+                "{:<16} {operand:4} {} ({arg_count} args)",
+                format!("{op:?}"),
+                format_value(method)
+            )
+        }
+        Instruction::CallConstant {
+            constant,
+            arg_count,
+        } => writeln!(
+            out,
+            "{:<16} {operand:4} {} ({arg_count} args)",
+            "CallConstant",
+            format_value(constant)
+        ),
+        Instruction::CallNamed { arg_count, named } => {
+            writeln!(out, "{:<16} {arg_count:4} {named:4}", "CallNamed")
+        }
+    };
+
+    next
+}
+
+/// Strings are quoted so they can be told apart from other values
+pub(crate) fn format_value(value: Value) -> String {
+    match value.as_str() {
+        Some(string) => format!("'{string}'"),
+        None => ValueWrapper(value).to_string(),
+    }
+}
@@ -0,0 +1,452 @@
+//! Peephole optimizations of compiled bytecode, run when [`VmOptions::optimize`] is set:
+//!
+//! - arithmetic on number constants is folded into a single constant
+//! - `Not Not` is dropped when only the truthiness of the value matters
+//! - jumps to unconditional jumps go straight to the final target
+//! - unreachable code after `Return`, `Throw` and unconditional jumps is removed
+//! - common sequences of instructions are fused into superinstructions, which do the same with
+//!   fewer dispatches (see [`fuse`])
+//!
+//! [`VmOptions::optimize`]: crate::VmOptions::optimize
+
+use super::{debug_info::LocalInfo, read_u24, Chunk, Instruction, Opcode, MAX_CONSTANTS};
+use crate::{
+    obj::ObjFunction,
+    value::{Unpacked, Value},
+};
+
+/// Optimize `function` and every function nested in it. Chunks that can't be optimized, e.g.
+/// because a jump would get too long, are left as they are
+pub fn optimize(function: &mut ObjFunction) {
+    for i in 0..function.chunk.constants.len() {
+        if let Some(mut nested) = function.chunk.constants[i].as_fn() {
+            optimize(&mut nested);
+        }
+    }
+
+    if let Some(chunk) = optimize_chunk(&function.chunk) {
+        function.chunk = chunk;
+    }
+}
+
+/// Decoded instruction, jumps refer to the index of their target instead of an offset
+#[derive(Clone, Debug)]
+enum Op {
+    /// Copied as is, the opcode followed by its operands
+    Bytes(Vec<u8>),
+    Constant(usize),
+    /// `Jump`, `JumpIfFalse`, `Loop`, `PushCatch` or `PushFinally`
+    Jump(Opcode, usize),
+    /// `ForIter` or `LessLocalJumpIfFalse`
+    SlotJump(Opcode, u8, usize),
+}
+
+impl Op {
+    fn is(&self, opcode: Opcode) -> bool {
+        matches!(self, Op::Bytes(bytes) if bytes[0] == opcode as u8)
+    }
+
+    fn target(&mut self) -> Option<&mut usize> {
+        match self {
+            Op::Jump(_, target) | Op::SlotJump(_, _, target) => Some(target),
+            Op::Bytes(_) | Op::Constant(_) => None,
+        }
+    }
+
+    /// Whether execution never continues with the next instruction
+    fn is_unconditional(&self) -> bool {
+        matches!(self, Op::Jump(Opcode::Jump | Opcode::Loop, _))
+            || self.is(Opcode::Return)
+            || self.is(Opcode::Throw)
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Op::Bytes(bytes) => bytes.len(),
+            Op::Constant(index) if *index <= u8::MAX as usize => 2,
+            Op::Constant(_) | Op::SlotJump(..) => 4,
+            Op::Jump(..) => 3,
+        }
+    }
+}
+
+struct Instr {
+    op: Op,
+    line: u32,
+    column: u32,
+    /// Where the instruction started before optimizing, to move the locals' scopes along
+    offset: usize,
+}
+
+fn optimize_chunk(chunk: &Chunk) -> Option<Chunk> {
+    let mut instrs = decode(chunk)?;
+    let mut constants = chunk.constants.clone();
+
+    // Every pass can make room for the others, `1 + 2 + 3` takes two rounds of folding
+    loop {
+        let mut removed = vec![false; instrs.len()];
+        let changed = thread_jumps(&mut instrs, &mut removed)
+            | remove_dead_code(&instrs, &mut removed)
+            | fold_constants(&mut instrs, &mut removed, &mut constants)
+            | remove_double_not(&instrs, &mut removed);
+        if !changed {
+            break;
+        }
+        instrs = compact(instrs, &removed);
+    }
+
+    // Last, the other passes only know the plain instructions
+    let mut removed = vec![false; instrs.len()];
+    if fuse(&mut instrs, &mut removed) {
+        instrs = compact(instrs, &removed);
+    }
+
+    encode(&instrs, constants, &chunk.debug.locals)
+}
+
+fn decode(chunk: &Chunk) -> Option<Vec<Instr>> {
+    let mut instrs = vec![];
+    // Index of the instruction starting at each offset, jumps are resolved once all are known
+    let mut indices = vec![None; chunk.len() + 1];
+    let mut offset = 0;
+    while offset < chunk.len() {
+        let start = offset;
+        Opcode::from_u8(chunk[offset])?;
+        let op = match chunk.disassemble_instruction(&mut offset)? {
+            Instruction::Constant(Opcode::Constant, _) => Op::Constant(chunk[start + 1] as usize),
+            Instruction::Constant(Opcode::ConstantLong, _) => {
+                Op::Constant(read_u24(&chunk[start + 1..]))
+            }
+            Instruction::Jump(Opcode::Loop, jump) => {
+                Op::Jump(Opcode::Loop, offset.checked_sub(jump as usize)?)
+            }
+            Instruction::Jump(op, jump) => Op::Jump(op, offset + jump as usize),
+            Instruction::SlotJump(op, slot, jump) => Op::SlotJump(op, slot, offset + jump as usize),
+            _ => Op::Bytes(chunk[start..offset].to_vec()),
+        };
+        indices[start] = Some(instrs.len());
+        instrs.push(Instr {
+            op,
+            line: chunk.get_line(start),
+            column: chunk.debug.get_column(start),
+            offset: start,
+        });
+    }
+    indices[chunk.len()] = Some(instrs.len());
+
+    for instr in &mut instrs {
+        if let Some(target) = instr.op.target() {
+            *target = (*indices.get(*target)?)?;
+        }
+    }
+    Some(instrs)
+}
+
+/// Which instructions are jumped to, with an extra entry for the end of the chunk
+fn jump_targets(instrs: &[Instr]) -> Vec<bool> {
+    let mut targets = vec![false; instrs.len() + 1];
+    for instr in instrs {
+        if let Op::Jump(_, target) | Op::SlotJump(_, _, target) = instr.op {
+            targets[target] = true;
+        }
+    }
+    targets
+}
+
+/// Point jumps that land on an unconditional jump at its target instead, and drop jumps to the
+/// next instruction
+fn thread_jumps(instrs: &mut [Instr], removed: &mut [bool]) -> bool {
+    let mut changed = false;
+    for i in 0..instrs.len() {
+        let Op::Jump(op @ (Opcode::Jump | Opcode::JumpIfFalse), target) = instrs[i].op else {
+            continue;
+        };
+
+        let mut last = target;
+        let mut steps = 0;
+        while let Some(Op::Jump(Opcode::Jump | Opcode::Loop, next)) =
+            instrs.get(last).map(|instr| &instr.op)
+        {
+            // Jumps going around in circles
+            if steps == instrs.len() {
+                break;
+            }
+            last = *next;
+            steps += 1;
+        }
+
+        if op == Opcode::Jump && last == i + 1 {
+            removed[i] = true;
+            changed = true;
+        } else if last != target && steps < instrs.len() && (last > i || op == Opcode::Jump) {
+            let op = if last > i { op } else { Opcode::Loop };
+            instrs[i].op = Op::Jump(op, last);
+            changed = true;
+        }
+    }
+    changed
+}
+
+fn remove_dead_code(instrs: &[Instr], removed: &mut [bool]) -> bool {
+    let targets = jump_targets(instrs);
+    let mut changed = false;
+    let mut reachable = true;
+    for (i, instr) in instrs.iter().enumerate() {
+        reachable |= targets[i];
+        if !reachable {
+            removed[i] = true;
+            changed = true;
+        }
+        if instr.op.is_unconditional() {
+            reachable = false;
+        }
+    }
+    changed
+}
+
+fn fold_constants(instrs: &mut [Instr], removed: &mut [bool], constants: &mut Vec<Value>) -> bool {
+    let targets = jump_targets(instrs);
+    let mut changed = false;
+    for i in 1..instrs.len() {
+        let Op::Bytes(bytes) = &instrs[i].op else {
+            continue;
+        };
+        if targets[i] || removed[i] {
+            continue;
+        }
+
+        let folded = match Opcode::from_u8(bytes[0]) {
+            Some(Opcode::Negate) => number(instrs, removed, constants, i - 1).map(|a| (i - 1, -a)),
+            Some(op @ (Opcode::Add | Opcode::Subtract | Opcode::Multiply | Opcode::Divide))
+                if i >= 2 && !targets[i - 1] =>
+            {
+                let (Some(a), Some(b)) = (
+                    number(instrs, removed, constants, i - 2),
+                    number(instrs, removed, constants, i - 1),
+                ) else {
+                    continue;
+                };
+                let result = match op {
+                    Opcode::Add => a + b,
+                    Opcode::Subtract => a - b,
+                    Opcode::Multiply => a * b,
+                    _ => a / b,
+                };
+                Some((i - 2, result))
+            }
+            _ => None,
+        };
+        let Some((first, result)) = folded else {
+            continue;
+        };
+
+        let result = Value::number(result);
+        let index = constants
+            .iter()
+            .position(|constant| match (constant.unpack(), result.unpack()) {
+                (Unpacked::Number(a), Unpacked::Number(b)) => a.to_bits() == b.to_bits(),
+                (Unpacked::Int(a), Unpacked::Int(b)) => a == b,
+                _ => false,
+            })
+            .unwrap_or_else(|| {
+                constants.push(result);
+                constants.len() - 1
+            });
+        if index >= MAX_CONSTANTS {
+            continue;
+        }
+        instrs[first].op = Op::Constant(index);
+        removed[first + 1..=i].fill(true);
+        changed = true;
+    }
+    changed
+}
+
+/// Value of the instruction at `i` if it loads a number constant
+fn number(instrs: &[Instr], removed: &[bool], constants: &[Value], i: usize) -> Option<f64> {
+    match instrs[i].op {
+        Op::Constant(index) if !removed[i] => constants[index].as_number(),
+        _ => None,
+    }
+}
+
+/// `Not Not Not` is the same as `Not`, and `Not Not` right before an `if` or `while` condition
+/// only changes a value that's tested and popped on both paths
+fn remove_double_not(instrs: &[Instr], removed: &mut [bool]) -> bool {
+    let targets = jump_targets(instrs);
+    let is_pop = |i: usize| {
+        instrs
+            .get(i)
+            .map_or(false, |instr| instr.op.is(Opcode::Pop))
+    };
+
+    let mut changed = false;
+    let mut i = 0;
+    while i + 2 < instrs.len() {
+        let redundant = instrs[i].op.is(Opcode::Not)
+            && instrs[i + 1].op.is(Opcode::Not)
+            && !targets[i + 1]
+            && !removed[i..=i + 2].contains(&true)
+            && match instrs[i + 2].op {
+                Op::Jump(Opcode::JumpIfFalse, target) => is_pop(i + 3) && is_pop(target),
+                ref op => op.is(Opcode::Not),
+            };
+        if redundant {
+            removed[i] = true;
+            removed[i + 1] = true;
+            changed = true;
+            i += 2;
+        } else {
+            i += 1;
+        }
+    }
+    changed
+}
+
+/// Replace sequences that are common in loops and calls with a superinstruction:
+///
+/// - `GetLocal a; GetLocal b; Add` with `AddLocals a b`
+/// - `Constant c; Call n` with `CallConstant c n`
+/// - `GetLocal s; Less; JumpIfFalse` with `LessLocalJumpIfFalse s`
+///
+/// Only the first instruction of a sequence may be a jump target
+fn fuse(instrs: &mut [Instr], removed: &mut [bool]) -> bool {
+    let targets = jump_targets(instrs);
+    let mut changed = false;
+    let mut i = 0;
+    while i < instrs.len() {
+        // The instructions following `i` that can be fused into it
+        let next = |n: usize| {
+            instrs
+                .get(i + n)
+                .filter(|_| !targets[i + n])
+                .map(|instr| &instr.op)
+        };
+        let fused = match (&instrs[i].op, next(1), next(2)) {
+            (first, Some(second), Some(add)) if add.is(Opcode::Add) => {
+                match (local(first), local(second)) {
+                    (Some(a), Some(b)) => Some((Op::Bytes(vec![Opcode::AddLocals as u8, a, b]), 3)),
+                    _ => None,
+                }
+            }
+            (first, Some(less), Some(&Op::Jump(Opcode::JumpIfFalse, target)))
+                if less.is(Opcode::Less) =>
+            {
+                local(first)
+                    .map(|slot| (Op::SlotJump(Opcode::LessLocalJumpIfFalse, slot, target), 3))
+            }
+            (&Op::Constant(index), Some(Op::Bytes(call)), _)
+                if index <= u8::MAX as usize && call[0] == Opcode::Call as u8 =>
+            {
+                let bytes = vec![Opcode::CallConstant as u8, index as u8, call[1]];
+                Some((Op::Bytes(bytes), 2))
+            }
+            _ => None,
+        };
+
+        match fused {
+            Some((op, len)) => {
+                instrs[i].op = op;
+                removed[i + 1..i + len].fill(true);
+                changed = true;
+                i += len;
+            }
+            None => i += 1,
+        }
+    }
+    changed
+}
+
+/// Slot of a `GetLocal`
+fn local(op: &Op) -> Option<u8> {
+    match op {
+        Op::Bytes(bytes) if bytes[0] == Opcode::GetLocal as u8 => Some(bytes[1]),
+        _ => None,
+    }
+}
+
+/// Drop the removed instructions, jumps to one of them go to the next instruction that's kept
+fn compact(instrs: Vec<Instr>, removed: &[bool]) -> Vec<Instr> {
+    let mut new_indices = vec![0; instrs.len() + 1];
+    let mut kept = removed.iter().filter(|removed| !**removed).count();
+    new_indices[instrs.len()] = kept;
+    for i in (0..instrs.len()).rev() {
+        if !removed[i] {
+            kept -= 1;
+        }
+        new_indices[i] = kept;
+    }
+
+    instrs
+        .into_iter()
+        .zip(removed)
+        .filter(|(_, removed)| !**removed)
+        .map(|(mut instr, _)| {
+            if let Some(target) = instr.op.target() {
+                *target = new_indices[*target];
+            }
+            instr
+        })
+        .collect()
+}
+
+fn encode(instrs: &[Instr], constants: Vec<Value>, locals: &[LocalInfo]) -> Option<Chunk> {
+    let mut offsets = Vec::with_capacity(instrs.len() + 1);
+    let mut offset = 0;
+    for instr in instrs {
+        offsets.push(offset);
+        offset += instr.op.len();
+    }
+    offsets.push(offset);
+
+    let mut chunk = Chunk::new();
+    chunk.constants = constants;
+    for (i, instr) in instrs.iter().enumerate() {
+        let end = offsets[i + 1];
+        let bytes = match instr.op {
+            Op::Bytes(ref bytes) => bytes.clone(),
+            Op::Constant(index) => match u8::try_from(index) {
+                Ok(index) => vec![Opcode::Constant as u8, index],
+                Err(_) => {
+                    let [_, high, mid, low] = (index as u32).to_be_bytes();
+                    vec![Opcode::ConstantLong as u8, high, mid, low]
+                }
+            },
+            Op::Jump(Opcode::Loop, target) => {
+                let [high, low] = u16::try_from(end - offsets[target]).ok()?.to_be_bytes();
+                vec![Opcode::Loop as u8, high, low]
+            }
+            Op::Jump(op, target) => {
+                let jump = offsets[target].checked_sub(end)?;
+                let [high, low] = u16::try_from(jump).ok()?.to_be_bytes();
+                vec![op as u8, high, low]
+            }
+            Op::SlotJump(op, slot, target) => {
+                let jump = offsets[target].checked_sub(end)?;
+                let [high, low] = u16::try_from(jump).ok()?.to_be_bytes();
+                vec![op as u8, slot, high, low]
+            }
+        };
+        for byte in bytes {
+            chunk.write(byte, instr.line, instr.column);
+        }
+    }
+
+    // The locals' scopes start and end at the next instruction that was kept
+    let new_offset = |offset: u32| {
+        if offset == LocalInfo::OPEN {
+            return offset;
+        }
+        let index = instrs.partition_point(|instr| instr.offset < offset as usize);
+        offsets[index] as u32
+    };
+    chunk.debug.locals = locals
+        .iter()
+        .map(|local| LocalInfo {
+            start: new_offset(local.start),
+            end: new_offset(local.end),
+            ..local.clone()
+        })
+        .collect();
+    Some(chunk)
+}
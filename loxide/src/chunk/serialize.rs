@@ -0,0 +1,377 @@
+//! `.loxc` files, compiled scripts that can be run without parsing them again.
+//!
+//! All numbers are little endian. A file is the magic bytes and format version followed by the
+//! script function:
+//!
+//! ```text
+//! function  = name line:u32 arity:u8 optional:u8 rest:u8 generator:u8 params:string* upvalue_count:u8
+//!             code:bytes lines:line* columns:column*
+//!             locals:local* constants:constant*
+//! name      = 0 | 1 string
+//! line      = offset:u32 line:u32
+//! column    = offset:u32 column:u32
+//! local     = string slot:u8 start:u32 end:u32
+//! constant  = 0 (nil) | 1 (false) | 2 (true) | 3 f64 | 4 string | 5 function | 6 i32
+//! string    = bytes
+//! bytes     = len:u32 u8*
+//! ```
+//!
+//! `params`, `lines`, `columns`, `locals` and `constants` start with their length as a `u32`.
+//! `params` are the names of the parameters, `lines` the chunk's [`LineStart`]s, `columns` and
+//! `locals` its [`DebugInfo`].
+
+use super::{
+    debug_info::{ColumnStart, DebugInfo, LocalInfo},
+    stack, Instruction, LineStart, Opcode,
+};
+use crate::{
+    mem::{Gc, Mem},
+    obj::ObjFunction,
+    value::{Unpacked, Value},
+};
+
+/// Start of every `.loxc` file
+pub const MAGIC: &[u8; 4] = b"LOXC";
+/// Bumped whenever the format or the meaning of the opcodes changes
+pub const VERSION: u8 = 10;
+
+/// Whether `bytes` look like a `.loxc` file rather than source code
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Serialize `function` and every function nested in it
+pub(crate) fn serialize(function: &ObjFunction) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    out.push(VERSION);
+    write_function(&mut out, function);
+    out
+}
+
+fn write_function(out: &mut Vec<u8>, function: &ObjFunction) {
+    match function.name {
+        Some(name) => {
+            out.push(1);
+            write_bytes(out, name.as_str().as_bytes());
+        }
+        None => out.push(0),
+    }
+    out.extend_from_slice(&function.line.to_le_bytes());
+    out.push(function.arity);
+    out.push(function.optional);
+    out.push(function.rest as u8);
+    out.push(function.generator as u8);
+    out.extend_from_slice(&(function.params.len() as u32).to_le_bytes());
+    for param in &function.params {
+        write_bytes(out, param.as_str().as_bytes());
+    }
+    out.push(function.upvalue_count);
+
+    let chunk = &function.chunk;
+    write_bytes(out, &chunk.code);
+    out.extend_from_slice(&(chunk.lines.len() as u32).to_le_bytes());
+    for start in &chunk.lines {
+        out.extend_from_slice(&start.offset.to_le_bytes());
+        out.extend_from_slice(&start.line.to_le_bytes());
+    }
+    out.extend_from_slice(&(chunk.debug.columns.len() as u32).to_le_bytes());
+    for start in &chunk.debug.columns {
+        out.extend_from_slice(&start.offset.to_le_bytes());
+        out.extend_from_slice(&start.column.to_le_bytes());
+    }
+    out.extend_from_slice(&(chunk.debug.locals.len() as u32).to_le_bytes());
+    for local in &chunk.debug.locals {
+        write_bytes(out, local.name.as_bytes());
+        out.push(local.slot);
+        out.extend_from_slice(&local.start.to_le_bytes());
+        out.extend_from_slice(&local.end.to_le_bytes());
+    }
+
+    out.extend_from_slice(&(chunk.constants.len() as u32).to_le_bytes());
+    for constant in &chunk.constants {
+        match constant.unpack() {
+            Unpacked::Nil => out.push(0),
+            Unpacked::Bool(false) => out.push(1),
+            Unpacked::Bool(true) => out.push(2),
+            Unpacked::Number(number) => {
+                out.push(3);
+                out.extend_from_slice(&number.to_le_bytes());
+            }
+            Unpacked::Int(int) => {
+                out.push(6);
+                out.extend_from_slice(&int.to_le_bytes());
+            }
+            Unpacked::Obj(_) => {
+                let value = *constant;
+                if let Some(string) = value.as_str() {
+                    out.push(4);
+                    write_bytes(out, string.as_bytes());
+                } else if let Some(nested) = value.as_fn() {
+                    out.push(5);
+                    write_function(out, nested.as_ref());
+                } else {
+                    unreachable!("the compiler only emits strings and functions as constants")
+                }
+            }
+            Unpacked::Handle(_) => unreachable!("constants aren't handed to the host"),
+        }
+    }
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    out.extend_from_slice(bytes);
+}
+
+/// Load a script serialized with [`serialize`], the strings are interned in `mem`.
+///
+/// The bytecode is checked enough that running it stays inside of its chunks and its frame's
+/// slots, see [`validate`]. Past that the VM checks what the compiler would have guaranteed:
+/// an instruction that gets a value of the wrong type, like `Method` without a class below it,
+/// is a runtime error.
+///
+/// Nothing is collected while loading, the returned function isn't rooted though.
+pub(crate) fn deserialize(mem: &mut Mem, bytes: &[u8]) -> Result<Gc<ObjFunction>, String> {
+    let Some(rest) = bytes.strip_prefix(MAGIC) else {
+        return Err("Not a loxide bytecode file.".to_string());
+    };
+    let mut reader = Reader { bytes: rest, mem };
+    let version = reader.u8()?;
+    if version != VERSION {
+        return Err(format!(
+            "Unsupported bytecode version {version}, expected {VERSION}."
+        ));
+    }
+
+    let function = reader.function()?;
+    if !reader.bytes.is_empty() {
+        return Err("Unexpected data after the script.".to_string());
+    }
+    Ok(function)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    mem: &'a mut Mem,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.bytes.len() < len {
+            return Err("Unexpected end of bytecode file.".to_string());
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], String> {
+        let len = self.u32()?;
+        self.take(len as usize)
+    }
+
+    fn string(&mut self) -> Result<Value, String> {
+        let string = std::str::from_utf8(self.bytes()?)
+            .map_err(|_| "Invalid UTF-8 in bytecode string.".to_string())?;
+        Ok(Value::Obj(self.mem.copy_constant(string).upcast()))
+    }
+
+    fn debug_info(&mut self) -> Result<DebugInfo, String> {
+        let mut debug = DebugInfo::default();
+        let columns = self.u32()?;
+        for _ in 0..columns {
+            let offset = self.u32()?;
+            let column = self.u32()?;
+            debug.columns.push(ColumnStart { offset, column });
+        }
+        let locals = self.u32()?;
+        for _ in 0..locals {
+            let name = std::str::from_utf8(self.bytes()?)
+                .map_err(|_| "Invalid UTF-8 in bytecode string.".to_string())?;
+            debug.locals.push(LocalInfo {
+                name: name.to_string(),
+                slot: self.u8()?,
+                start: self.u32()?,
+                end: self.u32()?,
+            });
+        }
+        Ok(debug)
+    }
+
+    fn function(&mut self) -> Result<Gc<ObjFunction>, String> {
+        let name = match self.u8()? {
+            0 => None,
+            1 => Some(self.string()?.as_obj_str().unwrap()),
+            tag => return Err(format!("Invalid function name tag {tag}.")),
+        };
+        let mut function = ObjFunction::new(name);
+        function.line = self.u32()?;
+        function.arity = self.u8()?;
+        function.optional = self.u8()?;
+        function.rest = match self.u8()? {
+            0 => false,
+            1 => true,
+            flag => return Err(format!("Invalid rest parameter flag {flag}.")),
+        };
+        function.generator = match self.u8()? {
+            0 => false,
+            1 => true,
+            flag => return Err(format!("Invalid generator flag {flag}.")),
+        };
+        if function.param_count() > u8::MAX as usize {
+            return Err("Too many parameters.".to_string());
+        }
+        let params = self.u32()?;
+        for _ in 0..params {
+            let param = self.string()?.as_obj_str().unwrap();
+            function.params.push(param);
+        }
+        if function.params.len() != function.param_count() {
+            return Err("Parameter names don't match the parameters.".to_string());
+        }
+        function.upvalue_count = self.u8()?;
+
+        let chunk = &mut function.chunk;
+        chunk.code = self.bytes()?.to_vec();
+        let lines = self.u32()?;
+        for _ in 0..lines {
+            let offset = self.u32()?;
+            let line = self.u32()?;
+            chunk.lines.push(LineStart { offset, line });
+        }
+        chunk.debug = self.debug_info()?;
+
+        let constants = self.u32()?;
+        for _ in 0..constants {
+            let constant = match self.u8()? {
+                0 => Value::Nil,
+                1 => Value::Bool(false),
+                2 => Value::Bool(true),
+                3 => Value::Number(f64::from_le_bytes(self.take(8)?.try_into().unwrap())),
+                4 => self.string()?,
+                5 => Value::Obj(self.function()?.upcast()),
+                6 => Value::Int(i32::from_le_bytes(self.take(4)?.try_into().unwrap())),
+                tag => return Err(format!("Invalid constant tag {tag}.")),
+            };
+            function.chunk.constants.push(constant);
+        }
+
+        validate(&function)?;
+        Ok(self.mem.alloc_obj(function))
+    }
+}
+
+/// Check that every instruction is complete, its constants exist and have the right type, jumps
+/// land on an instruction, the last instruction doesn't run off its end and every byte has a
+/// line. The stack must have the same height whichever way an instruction is reached, and the
+/// locals and upvalues the instructions use must exist at that height
+fn validate(function: &ObjFunction) -> Result<(), String> {
+    let chunk = &function.chunk;
+    let ordered = chunk
+        .lines
+        .windows(2)
+        .all(|pair| pair[0].offset < pair[1].offset);
+    let in_code = chunk.lines.last().map(|start| start.offset as usize) < Some(chunk.len());
+    if chunk.lines.first().map(|start| start.offset) != Some(0) || !ordered || !in_code {
+        return Err("Invalid line information.".to_string());
+    }
+
+    let invalid = |start| format!("Invalid instruction at offset {start}.");
+    let mut instructions = vec![];
+    let mut targets = vec![];
+    let mut offset = 0;
+    while offset < chunk.len() {
+        let start = offset;
+        if Opcode::from_u8(chunk.code[offset]).is_none() {
+            return Err(invalid(start));
+        }
+        let instruction = chunk
+            .disassemble_instruction(&mut offset)
+            .ok_or_else(|| invalid(start))?;
+
+        let target = match instruction {
+            Instruction::Constant(op, constant)
+                if !matches!(op, Opcode::Constant | Opcode::ConstantLong | Opcode::Assert) =>
+            {
+                constant.as_str().ok_or_else(|| invalid(start))?;
+                None
+            }
+            Instruction::Invoke { method, .. } => {
+                method.as_str().ok_or_else(|| invalid(start))?;
+                None
+            }
+            Instruction::Jump(Opcode::Loop, jump) => Some(
+                offset
+                    .checked_sub(jump as usize)
+                    .ok_or_else(|| invalid(start))?,
+            ),
+            Instruction::Jump(_, jump) => Some(offset + jump as usize),
+            Instruction::SlotJump(_, _, jump) => Some(offset + jump as usize),
+            _ => None,
+        };
+        targets.extend(target.map(|target| (start, target)));
+        instructions.push((start, instruction));
+    }
+
+    match instructions.last() {
+        Some((_, Instruction::Simple(Opcode::Return | Opcode::Throw)))
+        | Some((_, Instruction::Jump(Opcode::Jump | Opcode::Loop, _))) => {}
+        _ => return Err("Bytecode function doesn't end with a return.".to_string()),
+    }
+    for (start, target) in targets {
+        if instructions
+            .binary_search_by_key(&target, |&(start, _)| start)
+            .is_err()
+        {
+            return Err(invalid(start));
+        }
+    }
+
+    let heights = stack::heights(chunk)
+        .ok_or_else(|| "Bytecode leaves the stack at different heights.".to_string())?;
+    for (start, instruction) in instructions {
+        // Never runs
+        let Some(height) = heights[start] else {
+            continue;
+        };
+        // The callee, the parameters and what's on the stack above them
+        let frame = (1 + function.param_count()) as isize + height;
+        let local = |slot: u8| (slot as isize) < frame;
+        let upvalue = |index: u8| index < function.upvalue_count;
+        let valid = height >= 0
+            && match instruction {
+                Instruction::Simple(Opcode::Return | Opcode::Throw) => height >= 1,
+                Instruction::Byte(
+                    Opcode::GetLocal | Opcode::SetLocal | Opcode::CloseLocal,
+                    slot,
+                ) => local(slot),
+                Instruction::Byte(Opcode::GetUpvalue | Opcode::SetUpvalue, index) => upvalue(index),
+                Instruction::Locals(_, a, b) => local(a) && local(b),
+                // The cursor is in the slot after the collection
+                Instruction::SlotJump(Opcode::ForIter, slot, _) => {
+                    local(slot) && slot.checked_add(1).map_or(false, local)
+                }
+                Instruction::SlotJump(_, slot, _) => local(slot),
+                Instruction::Closure { upvalues, .. } => upvalues.iter().all(|captured| {
+                    if captured.is_local {
+                        local(captured.index)
+                    } else {
+                        upvalue(captured.index)
+                    }
+                }),
+                _ => true,
+            };
+        if !valid {
+            return Err(invalid(start));
+        }
+    }
+    Ok(())
+}
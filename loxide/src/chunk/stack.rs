@@ -0,0 +1,108 @@
+//! How many values a chunk pushes onto the stack at most, so the VM can check that a call fits
+//! on the stack once when it starts instead of on every push.
+
+use super::{Chunk, Instruction, Opcode};
+
+/// What [`max_height`] returns for bytecode whose stack can grow without bound, like a loop that
+/// pushes a value on every iteration. The compiler never produces this, a chunk that needs this
+/// many values always overflows
+pub const UNBOUNDED: usize = u32::MAX as usize;
+
+/// Most values on the stack at once while `chunk` runs, above the callee and its arguments.
+///
+/// A few instructions push more than they leave while they run (e.g. `AddLocals` pushes both
+/// locals before adding them), the VM's stack slack covers that
+pub fn max_height(chunk: &Chunk) -> usize {
+    heights(chunk).map_or(UNBOUNDED, |heights| {
+        heights.into_iter().flatten().max().unwrap_or(0) as usize
+    })
+}
+
+/// Height of the stack at the start of each instruction, above the callee and its arguments,
+/// `None` for bytes that aren't the start of a reachable instruction. Returns `None` if the
+/// height isn't known, like for a loop that pushes on every iteration or an invalid instruction.
+///
+/// This follows every path through the code: jumps continue with the height at the jump, and
+/// `try` handlers with the height at `PushCatch`/`PushFinally` plus the values pushed for them.
+/// The compiler leaves the stack at the same height whichever way an instruction is reached, so
+/// each one is only looked at once
+pub(crate) fn heights(chunk: &Chunk) -> Option<Vec<Option<isize>>> {
+    let mut heights: Vec<Option<isize>> = vec![None; chunk.len()];
+    let mut pending = vec![(0, 0)];
+    while let Some((mut offset, mut height)) = pending.pop() {
+        while offset < chunk.len() {
+            match heights[offset] {
+                Some(known) if height == known => break,
+                // Like a loop that pushes on every iteration
+                Some(_) => return None,
+                None => heights[offset] = Some(height),
+            }
+
+            let opcode = Opcode::from_u8(chunk[offset])?;
+            let instruction = chunk.disassemble_instruction(&mut offset)?;
+
+            let effect = match instruction {
+                Instruction::Simple(Opcode::Return | Opcode::Throw) => break,
+                Instruction::Jump(op, distance) => {
+                    let distance = distance as usize;
+                    // `try` handlers get the exception, or the completion and its value for
+                    // `finally`
+                    let (target, pushed) = match op {
+                        Opcode::Loop => (offset.checked_sub(distance)?, 0),
+                        Opcode::PushCatch => (offset + distance, 1),
+                        Opcode::PushFinally => (offset + distance, 2),
+                        _ => (offset + distance, 0),
+                    };
+                    pending.push((target, height + pushed));
+                    if matches!(op, Opcode::Jump | Opcode::Loop) {
+                        break;
+                    }
+                    0
+                }
+                Instruction::SlotJump(op, _, distance) => {
+                    pending.push((offset + distance as usize, height));
+                    // `ForIter` pushes the next item unless it's done
+                    isize::from(op == Opcode::ForIter)
+                }
+                instruction => effect(opcode, &instruction),
+            };
+            height += effect;
+        }
+    }
+    Some(heights)
+}
+
+/// How many values an instruction that doesn't jump pushes minus how many it pops
+fn effect(opcode: Opcode, instruction: &Instruction) -> isize {
+    use Opcode::*;
+    let count = match instruction {
+        Instruction::Byte(_, count) => *count as isize,
+        Instruction::Invoke { arg_count, .. } | Instruction::CallConstant { arg_count, .. } => {
+            *arg_count as isize
+        }
+        // A name and a value for each named argument
+        Instruction::CallNamed { arg_count, named } => *arg_count as isize + 2 * *named as isize,
+        _ => 0,
+    };
+    match opcode {
+        Constant | ConstantLong | Nil | True | False | Dup | GetGlobal | GetLocal | GetUpvalue
+        | Class | Closure | AddLocals | Import | ImportNative | EndModule => 1,
+        Negate | Not | BitNot | ToString | GetIter | SetGlobal | SetLocal | SetUpvalue
+        | GetProperty | CloseLocal | PopHandler | Await => 0,
+        Add | Subtract | Multiply | Divide | Modulo | FloorDivide | Power | BitAnd | BitOr
+        | BitXor | ShiftLeft | ShiftRight | Equal | Greater | Less | Print | Pop | DefineGlobal
+        | CloseUpvalue | SetProperty | Method | Field | Getter | Setter | StaticMethod
+        | StaticField | Inherit | GetSuper | GetIndex | Assert | Yield => -1,
+        SetIndex | Slice | EndFinally => -2,
+        // The arguments and the callee are replaced by the result
+        Call | TailCall | CallNamed | Invoke => -count,
+        // And the superclass is popped
+        SuperInvoke => -count - 1,
+        // The constant is the last argument
+        CallConstant => 1 - count,
+        BuildList | Concat => 1 - count,
+        BuildMap => 1 - 2 * count,
+        Return | Throw | Jump | JumpIfFalse | Loop | PushCatch | PushFinally | ForIter
+        | LessLocalJumpIfFalse => unreachable!("handled by heights"),
+    }
+}
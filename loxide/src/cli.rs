@@ -0,0 +1,700 @@
+use std::path::PathBuf;
+
+use loxide::{compile::lint::Rule, mem::GcConfig};
+
+pub const USAGE: &str = "\
+Usage: loxide [options] [script [args...]]
+       loxide [options] - [args...]
+       loxide run [options] [--watch] <script> [args...]
+       loxide [options] -e <code> [args...]
+       loxide compile [--opt] <script> [-o <output>]
+       loxide debug <script> [args...]
+       loxide bench [--opt] [--warmup <n>] [--runs <n>] [--format json|csv]
+                    [--interpreter <path>] [dir]
+       loxide test [--opt] [--gc-stress] [path...]
+       loxide fmt [--check] [path...]
+       loxide lint [--allow <rule>...] [path...]
+       loxide lsp
+
+Without a script or -e, starts an interactive REPL, or runs the program on stdin if it
+isn't a terminal, as `-` for the script does. `run` runs the script, with --watch again
+every time it or a module it imports changes. `compile` writes the script's bytecode
+to <output> (by default the script with a .loxc extension), which can be run like a script.
+`debug` runs the script under a debugger with breakpoints and stepping, type `help` there
+for its commands. `bench` times every .lox file in <dir> (by default ./benchmarks), after
+<n> warmup runs (1) over <n> runs (5), and prints the results as JSON or CSV. With
+--interpreter it times another interpreter instead, e.g. zlox, for comparisons. `test`
+runs every .lox file in the paths (by default ./test) and checks its output against the
+`// expect: ...` comments in it, like the Crafting Interpreters test suite. `fmt` rewrites
+every .lox file in the paths (by default .) in the canonical layout, with --check it only
+lists the ones that aren't and fails if there are any. `lint` warns about unused locals,
+unreachable code, shadowed variables, constant conditions and globals used before they're
+declared in the .lox files in the paths (.), --allow turns off one of these rules:
+unused-local, unreachable-code, shadowed-variable, constant-condition, use-before-definition.
+`lsp` runs a language server on stdin and stdout for editors, with diagnostics, go to
+definition, hover and document symbols.
+
+Options:
+  -e, --eval <code>  Run <code> instead of a script
+      --gc-stress    Collect garbage on every allocation
+      --gc-log       Log allocations, marks and frees to stderr
+      --seed <n>     Seed random() and randomInt() to make runs repeatable
+      --replay <trace>
+                     Record what clock(), now(), random(), randomInt() and readLine()
+                     return to <trace>, or if it exists, return that instead so the
+                     recorded run repeats exactly
+      --disassemble  Print the bytecode of the script and its functions instead of
+                     running it
+      --dump-ast json|sexp
+                     Print the syntax tree of the script as JSON or s-expressions
+                     instead of running it
+      --trace        Print the stack and each instruction to stderr as it runs
+      --opt          Run the peephole optimizer over the compiled bytecode
+      --no-tail-calls
+                     Give every call its own frame, also in `return f(...)`, so stack
+                     traces show all of them
+      --per-iteration-loops
+                     Give each iteration of a for loop its own loop variable, so
+                     closures created in the body keep its value from their iteration
+      --count-dispatches
+                     Print how many instructions ran to stderr when the script ends
+      --profile      Print how often each opcode ran and the time spent in each function
+                     to stderr when the script ends
+      --coverage     Write which lines and branches ran to lcov.info, and each script
+                     annotated with how often its lines ran to <script>.cov
+      --mem-stats    Print the heap size, peak, collections and objects by type to stderr
+                     when the script ends
+      --no-color     Print errors without colors, which are only used on a terminal
+  -h, --help         Print this help and exit
+  -V, --version      Print the version and exit
+
+Arguments after the script (or after --) are passed to it, see `args()`.";
+
+#[derive(Debug, PartialEq)]
+pub enum Mode {
+    Repl,
+    File(PathBuf),
+    /// `-` for the script, read it from stdin
+    Stdin,
+    Eval(String),
+    Compile {
+        input: PathBuf,
+        output: PathBuf,
+    },
+    Debug(PathBuf),
+    /// `run --watch`, run the script again when it changes
+    Watch(PathBuf),
+    Bench(Bench),
+    /// Files and directories of scripts with expectation comments
+    Test(Vec<PathBuf>),
+    /// Files and directories of scripts to format
+    Fmt {
+        paths: Vec<PathBuf>,
+        check: bool,
+    },
+    /// Files and directories of scripts to lint, without the rules turned off
+    Lint {
+        paths: Vec<PathBuf>,
+        allow: Vec<Rule>,
+    },
+    /// A language server on stdin and stdout
+    Lsp,
+    Help,
+    Version,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Bench {
+    pub dir: PathBuf,
+    pub warmup: u32,
+    pub runs: u32,
+    pub format: BenchFormat,
+    /// Run the benchmarks with this instead of loxide itself
+    pub interpreter: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BenchFormat {
+    Json,
+    Csv,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AstFormat {
+    Json,
+    Sexp,
+}
+
+#[derive(Debug)]
+pub struct Options {
+    pub mode: Mode,
+    pub gc_config: GcConfig,
+    pub random_seed: Option<u64>,
+    /// Trace to record to or replay, see `loxide::replay`
+    pub replay: Option<PathBuf>,
+    /// Only compile and print the bytecode
+    pub disassemble: bool,
+    /// Only parse and print the syntax tree
+    pub dump_ast: Option<AstFormat>,
+    pub trace_execution: bool,
+    pub optimize: bool,
+    pub tail_calls: bool,
+    pub per_iteration_loops: bool,
+    pub count_dispatches: bool,
+    pub profile: bool,
+    pub coverage: bool,
+    pub mem_stats: bool,
+    /// Color errors, if stderr is a terminal
+    pub color: bool,
+    /// Arguments passed through to the script
+    pub script_args: Vec<String>,
+}
+
+/// Parse the command line arguments, not including the program name.
+///
+/// Options are only recognized before the script path, everything after it belongs to the
+/// script.
+pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Options, String> {
+    let mut args = args.into_iter().peekable();
+    if args.peek().map(String::as_str) == Some("compile") {
+        args.next();
+        return parse_compile(args);
+    }
+    if args.peek().map(String::as_str) == Some("bench") {
+        args.next();
+        return parse_bench(args);
+    }
+    if args.peek().map(String::as_str) == Some("test") {
+        args.next();
+        return parse_test(args);
+    }
+    if args.peek().map(String::as_str) == Some("fmt") {
+        args.next();
+        return parse_fmt(args);
+    }
+    if args.peek().map(String::as_str) == Some("lint") {
+        args.next();
+        return parse_lint(args);
+    }
+    if args.peek().map(String::as_str) == Some("lsp") {
+        args.next();
+        // Editors pass --stdio to servers that can also use other transports
+        if let Some(arg) = args.find(|arg| arg != "--stdio") {
+            return Err(format!("Unexpected argument '{arg}'."));
+        }
+        return Ok(Options::new(Mode::Lsp, GcConfig::default()));
+    }
+    if args.peek().map(String::as_str) == Some("debug") {
+        args.next();
+        let Some(script) = args.next() else {
+            return Err("Missing script to debug.".to_string());
+        };
+        return Ok(Options {
+            script_args: args.collect(),
+            ..Options::new(Mode::Debug(script.into()), GcConfig::default())
+        });
+    }
+
+    // The same as without `run`, which only adds --watch
+    let run = args.peek().map(String::as_str) == Some("run");
+    if run {
+        args.next();
+    }
+
+    let mut gc_config = GcConfig::default();
+    let mut random_seed = None;
+    let mut replay = None;
+    let mut disassemble = false;
+    let mut dump_ast = None;
+    let mut trace_execution = false;
+    let mut optimize = false;
+    let mut tail_calls = true;
+    let mut per_iteration_loops = false;
+    let mut count_dispatches = false;
+    let mut profile = false;
+    let mut coverage = false;
+    let mut mem_stats = false;
+    let mut color = true;
+    let mut watch = false;
+    let mut eval = None;
+    let mut script = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Options::new(Mode::Help, gc_config)),
+            "-V" | "--version" => return Ok(Options::new(Mode::Version, gc_config)),
+            "--gc-stress" => gc_config.stress = true,
+            "--gc-log" => gc_config.log = true,
+            "--seed" => match args.next().map(|seed| seed.parse()) {
+                Some(Ok(seed)) => random_seed = Some(seed),
+                Some(Err(_)) => return Err(format!("Expected an integer after '{arg}'.")),
+                None => return Err(format!("Missing seed after '{arg}'.")),
+            },
+            "--replay" => match args.next() {
+                Some(path) => replay = Some(path.into()),
+                None => return Err(format!("Missing path after '{arg}'.")),
+            },
+            "--disassemble" => disassemble = true,
+            "--dump-ast" => match args.next().as_deref() {
+                Some("json") => dump_ast = Some(AstFormat::Json),
+                Some("sexp") => dump_ast = Some(AstFormat::Sexp),
+                Some(format) => return Err(format!("Unknown format '{format}'.")),
+                None => return Err(format!("Missing format after '{arg}'.")),
+            },
+            "--trace" => trace_execution = true,
+            "--opt" => optimize = true,
+            "--no-tail-calls" => tail_calls = false,
+            "--per-iteration-loops" => per_iteration_loops = true,
+            "--count-dispatches" => count_dispatches = true,
+            "--profile" => profile = true,
+            "--coverage" => coverage = true,
+            "--mem-stats" => mem_stats = true,
+            "--no-color" => color = false,
+            "--watch" if run => watch = true,
+            "-e" | "--eval" => match args.next() {
+                Some(code) => eval = Some(code),
+                None => return Err(format!("Missing code after '{arg}'.")),
+            },
+            "--" => break,
+            _ if arg.starts_with('-') && arg != "-" => {
+                return Err(format!("Unknown option '{arg}'."))
+            }
+            _ => {
+                script = Some(arg);
+                break;
+            }
+        }
+    }
+
+    let mut script_args: Vec<String> = args.collect();
+    let mode = match (eval, script) {
+        (Some(code), script) => {
+            // With -e there's no script, a positional argument is the first script argument
+            if let Some(script) = script {
+                script_args.insert(0, script);
+            }
+            Mode::Eval(code)
+        }
+        (None, Some(script)) if script == "-" => Mode::Stdin,
+        (None, Some(script)) => Mode::File(script.into()),
+        (None, None) if script_args.is_empty() => Mode::Repl,
+        // `loxide -- script.lox args...`
+        (None, None) => Mode::File(script_args.remove(0).into()),
+    };
+    let mode = match mode {
+        Mode::File(script) if watch => Mode::Watch(script),
+        _ if watch => return Err("Missing script to watch.".to_string()),
+        Mode::Repl if run => return Err("Missing script to run.".to_string()),
+        mode => mode,
+    };
+
+    Ok(Options {
+        mode,
+        gc_config,
+        random_seed,
+        replay,
+        disassemble,
+        dump_ast,
+        trace_execution,
+        optimize,
+        tail_calls,
+        per_iteration_loops,
+        count_dispatches,
+        profile,
+        coverage,
+        mem_stats,
+        color,
+        script_args,
+    })
+}
+
+fn parse_compile<I: Iterator<Item = String>>(args: I) -> Result<Options, String> {
+    let mut args = args;
+    let mut input: Option<PathBuf> = None;
+    let mut output = None;
+    let mut optimize = false;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--opt" => optimize = true,
+            "-o" | "--output" => match args.next() {
+                Some(path) => output = Some(path.into()),
+                None => return Err(format!("Missing path after '{arg}'.")),
+            },
+            _ if arg.starts_with('-') => return Err(format!("Unknown option '{arg}'.")),
+            _ if input.is_some() => return Err(format!("Unexpected argument '{arg}'.")),
+            _ => input = Some(arg.into()),
+        }
+    }
+
+    let Some(input) = input else {
+        return Err("Missing script to compile.".to_string());
+    };
+    let output = output.unwrap_or_else(|| input.with_extension("loxc"));
+    Ok(Options {
+        optimize,
+        ..Options::new(Mode::Compile { input, output }, GcConfig::default())
+    })
+}
+
+fn parse_bench<I: Iterator<Item = String>>(args: I) -> Result<Options, String> {
+    let mut args = args;
+    let mut bench = Bench {
+        dir: "benchmarks".into(),
+        warmup: 1,
+        runs: 5,
+        format: BenchFormat::Json,
+        interpreter: None,
+    };
+    let mut dir = None;
+    let mut optimize = false;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--opt" => optimize = true,
+            "--warmup" | "--runs" => {
+                let count = match args.next().map(|count| count.parse()) {
+                    Some(Ok(count)) => count,
+                    Some(Err(_)) => return Err(format!("Expected a number after '{arg}'.")),
+                    None => return Err(format!("Missing number after '{arg}'.")),
+                };
+                if arg == "--warmup" {
+                    bench.warmup = count;
+                } else if count == 0 {
+                    return Err("Expected at least one run.".to_string());
+                } else {
+                    bench.runs = count;
+                }
+            }
+            "--format" => match args.next().as_deref() {
+                Some("json") => bench.format = BenchFormat::Json,
+                Some("csv") => bench.format = BenchFormat::Csv,
+                Some(format) => return Err(format!("Unknown format '{format}'.")),
+                None => return Err(format!("Missing format after '{arg}'.")),
+            },
+            "--interpreter" => match args.next() {
+                Some(path) => bench.interpreter = Some(path.into()),
+                None => return Err(format!("Missing path after '{arg}'.")),
+            },
+            _ if arg.starts_with('-') => return Err(format!("Unknown option '{arg}'.")),
+            _ if dir.is_some() => return Err(format!("Unexpected argument '{arg}'.")),
+            _ => dir = Some(arg.into()),
+        }
+    }
+
+    if let Some(dir) = dir {
+        bench.dir = dir;
+    }
+    Ok(Options {
+        optimize,
+        ..Options::new(Mode::Bench(bench), GcConfig::default())
+    })
+}
+
+fn parse_test<I: Iterator<Item = String>>(args: I) -> Result<Options, String> {
+    let mut gc_config = GcConfig::default();
+    let mut optimize = false;
+    let mut paths = vec![];
+
+    for arg in args {
+        match arg.as_str() {
+            "--opt" => optimize = true,
+            "--gc-stress" => gc_config.stress = true,
+            _ if arg.starts_with('-') => return Err(format!("Unknown option '{arg}'.")),
+            _ => paths.push(arg.into()),
+        }
+    }
+
+    if paths.is_empty() {
+        paths.push("test".into());
+    }
+    Ok(Options {
+        optimize,
+        ..Options::new(Mode::Test(paths), gc_config)
+    })
+}
+
+fn parse_fmt<I: Iterator<Item = String>>(args: I) -> Result<Options, String> {
+    let mut check = false;
+    let mut paths = vec![];
+
+    for arg in args {
+        match arg.as_str() {
+            "--check" => check = true,
+            _ if arg.starts_with('-') => return Err(format!("Unknown option '{arg}'.")),
+            _ => paths.push(arg.into()),
+        }
+    }
+
+    if paths.is_empty() {
+        paths.push(".".into());
+    }
+    let mode = Mode::Fmt { paths, check };
+    Ok(Options::new(mode, GcConfig::default()))
+}
+
+fn parse_lint<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+    let mut allow = vec![];
+    let mut paths = vec![];
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--allow" => match args.next() {
+                Some(code) => match Rule::from_code(&code) {
+                    Some(rule) => allow.push(rule),
+                    None => return Err(format!("Unknown rule '{code}'.")),
+                },
+                None => return Err(format!("Missing rule after '{arg}'.")),
+            },
+            _ if arg.starts_with('-') => return Err(format!("Unknown option '{arg}'.")),
+            _ => paths.push(arg.into()),
+        }
+    }
+
+    if paths.is_empty() {
+        paths.push(".".into());
+    }
+    let mode = Mode::Lint { paths, allow };
+    Ok(Options::new(mode, GcConfig::default()))
+}
+
+impl Options {
+    fn new(mode: Mode, gc_config: GcConfig) -> Self {
+        Self {
+            mode,
+            gc_config,
+            random_seed: None,
+            replay: None,
+            disassemble: false,
+            dump_ast: None,
+            trace_execution: false,
+            optimize: false,
+            tail_calls: true,
+            per_iteration_loops: false,
+            count_dispatches: false,
+            profile: false,
+            coverage: false,
+            mem_stats: false,
+            color: true,
+            script_args: vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse, AstFormat, Bench, BenchFormat, GcConfig, Mode, Rule};
+
+    fn parse_strs(args: &[&str]) -> Result<super::Options, String> {
+        parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn modes() {
+        assert_eq!(parse_strs(&[]).unwrap().mode, Mode::Repl);
+        assert_eq!(parse_strs(&["--help"]).unwrap().mode, Mode::Help);
+        assert_eq!(parse_strs(&["-V"]).unwrap().mode, Mode::Version);
+        assert_eq!(
+            parse_strs(&["script.lox"]).unwrap().mode,
+            Mode::File("script.lox".into())
+        );
+        assert_eq!(parse_strs(&["-", "a"]).unwrap().mode, Mode::Stdin);
+        assert_eq!(
+            parse_strs(&["-e", "print 1;"]).unwrap().mode,
+            Mode::Eval("print 1;".into())
+        );
+        assert_eq!(
+            parse_strs(&["compile", "dir/script.lox"]).unwrap().mode,
+            Mode::Compile {
+                input: "dir/script.lox".into(),
+                output: "dir/script.loxc".into()
+            }
+        );
+        assert_eq!(
+            parse_strs(&["run", "script.lox"]).unwrap().mode,
+            Mode::File("script.lox".into())
+        );
+        assert_eq!(
+            parse_strs(&["run", "--watch", "script.lox", "--watch"])
+                .unwrap()
+                .mode,
+            Mode::Watch("script.lox".into())
+        );
+        assert_eq!(
+            parse_strs(&["debug", "script.lox"]).unwrap().mode,
+            Mode::Debug("script.lox".into())
+        );
+        assert_eq!(
+            parse_strs(&["bench"]).unwrap().mode,
+            Mode::Bench(Bench {
+                dir: "benchmarks".into(),
+                warmup: 1,
+                runs: 5,
+                format: BenchFormat::Json,
+                interpreter: None,
+            })
+        );
+        let options = parse_strs(&[
+            "bench",
+            "--runs",
+            "3",
+            "--opt",
+            "--format",
+            "csv",
+            "--interpreter",
+            "zlox",
+            "lox",
+        ])
+        .unwrap();
+        assert!(options.optimize);
+        assert_eq!(
+            options.mode,
+            Mode::Bench(Bench {
+                dir: "lox".into(),
+                warmup: 1,
+                runs: 3,
+                format: BenchFormat::Csv,
+                interpreter: Some("zlox".into()),
+            })
+        );
+        assert_eq!(
+            parse_strs(&["test"]).unwrap().mode,
+            Mode::Test(vec!["test".into()])
+        );
+        let options = parse_strs(&["test", "--gc-stress", "a.lox", "dir"]).unwrap();
+        assert!(options.gc_config.stress);
+        assert_eq!(options.mode, Mode::Test(vec!["a.lox".into(), "dir".into()]));
+        assert_eq!(
+            parse_strs(&["fmt"]).unwrap().mode,
+            Mode::Fmt {
+                paths: vec![".".into()],
+                check: false
+            }
+        );
+        assert_eq!(
+            parse_strs(&["fmt", "--check", "a.lox", "dir"])
+                .unwrap()
+                .mode,
+            Mode::Fmt {
+                paths: vec!["a.lox".into(), "dir".into()],
+                check: true
+            }
+        );
+        assert_eq!(
+            parse_strs(&["lint", "--allow", "unused-local", "a.lox"])
+                .unwrap()
+                .mode,
+            Mode::Lint {
+                paths: vec!["a.lox".into()],
+                allow: vec![Rule::UnusedLocal]
+            }
+        );
+        assert_eq!(
+            parse_strs(&["lint", "--allow", "typos"]).unwrap_err(),
+            "Unknown rule 'typos'."
+        );
+        assert_eq!(parse_strs(&["lsp"]).unwrap().mode, Mode::Lsp);
+        assert_eq!(parse_strs(&["lsp", "--stdio"]).unwrap().mode, Mode::Lsp);
+        assert_eq!(
+            parse_strs(&["lsp", "a.lox"]).unwrap_err(),
+            "Unexpected argument 'a.lox'."
+        );
+        assert_eq!(
+            parse_strs(&["compile", "-o", "out.bin", "script.lox"])
+                .unwrap()
+                .mode,
+            Mode::Compile {
+                input: "script.lox".into(),
+                output: "out.bin".into()
+            }
+        );
+    }
+
+    #[test]
+    fn script_args() {
+        let options = parse_strs(&["--gc-stress", "script.lox", "a", "--gc-log", "-e"]).unwrap();
+        assert_eq!(options.mode, Mode::File("script.lox".into()));
+        assert!(options.gc_config.stress);
+        // `--gc-log` after the script is the script's, the log is only on with `debug_gc`
+        assert_eq!(options.gc_config.log, GcConfig::default().log);
+        assert_eq!(options.script_args, vec!["a", "--gc-log", "-e"]);
+
+        let options = parse_strs(&["-e", "print 1;", "a", "b"]).unwrap();
+        assert_eq!(options.script_args, vec!["a", "b"]);
+
+        let options = parse_strs(&["--seed", "7", "script.lox"]).unwrap();
+        assert_eq!(options.random_seed, Some(7));
+        let options = parse_strs(&["--replay", "run.trace", "script.lox"]).unwrap();
+        assert_eq!(options.replay, Some("run.trace".into()));
+        assert!(!options.disassemble);
+
+        let options = parse_strs(&["--disassemble", "-e", "print 1;"]).unwrap();
+        assert!(options.disassemble);
+        let options = parse_strs(&["--dump-ast", "sexp", "a.lox"]).unwrap();
+        assert_eq!(options.dump_ast, Some(AstFormat::Sexp));
+        assert_eq!(options.mode, Mode::File("a.lox".into()));
+        assert_eq!(
+            parse_strs(&["--dump-ast", "xml", "a.lox"]).unwrap_err(),
+            "Unknown format 'xml'."
+        );
+        assert!(parse_strs(&["--dump-ast"]).is_err());
+        assert!(!options.trace_execution);
+
+        let options = parse_strs(&["--trace", "script.lox"]).unwrap();
+        assert!(options.trace_execution);
+        assert!(!options.optimize);
+
+        let options = parse_strs(&["--opt", "script.lox"]).unwrap();
+        assert!(options.optimize);
+        assert!(parse_strs(&["compile", "--opt", "a.lox"]).unwrap().optimize);
+        assert!(
+            parse_strs(&["--count-dispatches", "a.lox"])
+                .unwrap()
+                .count_dispatches
+        );
+        assert!(parse_strs(&["--profile", "a.lox"]).unwrap().profile);
+        assert!(parse_strs(&["--coverage", "a.lox"]).unwrap().coverage);
+        assert!(!parse_strs(&["a.lox", "--coverage"]).unwrap().coverage);
+        assert!(parse_strs(&["a.lox"]).unwrap().tail_calls);
+        assert!(
+            !parse_strs(&["--no-tail-calls", "a.lox"])
+                .unwrap()
+                .tail_calls
+        );
+        assert!(
+            parse_strs(&["--per-iteration-loops", "a.lox"])
+                .unwrap()
+                .per_iteration_loops
+        );
+        assert!(parse_strs(&["--mem-stats", "-e", "1;"]).unwrap().mem_stats);
+        assert!(parse_strs(&["a.lox"]).unwrap().color);
+        assert!(!parse_strs(&["--no-color", "a.lox"]).unwrap().color);
+
+        let options = parse_strs(&["debug", "script.lox", "--opt"]).unwrap();
+        assert_eq!(options.script_args, vec!["--opt"]);
+        assert!(!options.optimize);
+
+        let options = parse_strs(&["--", "-weird.lox", "a"]).unwrap();
+        assert_eq!(options.mode, Mode::File("-weird.lox".into()));
+        assert_eq!(options.script_args, vec!["a"]);
+    }
+
+    #[test]
+    fn errors() {
+        assert!(parse_strs(&["--nope"]).is_err());
+        assert!(parse_strs(&["-e"]).is_err());
+        assert!(parse_strs(&["--seed"]).is_err());
+        assert!(parse_strs(&["--replay"]).is_err());
+        assert!(parse_strs(&["--seed", "-1"]).is_err());
+        assert!(parse_strs(&["--watch", "a.lox"]).is_err());
+        assert!(parse_strs(&["run"]).is_err());
+        assert!(parse_strs(&["run", "--watch", "-e", "print 1;"]).is_err());
+        assert!(parse_strs(&["compile"]).is_err());
+        assert!(parse_strs(&["compile", "a.lox", "-o"]).is_err());
+        assert!(parse_strs(&["compile", "a.lox", "b.lox"]).is_err());
+        assert!(parse_strs(&["debug"]).is_err());
+        assert!(parse_strs(&["bench", "--runs", "0"]).is_err());
+        assert!(parse_strs(&["bench", "--format", "xml"]).is_err());
+        assert!(parse_strs(&["bench", "a", "b"]).is_err());
+    }
+}
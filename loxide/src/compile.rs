@@ -1,18 +1,38 @@
+pub mod ast;
+pub mod fmt;
+pub mod lexer;
+pub mod lint;
+pub mod symbols;
+
 use std::{
+    collections::HashMap,
     mem::MaybeUninit,
-    ptr::{addr_of_mut, null_mut, NonNull},
+    ptr::{addr_of_mut, NonNull},
 };
 
 use crate::{
-    chunk::{Chunk, Opcode},
+    chunk::{Chunk, Opcode, FINALLY_NORMAL, MAX_CONSTANTS},
     mem::{Gc, Mem},
-    obj::ObjFunction,
-    value::Value,
+    obj::{Obj, ObjFunction, ObjPunnable, ObjString},
+    value::{Unpacked, Value},
 };
 
 #[derive(Debug, Clone, Copy)]
 struct ParseRuleCtx {
     can_assign: bool,
+    /// Whether a `+` may follow, for string literals starting a [`Concatenation`]
+    can_concat: bool,
+}
+
+/// A `+` chain whose left operand is a string, see [`Parser::concatenation`]
+#[derive(Default)]
+struct Concatenation {
+    /// Operands already on the stack
+    parts: u8,
+    /// Literal text after them, which isn't emitted until the next operand that isn't a literal
+    literal: String,
+    /// Whether any of the operands isn't a literal
+    dynamic: bool,
 }
 
 type ParseFn<'a, 'src> = fn(&mut Parser<'a, 'src>, ParseRuleCtx);
@@ -27,13 +47,21 @@ pub struct ParseRule<'a, 'src> {
 pub enum Precedence {
     None = 0,
     Assignment,
+    Conditional,
     Or,
     And,
     Equality,
     Comparison,
+    // Bitwise operators bind tighter than comparisons (like Python, unlike C) so
+    // `x & 1 == 0` means `(x & 1) == 0`
+    BitOr,
+    BitXor,
+    BitAnd,
+    Shift,
     Term,
     Factor,
     Unary,
+    Exponent,
     Call,
     Primary,
 }
@@ -44,15 +72,21 @@ impl Precedence {
         match val {
             0 => Some(None),
             1 => Some(Assignment),
-            2 => Some(Or),
-            3 => Some(And),
-            4 => Some(Equality),
-            5 => Some(Comparison),
-            6 => Some(Term),
-            7 => Some(Factor),
-            8 => Some(Unary),
-            9 => Some(Call),
-            10 => Some(Primary),
+            2 => Some(Conditional),
+            3 => Some(Or),
+            4 => Some(And),
+            5 => Some(Equality),
+            6 => Some(Comparison),
+            7 => Some(BitOr),
+            8 => Some(BitXor),
+            9 => Some(BitAnd),
+            10 => Some(Shift),
+            11 => Some(Term),
+            12 => Some(Factor),
+            13 => Some(Unary),
+            14 => Some(Exponent),
+            15 => Some(Call),
+            16 => Some(Primary),
             _ => Option::None,
         }
     }
@@ -96,6 +130,11 @@ pub struct Local<'src> {
     name: Token<'src>,
     depth: Option<u32>,
     is_captured: bool,
+    /// Declared with `const`, assigning to it is a compile error
+    is_const: bool,
+    /// The function a `fun` declaration bound it to, as long as nothing was assigned to it
+    /// since. Calls to it can match named arguments to parameters at compile time
+    function: Option<Gc<ObjFunction>>,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq)]
@@ -104,14 +143,12 @@ pub enum FunctionKind {
     Function,
     Script,
     Initializer,
-}
-
-#[derive(Debug, Clone, PartialEq)]
-pub enum FunctionKindT<T> {
-    Function(T),
-    Method(T),
-    Script,
-    Initializer,
+    /// `get name { ... }`, a method without parameters
+    Getter,
+    /// `set name(value) { ... }`, returns its parameter so assignments still yield the value
+    Setter,
+    /// `var name = value;` in a class body, evaluates `value` for each new instance
+    FieldInitializer,
 }
 
 pub struct Locals<'src> {
@@ -131,6 +168,9 @@ pub struct Upvalue {
 pub struct ClassCompiler {
     enclosing: Option<Box<ClassCompiler>>,
     has_superclass: bool,
+    /// Set while compiling the initializer of a `static var`, which runs once where there's
+    /// no `this`
+    in_static_field: bool,
 }
 
 impl ClassCompiler {
@@ -138,43 +178,87 @@ impl ClassCompiler {
         Self {
             enclosing,
             has_superclass: false,
+            in_static_field: false,
         }
     }
 }
 
+/// A loop being compiled, innermost last in `Compiler::loops`
+pub struct Loop {
+    /// Where `continue` jumps back to
+    start: usize,
+    /// Scope depth of the loop itself, locals deeper than this belong to the body
+    scope_depth: usize,
+    /// Offsets of the `break` jumps to patch once the end of the loop is known
+    breaks: Vec<u32>,
+    /// Number of enclosing exception handlers when the loop started
+    handler_depth: usize,
+}
+
+/// An exception handler active while compiling, mirroring the handlers the VM pushes
+#[derive(Clone, Copy, PartialEq)]
+pub enum Handler {
+    /// Around a `try` block, popped before its `catch` block
+    Catch,
+    /// Around the `try` and `catch` blocks
+    Finally,
+}
+
 pub struct Compiler<'src> {
-    pub function: Gc<ObjFunction>,
+    pub(crate) function: Gc<ObjFunction>,
     enclosing: Option<Box<Compiler<'src>>>,
     class_compiler: Option<Box<ClassCompiler>>,
     function_kind: FunctionKind,
     locals: Locals<'src>,
     scope_depth: usize,
     upvalues: [MaybeUninit<Upvalue>; u8::MAX as usize],
+    loops: Vec<Loop>,
+    handlers: Vec<Handler>,
+    /// Offset of the last `Call` emitted, a `return` right after it makes it a `TailCall`
+    last_call: Option<usize>,
+    /// Offset of the last `GetLocal` of a local bound by `fun`, and its function
+    known_callee: Option<(usize, Gc<ObjFunction>)>,
+    /// Index of every constant added to the chunk that can be shared
+    constants: HashMap<ConstantKey, usize>,
+}
+
+/// Constants that are reused when the same value is needed again. Ints are compared by value and
+/// numbers by their bits, so `0` and `-0` get their own constants, strings by identity since
+/// they're interned
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Nil,
+    Bool(bool),
+    Number(u64),
+    Int(i32),
+    String(*mut ObjString),
+}
+
+impl ConstantKey {
+    /// `None` for functions, every closure gets its own constant
+    fn new(value: Value) -> Option<Self> {
+        match value.unpack() {
+            Unpacked::Nil => Some(Self::Nil),
+            Unpacked::Bool(bool) => Some(Self::Bool(bool)),
+            Unpacked::Number(number) => Some(Self::Number(number.to_bits())),
+            Unpacked::Int(int) => Some(Self::Int(int)),
+            Unpacked::Obj(_) => value
+                .as_obj_str()
+                .map(|string| Self::String(string.as_ptr())),
+            Unpacked::Handle(_) => unreachable!("constants aren't handed to the host"),
+        }
+    }
 }
 
 impl<'src> Compiler<'src> {
     const UNINTIALIZED_LOCAL: MaybeUninit<Local<'src>> = MaybeUninit::uninit();
     const UNINTIALIZED_UPVALUE: MaybeUninit<Upvalue> = MaybeUninit::uninit();
 
-    pub fn new(
-        function_kind: FunctionKindT<Token>,
+    pub(crate) fn new(
+        function_kind: FunctionKind,
+        function: Gc<ObjFunction>,
         class_compiler: Option<Box<ClassCompiler>>,
-        mem: &mut Mem,
     ) -> Self {
-        let (function_name, function_kind) = match function_kind {
-            FunctionKindT::Function(prev_tok) => (
-                mem.copy_string(prev_tok.msg).as_ptr(),
-                FunctionKind::Function,
-            ),
-            FunctionKindT::Method(prev_tok) => {
-                (mem.copy_string(prev_tok.msg).as_ptr(), FunctionKind::Method)
-            }
-            FunctionKindT::Script => (null_mut(), FunctionKind::Script),
-            FunctionKindT::Initializer => (null_mut(), FunctionKind::Initializer),
-        };
-
-        let function = mem.alloc_obj(ObjFunction::new(function_name));
-
         let mut this = Self {
             enclosing: None,
             class_compiler,
@@ -186,6 +270,11 @@ impl<'src> Compiler<'src> {
             },
             scope_depth: 0,
             upvalues: [Self::UNINTIALIZED_UPVALUE; u8::MAX as usize],
+            loops: vec![],
+            handlers: vec![],
+            last_call: None,
+            known_callee: None,
+            constants: HashMap::new(),
         };
 
         // Safety:
@@ -194,10 +283,13 @@ impl<'src> Compiler<'src> {
         unsafe {
             let mut local_ptr = this.locals.stack[0].as_mut_ptr();
             (*local_ptr).is_captured = false;
+            (*local_ptr).is_const = false;
+            (*local_ptr).function = None;
             (*local_ptr).depth = Some(0);
             (*local_ptr).name = Token {
                 kind: TokenKind::Nil,
                 line: 0,
+                column: 0,
                 msg: if function_kind != FunctionKind::Function {
                     "this"
                 } else {
@@ -206,6 +298,16 @@ impl<'src> Compiler<'src> {
             };
         }
         this.locals.count += 1;
+        if matches!(
+            function_kind,
+            FunctionKind::Method
+                | FunctionKind::Initializer
+                | FunctionKind::Getter
+                | FunctionKind::Setter
+                | FunctionKind::FieldInitializer
+        ) {
+            this.current_chunk_mut().debug.add_local("this", 0, 0);
+        }
 
         this
     }
@@ -314,6 +416,44 @@ impl<'src> Compiler<'src> {
 
         None
     }
+
+    /// Whether the local or upvalue `name` resolves to was declared with `const`, `None` if
+    /// it's a global
+    fn local_is_const(&self, name: Token) -> Option<bool> {
+        let local = self
+            .locals
+            .stack
+            .iter()
+            .take(self.locals.count as usize)
+            .map(|local| unsafe { local.assume_init_ref() })
+            .rfind(|local| local.name.msg == name.msg);
+
+        match local {
+            Some(local) => Some(local.is_const),
+            None => self.enclosing.as_ref()?.local_is_const(name),
+        }
+    }
+
+    /// Something is assigned to the local or upvalue `name` resolves to, so it can't be
+    /// assumed to hold the function it was declared with anymore
+    fn forget_function(&mut self, name: Token) {
+        let local = self
+            .locals
+            .stack
+            .iter_mut()
+            .take(self.locals.count as usize)
+            .map(|local| unsafe { local.assume_init_mut() })
+            .rfind(|local| local.name.msg == name.msg);
+
+        match local {
+            Some(local) => local.function = None,
+            None => {
+                if let Some(enclosing) = &mut self.enclosing {
+                    enclosing.forget_function(name)
+                }
+            }
+        }
+    }
 }
 
 pub struct Parser<'a, 'src> {
@@ -325,20 +465,39 @@ pub struct Parser<'a, 'src> {
     cur: MaybeUninit<Token<'src>>,
     prev: MaybeUninit<Token<'src>>,
 
-    had_error: bool,
+    errors: Vec<CompileError>,
     panic_mode: bool,
+
+    /// When set, a top-level expression at the very end of the source that is missing its
+    /// semicolon becomes the script's return value (used for embedding and the REPL)
+    implicit_return: bool,
+    /// Set when compiling a module, the script ends with `EndModule` instead of returning nil
+    module: bool,
+    /// Give each iteration of a `for` loop its own loop variable, see
+    /// [`VM::per_iteration_loops`](crate::VM::per_iteration_loops)
+    per_iteration_loops: bool,
+    /// Objects of the caller that have to survive collections while compiling, e.g. the VM's
+    /// when a module is compiled while the importing script runs
+    roots: Vec<Gc<Obj>>,
+    /// Assignments to globals that weren't constants yet, see
+    /// [`Parser::check_global_assignments`]
+    global_assignments: Vec<Token<'src>>,
 }
 
 impl<'a, 'src: 'a> Parser<'a, 'src> {
-    pub const PARSE_RULES: [ParseRule<'a, 'src>; 40] = [
+    pub const PARSE_RULES: [ParseRule<'a, 'src>; 78] = [
         // left paren
         parse_rule!(pre = Parser::grouping, inf = Parser::call, Precedence::Call),
         // right paren
         none_prec!(),
         // left brace
-        none_prec!(),
+        parse_rule!(pre = Parser::map, Precedence::None),
         // right brace
         none_prec!(),
+        // left bracket
+        parse_rule!(pre = Parser::list, inf = Parser::index, Precedence::Call),
+        // right bracket
+        none_prec!(),
         // comma
         none_prec!(),
         // dot
@@ -353,12 +512,26 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         parse_rule!(inf = Parser::binary, Precedence::Factor),
         // star
         parse_rule!(inf = Parser::binary, Precedence::Factor),
+        // colon
+        none_prec!(),
+        // question
+        parse_rule!(inf = Parser::conditional, Precedence::Conditional),
+        // percent
+        parse_rule!(inf = Parser::binary, Precedence::Factor),
+        // ampersand
+        parse_rule!(inf = Parser::binary, Precedence::BitAnd),
+        // pipe
+        parse_rule!(inf = Parser::binary, Precedence::BitOr),
+        // caret
+        parse_rule!(inf = Parser::binary, Precedence::BitXor),
+        // tilde
+        parse_rule!(pre = Parser::unary, Precedence::None),
         // bang
         parse_rule!(pre = Parser::unary, Precedence::None),
         // bangequal
         parse_rule!(inf = Parser::binary, Precedence::Equality),
         // equal
-        parse_rule!(inf = Parser::binary, Precedence::Equality),
+        none_prec!(),
         // equalequal
         parse_rule!(inf = Parser::binary, Precedence::Comparison),
         // greater
@@ -369,26 +542,76 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         parse_rule!(inf = Parser::binary, Precedence::Comparison),
         // lessequal
         parse_rule!(inf = Parser::binary, Precedence::Comparison),
+        // starstar
+        parse_rule!(inf = Parser::exponent, Precedence::Exponent),
+        // tildeslash
+        parse_rule!(inf = Parser::binary, Precedence::Factor),
+        // plusequal
+        none_prec!(),
+        // minusequal
+        none_prec!(),
+        // starequal
+        none_prec!(),
+        // slashequal
+        none_prec!(),
+        // plusplus
+        parse_rule!(pre = Parser::prefix_increment, Precedence::None),
+        // minusminus
+        parse_rule!(pre = Parser::prefix_increment, Precedence::None),
+        // lessless
+        parse_rule!(inf = Parser::binary, Precedence::Shift),
+        // greatergreater
+        parse_rule!(inf = Parser::binary, Precedence::Shift),
+        // dotdotdot
+        none_prec!(),
         // identifier
         parse_rule!(pre = Parser::variable, Precedence::None),
         // string
         parse_rule!(pre = Parser::string, Precedence::None),
+        // interpolation
+        parse_rule!(pre = Parser::interpolation, Precedence::None),
+        // interpolation middle
+        none_prec!(),
+        // interpolation end
+        none_prec!(),
         // number
         parse_rule!(pre = Parser::number, Precedence::None),
         // and
         parse_rule!(inf = Parser::and, Precedence::And),
+        // assert
+        none_prec!(),
+        // await
+        parse_rule!(pre = Parser::unary, Precedence::None),
+        // break
+        none_prec!(),
+        // case
+        none_prec!(),
+        // catch
+        none_prec!(),
         // class
         none_prec!(),
+        // const
+        none_prec!(),
+        // continue
+        none_prec!(),
+        // default
+        none_prec!(),
         // else
         none_prec!(),
         // false
         parse_rule!(pre = Parser::literal, Precedence::None),
+        // finally
+        none_prec!(),
         // for
         none_prec!(),
         // fun
-        none_prec!(),
+        parse_rule!(pre = Parser::lambda, Precedence::None),
         // if
         none_prec!(),
+        // import
+        none_prec!(),
+        // in
+        none_prec!(),
         // nil
         parse_rule!(pre = Parser::literal, Precedence::None),
         // or
@@ -399,14 +622,22 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         none_prec!(),
         // super
         parse_rule!(pre = Parser::super_, Precedence::None),
+        // switch
+        none_prec!(),
         // this
         parse_rule!(pre = Parser::this, Precedence::None),
+        // throw
+        none_prec!(),
         // true
         parse_rule!(pre = Parser::literal, Precedence::None),
+        // try
+        none_prec!(),
         // var
         none_prec!(),
         // while
         none_prec!(),
+        // yield
+        none_prec!(),
         // error
         none_prec!(),
         // eof
@@ -415,7 +646,8 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
 
     pub fn new(src: &'src str, mem: &'a mut Mem) -> Self {
         let scanner = Scanner::new(src);
-        let compiler = Box::new(Compiler::new(FunctionKindT::Script, None, mem));
+        let function = mem.alloc_obj(ObjFunction::new(None));
+        let compiler = Box::new(Compiler::new(FunctionKind::Script, function, None));
 
         Self {
             compiler,
@@ -423,11 +655,38 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
             scanner,
             cur: MaybeUninit::uninit(),
             prev: MaybeUninit::uninit(),
-            had_error: false,
+            errors: vec![],
             panic_mode: false,
+            implicit_return: false,
+            per_iteration_loops: false,
+            module: false,
+            roots: vec![],
+            global_assignments: vec![],
         }
     }
 
+    pub fn implicit_return(mut self, implicit_return: bool) -> Self {
+        self.implicit_return = implicit_return;
+        self
+    }
+
+    /// Compile a module, its script pushes the module when it's done, see [`Opcode::EndModule`]
+    pub fn module(mut self, module: bool) -> Self {
+        self.module = module;
+        self
+    }
+
+    pub fn per_iteration_loops(mut self, per_iteration_loops: bool) -> Self {
+        self.per_iteration_loops = per_iteration_loops;
+        self
+    }
+
+    /// Keep `roots` alive through the collections triggered while compiling
+    pub(crate) fn roots(mut self, roots: Vec<Gc<Obj>>) -> Self {
+        self.roots = roots;
+        self
+    }
+
     #[inline]
     fn cur(&self) -> Token<'src> {
         unsafe { self.cur.assume_init() }
@@ -438,15 +697,24 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         unsafe { self.prev.assume_init() }
     }
 
-    pub fn compile(&mut self) -> bool {
+    /// Compile the whole source into `self.compiler.function`.
+    ///
+    /// The parser recovers at statement boundaries after an error, so every error in the
+    /// source is reported, not just the first one
+    pub fn compile(&mut self) -> Result<(), Vec<CompileError>> {
         self.advance();
 
         while !self.match_tok(TokenKind::Eof) {
             self.declaration();
         }
+        self.check_global_assignments();
 
         self.end();
-        !self.had_error
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(std::mem::take(&mut self.errors))
+        }
     }
 
     fn synchronize(&mut self) {
@@ -459,7 +727,8 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
 
             use TokenKind::*;
             match self.cur().kind {
-                Class | Fun | Var | For | If | While | Print | Return => return,
+                Assert | Class | Const | Fun | Var | For | If | Import | While | Print | Return
+                | Switch | Throw | Try | Yield => return,
                 _ => (),
             }
 
@@ -487,12 +756,47 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         ret
     }
 
+    /// Allocate an object, collecting garbage first if needed
+    fn alloc_obj<T: ObjPunnable>(&mut self, obj: T) -> Gc<T> {
+        if self.mem.should_run_gc::<T>() {
+            self.collect_garbage();
+        }
+
+        self.mem.alloc_obj(obj)
+    }
+
+    fn copy_string(&mut self, string: &str) -> Gc<ObjString> {
+        if self.mem.should_run_gc::<ObjString>() {
+            self.collect_garbage();
+        }
+
+        self.mem.copy_constant(string)
+    }
+
+    /// The roots while compiling are the functions of every compiler in the chain,
+    /// everything else they reference is reachable from their constant tables
+    fn collect_garbage(&mut self) {
+        let mut greystack = std::mem::take(&mut self.mem.grey_stack);
+        for &root in &self.roots {
+            Obj::mark(root, &mut greystack);
+        }
+
+        let mut compiler = Some(&self.compiler);
+        while let Some(current) = compiler {
+            Obj::mark(current.function.upcast(), &mut greystack);
+            compiler = current.enclosing.as_ref();
+        }
+
+        self.mem.collect_garbage(greystack);
+    }
+
     fn get_rule(kind: TokenKind) -> &'a ParseRule<'a, 'src> {
         &Self::PARSE_RULES[kind as u8 as usize]
     }
 
-    fn named_variable(&mut self, name: Token, ctx: ParseRuleCtx) {
-        let (arg, get_op, set_op) = match self.resolve_local(name) {
+    /// Returns the operand and the get and set instructions for the variable `name`
+    fn resolve_variable(&mut self, name: Token) -> (u8, u8, u8) {
+        match self.resolve_local(name) {
             Some(arg) => (arg, Opcode::GetLocal as u8, Opcode::SetLocal as u8),
             None => self
                 .resolve_upvalue(name)
@@ -504,23 +808,139 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
                         Opcode::SetGlobal as u8,
                     )
                 }),
-        };
+        }
+    }
+
+    fn is_const(&mut self, name: Token) -> bool {
+        match self.compiler.local_is_const(name) {
+            Some(is_const) => is_const,
+            None => {
+                let name = self.copy_string(name.msg);
+                self.mem.const_globals.get(name).is_some()
+            }
+        }
+    }
+
+    fn check_assignable(&mut self, name: Token<'src>) {
+        if self.is_const(name) {
+            self.error_at(name, "Can't assign to a constant.");
+        } else if self.compiler.local_is_const(name).is_none() {
+            self.global_assignments.push(name);
+        }
+        self.compiler.forget_function(name);
+    }
+
+    /// Functions can assign to a global that's declared as a constant further down the script,
+    /// so the assignments are checked again once all of it is compiled. Constants declared in a
+    /// later script (the next REPL input or a module) are still unknown, the VM checks for those
+    /// when the assignment runs
+    fn check_global_assignments(&mut self) {
+        for name in std::mem::take(&mut self.global_assignments) {
+            let name_str = self.copy_string(name.msg);
+            if self.mem.const_globals.get(name_str).is_none() {
+                continue;
+            }
+            // In source order with the other errors
+            let error = CompileError::new(&self.scanner, name, "Can't assign to a constant.");
+            let at = self
+                .errors
+                .partition_point(|other| (other.line, other.column) <= (error.line, error.column));
+            self.errors.insert(at, error);
+        }
+    }
+
+    fn named_variable(&mut self, name: Token<'src>, ctx: ParseRuleCtx) {
+        let (arg, get_op, set_op) = self.resolve_variable(name);
 
         if ctx.can_assign && self.match_tok(TokenKind::Equal) {
+            self.check_assignable(name);
+            self.expression();
+            self.emit_bytes(set_op, arg);
+        } else if ctx.can_assign && let Some(op) = self.match_compound_assignment() {
+            // `a += b` is compiled as `a = a + b`
+            self.check_assignable(name);
+            self.emit_bytes(get_op, arg);
             self.expression();
+            self.emit_byte(op as u8);
             self.emit_bytes(set_op, arg);
+        } else if let Some(op) = self.match_increment() {
+            // Postfix, the old value is left on the stack. Unlike assignment this binds
+            // tighter than any operator, so `a + b++` works
+            self.check_assignable(name);
+            self.emit_bytes(get_op, arg);
+            self.emit_byte(Opcode::Dup as u8);
+            self.emit_constant(Value::Int(1));
+            self.emit_byte(op as u8);
+            self.emit_bytes(set_op, arg);
+            self.emit_byte(Opcode::Pop as u8);
         } else {
+            if get_op == Opcode::GetLocal as u8
+                && let Some(function) = unsafe { self.compiler.locals.stack[arg as usize].assume_init_ref() }.function
+            {
+                let offset = self.compiler.current_chunk().len();
+                self.compiler.known_callee = Some((offset, function));
+            }
             self.emit_bytes(get_op, arg);
         }
     }
 
+    /// `++x` and `--x`, evaluates to the new value
+    fn prefix_increment(&mut self, _ctx: ParseRuleCtx) {
+        let op = if self.prev().kind == TokenKind::PlusPlus {
+            Opcode::Add
+        } else {
+            Opcode::Subtract
+        };
+        self.consume(
+            TokenKind::Identifier,
+            "Expect variable name after increment or decrement.",
+        );
+
+        let name = self.prev();
+        self.check_assignable(name);
+        let (arg, get_op, set_op) = self.resolve_variable(name);
+        self.emit_bytes(get_op, arg);
+        self.emit_constant(Value::Int(1));
+        self.emit_byte(op as u8);
+        self.emit_bytes(set_op, arg);
+    }
+
+    /// Consume a postfix `++` or `--` and return the matching binary operation
+    fn match_increment(&mut self) -> Option<Opcode> {
+        let op = match self.cur().kind {
+            TokenKind::PlusPlus => Opcode::Add,
+            TokenKind::MinusMinus => Opcode::Subtract,
+            _ => return None,
+        };
+        self.advance();
+        Some(op)
+    }
+
+    /// Consume a `+=`, `-=`, `*=` or `/=` and return the matching binary operation
+    fn match_compound_assignment(&mut self) -> Option<Opcode> {
+        let op = match self.cur().kind {
+            TokenKind::PlusEqual => Opcode::Add,
+            TokenKind::MinusEqual => Opcode::Subtract,
+            TokenKind::StarEqual => Opcode::Multiply,
+            TokenKind::SlashEqual => Opcode::Divide,
+            _ => return None,
+        };
+        self.advance();
+        Some(op)
+    }
+
     fn declaration(&mut self) {
         if self.match_tok(TokenKind::Class) {
             self.class_declaration()
-        } else if self.match_tok(TokenKind::Fun) {
+        } else if self.check(TokenKind::Fun) && self.peek_next_kind() != TokenKind::LeftParen {
+            self.advance();
             self.fn_declaration();
         } else if self.match_tok(TokenKind::Var) {
             self.var_declaration();
+        } else if self.match_tok(TokenKind::Const) {
+            self.const_declaration();
+        } else if self.match_tok(TokenKind::Import) {
+            self.import_declaration();
         } else {
             self.statement();
         }
@@ -545,7 +965,10 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
 
         if self.match_tok(TokenKind::Less) {
             self.consume(TokenKind::Identifier, "Expect superclass name.");
-            self.variable(ParseRuleCtx { can_assign: false });
+            self.variable(ParseRuleCtx {
+                can_assign: false,
+                can_concat: false,
+            });
 
             if class_name.msg == self.prev().msg {
                 self.error("A class can't inherit from itself.");
@@ -555,7 +978,13 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
             self.add_local(&Token::synthetic("super"));
             self.define_variable(0);
 
-            self.named_variable(class_name, ParseRuleCtx { can_assign: false });
+            self.named_variable(
+                class_name,
+                ParseRuleCtx {
+                    can_assign: false,
+                    can_concat: false,
+                },
+            );
             self.emit_byte(Opcode::Inherit as u8);
             self.compiler
                 .class_compiler
@@ -564,11 +993,17 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
                 .has_superclass = true;
         }
 
-        self.named_variable(class_name, ParseRuleCtx { can_assign: false });
+        self.named_variable(
+            class_name,
+            ParseRuleCtx {
+                can_assign: false,
+                can_concat: false,
+            },
+        );
 
         self.consume(TokenKind::LeftBrace, "Expect '{' before class body.");
         while !self.check(TokenKind::RightBrace) && !self.check(TokenKind::Eof) {
-            self.method();
+            self.class_member();
         }
         self.consume(TokenKind::RightBrace, "Expect '}' after class body.");
         self.emit_byte(Opcode::Pop as u8);
@@ -589,6 +1024,76 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
             .and_then(|cc| cc.enclosing);
     }
 
+    /// A method, an accessor or a field declaration, possibly `static`
+    fn class_member(&mut self) {
+        let accessor = self.check_word("get") || self.check_word("set");
+        let next = self.peek_next_kind();
+        if self.check_word("static") && matches!(next, TokenKind::Identifier | TokenKind::Var) {
+            self.advance();
+            if self.match_tok(TokenKind::Var) {
+                self.field(Opcode::StaticField);
+            } else {
+                self.consume(TokenKind::Identifier, "Expect method name.");
+                let constant = self.identifier_constant(self.prev());
+                // Bound to the class, so `this` is the class
+                self.function(FunctionKind::Method, self.prev().msg);
+                self.emit_bytes(Opcode::StaticMethod as u8, constant);
+            }
+        } else if self.match_tok(TokenKind::Var) {
+            self.field(Opcode::Field);
+        } else if accessor && next == TokenKind::Identifier {
+            self.accessor();
+        } else {
+            self.method();
+        }
+    }
+
+    /// `var name = value;` in a class body. The initializer is compiled into a function that
+    /// runs for each new instance, with `this` bound to it. `op` is `StaticField` for
+    /// `static var`, which sets a field of the class once, when the class is declared
+    fn field(&mut self, op: Opcode) {
+        self.consume(TokenKind::Identifier, "Expect field name.");
+        let name = self.prev();
+        let constant = self.identifier_constant(name);
+
+        if self.match_tok(TokenKind::Equal) {
+            if op == Opcode::Field {
+                self.function(FunctionKind::FieldInitializer, name.msg);
+            } else {
+                self.set_in_static_field(true);
+                self.expression();
+                self.set_in_static_field(false);
+            }
+        } else {
+            self.emit_byte(Opcode::Nil as u8);
+        }
+        self.consume(TokenKind::Semicolon, "Expect ';' after field declaration.");
+        self.emit_bytes(op as u8, constant);
+    }
+
+    fn set_in_static_field(&mut self, in_static_field: bool) {
+        if let Some(class_compiler) = self.compiler.class_compiler.as_mut() {
+            class_compiler.in_static_field = in_static_field;
+        }
+    }
+
+    /// `get name { ... }` runs when `name` is read from an instance that has no such field,
+    /// `set name(value) { ... }` when it is assigned
+    fn accessor(&mut self) {
+        let getter = self.check_word("get");
+        self.advance();
+        self.consume(TokenKind::Identifier, "Expect property name.");
+        let constant = self.identifier_constant(self.prev());
+
+        if getter {
+            self.function(FunctionKind::Getter, self.prev().msg);
+            self.emit_bytes(Opcode::Getter as u8, constant);
+        } else {
+            self.function(FunctionKind::Setter, self.prev().msg);
+            self.emit_bytes(Opcode::Setter as u8, constant);
+        }
+    }
+
     fn method(&mut self) {
         self.consume(TokenKind::Identifier, "Expect method name.");
         let constant = self.identifier_constant(self.prev());
@@ -597,17 +1102,26 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         if self.prev().msg == "init" {
             kind = FunctionKind::Initializer;
         }
-        self.function(kind);
+        self.function(kind, self.prev().msg);
         self.emit_bytes(Opcode::Method as u8, constant);
     }
 
     fn fn_declaration(&mut self) {
         let global = self.parse_variable("Expect function name.");
         self.mark_initialized();
-        self.function(FunctionKind::Function);
+        let function = self.function(FunctionKind::Function, self.prev().msg);
+        if self.compiler.scope_depth > 0 {
+            let slot = self.compiler.locals.count as usize - 1;
+            unsafe { self.compiler.locals.stack[slot].assume_init_mut() }.function = Some(function);
+        }
         self.define_variable(global);
     }
 
+    /// `fun (a, b) { return a + b; }`, an anonymous function as an expression
+    fn lambda(&mut self, _ctx: ParseRuleCtx) {
+        self.function(FunctionKind::Function, "lambda");
+    }
+
     fn and(&mut self, _ctx: ParseRuleCtx) {
         let end_jump = self.emit_jump(Opcode::JumpIfFalse as u8);
         self.emit_byte(Opcode::Pop as u8);
@@ -615,8 +1129,27 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         self.patch_jump(end_jump);
     }
 
+    /// `condition ? then : else`, only one of the branches is evaluated
+    fn conditional(&mut self, _ctx: ParseRuleCtx) {
+        let else_jump = self.emit_jump(Opcode::JumpIfFalse as u8);
+        self.emit_byte(Opcode::Pop as u8);
+        self.expression();
+        self.consume(
+            TokenKind::Colon,
+            "Expect ':' after then branch of conditional expression.",
+        );
+
+        let end_jump = self.emit_jump(Opcode::Jump as u8);
+        self.patch_jump(else_jump);
+        self.emit_byte(Opcode::Pop as u8);
+        // Right-associative: `a ? b : c ? d : e` is `a ? b : (c ? d : e)`
+        self.parse_precedence(Precedence::Conditional);
+
+        self.patch_jump(end_jump);
+    }
+
     fn or(&mut self, _ctx: ParseRuleCtx) {
-        let else_jump = self.emit_jump(Opcode::Jump as u8);
+        let else_jump = self.emit_jump(Opcode::JumpIfFalse as u8);
         let end_jump = self.emit_jump(Opcode::Jump as u8);
 
         self.patch_jump(else_jump);
@@ -628,17 +1161,104 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
     }
 
     fn number(&mut self, _ctx: ParseRuleCtx) {
-        let value: f64 = self.prev().msg.parse().unwrap();
-        self.emit_constant(value.into())
+        let value = parse_number(self.prev().msg);
+        self.emit_constant(Value::number(value))
     }
 
-    fn string(&mut self, _ctx: ParseRuleCtx) {
+    fn string(&mut self, ctx: ParseRuleCtx) {
         let string = self.prev().msg;
-
         // get rid of the quotations
-        let obj_str = self.mem.copy_string(&string[1..string.len() - 1]);
+        let string = &string[1..string.len() - 1];
+
+        if ctx.can_concat && self.check(TokenKind::Plus) {
+            let concat = Concatenation {
+                literal: string.to_owned(),
+                ..Concatenation::default()
+            };
+            self.concatenation(concat, ctx);
+        } else {
+            let obj_str = self.copy_string(string);
+            self.emit_constant(Value::Obj(obj_str.upcast()));
+        }
+    }
+
+    /// `"a ${b} c"` is compiled like `"a " + str(b) + " c"`. The scanner splits the string into
+    /// a token for every part followed by an expression and a final one for the rest. Only the
+    /// first can start an expression, so the others can't be taken for an operand
+    fn interpolation(&mut self, ctx: ParseRuleCtx) {
+        let mut concat = Concatenation::default();
+        loop {
+            let part = self.prev().msg;
+            // get rid of the `"` or `}` before and the `${` after
+            concat.literal.push_str(&part[1..part.len() - 2]);
+            self.concat_operand(&mut concat, Self::expression);
+
+            if !self.match_tok(TokenKind::InterpolationMiddle) {
+                break;
+            }
+        }
+
+        if self.match_tok(TokenKind::InterpolationEnd) {
+            let string = self.prev().msg;
+            concat.literal.push_str(&string[1..string.len() - 1]);
+        } else {
+            self.error_at_current("Expect end of string interpolation.");
+        }
+        self.concatenation(concat, ctx);
+    }
 
-        self.emit_constant(Value::Obj(obj_str.cast()));
+    /// The rest of a `+` chain whose left operand is a string, so every `+` concatenates. The
+    /// operands are converted to strings right after they're evaluated, which is when `Add`
+    /// would convert them, and a single `Concat` joins them all at the end instead of copying
+    /// the string so far at every `+`. Adjacent literals are joined right here
+    fn concatenation(&mut self, mut concat: Concatenation, ctx: ParseRuleCtx) {
+        while ctx.can_concat && self.match_tok(TokenKind::Plus) {
+            let operand_ends =
+                Self::get_rule(self.peek_next_kind()).precedence as u8 <= Precedence::Term as u8;
+            if self.check(TokenKind::String) && operand_ends {
+                self.advance();
+                let string = self.prev().msg;
+                concat.literal.push_str(&string[1..string.len() - 1]);
+            } else {
+                self.concat_operand(&mut concat, |parser| {
+                    parser.parse_precedence(Precedence::Factor)
+                });
+            }
+        }
+
+        if !concat.literal.is_empty() || concat.parts == 0 {
+            self.concat_literal(&mut concat);
+        }
+        if concat.dynamic {
+            self.emit_bytes(Opcode::Concat as u8, concat.parts);
+        }
+    }
+
+    /// Emit an operand of `concat` that isn't a literal
+    fn concat_operand(&mut self, concat: &mut Concatenation, operand: impl FnOnce(&mut Self)) {
+        if !concat.literal.is_empty() {
+            self.concat_literal(concat);
+        }
+        operand(self);
+        self.emit_byte(Opcode::ToString as u8);
+        concat.dynamic = true;
+        self.concat_part(concat);
+    }
+
+    fn concat_literal(&mut self, concat: &mut Concatenation) {
+        let literal = std::mem::take(&mut concat.literal);
+        let obj_str = self.copy_string(&literal);
+        self.emit_constant(Value::Obj(obj_str.upcast()));
+        self.concat_part(concat);
+    }
+
+    fn concat_part(&mut self, concat: &mut Concatenation) {
+        concat.parts += 1;
+        // The operand is a byte, longer chains are joined in steps
+        if concat.parts == u8::MAX {
+            self.emit_bytes(Opcode::Concat as u8, u8::MAX);
+            concat.parts = 1;
+        }
     }
 
     fn literal(&mut self, _ctx: ParseRuleCtx) {
@@ -656,19 +1276,77 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
     }
 
     fn call(&mut self, _ctx: ParseRuleCtx) {
-        let arg_count = self.argument_list();
-        self.emit_bytes(Opcode::Call as u8, arg_count);
+        // Named arguments to a function bound by `fun` are put in place here, anything else
+        // matches them when it's called
+        let callee = self
+            .compiler
+            .known_callee
+            .take()
+            .filter(|&(offset, _)| offset + 2 == self.compiler.current_chunk().len());
+        let (positional, names) = self.named_arguments();
+        let params = match callee {
+            Some((_, function)) if !names.is_empty() => {
+                self.resolve_named_arguments(function, positional, &names)
+            }
+            _ => None,
+        };
+
+        let (arg_count, named) = self.argument_list(params);
+        if named > 0 {
+            self.emit_bytes(Opcode::CallNamed as u8, arg_count);
+            self.emit_byte(named);
+        } else {
+            self.compiler.last_call = Some(self.compiler.current_chunk().len());
+            self.emit_bytes(Opcode::Call as u8, arg_count);
+        }
     }
 
-    fn argument_list(&mut self) -> u8 {
+    /// Compile the arguments of a call, returns the number of positional and named ones. A named
+    /// argument `x: value` pushes its name before its value, for `CallNamed`. If the function's
+    /// `params` are known it goes in the parameter's position instead, with `nil` for the
+    /// optional parameters skipped over, and isn't counted as named
+    fn argument_list(&mut self, params: Option<Gc<ObjFunction>>) -> (u8, u8) {
         let mut arg_count = 0;
+        let mut named = 0;
+        let mut seen_named = false;
         if !self.check(TokenKind::RightParen) {
             loop {
-                self.expression();
-                if arg_count == u8::MAX {
-                    self.error("Can't have more than 255 arguments");
+                if self.check(TokenKind::Identifier) && self.peek_next_kind() == TokenKind::Colon {
+                    self.advance();
+                    let name = self.prev();
+                    self.advance();
+                    seen_named = true;
+
+                    match params.and_then(|function| function.named_param(name.msg)) {
+                        Some(index) => {
+                            while (arg_count as usize) < index {
+                                self.emit_byte(Opcode::Nil as u8);
+                                arg_count += 1;
+                            }
+                            self.expression();
+                            arg_count += 1;
+                        }
+                        None => {
+                            let name = self.identifier_constant(name);
+                            self.emit_bytes(Opcode::Constant as u8, name);
+                            self.expression();
+                            if named == u8::MAX {
+                                self.error("Can't have more than 255 named arguments.");
+                            }
+                            named += 1;
+                        }
+                    }
+                } else {
+                    if seen_named {
+                        self.error_at_current("Positional arguments must come before named ones.");
+                    }
+                    self.expression();
+                    if arg_count == u8::MAX {
+                        self.error("Can't have more than 255 arguments");
+                    }
+                    arg_count += 1;
                 }
-                arg_count += 1;
+
                 if !self.match_tok(TokenKind::Comma) {
                     break;
                 }
@@ -677,26 +1355,201 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
 
         self.consume(TokenKind::RightParen, "Expect ')' after arguments.");
 
-        arg_count
+        (arg_count, named)
     }
 
-    fn dot(&mut self, ctx: ParseRuleCtx) {
-        self.consume(TokenKind::Identifier, "Expect property name after '.'.");
-        let name = self.identifier_constant(self.prev());
+    /// Look ahead through the argument list starting at the current token, returns the number
+    /// of positional arguments before the first named one and the names of the named ones
+    fn named_arguments(&self) -> (usize, Vec<&'src str>) {
+        let mut scanner = self.scanner.clone();
+        let mut token = self.cur();
+        let mut next = scanner.token();
+        let mut positional = 0;
+        let mut names = vec![];
 
-        if ctx.can_assign && self.match_tok(TokenKind::Equal) {
-            self.expression();
-            self.emit_bytes(Opcode::SetProperty as u8, name);
-        } else if self.match_tok(TokenKind::LeftParen) {
-            let arg_count = self.argument_list();
-            self.emit_bytes(Opcode::Invoke as u8, name);
-            self.emit_byte(arg_count);
-        } else {
-            self.emit_bytes(Opcode::GetProperty as u8, name);
-        }
-    }
+        let mut depth = 0;
+        let mut starts_argument = true;
+        loop {
+            if depth == 0 && starts_argument && token.kind != TokenKind::RightParen {
+                if token.kind == TokenKind::Identifier && next.kind == TokenKind::Colon {
+                    names.push(token.msg);
+                } else if names.is_empty() {
+                    positional += 1;
+                }
+            }
 
-    fn unary(&mut self, _ctx: ParseRuleCtx) {
+            match token.kind {
+                TokenKind::LeftParen | TokenKind::LeftBrace | TokenKind::LeftBracket => depth += 1,
+                TokenKind::RightParen | TokenKind::RightBrace | TokenKind::RightBracket
+                    if depth > 0 =>
+                {
+                    depth -= 1
+                }
+                TokenKind::RightParen
+                | TokenKind::RightBrace
+                | TokenKind::RightBracket
+                | TokenKind::Semicolon
+                | TokenKind::Eof => break,
+                _ => (),
+            }
+
+            starts_argument = depth == 0 && token.kind == TokenKind::Comma;
+            token = next;
+            next = scanner.token();
+        }
+
+        (positional, names)
+    }
+
+    /// Check the named arguments to `function` against its parameters. Returns the function if
+    /// they can be put in their positions, which needs them in the order of the parameters as
+    /// arguments are evaluated left to right
+    fn resolve_named_arguments(
+        &mut self,
+        function: Gc<ObjFunction>,
+        positional: usize,
+        names: &[&str],
+    ) -> Option<Gc<ObjFunction>> {
+        let mut given: Vec<_> = (0..function.params.len())
+            .map(|index| index < positional)
+            .collect();
+        let mut in_order = true;
+        for name in names {
+            let Some(index) = function.named_param(name) else {
+                self.error(&format!("No parameter named '{name}'."));
+                return None;
+            };
+            if given[index] {
+                self.error(&format!(
+                    "Got more than one argument for parameter '{name}'."
+                ));
+                return None;
+            }
+            in_order &= given[index + 1..].iter().all(|given| !given);
+            given[index] = true;
+        }
+
+        if let Some(missing) = (0..function.arity as usize).find(|&index| !given[index]) {
+            let message = format!(
+                "Missing argument for parameter '{}'.",
+                function.params[missing].as_str()
+            );
+            self.error(&message);
+            return None;
+        }
+
+        in_order.then_some(function)
+    }
+
+    fn dot(&mut self, ctx: ParseRuleCtx) {
+        self.consume(TokenKind::Identifier, "Expect property name after '.'.");
+        let name = self.identifier_constant(self.prev());
+
+        if ctx.can_assign && self.match_tok(TokenKind::Equal) {
+            self.expression();
+            self.emit_bytes(Opcode::SetProperty as u8, name);
+        } else if ctx.can_assign && let Some(op) = self.match_compound_assignment() {
+            // Keep the instance around for the `SetProperty`
+            self.emit_byte(Opcode::Dup as u8);
+            self.emit_bytes(Opcode::GetProperty as u8, name);
+            self.expression();
+            self.emit_byte(op as u8);
+            self.emit_bytes(Opcode::SetProperty as u8, name);
+        } else if self.match_tok(TokenKind::LeftParen) {
+            if self.named_arguments().1.is_empty() {
+                let (arg_count, _) = self.argument_list(None);
+                self.emit_bytes(Opcode::Invoke as u8, name);
+                self.emit_byte(arg_count);
+            } else {
+                // The method is called like any other value, which matches the names
+                self.emit_bytes(Opcode::GetProperty as u8, name);
+                let (arg_count, named) = self.argument_list(None);
+                self.emit_bytes(Opcode::CallNamed as u8, arg_count);
+                self.emit_byte(named);
+            }
+        } else {
+            self.emit_bytes(Opcode::GetProperty as u8, name);
+        }
+    }
+
+    /// `[a, b, c]`, a trailing comma is allowed
+    fn list(&mut self, _ctx: ParseRuleCtx) {
+        let mut count: usize = 0;
+        while !self.check(TokenKind::RightBracket) {
+            self.expression();
+            if count == u8::MAX as usize {
+                self.error("Can't have more than 255 elements in a list literal.");
+            }
+            count += 1;
+
+            if !self.match_tok(TokenKind::Comma) {
+                break;
+            }
+        }
+
+        self.consume(TokenKind::RightBracket, "Expect ']' after list elements.");
+        self.emit_bytes(Opcode::BuildList as u8, count as u8);
+    }
+
+    /// `{"key": value}`, a trailing comma is allowed. Only parsed in expression position, at
+    /// the start of a statement a brace opens a block
+    fn map(&mut self, _ctx: ParseRuleCtx) {
+        let mut count: usize = 0;
+        while !self.check(TokenKind::RightBrace) {
+            self.expression();
+            self.consume(TokenKind::Colon, "Expect ':' after map key.");
+            self.expression();
+            if count == u8::MAX as usize {
+                self.error("Can't have more than 255 entries in a map literal.");
+            }
+            count += 1;
+
+            if !self.match_tok(TokenKind::Comma) {
+                break;
+            }
+        }
+
+        self.consume(TokenKind::RightBrace, "Expect '}' after map entries.");
+        self.emit_bytes(Opcode::BuildMap as u8, count as u8);
+    }
+
+    /// `target[index]`, `target[index] = value` and slices `target[start:end]`
+    fn index(&mut self, ctx: ParseRuleCtx) {
+        // Either end of a slice can be left out
+        if self.match_tok(TokenKind::Colon) {
+            self.emit_byte(Opcode::Nil as u8);
+            self.slice_end();
+            return;
+        }
+
+        self.expression();
+        if self.match_tok(TokenKind::Colon) {
+            self.slice_end();
+            return;
+        }
+
+        self.consume(TokenKind::RightBracket, "Expect ']' after index.");
+
+        if ctx.can_assign && self.match_tok(TokenKind::Equal) {
+            self.expression();
+            self.emit_byte(Opcode::SetIndex as u8);
+        } else {
+            self.emit_byte(Opcode::GetIndex as u8);
+        }
+    }
+
+    fn slice_end(&mut self) {
+        if self.check(TokenKind::RightBracket) {
+            self.emit_byte(Opcode::Nil as u8);
+        } else {
+            self.expression();
+        }
+
+        self.consume(TokenKind::RightBracket, "Expect ']' after slice.");
+        self.emit_byte(Opcode::Slice as u8);
+    }
+
+    fn unary(&mut self, _ctx: ParseRuleCtx) {
         let op_kind = self.prev().kind;
 
         self.parse_precedence(Precedence::Unary);
@@ -704,6 +1557,8 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         match op_kind {
             TokenKind::Minus => self.emit_byte(Opcode::Negate as u8),
             TokenKind::Bang => self.emit_byte(Opcode::Not as u8),
+            TokenKind::Tilde => self.emit_byte(Opcode::BitNot as u8),
+            TokenKind::Await => self.emit_byte(Opcode::Await as u8),
             _ => (),
         }
     }
@@ -724,50 +1579,57 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
             TokenKind::Minus => self.emit_byte(Opcode::Subtract as u8),
             TokenKind::Star => self.emit_byte(Opcode::Multiply as u8),
             TokenKind::Slash => self.emit_byte(Opcode::Divide as u8),
+            TokenKind::Percent => self.emit_byte(Opcode::Modulo as u8),
+            TokenKind::TildeSlash => self.emit_byte(Opcode::FloorDivide as u8),
+            TokenKind::Ampersand => self.emit_byte(Opcode::BitAnd as u8),
+            TokenKind::Pipe => self.emit_byte(Opcode::BitOr as u8),
+            TokenKind::Caret => self.emit_byte(Opcode::BitXor as u8),
+            TokenKind::LessLess => self.emit_byte(Opcode::ShiftLeft as u8),
+            TokenKind::GreaterGreater => self.emit_byte(Opcode::ShiftRight as u8),
             other => unreachable!("{:?}", other),
         }
     }
 
-    fn function(&mut self, kind: FunctionKind) {
-        let kindt = match kind {
-            FunctionKind::Function => FunctionKindT::Function(self.prev()),
-            FunctionKind::Method => FunctionKindT::Method(self.prev()),
-            FunctionKind::Script => FunctionKindT::Script,
-            FunctionKind::Initializer => FunctionKindT::Initializer,
-        };
+    /// `**` is right-associative and binds tighter than unary minus, so `-2 ** 2` is `-4`
+    fn exponent(&mut self, _ctx: ParseRuleCtx) {
+        self.parse_precedence(Precedence::Exponent);
+        self.emit_byte(Opcode::Power as u8);
+    }
 
+    fn function(&mut self, kind: FunctionKind, name: &'src str) -> Gc<ObjFunction> {
+        let function = self.alloc_obj(ObjFunction::new(None));
         let temp = self.compiler.class_compiler.take();
         let temp_compiler = std::mem::replace(
             &mut self.compiler,
-            Box::new(Compiler::new(kindt, temp, self.mem)),
+            Box::new(Compiler::new(kind, function, temp)),
         );
         self.compiler.enclosing = Some(temp_compiler);
 
-        self.begin_scope();
+        // The function is reachable from the compiler chain now, so it's safe to allocate its name
+        let name = self.copy_string(name);
+        self.compiler.current_fn_mut().name = Some(name);
+        self.compiler.current_fn_mut().line = self.prev().line;
 
-        self.consume(TokenKind::LeftParen, "Expect '(' after function name.");
-        if !self.check(TokenKind::RightParen) {
-            loop {
-                match self.compiler.current_fn().arity.checked_add(1) {
-                    Some(new_arity) => {
-                        self.compiler.current_fn_mut().arity = new_arity;
-                    }
-                    None => {
-                        self.error_at_current("Can't have more than 255 parameters");
-                    }
-                };
+        self.begin_scope();
 
-                let constant = self.parse_variable("Expect parameter name.");
-                self.define_variable(constant);
-                if !self.match_tok(TokenKind::Comma) {
-                    break;
-                }
+        if kind == FunctionKind::FieldInitializer {
+            self.expression();
+            self.emit_byte(Opcode::Return as u8);
+        } else {
+            // Getters have no parameter list
+            if kind != FunctionKind::Getter {
+                self.parameters();
             }
-        }
-        self.consume(TokenKind::RightParen, "Expect ')' after parameters.");
-        self.consume(TokenKind::LeftBrace, "Expect '{' before function body.");
+            let function = self.compiler.current_fn();
+            if kind == FunctionKind::Setter
+                && (function.arity != 1 || function.param_count() != 1)
+            {
+                self.error("A setter takes exactly one parameter.");
+            }
+            self.consume(TokenKind::LeftBrace, "Expect '{' before function body.");
 
-        self.block();
+            self.block();
+        }
 
         self.end();
 
@@ -778,22 +1640,80 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         let temp_compiler = std::mem::replace(&mut self.compiler, temp_compiler);
         self.compiler.class_compiler = temp_class_compiler;
 
-        let val = self.make_constant(Value::Obj(func.cast()));
+        let val = self.make_constant(Value::Obj(func.upcast()));
         self.emit_bytes(Opcode::Closure as u8, val);
 
         let upvalue_count = func.as_ref().upvalue_count;
-        let _func_name = unsafe {
-            func.as_ref()
-                .name
-                .as_ref()
-                .map(|obj| obj.as_str())
-                .unwrap_or("top_level")
-        };
+        let _func_name = func
+            .as_ref()
+            .name
+            .as_ref()
+            .map(|obj| obj.as_str())
+            .unwrap_or("top_level");
         for i in 0..upvalue_count {
             let upvalue = unsafe { temp_compiler.upvalues[i as usize].assume_init() };
             self.emit_byte(if upvalue.is_local { 1 } else { 0 });
             self.emit_byte(upvalue.index);
         }
+
+        func
+    }
+
+    /// `(a, b = 10, ...rest)`. Parameters with a default value have to come after the required
+    /// ones, and a rest parameter last
+    fn parameters(&mut self) {
+        self.consume(TokenKind::LeftParen, "Expect '(' after function name.");
+        if !self.check(TokenKind::RightParen) {
+            loop {
+                if self.compiler.current_fn().param_count() == u8::MAX as usize {
+                    self.error_at_current("Can't have more than 255 parameters");
+                }
+
+                let rest = self.match_tok(TokenKind::DotDotDot);
+                let constant = self.parse_variable("Expect parameter name.");
+                self.define_variable(constant);
+                let name = self.copy_string(self.prev().msg);
+                let mut function = self.compiler.function;
+                function.params.push(name);
+                if rest {
+                    function.rest = true;
+                    if self.check(TokenKind::Comma) {
+                        self.error_at_current("A rest parameter must be the last parameter.");
+                    }
+                    break;
+                } else if self.match_tok(TokenKind::Equal) {
+                    function.optional = function.optional.saturating_add(1);
+                    self.default_value();
+                } else if function.optional > 0 {
+                    self.error("Parameters with a default value must come last.");
+                } else {
+                    function.arity = function.arity.saturating_add(1);
+                }
+
+                if !self.match_tok(TokenKind::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenKind::RightParen, "Expect ')' after parameters.");
+    }
+
+    /// The default value of the parameter just declared, compiled as `if (param == nil) param =
+    /// value;` at the start of the function, calls leave the parameters they don't pass `nil`
+    fn default_value(&mut self) {
+        let slot = self.compiler.locals.count - 1;
+        self.emit_bytes(Opcode::GetLocal as u8, slot);
+        self.emit_byte(Opcode::Nil as u8);
+        self.emit_byte(Opcode::Equal as u8);
+        let passed_jump = self.emit_jump(Opcode::JumpIfFalse as u8);
+        self.emit_byte(Opcode::Pop as u8);
+        self.expression();
+        self.emit_bytes(Opcode::SetLocal as u8, slot);
+        self.emit_byte(Opcode::Pop as u8);
+        let end_jump = self.emit_jump(Opcode::Jump as u8);
+        self.patch_jump(passed_jump);
+        self.emit_byte(Opcode::Pop as u8);
+        self.patch_jump(end_jump);
     }
 
     fn var_declaration(&mut self) {
@@ -813,6 +1733,72 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         self.define_variable(global);
     }
 
+    /// `const x = ...;` must be initialized and can't be assigned to afterwards
+    fn const_declaration(&mut self) {
+        let global = self.parse_variable("Expect constant name.");
+        let name = self.prev();
+
+        self.consume(TokenKind::Equal, "Expect '=' after constant name.");
+        self.expression();
+        self.consume(
+            TokenKind::Semicolon,
+            "Expect ';' after constant declaration.",
+        );
+
+        if self.compiler.scope_depth > 0 {
+            unsafe {
+                self.compiler.locals.stack[self.compiler.locals.count as usize - 1]
+                    .assume_init_mut()
+                    .is_const = true;
+            }
+        } else {
+            let name = self.copy_string(name.msg);
+            self.mem.const_globals.set(name, Value::Bool(true));
+        }
+
+        self.define_variable(global);
+    }
+
+    /// `import "path";` runs the module, `import name from "path";` also binds it to `name`.
+    /// `import native "name";` loads a shared library instead
+    fn import_declaration(&mut self) {
+        let mut global = None;
+        // `native` can still be the name of a module
+        if self.check(TokenKind::Identifier)
+            && (!self.check_word("native") || self.peek_next_kind() == TokenKind::Identifier)
+        {
+            global = Some(self.parse_variable("Expect module name."));
+            if !self.check_word("from") {
+                self.error_at_current("Expect 'from' after module name.");
+            }
+            self.advance();
+        }
+        let native = self.check_word("native");
+        if native {
+            self.advance();
+        }
+
+        self.consume(TokenKind::String, "Expect module path.");
+        if self.prev().kind != TokenKind::String {
+            return;
+        }
+        let path = self.prev().msg;
+        let path = self.copy_string(&path[1..path.len() - 1]);
+        let path = self.make_constant(Value::Obj(path.upcast()));
+        self.consume(TokenKind::Semicolon, "Expect ';' after import.");
+
+        let import = if native {
+            Opcode::ImportNative
+        } else {
+            Opcode::Import
+        };
+        self.emit_bytes(import as u8, path);
+        match global {
+            Some(global) => self.define_variable(global),
+            None => self.emit_byte(Opcode::Pop as u8),
+        }
+    }
+
     fn parse_variable(&mut self, err_msg: &str) -> u8 {
         self.consume(TokenKind::Identifier, err_msg);
 
@@ -821,7 +1807,18 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
             return 0;
         }
 
+        self.identifier_constant(self.prev())
+    }
+
+    fn declare_variable(&mut self) {
         let name = self.prev();
+        if self.compiler.scope_depth == 0 {
+            let name_str = self.copy_string(name.msg);
+            if self.mem.const_globals.get(name_str).is_some() {
+                self.error("Already a constant with this name.");
+            }
+            return;
+        }
 
         let mut had_error = false;
         for local in self
@@ -839,7 +1836,7 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
                 break;
             }
 
-            if name == local.name {
+            if name.msg == local.name.msg {
                 had_error = true;
             }
         }
@@ -848,15 +1845,7 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
             self.error("Already a variable with this name in this scope.");
         }
 
-        self.identifier_constant(name)
-    }
-
-    fn declare_variable(&mut self) {
-        if self.compiler.scope_depth == 0 {
-            return;
-        }
-
-        self.add_local(&self.prev());
+        self.add_local(&name);
     }
 
     fn add_local(&mut self, tok: &Token<'src>) {
@@ -872,6 +1861,8 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
             (*local).name = *tok;
             (*local).depth = None;
             (*local).is_captured = false;
+            (*local).is_const = false;
+            (*local).function = None;
         }
     }
 
@@ -889,15 +1880,21 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
             return;
         }
         let scope_depth = self.compiler.scope_depth;
-        unsafe {
-            self.compiler.locals.stack[self.compiler.locals.count as usize - 1]
-                .assume_init_mut()
-                .depth = Some(scope_depth as u32);
+        let slot = self.compiler.locals.count - 1;
+        let local = unsafe { self.compiler.locals.stack[slot as usize].assume_init_mut() };
+        let declared = local.depth.is_none() && local.name.kind != TokenKind::Synthetic;
+        let name = local.name.msg;
+        local.depth = Some(scope_depth as u32);
+
+        if declared {
+            let chunk = self.compiler.current_chunk_mut();
+            let offset = chunk.len();
+            chunk.debug.add_local(name, slot, offset);
         }
     }
 
     fn identifier_constant(&mut self, name: Token) -> u8 {
-        let constant = Value::Obj(self.mem.copy_string(name.msg).cast());
+        let constant = Value::Obj(self.copy_string(name.msg).upcast());
         self.make_constant(constant)
     }
 
@@ -916,6 +1913,20 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
             self.return_statement();
         } else if self.match_tok(TokenKind::While) {
             self.while_statement();
+        } else if self.match_tok(TokenKind::Switch) {
+            self.switch_statement();
+        } else if self.match_tok(TokenKind::Break) {
+            self.break_statement();
+        } else if self.match_tok(TokenKind::Continue) {
+            self.continue_statement();
+        } else if self.match_tok(TokenKind::Throw) {
+            self.throw_statement();
+        } else if self.match_tok(TokenKind::Yield) {
+            self.yield_statement();
+        } else if self.match_tok(TokenKind::Try) {
+            self.try_statement();
+        } else if self.match_tok(TokenKind::Assert) {
+            self.assert_statement();
         } else {
             self.expression_statement();
         }
@@ -937,17 +1948,35 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         self.begin_scope();
 
         self.consume(TokenKind::LeftParen, "Expect '(' after 'for'.");
+        if self.is_for_in() {
+            self.for_in_statement();
+            self.end_scope();
+            return;
+        }
 
         // Handle the initializer caluse
+        let mut loop_variable = None;
         if self.match_tok(TokenKind::Semicolon) {
             // No initializer
         } else if self.match_tok(TokenKind::Var) {
             self.var_declaration();
+            if self.per_iteration_loops {
+                loop_variable = Some(self.compiler.locals.count - 1);
+            }
         } else {
             self.expression_statement();
         }
 
         let mut loop_start = self.compiler.current_chunk().len();
+        // With `per_iteration_loops` each iteration gets its own loop variable: closures that
+        // captured it keep the value from the end of their iteration, the next one starts with a
+        // fresh upvalue. This runs before the increment, so `continue` goes through it too
+        let close_loop_variable = |this: &mut Self| {
+            if let Some(slot) = loop_variable {
+                this.emit_bytes(Opcode::CloseLocal as u8, slot);
+            }
+        };
+        close_loop_variable(self);
 
         // Handle the loop condition
         let exit_jump = if !self.match_tok(TokenKind::Semicolon) {
@@ -967,6 +1996,7 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         if !self.match_tok(TokenKind::RightParen) {
             let body_jump = self.emit_jump(Opcode::Jump as u8);
             let increment_start = self.compiler.current_chunk().len();
+            close_loop_variable(self);
 
             self.expression();
             // discard value from increment caluse
@@ -979,6 +2009,7 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
             self.patch_jump(body_jump);
         }
 
+        self.begin_loop(loop_start);
         self.statement();
         self.emit_loop(loop_start);
 
@@ -986,8 +2017,63 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
             self.patch_jump(exit_jump);
             self.emit_byte(Opcode::Pop as u8);
         }
+        self.end_loop();
+
+        self.end_scope();
+    }
+
+    /// Whether the clauses are `item in collection` or `var item in collection`
+    fn is_for_in(&self) -> bool {
+        let mut scanner = self.scanner.clone();
+        let mut next = scanner.token().kind;
+        if self.check(TokenKind::Var) {
+            if next != TokenKind::Identifier {
+                return false;
+            }
+            next = scanner.token().kind;
+        } else if !self.check(TokenKind::Identifier) {
+            return false;
+        }
+
+        next == TokenKind::In
+    }
+
+    /// `for (item in collection) body`. The collection and a cursor into it live in hidden
+    /// locals, `ForIter` pushes the next item or jumps out of the loop when there is none.
+    /// The item is a fresh local in every iteration
+    fn for_in_statement(&mut self) {
+        self.match_tok(TokenKind::Var);
+        self.consume(TokenKind::Identifier, "Expect loop variable name.");
+        let item = self.prev();
+        self.consume(TokenKind::In, "Expect 'in' after loop variable.");
 
+        self.expression();
+        self.consume(TokenKind::RightParen, "Expect ')' after collection.");
+        self.emit_byte(Opcode::GetIter as u8);
+        self.add_local(&Token::synthetic("for collection"));
+        self.mark_initialized();
+        let collection_slot = self.compiler.locals.count - 1;
+
+        let cursor = self.make_constant(Value::Int(0));
+        self.emit_bytes(Opcode::Constant as u8, cursor);
+        self.add_local(&Token::synthetic("for cursor"));
+        self.mark_initialized();
+
+        let loop_start = self.compiler.current_chunk().len();
+        self.emit_bytes(Opcode::ForIter as u8, collection_slot);
+        self.emit_bytes(0xff, 0xff);
+        let exit_jump = self.compiler.current_chunk().len() as u32 - 2;
+
+        self.begin_loop(loop_start);
+        self.begin_scope();
+        self.add_local(&item);
+        self.mark_initialized();
+        self.statement();
         self.end_scope();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.end_loop();
     }
 
     fn while_statement(&mut self) {
@@ -999,11 +2085,300 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
 
         let exit_jump = self.emit_jump(Opcode::JumpIfFalse as u8);
         self.emit_byte(Opcode::Pop as u8);
+        self.begin_loop(loop_start);
         self.statement();
         self.emit_loop(loop_start);
 
         self.patch_jump(exit_jump);
         self.emit_byte(Opcode::Pop as u8);
+        self.end_loop();
+    }
+
+    /// `switch (value) { case a: ... case b: ... default: ... }`
+    ///
+    /// Compiled to a chain of equality tests, a matching case doesn't fall through to the next
+    fn switch_statement(&mut self) {
+        self.consume(TokenKind::LeftParen, "Expect '(' after 'switch'.");
+
+        // The value is kept in a hidden local so each case can compare against it, and so
+        // locals declared in the cases get the right slots
+        self.begin_scope();
+        self.expression();
+        self.add_local(&Token::synthetic("switch"));
+        self.mark_initialized();
+        let value_slot = self.compiler.locals.count - 1;
+
+        self.consume(TokenKind::RightParen, "Expect ')' after value.");
+        self.consume(TokenKind::LeftBrace, "Expect '{' before switch cases.");
+
+        let mut end_jumps = vec![];
+        let mut has_default = false;
+        while !self.check(TokenKind::RightBrace) && !self.check(TokenKind::Eof) {
+            if self.match_tok(TokenKind::Case) {
+                if has_default {
+                    self.error("Can't have a case after the default case.");
+                }
+
+                self.emit_bytes(Opcode::GetLocal as u8, value_slot);
+                self.expression();
+                self.consume(TokenKind::Colon, "Expect ':' after case value.");
+                self.emit_byte(Opcode::Equal as u8);
+
+                let next_case = self.emit_jump(Opcode::JumpIfFalse as u8);
+                self.emit_byte(Opcode::Pop as u8);
+                self.switch_case_body();
+                end_jumps.push(self.emit_jump(Opcode::Jump as u8));
+
+                self.patch_jump(next_case);
+                self.emit_byte(Opcode::Pop as u8);
+            } else if self.match_tok(TokenKind::Default) {
+                if has_default {
+                    self.error("Can't have more than one default case.");
+                }
+                has_default = true;
+
+                self.consume(TokenKind::Colon, "Expect ':' after 'default'.");
+                self.switch_case_body();
+            } else {
+                self.error_at_current("Expect 'case' or 'default'.");
+                // Skip the offending token so we don't loop forever
+                self.advance();
+            }
+        }
+
+        self.consume(TokenKind::RightBrace, "Expect '}' after switch cases.");
+
+        for jump in end_jumps {
+            self.patch_jump(jump);
+        }
+        self.end_scope();
+    }
+
+    fn switch_case_body(&mut self) {
+        self.begin_scope();
+        while !self.check(TokenKind::Case)
+            && !self.check(TokenKind::Default)
+            && !self.check(TokenKind::RightBrace)
+            && !self.check(TokenKind::Eof)
+        {
+            self.declaration();
+        }
+        self.end_scope();
+    }
+
+    fn begin_loop(&mut self, start: usize) {
+        let scope_depth = self.compiler.scope_depth;
+        let handler_depth = self.compiler.handlers.len();
+        self.compiler.loops.push(Loop {
+            start,
+            scope_depth,
+            breaks: vec![],
+            handler_depth,
+        });
+    }
+
+    /// Must be called after the condition is popped, `break` jumps here
+    fn end_loop(&mut self) {
+        let Some(finished) = self.compiler.loops.pop() else {
+            return;
+        };
+
+        for jump in finished.breaks {
+            self.patch_jump(jump);
+        }
+    }
+
+    fn break_statement(&mut self) {
+        self.consume(TokenKind::Semicolon, "Expect ';' after 'break'.");
+
+        let Some(scope_depth) = self.compiler.loops.last().map(|l| l.scope_depth) else {
+            self.error("Can't use 'break' outside of a loop.");
+            return;
+        };
+
+        self.discard_locals(scope_depth);
+        self.leave_handlers("break");
+        let jump = self.emit_jump(Opcode::Jump as u8);
+        if let Some(current) = self.compiler.loops.last_mut() {
+            current.breaks.push(jump);
+        }
+    }
+
+    fn continue_statement(&mut self) {
+        self.consume(TokenKind::Semicolon, "Expect ';' after 'continue'.");
+
+        let Some((start, scope_depth)) = self.compiler.loops.last().map(|l| (l.start, l.scope_depth)) else {
+            self.error("Can't use 'continue' outside of a loop.");
+            return;
+        };
+
+        self.discard_locals(scope_depth);
+        self.leave_handlers("continue");
+        self.emit_loop(start);
+    }
+
+    /// Pop the exception handlers entered since the start of the innermost loop, for `break`
+    /// and `continue`. Jumping out of a `finally` protected block isn't supported, since the
+    /// `finally` block would have to run first
+    fn leave_handlers(&mut self, keyword: &str) {
+        let Some(handler_depth) = self.compiler.loops.last().map(|l| l.handler_depth) else {
+            return;
+        };
+
+        let handlers = &self.compiler.handlers[handler_depth..];
+        if handlers.contains(&Handler::Finally) {
+            self.error(&format!(
+                "Can't use '{keyword}' to leave a try statement with a 'finally' block."
+            ));
+            return;
+        }
+
+        for _ in 0..handlers.len() {
+            self.emit_byte(Opcode::PopHandler as u8);
+        }
+    }
+
+    /// `yield value;`, `nil` without a value. Makes the function a generator function: calling
+    /// it returns a generator, which runs the body up to the next `yield` each time it's resumed
+    fn yield_statement(&mut self) {
+        match self.compiler.function_kind {
+            FunctionKind::Script => self.error("Can't yield from top-level code."),
+            FunctionKind::Initializer => self.error("Can't yield from an initializer."),
+            FunctionKind::Setter => self.error("Can't yield from a setter."),
+            _ => self.compiler.function.generator = true,
+        }
+
+        if self.match_tok(TokenKind::Semicolon) {
+            self.emit_byte(Opcode::Nil as u8);
+        } else {
+            self.expression();
+            self.consume(TokenKind::Semicolon, "Expect ';' after yield value.");
+        }
+        self.emit_byte(Opcode::Yield as u8);
+    }
+
+    fn throw_statement(&mut self) {
+        self.expression();
+        self.consume(TokenKind::Semicolon, "Expect ';' after thrown value.");
+        self.emit_byte(Opcode::Throw as u8);
+    }
+
+    /// `try { } catch (e) { } finally { }`, either the `catch` or the `finally` block can be
+    /// left out.
+    ///
+    /// The VM jumps to the `catch` block with the exception on the stack. The `finally` block
+    /// starts with two hidden locals, the completion (`FINALLY_NORMAL`, `FINALLY_THROW` or
+    /// `FINALLY_RETURN`) and its value, which `EndFinally` uses to carry on once the block is
+    /// done
+    fn try_statement(&mut self) {
+        let (has_catch, has_finally) = self.try_clauses();
+        if !has_catch && !has_finally {
+            self.error_at_current("Expect 'catch' or 'finally' after try block.");
+        }
+
+        // The handlers are pushed before the try block, but the blocks after it have to be
+        // known first. `try_clauses` looks ahead for them
+        let finally_handler = has_finally.then(|| {
+            self.compiler.handlers.push(Handler::Finally);
+            self.emit_jump(Opcode::PushFinally as u8)
+        });
+        let catch_handler = has_catch.then(|| {
+            self.compiler.handlers.push(Handler::Catch);
+            self.emit_jump(Opcode::PushCatch as u8)
+        });
+
+        self.consume(TokenKind::LeftBrace, "Expect '{' after 'try'.");
+        self.begin_scope();
+        self.block();
+        self.end_scope();
+
+        if let Some(catch_handler) = catch_handler {
+            self.emit_byte(Opcode::PopHandler as u8);
+            self.compiler.handlers.pop();
+            let skip_catch = self.emit_jump(Opcode::Jump as u8);
+
+            self.patch_jump(catch_handler);
+            self.consume(TokenKind::Catch, "Expect 'catch' after try block.");
+            self.consume(TokenKind::LeftParen, "Expect '(' after 'catch'.");
+            self.consume(TokenKind::Identifier, "Expect exception variable name.");
+            let name = self.prev();
+            self.consume(
+                TokenKind::RightParen,
+                "Expect ')' after exception variable.",
+            );
+
+            self.begin_scope();
+            self.add_local(&name);
+            self.mark_initialized();
+            self.consume(TokenKind::LeftBrace, "Expect '{' after catch clause.");
+            self.block();
+            self.end_scope();
+
+            self.patch_jump(skip_catch);
+        }
+
+        if let Some(finally_handler) = finally_handler {
+            self.emit_byte(Opcode::PopHandler as u8);
+            self.compiler.handlers.pop();
+            self.emit_byte(Opcode::Nil as u8);
+            let normal = self.make_constant(Value::Number(FINALLY_NORMAL));
+            self.emit_bytes(Opcode::Constant as u8, normal);
+
+            self.patch_jump(finally_handler);
+            self.consume(TokenKind::Finally, "Expect 'finally' after catch block.");
+
+            self.begin_scope();
+            self.add_local(&Token::synthetic("finally value"));
+            self.mark_initialized();
+            self.add_local(&Token::synthetic("finally completion"));
+            self.mark_initialized();
+
+            self.consume(TokenKind::LeftBrace, "Expect '{' after 'finally'.");
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+
+            // `EndFinally` pops the hidden locals itself
+            self.emit_byte(Opcode::EndFinally as u8);
+            self.compiler.locals.count -= 2;
+            self.compiler.scope_depth -= 1;
+        }
+    }
+
+    /// Whether the try statement starting at the current token has a `catch` and a `finally`
+    /// block, by skipping over the blocks
+    fn try_clauses(&self) -> (bool, bool) {
+        /// Advance `token` past the block it starts
+        fn skip_block<'src>(scanner: &mut Scanner<'src>, token: &mut Token<'src>) {
+            let mut depth = 0;
+            loop {
+                match token.kind {
+                    TokenKind::LeftBrace => depth += 1,
+                    TokenKind::RightBrace => depth -= 1,
+                    TokenKind::Eof => return,
+                    _ => (),
+                }
+                *token = scanner.token();
+                if depth <= 0 {
+                    return;
+                }
+            }
+        }
+
+        let mut scanner = self.scanner.clone();
+        let mut token = self.cur();
+        skip_block(&mut scanner, &mut token);
+
+        let has_catch = token.kind == TokenKind::Catch;
+        if has_catch {
+            // `catch (e)`
+            while !matches!(token.kind, TokenKind::LeftBrace | TokenKind::Eof) {
+                token = scanner.token();
+            }
+            skip_block(&mut scanner, &mut token);
+        }
+
+        (has_catch, token.kind == TokenKind::Finally)
     }
 
     fn return_statement(&mut self) {
@@ -1017,9 +2392,22 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
             if self.compiler.function_kind == FunctionKind::Initializer {
                 self.error("Can't return a value from an initializer.");
             }
+            if self.compiler.function_kind == FunctionKind::Setter {
+                self.error("Can't return a value from a setter.");
+            }
 
             self.expression();
             self.consume(TokenKind::Semicolon, "Expect ';' after return value.");
+            // Nothing is left to do in this frame once the call returns, unless a `catch` or
+            // `finally` block still has to run in it
+            // A value that's a single byte long, like `nil`, can start the chunk
+            let call = self.compiler.current_chunk().len().checked_sub(2);
+            if let Some(call) = call
+                && self.compiler.handlers.is_empty()
+                && self.compiler.last_call == Some(call)
+            {
+                self.compiler.current_chunk_mut().code[call] = Opcode::TailCall as u8;
+            }
             self.emit_byte(Opcode::Return as u8);
         }
     }
@@ -1085,17 +2473,31 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
     fn end_scope(&mut self) {
         self.compiler.scope_depth -= 1;
 
-        while self.compiler.locals.count > 0
+        let discarded = self.discard_locals(self.compiler.scope_depth);
+        self.compiler.locals.count -= discarded;
+        let count = self.compiler.locals.count;
+        let chunk = self.compiler.current_chunk_mut();
+        let offset = chunk.len();
+        chunk.debug.close_locals(count, offset);
+    }
+
+    /// Emit the instructions to pop every local deeper than `scope_depth` off the stack.
+    ///
+    /// The locals stay declared, this is also used to jump out of scopes early. Returns how
+    /// many locals were popped
+    fn discard_locals(&mut self, scope_depth: usize) -> u8 {
+        let mut count = self.compiler.locals.count;
+        while count > 0
             && unsafe {
-                self.compiler.locals.stack[self.compiler.locals.count as usize - 1]
+                self.compiler.locals.stack[count as usize - 1]
                     .assume_init_ref()
                     .depth
                     .map(|val| val as isize)
                     .unwrap_or(-1)
-            } > self.compiler.scope_depth as isize
+            } > scope_depth as isize
         {
             let is_captured = unsafe {
-                self.compiler.locals.stack[self.compiler.locals.count as usize - 1]
+                self.compiler.locals.stack[count as usize - 1]
                     .assume_init_ref()
                     .is_captured
             };
@@ -1104,8 +2506,10 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
             } else {
                 Opcode::Pop as u8
             });
-            self.compiler.locals.count -= 1;
+            count -= 1;
         }
+
+        self.compiler.locals.count - count
     }
 
     fn match_tok(&mut self, kind: TokenKind) -> bool {
@@ -1117,6 +2521,36 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         }
     }
 
+    /// `assert expr;` fails with a runtime error quoting the source of `expr` and its line
+    fn assert_statement(&mut self) {
+        let start = self.cur();
+        self.expression();
+        let source = self.source_between(start, self.prev());
+        let message = format!("Assertion failed at line {}: {source}", start.line);
+        self.consume(TokenKind::Semicolon, "Expect ';' after assertion.");
+
+        let message = self.copy_string(&message);
+        let constant = self.make_constant(Value::Obj(message.upcast()));
+        self.emit_bytes(Opcode::Assert as u8, constant);
+    }
+
+    /// The source text from the start of `first` to the end of `last`
+    fn source_between(&self, first: Token<'src>, last: Token<'src>) -> &'src str {
+        let src = self.scanner.src;
+        // error tokens don't point into the source
+        let offset = |msg: &str| {
+            (msg.as_ptr() as usize)
+                .checked_sub(src.as_ptr() as usize)
+                .filter(|&offset| offset + msg.len() <= src.len())
+        };
+        match (offset(first.msg), offset(last.msg)) {
+            (Some(start), Some(end)) if start <= end => {
+                std::str::from_utf8(&src[start..end + last.msg.len()]).unwrap_or(first.msg)
+            }
+            _ => first.msg,
+        }
+    }
+
     fn print_statement(&mut self) {
         self.expression();
         self.consume(TokenKind::Semicolon, "Expect ';' after value.");
@@ -1125,6 +2559,16 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
 
     fn expression_statement(&mut self) {
         self.expression();
+
+        if self.implicit_return
+            && self.compiler.function_kind == FunctionKind::Script
+            && self.compiler.scope_depth == 0
+            && self.check(TokenKind::Eof)
+        {
+            self.emit_byte(Opcode::Return as u8);
+            return;
+        }
+
         self.consume(TokenKind::Semicolon, "Expect ';' after expression.");
         self.emit_byte(Opcode::Pop as u8)
     }
@@ -1133,34 +2577,17 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         self.cur().kind == kind
     }
 
+    /// Whether the current token is the identifier `word`, for words that are only keywords in
+    /// some places like `from`
+    fn check_word(&self, word: &str) -> bool {
+        self.check(TokenKind::Identifier) && self.cur().msg == word
+    }
+
     fn end(&mut self) {
         self.emit_return();
-        #[cfg(debug_assertions)]
-        {
-            if !self.had_error {
-                unsafe {
-                    let name = self
-                        .compiler
-                        .function
-                        .as_ref()
-                        .name
-                        .as_ref()
-                        .map(|s| s.as_str())
-                        .unwrap_or("script");
-                    println!(
-                        "{} :: {:#?} :: upvalues :: {:#?}",
-                        name,
-                        self.compiler.current_chunk(),
-                        self.compiler
-                            .upvalues
-                            .iter()
-                            .take(self.compiler.current_fn().upvalue_count as usize)
-                            .map(|val| val.assume_init())
-                            .collect::<Vec<_>>()
-                    );
-                }
-            }
-        }
+        let chunk = self.compiler.current_chunk_mut();
+        let offset = chunk.len();
+        chunk.debug.close_locals(0, offset);
     }
 
     /// This is badly named. This function is called if a return statement has no expression,
@@ -1171,6 +2598,11 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         if self.compiler.function_kind == FunctionKind::Initializer {
             // slot 0 contains the instance
             self.emit_bytes(Opcode::GetLocal as u8, 0);
+        } else if self.compiler.function_kind == FunctionKind::Setter {
+            // and slot 1 the value assigned
+            self.emit_bytes(Opcode::GetLocal as u8, 1);
+        } else if self.module && self.compiler.function_kind == FunctionKind::Script {
+            self.emit_byte(Opcode::EndModule as u8);
         } else {
             self.emit_byte(Opcode::Nil as u8);
         }
@@ -1180,7 +2612,7 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
     fn emit_byte(&mut self, byte: u8) {
         self.compiler
             .current_chunk_mut()
-            .write(byte, self.prev().line)
+            .write(byte, self.prev().line, self.prev().column)
     }
 
     fn emit_bytes(&mut self, a: u8, b: u8) {
@@ -1189,18 +2621,42 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
     }
 
     fn emit_constant(&mut self, value: Value) {
-        let constant = self.make_constant(value);
-        self.emit_bytes(Opcode::Constant as u8, constant);
+        let constant_idx = self.add_constant(value);
+        if let Ok(constant) = u8::try_from(constant_idx) {
+            self.emit_bytes(Opcode::Constant as u8, constant);
+        } else if constant_idx < MAX_CONSTANTS {
+            let [_, high, mid, low] = (constant_idx as u32).to_be_bytes();
+            self.emit_bytes(Opcode::ConstantLong as u8, high);
+            self.emit_bytes(mid, low);
+        } else {
+            self.error("Too many constants in one chunk");
+        }
     }
 
-    fn make_constant(&mut self, value: Value) -> u8 {
-        let constant_idx = self.compiler.current_chunk_mut().add_constant(value);
-        if constant_idx >= u8::MAX {
-            self.error("Too many constants in one chunk");
-            return 0;
+    /// Index of `value` in the current chunk's constants, reusing an equal constant if possible
+    fn add_constant(&mut self, value: Value) -> usize {
+        let Some(key) = ConstantKey::new(value) else {
+            return self.compiler.current_chunk_mut().add_constant(value);
+        };
+        if let Some(&index) = self.compiler.constants.get(&key) {
+            return index;
         }
+        let index = self.compiler.current_chunk_mut().add_constant(value);
+        self.compiler.constants.insert(key, index);
+        index
+    }
 
-        constant_idx
+    /// Add a constant for an instruction with a one byte operand, unlike values loaded with
+    /// [`Parser::emit_constant`] these have to be among the first 256 constants of the chunk
+    fn make_constant(&mut self, value: Value) -> u8 {
+        let constant_idx = self.add_constant(value);
+        match u8::try_from(constant_idx) {
+            Ok(constant) => constant,
+            Err(_) => {
+                self.error("Too many constants in one chunk");
+                0
+            }
+        }
     }
 
     fn consume(&mut self, kind: TokenKind, msg: &str) {
@@ -1212,6 +2668,11 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         self.error_at_current(msg)
     }
 
+    /// Kind of the token after the current one, without consuming anything
+    fn peek_next_kind(&self) -> TokenKind {
+        self.scanner.clone().token().kind
+    }
+
     fn advance(&mut self) {
         self.prev = self.cur;
 
@@ -1239,18 +2700,8 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         }
 
         self.panic_mode = true;
-
-        eprint!("[line {}] Error", token.line);
-
-        if token.kind == TokenKind::Eof {
-            eprint!(" at end")
-        } else if token.kind == TokenKind::Error {
-        } else {
-            eprint!(" at {}", token.msg)
-        }
-
-        eprintln!(": {msg}");
-        self.had_error = true;
+        let error = CompileError::new(&self.scanner, token, msg);
+        self.errors.push(error);
     }
 
     fn parse_precedence(&mut self, precedence: Precedence) {
@@ -1265,6 +2716,7 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
 
         let ctx = ParseRuleCtx {
             can_assign: precedence as u8 <= Precedence::Assignment as u8,
+            can_concat: precedence as u8 <= Precedence::Term as u8,
         };
         rule(self, ctx);
 
@@ -1277,7 +2729,11 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
             infix_rule(self, ctx);
         }
 
-        if ctx.can_assign && self.match_tok(TokenKind::Equal) {
+        if ctx.can_assign
+            && (self.match_tok(TokenKind::Equal)
+                || self.match_compound_assignment().is_some()
+                || self.match_increment().is_some())
+        {
             self.error("Invalid assignment target.");
         }
     }
@@ -1291,16 +2747,29 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
     }
 
     fn this(&mut self, _ctx: ParseRuleCtx) {
-        if self.compiler.class_compiler.is_none() {
-            self.error("Can't use 'this' outside of a class.");
-            return;
+        match self.compiler.class_compiler.as_ref() {
+            None => {
+                self.error("Can't use 'this' outside of a class.");
+                return;
+            }
+            Some(class_compiler) if class_compiler.in_static_field => {
+                self.error("Can't use 'this' in a static field initializer.");
+                return;
+            }
+            _ => (),
         }
-        self.variable(ParseRuleCtx { can_assign: false })
+        self.variable(ParseRuleCtx {
+            can_assign: false,
+            can_concat: false,
+        })
     }
 
     fn super_(&mut self, ctx: ParseRuleCtx) {
         match self.compiler.class_compiler.as_ref() {
             None => self.error("Can't use 'super' outside of a class."),
+            Some(class_compiler) if class_compiler.in_static_field => {
+                self.error("Can't use 'super' in a static field initializer.")
+            }
             Some(class_compiler) if !class_compiler.has_superclass => {
                 self.error("Can't use 'super' in a class with no superclass.")
             }
@@ -1311,15 +2780,24 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         self.consume(TokenKind::Identifier, "Expect superclass method name.");
         let name = self.identifier_constant(self.prev());
 
-        let ctx = ParseRuleCtx { can_assign: false };
+        let ctx = ParseRuleCtx {
+            can_assign: false,
+            can_concat: false,
+        };
 
         self.named_variable(Token::synthetic("this"), ctx);
 
-        if self.match_tok(TokenKind::LeftParen) {
-            let arg_count = self.argument_list();
+        if self.match_tok(TokenKind::LeftParen) && self.named_arguments().1.is_empty() {
+            let (arg_count, _) = self.argument_list(None);
             self.named_variable(Token::synthetic("super"), ctx);
             self.emit_bytes(Opcode::SuperInvoke as u8, name);
             self.emit_byte(arg_count);
+        } else if self.prev().kind == TokenKind::LeftParen {
+            self.named_variable(Token::synthetic("super"), ctx);
+            self.emit_bytes(Opcode::GetSuper as u8, name);
+            let (arg_count, named) = self.argument_list(None);
+            self.emit_bytes(Opcode::CallNamed as u8, arg_count);
+            self.emit_byte(named);
         } else {
             self.named_variable(Token::synthetic("super"), ctx);
             self.emit_bytes(Opcode::GetSuper as u8, name);
@@ -1334,6 +2812,8 @@ pub enum TokenKind {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
     Dot,
     Minus,
@@ -1341,6 +2821,13 @@ pub enum TokenKind {
     Semicolon,
     Slash,
     Star,
+    Colon,
+    Question,
+    Percent,
+    Ampersand,
+    Pipe,
+    Caret,
+    Tilde,
 
     // One or two character tokens.
     Bang,
@@ -1351,29 +2838,62 @@ pub enum TokenKind {
     GreaterEqual,
     Less,
     LessEqual,
+    StarStar,
+    TildeSlash,
+    PlusEqual,
+    MinusEqual,
+    StarEqual,
+    SlashEqual,
+    PlusPlus,
+    MinusMinus,
+    LessLess,
+    GreaterGreater,
+    /// `...` before a rest parameter
+    DotDotDot,
 
     // Literals.
     Identifier,
     String,
+    /// Part of a string before its first `${`
+    Interpolation,
+    /// Part of a string between `}` and the next `${`
+    InterpolationMiddle,
+    /// Rest of a string after the `}` of its last interpolation
+    InterpolationEnd,
     Number,
 
     // Keywords.
     And,
+    Assert,
+    Await,
+    Break,
+    Case,
+    Catch,
     Class,
+    Const,
+    Continue,
+    Default,
     Else,
     False,
+    Finally,
     For,
     Fun,
     If,
+    Import,
+    In,
     Nil,
     Or,
     Print,
     Return,
     Super,
+    Switch,
     This,
+    Throw,
     True,
+    Try,
     Var,
     While,
+    Yield,
 
     Error,
     Eof,
@@ -1385,6 +2905,8 @@ pub enum TokenKind {
 pub struct Token<'src> {
     kind: TokenKind,
     line: u32,
+    /// 1-based column of the first character of the token
+    column: u32,
     msg: &'src str,
 }
 
@@ -1393,28 +2915,162 @@ impl<'src> Token<'src> {
         Self {
             kind: TokenKind::Synthetic,
             line: u32::MAX,
+            column: 0,
             msg,
         }
     }
 }
 
+/// A syntax or resolution error found while compiling
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompileError {
+    pub line: u32,
+    /// 1-based, 0 if the error doesn't point at a real token
+    pub column: u32,
+    pub message: String,
+    /// What the error points at: a quoted lexeme, `end`, or empty for scanner errors
+    pub at: String,
+    /// The source line the error is on
+    pub snippet: String,
+}
+
+impl CompileError {
+    /// An error at `token`, quoting the line of `scanner`'s source it's on
+    fn new(scanner: &Scanner, token: Token, message: &str) -> Self {
+        let at = match token.kind {
+            TokenKind::Eof => "end".to_string(),
+            // Scanner errors carry the message in place of the lexeme
+            TokenKind::Error => String::new(),
+            _ => format!("'{}'", token.msg),
+        };
+        let snippet = scanner.source_line(token.line).unwrap_or_default();
+
+        CompileError {
+            line: token.line,
+            column: token.column,
+            message: message.to_string(),
+            at,
+            snippet: snippet.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[line {}:{}] Error", self.line, self.column)?;
+        if !self.at.is_empty() {
+            write!(f, " at {}", self.at)?;
+        }
+        write!(f, ": {}", self.message)?;
+
+        if !self.snippet.is_empty() && self.column > 0 {
+            write!(
+                f,
+                "\n    {}\n    {:>width$}",
+                self.snippet,
+                "^",
+                width = self.column as usize
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+/// The value of a number literal, which the scanner already checked is well formed: decimal,
+/// `0x` hex or `0b` binary, with `_` between digits
+fn parse_number(literal: &str) -> f64 {
+    let literal: String = literal.chars().filter(|&c| c != '_').collect();
+    let radix = match literal.get(..2) {
+        Some("0x" | "0X") => 16,
+        Some("0b" | "0B") => 2,
+        _ => 10,
+    };
+
+    if radix == 10 {
+        literal.parse().unwrap()
+    } else {
+        literal[2..].chars().fold(0.0, |value, digit| {
+            value * radix as f64 + digit.to_digit(radix).unwrap() as f64
+        })
+    }
+}
+
+/// Whether `src` stops in the middle of a block, a grouping, a list or map literal or a string,
+/// meaning the REPL should ask for more input before compiling it
+pub fn is_incomplete(src: &str) -> bool {
+    let mut scanner = Scanner::new(src);
+    let mut depth = 0i32;
+
+    loop {
+        let token = scanner.token();
+        match token.kind {
+            TokenKind::LeftParen | TokenKind::LeftBrace | TokenKind::LeftBracket => depth += 1,
+            TokenKind::RightParen | TokenKind::RightBrace | TokenKind::RightBracket => depth -= 1,
+            TokenKind::Error if token.msg == UNTERMINATED_STRING => return true,
+            TokenKind::Error if token.msg == UNTERMINATED_COMMENT => return true,
+            TokenKind::Eof => return depth > 0 || !scanner.interpolations.is_empty(),
+            _ => (),
+        }
+    }
+}
+
+const UNTERMINATED_STRING: &str = "Unterminated string.";
+const UNTERMINATED_COMMENT: &str = "Unterminated block comment.";
+
+/// Length of the `#!` line `src` starts with, without the newline, or 0 if there is none
+fn shebang_len(src: &str) -> usize {
+    if src.starts_with("#!") {
+        src.find('\n').unwrap_or(src.len())
+    } else {
+        0
+    }
+}
+
+#[derive(Clone)]
 pub struct Scanner<'src> {
     src: &'src [u8],
     start: usize,
     current: usize,
     line: usize,
+    /// Offset of the first character of the current line
+    line_start: usize,
+    /// Column of `start`, computed before the token is scanned since it can span lines
+    start_column: u32,
+    /// Unclosed braces in each string interpolation currently being scanned, innermost last.
+    /// The `}` that closes an interpolation continues its string
+    interpolations: Vec<u32>,
 }
 
 impl<'src> Scanner<'src> {
+    /// Skips a `#!` line at the start, so scripts can be run as executables
     pub fn new(src: &'src str) -> Self {
+        let shebang = shebang_len(src);
         Self {
             src: src.as_bytes(),
-            start: 0,
-            current: 0,
+            start: shebang,
+            current: shebang,
             line: 1,
+            line_start: 0,
+            start_column: 1,
+            interpolations: vec![],
         }
     }
 
+    /// The text of the given 1-based line, without its line terminator
+    fn source_line(&self, line: u32) -> Option<&'src str> {
+        // Safety:
+        // The input is guaranteed to be valid utf8 so this is safe
+        let src = unsafe { std::str::from_utf8_unchecked(self.src) };
+        src.lines().nth(line.checked_sub(1)? as usize)
+    }
+
+    fn newline(&mut self) {
+        self.line += 1;
+        self.line_start = self.current;
+    }
+
     fn advance(&mut self) -> u8 {
         let ret = self.src[self.current];
         self.current += 1;
@@ -1426,14 +3082,11 @@ impl<'src> Scanner<'src> {
     }
 
     fn peek_next(&mut self) -> u8 {
-        if self.is_at_end() {
-            b'\0'
-        } else {
-            self.src[self.current + 1]
-        }
+        self.src.get(self.current + 1).cloned().unwrap_or(b'\0')
     }
 
-    fn skip_whitespace(&mut self) {
+    /// Skips whitespace and comments, returns an error token for an unterminated block comment
+    fn skip_whitespace(&mut self) -> Result<(), Token<'src>> {
         loop {
             let c = self.peek();
 
@@ -1442,26 +3095,65 @@ impl<'src> Scanner<'src> {
                     self.advance();
                 }
                 b'\n' => {
-                    self.line += 1;
                     self.advance();
+                    self.newline();
                 }
                 b'/' => {
                     if self.peek_next() == b'/' {
                         while self.peek() != b'\n' && !self.is_at_end() {
                             self.advance();
                         }
+                    } else if self.peek_next() == b'*' {
+                        self.block_comment()?;
                     } else {
-                        return;
+                        return Ok(());
                     }
                 }
-                _ => return,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    /// `/* ... */`, which can be nested
+    fn block_comment(&mut self) -> Result<(), Token<'src>> {
+        self.start = self.current;
+        self.start_column = (self.start - self.line_start) as u32 + 1;
+        let start_line = self.line as u32;
+        self.advance();
+        self.advance();
+
+        let mut depth = 1;
+        while depth > 0 {
+            if self.is_at_end() {
+                // point at where the comment starts, not the end of the file
+                let mut err = self.error_token(UNTERMINATED_COMMENT);
+                err.line = start_line;
+                return Err(err);
+            }
+
+            match self.advance() {
+                b'\n' => self.newline(),
+                b'/' if self.peek() == b'*' => {
+                    self.advance();
+                    depth += 1;
+                }
+                b'*' if self.peek() == b'/' => {
+                    self.advance();
+                    depth -= 1;
+                }
+                _ => (),
             }
         }
+
+        Ok(())
     }
 
     pub fn token(&mut self) -> Token<'src> {
-        self.skip_whitespace();
+        if let Err(err) = self.skip_whitespace() {
+            return err;
+        }
         self.start = self.current;
+        self.start_column = (self.start - self.line_start) as u32 + 1;
 
         if self.is_at_end() {
             return self.make_token(TokenKind::Eof);
@@ -1480,15 +3172,85 @@ impl<'src> Scanner<'src> {
         match c {
             b'(' => return self.make_token(TokenKind::LeftParen),
             b')' => return self.make_token(TokenKind::RightParen),
-            b'{' => return self.make_token(TokenKind::LeftBrace),
-            b'}' => return self.make_token(TokenKind::RightBrace),
+            b'{' => {
+                if let Some(braces) = self.interpolations.last_mut() {
+                    *braces += 1;
+                }
+                return self.make_token(TokenKind::LeftBrace);
+            }
+            b'}' => {
+                match self.interpolations.last_mut() {
+                    Some(0) => {
+                        self.interpolations.pop();
+                        return self.string(true);
+                    }
+                    Some(braces) => *braces -= 1,
+                    None => (),
+                }
+                return self.make_token(TokenKind::RightBrace);
+            }
+            b'[' => return self.make_token(TokenKind::LeftBracket),
+            b']' => return self.make_token(TokenKind::RightBracket),
             b';' => return self.make_token(TokenKind::Semicolon),
+            b':' => return self.make_token(TokenKind::Colon),
+            b'?' => return self.make_token(TokenKind::Question),
             b',' => return self.make_token(TokenKind::Comma),
+            b'.' if self.peek() == b'.' && self.peek_next() == b'.' => {
+                self.current += 2;
+                return self.make_token(TokenKind::DotDotDot);
+            }
             b'.' => return self.make_token(TokenKind::Dot),
-            b'-' => return self.make_token(TokenKind::Minus),
-            b'+' => return self.make_token(TokenKind::Plus),
-            b'/' => return self.make_token(TokenKind::Slash),
-            b'*' => return self.make_token(TokenKind::Star),
+            b'-' => {
+                let kind = if self.matches(b'=') {
+                    TokenKind::MinusEqual
+                } else if self.matches(b'-') {
+                    TokenKind::MinusMinus
+                } else {
+                    TokenKind::Minus
+                };
+                return self.make_token(kind);
+            }
+            b'+' => {
+                let kind = if self.matches(b'=') {
+                    TokenKind::PlusEqual
+                } else if self.matches(b'+') {
+                    TokenKind::PlusPlus
+                } else {
+                    TokenKind::Plus
+                };
+                return self.make_token(kind);
+            }
+            b'/' => {
+                let kind = if self.matches(b'=') {
+                    TokenKind::SlashEqual
+                } else {
+                    TokenKind::Slash
+                };
+                return self.make_token(kind);
+            }
+            b'*' => {
+                let kind = if self.matches(b'*') {
+                    TokenKind::StarStar
+                } else if self.matches(b'=') {
+                    TokenKind::StarEqual
+                } else {
+                    TokenKind::Star
+                };
+                return self.make_token(kind);
+            }
+            b'%' => return self.make_token(TokenKind::Percent),
+            b'&' => return self.make_token(TokenKind::Ampersand),
+            b'|' => return self.make_token(TokenKind::Pipe),
+            b'^' => return self.make_token(TokenKind::Caret),
+            b'~' => {
+                // `//` starts a comment, so floor division is spelled `~/`
+                let kind = if self.matches(b'/') {
+                    TokenKind::TildeSlash
+                } else {
+                    TokenKind::Tilde
+                };
+                return self.make_token(kind);
+            }
             b'!' => {
                 let kind = if self.matches(b'=') {
                     TokenKind::BangEqual
@@ -1508,6 +3270,8 @@ impl<'src> Scanner<'src> {
             b'<' => {
                 let kind = if self.matches(b'=') {
                     TokenKind::LessEqual
+                } else if self.matches(b'<') {
+                    TokenKind::LessLess
                 } else {
                     TokenKind::Less
                 };
@@ -1516,12 +3280,14 @@ impl<'src> Scanner<'src> {
             b'>' => {
                 let kind = if self.matches(b'=') {
                     TokenKind::GreaterEqual
+                } else if self.matches(b'>') {
+                    TokenKind::GreaterGreater
                 } else {
                     TokenKind::Greater
                 };
                 return self.make_token(kind);
             }
-            b'"' => return self.string(),
+            b'"' => return self.string(false),
             _ => (),
         }
 
@@ -1542,28 +3308,65 @@ impl<'src> Scanner<'src> {
 
     fn identifier_kind(&self) -> TokenKind {
         match self.src[self.start] {
-            b'a' => self.check_keyword(1, 2, "nd", TokenKind::And),
-            b'c' => self.check_keyword(1, 4, "lass", TokenKind::Class),
+            b'a' => match self.check_keyword(1, 2, "nd", TokenKind::And) {
+                TokenKind::Identifier => match self.check_keyword(1, 5, "ssert", TokenKind::Assert)
+                {
+                    TokenKind::Identifier => self.check_keyword(1, 4, "wait", TokenKind::Await),
+                    kind => kind,
+                },
+                kind => kind,
+            },
+            b'b' => self.check_keyword(1, 4, "reak", TokenKind::Break),
+            b'c' if self.current as i64 - self.start as i64 > 1 => match self.src[self.start + 1] {
+                b'a' => match self.check_keyword(2, 2, "se", TokenKind::Case) {
+                    TokenKind::Identifier => self.check_keyword(2, 3, "tch", TokenKind::Catch),
+                    kind => kind,
+                },
+                b'l' => self.check_keyword(2, 3, "ass", TokenKind::Class),
+                b'o' => match self.check_keyword(2, 6, "ntinue", TokenKind::Continue) {
+                    TokenKind::Identifier => self.check_keyword(2, 3, "nst", TokenKind::Const),
+                    kind => kind,
+                },
+                _ => TokenKind::Identifier,
+            },
+            b'd' => self.check_keyword(1, 6, "efault", TokenKind::Default),
             b'e' => self.check_keyword(1, 3, "lse", TokenKind::Else),
             b'f' if self.current as i64 - self.start as i64 > 1 => match self.src[self.start + 1] {
                 b'a' => self.check_keyword(2, 3, "lse", TokenKind::False),
+                b'i' => self.check_keyword(2, 5, "nally", TokenKind::Finally),
                 b'o' => self.check_keyword(2, 1, "r", TokenKind::For),
                 b'u' => self.check_keyword(2, 1, "n", TokenKind::Fun),
                 _ => TokenKind::Identifier,
             },
-            b'i' => self.check_keyword(1, 1, "f", TokenKind::If),
+            b'i' if self.current as i64 - self.start as i64 > 1 => match self.src[self.start + 1] {
+                b'f' => self.check_keyword(2, 0, "", TokenKind::If),
+                b'm' => self.check_keyword(2, 4, "port", TokenKind::Import),
+                b'n' => self.check_keyword(2, 0, "", TokenKind::In),
+                _ => TokenKind::Identifier,
+            },
             b'n' => self.check_keyword(1, 2, "il", TokenKind::Nil),
             b'o' => self.check_keyword(1, 1, "r", TokenKind::Or),
             b'p' => self.check_keyword(1, 4, "rint", TokenKind::Print),
             b'r' => self.check_keyword(1, 5, "eturn", TokenKind::Return),
-            b's' => self.check_keyword(1, 4, "uper", TokenKind::Super),
+            b's' if self.current as i64 - self.start as i64 > 1 => match self.src[self.start + 1] {
+                b'u' => self.check_keyword(2, 3, "per", TokenKind::Super),
+                b'w' => self.check_keyword(2, 4, "itch", TokenKind::Switch),
+                _ => TokenKind::Identifier,
+            },
             b't' if self.current as i64 - self.start as i64 > 1 => match self.src[self.start + 1] {
-                b'h' => self.check_keyword(2, 2, "is", TokenKind::This),
-                b'r' => self.check_keyword(2, 2, "ue", TokenKind::True),
+                b'h' => match self.check_keyword(2, 2, "is", TokenKind::This) {
+                    TokenKind::Identifier => self.check_keyword(2, 3, "row", TokenKind::Throw),
+                    kind => kind,
+                },
+                b'r' => match self.check_keyword(2, 2, "ue", TokenKind::True) {
+                    TokenKind::Identifier => self.check_keyword(2, 1, "y", TokenKind::Try),
+                    kind => kind,
+                },
                 _ => TokenKind::Identifier,
             },
             b'v' => self.check_keyword(1, 2, "ar", TokenKind::Var),
             b'w' => self.check_keyword(1, 4, "hile", TokenKind::While),
+            b'y' => self.check_keyword(1, 4, "ield", TokenKind::Yield),
             _ => TokenKind::Identifier,
         }
     }
@@ -1578,22 +3381,36 @@ impl<'src> Scanner<'src> {
         TokenKind::Identifier
     }
 
-    fn string(&mut self) -> Token<'src> {
+    /// The rest of a string, `continued` after the `}` of an interpolation
+    fn string(&mut self, continued: bool) -> Token<'src> {
         while self.peek() != b'"' && !self.is_at_end() {
-            if self.peek() == b'\n' {
-                self.line += 1;
+            if self.peek() == b'$' && self.peek_next() == b'{' {
+                self.advance();
+                self.advance();
+                self.interpolations.push(0);
+                return self.make_token(if continued {
+                    TokenKind::InterpolationMiddle
+                } else {
+                    TokenKind::Interpolation
+                });
             }
 
-            self.advance();
+            if self.advance() == b'\n' {
+                self.newline();
+            }
         }
 
         if self.is_at_end() {
-            return self.error_token("Unterminated string.");
+            return self.error_token(UNTERMINATED_STRING);
         }
 
         // closing quote
         self.advance();
-        self.make_token(TokenKind::String)
+        self.make_token(if continued {
+            TokenKind::InterpolationEnd
+        } else {
+            TokenKind::String
+        })
     }
 
     fn is_digit(c: u8) -> bool {
@@ -1603,23 +3420,91 @@ impl<'src> Scanner<'src> {
         }
     }
 
+    /// Decimal numbers with an optional fraction and exponent (`1.5e3`), or hexadecimal (`0xFF`)
+    /// and binary (`0b1010`) integers. Digits can be separated by underscores (`1_000`)
     fn number(&mut self) -> Token<'src> {
-        while Self::is_digit(self.peek()) {
-            self.advance();
+        if self.src[self.start] == b'0' && matches!(self.peek(), b'x' | b'X' | b'b' | b'B') {
+            let (radix, err) = match self.advance() {
+                b'x' | b'X' => (16, "Expect hex digits after '0x'."),
+                _ => (2, "Expect binary digits after '0b'."),
+            };
+            if !Self::is_radix_digit(self.peek(), radix) {
+                return self.malformed_number(err);
+            }
+            if let Err(err) = self.digits(radix) {
+                return err;
+            }
+            return self.end_number();
+        }
+
+        if let Err(err) = self.digits(10) {
+            return err;
         }
 
         if self.peek() == b'.' && Self::is_digit(self.peek_next()) {
             // consume the '.'
             self.advance();
 
-            while Self::is_digit(self.peek()) {
+            if let Err(err) = self.digits(10) {
+                return err;
+            }
+        }
+
+        if matches!(self.peek(), b'e' | b'E') {
+            self.advance();
+            if matches!(self.peek(), b'+' | b'-') {
+                self.advance();
+            }
+            if !Self::is_digit(self.peek()) {
+                return self.malformed_number("Expect digits in exponent.");
+            }
+            if let Err(err) = self.digits(10) {
+                return err;
+            }
+        }
+
+        self.end_number()
+    }
+
+    /// Consume digits in `radix` and the underscores between them
+    fn digits(&mut self, radix: u32) -> Result<(), Token<'src>> {
+        loop {
+            let c = self.peek();
+            if Self::is_radix_digit(c, radix) {
+                self.advance();
+            } else if c == b'_' {
+                if !Self::is_radix_digit(self.peek_next(), radix) {
+                    return Err(self.malformed_number("Expect digit after '_' in number."));
+                }
                 self.advance();
+            } else {
+                return Ok(());
             }
         }
+    }
+
+    /// A number can't run into an identifier, this catches things like `0b102` or `0xFG`
+    fn end_number(&mut self) -> Token<'src> {
+        if Self::is_alpha(self.peek()) || Self::is_digit(self.peek()) {
+            return self.malformed_number("Invalid character in number.");
+        }
 
         self.make_token(TokenKind::Number)
     }
 
+    /// Skip the rest of a malformed number so scanning resumes after it
+    fn malformed_number(&mut self, err: &'src str) -> Token<'src> {
+        while Self::is_alpha(self.peek()) || Self::is_digit(self.peek()) {
+            self.advance();
+        }
+
+        self.error_token(err)
+    }
+
+    fn is_radix_digit(c: u8, radix: u32) -> bool {
+        (c as char).is_digit(radix)
+    }
+
     fn matches(&mut self, expected: u8) -> bool {
         if self.is_at_end() {
             return false;
@@ -1646,6 +3531,7 @@ impl<'src> Scanner<'src> {
             // The input is guaranteed to be valid utf8 so this is safe
             msg: unsafe { std::str::from_utf8_unchecked(&self.src[self.start..self.current]) },
             line: self.line as u32,
+            column: self.start_column,
         }
     }
 
@@ -1654,6 +3540,7 @@ impl<'src> Scanner<'src> {
             kind: TokenKind::Error,
             msg: err,
             line: self.line as u32,
+            column: self.start_column,
         }
     }
 }
@@ -0,0 +1,1852 @@
+//! A syntax tree of a script, for tools that look at the whole program instead of running it.
+//!
+//! The compiler doesn't build one, it emits bytecode while it parses. [`parse`] parses the same
+//! grammar with the same precedences (the ones in [`Parser::PARSE_RULES`]) and reports the same
+//! syntax errors, but leaves out the checks that need scopes, like `return` at the top level.
+//! [`to_json`] and [`to_sexp`] print the tree for `loxide --dump-ast`.
+
+use std::fmt::Write;
+
+use super::{
+    parse_number, shebang_len, CompileError, Parser, Precedence, Scanner, Token, TokenKind,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stmt {
+    pub line: u32,
+    pub column: u32,
+    /// Line of the statement's last token
+    pub end_line: u32,
+    pub kind: StmtKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StmtKind {
+    Expression(Expr),
+    Print(Expr),
+    Var {
+        name: String,
+        init: Option<Expr>,
+    },
+    Const {
+        name: String,
+        init: Expr,
+    },
+    Function(Function),
+    Class(Class),
+    /// `import "path";`, `import name from "path";` and `import native "name";`
+    Import {
+        name: Option<String>,
+        native: bool,
+        path: String,
+    },
+    Block(Block),
+    If {
+        condition: Expr,
+        then: Box<Stmt>,
+        otherwise: Option<Box<Stmt>>,
+    },
+    While {
+        condition: Expr,
+        body: Box<Stmt>,
+    },
+    /// `for (init; condition; increment) body`, the initializer is a `Var` or an `Expression`
+    For {
+        init: Option<Box<Stmt>>,
+        condition: Option<Expr>,
+        increment: Option<Expr>,
+        body: Box<Stmt>,
+    },
+    /// `for (item in collection) body`, `var` before the item is optional
+    ForIn {
+        item: String,
+        collection: Expr,
+        body: Box<Stmt>,
+    },
+    Switch {
+        value: Expr,
+        cases: Vec<(Expr, Vec<Stmt>)>,
+        default: Option<Vec<Stmt>>,
+    },
+    Break,
+    Continue,
+    Return(Option<Expr>),
+    Yield(Option<Expr>),
+    Throw(Expr),
+    Try {
+        body: Block,
+        /// The exception variable and the block
+        catch: Option<(String, Block)>,
+        finally: Option<Block>,
+    },
+    Assert(Expr),
+}
+
+/// The statements between braces
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub stmts: Vec<Stmt>,
+    /// Line of the `}`
+    pub end_line: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    pub line: u32,
+    pub column: u32,
+    pub kind: ExprKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind {
+    Nil,
+    Bool(bool),
+    /// The literal as written, like `0xff` or `1_000`, see [`Expr::number`]
+    Number(String),
+    String(String),
+    /// `"a ${b} c"`: the text before each expression, and the text after the last one
+    Interpolation {
+        parts: Vec<(String, Expr)>,
+        tail: String,
+    },
+    Variable(String),
+    This,
+    /// `super.method`
+    Super(String),
+    /// Parentheses, kept so the tree can be printed back as it was written
+    Grouping(Box<Expr>),
+    Unary {
+        op: UnaryOp,
+        operand: Box<Expr>,
+    },
+    Binary {
+        left: Box<Expr>,
+        op: BinaryOp,
+        right: Box<Expr>,
+    },
+    /// `and` and `or`, which only evaluate `right` if they have to
+    Logical {
+        left: Box<Expr>,
+        op: LogicalOp,
+        right: Box<Expr>,
+    },
+    /// `condition ? then : otherwise`
+    Conditional {
+        condition: Box<Expr>,
+        then: Box<Expr>,
+        otherwise: Box<Expr>,
+    },
+    /// `target = value`, or `target += value` with the operator. The target is a `Variable`,
+    /// a `Get` or an `Index`, which only takes `=`
+    Assign {
+        target: Box<Expr>,
+        op: Option<BinaryOp>,
+        value: Box<Expr>,
+    },
+    /// `++x` and `x--`, `op` is `Add` or `Subtract`
+    Increment {
+        name: String,
+        op: BinaryOp,
+        prefix: bool,
+    },
+    Call {
+        callee: Box<Expr>,
+        args: Vec<Argument>,
+    },
+    /// `object.name`
+    Get {
+        object: Box<Expr>,
+        name: String,
+    },
+    /// `object[index]`
+    Index {
+        object: Box<Expr>,
+        index: Box<Expr>,
+    },
+    /// `object[start:end]`, either end can be left out
+    Slice {
+        object: Box<Expr>,
+        start: Option<Box<Expr>>,
+        end: Option<Box<Expr>>,
+    },
+    List(Vec<Expr>),
+    Map(Vec<(Expr, Expr)>),
+    /// `fun (a) { ... }`
+    Lambda(Box<Function>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Negate,
+    Not,
+    BitNot,
+    Await,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+    FloorDivide,
+    Power,
+    BitAnd,
+    BitOr,
+    BitXor,
+    ShiftLeft,
+    ShiftRight,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogicalOp {
+    And,
+    Or,
+}
+
+/// An argument of a call, `name` is set for named arguments `name: value`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Argument {
+    pub name: Option<String>,
+    pub value: Expr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    /// `None` for lambdas
+    pub name: Option<String>,
+    pub line: u32,
+    pub column: u32,
+    pub params: Vec<Param>,
+    pub body: Block,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Param {
+    pub name: String,
+    pub default: Option<Expr>,
+    /// `...name`, collects the rest of the arguments into a list
+    pub rest: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Class {
+    pub name: String,
+    pub superclass: Option<String>,
+    pub members: Vec<Member>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Member {
+    Method {
+        is_static: bool,
+        function: Function,
+    },
+    /// `get name { ... }`, the function has no parameters
+    Getter(Function),
+    /// `set name(value) { ... }`
+    Setter(Function),
+    /// `var name = init;`
+    Field {
+        is_static: bool,
+        name: String,
+        init: Option<Expr>,
+        line: u32,
+    },
+}
+
+impl Expr {
+    /// The value of a `Number` literal
+    pub fn number(&self) -> Option<f64> {
+        match &self.kind {
+            ExprKind::Number(literal) => Some(parse_number(literal)),
+            _ => None,
+        }
+    }
+}
+
+impl UnaryOp {
+    pub fn symbol(self) -> &'static str {
+        match self {
+            UnaryOp::Negate => "-",
+            UnaryOp::Not => "!",
+            UnaryOp::BitNot => "~",
+            UnaryOp::Await => "await",
+        }
+    }
+}
+
+impl BinaryOp {
+    pub fn symbol(self) -> &'static str {
+        use BinaryOp::*;
+        match self {
+            Equal => "==",
+            NotEqual => "!=",
+            Greater => ">",
+            GreaterEqual => ">=",
+            Less => "<",
+            LessEqual => "<=",
+            Add => "+",
+            Subtract => "-",
+            Multiply => "*",
+            Divide => "/",
+            Modulo => "%",
+            FloorDivide => "~/",
+            Power => "**",
+            BitAnd => "&",
+            BitOr => "|",
+            BitXor => "^",
+            ShiftLeft => "<<",
+            ShiftRight => ">>",
+        }
+    }
+
+    fn from_token(kind: TokenKind) -> Option<Self> {
+        use BinaryOp::*;
+        Some(match kind {
+            TokenKind::EqualEqual => Equal,
+            TokenKind::BangEqual => NotEqual,
+            TokenKind::Greater => Greater,
+            TokenKind::GreaterEqual => GreaterEqual,
+            TokenKind::Less => Less,
+            TokenKind::LessEqual => LessEqual,
+            TokenKind::Plus => Add,
+            TokenKind::Minus => Subtract,
+            TokenKind::Star => Multiply,
+            TokenKind::Slash => Divide,
+            TokenKind::Percent => Modulo,
+            TokenKind::TildeSlash => FloorDivide,
+            TokenKind::StarStar => Power,
+            TokenKind::Ampersand => BitAnd,
+            TokenKind::Pipe => BitOr,
+            TokenKind::Caret => BitXor,
+            TokenKind::LessLess => ShiftLeft,
+            TokenKind::GreaterGreater => ShiftRight,
+            _ => return None,
+        })
+    }
+}
+
+impl LogicalOp {
+    pub fn symbol(self) -> &'static str {
+        match self {
+            LogicalOp::And => "and",
+            LogicalOp::Or => "or",
+        }
+    }
+}
+
+/// Parse `src` into its statements. Like the compiler it recovers at statement boundaries, so
+/// the errors are all of the syntax errors in the source
+pub fn parse(src: &str) -> Result<Vec<Stmt>, Vec<CompileError>> {
+    let (stmts, errors) = parse_partial(src);
+    if errors.is_empty() {
+        Ok(stmts)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Parse `src` into the statements without syntax errors and the errors of the others, for
+/// code that's being edited. A statement with an error is left out, the one around it is kept
+pub fn parse_partial(src: &str) -> (Vec<Stmt>, Vec<CompileError>) {
+    let mut parser = AstParser {
+        scanner: Scanner::new(src),
+        cur: Token::synthetic(""),
+        prev: Token::synthetic(""),
+        errors: vec![],
+        panic_mode: false,
+    };
+    parser.advance();
+
+    let mut stmts = vec![];
+    while !parser.match_tok(TokenKind::Eof) {
+        if let Some(stmt) = parser.declaration() {
+            stmts.push(stmt);
+        }
+    }
+    (stmts, parser.errors)
+}
+
+struct AstParser<'src> {
+    scanner: Scanner<'src>,
+    cur: Token<'src>,
+    prev: Token<'src>,
+    errors: Vec<CompileError>,
+    panic_mode: bool,
+}
+
+impl<'src> AstParser<'src> {
+    fn declaration(&mut self) -> Option<Stmt> {
+        let start = self.cur;
+        let kind = if self.match_tok(TokenKind::Class) {
+            self.class_declaration()
+        } else if self.check(TokenKind::Fun) && self.peek_next_kind() != TokenKind::LeftParen {
+            self.advance();
+            self.consume(TokenKind::Identifier, "Expect function name.");
+            StmtKind::Function(self.function(Some(self.prev)))
+        } else if self.match_tok(TokenKind::Var) {
+            self.var_declaration()
+        } else if self.match_tok(TokenKind::Const) {
+            self.consume(TokenKind::Identifier, "Expect constant name.");
+            let name = self.prev.msg.to_string();
+            self.consume(TokenKind::Equal, "Expect '=' after constant name.");
+            let init = self.expression();
+            self.consume(
+                TokenKind::Semicolon,
+                "Expect ';' after constant declaration.",
+            );
+            StmtKind::Const { name, init }
+        } else if self.match_tok(TokenKind::Import) {
+            self.import_declaration()
+        } else {
+            return self.statement();
+        };
+
+        if self.panic_mode {
+            self.synchronize();
+            return None;
+        }
+        Some(self.stmt(start, kind))
+    }
+
+    fn stmt(&self, start: Token, kind: StmtKind) -> Stmt {
+        Stmt {
+            line: start.line,
+            column: start.column,
+            end_line: self.prev.line,
+            kind,
+        }
+    }
+
+    fn class_declaration(&mut self) -> StmtKind {
+        self.consume(TokenKind::Identifier, "Expect class name.");
+        let name = self.prev.msg.to_string();
+        let superclass = self.match_tok(TokenKind::Less).then(|| {
+            self.consume(TokenKind::Identifier, "Expect superclass name.");
+            self.prev.msg.to_string()
+        });
+
+        self.consume(TokenKind::LeftBrace, "Expect '{' before class body.");
+        let mut members = vec![];
+        while !self.check(TokenKind::RightBrace) && !self.check(TokenKind::Eof) {
+            members.push(self.class_member());
+        }
+        self.consume(TokenKind::RightBrace, "Expect '}' after class body.");
+
+        StmtKind::Class(Class {
+            name,
+            superclass,
+            members,
+        })
+    }
+
+    fn class_member(&mut self) -> Member {
+        let accessor = self.check_word("get") || self.check_word("set");
+        let next = self.peek_next_kind();
+        if self.check_word("static") && matches!(next, TokenKind::Identifier | TokenKind::Var) {
+            self.advance();
+            if self.match_tok(TokenKind::Var) {
+                self.field(true)
+            } else {
+                self.consume(TokenKind::Identifier, "Expect method name.");
+                let function = self.function(Some(self.prev));
+                Member::Method {
+                    is_static: true,
+                    function,
+                }
+            }
+        } else if self.match_tok(TokenKind::Var) {
+            self.field(false)
+        } else if accessor && next == TokenKind::Identifier {
+            let getter = self.check_word("get");
+            self.advance();
+            self.consume(TokenKind::Identifier, "Expect property name.");
+            if getter {
+                Member::Getter(self.function_body(Some(self.prev), vec![]))
+            } else {
+                Member::Setter(self.function(Some(self.prev)))
+            }
+        } else {
+            self.consume(TokenKind::Identifier, "Expect method name.");
+            let function = self.function(Some(self.prev));
+            Member::Method {
+                is_static: false,
+                function,
+            }
+        }
+    }
+
+    fn field(&mut self, is_static: bool) -> Member {
+        let line = self.prev.line;
+        self.consume(TokenKind::Identifier, "Expect field name.");
+        let name = self.prev.msg.to_string();
+        let init = self.match_tok(TokenKind::Equal).then(|| self.expression());
+        self.consume(TokenKind::Semicolon, "Expect ';' after field declaration.");
+        Member::Field {
+            is_static,
+            name,
+            init,
+            line,
+        }
+    }
+
+    /// The parameters and body of a function named `name`, `None` for a lambda
+    fn function(&mut self, name: Option<Token>) -> Function {
+        let params = self.parameters();
+        self.function_body(name, params)
+    }
+
+    fn function_body(&mut self, name: Option<Token>, params: Vec<Param>) -> Function {
+        let start = name.unwrap_or(self.prev);
+        self.consume(TokenKind::LeftBrace, "Expect '{' before function body.");
+        Function {
+            name: name.map(|name| name.msg.to_string()),
+            line: start.line,
+            column: start.column,
+            params,
+            body: self.block(),
+        }
+    }
+
+    fn parameters(&mut self) -> Vec<Param> {
+        self.consume(TokenKind::LeftParen, "Expect '(' after function name.");
+        let mut params: Vec<Param> = vec![];
+        if !self.check(TokenKind::RightParen) {
+            loop {
+                let rest = self.match_tok(TokenKind::DotDotDot);
+                self.consume(TokenKind::Identifier, "Expect parameter name.");
+                let name = self.prev.msg.to_string();
+                let mut default = None;
+                if rest {
+                    if self.check(TokenKind::Comma) {
+                        self.error_at_current("A rest parameter must be the last parameter.");
+                    }
+                } else if self.match_tok(TokenKind::Equal) {
+                    default = Some(self.expression());
+                } else if params.iter().any(|param| param.default.is_some()) {
+                    self.error("Parameters with a default value must come last.");
+                }
+                params.push(Param {
+                    name,
+                    default,
+                    rest,
+                });
+
+                if rest || !self.match_tok(TokenKind::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenKind::RightParen, "Expect ')' after parameters.");
+        params
+    }
+
+    fn var_declaration(&mut self) -> StmtKind {
+        self.consume(TokenKind::Identifier, "Expect variable name.");
+        let name = self.prev.msg.to_string();
+        let init = self.match_tok(TokenKind::Equal).then(|| self.expression());
+        self.consume(
+            TokenKind::Semicolon,
+            "Expect ';' after variable declaration.",
+        );
+        StmtKind::Var { name, init }
+    }
+
+    fn import_declaration(&mut self) -> StmtKind {
+        let mut name = None;
+        if self.check(TokenKind::Identifier)
+            && (!self.check_word("native") || self.peek_next_kind() == TokenKind::Identifier)
+        {
+            self.advance();
+            name = Some(self.prev.msg.to_string());
+            if !self.check_word("from") {
+                self.error_at_current("Expect 'from' after module name.");
+            }
+            self.advance();
+        }
+        let native = self.check_word("native");
+        if native {
+            self.advance();
+        }
+
+        self.consume(TokenKind::String, "Expect module path.");
+        // The statement is thrown away with the error
+        let path = match self.prev.kind {
+            TokenKind::String => self.prev.msg[1..self.prev.msg.len() - 1].to_string(),
+            _ => String::new(),
+        };
+        self.consume(TokenKind::Semicolon, "Expect ';' after import.");
+        StmtKind::Import { name, native, path }
+    }
+
+    fn statement(&mut self) -> Option<Stmt> {
+        let start = self.cur;
+        let kind = if self.match_tok(TokenKind::Print) {
+            let value = self.expression();
+            self.consume(TokenKind::Semicolon, "Expect ';' after value.");
+            StmtKind::Print(value)
+        } else if self.match_tok(TokenKind::For) {
+            self.for_statement()
+        } else if self.match_tok(TokenKind::LeftBrace) {
+            StmtKind::Block(self.block())
+        } else if self.match_tok(TokenKind::If) {
+            self.consume(TokenKind::LeftParen, "Expect '(' after 'if'.");
+            let condition = self.expression();
+            self.consume(TokenKind::RightParen, "Expect ')' after condition.");
+            let then = self.sub_statement();
+            let otherwise = self
+                .match_tok(TokenKind::Else)
+                .then(|| self.sub_statement());
+            StmtKind::If {
+                condition,
+                then,
+                otherwise,
+            }
+        } else if self.match_tok(TokenKind::Return) {
+            StmtKind::Return(self.optional_value("Expect ';' after return value."))
+        } else if self.match_tok(TokenKind::While) {
+            self.consume(TokenKind::LeftParen, "Expect '(' after 'while'.");
+            let condition = self.expression();
+            self.consume(TokenKind::RightParen, "Expect ')' after condition.");
+            let body = self.sub_statement();
+            StmtKind::While { condition, body }
+        } else if self.match_tok(TokenKind::Switch) {
+            self.switch_statement()
+        } else if self.match_tok(TokenKind::Break) {
+            self.consume(TokenKind::Semicolon, "Expect ';' after 'break'.");
+            StmtKind::Break
+        } else if self.match_tok(TokenKind::Continue) {
+            self.consume(TokenKind::Semicolon, "Expect ';' after 'continue'.");
+            StmtKind::Continue
+        } else if self.match_tok(TokenKind::Throw) {
+            let value = self.expression();
+            self.consume(TokenKind::Semicolon, "Expect ';' after thrown value.");
+            StmtKind::Throw(value)
+        } else if self.match_tok(TokenKind::Yield) {
+            StmtKind::Yield(self.optional_value("Expect ';' after yield value."))
+        } else if self.match_tok(TokenKind::Try) {
+            self.try_statement()
+        } else if self.match_tok(TokenKind::Assert) {
+            let value = self.expression();
+            self.consume(TokenKind::Semicolon, "Expect ';' after assertion.");
+            StmtKind::Assert(value)
+        } else {
+            let value = self.expression();
+            self.consume(TokenKind::Semicolon, "Expect ';' after expression.");
+            StmtKind::Expression(value)
+        };
+
+        if self.panic_mode {
+            self.synchronize();
+            return None;
+        }
+        Some(self.stmt(start, kind))
+    }
+
+    /// The body of an `if`, a loop or a `for` initializer, where an error leaves a placeholder
+    fn sub_statement(&mut self) -> Box<Stmt> {
+        let start = self.cur;
+        let stmt = self.statement();
+        Box::new(stmt.unwrap_or_else(|| {
+            let empty = Block {
+                stmts: vec![],
+                end_line: start.line,
+            };
+            self.stmt(start, StmtKind::Block(empty))
+        }))
+    }
+
+    /// The value of `return` and `yield`, if there is one
+    fn optional_value(&mut self, msg: &str) -> Option<Expr> {
+        if self.match_tok(TokenKind::Semicolon) {
+            return None;
+        }
+        let value = self.expression();
+        self.consume(TokenKind::Semicolon, msg);
+        Some(value)
+    }
+
+    fn for_statement(&mut self) -> StmtKind {
+        self.consume(TokenKind::LeftParen, "Expect '(' after 'for'.");
+        if self.is_for_in() {
+            self.match_tok(TokenKind::Var);
+            self.consume(TokenKind::Identifier, "Expect loop variable name.");
+            let item = self.prev.msg.to_string();
+            self.consume(TokenKind::In, "Expect 'in' after loop variable.");
+            let collection = self.expression();
+            self.consume(TokenKind::RightParen, "Expect ')' after collection.");
+            let body = self.sub_statement();
+            return StmtKind::ForIn {
+                item,
+                collection,
+                body,
+            };
+        }
+
+        let start = self.cur;
+        let init = if self.match_tok(TokenKind::Semicolon) {
+            None
+        } else if self.match_tok(TokenKind::Var) {
+            let var = self.var_declaration();
+            Some(Box::new(self.stmt(start, var)))
+        } else {
+            let value = self.expression();
+            self.consume(TokenKind::Semicolon, "Expect ';' after expression.");
+            Some(Box::new(self.stmt(start, StmtKind::Expression(value))))
+        };
+
+        let condition = (!self.match_tok(TokenKind::Semicolon)).then(|| {
+            let condition = self.expression();
+            self.consume(TokenKind::Semicolon, "Expect ';' after loop condition.");
+            condition
+        });
+        let increment = (!self.match_tok(TokenKind::RightParen)).then(|| {
+            let increment = self.expression();
+            self.consume(TokenKind::RightParen, "Expect ')' after for clauses.");
+            increment
+        });
+
+        StmtKind::For {
+            init,
+            condition,
+            increment,
+            body: self.sub_statement(),
+        }
+    }
+
+    /// Whether the clauses are `item in collection` or `var item in collection`
+    fn is_for_in(&self) -> bool {
+        let mut scanner = self.scanner.clone();
+        let mut next = scanner.token().kind;
+        if self.check(TokenKind::Var) {
+            if next != TokenKind::Identifier {
+                return false;
+            }
+            next = scanner.token().kind;
+        } else if !self.check(TokenKind::Identifier) {
+            return false;
+        }
+
+        next == TokenKind::In
+    }
+
+    fn switch_statement(&mut self) -> StmtKind {
+        self.consume(TokenKind::LeftParen, "Expect '(' after 'switch'.");
+        let value = self.expression();
+        self.consume(TokenKind::RightParen, "Expect ')' after value.");
+        self.consume(TokenKind::LeftBrace, "Expect '{' before switch cases.");
+
+        let mut cases = vec![];
+        let mut default = None;
+        while !self.check(TokenKind::RightBrace) && !self.check(TokenKind::Eof) {
+            if self.match_tok(TokenKind::Case) {
+                if default.is_some() {
+                    self.error("Can't have a case after the default case.");
+                }
+                let value = self.expression();
+                self.consume(TokenKind::Colon, "Expect ':' after case value.");
+                cases.push((value, self.switch_case_body()));
+            } else if self.match_tok(TokenKind::Default) {
+                if default.is_some() {
+                    self.error("Can't have more than one default case.");
+                }
+                self.consume(TokenKind::Colon, "Expect ':' after 'default'.");
+                default = Some(self.switch_case_body());
+            } else {
+                self.error_at_current("Expect 'case' or 'default'.");
+                self.advance();
+            }
+        }
+        self.consume(TokenKind::RightBrace, "Expect '}' after switch cases.");
+
+        StmtKind::Switch {
+            value,
+            cases,
+            default,
+        }
+    }
+
+    fn switch_case_body(&mut self) -> Vec<Stmt> {
+        let mut stmts = vec![];
+        while !self.check(TokenKind::Case)
+            && !self.check(TokenKind::Default)
+            && !self.check(TokenKind::RightBrace)
+            && !self.check(TokenKind::Eof)
+        {
+            stmts.extend(self.declaration());
+        }
+        stmts
+    }
+
+    fn try_statement(&mut self) -> StmtKind {
+        self.consume(TokenKind::LeftBrace, "Expect '{' after 'try'.");
+        let body = self.block();
+
+        let catch = self.match_tok(TokenKind::Catch).then(|| {
+            self.consume(TokenKind::LeftParen, "Expect '(' after 'catch'.");
+            self.consume(TokenKind::Identifier, "Expect exception variable name.");
+            let name = self.prev.msg.to_string();
+            self.consume(
+                TokenKind::RightParen,
+                "Expect ')' after exception variable.",
+            );
+            self.consume(TokenKind::LeftBrace, "Expect '{' after catch clause.");
+            (name, self.block())
+        });
+        let finally = self.match_tok(TokenKind::Finally).then(|| {
+            self.consume(TokenKind::LeftBrace, "Expect '{' after 'finally'.");
+            self.block()
+        });
+        if catch.is_none() && finally.is_none() {
+            self.error_at_current("Expect 'catch' or 'finally' after try block.");
+        }
+
+        StmtKind::Try {
+            body,
+            catch,
+            finally,
+        }
+    }
+
+    /// The statements up to the `}` closing a block whose `{` was just consumed
+    fn block(&mut self) -> Block {
+        let mut stmts = vec![];
+        while !self.check(TokenKind::RightBrace) && !self.check(TokenKind::Eof) {
+            stmts.extend(self.declaration());
+        }
+        self.consume(TokenKind::RightBrace, "Expect '}' after block.");
+        Block {
+            stmts,
+            end_line: self.prev.line,
+        }
+    }
+
+    fn expression(&mut self) -> Expr {
+        self.parse_precedence(Precedence::Assignment)
+    }
+
+    fn parse_precedence(&mut self, precedence: Precedence) -> Expr {
+        self.advance();
+        let can_assign = precedence as u8 <= Precedence::Assignment as u8;
+        let Some(mut expr) = self.prefix(can_assign) else {
+            self.error("Expect expression");
+            return self.expr(self.prev, ExprKind::Nil);
+        };
+
+        while precedence as u8 <= Parser::get_rule(self.cur.kind).precedence as u8 {
+            self.advance();
+            expr = self.infix(expr, can_assign);
+        }
+
+        if can_assign
+            && (self.match_tok(TokenKind::Equal)
+                || self.match_compound_assignment().is_some()
+                || self.match_increment().is_some())
+        {
+            self.error("Invalid assignment target.");
+        }
+        expr
+    }
+
+    fn expr(&self, start: Token, kind: ExprKind) -> Expr {
+        Expr {
+            line: start.line,
+            column: start.column,
+            kind,
+        }
+    }
+
+    /// The expression starting with the token just consumed, `None` if no expression starts
+    /// with it
+    fn prefix(&mut self, can_assign: bool) -> Option<Expr> {
+        let token = self.prev;
+        let kind = match token.kind {
+            TokenKind::LeftParen => {
+                let inner = self.expression();
+                self.consume(TokenKind::RightParen, "Expect ')' after expression.");
+                ExprKind::Grouping(Box::new(inner))
+            }
+            TokenKind::LeftBrace => self.map(),
+            TokenKind::LeftBracket => {
+                let mut elements = vec![];
+                while !self.check(TokenKind::RightBracket) {
+                    elements.push(self.expression());
+                    if !self.match_tok(TokenKind::Comma) {
+                        break;
+                    }
+                }
+                self.consume(TokenKind::RightBracket, "Expect ']' after list elements.");
+                ExprKind::List(elements)
+            }
+            TokenKind::Minus | TokenKind::Bang | TokenKind::Tilde | TokenKind::Await => {
+                let op = match token.kind {
+                    TokenKind::Minus => UnaryOp::Negate,
+                    TokenKind::Bang => UnaryOp::Not,
+                    TokenKind::Tilde => UnaryOp::BitNot,
+                    _ => UnaryOp::Await,
+                };
+                let operand = Box::new(self.parse_precedence(Precedence::Unary));
+                ExprKind::Unary { op, operand }
+            }
+            TokenKind::PlusPlus | TokenKind::MinusMinus => {
+                self.consume(
+                    TokenKind::Identifier,
+                    "Expect variable name after increment or decrement.",
+                );
+                ExprKind::Increment {
+                    name: self.prev.msg.to_string(),
+                    op: match token.kind {
+                        TokenKind::PlusPlus => BinaryOp::Add,
+                        _ => BinaryOp::Subtract,
+                    },
+                    prefix: true,
+                }
+            }
+            TokenKind::Identifier => return Some(self.variable(can_assign)),
+            TokenKind::String => ExprKind::String(token.msg[1..token.msg.len() - 1].to_string()),
+            TokenKind::Interpolation => self.interpolation(),
+            TokenKind::Number => ExprKind::Number(token.msg.to_string()),
+            TokenKind::True => ExprKind::Bool(true),
+            TokenKind::False => ExprKind::Bool(false),
+            TokenKind::Nil => ExprKind::Nil,
+            TokenKind::Fun => ExprKind::Lambda(Box::new(self.function(None))),
+            TokenKind::This => ExprKind::This,
+            TokenKind::Super => {
+                self.consume(TokenKind::Dot, "Expect '.' after 'super'.");
+                self.consume(TokenKind::Identifier, "Expect superclass method name.");
+                ExprKind::Super(self.prev.msg.to_string())
+            }
+            _ => return None,
+        };
+        Some(self.expr(token, kind))
+    }
+
+    fn variable(&mut self, can_assign: bool) -> Expr {
+        let token = self.prev;
+        let name = token.msg.to_string();
+        let variable = self.expr(token, ExprKind::Variable(name.clone()));
+        if let Some(assign) = self.assignment(&variable, can_assign, true) {
+            assign
+        } else if let Some(op) = self.match_increment() {
+            let kind = ExprKind::Increment {
+                name,
+                op,
+                prefix: false,
+            };
+            self.expr(token, kind)
+        } else {
+            variable
+        }
+    }
+
+    /// An assignment to `target` if one follows. `compound` if the target takes `+=` and the
+    /// other operators too
+    fn assignment(&mut self, target: &Expr, can_assign: bool, compound: bool) -> Option<Expr> {
+        if !can_assign {
+            return None;
+        }
+        let op = if self.match_tok(TokenKind::Equal) {
+            None
+        } else if compound && let Some(op) = self.match_compound_assignment() {
+            Some(op)
+        } else {
+            return None;
+        };
+        let value = self.expression();
+        let kind = ExprKind::Assign {
+            target: Box::new(target.clone()),
+            op,
+            value: Box::new(value),
+        };
+        Some(Expr { kind, ..*target })
+    }
+
+    fn interpolation(&mut self) -> ExprKind {
+        let mut parts = vec![];
+        loop {
+            let part = self.prev.msg;
+            // get rid of the `"` or `}` before and the `${` after
+            let text = part[1..part.len() - 2].to_string();
+            parts.push((text, self.expression()));
+            if !self.match_tok(TokenKind::InterpolationMiddle) {
+                break;
+            }
+        }
+
+        let mut tail = String::new();
+        if self.match_tok(TokenKind::InterpolationEnd) {
+            let string = self.prev.msg;
+            tail.push_str(&string[1..string.len() - 1]);
+        } else {
+            self.error_at_current("Expect end of string interpolation.");
+        }
+        ExprKind::Interpolation { parts, tail }
+    }
+
+    fn map(&mut self) -> ExprKind {
+        let mut entries = vec![];
+        while !self.check(TokenKind::RightBrace) {
+            let key = self.expression();
+            self.consume(TokenKind::Colon, "Expect ':' after map key.");
+            entries.push((key, self.expression()));
+            if !self.match_tok(TokenKind::Comma) {
+                break;
+            }
+        }
+        self.consume(TokenKind::RightBrace, "Expect '}' after map entries.");
+        ExprKind::Map(entries)
+    }
+
+    /// The rest of the expression whose operator was just consumed, with `left` before it
+    fn infix(&mut self, left: Expr, can_assign: bool) -> Expr {
+        let token = self.prev;
+        // The expression starts where its left operand does
+        let start = Token {
+            line: left.line,
+            column: left.column,
+            ..token
+        };
+        let left = Box::new(left);
+        let kind = match token.kind {
+            TokenKind::LeftParen => ExprKind::Call {
+                callee: left,
+                args: self.arguments(),
+            },
+            TokenKind::LeftBracket => return self.index(left, start, can_assign),
+            TokenKind::Dot => {
+                self.consume(TokenKind::Identifier, "Expect property name after '.'.");
+                let get = ExprKind::Get {
+                    object: left,
+                    name: self.prev.msg.to_string(),
+                };
+                let get = self.expr(start, get);
+                return self.assignment(&get, can_assign, true).unwrap_or(get);
+            }
+            TokenKind::Question => {
+                let then = Box::new(self.expression());
+                self.consume(
+                    TokenKind::Colon,
+                    "Expect ':' after then branch of conditional expression.",
+                );
+                // Right-associative
+                let otherwise = Box::new(self.parse_precedence(Precedence::Conditional));
+                ExprKind::Conditional {
+                    condition: left,
+                    then,
+                    otherwise,
+                }
+            }
+            TokenKind::And | TokenKind::Or => {
+                let op = match token.kind {
+                    TokenKind::And => LogicalOp::And,
+                    _ => LogicalOp::Or,
+                };
+                let right = Box::new(self.parse_precedence(Precedence::And));
+                ExprKind::Logical { left, op, right }
+            }
+            kind => {
+                let op = BinaryOp::from_token(kind).unwrap();
+                // `**` is right-associative, the others are left-associative
+                let precedence = Parser::get_rule(kind).precedence as u8;
+                let precedence = match op {
+                    BinaryOp::Power => Precedence::Exponent,
+                    _ => Precedence::from_u8(precedence + 1).unwrap(),
+                };
+                let right = Box::new(self.parse_precedence(precedence));
+                ExprKind::Binary { left, op, right }
+            }
+        };
+        Expr {
+            kind,
+            ..self.expr(start, ExprKind::Nil)
+        }
+    }
+
+    fn arguments(&mut self) -> Vec<Argument> {
+        let mut args = vec![];
+        if !self.check(TokenKind::RightParen) {
+            loop {
+                let mut name = None;
+                if self.check(TokenKind::Identifier) && self.peek_next_kind() == TokenKind::Colon {
+                    self.advance();
+                    name = Some(self.prev.msg.to_string());
+                    self.advance();
+                } else if args.iter().any(|arg: &Argument| arg.name.is_some()) {
+                    self.error_at_current("Positional arguments must come before named ones.");
+                }
+                let value = self.expression();
+                args.push(Argument { name, value });
+
+                if !self.match_tok(TokenKind::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenKind::RightParen, "Expect ')' after arguments.");
+        args
+    }
+
+    fn index(&mut self, object: Box<Expr>, start: Token, can_assign: bool) -> Expr {
+        let token = start;
+        // Either end of a slice can be left out
+        let start = (!self.check(TokenKind::Colon)).then(|| Box::new(self.expression()));
+        let index = match start {
+            Some(index) if !self.check(TokenKind::Colon) => index,
+            start => {
+                self.advance();
+                let end =
+                    (!self.check(TokenKind::RightBracket)).then(|| Box::new(self.expression()));
+                self.consume(TokenKind::RightBracket, "Expect ']' after slice.");
+                let kind = ExprKind::Slice { object, start, end };
+                return self.expr(token, kind);
+            }
+        };
+        self.consume(TokenKind::RightBracket, "Expect ']' after index.");
+
+        let get = self.expr(token, ExprKind::Index { object, index });
+        self.assignment(&get, can_assign, false).unwrap_or(get)
+    }
+
+    fn match_increment(&mut self) -> Option<BinaryOp> {
+        let op = match self.cur.kind {
+            TokenKind::PlusPlus => BinaryOp::Add,
+            TokenKind::MinusMinus => BinaryOp::Subtract,
+            _ => return None,
+        };
+        self.advance();
+        Some(op)
+    }
+
+    fn match_compound_assignment(&mut self) -> Option<BinaryOp> {
+        let op = match self.cur.kind {
+            TokenKind::PlusEqual => BinaryOp::Add,
+            TokenKind::MinusEqual => BinaryOp::Subtract,
+            TokenKind::StarEqual => BinaryOp::Multiply,
+            TokenKind::SlashEqual => BinaryOp::Divide,
+            _ => return None,
+        };
+        self.advance();
+        Some(op)
+    }
+
+    fn synchronize(&mut self) {
+        self.panic_mode = false;
+
+        while self.cur.kind != TokenKind::Eof {
+            if self.prev.kind == TokenKind::Semicolon {
+                return;
+            }
+
+            use TokenKind::*;
+            match self.cur.kind {
+                Assert | Class | Const | Fun | Var | For | If | Import | While | Print | Return
+                | Switch | Throw | Try | Yield => return,
+                _ => (),
+            }
+
+            self.advance()
+        }
+    }
+
+    fn advance(&mut self) {
+        self.prev = self.cur;
+        loop {
+            self.cur = self.scanner.token();
+            if self.cur.kind != TokenKind::Error {
+                break;
+            }
+            self.error_at(self.cur, self.cur.msg);
+        }
+    }
+
+    fn check(&self, kind: TokenKind) -> bool {
+        self.cur.kind == kind
+    }
+
+    fn check_word(&self, word: &str) -> bool {
+        self.check(TokenKind::Identifier) && self.cur.msg == word
+    }
+
+    fn peek_next_kind(&self) -> TokenKind {
+        self.scanner.clone().token().kind
+    }
+
+    fn match_tok(&mut self, kind: TokenKind) -> bool {
+        let matches = self.check(kind);
+        if matches {
+            self.advance();
+        }
+        matches
+    }
+
+    fn consume(&mut self, kind: TokenKind, msg: &str) {
+        if !self.match_tok(kind) {
+            self.error_at_current(msg);
+        }
+    }
+
+    fn error_at_current(&mut self, msg: &str) {
+        self.error_at(self.cur, msg)
+    }
+
+    fn error(&mut self, msg: &str) {
+        self.error_at(self.prev, msg)
+    }
+
+    fn error_at(&mut self, token: Token, msg: &str) {
+        if self.panic_mode {
+            return;
+        }
+        self.panic_mode = true;
+        let error = CompileError::new(&self.scanner, token, msg);
+        self.errors.push(error);
+    }
+}
+
+/// A comment, which the tree leaves out, see [`comments`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comment<'src> {
+    /// With the `//` or `/* */`, or a `#!` line at the start
+    pub text: &'src str,
+    pub line: u32,
+    /// Of the start, in bytes and 1-based like the tokens'
+    pub column: u32,
+    /// Differs from `line` for block comments over several lines
+    pub end_line: u32,
+    /// Whether there's code before it on its line
+    pub trailing: bool,
+}
+
+/// The comments in `src`, which has no syntax errors
+pub fn comments(src: &str) -> Vec<Comment> {
+    let mut comments = vec![];
+    let mut scanner = Scanner::new(src);
+    let mut line = 1;
+    let mut line_start = 0;
+    // Where the previous token ends
+    let mut end = 0;
+    loop {
+        let token = scanner.token();
+        let gap = &src[end..scanner.start];
+        let mut trailing = end > 0;
+        let mut i = 0;
+        while i < gap.len() {
+            let rest = &gap[i..];
+            let len = if rest.starts_with("//") {
+                rest.find('\n').unwrap_or(rest.len())
+            } else if rest.starts_with("/*") {
+                block_comment_len(rest)
+            } else if end + i == 0 && shebang_len(rest) > 0 {
+                shebang_len(rest)
+            } else {
+                if rest.starts_with('\n') {
+                    line += 1;
+                    line_start = end + i + 1;
+                    trailing = false;
+                }
+                i += 1;
+                continue;
+            };
+
+            let text = rest[..len].trim_end();
+            let end_line = line + text.matches('\n').count() as u32;
+            comments.push(Comment {
+                text,
+                line,
+                column: (end + i - line_start) as u32 + 1,
+                end_line,
+                trailing,
+            });
+            if let Some(newline) = text.rfind('\n') {
+                line_start = end + i + newline + 1;
+            }
+            line = end_line;
+            trailing = true;
+            i += len;
+        }
+
+        if token.kind == TokenKind::Eof {
+            return comments;
+        }
+        let lexeme = &src[scanner.start..scanner.current];
+        line += lexeme.matches('\n').count() as u32;
+        if let Some(newline) = lexeme.rfind('\n') {
+            line_start = scanner.start + newline + 1;
+        }
+        end = scanner.current;
+    }
+}
+
+/// Length of the block comment `src` starts with, they can be nested
+pub(super) fn block_comment_len(src: &str) -> usize {
+    let src = src.as_bytes();
+    let mut depth = 0;
+    let mut i = 0;
+    while i < src.len() {
+        if src[i..].starts_with(b"/*") {
+            depth += 1;
+            i += 2;
+        } else if src[i..].starts_with(b"*/") {
+            depth -= 1;
+            i += 2;
+            if depth == 0 {
+                break;
+            }
+        } else {
+            i += 1;
+        }
+    }
+    i
+}
+
+/// A node of the tree as [`to_json`] and [`to_sexp`] see it
+enum Node {
+    /// The type, the position if it has one and the fields
+    Object(&'static str, Option<(u32, u32)>, Vec<(&'static str, Node)>),
+    List(Vec<Node>),
+    /// An identifier or an operator
+    Name(String),
+    String(String),
+    /// A number literal as written
+    Number(String),
+    Bool(bool),
+    None,
+}
+
+impl Node {
+    fn stmt(stmt: &Stmt, fields: Vec<(&'static str, Node)>, kind: &'static str) -> Self {
+        Node::Object(kind, Some((stmt.line, stmt.column)), fields)
+    }
+
+    fn stmts(stmts: &[Stmt]) -> Self {
+        Node::List(stmts.iter().map(Node::from).collect())
+    }
+
+    fn expr(expr: &Expr) -> Self {
+        expr.into()
+    }
+
+    fn name(name: &str) -> Self {
+        Node::Name(name.to_string())
+    }
+
+    fn option<T>(value: &Option<T>, node: impl FnOnce(&T) -> Node) -> Self {
+        value.as_ref().map_or(Node::None, node)
+    }
+}
+
+impl From<&Stmt> for Node {
+    fn from(stmt: &Stmt) -> Self {
+        let (kind, fields) = match &stmt.kind {
+            StmtKind::Expression(value) => ("Expression", vec![("value", value.into())]),
+            StmtKind::Print(value) => ("Print", vec![("value", value.into())]),
+            StmtKind::Var { name, init } => (
+                "Var",
+                vec![
+                    ("name", Node::name(name)),
+                    ("init", Node::option(init, Node::expr)),
+                ],
+            ),
+            StmtKind::Const { name, init } => (
+                "Const",
+                vec![("name", Node::name(name)), ("init", init.into())],
+            ),
+            StmtKind::Function(function) => return function.into(),
+            StmtKind::Class(class) => {
+                let members = class.members.iter().map(Node::from).collect();
+                (
+                    "Class",
+                    vec![
+                        ("name", Node::name(&class.name)),
+                        (
+                            "superclass",
+                            Node::option(&class.superclass, |s| Node::name(s)),
+                        ),
+                        ("members", Node::List(members)),
+                    ],
+                )
+            }
+            StmtKind::Import { name, native, path } => (
+                "Import",
+                vec![
+                    ("name", Node::option(name, |name| Node::name(name))),
+                    ("native", Node::Bool(*native)),
+                    ("path", Node::String(path.clone())),
+                ],
+            ),
+            StmtKind::Block(block) => ("Block", vec![("body", Node::stmts(&block.stmts))]),
+            StmtKind::If {
+                condition,
+                then,
+                otherwise,
+            } => (
+                "If",
+                vec![
+                    ("condition", condition.into()),
+                    ("then", then.as_ref().into()),
+                    ("else", Node::option(otherwise, |stmt| stmt.as_ref().into())),
+                ],
+            ),
+            StmtKind::While { condition, body } => (
+                "While",
+                vec![
+                    ("condition", condition.into()),
+                    ("body", body.as_ref().into()),
+                ],
+            ),
+            StmtKind::For {
+                init,
+                condition,
+                increment,
+                body,
+            } => (
+                "For",
+                vec![
+                    ("init", Node::option(init, |stmt| stmt.as_ref().into())),
+                    ("condition", Node::option(condition, Node::expr)),
+                    ("increment", Node::option(increment, Node::expr)),
+                    ("body", body.as_ref().into()),
+                ],
+            ),
+            StmtKind::ForIn {
+                item,
+                collection,
+                body,
+            } => (
+                "ForIn",
+                vec![
+                    ("item", Node::name(item)),
+                    ("collection", collection.into()),
+                    ("body", body.as_ref().into()),
+                ],
+            ),
+            StmtKind::Switch {
+                value,
+                cases,
+                default,
+            } => {
+                let cases = cases.iter().map(|(value, body)| {
+                    let fields = vec![("value", value.into()), ("body", Node::stmts(body))];
+                    Node::Object("Case", None, fields)
+                });
+                (
+                    "Switch",
+                    vec![
+                        ("value", value.into()),
+                        ("cases", Node::List(cases.collect())),
+                        ("default", Node::option(default, |body| Node::stmts(body))),
+                    ],
+                )
+            }
+            StmtKind::Break => ("Break", vec![]),
+            StmtKind::Continue => ("Continue", vec![]),
+            StmtKind::Return(value) => ("Return", vec![("value", Node::option(value, Node::expr))]),
+            StmtKind::Yield(value) => ("Yield", vec![("value", Node::option(value, Node::expr))]),
+            StmtKind::Throw(value) => ("Throw", vec![("value", value.into())]),
+            StmtKind::Try {
+                body,
+                catch,
+                finally,
+            } => {
+                let catch = Node::option(catch, |(name, body)| {
+                    let fields = vec![
+                        ("name", Node::name(name)),
+                        ("body", Node::stmts(&body.stmts)),
+                    ];
+                    Node::Object("Catch", None, fields)
+                });
+                (
+                    "Try",
+                    vec![
+                        ("body", Node::stmts(&body.stmts)),
+                        ("catch", catch),
+                        (
+                            "finally",
+                            Node::option(finally, |body| Node::stmts(&body.stmts)),
+                        ),
+                    ],
+                )
+            }
+            StmtKind::Assert(value) => ("Assert", vec![("value", value.into())]),
+        };
+        Node::stmt(stmt, fields, kind)
+    }
+}
+
+impl From<&Function> for Node {
+    fn from(function: &Function) -> Self {
+        let params = function.params.iter().map(|param| {
+            let fields = vec![
+                ("name", Node::name(&param.name)),
+                ("default", Node::option(&param.default, Node::expr)),
+                ("rest", Node::Bool(param.rest)),
+            ];
+            Node::Object("Param", None, fields)
+        });
+        let fields = vec![
+            (
+                "name",
+                Node::option(&function.name, |name| Node::name(name)),
+            ),
+            ("params", Node::List(params.collect())),
+            ("body", Node::stmts(&function.body.stmts)),
+        ];
+        Node::Object("Function", Some((function.line, function.column)), fields)
+    }
+}
+
+impl From<&Member> for Node {
+    fn from(member: &Member) -> Self {
+        let (kind, fields) = match member {
+            Member::Method {
+                is_static,
+                function,
+            } => (
+                "Method",
+                vec![
+                    ("static", Node::Bool(*is_static)),
+                    ("function", function.into()),
+                ],
+            ),
+            Member::Getter(function) => ("Getter", vec![("function", function.into())]),
+            Member::Setter(function) => ("Setter", vec![("function", function.into())]),
+            Member::Field {
+                is_static,
+                name,
+                init,
+                ..
+            } => (
+                "Field",
+                vec![
+                    ("static", Node::Bool(*is_static)),
+                    ("name", Node::name(name)),
+                    ("init", Node::option(init, Node::expr)),
+                ],
+            ),
+        };
+        Node::Object(kind, None, fields)
+    }
+}
+
+impl From<&Expr> for Node {
+    fn from(expr: &Expr) -> Self {
+        let (kind, fields) = match &expr.kind {
+            ExprKind::Nil => ("Nil", vec![]),
+            ExprKind::Bool(value) => ("Bool", vec![("value", Node::Bool(*value))]),
+            ExprKind::Number(literal) => ("Number", vec![("value", Node::Number(literal.clone()))]),
+            ExprKind::String(string) => ("String", vec![("value", Node::String(string.clone()))]),
+            ExprKind::Interpolation { parts, tail } => {
+                let parts = parts
+                    .iter()
+                    .flat_map(|(text, expr)| [Node::String(text.clone()), Node::from(expr)]);
+                let parts = parts.chain([Node::String(tail.clone())]);
+                (
+                    "Interpolation",
+                    vec![("parts", Node::List(parts.collect()))],
+                )
+            }
+            ExprKind::Variable(name) => ("Variable", vec![("name", Node::name(name))]),
+            ExprKind::This => ("This", vec![]),
+            ExprKind::Super(method) => ("Super", vec![("method", Node::name(method))]),
+            ExprKind::Grouping(inner) => ("Grouping", vec![("expression", Node::expr(inner))]),
+            ExprKind::Unary { op, operand } => (
+                "Unary",
+                vec![
+                    ("operator", Node::name(op.symbol())),
+                    ("operand", Node::expr(operand)),
+                ],
+            ),
+            ExprKind::Binary { left, op, right } => (
+                "Binary",
+                vec![
+                    ("operator", Node::name(op.symbol())),
+                    ("left", Node::expr(left)),
+                    ("right", Node::expr(right)),
+                ],
+            ),
+            ExprKind::Logical { left, op, right } => (
+                "Logical",
+                vec![
+                    ("operator", Node::name(op.symbol())),
+                    ("left", Node::expr(left)),
+                    ("right", Node::expr(right)),
+                ],
+            ),
+            ExprKind::Conditional {
+                condition,
+                then,
+                otherwise,
+            } => (
+                "Conditional",
+                vec![
+                    ("condition", Node::expr(condition)),
+                    ("then", Node::expr(then)),
+                    ("else", Node::expr(otherwise)),
+                ],
+            ),
+            ExprKind::Assign { target, op, value } => {
+                let op = op.map_or("=".to_string(), |op| format!("{}=", op.symbol()));
+                (
+                    "Assign",
+                    vec![
+                        ("operator", Node::Name(op)),
+                        ("target", Node::expr(target)),
+                        ("value", Node::expr(value)),
+                    ],
+                )
+            }
+            ExprKind::Increment { name, op, prefix } => {
+                let op = op.symbol().repeat(2);
+                (
+                    "Increment",
+                    vec![
+                        ("operator", Node::Name(op)),
+                        ("name", Node::name(name)),
+                        ("prefix", Node::Bool(*prefix)),
+                    ],
+                )
+            }
+            ExprKind::Call { callee, args } => {
+                let args = args.iter().map(|arg| match &arg.name {
+                    Some(name) => {
+                        let fields = vec![
+                            ("name", Node::name(name)),
+                            ("value", Node::expr(&arg.value)),
+                        ];
+                        Node::Object("Named", None, fields)
+                    }
+                    None => Node::expr(&arg.value),
+                });
+                (
+                    "Call",
+                    vec![
+                        ("callee", Node::expr(callee)),
+                        ("args", Node::List(args.collect())),
+                    ],
+                )
+            }
+            ExprKind::Get { object, name } => (
+                "Get",
+                vec![("object", Node::expr(object)), ("name", Node::name(name))],
+            ),
+            ExprKind::Index { object, index } => (
+                "Index",
+                vec![("object", Node::expr(object)), ("index", Node::expr(index))],
+            ),
+            ExprKind::Slice { object, start, end } => (
+                "Slice",
+                vec![
+                    ("object", Node::expr(object)),
+                    ("start", Node::option(start, |start| Node::expr(start))),
+                    ("end", Node::option(end, |end| Node::expr(end))),
+                ],
+            ),
+            ExprKind::List(elements) => (
+                "List",
+                vec![(
+                    "elements",
+                    Node::List(elements.iter().map(Node::from).collect()),
+                )],
+            ),
+            ExprKind::Map(entries) => {
+                let entries = entries.iter().map(|(key, value)| {
+                    let fields = vec![("key", Node::expr(key)), ("value", Node::expr(value))];
+                    Node::Object("Entry", None, fields)
+                });
+                ("Map", vec![("entries", Node::List(entries.collect()))])
+            }
+            ExprKind::Lambda(function) => return function.as_ref().into(),
+        };
+        Node::Object(kind, Some((expr.line, expr.column)), fields)
+    }
+}
+
+/// `string` quoted with the escapes JSON needs
+fn quote(string: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in string.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c if c.is_control() => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// The tree as a JSON array of statements. Every statement and expression is an object with
+/// its `type`, `line` and `column`, and its fields by name
+pub fn to_json(stmts: &[Stmt]) -> String {
+    let mut out = String::new();
+    write_json(&mut out, &Node::stmts(stmts), 0);
+    out.push('\n');
+    out
+}
+
+fn write_json(out: &mut String, node: &Node, indent: usize) {
+    let pad = |out: &mut String, indent: usize| {
+        out.push('\n');
+        out.extend(std::iter::repeat(' ').take(indent));
+    };
+    match node {
+        Node::Object(kind, position, fields) => {
+            out.push('{');
+            pad(out, indent + 2);
+            let _ = write!(out, "\"type\": {}", quote(kind));
+            if let Some((line, column)) = position {
+                let _ = write!(out, ",");
+                pad(out, indent + 2);
+                let _ = write!(out, "\"line\": {line}, \"column\": {column}");
+            }
+            for (name, field) in fields {
+                out.push(',');
+                pad(out, indent + 2);
+                let _ = write!(out, "{}: ", quote(name));
+                write_json(out, field, indent + 2);
+            }
+            pad(out, indent);
+            out.push('}');
+        }
+        Node::List(nodes) if nodes.is_empty() => out.push_str("[]"),
+        Node::List(nodes) => {
+            out.push('[');
+            for (i, node) in nodes.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                pad(out, indent + 2);
+                write_json(out, node, indent + 2);
+            }
+            pad(out, indent);
+            out.push(']');
+        }
+        Node::Name(string) | Node::String(string) => out.push_str(&quote(string)),
+        Node::Number(literal) => match parse_number(literal) {
+            value if value.is_finite() => {
+                let _ = write!(out, "{value}");
+            }
+            _ => out.push_str("null"),
+        },
+        Node::Bool(value) => {
+            let _ = write!(out, "{value}");
+        }
+        Node::None => out.push_str("null"),
+    }
+}
+
+/// Longest line [`to_sexp`] prints a list on before it puts each element on a line of its own
+const SEXP_WIDTH: usize = 80;
+
+/// The tree as s-expressions, one for each statement, like `(var x (+ 1 2))`. Operators lead
+/// their operands, literals and variables are printed as themselves and a missing part as `()`
+pub fn to_sexp(stmts: &[Stmt]) -> String {
+    let mut out = String::new();
+    for stmt in stmts {
+        write_sexp(&mut out, &Node::from(stmt), 0);
+        out.push('\n');
+    }
+    out
+}
+
+/// The head and elements of `node` as a list, `None` for atoms
+fn sexp_list(node: &Node) -> Option<(String, Vec<&Node>)> {
+    match node {
+        Node::Object(kind, _, fields) => {
+            let mut fields = fields.iter().map(|(_, field)| field).peekable();
+            let head = match fields.peek() {
+                Some(Node::Name(op))
+                    if matches!(
+                        *kind,
+                        "Unary" | "Binary" | "Logical" | "Assign" | "Increment"
+                    ) =>
+                {
+                    let op = op.clone();
+                    fields.next();
+                    op
+                }
+                _ => {
+                    // `ForIn` is `for-in`
+                    let mut head = String::new();
+                    for (i, c) in kind.chars().enumerate() {
+                        if c.is_uppercase() && i > 0 {
+                            head.push('-');
+                        }
+                        head.push(c.to_ascii_lowercase());
+                    }
+                    head
+                }
+            };
+            Some((head, fields.collect()))
+        }
+        Node::List(nodes) => Some((String::new(), nodes.iter().collect())),
+        _ => None,
+    }
+}
+
+fn sexp_inline(node: &Node) -> String {
+    match node {
+        // Literals and variables are only their value
+        Node::Object("Nil" | "This" | "Bool" | "Number" | "String" | "Variable", _, fields) => {
+            match fields.first() {
+                Some((_, field)) => sexp_inline(field),
+                None => sexp_list(node).unwrap().0,
+            }
+        }
+        Node::Name(name) | Node::Number(name) => name.clone(),
+        Node::String(string) => quote(string),
+        Node::Bool(value) => value.to_string(),
+        Node::None => "()".to_string(),
+        _ => {
+            let (head, elements) = sexp_list(node).unwrap();
+            let elements = elements.into_iter().map(sexp_inline);
+            let parts: Vec<String> = (!head.is_empty())
+                .then_some(head)
+                .into_iter()
+                .chain(elements)
+                .collect();
+            format!("({})", parts.join(" "))
+        }
+    }
+}
+
+fn write_sexp(out: &mut String, node: &Node, indent: usize) {
+    let inline = sexp_inline(node);
+    let Some((head, elements)) = sexp_list(node) else {
+        out.push_str(&inline);
+        return;
+    };
+    if indent + inline.len() <= SEXP_WIDTH || !inline.starts_with('(') {
+        out.push_str(&inline);
+        return;
+    }
+
+    // Elements of a list line up, the ones after a head are indented below it
+    let indent = if head.is_empty() {
+        indent + 1
+    } else {
+        indent + 2
+    };
+    out.push('(');
+    out.push_str(&head);
+    for (i, element) in elements.into_iter().enumerate() {
+        if i > 0 || !head.is_empty() {
+            out.push('\n');
+            out.extend(std::iter::repeat(' ').take(indent));
+        }
+        write_sexp(out, element, indent);
+    }
+    out.push(')');
+}
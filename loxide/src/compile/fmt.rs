@@ -0,0 +1,1020 @@
+//! `loxide fmt`: print a script back in one canonical layout.
+//!
+//! The script is parsed into its [syntax tree](super::ast) and printed from that, with two
+//! spaces of indentation, one statement per line and the bodies of `if`s and loops that aren't
+//! blocks on the line of their header. Blank lines between statements are kept, but never more
+//! than one in a row. An expression that doesn't fit in [`WIDTH`] columns puts each argument of
+//! its calls and each element of its lists and maps on a line of its own, except for a lambda
+//! passed last, which stays on the line of the call.
+//!
+//! The tree has no comments. They're picked up from between the tokens and printed in front of
+//! the statement they were in front of, or at the end of the line they ended. A comment at the
+//! end of a line with several statements goes behind the last of them. A comment between the
+//! arguments of a call or the elements of a list or map breaks it over lines and stays behind
+//! the item it followed, or in front of the one it was in front of. Any other comment in the
+//! middle of an expression moves behind its statement.
+
+use super::{
+    ast::{
+        self, Argument, BinaryOp, Block, Class, Comment, Expr, ExprKind, Function, LogicalOp,
+        Member, Stmt, StmtKind, UnaryOp,
+    },
+    lexer::Lexer,
+    CompileError, Precedence, TokenKind,
+};
+
+/// Longest line the formatter makes, unless a single token doesn't fit
+pub const WIDTH: usize = 100;
+
+/// `src` in the canonical layout, or its syntax errors
+pub fn format(src: &str) -> Result<String, Vec<CompileError>> {
+    let stmts = ast::parse(src)?;
+    let mut printer = Printer {
+        out: String::new(),
+        indent: 0,
+        comments: ast::comments(src),
+        next_comment: 0,
+        tokens: Lexer::new(src)
+            .tokens()
+            .filter_map(|lexeme| Some(((lexeme.span.line, lexeme.span.column), lexeme.kind?)))
+            .collect(),
+        last_line: 0,
+        block_start: true,
+        block_end: u32::MAX,
+        next_line: u32::MAX,
+    };
+    printer.stmts(&stmts);
+    printer.comments_before(u32::MAX);
+    Ok(printer.out)
+}
+
+struct Printer<'src> {
+    out: String,
+    /// Levels of indentation of the current line
+    indent: usize,
+    comments: Vec<Comment<'src>>,
+    /// Index of the first comment that hasn't been printed
+    next_comment: usize,
+    /// Where each token starts, to find the brackets around the comments
+    tokens: Vec<((u32, u32), TokenKind)>,
+    /// Last line of the source that has been printed, to tell where the blank lines were
+    last_line: u32,
+    /// Whether nothing has been printed in the current block yet, which never starts with a
+    /// blank line
+    block_start: bool,
+    /// Line of the `}` of the innermost block, comments from there on come after it
+    block_end: u32,
+    /// Line the statement or member after the one being printed starts on, the comments at the
+    /// end of that line go behind the later one
+    next_line: u32,
+}
+
+impl Printer<'_> {
+    /// Add `text` to the current line
+    fn write(&mut self, text: &str) {
+        if self.at_line_start() && !text.is_empty() {
+            self.out.extend(std::iter::repeat("  ").take(self.indent));
+        }
+        self.out.push_str(text);
+    }
+
+    fn at_line_start(&self) -> bool {
+        self.out.is_empty() || self.out.ends_with('\n')
+    }
+
+    /// Column the next character goes to, counting from 0
+    fn column(&self) -> usize {
+        if self.at_line_start() {
+            return self.indent * 2;
+        }
+        let start = self.out.rfind('\n').map_or(0, |i| i + 1);
+        width(&self.out[start..])
+    }
+
+    /// End the current line, which ends at `line` in the source, with its comments. Comments
+    /// that were inside the code on the line follow on lines of their own
+    fn newline(&mut self, line: u32) {
+        let line = line.min(self.block_end - 1);
+        let comments_line = if line == self.next_line {
+            line - 1
+        } else {
+            line
+        };
+        while let Some(comment) = self.comments.get(self.next_comment)
+            && comment.trailing
+            && comment.line <= comments_line
+        {
+            self.out.push(' ');
+            self.out.push_str(comment.text);
+            self.next_comment += 1;
+            if comment.text.starts_with("//") {
+                break;
+            }
+        }
+        self.out.push('\n');
+        self.last_line = self.last_line.max(line);
+
+        while let Some(comment) = self.comments.get(self.next_comment)
+            && comment.line <= comments_line
+        {
+            self.comment();
+        }
+    }
+
+    /// End the line of a `{` at `line`, whose contents start at `first`. The comments at the
+    /// end of the line go after the `{` if the contents start on a line of their own, otherwise
+    /// they were behind the contents and stay there
+    fn open(&mut self, line: u32, first: u32) {
+        if first > line {
+            self.newline(line);
+        } else {
+            self.line_break();
+        }
+    }
+
+    /// Break the line in the middle of an expression
+    fn line_break(&mut self) {
+        self.out.push('\n');
+    }
+
+    /// Print the comments that come before `line`, each on a line of its own
+    fn comments_before(&mut self, line: u32) {
+        while let Some(comment) = self.comments.get(self.next_comment)
+            && (comment.line < line || comment.line == line && !comment.trailing)
+        {
+            self.blank_line(comment.line);
+            self.comment();
+        }
+    }
+
+    fn has_comments_before(&self, line: u32) -> bool {
+        self.comments
+            .get(self.next_comment)
+            .map_or(false, |comment| comment.line < line)
+    }
+
+    /// Print the comments before `at` that haven't been printed, each on a line of its own
+    fn comments_in_front(&mut self, at: (u32, u32)) {
+        while let Some(comment) = self.comments.get(self.next_comment)
+            && (comment.line, comment.column) < at
+        {
+            self.comment();
+        }
+    }
+
+    /// Add the comments before `next` that had code in front of them to the current line, up to
+    /// the first `//` one
+    fn comments_behind(&mut self, next: (u32, u32)) {
+        while let Some(comment) = self.comments.get(self.next_comment)
+            && comment.trailing
+            && (comment.line, comment.column) < next
+        {
+            self.out.push(' ');
+            self.out.push_str(comment.text);
+            self.next_comment += 1;
+            if comment.text.starts_with("//") {
+                break;
+            }
+        }
+    }
+
+    /// The innermost brackets around `at`
+    fn brackets(&self, at: (u32, u32)) -> ((u32, u32), (u32, u32)) {
+        let next = self.tokens.partition_point(|&(start, _)| start < at);
+        let mut depth = 0;
+        let close = self.tokens[next..]
+            .iter()
+            .position(|&(_, kind)| {
+                depth += nesting(kind);
+                depth < 0
+            })
+            .unwrap();
+        depth = 0;
+        let open = self.tokens[..next]
+            .iter()
+            .rposition(|&(_, kind)| {
+                depth -= nesting(kind);
+                depth < 0
+            })
+            .unwrap();
+        (self.tokens[open].0, self.tokens[next + close].0)
+    }
+
+    /// Whether a comment that hasn't been printed is between `brackets`, and not inside other
+    /// brackets between them
+    fn has_comments_in(&self, (open, close): ((u32, u32), (u32, u32))) -> bool {
+        self.comments[self.next_comment..]
+            .iter()
+            .map(|comment| (comment.line, comment.column))
+            .take_while(|&at| at < close)
+            .any(|at| at > open && self.brackets(at).0 == open)
+    }
+
+    /// Whether a comment that hasn't been printed is between the arguments of a call or the
+    /// elements of a list or map in `expr`, which then has to be broken over lines to keep it
+    /// there
+    fn has_comments(&self, expr: &Expr) -> bool {
+        if self.next_comment == self.comments.len() {
+            return false;
+        }
+        let (first, children): (_, Vec<&Expr>) = match &expr.kind {
+            ExprKind::Interpolation { parts, .. } => {
+                (None, parts.iter().map(|(_, expr)| expr).collect())
+            }
+            ExprKind::Grouping(inner) => (None, vec![&**inner]),
+            ExprKind::Unary { operand, .. } => (None, vec![&**operand]),
+            ExprKind::Binary { left, right, .. } | ExprKind::Logical { left, right, .. } => {
+                (None, vec![&**left, &**right])
+            }
+            ExprKind::Conditional {
+                condition,
+                then,
+                otherwise,
+            } => (None, vec![&**condition, &**then, &**otherwise]),
+            ExprKind::Assign { target, value, .. } => (None, vec![&**target, &**value]),
+            ExprKind::Call { callee, args } => {
+                let args = args.iter().map(|arg| &arg.value);
+                (
+                    args.clone().next(),
+                    [&**callee].into_iter().chain(args).collect(),
+                )
+            }
+            ExprKind::Get { object, .. } => (None, vec![&**object]),
+            ExprKind::Index { object, index } => (None, vec![&**object, &**index]),
+            ExprKind::Slice { object, start, end } => (
+                None,
+                [object]
+                    .into_iter()
+                    .chain(start)
+                    .chain(end)
+                    .map(|expr| &**expr)
+                    .collect(),
+            ),
+            ExprKind::List(elements) => (elements.first(), elements.iter().collect()),
+            ExprKind::Map(entries) => (
+                entries.first().map(|(key, _)| key),
+                entries
+                    .iter()
+                    .flat_map(|(key, value)| [key, value])
+                    .collect(),
+            ),
+            _ => (None, vec![]),
+        };
+        first.map_or(false, |first| {
+            self.has_comments_in(self.brackets(start(first)))
+        }) || children.into_iter().any(|child| self.has_comments(child))
+    }
+
+    /// Print the next comment on a line of its own
+    fn comment(&mut self) {
+        let comment = &self.comments[self.next_comment];
+        let (text, end_line) = (comment.text, comment.end_line);
+        self.next_comment += 1;
+        self.write(text);
+        self.out.push('\n');
+        self.last_line = self.last_line.max(end_line);
+        self.block_start = false;
+    }
+
+    /// Keep the blank line before something at `line`, if there was one
+    fn blank_line(&mut self, line: u32) {
+        if !self.block_start && line > self.last_line + 1 {
+            self.out.push('\n');
+        }
+        self.block_start = false;
+    }
+
+    /// `stmts` one after the other
+    fn stmts(&mut self, stmts: &[Stmt]) {
+        let next_line = self.next_line;
+        for (i, stmt) in stmts.iter().enumerate() {
+            self.next_line = stmts.get(i + 1).map_or(next_line, |next| next.line);
+            self.stmt(stmt);
+        }
+        self.next_line = next_line;
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        self.comments_before(stmt.line);
+        self.blank_line(stmt.line);
+        self.stmt_line(stmt);
+    }
+
+    /// The statement from where the current line is at, up to the end of its last line
+    fn stmt_line(&mut self, stmt: &Stmt) {
+        match &stmt.kind {
+            StmtKind::Expression(value) => self.expr(value, 1),
+            StmtKind::Print(value) => {
+                self.write("print ");
+                self.expr(value, 1);
+            }
+            StmtKind::Var { name, init } => {
+                self.write(&format!("var {name}"));
+                if let Some(init) = init {
+                    self.write(" = ");
+                    self.expr(init, 1);
+                }
+            }
+            StmtKind::Const { name, init } => {
+                self.write(&format!("const {name} = "));
+                self.expr(init, 1);
+            }
+            StmtKind::Function(function) => {
+                self.write("fun ");
+                self.function(function);
+                return self.newline(stmt.end_line);
+            }
+            StmtKind::Class(class) => return self.class(stmt, class),
+            StmtKind::Import { name, native, path } => {
+                self.write("import ");
+                if let Some(name) = name {
+                    self.write(&format!("{name} from "));
+                }
+                if *native {
+                    self.write("native ");
+                }
+                self.write(&format!("\"{path}\""));
+            }
+            StmtKind::Block(block) => {
+                self.block(block, stmt.line);
+                return self.newline(stmt.end_line);
+            }
+            StmtKind::If {
+                condition,
+                then,
+                otherwise,
+            } => {
+                self.write("if (");
+                self.expr(condition, 3);
+                self.write(")");
+                let Some(otherwise) = otherwise else {
+                    return self.body(then, stmt.line);
+                };
+                // `else` goes after the `}` of a block, or on the next line
+                if let StmtKind::Block(block) = &then.kind {
+                    self.write(" ");
+                    self.block(block, stmt.line);
+                    self.write(" else");
+                } else {
+                    let next_line = std::mem::replace(&mut self.next_line, otherwise.line);
+                    self.body(then, stmt.line);
+                    self.next_line = next_line;
+                    self.write("else");
+                }
+                if let StmtKind::If { .. } = otherwise.kind {
+                    self.write(" ");
+                    return self.stmt_line(otherwise);
+                }
+                return self.body(otherwise, then.end_line);
+            }
+            StmtKind::While { condition, body } => {
+                self.write("while (");
+                self.expr(condition, 3);
+                self.write(")");
+                return self.body(body, stmt.line);
+            }
+            StmtKind::For {
+                init,
+                condition,
+                increment,
+                body,
+            } => {
+                self.write("for (");
+                match init.as_deref().map(|init| &init.kind) {
+                    Some(StmtKind::Var { name, init }) => {
+                        self.write(&format!("var {name}"));
+                        if let Some(init) = init {
+                            self.write(" = ");
+                            self.expr(init, 1);
+                        }
+                    }
+                    Some(StmtKind::Expression(init)) => self.expr(init, 1),
+                    _ => (),
+                }
+                self.write(";");
+                if let Some(condition) = condition {
+                    self.write(" ");
+                    self.expr(condition, 1);
+                }
+                self.write(";");
+                if let Some(increment) = increment {
+                    self.write(" ");
+                    self.expr(increment, 3);
+                }
+                self.write(")");
+                return self.body(body, stmt.line);
+            }
+            StmtKind::ForIn {
+                item,
+                collection,
+                body,
+            } => {
+                self.write(&format!("for ({item} in "));
+                self.expr(collection, 3);
+                self.write(")");
+                return self.body(body, stmt.line);
+            }
+            StmtKind::Switch {
+                value,
+                cases,
+                default,
+            } => {
+                self.write("switch (");
+                self.expr(value, 3);
+                self.write(") {");
+                let first = cases.first().map_or(stmt.end_line, |(value, _)| value.line);
+                self.open(stmt.line, first);
+                let block_end = self.enter(stmt.end_line);
+                for (value, body) in cases {
+                    self.comments_before(value.line);
+                    self.blank_line(value.line);
+                    self.write("case ");
+                    self.expr(value, 1);
+                    self.write(":");
+                    self.newline(value.line);
+                    self.case_body(body);
+                }
+                if let Some(body) = default {
+                    // The tree doesn't know which line `default:` is on
+                    self.write("default:");
+                    self.line_break();
+                    self.case_body(body);
+                }
+                self.leave(stmt.end_line, block_end);
+                return self.newline(stmt.end_line);
+            }
+            StmtKind::Break => self.write("break"),
+            StmtKind::Continue => self.write("continue"),
+            StmtKind::Return(value) | StmtKind::Yield(value) => {
+                let keyword = match stmt.kind {
+                    StmtKind::Return(_) => "return",
+                    _ => "yield",
+                };
+                self.write(keyword);
+                if let Some(value) = value {
+                    self.write(" ");
+                    self.expr(value, 1);
+                }
+            }
+            StmtKind::Throw(value) => {
+                self.write("throw ");
+                self.expr(value, 1);
+            }
+            StmtKind::Try {
+                body,
+                catch,
+                finally,
+            } => {
+                self.write("try ");
+                self.block(body, stmt.line);
+                let mut line = body.end_line;
+                if let Some((name, body)) = catch {
+                    self.write(&format!(" catch ({name}) "));
+                    self.block(body, line);
+                    line = body.end_line;
+                }
+                if let Some(body) = finally {
+                    self.write(" finally ");
+                    self.block(body, line);
+                }
+                return self.newline(stmt.end_line);
+            }
+            StmtKind::Assert(value) => {
+                self.write("assert ");
+                self.expr(value, 1);
+            }
+        }
+        self.write(";");
+        self.newline(stmt.end_line);
+    }
+
+    /// The body of an `if` or a loop whose header starts at `line`: a block opens on the
+    /// header's line, other statements go there as a whole
+    fn body(&mut self, body: &Stmt, line: u32) {
+        self.write(" ");
+        match &body.kind {
+            StmtKind::Block(block) => {
+                self.block(block, line);
+                self.newline(body.end_line);
+            }
+            _ => self.stmt_line(body),
+        }
+    }
+
+    fn case_body(&mut self, body: &[Stmt]) {
+        self.indent += 1;
+        self.block_start = true;
+        self.stmts(body);
+        self.indent -= 1;
+    }
+
+    /// `{`, the statements of `block` indented on lines of their own and `}`, which ends the
+    /// line. The `{` is on `line` in the source
+    fn block(&mut self, block: &Block, line: u32) {
+        if block.stmts.is_empty() && !self.has_comments_before(block.end_line) {
+            return self.write("{}");
+        }
+
+        self.write("{");
+        let first = block.stmts.first().map_or(block.end_line, |stmt| stmt.line);
+        self.open(line, first);
+        let block_end = self.enter(block.end_line);
+        self.stmts(&block.stmts);
+        self.leave(block.end_line, block_end);
+    }
+
+    /// Indent the contents of a block whose `}` is at `end_line`, returns the enclosing
+    /// block's end for [`Printer::leave`]
+    fn enter(&mut self, end_line: u32) -> u32 {
+        self.indent += 1;
+        self.block_start = true;
+        let end_line = end_line.min(self.block_end);
+        std::mem::replace(&mut self.block_end, end_line)
+    }
+
+    /// Print the comments left before the `}` at `end_line` and the `}`
+    fn leave(&mut self, end_line: u32, block_end: u32) {
+        self.comments_before(end_line);
+        self.block_end = block_end;
+        self.indent -= 1;
+        self.write("}");
+    }
+
+    /// The name, parameters and body of a function or method
+    fn function(&mut self, function: &Function) {
+        if let Some(name) = &function.name {
+            self.write(name);
+        }
+        match params(function) {
+            Some(params) => self.write(&params),
+            None => {
+                self.write("(");
+                for (i, param) in function.params.iter().enumerate() {
+                    if i > 0 {
+                        self.write(", ");
+                    }
+                    self.write(&param.name);
+                    if let Some(default) = &param.default {
+                        self.write(" = ");
+                        self.expr(default, 2);
+                    }
+                }
+                self.write(")");
+            }
+        }
+        self.write(" ");
+        self.block(&function.body, function.line);
+    }
+
+    fn class(&mut self, stmt: &Stmt, class: &Class) {
+        self.write(&format!("class {}", class.name));
+        if let Some(superclass) = &class.superclass {
+            self.write(&format!(" < {superclass}"));
+        }
+        if class.members.is_empty() && !self.has_comments_before(stmt.end_line) {
+            self.write(" {}");
+            return self.newline(stmt.end_line);
+        }
+
+        self.write(" {");
+        let first = class.members.first().map_or(stmt.end_line, member_line);
+        self.open(stmt.line, first);
+        let block_end = self.enter(stmt.end_line);
+        let next_line = self.next_line;
+        for (i, member) in class.members.iter().enumerate() {
+            self.next_line = class.members.get(i + 1).map_or(next_line, member_line);
+            let line = member_line(member);
+            let end_line = match member {
+                Member::Method { function, .. }
+                | Member::Getter(function)
+                | Member::Setter(function) => function.body.end_line,
+                Member::Field { line, .. } => *line,
+            };
+            self.comments_before(line);
+            self.blank_line(line);
+            match member {
+                Member::Method {
+                    is_static,
+                    function,
+                } => {
+                    if *is_static {
+                        self.write("static ");
+                    }
+                    self.function(function);
+                }
+                Member::Getter(function) => {
+                    self.write("get ");
+                    self.write(function.name.as_deref().unwrap_or_default());
+                    self.write(" ");
+                    self.block(&function.body, function.line);
+                }
+                Member::Setter(function) => {
+                    self.write("set ");
+                    self.function(function);
+                }
+                Member::Field {
+                    is_static,
+                    name,
+                    init,
+                    ..
+                } => {
+                    if *is_static {
+                        self.write("static ");
+                    }
+                    self.write(&format!("var {name}"));
+                    if let Some(init) = init {
+                        self.write(" = ");
+                        self.expr(init, 1);
+                    }
+                    self.write(";");
+                }
+            }
+            self.newline(end_line);
+        }
+        self.next_line = next_line;
+        self.leave(stmt.end_line, block_end);
+        self.newline(stmt.end_line);
+    }
+
+    /// `expr` from where the current line is at, broken over several lines if it doesn't fit
+    /// with the `trailing` characters that follow it
+    fn expr(&mut self, expr: &Expr, trailing: usize) {
+        if !self.has_comments(expr)
+            && let Some(flat) = flat(expr)
+            && self.column() + width(&flat) + trailing <= WIDTH
+        {
+            return self.write(&flat);
+        }
+
+        match &expr.kind {
+            ExprKind::Interpolation { parts, tail } => {
+                self.write("\"");
+                for (text, expr) in parts {
+                    self.write(&format!("{text}${{"));
+                    self.expr(expr, 1);
+                    self.write("}");
+                }
+                self.write(&format!("{tail}\""));
+            }
+            ExprKind::Grouping(inner) => {
+                self.write("(");
+                self.expr(inner, trailing + 1);
+                self.write(")");
+            }
+            ExprKind::Unary { op, operand } => {
+                self.write(unary(*op, ""));
+                let start = self.out.len();
+                self.expr(operand, trailing);
+                if self.out[start..].starts_with('-') {
+                    self.out.insert(start, ' ');
+                }
+            }
+            ExprKind::Binary { .. } | ExprKind::Logical { .. } => self.operations(expr, trailing),
+            ExprKind::Conditional {
+                condition,
+                then,
+                otherwise,
+            } => {
+                // Each branch on a line of its own, indented two levels deeper
+                self.expr(condition, 0);
+                self.indent += 2;
+                self.line_break();
+                self.write("? ");
+                self.expr(then, 0);
+                self.line_break();
+                self.write(": ");
+                self.expr(otherwise, trailing);
+                self.indent -= 2;
+            }
+            ExprKind::Assign { target, op, value } => {
+                let op = op.map_or(String::new(), |op| op.symbol().to_string());
+                self.expr(target, op.len() + 2);
+                self.write(&format!(" {op}= "));
+                self.expr(value, trailing);
+            }
+            ExprKind::Call { callee, args } => {
+                self.expr(callee, 1);
+                self.arguments(args);
+            }
+            ExprKind::Get { object, name } => {
+                self.expr(object, name.len() + 1 + trailing);
+                self.write(&format!(".{name}"));
+            }
+            ExprKind::Index { object, index } => {
+                self.expr(object, 1);
+                self.write("[");
+                self.expr(index, trailing + 1);
+                self.write("]");
+            }
+            ExprKind::Slice { object, start, end } => {
+                self.expr(object, 1);
+                self.write("[");
+                if let Some(start) = start {
+                    self.expr(start, 1);
+                }
+                self.write(":");
+                if let Some(end) = end {
+                    self.expr(end, trailing + 1);
+                }
+                self.write("]");
+            }
+            ExprKind::List(elements) => {
+                self.write("[");
+                let elements = elements.iter().map(|element| (start(element), element));
+                self.lines(elements, true, |printer, element| printer.expr(element, 1));
+                self.write("]");
+            }
+            ExprKind::Map(entries) => {
+                self.write("{");
+                let entries = entries
+                    .iter()
+                    .map(|(key, value)| (start(key), (key, value)));
+                self.lines(entries, true, |printer, (key, value)| {
+                    printer.expr(key, 1);
+                    printer.write(": ");
+                    printer.expr(value, 1);
+                });
+                self.write("}");
+            }
+            ExprKind::Lambda(function) => {
+                self.write("fun ");
+                self.function(function);
+            }
+            // Don't break
+            _ => self.write(&flat(expr).unwrap()),
+        }
+    }
+
+    /// A chain of operators with the same precedence, like `a + b - c`, with a line break after
+    /// each operator. The operands after the first are indented two levels deeper
+    fn operations(&mut self, expr: &Expr, trailing: usize) {
+        let (_, _, _, precedence) = operation(expr).unwrap();
+        let mut rest = vec![];
+        let mut first = expr;
+        while let Some((op, left, right, next)) = operation(first)
+            && next == precedence
+        {
+            rest.push((op, right));
+            first = left;
+        }
+        rest.reverse();
+
+        self.expr(first, rest[0].0.len() + 1);
+        self.indent += 2;
+        for (i, &(op, operand)) in rest.iter().enumerate() {
+            self.write(&format!(" {op}"));
+            self.line_break();
+            let trailing = rest.get(i + 1).map_or(trailing, |(op, _)| op.len() + 1);
+            self.expr(operand, trailing);
+        }
+        self.indent -= 2;
+    }
+
+    /// The arguments of a call in parentheses, on lines of their own if they don't fit
+    fn arguments(&mut self, args: &[Argument]) {
+        // A lambda passed last stays on the line, unless the arguments before it don't fit or
+        // have comments between them
+        let comments = args.first().map_or(false, |first| {
+            self.has_comments_in(self.brackets(start(&first.value)))
+        }) || args.iter().any(|arg| self.has_comments(&arg.value));
+        if !comments
+            && let Some((last, rest)) = args.split_last()
+            && let ExprKind::Lambda(function) = &last.value.kind
+            && let Some(rest) = rest.iter().map(argument).collect::<Option<Vec<_>>>()
+            && let Some(params) = params(function)
+        {
+            let mut head = rest.join(", ");
+            if !rest.is_empty() {
+                head.push_str(", ");
+            }
+            if let Some(name) = &last.name {
+                head.push_str(&format!("{name}: "));
+            }
+            let header = format!("fun {params} {{");
+            if self.column() + 1 + width(&head) + width(&header) <= WIDTH {
+                self.write(&format!("({head}fun "));
+                self.function(function);
+                return self.write(")");
+            }
+        }
+
+        self.write("(");
+        // Calls don't take a trailing comma
+        let args = args.iter().map(|arg| (start(&arg.value), arg));
+        self.lines(args, false, |printer, arg| {
+            if let Some(name) = &arg.name {
+                printer.write(&format!("{name}: "));
+            }
+            printer.expr(&arg.value, 1);
+        });
+        self.write(")");
+    }
+
+    /// `items` one per line, indented one level deeper than the current line, which goes on
+    /// after the last one. Each item comes with where it starts, the comments between the items
+    /// go behind the one before them if they had code in front of them, otherwise on lines of
+    /// their own
+    fn lines<T>(
+        &mut self,
+        items: impl ExactSizeIterator<Item = ((u32, u32), T)>,
+        trailing_comma: bool,
+        mut item: impl FnMut(&mut Self, T),
+    ) {
+        let items: Vec<_> = items.collect();
+        let Some(&(first, _)) = items.first() else {
+            return;
+        };
+        let (_, close) = self.brackets(first);
+        let mut next: Vec<_> = items.iter().skip(1).map(|&(start, _)| start).collect();
+        next.push(close);
+
+        self.line_break();
+        self.indent += 1;
+        let count = items.len();
+        for (i, (start, element)) in items.into_iter().enumerate() {
+            self.comments_in_front(start);
+            item(self, element);
+            if trailing_comma || i + 1 < count {
+                self.write(",");
+            }
+            self.comments_behind(next[i]);
+            self.line_break();
+        }
+        self.comments_in_front(close);
+        self.indent -= 1;
+    }
+}
+
+/// Where `expr` starts in the source
+fn start(expr: &Expr) -> (u32, u32) {
+    (expr.line, expr.column)
+}
+
+/// How a token changes how deep in brackets the tokens after it are
+fn nesting(kind: TokenKind) -> i32 {
+    match kind {
+        TokenKind::LeftParen | TokenKind::LeftBracket | TokenKind::LeftBrace => 1,
+        TokenKind::RightParen | TokenKind::RightBracket | TokenKind::RightBrace => -1,
+        _ => 0,
+    }
+}
+
+fn member_line(member: &Member) -> u32 {
+    match member {
+        Member::Method { function, .. } | Member::Getter(function) | Member::Setter(function) => {
+            function.line
+        }
+        Member::Field { line, .. } => *line,
+    }
+}
+
+/// The operator, operands and precedence of a binary or logical expression
+fn operation(expr: &Expr) -> Option<(&'static str, &Expr, &Expr, Precedence)> {
+    let (op, left, right, precedence) = match &expr.kind {
+        ExprKind::Binary { left, op, right } => {
+            use BinaryOp::*;
+            let precedence = match op {
+                Equal | NotEqual => Precedence::Equality,
+                Greater | GreaterEqual | Less | LessEqual => Precedence::Comparison,
+                Add | Subtract => Precedence::Term,
+                Multiply | Divide | Modulo | FloorDivide => Precedence::Factor,
+                Power => Precedence::Exponent,
+                BitAnd => Precedence::BitAnd,
+                BitOr => Precedence::BitOr,
+                BitXor => Precedence::BitXor,
+                ShiftLeft | ShiftRight => Precedence::Shift,
+            };
+            (op.symbol(), left, right, precedence)
+        }
+        ExprKind::Logical { left, op, right } => {
+            let precedence = match op {
+                LogicalOp::And => Precedence::And,
+                LogicalOp::Or => Precedence::Or,
+            };
+            (op.symbol(), left, right, precedence)
+        }
+        _ => return None,
+    };
+    Some((op, left, right, precedence))
+}
+
+/// Characters `text` takes up on a line
+fn width(text: &str) -> usize {
+    text.chars().count()
+}
+
+/// `(a, b = 1, ...rest)`, `None` if a default value has a lambda in it
+fn params(function: &Function) -> Option<String> {
+    let params: Option<Vec<String>> = function
+        .params
+        .iter()
+        .map(|param| {
+            let rest = if param.rest { "..." } else { "" };
+            Some(match &param.default {
+                Some(default) => format!("{rest}{} = {}", param.name, flat(default)?),
+                None => format!("{rest}{}", param.name),
+            })
+        })
+        .collect();
+    Some(format!("({})", params?.join(", ")))
+}
+
+/// The operator of a unary expression in front of its `operand` as printed. A `-` before
+/// another `-` needs a space, or the two turn into `--`
+fn unary(op: UnaryOp, operand: &str) -> &'static str {
+    match op {
+        UnaryOp::Negate if operand.starts_with('-') => "- ",
+        UnaryOp::Await => "await ",
+        op => op.symbol(),
+    }
+}
+
+fn argument(arg: &Argument) -> Option<String> {
+    let value = flat(&arg.value)?;
+    Some(match &arg.name {
+        Some(name) => format!("{name}: {value}"),
+        None => value,
+    })
+}
+
+/// `expr` on a single line, `None` if it has a lambda in it, which takes several
+fn flat(expr: &Expr) -> Option<String> {
+    Some(match &expr.kind {
+        ExprKind::Nil => "nil".to_string(),
+        ExprKind::Bool(value) => value.to_string(),
+        ExprKind::Number(literal) => literal.clone(),
+        ExprKind::String(string) => format!("\"{string}\""),
+        ExprKind::Interpolation { parts, tail } => {
+            let mut text = String::from('"');
+            for (part, expr) in parts {
+                text.push_str(&format!("{part}${{{}}}", flat(expr)?));
+            }
+            format!("{text}{tail}\"")
+        }
+        ExprKind::Variable(name) => name.clone(),
+        ExprKind::This => "this".to_string(),
+        ExprKind::Super(method) => format!("super.{method}"),
+        ExprKind::Grouping(inner) => format!("({})", flat(inner)?),
+        ExprKind::Unary { op, operand } => {
+            let operand = flat(operand)?;
+            format!("{}{operand}", unary(*op, &operand))
+        }
+        ExprKind::Binary { left, op, right } => {
+            format!("{} {} {}", flat(left)?, op.symbol(), flat(right)?)
+        }
+        ExprKind::Logical { left, op, right } => {
+            format!("{} {} {}", flat(left)?, op.symbol(), flat(right)?)
+        }
+        ExprKind::Conditional {
+            condition,
+            then,
+            otherwise,
+        } => format!(
+            "{} ? {} : {}",
+            flat(condition)?,
+            flat(then)?,
+            flat(otherwise)?
+        ),
+        ExprKind::Assign { target, op, value } => {
+            let op = op.map_or("", |op| op.symbol());
+            format!("{} {op}= {}", flat(target)?, flat(value)?)
+        }
+        ExprKind::Increment { name, op, prefix } => {
+            let op = op.symbol().repeat(2);
+            if *prefix {
+                format!("{op}{name}")
+            } else {
+                format!("{name}{op}")
+            }
+        }
+        ExprKind::Call { callee, args } => {
+            let args: Option<Vec<_>> = args.iter().map(argument).collect();
+            format!("{}({})", flat(callee)?, args?.join(", "))
+        }
+        ExprKind::Get { object, name } => format!("{}.{name}", flat(object)?),
+        ExprKind::Index { object, index } => format!("{}[{}]", flat(object)?, flat(index)?),
+        ExprKind::Slice { object, start, end } => {
+            let start = match start {
+                Some(start) => flat(start)?,
+                None => String::new(),
+            };
+            let end = match end {
+                Some(end) => flat(end)?,
+                None => String::new(),
+            };
+            format!("{}[{start}:{end}]", flat(object)?)
+        }
+        ExprKind::List(elements) => {
+            let elements: Option<Vec<_>> = elements.iter().map(flat).collect();
+            format!("[{}]", elements?.join(", "))
+        }
+        ExprKind::Map(entries) => {
+            let entries: Option<Vec<_>> = entries
+                .iter()
+                .map(|(key, value)| Some(format!("{}: {}", flat(key)?, flat(value)?)))
+                .collect();
+            format!("{{{}}}", entries?.join(", "))
+        }
+        ExprKind::Lambda(_) => return None,
+    })
+}
@@ -0,0 +1,209 @@
+//! The scanner as a stream of tokens for highlighting, in editors or the REPL.
+//!
+//! [`Lexer::tokens`] yields every token of the source with where it is and a [`Category`] to
+//! color it by, comments included. It never fails: malformed input comes out as tokens with an
+//! [`error`](Lexeme::error), and an unterminated string or comment still counts as a string or
+//! a comment so it can be highlighted while it's being typed.
+//!
+//! ```
+//! use loxide::compile::lexer::{Category, Lexer};
+//!
+//! let categories: Vec<_> = Lexer::new("var x = \"hi\"; // greet")
+//!     .tokens()
+//!     .map(|lexeme| lexeme.category)
+//!     .collect();
+//! assert_eq!(
+//!     categories,
+//!     [
+//!         Category::Keyword,
+//!         Category::Identifier,
+//!         Category::Operator,
+//!         Category::String,
+//!         Category::Punctuation,
+//!         Category::Comment,
+//!     ]
+//! );
+//! ```
+
+use std::collections::VecDeque;
+
+use super::{ast, shebang_len, Scanner, TokenKind, UNTERMINATED_COMMENT, UNTERMINATED_STRING};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    /// Including `true`, `false`, `nil`, `this` and `super`
+    Keyword,
+    Identifier,
+    Number,
+    /// Including the parts of an interpolated string around the expressions
+    String,
+    Operator,
+    /// Brackets, `,`, `.`, `...`, `:` and `;`
+    Punctuation,
+    Comment,
+    /// A character that doesn't start a token, or a malformed number
+    Error,
+}
+
+/// Where a token is in the source
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    /// Byte offsets, the end is exclusive
+    pub start: usize,
+    pub end: usize,
+    /// Of the start, the column in bytes and 1-based like the compiler's
+    pub line: u32,
+    pub column: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lexeme<'src> {
+    /// `None` for comments
+    pub kind: Option<TokenKind>,
+    pub category: Category,
+    pub text: &'src str,
+    pub span: Span,
+    /// What the scanner reported, if the token is malformed
+    pub error: Option<&'src str>,
+}
+
+pub struct Lexer<'src> {
+    src: &'src str,
+}
+
+impl<'src> Lexer<'src> {
+    pub fn new(src: &'src str) -> Self {
+        Self { src }
+    }
+
+    /// The tokens of the source in order, without the end of file
+    pub fn tokens(self) -> Tokens<'src> {
+        Tokens {
+            src: self.src,
+            scanner: Scanner::new(self.src),
+            pending: VecDeque::new(),
+            end: 0,
+            line: 1,
+            line_start: 0,
+            counted: 0,
+            done: false,
+        }
+    }
+}
+
+pub struct Tokens<'src> {
+    src: &'src str,
+    scanner: Scanner<'src>,
+    /// The comments before the last token and the token itself
+    pending: VecDeque<Lexeme<'src>>,
+    /// Where the last token ends
+    end: usize,
+    /// The line and where it starts for `counted`, the offset the newlines are counted up to
+    line: u32,
+    line_start: usize,
+    counted: usize,
+    done: bool,
+}
+
+impl<'src> Tokens<'src> {
+    fn lexeme(
+        &mut self,
+        kind: Option<TokenKind>,
+        category: Category,
+        (start, end): (usize, usize),
+        error: Option<&'src str>,
+    ) -> Lexeme<'src> {
+        for (i, byte) in self.src.as_bytes()[self.counted..start].iter().enumerate() {
+            if *byte == b'\n' {
+                self.line += 1;
+                self.line_start = self.counted + i + 1;
+            }
+        }
+        self.counted = start;
+        Lexeme {
+            kind,
+            category,
+            text: &self.src[start..end],
+            span: Span {
+                start,
+                end,
+                line: self.line,
+                column: (start - self.line_start) as u32 + 1,
+            },
+            error,
+        }
+    }
+
+    /// Queue the comments between the last token and `until`
+    fn comments(&mut self, until: usize) {
+        let mut i = self.end;
+        while i < until {
+            let rest = &self.src[i..until];
+            let len = if rest.starts_with("//") {
+                rest.find('\n').unwrap_or(rest.len())
+            } else if rest.starts_with("/*") {
+                ast::block_comment_len(rest)
+            } else if i == 0 && shebang_len(rest) > 0 {
+                shebang_len(rest)
+            } else {
+                i += 1;
+                continue;
+            };
+            let comment = self.lexeme(None, Category::Comment, (i, i + len), None);
+            self.pending.push_back(comment);
+            i += len;
+        }
+    }
+}
+
+impl<'src> Iterator for Tokens<'src> {
+    type Item = Lexeme<'src>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pending.is_empty() && !self.done {
+            let token = self.scanner.token();
+            let start = self.scanner.start;
+            // The rest of a character that isn't ASCII, already part of the last error
+            if start < self.end {
+                continue;
+            }
+            self.comments(start);
+            if token.kind == TokenKind::Eof {
+                self.done = true;
+                break;
+            }
+
+            let mut end = self.scanner.current;
+            while !self.src.is_char_boundary(end) {
+                end += 1;
+            }
+            let (category, error) = match (token.kind, token.msg) {
+                (TokenKind::Error, UNTERMINATED_STRING) => (Category::String, Some(token.msg)),
+                (TokenKind::Error, UNTERMINATED_COMMENT) => (Category::Comment, Some(token.msg)),
+                (TokenKind::Error, _) => (Category::Error, Some(token.msg)),
+                (kind, _) => (category(kind), None),
+            };
+            let lexeme = self.lexeme(Some(token.kind), category, (start, end), error);
+            self.pending.push_back(lexeme);
+            self.end = end;
+        }
+        self.pending.pop_front()
+    }
+}
+
+fn category(kind: TokenKind) -> Category {
+    use TokenKind::*;
+
+    match kind {
+        Identifier => Category::Identifier,
+        Number => Category::Number,
+        String | Interpolation | InterpolationMiddle | InterpolationEnd => Category::String,
+        LeftParen | RightParen | LeftBrace | RightBrace | LeftBracket | RightBracket | Comma
+        | Dot | DotDotDot | Colon | Semicolon => Category::Punctuation,
+        And | Assert | Await | Break | Case | Catch | Class | Const | Continue | Default | Else
+        | False | Finally | For | Fun | If | Import | In | Nil | Or | Print | Return | Super
+        | Switch | This | Throw | True | Try | Var | While | Yield => Category::Keyword,
+        Error | Eof | Synthetic => Category::Error,
+        _ => Category::Operator,
+    }
+}
@@ -0,0 +1,542 @@
+//! `loxide lint`: warnings about code that runs, but probably not the way it was meant to.
+//!
+//! The linter walks the [syntax tree](super::ast) with the scopes the compiler would give it.
+//! Every warning comes from a [`Rule`], which can be turned off for a whole run, or for a line
+//! with a comment on it or on the line before it:
+//!
+//! ```lox
+//! // lox-lint: allow(unused-local, shadowed-variable)
+//! var unused = 1;
+//! ```
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+
+use super::{
+    ast::{self, Block, Class, Expr, ExprKind, Function, Member, Stmt, StmtKind, UnaryOp},
+    CompileError,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Rule {
+    /// A local variable, function or class that's never read. Names starting with `_` are
+    /// meant to be unused
+    UnusedLocal,
+    /// Statements after a `return`, `throw`, `break` or `continue`
+    UnreachableCode,
+    /// A local with the same name as one of an enclosing scope
+    ShadowedVariable,
+    /// An `if`, loop or `?:` whose condition is a literal, apart from `while (true)`
+    ConstantCondition,
+    /// Top-level code reading a global that's only declared further down
+    UseBeforeDefinition,
+}
+
+impl Rule {
+    pub const ALL: [Rule; 5] = [
+        Rule::UnusedLocal,
+        Rule::UnreachableCode,
+        Rule::ShadowedVariable,
+        Rule::ConstantCondition,
+        Rule::UseBeforeDefinition,
+    ];
+
+    /// The name warnings are reported with and `allow(...)` takes
+    pub fn code(self) -> &'static str {
+        match self {
+            Rule::UnusedLocal => "unused-local",
+            Rule::UnreachableCode => "unreachable-code",
+            Rule::ShadowedVariable => "shadowed-variable",
+            Rule::ConstantCondition => "constant-condition",
+            Rule::UseBeforeDefinition => "use-before-definition",
+        }
+    }
+
+    pub fn from_code(code: &str) -> Option<Self> {
+        Rule::ALL.into_iter().find(|rule| rule.code() == code)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning {
+    pub rule: Rule,
+    pub line: u32,
+    pub column: u32,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[line {}:{}] Warning[{}]: {}",
+            self.line,
+            self.column,
+            self.rule.code(),
+            self.message
+        )
+    }
+}
+
+/// The warnings for `src` in the order of the lines they're on, leaving out the `allowed` rules
+/// and the ones suppressed by comments. Fails with the syntax errors if `src` doesn't parse
+pub fn lint(src: &str, allowed: &[Rule]) -> Result<Vec<Warning>, Vec<CompileError>> {
+    let stmts = ast::parse(src)?;
+    let mut linter = Linter {
+        warnings: vec![],
+        scopes: vec![],
+        globals: HashMap::new(),
+        defined: HashSet::new(),
+        functions: 0,
+    };
+    for stmt in &stmts {
+        linter.declare_global(stmt);
+    }
+    linter.stmts(&stmts);
+
+    // `allow(...)` comments cover their own line and the next one
+    let mut suppressed: Vec<(u32, Rule)> = vec![];
+    for comment in ast::comments(src) {
+        let Some(rules) = comment
+            .text
+            .split_once("lox-lint: allow(")
+            .and_then(|(_, rest)| rest.split_once(')'))
+        else {
+            continue;
+        };
+        for rule in rules
+            .0
+            .split(',')
+            .filter_map(|code| Rule::from_code(code.trim()))
+        {
+            suppressed.push((comment.line, rule));
+            suppressed.push((comment.end_line + 1, rule));
+        }
+    }
+
+    let mut warnings = linter.warnings;
+    warnings.retain(|warning| {
+        !allowed.contains(&warning.rule) && !suppressed.contains(&(warning.line, warning.rule))
+    });
+    warnings.sort_by_key(|warning| (warning.line, warning.column));
+    Ok(warnings)
+}
+
+struct Local {
+    name: String,
+    line: u32,
+    column: u32,
+    used: bool,
+    /// Parameters and catch variables aren't reported when unused
+    report_unused: bool,
+}
+
+struct Linter {
+    warnings: Vec<Warning>,
+    /// Locals of the enclosing scopes, innermost last. Functions see the scopes around them
+    scopes: Vec<Vec<Local>>,
+    /// Where each global is declared at the top level
+    globals: HashMap<String, (u32, u32)>,
+    /// The globals that have been declared by the top-level code so far
+    defined: HashSet<String>,
+    /// How many functions the code is nested in
+    functions: usize,
+}
+
+impl Linter {
+    fn warn(&mut self, rule: Rule, line: u32, column: u32, message: String) {
+        self.warnings.push(Warning {
+            rule,
+            line,
+            column,
+            message,
+        });
+    }
+
+    fn declare_global(&mut self, stmt: &Stmt) {
+        let name = match &stmt.kind {
+            StmtKind::Var { name, .. } | StmtKind::Const { name, .. } => name,
+            StmtKind::Function(Function {
+                name: Some(name), ..
+            }) => name,
+            StmtKind::Class(Class { name, .. }) => name,
+            StmtKind::Import {
+                name: Some(name), ..
+            } => name,
+            _ => return,
+        };
+        self.globals
+            .entry(name.clone())
+            .or_insert((stmt.line, stmt.column));
+    }
+
+    /// Declare `name` in the innermost scope, or as a global at the top level
+    fn declare(&mut self, name: &str, line: u32, column: u32, report_unused: bool) {
+        let Some(scope) = self.scopes.last() else {
+            self.defined.insert(name.to_string());
+            return;
+        };
+
+        // Redeclaring a local in the same scope is a compile error
+        if !scope.iter().any(|local| local.name == name)
+            && let Some(shadowed) = self.scopes[..self.scopes.len() - 1]
+                .iter()
+                .flatten()
+                .rev()
+                .find(|local| local.name == name)
+        {
+            let message = format!(
+                "'{name}' shadows the variable declared on line {}.",
+                shadowed.line
+            );
+            self.warn(Rule::ShadowedVariable, line, column, message);
+        }
+        self.scopes.last_mut().unwrap().push(Local {
+            name: name.to_string(),
+            line,
+            column,
+            used: false,
+            report_unused,
+        });
+    }
+
+    fn begin_scope(&mut self) {
+        self.scopes.push(vec![]);
+    }
+
+    fn end_scope(&mut self) {
+        for local in self.scopes.pop().unwrap() {
+            if local.report_unused && !local.used && !local.name.starts_with('_') {
+                let message = format!("'{}' is never used.", local.name);
+                self.warn(Rule::UnusedLocal, local.line, local.column, message);
+            }
+        }
+    }
+
+    /// A read of `name`
+    fn use_variable(&mut self, name: &str, line: u32, column: u32) {
+        if let Some(local) = self
+            .scopes
+            .iter_mut()
+            .flatten()
+            .rev()
+            .find(|local| local.name == name)
+        {
+            local.used = true;
+            return;
+        }
+
+        // Functions run later, when the global may be there
+        if self.functions == 0
+            && !self.defined.contains(name)
+            && let Some(&(declared, _)) = self.globals.get(name)
+        {
+            let message = format!("'{name}' is used before it's declared on line {declared}.");
+            self.warn(Rule::UseBeforeDefinition, line, column, message);
+        }
+    }
+
+    fn stmts(&mut self, stmts: &[Stmt]) {
+        // Only the first unreachable statement is reported
+        if let Some(i) = stmts.iter().position(exits)
+            && let Some(unreachable) = stmts.get(i + 1)
+        {
+            let message = "Unreachable code.".to_string();
+            self.warn(
+                Rule::UnreachableCode,
+                unreachable.line,
+                unreachable.column,
+                message,
+            );
+        }
+        for stmt in stmts {
+            self.stmt(stmt);
+        }
+    }
+
+    fn block(&mut self, block: &Block) {
+        self.begin_scope();
+        self.stmts(&block.stmts);
+        self.end_scope();
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        match &stmt.kind {
+            StmtKind::Expression(value)
+            | StmtKind::Print(value)
+            | StmtKind::Throw(value)
+            | StmtKind::Assert(value) => self.expr(value),
+            StmtKind::Var { name, init } => {
+                if let Some(init) = init {
+                    self.expr(init);
+                }
+                self.declare(name, stmt.line, stmt.column, true);
+            }
+            StmtKind::Const { name, init } => {
+                self.expr(init);
+                self.declare(name, stmt.line, stmt.column, true);
+            }
+            StmtKind::Function(function) => {
+                // Declared first, so it can call itself
+                let name = function.name.as_deref().unwrap_or_default();
+                self.declare(name, stmt.line, stmt.column, true);
+                self.function(function);
+            }
+            StmtKind::Class(class) => {
+                if let Some(superclass) = &class.superclass {
+                    self.use_variable(superclass, stmt.line, stmt.column);
+                }
+                self.declare(&class.name, stmt.line, stmt.column, true);
+                for member in &class.members {
+                    match member {
+                        Member::Method { function, .. }
+                        | Member::Getter(function)
+                        | Member::Setter(function) => self.function(function),
+                        Member::Field { init, .. } => {
+                            if let Some(init) = init {
+                                self.functions += 1;
+                                self.expr(init);
+                                self.functions -= 1;
+                            }
+                        }
+                    }
+                }
+            }
+            StmtKind::Import { name, .. } => {
+                if let Some(name) = name {
+                    self.declare(name, stmt.line, stmt.column, true);
+                }
+            }
+            StmtKind::Block(block) => self.block(block),
+            StmtKind::If {
+                condition,
+                then,
+                otherwise,
+            } => {
+                self.condition(condition, false);
+                self.stmt(then);
+                if let Some(otherwise) = otherwise {
+                    self.stmt(otherwise);
+                }
+            }
+            StmtKind::While { condition, body } => {
+                self.condition(condition, true);
+                self.stmt(body);
+            }
+            StmtKind::For {
+                init,
+                condition,
+                increment,
+                body,
+            } => {
+                self.begin_scope();
+                if let Some(init) = init {
+                    self.stmt(init);
+                }
+                if let Some(condition) = condition {
+                    self.condition(condition, true);
+                }
+                self.stmt(body);
+                if let Some(increment) = increment {
+                    self.expr(increment);
+                }
+                self.end_scope();
+            }
+            StmtKind::ForIn {
+                item,
+                collection,
+                body,
+            } => {
+                self.expr(collection);
+                self.begin_scope();
+                self.declare(item, stmt.line, stmt.column, true);
+                self.stmt(body);
+                self.end_scope();
+            }
+            StmtKind::Switch {
+                value,
+                cases,
+                default,
+            } => {
+                self.expr(value);
+                for (value, body) in cases {
+                    self.expr(value);
+                    self.begin_scope();
+                    self.stmts(body);
+                    self.end_scope();
+                }
+                if let Some(body) = default {
+                    self.begin_scope();
+                    self.stmts(body);
+                    self.end_scope();
+                }
+            }
+            StmtKind::Break | StmtKind::Continue => (),
+            StmtKind::Return(value) | StmtKind::Yield(value) => {
+                if let Some(value) = value {
+                    self.expr(value);
+                }
+            }
+            StmtKind::Try {
+                body,
+                catch,
+                finally,
+            } => {
+                self.block(body);
+                if let Some((name, body)) = catch {
+                    self.begin_scope();
+                    self.declare(name, stmt.line, stmt.column, false);
+                    self.block(body);
+                    self.end_scope();
+                }
+                if let Some(body) = finally {
+                    self.block(body);
+                }
+            }
+        }
+    }
+
+    fn function(&mut self, function: &Function) {
+        self.functions += 1;
+        self.begin_scope();
+        for param in &function.params {
+            if let Some(default) = &param.default {
+                self.expr(default);
+            }
+            self.declare(&param.name, function.line, function.column, false);
+        }
+        // The body shares the scope of the parameters
+        self.stmts(&function.body.stmts);
+        self.end_scope();
+        self.functions -= 1;
+    }
+
+    /// The condition of an `if`, a loop when `is_loop` or a `?:`
+    fn condition(&mut self, condition: &Expr, is_loop: bool) {
+        self.expr(condition);
+        match truthiness(condition) {
+            // `while (true)` loops until something breaks out of it
+            Some(true) if is_loop && matches!(condition.kind, ExprKind::Bool(true)) => (),
+            Some(value) => {
+                let message = format!("The condition is always {value}.");
+                self.warn(
+                    Rule::ConstantCondition,
+                    condition.line,
+                    condition.column,
+                    message,
+                );
+            }
+            None => (),
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Nil
+            | ExprKind::Bool(_)
+            | ExprKind::Number(_)
+            | ExprKind::String(_)
+            | ExprKind::This
+            | ExprKind::Super(_) => (),
+            ExprKind::Interpolation { parts, .. } => {
+                for (_, expr) in parts {
+                    self.expr(expr);
+                }
+            }
+            ExprKind::Variable(name) => self.use_variable(name, expr.line, expr.column),
+            ExprKind::Grouping(inner) => self.expr(inner),
+            ExprKind::Unary { operand, .. } => self.expr(operand),
+            ExprKind::Binary { left, right, .. } | ExprKind::Logical { left, right, .. } => {
+                self.expr(left);
+                self.expr(right);
+            }
+            ExprKind::Conditional {
+                condition,
+                then,
+                otherwise,
+            } => {
+                self.condition(condition, false);
+                self.expr(then);
+                self.expr(otherwise);
+            }
+            ExprKind::Assign { target, op, value } => {
+                self.expr(value);
+                match &target.kind {
+                    // Only `+=` and the like read the variable
+                    ExprKind::Variable(name) if op.is_none() => {
+                        if self.functions == 0 && self.scopes.is_empty() {
+                            self.defined.insert(name.clone());
+                        }
+                    }
+                    _ => self.expr(target),
+                }
+            }
+            ExprKind::Increment { name, .. } => self.use_variable(name, expr.line, expr.column),
+            ExprKind::Call { callee, args } => {
+                self.expr(callee);
+                for arg in args {
+                    self.expr(&arg.value);
+                }
+            }
+            ExprKind::Get { object, .. } => self.expr(object),
+            ExprKind::Index { object, index } => {
+                self.expr(object);
+                self.expr(index);
+            }
+            ExprKind::Slice { object, start, end } => {
+                self.expr(object);
+                for bound in [start, end].into_iter().flatten() {
+                    self.expr(bound);
+                }
+            }
+            ExprKind::List(elements) => {
+                for element in elements {
+                    self.expr(element);
+                }
+            }
+            ExprKind::Map(entries) => {
+                for (key, value) in entries {
+                    self.expr(key);
+                    self.expr(value);
+                }
+            }
+            ExprKind::Lambda(function) => self.function(function),
+        }
+    }
+}
+
+/// Whether the statements after `stmt` never run
+fn exits(stmt: &Stmt) -> bool {
+    match &stmt.kind {
+        StmtKind::Return(_) | StmtKind::Throw(_) | StmtKind::Break | StmtKind::Continue => true,
+        StmtKind::Block(block) => block.stmts.iter().any(exits),
+        StmtKind::If {
+            then,
+            otherwise: Some(otherwise),
+            ..
+        } => exits(then) && exits(otherwise),
+        _ => false,
+    }
+}
+
+/// Whether `expr` is always truthy or always falsey, if it's a literal
+fn truthiness(expr: &Expr) -> Option<bool> {
+    match &expr.kind {
+        ExprKind::Nil => Some(false),
+        ExprKind::Bool(value) => Some(*value),
+        ExprKind::Number(_)
+        | ExprKind::String(_)
+        | ExprKind::Interpolation { .. }
+        | ExprKind::List(_)
+        | ExprKind::Map(_)
+        | ExprKind::Lambda(_) => Some(true),
+        ExprKind::Grouping(inner) => truthiness(inner),
+        ExprKind::Unary {
+            op: UnaryOp::Not,
+            operand,
+        } => truthiness(operand).map(|value| !value),
+        _ => None,
+    }
+}
@@ -0,0 +1,551 @@
+//! What the names in a script refer to, for editors: `loxide lsp` answers go-to-definition,
+//! hover and document symbol requests from it.
+//!
+//! Like the [linter](super::lint) this resolves names on the [syntax tree](super::ast) with the
+//! scopes the compiler would give them. Globals can be used before they're declared, from
+//! functions that run later. `this.name` in a method refers to the member of its class. The
+//! tree only knows where statements start, so the names in declarations are looked up among
+//! the tokens that follow.
+
+use std::collections::HashMap;
+
+use super::{
+    ast::{self, Block, Class, Expr, ExprKind, Function, Member, Stmt, StmtKind},
+    Scanner, TokenKind,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    Variable,
+    Constant,
+    Function,
+    Class,
+    Method,
+    Field,
+    Parameter,
+    Module,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Definition {
+    pub name: String,
+    pub kind: SymbolKind,
+    /// Where the name is in the declaration, the column in bytes
+    pub line: u32,
+    pub column: u32,
+    /// Last line of the declaration
+    pub end_line: u32,
+    /// The parameters of a function or method, or of the initializer of a class. A rest
+    /// parameter starts with `...`
+    pub params: Option<Vec<String>>,
+    /// How many arguments a call needs at least and takes at most, `None` with a rest parameter
+    pub arity: Option<(usize, Option<usize>)>,
+    /// The class of a method or field
+    pub parent: Option<usize>,
+    /// Whether it's a global, or a member of a global class
+    pub top_level: bool,
+}
+
+/// A name that refers to a definition, declarations refer to themselves
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reference {
+    pub line: u32,
+    /// In bytes
+    pub column: u32,
+    /// Length of the name in bytes
+    pub len: u32,
+    /// Index into [`Symbols::definitions`]
+    pub definition: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct Symbols {
+    pub definitions: Vec<Definition>,
+    /// Sorted by position
+    pub references: Vec<Reference>,
+}
+
+impl Symbols {
+    /// The reference at `line` and `column`, in bytes, if a name is there
+    pub fn at(&self, line: u32, column: u32) -> Option<&Reference> {
+        self.references.iter().find(|reference| {
+            reference.line == line
+                && (reference.column..reference.column + reference.len).contains(&column)
+        })
+    }
+
+    /// The definition of the global `name`
+    pub fn global(&self, name: &str) -> Option<&Definition> {
+        self.definitions.iter().find(|definition| {
+            definition.top_level && definition.parent.is_none() && definition.name == name
+        })
+    }
+}
+
+/// Resolve the names in `src`. Statements with syntax errors are left out and the rest is
+/// resolved anyway, editors ask about code while it's being written
+pub fn analyze(src: &str) -> Symbols {
+    let (stmts, _) = ast::parse_partial(src);
+
+    let mut names = vec![];
+    let mut scanner = Scanner::new(src);
+    loop {
+        let token = scanner.token();
+        match token.kind {
+            TokenKind::Eof => break,
+            TokenKind::Identifier => names.push((token.line, token.column, token.msg)),
+            _ => (),
+        }
+    }
+
+    let mut resolver = Resolver {
+        names,
+        symbols: Symbols::default(),
+        scopes: vec![],
+        globals: HashMap::new(),
+        class: None,
+    };
+    for stmt in &stmts {
+        resolver.declare_global(stmt);
+    }
+    for stmt in &stmts {
+        resolver.stmt(stmt);
+    }
+
+    let mut symbols = resolver.symbols;
+    symbols
+        .references
+        .sort_by_key(|reference| (reference.line, reference.column));
+    symbols.references.dedup();
+    symbols
+}
+
+struct Resolver<'src> {
+    /// The identifier tokens of the source with their line and column
+    names: Vec<(u32, u32, &'src str)>,
+    symbols: Symbols,
+    /// The locals of the enclosing scopes, innermost last
+    scopes: Vec<Vec<(String, usize)>>,
+    globals: HashMap<String, usize>,
+    /// The class whose methods are being resolved, for `this.name`
+    class: Option<usize>,
+}
+
+impl Resolver<'_> {
+    /// Where `name` is first written at or after `line` and `column`
+    fn find(&self, name: &str, line: u32, column: u32) -> (u32, u32) {
+        self.names
+            .iter()
+            .find(|&&(l, c, text)| (l, c) >= (line, column) && text == name)
+            .map_or((line, column), |&(l, c, _)| (l, c))
+    }
+
+    /// Add a definition of `name` at `position` and a reference to it there
+    fn define(
+        &mut self,
+        name: &str,
+        kind: SymbolKind,
+        position: (u32, u32),
+        end_line: u32,
+    ) -> usize {
+        let index = self.symbols.definitions.len();
+        self.symbols.definitions.push(Definition {
+            name: name.to_string(),
+            kind,
+            line: position.0,
+            column: position.1,
+            end_line,
+            params: None,
+            arity: None,
+            parent: None,
+            top_level: self.scopes.is_empty(),
+        });
+        self.reference(name, position, index);
+        index
+    }
+
+    fn reference(&mut self, name: &str, (line, column): (u32, u32), definition: usize) {
+        self.symbols.references.push(Reference {
+            line,
+            column,
+            len: name.len() as u32,
+            definition,
+        });
+    }
+
+    /// Define the globals first, functions can use the ones declared after them
+    fn declare_global(&mut self, stmt: &Stmt) {
+        let (name, kind) = match &stmt.kind {
+            StmtKind::Var { name, .. } => (name, SymbolKind::Variable),
+            StmtKind::Const { name, .. } => (name, SymbolKind::Constant),
+            StmtKind::Function(Function {
+                name: Some(name), ..
+            }) => (name, SymbolKind::Function),
+            StmtKind::Class(Class { name, .. }) => (name, SymbolKind::Class),
+            StmtKind::Import {
+                name: Some(name), ..
+            } => (name, SymbolKind::Module),
+            _ => return,
+        };
+        if self.globals.contains_key(name) {
+            return;
+        }
+        let position = match &stmt.kind {
+            StmtKind::Function(function) => (function.line, function.column),
+            _ => self.find(name, stmt.line, stmt.column),
+        };
+        let index = self.define(name, kind, position, stmt.end_line);
+        if let StmtKind::Function(function) = &stmt.kind {
+            self.signature(index, function);
+        }
+        self.globals.insert(name.clone(), index);
+    }
+
+    /// Declare `name` in the innermost scope. Globals are already defined
+    fn declare(
+        &mut self,
+        name: &str,
+        kind: SymbolKind,
+        position: (u32, u32),
+        end_line: u32,
+    ) -> usize {
+        if self.scopes.is_empty()
+            && let Some(&index) = self.globals.get(name)
+        {
+            self.reference(name, position, index);
+            return index;
+        }
+        let index = self.define(name, kind, position, end_line);
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.push((name.to_string(), index));
+        }
+        index
+    }
+
+    fn signature(&mut self, index: usize, function: &Function) {
+        let params = &function.params;
+        let required = params
+            .iter()
+            .filter(|param| param.default.is_none() && !param.rest)
+            .count();
+        let rest = params.iter().any(|param| param.rest);
+        let definition = &mut self.symbols.definitions[index];
+        definition.params = Some(
+            params
+                .iter()
+                .map(|param| {
+                    let rest = if param.rest { "..." } else { "" };
+                    format!("{rest}{}", param.name)
+                })
+                .collect(),
+        );
+        definition.arity = Some((required, (!rest).then_some(params.len())));
+    }
+
+    fn resolve(&mut self, name: &str, position: (u32, u32)) {
+        let local = self
+            .scopes
+            .iter()
+            .flatten()
+            .rev()
+            .find(|(local, _)| local == name)
+            .map(|&(_, index)| index);
+        if let Some(index) = local.or_else(|| self.globals.get(name).copied()) {
+            self.reference(name, position, index);
+        }
+    }
+
+    fn stmts(&mut self, stmts: &[Stmt]) {
+        for stmt in stmts {
+            self.stmt(stmt);
+        }
+    }
+
+    fn block(&mut self, block: &Block) {
+        self.scopes.push(vec![]);
+        self.stmts(&block.stmts);
+        self.scopes.pop();
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        let start = (stmt.line, stmt.column);
+        match &stmt.kind {
+            StmtKind::Expression(value)
+            | StmtKind::Print(value)
+            | StmtKind::Throw(value)
+            | StmtKind::Assert(value) => self.expr(value),
+            StmtKind::Var { name, init } => {
+                if let Some(init) = init {
+                    self.expr(init);
+                }
+                let position = self.find(name, start.0, start.1);
+                self.declare(name, SymbolKind::Variable, position, stmt.end_line);
+            }
+            StmtKind::Const { name, init } => {
+                self.expr(init);
+                let position = self.find(name, start.0, start.1);
+                self.declare(name, SymbolKind::Constant, position, stmt.end_line);
+            }
+            StmtKind::Function(function) => {
+                let name = function.name.as_deref().unwrap_or_default();
+                let position = (function.line, function.column);
+                let index = self.declare(name, SymbolKind::Function, position, stmt.end_line);
+                self.signature(index, function);
+                self.function(function);
+            }
+            StmtKind::Class(class) => self.class(stmt, class),
+            StmtKind::Import { name, .. } => {
+                if let Some(name) = name {
+                    let position = self.find(name, start.0, start.1);
+                    self.declare(name, SymbolKind::Module, position, stmt.end_line);
+                }
+            }
+            StmtKind::Block(block) => self.block(block),
+            StmtKind::If {
+                condition,
+                then,
+                otherwise,
+            } => {
+                self.expr(condition);
+                self.stmt(then);
+                if let Some(otherwise) = otherwise {
+                    self.stmt(otherwise);
+                }
+            }
+            StmtKind::While { condition, body } => {
+                self.expr(condition);
+                self.stmt(body);
+            }
+            StmtKind::For {
+                init,
+                condition,
+                increment,
+                body,
+            } => {
+                self.scopes.push(vec![]);
+                if let Some(init) = init {
+                    self.stmt(init);
+                }
+                for expr in [condition, increment].into_iter().flatten() {
+                    self.expr(expr);
+                }
+                self.stmt(body);
+                self.scopes.pop();
+            }
+            StmtKind::ForIn {
+                item,
+                collection,
+                body,
+            } => {
+                self.expr(collection);
+                self.scopes.push(vec![]);
+                let position = self.find(item, start.0, start.1);
+                self.declare(item, SymbolKind::Variable, position, stmt.end_line);
+                self.stmt(body);
+                self.scopes.pop();
+            }
+            StmtKind::Switch {
+                value,
+                cases,
+                default,
+            } => {
+                self.expr(value);
+                for (value, body) in cases {
+                    self.expr(value);
+                    self.scopes.push(vec![]);
+                    self.stmts(body);
+                    self.scopes.pop();
+                }
+                if let Some(body) = default {
+                    self.scopes.push(vec![]);
+                    self.stmts(body);
+                    self.scopes.pop();
+                }
+            }
+            StmtKind::Break | StmtKind::Continue => (),
+            StmtKind::Return(value) | StmtKind::Yield(value) => {
+                if let Some(value) = value {
+                    self.expr(value);
+                }
+            }
+            StmtKind::Try {
+                body,
+                catch,
+                finally,
+            } => {
+                self.block(body);
+                if let Some((name, catch)) = catch {
+                    self.scopes.push(vec![]);
+                    let position = self.find(name, body.end_line, 1);
+                    self.declare(name, SymbolKind::Variable, position, catch.end_line);
+                    self.block(catch);
+                    self.scopes.pop();
+                }
+                if let Some(finally) = finally {
+                    self.block(finally);
+                }
+            }
+        }
+    }
+
+    fn class(&mut self, stmt: &Stmt, class: &Class) {
+        let position = self.find(&class.name, stmt.line, stmt.column);
+        let index = self.declare(&class.name, SymbolKind::Class, position, stmt.end_line);
+        if let Some(superclass) = &class.superclass {
+            let position = self.find(superclass, position.0, position.1 + 1);
+            self.resolve(superclass, position);
+        }
+
+        // The members first, methods can use the ones declared after them
+        let mut members = vec![];
+        for member in &class.members {
+            let (function, kind, name, position, end_line) = match member {
+                Member::Method { function, .. }
+                | Member::Getter(function)
+                | Member::Setter(function) => {
+                    let name = function.name.clone().unwrap_or_default();
+                    let position = (function.line, function.column);
+                    let end_line = function.body.end_line;
+                    (Some(function), SymbolKind::Method, name, position, end_line)
+                }
+                Member::Field { name, line, .. } => {
+                    let position = self.find(name, *line, 1);
+                    (None, SymbolKind::Field, name.clone(), position, *line)
+                }
+            };
+            // A getter and its setter are one property
+            if let Some(&existing) = members
+                .iter()
+                .find(|&&member: &&usize| self.symbols.definitions[member].name == name)
+            {
+                self.reference(&name, position, existing);
+                continue;
+            }
+            let member = self.define(&name, kind, position, end_line);
+            self.symbols.definitions[member].parent = Some(index);
+            if let Some(function) = function {
+                self.signature(member, function);
+                if name == "init" {
+                    let init = self.symbols.definitions[member].clone();
+                    let class = &mut self.symbols.definitions[index];
+                    class.params = init.params;
+                    class.arity = init.arity;
+                }
+            }
+            members.push(member);
+        }
+
+        let enclosing = self.class.replace(index);
+        for member in &class.members {
+            match member {
+                Member::Method { function, .. }
+                | Member::Getter(function)
+                | Member::Setter(function) => self.function(function),
+                Member::Field {
+                    init: Some(init), ..
+                } => self.expr(init),
+                Member::Field { .. } => (),
+            }
+        }
+        self.class = enclosing;
+    }
+
+    fn function(&mut self, function: &Function) {
+        self.scopes.push(vec![]);
+        let mut after = (function.line, function.column);
+        for param in &function.params {
+            if let Some(default) = &param.default {
+                self.expr(default);
+            }
+            let position = self.find(&param.name, after.0, after.1);
+            self.declare(&param.name, SymbolKind::Parameter, position, function.line);
+            after = (position.0, position.1 + 1);
+        }
+        // The body shares the scope of the parameters
+        self.stmts(&function.body.stmts);
+        self.scopes.pop();
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        let start = (expr.line, expr.column);
+        match &expr.kind {
+            ExprKind::Nil
+            | ExprKind::Bool(_)
+            | ExprKind::Number(_)
+            | ExprKind::String(_)
+            | ExprKind::This
+            | ExprKind::Super(_) => (),
+            ExprKind::Interpolation { parts, .. } => {
+                for (_, expr) in parts {
+                    self.expr(expr);
+                }
+            }
+            ExprKind::Variable(name) => self.resolve(name, start),
+            ExprKind::Grouping(inner) => self.expr(inner),
+            ExprKind::Unary { operand, .. } => self.expr(operand),
+            ExprKind::Binary { left, right, .. } | ExprKind::Logical { left, right, .. } => {
+                self.expr(left);
+                self.expr(right);
+            }
+            ExprKind::Conditional {
+                condition,
+                then,
+                otherwise,
+            } => {
+                self.expr(condition);
+                self.expr(then);
+                self.expr(otherwise);
+            }
+            ExprKind::Assign { target, value, .. } => {
+                self.expr(target);
+                self.expr(value);
+            }
+            ExprKind::Increment { name, .. } => {
+                let position = self.find(name, start.0, start.1);
+                self.resolve(name, position);
+            }
+            ExprKind::Call { callee, args } => {
+                self.expr(callee);
+                for arg in args {
+                    self.expr(&arg.value);
+                }
+            }
+            ExprKind::Get { object, name } => {
+                self.expr(object);
+                if let ExprKind::This = object.kind
+                    && let Some(class) = self.class
+                    && let Some(member) = self
+                        .symbols
+                        .definitions
+                        .iter()
+                        .position(|member| member.parent == Some(class) && &member.name == name)
+                {
+                    let position = self.find(name, start.0, start.1);
+                    self.reference(name, position, member);
+                }
+            }
+            ExprKind::Index { object, index } => {
+                self.expr(object);
+                self.expr(index);
+            }
+            ExprKind::Slice { object, start, end } => {
+                self.expr(object);
+                for bound in [start, end].into_iter().flatten() {
+                    self.expr(bound);
+                }
+            }
+            ExprKind::List(elements) => {
+                for element in elements {
+                    self.expr(element);
+                }
+            }
+            ExprKind::Map(entries) => {
+                for (key, value) in entries {
+                    self.expr(key);
+                    self.expr(value);
+                }
+            }
+            ExprKind::Lambda(function) => self.function(function),
+        }
+    }
+}
@@ -0,0 +1,294 @@
+use std::io::{self, BufRead, Write};
+
+use loxide::{vm::VM, Event, InterpretResult, Script, Status, Value};
+
+const PROMPT: &str = "(debug) ";
+
+const HELP: &str = "\
+Commands:
+  break <line|function>   Stop at a line, or when a function is called (b)
+  delete <line|function>  Remove a breakpoint (d)
+  step                    Run to the next line, into calls (s)
+  next                    Run to the next line, over calls (n)
+  stepi                   Run a single instruction (si)
+  finish                  Run until the current call returns
+  continue                Run to the next breakpoint or the end of the script (c)
+  backtrace               Print the calls being run (bt)
+  stack                   Print the whole stack
+  locals                  Print the slots of the current call: the callee, its arguments
+                          and locals by name, and temporaries
+  print <global>          Print a global variable (p)
+  help                    Print this help (h)
+  quit                    Stop debugging (q)
+An empty line repeats the last command.";
+
+#[derive(Default)]
+struct Breakpoints {
+    lines: Vec<u32>,
+    functions: Vec<String>,
+}
+
+impl Breakpoints {
+    /// Whether the VM paused at a breakpoint. `last` is the call depth and line it paused at
+    /// before, a line only counts when it's entered from another line or call
+    fn hit(&self, vm: &VM, last: (u32, Option<u32>)) -> bool {
+        let depth = vm.call_depth();
+        let line = vm.current_line();
+        let entered_call = depth > last.0;
+        let at_line = line.map_or(false, |line| self.lines.contains(&line))
+            && (entered_call || line != last.1);
+        let at_function = entered_call
+            && vm.backtrace()[0]
+                .function
+                .as_ref()
+                .map_or(false, |name| self.functions.contains(name));
+        at_line || at_function
+    }
+}
+
+/// `loxide debug`: run `script` under a command line debugger that reads commands from `input`
+/// and writes to `out`. The script's own output still goes to stdout
+pub fn run(script: Script, mut input: impl BufRead, mut out: impl Write) -> io::Result<()> {
+    let vm = script.start();
+    let mut breakpoints = Breakpoints::default();
+    let mut last_command = String::new();
+    print_location(vm, &mut out)?;
+
+    loop {
+        write!(out, "{PROMPT}")?;
+        out.flush()?;
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let line = match line.trim() {
+            "" => last_command.clone(),
+            line => line.to_string(),
+        };
+        last_command = line.clone();
+
+        let (command, arg) = line.split_once(' ').unwrap_or((&line, ""));
+        let arg = arg.trim();
+        let result = match command {
+            "s" | "step" => vm.run_until(Event::Line),
+            "n" | "next" => next(vm, &breakpoints),
+            "si" | "stepi" => vm.step(),
+            "finish" => vm.run_until(Event::Return),
+            "c" | "continue" => continue_(vm, &breakpoints),
+            "b" | "break" | "d" | "delete" => {
+                let add = matches!(command, "b" | "break");
+                edit_breakpoints(&mut breakpoints, add, arg, &mut out)?;
+                continue;
+            }
+            "bt" | "backtrace" => {
+                for frame in vm.backtrace() {
+                    writeln!(out, "{frame}")?;
+                }
+                continue;
+            }
+            "stack" => {
+                let values = vm.stack_values();
+                print_values(vm, &values, &mut out)?;
+                continue;
+            }
+            "locals" => {
+                print_locals(vm, &mut out)?;
+                continue;
+            }
+            "p" | "print" => {
+                match vm.get_global(arg).and_then(|value| vm.value_ref(value)) {
+                    // Same format as `print`
+                    Some(value) => writeln!(out, "{value}")?,
+                    None => writeln!(out, "No global named '{arg}'.")?,
+                }
+                continue;
+            }
+            "h" | "help" => {
+                writeln!(out, "{HELP}")?;
+                continue;
+            }
+            "q" | "quit" => return Ok(()),
+            _ => {
+                writeln!(out, "Unknown command '{command}', try 'help'.")?;
+                continue;
+            }
+        };
+
+        match result {
+            Ok(Status::Paused) => print_location(vm, &mut out)?,
+            Ok(Status::Finished(_)) => {
+                writeln!(out, "The script finished.")?;
+                return Ok(());
+            }
+            // The VM already reported the error on stderr
+            Err(_) => {
+                writeln!(out, "The script failed.")?;
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Run to the next line of the current call or one of its callers, stopping early at a
+/// breakpoint in a callee
+fn next(vm: &mut VM, breakpoints: &Breakpoints) -> InterpretResult<Status> {
+    let depth = vm.call_depth();
+    let line = vm.current_line();
+    let mut last = (depth, line);
+    loop {
+        let status = vm.run_until(Event::Line)?;
+        let here = (vm.call_depth(), vm.current_line());
+        if status != Status::Paused
+            || here.0 < depth
+            || (here.0 == depth && here.1 != line)
+            || breakpoints.hit(vm, last)
+        {
+            return Ok(status);
+        }
+        last = here;
+    }
+}
+
+fn continue_(vm: &mut VM, breakpoints: &Breakpoints) -> InterpretResult<Status> {
+    let mut last = (vm.call_depth(), vm.current_line());
+    loop {
+        let status = vm.run_until(Event::Line)?;
+        if status != Status::Paused || breakpoints.hit(vm, last) {
+            return Ok(status);
+        }
+        last = (vm.call_depth(), vm.current_line());
+    }
+}
+
+fn edit_breakpoints(
+    breakpoints: &mut Breakpoints,
+    add: bool,
+    arg: &str,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    if arg.is_empty() {
+        return writeln!(out, "Expected a line or a function name.");
+    }
+    match arg.parse::<u32>() {
+        Ok(line) if add => breakpoints.lines.push(line),
+        Ok(line) => breakpoints.lines.retain(|&other| other != line),
+        Err(_) if add => breakpoints.functions.push(arg.to_string()),
+        Err(_) => breakpoints.functions.retain(|other| other != arg),
+    }
+    let what = match arg.parse::<u32>() {
+        Ok(line) => format!("line {line}"),
+        Err(_) => format!("function {arg}"),
+    };
+    let done = if add {
+        "Breakpoint at"
+    } else {
+        "Deleted breakpoint at"
+    };
+    writeln!(out, "{done} {what}.")
+}
+
+/// The call, line and instruction the script is paused at
+fn print_location(vm: &VM, out: &mut impl Write) -> io::Result<()> {
+    let Some(instruction) = vm.current_instruction() else {
+        return Ok(());
+    };
+    writeln!(out, "{}: {instruction}", vm.backtrace()[0])
+}
+
+/// Like [`print_values`], with the names of the locals that are in scope
+fn print_locals(vm: &mut VM, out: &mut impl Write) -> io::Result<()> {
+    let values = vm.frame_slots();
+    let names = vm.local_names();
+    for (slot, value) in values.iter().enumerate() {
+        let value = format_value(vm, *value);
+        match names.iter().find(|(local, _)| *local as usize == slot) {
+            Some((_, name)) => writeln!(out, "{slot:4}: {name} = {value}")?,
+            None => writeln!(out, "{slot:4}: {value}")?,
+        }
+    }
+    Ok(())
+}
+
+/// Like `--trace`
+fn print_values(vm: &VM, values: &[Value], out: &mut impl Write) -> io::Result<()> {
+    for (slot, value) in values.iter().enumerate() {
+        writeln!(out, "{slot:4}: {}", format_value(vm, *value))?;
+    }
+    Ok(())
+}
+
+/// Like `--trace` shows values: strings are quoted so they can be told apart from other values
+fn format_value(vm: &VM, value: Value) -> String {
+    let value = vm
+        .value_ref(value)
+        .expect("the stack only holds live objects");
+    match value.as_str() {
+        Some(string) => format!("'{string}'"),
+        None => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use loxide::{compile, vm::VM};
+
+    fn debug(src: &str, commands: &str) -> String {
+        let mut vm = VM::new();
+        let script = compile(&mut vm, src).unwrap();
+        let mut out = vec![];
+        super::run(script, commands.as_bytes(), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn commands() {
+        let src = "var a = 1;
+fun add(x, y) {
+    var sum = x + y;
+    return sum;
+}
+var b = add(a, 2);
+var c = add(b, 3);";
+        let out = debug(
+            src,
+            "break add\ncontinue\nlocals\nbt\nnext\nfinish\nbreak 7\ncontinue\nprint b\n\n",
+        );
+        let expected = "\
+[line 1] in script: 0000    1 Constant            1 1
+(debug) Breakpoint at function add.
+(debug) [line 3] in add(1, 2): 0000    3 GetLocal            1
+(debug)    0: <fn add>
+   1: x = 1
+   2: y = 2
+(debug) [line 3] in add(1, 2)
+[line 6] in script
+(debug) [line 4] in add(1, 2): 0005    4 GetLocal            3
+(debug) [line 6] in script: 0016    | DefineGlobal        4 'b'
+(debug) Breakpoint at line 7.
+(debug) [line 7] in script: 0018    7 GetGlobal           2 'add'
+(debug) 3
+(debug) 3
+(debug) ";
+        assert_eq!(out, expected);
+
+        // Stepping into calls, instruction by instruction and past the end
+        let out = debug(src, "b 6\nc\ns\nsi\nstack\nd 6\nc\nc\n");
+        assert!(out.contains("[line 3] in add(1, 2): 0000    3 GetLocal            1\n(debug) [line 3] in add(1, 2): 0002    | GetLocal            2\n"), "{out}");
+        assert!(
+            out.contains("   0: <script>\n   1: <fn add>\n   2: 1\n   3: 2\n   4: 1\n"),
+            "{out}"
+        );
+        assert!(
+            out.ends_with("Deleted breakpoint at line 6.\n(debug) The script finished.\n"),
+            "{out}"
+        );
+
+        let out = debug(src, "b 4\nc\nlocals\n");
+        assert!(out.contains("   3: sum = 3\n"), "{out}");
+
+        let out = debug("var a = 1;\nvar b = a + nil;", "next\nnext\nwhat\n");
+        assert!(out.ends_with("(debug) The script failed.\n"), "{out}");
+        let out = debug("print 1;", "what\nb\nq\nnext\n");
+        assert!(out.ends_with("Unknown command 'what', try 'help'.\n(debug) Expected a line or a function name.\n(debug) "), "{out}");
+    }
+}
@@ -0,0 +1,339 @@
+//! C API for embedding loxide in other languages, declared in `include/loxide.h`.
+//!
+//! A `LoxideVm` is an opaque handle made by [`loxide_new`] and freed with [`loxide_free`], it
+//! wraps a [`Loxide`] engine so globals persist between calls to [`loxide_eval`]. Values cross
+//! the boundary as [`LoxideValue`]s. Strings in them point into the VM and are only valid until
+//! the next call on the handle, copy them to keep them.
+//!
+//! None of the functions may be called with a null or freed handle, and a handle must only be
+//! used from one thread at a time.
+
+use std::{
+    ffi::{c_char, c_int, c_void, CStr, CString},
+    ptr::null,
+};
+
+use crate::{native_fn::Arity, InterpretError, Loxide, Value, ValueRef, VM};
+
+pub const LOXIDE_OK: c_int = 0;
+pub const LOXIDE_COMPILE_ERROR: c_int = 1;
+pub const LOXIDE_RUNTIME_ERROR: c_int = 2;
+
+/// Handle to an interpreter
+pub struct LoxideVm {
+    engine: Loxide,
+    /// Message of the last error, see [`loxide_error`]
+    error: CString,
+    /// Text of the last object value handed out that isn't a string
+    scratch: CString,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoxideValueKind {
+    Nil = 0,
+    Bool = 1,
+    Number = 2,
+    String = 3,
+    /// Any other object, `string` is how `print` shows it
+    Object = 4,
+}
+
+/// A Lox value. `boolean` is only meaningful for `Bool`, `number` for `Number`, and `string`
+/// and `len` for `String` and `Object`. Strings aren't NUL-terminated
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct LoxideValue {
+    pub kind: LoxideValueKind,
+    pub boolean: bool,
+    pub number: f64,
+    pub string: *const c_char,
+    pub len: usize,
+}
+
+impl LoxideValue {
+    const NIL: Self = Self {
+        kind: LoxideValueKind::Nil,
+        boolean: false,
+        number: 0.0,
+        string: null(),
+        len: 0,
+    };
+}
+
+/// A native implemented in C. It gets the `user_data` it was registered with and the
+/// arguments, and returns 0 after storing the result in `result`. Anything else raises a runtime
+/// error, with the string in `result` as the message if there is one
+pub type LoxideNativeFn = unsafe extern "C" fn(
+    user_data: *mut c_void,
+    args: *const LoxideValue,
+    arg_count: usize,
+    result: *mut LoxideValue,
+) -> c_int;
+
+/// A new interpreter with the default options
+#[no_mangle]
+pub extern "C" fn loxide_new() -> *mut LoxideVm {
+    Box::into_raw(Box::new(LoxideVm {
+        engine: Loxide::new(),
+        error: CString::default(),
+        scratch: CString::default(),
+    }))
+}
+
+/// Free an interpreter made by `loxide_new`, null is ignored
+///
+/// # Safety
+///
+/// `vm` must be null or a handle that wasn't freed yet
+#[no_mangle]
+pub unsafe extern "C" fn loxide_free(vm: *mut LoxideVm) {
+    if !vm.is_null() {
+        drop(unsafe { Box::from_raw(vm) });
+    }
+}
+
+/// Compile and run the NUL-terminated `source`. Returns `LOXIDE_OK` and stores the value of a
+/// trailing expression without a semicolon (or nil) in `result` if it isn't null, or the kind
+/// of error, see `loxide_error`
+///
+/// # Safety
+///
+/// `vm` must be a live handle, `source` a NUL-terminated string and `result` null or writable
+#[no_mangle]
+pub unsafe extern "C" fn loxide_eval(
+    vm: *mut LoxideVm,
+    source: *const c_char,
+    result: *mut LoxideValue,
+) -> c_int {
+    let vm = unsafe { &mut *vm };
+    let Ok(source) = unsafe { CStr::from_ptr(source) }.to_str() else {
+        vm.set_error("Source is not valid UTF-8.".to_string());
+        return LOXIDE_COMPILE_ERROR;
+    };
+
+    match vm.engine.eval(source) {
+        Ok(value) => {
+            if !result.is_null() {
+                unsafe { *result = vm.export(value) };
+            }
+            LOXIDE_OK
+        }
+        Err(err) => {
+            let status = match err {
+                InterpretError::CompileError(_) => LOXIDE_COMPILE_ERROR,
+                _ => LOXIDE_RUNTIME_ERROR,
+            };
+            vm.set_error(err.to_string());
+            status
+        }
+    }
+}
+
+/// The message of the last error, an empty string if there was none. Valid until the next
+/// call on the handle
+///
+/// # Safety
+///
+/// `vm` must be a live handle
+#[no_mangle]
+pub unsafe extern "C" fn loxide_error(vm: *const LoxideVm) -> *const c_char {
+    unsafe { (*vm).error.as_ptr() }
+}
+
+/// Look up the global `name` and store it in `result`. Returns whether there is such a global
+///
+/// # Safety
+///
+/// `vm` must be a live handle, `name` a NUL-terminated string and `result` writable
+#[no_mangle]
+pub unsafe extern "C" fn loxide_get_global(
+    vm: *mut LoxideVm,
+    name: *const c_char,
+    result: *mut LoxideValue,
+) -> bool {
+    let vm = unsafe { &mut *vm };
+    let Ok(name) = unsafe { CStr::from_ptr(name) }.to_str() else {
+        return false;
+    };
+    match vm.engine.get_global(name) {
+        Some(value) => {
+            unsafe { *result = vm.export(value) };
+            true
+        }
+        None => false,
+    }
+}
+
+/// Define the global `name` as a native calling `native` with `user_data`. `arity` is the
+/// number of arguments the VM checks for, or -1 to take any number
+///
+/// # Safety
+///
+/// `vm` must be a live handle and `name` a NUL-terminated string. `native` is called with
+/// `user_data` for as long as the handle lives
+#[no_mangle]
+pub unsafe extern "C" fn loxide_register_native(
+    vm: *mut LoxideVm,
+    name: *const c_char,
+    arity: c_int,
+    native: LoxideNativeFn,
+    user_data: *mut c_void,
+) -> bool {
+    let vm = unsafe { &mut *vm };
+    let Some((name, arity)) = (unsafe { signature(name, arity) }) else {
+        return false;
+    };
+    let function = unsafe { host_fn(native, user_data) };
+    vm.engine.register_native(name, arity, function);
+    true
+}
+
+/// The name and arity of a native registered from C, `None` if they aren't valid
+///
+/// # Safety
+///
+/// `name` must be a NUL-terminated string that lives for `'a`
+pub(crate) unsafe fn signature<'a>(name: *const c_char, arity: c_int) -> Option<(&'a str, Arity)> {
+    let name = unsafe { CStr::from_ptr(name) }.to_str().ok()?;
+    let arity = match arity {
+        -1 => Arity::Variadic,
+        arity => Arity::Fixed(u8::try_from(arity).ok()?),
+    };
+    Some((name, arity))
+}
+
+/// A host function calling the C function `native` with `user_data`
+///
+/// # Safety
+///
+/// `native` must be safe to call with `user_data` for as long as the result lives
+pub(crate) unsafe fn host_fn(
+    native: LoxideNativeFn,
+    user_data: *mut c_void,
+) -> impl Fn(&mut VM, &[Value]) -> Result<Value, String> + Send + 'static {
+    let user_data = UserData(user_data);
+    move |vm, args| {
+        // Object values in the arguments get their text from here
+        let mut scratch = vec![];
+        let args: Vec<_> = args
+            .iter()
+            .map(|&value| {
+                let value = vm
+                    .value_ref(value)
+                    .expect("arguments are alive during the call");
+                export(value, &mut scratch)
+            })
+            .collect();
+        let mut result = LoxideValue::NIL;
+        // Safety: the caller of `host_fn` vouched for `native` and `user_data`, and `result`
+        // holds a string of the given length if the native set one
+        unsafe {
+            if native(user_data.get(), args.as_ptr(), args.len(), &mut result) != 0 {
+                return Err(match result.kind {
+                    LoxideValueKind::String => string(&result)?.to_string(),
+                    _ => "Native function failed.".to_string(),
+                });
+            }
+            import(vm, &result)
+        }
+    }
+}
+
+/// The `user_data` of a native, which goes wherever the handle goes
+struct UserData(*mut c_void);
+
+// Safety: a handle (or VM) is only used from one thread at a time, so the native only ever runs
+// on the thread it is used from
+unsafe impl Send for UserData {}
+
+impl UserData {
+    fn get(&self) -> *mut c_void {
+        self.0
+    }
+}
+
+impl LoxideVm {
+    fn set_error(&mut self, message: String) {
+        // Lox strings can contain NUL, C can't see past it anyway
+        let message = message.split('\0').next().unwrap_or_default();
+        self.error = CString::new(message).unwrap();
+    }
+
+    fn export(&mut self, value: Value) -> LoxideValue {
+        let Some(value) = self.engine.vm.value_ref(value) else {
+            return LoxideValue::NIL;
+        };
+        let mut scratch = vec![];
+        let exported = export(value, &mut scratch);
+        if let Some(text) = scratch.pop() {
+            self.scratch = text;
+            return LoxideValue {
+                string: self.scratch.as_ptr(),
+                ..exported
+            };
+        }
+        exported
+    }
+}
+
+/// `value` for C. The text of objects that aren't strings is added to `scratch`, which has to
+/// live as long as the result is used
+fn export(value: ValueRef<'_>, scratch: &mut Vec<CString>) -> LoxideValue {
+    if let Ok(()) = value.try_into() {
+        return LoxideValue::NIL;
+    }
+    if let Ok(boolean) = value.try_into() {
+        return LoxideValue {
+            kind: LoxideValueKind::Bool,
+            boolean,
+            ..LoxideValue::NIL
+        };
+    }
+    if let Ok(number) = value.try_into() {
+        return LoxideValue {
+            kind: LoxideValueKind::Number,
+            number,
+            ..LoxideValue::NIL
+        };
+    }
+    if let Some(string) = value.as_str() {
+        return LoxideValue {
+            kind: LoxideValueKind::String,
+            string: string.as_ptr().cast(),
+            len: string.len(),
+            ..LoxideValue::NIL
+        };
+    }
+    let text = value.to_string().replace('\0', "");
+    let text = CString::new(text).unwrap();
+    let exported = LoxideValue {
+        kind: LoxideValueKind::Object,
+        string: text.as_ptr(),
+        len: text.as_bytes().len(),
+        ..LoxideValue::NIL
+    };
+    // Moving the `CString` doesn't move its buffer
+    scratch.push(text);
+    exported
+}
+
+/// A value from C, strings are copied into the VM
+unsafe fn import(vm: &mut VM, value: &LoxideValue) -> Result<Value, String> {
+    match value.kind {
+        LoxideValueKind::Nil => Ok(Value::Nil),
+        LoxideValueKind::Bool => Ok(Value::Bool(value.boolean)),
+        LoxideValueKind::Number => Ok(Value::Number(value.number)),
+        LoxideValueKind::String => Ok(vm.create_string(unsafe { string(value) }?)),
+        LoxideValueKind::Object => Err("Natives can't return objects.".to_string()),
+    }
+}
+
+/// The string in `value`, which may be null if it's empty
+unsafe fn string(value: &LoxideValue) -> Result<&str, String> {
+    if value.string.is_null() {
+        return Ok("");
+    }
+    let bytes = unsafe { std::slice::from_raw_parts(value.string.cast(), value.len) };
+    std::str::from_utf8(bytes).map_err(|_| "Native returned a string that isn't UTF-8.".to_string())
+}
@@ -0,0 +1,115 @@
+use crate::{
+    mem::{Gc, Greystack},
+    obj::ObjString,
+    table::Table,
+    value::{display::ValueWrapper, Value},
+};
+
+/// Global variables.
+///
+/// Every name gets a slot the first time it's defined or looked up, and keeps it for the
+/// lifetime of the VM. The VM caches the slot of each global an instruction refers to in the
+/// instruction's chunk (see [`Chunk::global_slots`]), so only the first access hashes the name.
+///
+/// [`Chunk::global_slots`]: crate::chunk::Chunk::global_slots
+pub struct Globals {
+    /// Slot of each name, as a number
+    slots: Table,
+    names: Vec<Gc<ObjString>>,
+    /// `None` for names that were looked up but never defined
+    values: Vec<Option<Value>>,
+}
+
+impl Globals {
+    pub fn new() -> Self {
+        Self {
+            slots: Table::new(),
+            names: vec![],
+            values: vec![],
+        }
+    }
+
+    /// Slot of `name`, reserving a new one if the name hasn't been seen yet
+    pub(crate) fn slot(&mut self, name: Gc<ObjString>) -> usize {
+        if let Some(slot) = self.slots.get(name) {
+            return slot.as_number().unwrap() as usize;
+        }
+
+        let slot = self.values.len();
+        self.slots.set(name, Value::Number(slot as f64));
+        self.names.push(name);
+        self.values.push(None);
+        slot
+    }
+
+    #[inline]
+    pub fn get_slot(&self, slot: usize) -> Option<Value> {
+        self.values[slot]
+    }
+
+    #[inline]
+    pub fn set_slot(&mut self, slot: usize, value: Value) {
+        self.values[slot] = Some(value);
+    }
+
+    pub(crate) fn name(&self, slot: usize) -> Gc<ObjString> {
+        self.names[slot]
+    }
+
+    /// The names of the globals that are defined
+    pub(crate) fn defined(&self) -> impl Iterator<Item = Gc<ObjString>> + '_ {
+        self.names
+            .iter()
+            .zip(&self.values)
+            .filter(|(_, value)| value.is_some())
+            .map(|(&name, _)| name)
+    }
+
+    pub(crate) fn get(&self, name: Gc<ObjString>) -> Option<Value> {
+        let slot = self.slots.get(name)?;
+        self.values[slot.as_number().unwrap() as usize]
+    }
+
+    /// Define or overwrite `name`. Returns whether it wasn't defined before
+    pub(crate) fn set(&mut self, name: Gc<ObjString>, value: Value) -> bool {
+        let slot = self.slot(name);
+        self.values[slot].replace(value).is_none()
+    }
+
+    /// Undefine `name`, its slot stays reserved. Returns whether it was defined
+    #[cfg(test)]
+    pub(crate) fn delete(&mut self, name: Gc<ObjString>) -> bool {
+        match self.slots.get(name) {
+            Some(slot) => self.values[slot.as_number().unwrap() as usize]
+                .take()
+                .is_some(),
+            None => false,
+        }
+    }
+
+    pub(crate) fn mark(&self, greystack: &mut Greystack) {
+        self.slots.mark(greystack);
+        for value in self.values.iter().flatten() {
+            value.mark(greystack);
+        }
+    }
+}
+
+impl std::fmt::Debug for Globals {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_map()
+            .entries(
+                self.names
+                    .iter()
+                    .zip(&self.values)
+                    .filter_map(|(name, value)| Some((name.as_str(), ValueWrapper((*value)?)))),
+            )
+            .finish()
+    }
+}
+
+impl Default for Globals {
+    fn default() -> Self {
+        Self::new()
+    }
+}
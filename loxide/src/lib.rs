@@ -0,0 +1,5681 @@
+#![deny(unsafe_op_in_unsafe_fn)]
+#![feature(ptr_sub_ptr)]
+#![feature(allocator_api)]
+#![feature(slice_ptr_get)]
+#![feature(let_chains)]
+#![cfg_attr(test, feature(test))]
+
+pub mod chunk;
+pub mod compile;
+pub mod ffi;
+pub mod globals;
+pub mod mem;
+pub mod module;
+pub mod native_fn;
+pub mod obj;
+pub mod pool;
+pub mod replay;
+pub mod report;
+pub mod table;
+pub mod value;
+pub mod vm;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+use std::io::Write;
+
+use compile::Parser;
+use mem::{Gc, GcConfig};
+use native_fn::Arity;
+use obj::ObjFunction;
+use pool::Channel;
+
+pub use compile::CompileError;
+pub(crate) use value::Unpacked;
+pub use value::{Value, ValueRef};
+pub use vm::{
+    Event, InterpretError, InterpretResult, RuntimeError, Status, TraceFrame, VmOptions, VM,
+};
+
+/// Compile and run `src` on `vm`
+pub fn interpret(vm: &mut VM, src: &str) -> InterpretResult<Value> {
+    compile_and_run(vm, src, false)
+}
+
+/// Compile `src` without running it, optimized if [`VM::optimize`] is set
+pub fn compile<'vm>(vm: &'vm mut VM, src: &str) -> InterpretResult<Script<'vm>> {
+    let function = compile_with(vm, src, false)?;
+    Ok(Script { vm, function })
+}
+
+/// Load bytecode written by [`Script::serialize`]. Truncated or corrupted files are rejected,
+/// crafted ones that get past that fail with runtime errors when they run
+pub fn load_bytecode<'vm>(vm: &'vm mut VM, bytes: &[u8]) -> Result<Script<'vm>, String> {
+    let function = chunk::serialize::deserialize(&mut vm.mem, bytes)?;
+    Ok(Script { vm, function })
+}
+
+/// A script compiled with [`compile`] or loaded with [`load_bytecode`] that hasn't run yet. It
+/// holds on to the VM, which can't collect the script's function before it runs
+pub struct Script<'vm> {
+    vm: &'vm mut VM,
+    function: Gc<ObjFunction>,
+}
+
+impl<'vm> Script<'vm> {
+    /// Run the script on the VM it was compiled for
+    pub fn run(self) -> InterpretResult<Value> {
+        run_function(self.vm, self.function)
+    }
+
+    /// Get the VM ready to run the script without running it, to go through it with
+    /// [`VM::step`] or [`VM::run_until`]
+    pub fn start(self) -> &'vm mut VM {
+        self.vm.init(self.function);
+        self.vm
+    }
+
+    /// Listing of the bytecode, see [`chunk::disassemble`]
+    pub fn disassemble(&self) -> String {
+        chunk::disassemble::disassemble_function(&self.function)
+    }
+
+    /// The bytecode in the format of [`chunk::serialize`]
+    pub fn serialize(&self) -> Vec<u8> {
+        chunk::serialize::serialize(&self.function)
+    }
+}
+
+/// Instructions [`run_str`] runs before giving up with [`InterpretError::OutOfFuel`]
+pub const RUN_STR_FUEL: u64 = 100_000;
+
+/// Compile `src` on a fresh VM, once as is and once optimized, and throw the bytecode away.
+/// Entry point for fuzzing the scanner and compiler, errors are returned but not printed
+pub fn compile_str(src: &str) -> InterpretResult<()> {
+    for optimize in [false, true] {
+        let mut vm = VM::with_options(VmOptions {
+            optimize,
+            ..sandboxed()
+        });
+        compile(&mut vm, src)?;
+    }
+    Ok(())
+}
+
+/// Compile and run `src` on a fresh VM and return what it printed. Entry point for fuzzing the
+/// VM: the script can't reach the file system or the environment, can't sleep and runs at most
+/// [`RUN_STR_FUEL`] instructions
+pub fn run_str(src: &str) -> InterpretResult<String> {
+    let stdout = vm::OutputBuffer::default();
+    let mut vm = VM::with_options(VmOptions {
+        stdout: Box::new(stdout.clone()),
+        max_instructions: Some(RUN_STR_FUEL),
+        ..sandboxed()
+    });
+    interpret(&mut vm, src)?;
+    Ok(stdout.contents())
+}
+
+/// Options for [`compile_str`] and [`run_str`]
+fn sandboxed() -> VmOptions {
+    VmOptions {
+        io: false,
+        time: false,
+        allow_process: false,
+        random_seed: Some(0),
+        stderr: Box::new(std::io::sink()),
+        ..VmOptions::default()
+    }
+}
+
+fn compile_with(vm: &mut VM, src: &str, implicit_return: bool) -> InterpretResult<Gc<ObjFunction>> {
+    let mut function = {
+        let mut parser = Parser::new(src, &mut vm.mem)
+            .implicit_return(implicit_return)
+            .per_iteration_loops(vm.per_iteration_loops);
+        if let Err(errors) = parser.compile() {
+            for error in errors.iter().filter(|_| vm.report_errors) {
+                let _ = writeln!(vm.stderr, "{error}");
+            }
+            return Err(InterpretError::CompileError(errors));
+        }
+        parser.compiler.function
+    };
+    if vm.optimize {
+        chunk::optimize::optimize(&mut function);
+    }
+    Ok(function)
+}
+
+/// Run a function returned by [`compile_with`] or loaded with
+/// [`chunk::serialize::deserialize`] as a script
+pub(crate) fn run_function(vm: &mut VM, function: Gc<ObjFunction>) -> InterpretResult<Value> {
+    vm.init(function);
+    vm.run()
+}
+
+fn compile_and_run(vm: &mut VM, src: &str, implicit_return: bool) -> InterpretResult<Value> {
+    let function = compile_with(vm, src, implicit_return)?;
+    vm.init(function);
+
+    vm.run()
+}
+
+/// Embeddable interpreter.
+///
+/// Globals, interned strings and the object heap persist across calls to [`Loxide::eval`]:
+///
+/// ```
+/// let mut engine = loxide::Loxide::new();
+/// engine.eval("var x = 1;").unwrap();
+/// assert_eq!(engine.eval("x + 2").unwrap(), loxide::Value::Number(3.0));
+/// ```
+pub struct Loxide {
+    pub vm: VM,
+}
+
+impl Default for Loxide {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Loxide {
+    pub fn new() -> Self {
+        Self { vm: VM::new() }
+    }
+
+    pub fn with_gc_config(gc_config: GcConfig) -> Self {
+        Self {
+            vm: VM::with_gc_config(gc_config),
+        }
+    }
+
+    pub fn with_options(options: VmOptions) -> Self {
+        Self {
+            vm: VM::with_options(options),
+        }
+    }
+
+    /// Compile and run `src`.
+    ///
+    /// If the source ends with an expression that has no trailing semicolon, its value is
+    /// returned, otherwise the result is `Value::Nil`.
+    ///
+    /// Objects in the returned value are only guaranteed to be alive until the next call into
+    /// the VM, assign them to a global to keep them around.
+    pub fn eval(&mut self, src: &str) -> InterpretResult<Value> {
+        compile_and_run(&mut self.vm, src, true)
+    }
+
+    /// Install a host function as a global, see [`VM::register_native`]
+    pub fn register_native<F>(&mut self, name: &str, arity: impl Into<Arity>, f: F) -> &mut Self
+    where
+        F: Fn(&mut VM, &[Value]) -> Result<Value, String> + Send + 'static,
+    {
+        self.vm.register_native(name, arity, f);
+        self
+    }
+
+    /// Define the global `name` as a handle to `channel`, see [`VM::define_channel`]
+    pub fn define_channel(&mut self, name: &str, channel: &Channel) -> &mut Self {
+        self.vm.define_channel(name, channel.clone());
+        self
+    }
+
+    /// Look up a global variable by name
+    pub fn get_global(&mut self, name: &str) -> Option<Value> {
+        self.vm.get_global(name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::{
+        cell::UnsafeCell,
+        collections::{HashMap, HashSet},
+        mem::{size_of, MaybeUninit},
+        sync::Arc,
+    };
+
+    use crate::{
+        chunk::Opcode,
+        compile::{is_incomplete, Token},
+        interpret,
+        mem::{Gc, GcConfig, Mem, StringPool},
+        native_fn::Arity,
+        obj::{Obj, ObjKind, ObjString},
+        table::{Key, ObjHash, Table},
+        value::{display::ValueWrapper, TypeError, Unpacked, Value},
+        vm::{
+            Event, InterpretError, OutputBuffer, Status, TraceFrame, ValueStack, VmOptions,
+            STACK_MAX, VM,
+        },
+        Loxide,
+    };
+
+    #[test]
+    fn fib() {
+        let src = r#"
+fun fib(x) {
+    if (x <= 1) {
+        return x;
+    }
+    return fib(x - 1) + fib(x - 2);
+}
+
+var result = fib(2);
+"#;
+
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        let result_var_str = vm.get_string("result");
+        let value = vm.mem.globals.get(result_var_str);
+        assert_eq!(value.unwrap(), Value::Number(1.0));
+    }
+
+    #[test]
+    fn superclasses() {
+        let src = r#"
+class Doughnut {
+  cook() {
+    print "Dunk in the fryer.";
+    this.finish("sprinkles");
+  }
+
+  finish(ingredient) {
+    return "Finish with " + ingredient;
+  }
+}
+
+class Cruller < Doughnut {
+  finish(ingredient) {
+    // No sprinkles, always icing.
+    return super.finish("icing");
+  }
+}
+
+var cruller = Cruller();
+var result = cruller.finish("noice");
+"#;
+
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        let result_var_str = vm.get_string("result");
+        let value = vm.mem.globals.get(result_var_str);
+        assert_eq!(value.unwrap().as_str().unwrap(), "Finish with icing");
+    }
+
+    #[test]
+    fn super_get() {
+        let src = r#"
+class A {
+  init(value) {
+    this.value = value;
+  }
+
+  method() {
+    return this.value;
+  }
+}
+
+class B < A {
+  init(value) {
+    super.init(value + 1);
+  }
+
+  method() {
+    var method = super.method;
+    return method() * 10;
+  }
+}
+
+var result = B(1).method();
+"#;
+
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        let result_var_str = vm.get_string("result");
+        let value = vm.mem.globals.get(result_var_str);
+        assert_eq!(value, Some(Value::Number(20.0)));
+    }
+
+    #[test]
+    fn inherit_non_class() {
+        let src = r#"
+var NotAClass = "nope";
+class Sub < NotAClass {}
+"#;
+
+        let mut vm = VM::new();
+        let err = interpret(&mut vm, src);
+        assert!(matches!(err, Err(InterpretError::RuntimeError(_))));
+    }
+
+    #[test]
+    fn runtime_error_trace() {
+        let src = r#"
+fun inner(x) {
+  return -x;
+}
+fun outer() {
+  inner("oops");
+}
+outer();
+"#;
+
+        let mut vm = VM::new();
+        let err = match interpret(&mut vm, src) {
+            Err(InterpretError::RuntimeError(err)) => err,
+            other => panic!("expected a runtime error, got {other:?}"),
+        };
+        assert_eq!(err.message, "Operand must be a number.");
+        assert_eq!(
+            err.trace,
+            vec![
+                TraceFrame {
+                    line: 3,
+                    column: 11,
+                    function: Some("inner".to_string()),
+                    args: vec!["'oops'".to_string()],
+                },
+                TraceFrame {
+                    line: 6,
+                    column: 15,
+                    function: Some("outer".to_string()),
+                    args: vec![],
+                },
+                TraceFrame {
+                    line: 8,
+                    column: 7,
+                    function: None,
+                    args: vec![],
+                },
+            ]
+        );
+        assert_eq!(
+            err.to_string(),
+            "Operand must be a number.\n[line 3] in inner('oops')\n[line 6] in outer()\n[line 8] in script"
+        );
+
+        // The VM is usable again after the error
+        assert_eq!(interpret(&mut vm, "var ok = 1;"), Ok(Value::Nil));
+    }
+
+    #[test]
+    fn compile_errors() {
+        let src = r#"
+var a = 1
+print a;
+{
+  var b = 1;
+  var b = 2;
+}
+print @;
+"#;
+
+        let mut vm = VM::new();
+        let errors = match interpret(&mut vm, src) {
+            Err(InterpretError::CompileError(errors)) => errors,
+            other => panic!("expected a compile error, got {other:?}"),
+        };
+
+        let summary: Vec<_> = errors
+            .iter()
+            .map(|err| (err.line, err.column, err.at.as_str(), err.message.as_str()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (3, 1, "'print'", "Expect ';' after variable declaration."),
+                (
+                    6,
+                    7,
+                    "'b'",
+                    "Already a variable with this name in this scope."
+                ),
+                (8, 7, "", "Unexpected character."),
+            ]
+        );
+
+        assert_eq!(errors[1].snippet, "  var b = 2;");
+        assert_eq!(
+            errors[1].to_string(),
+            "[line 6:7] Error at 'b': Already a variable with this name in this scope.\n      var b = 2;\n          ^"
+        );
+    }
+
+    #[test]
+    fn ast() {
+        use crate::compile::ast::{self, BinaryOp, ExprKind, StmtKind};
+
+        let src = "var x = -2 ** 2 + f(1, b: 0xff)[1:];
+for (item in list) { x += item; }
+class A < B { get size { return 1; } }";
+        let stmts = ast::parse(src).unwrap();
+        let StmtKind::Var { name, init: Some(init) } = &stmts[0].kind else {
+            panic!("{:?}", stmts[0]);
+        };
+        assert_eq!(name, "x");
+        let ExprKind::Binary { op, right, .. } = &init.kind else {
+            panic!("{init:?}");
+        };
+        assert_eq!(*op, BinaryOp::Add);
+        assert_eq!((right.line, right.column), (1, 19));
+        assert!(matches!(stmts[1].kind, StmtKind::ForIn { .. }));
+        assert_eq!((stmts[2].line, stmts[2].column), (3, 1));
+
+        assert_eq!(
+            ast::to_sexp(&stmts),
+            "(var x (+ (- (** 2 2)) (slice (call f (1 (named b 0xff))) 1 ())))
+(for-in item list (block ((expression (+= x item)))))
+(class A B ((getter (function size () ((return 1))))))
+"
+        );
+        let json = ast::to_json(&ast::parse("print 0x10;").unwrap());
+        assert_eq!(
+            json,
+            r#"[
+  {
+    "type": "Print",
+    "line": 1, "column": 1,
+    "value": {
+      "type": "Number",
+      "line": 1, "column": 7,
+      "value": 16
+    }
+  }
+]
+"#
+        );
+
+        // The same syntax errors as the compiler, in the same places
+        let mut vm = VM::new();
+        for src in [
+            "var a = 1\nprint a;",
+            "print (1 + ;",
+            "a + b = c;",
+            "class { }",
+            "f(a: 1, 2);",
+            "import foo;",
+            "import x from;",
+        ] {
+            let Err(InterpretError::CompileError(errors)) = interpret(&mut vm, src) else {
+                panic!("{src}");
+            };
+            assert_eq!(ast::parse(src), Err(errors));
+        }
+        // Only the compiler knows about scopes
+        assert!(ast::parse("return 1;").is_ok());
+    }
+
+    #[test]
+    fn fmt() {
+        use crate::compile::{ast, fmt};
+
+        let src = r#"// Counts
+var   count=0;   // so far
+
+
+fun add(n, step = 1) { count += n * step; return count; }
+class A < B { get size { return 1; } }
+if (count > 1) print "big"; else { print "small"; }
+for (x in [1, 2,]) add(x);
+register("an event name that is long", 10, fun (event) { print event; }); /* inline */
+var total = someFunction(argumentNumberOne, argumentNumberTwo, argumentNumberThree, argumentNumberFour);
+var x = 1; var y = 2; // trailing
+if (x) print x; else print y; // both
+print - -count;
+f(1, // first
+  2 /* two */, 3);
+var l = [1, // one
+  2];
+var m = {"a": 1, // a
+  // b next
+  "b": fun () { return 2; }, /* after b */
+};
+"#;
+        let formatted = fmt::format(src).unwrap();
+        assert_eq!(
+            formatted,
+            r#"// Counts
+var count = 0; // so far
+
+fun add(n, step = 1) {
+  count += n * step;
+  return count;
+}
+class A < B {
+  get size {
+    return 1;
+  }
+}
+if (count > 1) print "big";
+else {
+  print "small";
+}
+for (x in [1, 2]) add(x);
+register("an event name that is long", 10, fun (event) {
+  print event;
+}); /* inline */
+var total = someFunction(
+  argumentNumberOne,
+  argumentNumberTwo,
+  argumentNumberThree,
+  argumentNumberFour
+);
+var x = 1;
+var y = 2; // trailing
+if (x) print x;
+else print y; // both
+print - -count;
+f(
+  1, // first
+  2, /* two */
+  3
+);
+var l = [
+  1, // one
+  2,
+];
+var m = {
+  "a": 1, // a
+  // b next
+  "b": fun () {
+    return 2;
+  }, /* after b */
+};
+"#
+        );
+        // Formatting doesn't change what the script means, and a formatted script stays as it is
+        let tree = |src| ast::to_sexp(&ast::parse(src).unwrap());
+        assert_eq!(tree(src), tree(&formatted));
+        assert_eq!(fmt::format(&formatted).unwrap(), formatted);
+
+        assert_eq!(
+            fmt::format("print (1;"),
+            Err(ast::parse("print (1;").unwrap_err())
+        );
+    }
+
+    #[test]
+    fn lint() {
+        use crate::compile::lint::{lint, Rule};
+
+        let src = "print later;
+var later = 1;
+fun f(a, unusedParam) {
+  var unused = 1;
+  var _ignored = 2;
+  var b = a;
+  {
+    var b = 3;
+    print b;
+  }
+  return b;
+  print \"never\";
+}
+if (!nil) print 1;
+while (true) break;
+// lox-lint: allow(unused-local)
+fun g() { var quiet = 1; }
+fun h() { var quiet = 1; } // lox-lint: allow(unused-local, shadowed-variable)
+";
+        let warnings: Vec<_> = lint(src, &[])
+            .unwrap()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            warnings,
+            [
+                "[line 1:7] Warning[use-before-definition]: 'later' is used before it's declared on line 2.",
+                "[line 4:3] Warning[unused-local]: 'unused' is never used.",
+                "[line 8:5] Warning[shadowed-variable]: 'b' shadows the variable declared on line 6.",
+                "[line 12:3] Warning[unreachable-code]: Unreachable code.",
+                "[line 14:5] Warning[constant-condition]: The condition is always true.",
+            ]
+        );
+
+        let allowed = [Rule::UnusedLocal, Rule::ConstantCondition];
+        let rules: Vec<_> = lint(src, &allowed)
+            .unwrap()
+            .into_iter()
+            .map(|warning| warning.rule)
+            .collect();
+        assert_eq!(
+            rules,
+            [
+                Rule::UseBeforeDefinition,
+                Rule::ShadowedVariable,
+                Rule::UnreachableCode
+            ]
+        );
+        assert!(lint("print (1;", &[]).is_err());
+    }
+
+    #[test]
+    fn symbols() {
+        use crate::compile::symbols::{analyze, SymbolKind};
+
+        let src = "fun show() { print count; }
+var count = 1;
+class Counter < Base {
+  init(start, ...rest) { this.n = start; }
+  get value { return this.n; }
+  set value(v) { this.n = v; }
+  bump() { this.value = this.value + 1; }
+}
+{
+  var count = 2;
+  for (item in [count]) print item;
+}
+";
+        let symbols = analyze(src);
+        let references: Vec<_> = symbols
+            .references
+            .iter()
+            .map(|reference| {
+                let definition = &symbols.definitions[reference.definition];
+                format!(
+                    "{}:{} -> {}:{}",
+                    reference.line, reference.column, definition.line, definition.column
+                )
+            })
+            .collect();
+        assert_eq!(
+            references,
+            [
+                "1:5 -> 1:5",
+                "1:20 -> 2:5",
+                "2:5 -> 2:5",
+                "3:7 -> 3:7",
+                "4:3 -> 4:3",
+                "4:8 -> 4:8",
+                "4:18 -> 4:18",
+                "4:35 -> 4:8",
+                "5:7 -> 5:7",
+                "6:7 -> 5:7",
+                "6:13 -> 6:13",
+                "6:27 -> 6:13",
+                "7:3 -> 7:3",
+                "7:17 -> 5:7",
+                "7:30 -> 5:7",
+                "10:7 -> 10:7",
+                "11:8 -> 11:8",
+                "11:17 -> 10:7",
+                "11:31 -> 11:8",
+            ]
+        );
+
+        let counter = symbols.global("Counter").unwrap();
+        assert_eq!(counter.kind, SymbolKind::Class);
+        assert_eq!(counter.end_line, 8);
+        assert_eq!(
+            counter.params.as_deref(),
+            Some(&["start".to_string(), "...rest".to_string()][..])
+        );
+        assert_eq!(counter.arity, Some((1, None)));
+        assert_eq!(symbols.global("show").unwrap().arity, Some((0, Some(0))));
+        assert!(symbols.global("item").is_none());
+        let at = symbols.at(11, 33).unwrap();
+        assert_eq!(symbols.definitions[at.definition].name, "item");
+
+        // The statements around a syntax error still resolve
+        let symbols = analyze("var total = 0;\nprint total +;\nfun f() { var;\n return total; }\n");
+        assert!(symbols.global("f").is_some());
+        let at = symbols.at(4, 9).unwrap();
+        assert_eq!(symbols.definitions[at.definition].line, 1);
+    }
+
+    #[test]
+    fn lexer() {
+        use crate::compile::lexer::{Category, Lexer};
+
+        let src = "/* a /* nested */ comment */ print \"x${1 + y}z\";
+x = 0b12 @ é;
+// done
+\"open";
+        let lexemes: Vec<_> = Lexer::new(src)
+            .tokens()
+            .map(|lexeme| {
+                let span = lexeme.span;
+                let error = lexeme.error.unwrap_or_default();
+                format!(
+                    "{}:{} {:?} {} {error}",
+                    span.line, span.column, lexeme.category, lexeme.text
+                )
+            })
+            .collect();
+        assert_eq!(
+            lexemes,
+            [
+                "1:1 Comment /* a /* nested */ comment */ ",
+                "1:30 Keyword print ",
+                "1:36 String \"x${ ",
+                "1:40 Number 1 ",
+                "1:42 Operator + ",
+                "1:44 Identifier y ",
+                "1:45 String }z\" ",
+                "1:48 Punctuation ; ",
+                "2:1 Identifier x ",
+                "2:3 Operator = ",
+                "2:5 Error 0b12 Invalid character in number.",
+                "2:10 Error @ Unexpected character.",
+                "2:12 Error é Unexpected character.",
+                "2:14 Punctuation ; ",
+                "3:1 Comment // done ",
+                "4:1 String \"open Unterminated string.",
+            ]
+        );
+        let spans: Vec<_> = Lexer::new("a\n  bb").tokens().map(|l| l.span).collect();
+        assert_eq!((spans[1].start, spans[1].end, spans[1].line), (4, 6, 2));
+        let unterminated = Lexer::new("1 /* open").tokens().last().unwrap();
+        assert_eq!(unterminated.category, Category::Comment);
+        assert_eq!(unterminated.text, "/* open");
+    }
+
+    #[test]
+    fn report() {
+        use crate::report::{render, suggest};
+
+        assert_eq!(suggest("conut", ["count", "counter"]), Some("count"));
+        assert_eq!(suggest("lenght", ["len", "length"]), Some("length"));
+        assert_eq!(suggest("x", ["y", "x"]), Some("y"));
+        assert_eq!(suggest("total", ["count", "sum"]), None);
+
+        let src = "var count = 1;
+fun f(a) {
+\treturn a + conut;
+}
+print f(1);";
+        let mut vm = VM::with_options(VmOptions {
+            report_errors: false,
+            stderr: Box::new(std::io::sink()),
+            ..VmOptions::default()
+        });
+        let error = interpret(&mut vm, src).unwrap_err();
+        assert_eq!(
+            render(&error, Some(src), false),
+            "Error: Undefined variable 'conut'.
+  |
+3 | \treturn a + conut;
+  | \t           ^^^^^
+Did you mean 'count'?
+[line 3] in f(1)
+[line 5] in script
+"
+        );
+        // Without the source there's nothing to quote
+        assert!(!render(&error, None, false).contains('^'));
+        assert_eq!(
+            error.to_string(),
+            "Undefined variable 'conut'.\nDid you mean 'count'?\n[line 3] in f(1)\n[line 5] in script"
+        );
+
+        let error = crate::compile_str("var x = 1\nprint x;").unwrap_err();
+        assert_eq!(
+            render(&error, None, false),
+            "[line 2:1] Error at 'print': Expect ';' after variable declaration.
+  |
+2 | print x;
+  | ^^^^^
+"
+        );
+        assert!(render(&error, None, true)
+            .starts_with("[line 2:1] \x1b[1;31mError\x1b[0m at 'print': \x1b[1mExpect"));
+    }
+
+    #[test]
+    fn block_comments() {
+        let src = r#"
+var a = 1 /* inline */ + 2;
+/* spans
+   /* nested
+   */ lines
+*/
+a = a * 2; /**/
+print @;
+var b = 1;
+  /* never closed
+"#;
+
+        let mut vm = VM::new();
+        let errors = match interpret(&mut vm, src) {
+            Err(InterpretError::CompileError(errors)) => errors,
+            other => panic!("expected a compile error, got {other:?}"),
+        };
+        let summary: Vec<_> = errors
+            .iter()
+            .map(|err| (err.line, err.column, err.message.as_str()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (8, 7, "Unexpected character."),
+                (10, 3, "Unterminated block comment."),
+            ]
+        );
+
+        let mut vm = VM::new();
+        interpret(
+            &mut vm,
+            "var a = 1 /* inline */ + 2; /* a /* b */ c */ a = a * 2;",
+        )
+        .unwrap();
+        let a = vm.get_string("a");
+        assert_eq!(vm.mem.globals.get(a), Some(Value::Number(6.0)));
+    }
+
+    #[test]
+    fn shebang() {
+        use crate::compile::{fmt, lexer::Lexer};
+
+        let src = "#!/usr/bin/env loxide\nvar a = 1;\na = @;";
+        let mut vm = VM::new();
+        let errors = match interpret(&mut vm, src) {
+            Err(InterpretError::CompileError(errors)) => errors,
+            other => panic!("expected a compile error, got {other:?}"),
+        };
+        assert_eq!((errors[0].line, errors[0].column), (3, 5));
+
+        let mut vm = VM::new();
+        interpret(&mut vm, "#!loxide\nvar a = 1;").unwrap();
+        interpret(&mut vm, "#!loxide").unwrap();
+        // Only on the first line
+        assert!(interpret(&mut vm, "\n#!loxide").is_err());
+
+        let first = Lexer::new(src).tokens().next().unwrap();
+        assert_eq!(first.text, "#!/usr/bin/env loxide");
+        assert_eq!(
+            fmt::format("#!/usr/bin/env loxide\nvar   a=1;").unwrap(),
+            "#!/usr/bin/env loxide\nvar a = 1;\n"
+        );
+    }
+
+    #[test]
+    fn incomplete_input() {
+        assert!(!is_incomplete("var a = 1;"));
+        assert!(!is_incomplete("}"));
+        assert!(is_incomplete("fun f() {"));
+        assert!(is_incomplete("fun f() {\n  if (true) { print 1; }"));
+        assert!(is_incomplete("print (1 +"));
+        assert!(is_incomplete("var l = [1,"));
+        assert!(!is_incomplete("var l = [1,\n2];"));
+        assert!(is_incomplete("var m = {\"a\": [1,"));
+        assert!(is_incomplete("print \"multi\nline"));
+        assert!(!is_incomplete("print \"multi\nline\";"));
+        assert!(is_incomplete("print \"${1 +"));
+        assert!(is_incomplete("/* open\n/* nested */"));
+        assert!(!is_incomplete("/* closed /* nested */ */"));
+    }
+
+    #[test]
+    fn inherit_self() {
+        let src = r#"class Oops < Oops {}"#;
+
+        let mut vm = VM::new();
+        let err = interpret(&mut vm, src);
+        assert!(matches!(err, Err(InterpretError::CompileError(_))));
+    }
+
+    #[test]
+    fn break_continue() {
+        let src = r#"
+var sum = 0;
+for (var i = 0; i < 10; i = i + 1) {
+    var skip = i == 2;
+    if (skip) continue;
+    var j = 0;
+    while (true) {
+        var inner = j;
+        j = j + 1;
+        if (inner >= i) break;
+        if (inner == 1) continue;
+        sum = sum + 1;
+    }
+    if (i == 5) break;
+}
+
+var closures = "";
+var k = 0;
+while (k < 3) {
+    var captured = k;
+    fun f() { return captured; }
+    k = k + 1;
+    if (k == 2) continue;
+    closures = closures + "x";
+}
+"#;
+
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        // i = 1: 1, i = 3: 2, i = 4: 3, i = 5: 4
+        let sum_str = vm.get_string("sum");
+        assert_eq!(vm.mem.globals.get(sum_str), Some(Value::Number(10.0)));
+        let closures_str = vm.get_string("closures");
+        assert_eq!(
+            vm.mem.globals.get(closures_str).unwrap().as_str(),
+            Some("xx")
+        );
+    }
+
+    #[test]
+    fn break_outside_loop() {
+        let src = r#"
+fun f() {
+    while (true) {
+        fun g() { break; }
+    }
+}
+continue;
+"#;
+
+        let mut vm = VM::new();
+        match interpret(&mut vm, src) {
+            Err(InterpretError::CompileError(errors)) => {
+                let messages: Vec<_> = errors.iter().map(|err| err.message.as_str()).collect();
+                assert_eq!(
+                    messages,
+                    vec![
+                        "Can't use 'break' outside of a loop.",
+                        "Can't use 'continue' outside of a loop."
+                    ]
+                );
+            }
+            other => panic!("expected a compile error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn switch() {
+        let src = r#"
+fun describe(x) {
+    var result;
+    switch (x) {
+        case 1:
+            var one = "one";
+            result = one;
+        case "two":
+            result = "two";
+        case 1 + 2:
+            result = "three";
+        default:
+            result = "many";
+    }
+    return result;
+}
+
+var result = describe(1) + " " + describe("two") + " " + describe(3) + " " + describe(nil);
+
+var count = 0;
+for (var i = 0; i < 5; i = i + 1) {
+    switch (i) {
+        case 2: continue;
+        case 4: break;
+    }
+    count = count + 1;
+}
+"#;
+
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        let result_str = vm.get_string("result");
+        assert_eq!(
+            vm.mem.globals.get(result_str).unwrap().as_str(),
+            Some("one two three many")
+        );
+        let count_str = vm.get_string("count");
+        assert_eq!(vm.mem.globals.get(count_str), Some(Value::Number(3.0)));
+
+        let err = interpret(&mut vm, "switch (1) { default: print 1; case 1: print 2; }");
+        assert!(matches!(err, Err(InterpretError::CompileError(_))));
+    }
+
+    #[test]
+    fn conditional() {
+        let src = r#"
+var calls = 0;
+fun touch(value) {
+    calls = calls + 1;
+    return value;
+}
+
+fun sign(x) {
+    return x < 0 ? "negative" : x == 0 ? "zero" : "positive";
+}
+
+var a = true ? touch(1) : touch(2);
+var b = false or nil ? touch(3) : touch(4) + 10;
+var signs = sign(-5) + " " + sign(0) + " " + sign(5);
+"#;
+
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        for (name, expected) in [
+            ("a", Value::Number(1.0)),
+            ("b", Value::Number(14.0)),
+            ("calls", Value::Number(2.0)),
+        ] {
+            let name_str = vm.get_string(name);
+            assert_eq!(vm.mem.globals.get(name_str), Some(expected), "{name}");
+        }
+        let signs_str = vm.get_string("signs");
+        assert_eq!(
+            vm.mem.globals.get(signs_str).unwrap().as_str(),
+            Some("negative zero positive")
+        );
+
+        let err = interpret(&mut vm, "var c = true ? 1;");
+        assert!(matches!(err, Err(InterpretError::CompileError(_))));
+    }
+
+    #[test]
+    fn arithmetic_operators() {
+        let src = r#"
+var modulo = 7 % 3;
+var negative_modulo = -7 % 3;
+var floor_div = 7 ~/ 2;
+var negative_floor_div = -7 ~/ 2;
+var power = 2 ** 3 ** 2; // 2 ** 9
+var negative_power = -2 ** 2;
+var precedence = 1 + 2 * 3 % 4 ** 2;
+"#;
+
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        for (name, expected) in [
+            ("modulo", 1.0),
+            ("negative_modulo", 2.0),
+            ("floor_div", 3.0),
+            ("negative_floor_div", -4.0),
+            ("power", 512.0),
+            ("negative_power", -4.0),
+            ("precedence", 7.0),
+        ] {
+            let name_str = vm.get_string(name);
+            assert_eq!(
+                vm.mem.globals.get(name_str),
+                Some(Value::Number(expected)),
+                "{name}"
+            );
+        }
+
+        for src in ["1 % nil;", "\"a\" ~/ 2;", "true ** 2;"] {
+            let err = interpret(&mut vm, src);
+            assert!(matches!(err, Err(InterpretError::RuntimeError(_))), "{src}");
+        }
+    }
+
+    #[test]
+    fn compound_assignment() {
+        let src = r#"
+var global = 10;
+global += 5;
+global -= 3;
+global *= 2;
+global /= 4;
+
+var greeting = "hello";
+greeting += " world";
+
+class Counter {
+    init() { this.count = 1; }
+}
+var counter = Counter();
+counter.count += 41;
+
+fun closures() {
+    var local = 1;
+    local *= 3;
+    fun add(n) {
+        local += n;
+        return local;
+    }
+    add(2);
+    return add(10);
+}
+var upvalue = closures();
+"#;
+
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        for (name, expected) in [("global", 6.0), ("upvalue", 15.0)] {
+            let name_str = vm.get_string(name);
+            assert_eq!(
+                vm.mem.globals.get(name_str),
+                Some(Value::Number(expected)),
+                "{name}"
+            );
+        }
+        let greeting_str = vm.get_string("greeting");
+        assert_eq!(
+            vm.mem.globals.get(greeting_str).unwrap().as_str(),
+            Some("hello world")
+        );
+        assert_eq!(
+            interpret(&mut vm, "var count = counter.count;"),
+            Ok(Value::Nil)
+        );
+        let count_str = vm.get_string("count");
+        assert_eq!(vm.mem.globals.get(count_str), Some(Value::Number(42.0)));
+
+        for src in ["1 += 2;", "var a = 1; var b = 2; a + b = 3;", "a + b += 3;"] {
+            let err = interpret(&mut vm, src);
+            assert!(matches!(err, Err(InterpretError::CompileError(_))), "{src}");
+        }
+    }
+
+    #[test]
+    fn increment_decrement() {
+        let src = r#"
+var global = 1;
+var old = global++;
+var new = ++global;
+var sum = old + global--;
+
+fun closures() {
+    var local = 10;
+    var a = --local;
+    fun inc() { return local++; }
+    var b = inc();
+    return a + b * 100 + local * 10000;
+}
+var packed = closures();
+
+var loops = 0;
+for (var i = 0; i < 3; i++) loops++;
+"#;
+
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        for (name, expected) in [
+            ("old", 1.0),
+            ("new", 3.0),
+            ("sum", 4.0),
+            ("global", 2.0),
+            ("packed", 9.0 + 900.0 + 100000.0),
+            ("loops", 3.0),
+        ] {
+            let name_str = vm.get_string(name);
+            assert_eq!(
+                vm.mem.globals.get(name_str),
+                Some(Value::Number(expected)),
+                "{name}"
+            );
+        }
+
+        for src in ["1++;", "++1;", "var a; a + 1--;"] {
+            let err = interpret(&mut vm, src);
+            assert!(matches!(err, Err(InterpretError::CompileError(_))), "{src}");
+        }
+    }
+
+    #[test]
+    fn bitwise_operators() {
+        let src = r#"
+var bit_and = 12 & 10;
+var bit_or = 12 | 10;
+var xor = 12 ^ 10;
+var not = ~5;
+var shl = 1 << 4;
+var shr = -16 >> 2;
+var wrap = 1 << 32;
+var truncated = 7.9 & 3.2;
+var overflow = 4294967297 | 0;
+var precedence = 6 & 3 == 2;
+var mixed = 1 + 2 << 3 | 1;
+"#;
+
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        for (name, expected) in [
+            ("bit_and", Value::Number(8.0)),
+            ("bit_or", Value::Number(14.0)),
+            ("xor", Value::Number(6.0)),
+            ("not", Value::Number(-6.0)),
+            ("shl", Value::Number(16.0)),
+            ("shr", Value::Number(-4.0)),
+            ("wrap", Value::Number(1.0)),
+            ("truncated", Value::Number(3.0)),
+            ("overflow", Value::Number(1.0)),
+            ("precedence", Value::Bool(true)),
+            ("mixed", Value::Number(25.0)),
+        ] {
+            let name_str = vm.get_string(name);
+            assert_eq!(vm.mem.globals.get(name_str), Some(expected), "{name}");
+        }
+
+        for src in ["1 & nil;", "~\"a\";"] {
+            let err = interpret(&mut vm, src);
+            assert!(matches!(err, Err(InterpretError::RuntimeError(_))), "{src}");
+        }
+    }
+
+    #[test]
+    fn lists() {
+        let src = r#"
+var list = [1, 2, 3,];
+var first = list[0];
+list[1] = "two";
+var second = list[1];
+var assigned = list[2] = 4;
+var nested = [[1, 2], [3, [4]]];
+var deep = nested[1][1][0];
+var empty = len([]);
+
+push(list, 5);
+var pushed = len(list);
+var popped = pop(list);
+insert(list, 0, 0);
+insert(list, len(list), 9);
+var inserted = list[0] + list[4];
+var removed = remove(list, 1);
+var remaining = len(list);
+var string_len = len("héllo");
+"#;
+
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        for (name, expected) in [
+            ("first", Value::Number(1.0)),
+            ("assigned", Value::Number(4.0)),
+            ("deep", Value::Number(4.0)),
+            ("empty", Value::Number(0.0)),
+            ("pushed", Value::Number(4.0)),
+            ("popped", Value::Number(5.0)),
+            ("inserted", Value::Number(9.0)),
+            ("removed", Value::Number(1.0)),
+            ("remaining", Value::Number(4.0)),
+            ("string_len", Value::Number(5.0)),
+        ] {
+            let name_str = vm.get_string(name);
+            assert_eq!(vm.mem.globals.get(name_str), Some(expected), "{name}");
+        }
+
+        let second = vm.get_string("second");
+        let second = vm.mem.globals.get(second).unwrap();
+        assert_eq!(second.as_str(), Some("two"));
+
+        for src in [
+            "[1][1];",
+            "[1][-1];",
+            "[1][0.5];",
+            "[1][nil];",
+            "var x = 1; x[0];",
+            "[][0] = 1;",
+            "pop([]);",
+            "push(1, 2);",
+            "remove([1], 1);",
+            "insert([], 2, 1);",
+        ] {
+            let err = interpret(&mut vm, src);
+            assert!(matches!(err, Err(InterpretError::RuntimeError(_))), "{src}");
+        }
+
+        let err = interpret(&mut vm, "[1 2];");
+        assert!(matches!(err, Err(InterpretError::CompileError(_))));
+    }
+
+    #[test]
+    fn lists_survive_gc() {
+        let src = r#"
+var list = [];
+for (var i = 0; i < 100; i = i + 1) {
+    push(list, [i, "item"]);
+}
+var last = list[99][0];
+"#;
+
+        let mut vm = VM::with_gc_config(GcConfig {
+            stress: true,
+            ..GcConfig::default()
+        });
+        interpret(&mut vm, src).unwrap();
+        let last = vm.get_string("last");
+        assert_eq!(vm.mem.globals.get(last), Some(Value::Number(99.0)));
+    }
+
+    #[test]
+    fn self_containing_lists_and_maps() {
+        let src = r#"
+var l = [1];
+push(l, l);
+var m = {};
+m["self"] = m;
+print l;
+print "${m} ${[m, l]}";
+var deep = [];
+for (var i = 0; i < 100000; i = i + 1) deep = [deep];
+print length("${deep}");
+"#;
+        let stdout = OutputBuffer::default();
+        let mut vm = VM::with_options(VmOptions {
+            stdout: Box::new(stdout.clone()),
+            ..VmOptions::default()
+        });
+        interpret(&mut vm, src).unwrap();
+        // Cut off at the maximum depth
+        let depth = crate::value::display::MAX_DEPTH;
+        assert_eq!(
+            stdout.contents(),
+            format!(
+                "[1, [...]]\n{{\"self\": {{...}}}} [{{\"self\": {{...}}}}, [1, [...]]]\n{}\n",
+                2 * depth + 5
+            )
+        );
+
+        let l = vm.get_string("l");
+        let l = vm.mem.globals.get(l).unwrap();
+        assert_eq!(format!("{:?}", ValueWrapper(l)), "[Number(1.0), [...]]");
+        let m = vm.get_string("m");
+        let m = vm.mem.globals.get(m).unwrap();
+        assert_eq!(format!("{:?}", ValueWrapper(m)), r#"{"self": {...}}"#);
+    }
+
+    #[test]
+    fn maps() {
+        let src = r#"
+var map = {"a": 1, 2: "two", true: 3, nil == nil: 4,};
+var a = map["a"];
+var bool_key = map[true];
+map["b"] = map["a"] + 1;
+var b = map["b"];
+map[-0] = "zero";
+var zero = map[0];
+var list_key = [];
+map[list_key] = 5;
+var by_identity = map[list_key];
+var has_b = has(map, "b");
+var has_missing = has(map, "missing");
+var size = len(map);
+var empty = len({});
+var nested = {"inner": {"x": [1, 2]}}["inner"]["x"][1];
+var key_count = len(keys(map));
+var value_count = len(values(map));
+"#;
+
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        for (name, expected) in [
+            ("a", Value::Number(1.0)),
+            ("bool_key", Value::Number(4.0)),
+            ("b", Value::Number(2.0)),
+            ("by_identity", Value::Number(5.0)),
+            ("has_b", Value::Bool(true)),
+            ("has_missing", Value::Bool(false)),
+            ("size", Value::Number(6.0)),
+            ("empty", Value::Number(0.0)),
+            ("nested", Value::Number(2.0)),
+            ("key_count", Value::Number(6.0)),
+            ("value_count", Value::Number(6.0)),
+        ] {
+            let name_str = vm.get_string(name);
+            assert_eq!(vm.mem.globals.get(name_str), Some(expected), "{name}");
+        }
+
+        let zero = vm.get_string("zero");
+        let zero = vm.mem.globals.get(zero).unwrap();
+        assert_eq!(zero.as_str(), Some("zero"));
+
+        for src in [
+            "var m = {}; m[\"missing\"];",
+            "var m = {}; m[nil] = 1;",
+            "var m = {}; m[0 / 0] = 1;",
+            "var m = {nil: 1};",
+            "keys([]);",
+            "has(1, 2);",
+            "1[0];",
+        ] {
+            let err = interpret(&mut vm, src);
+            assert!(matches!(err, Err(InterpretError::RuntimeError(_))), "{src}");
+        }
+        assert_eq!(
+            runtime_message(interpret(&mut vm, "var m = {}; m[1.5];")),
+            "Undefined key 1.5."
+        );
+
+        for src in ["var m = {1 2};", "var m = {1: 2;"] {
+            let err = interpret(&mut vm, src);
+            assert!(matches!(err, Err(InterpretError::CompileError(_))), "{src}");
+        }
+    }
+
+    #[test]
+    fn keys_are_consistent_with_equality() {
+        let mut engine = Loxide::new();
+        engine
+            .eval("var l1 = []; var l2 = []; var m1 = {}; var m2 = {}; var kept = [];")
+            .unwrap();
+        // Kept in a list, so the strings made for the keys survive the next `eval`
+        let mut key = |src| {
+            let src = format!("push(kept, {src}); kept[len(kept) - 1]");
+            Key::new(eval_resolved(&mut engine, &src))
+        };
+        for (a, b) in [
+            ("0", "-0"),
+            ("1", "0.5 + 0.5"),
+            ("\"ab\"", "\"a\" + \"b\""),
+            ("\"1\"", "\"${1}\""),
+            ("true", "1 == 1"),
+            ("clock", "clock"),
+        ] {
+            let (a, b) = (key(a).unwrap(), key(b).unwrap());
+            assert_eq!(a, b, "{a:?} == {b:?}");
+            assert_eq!(a.hash(), b.hash(), "{a:?} == {b:?}");
+        }
+        for (a, b) in [("1", "\"1\""), ("0", "false"), ("l1", "l2"), ("m1", "m2")] {
+            assert_ne!(key(a), key(b), "{a} != {b}");
+        }
+        assert_eq!(key("nil"), None);
+        assert_eq!(key("0 / 0"), None);
+
+        let keys: HashSet<Key> = ["1", "1.0", "\"1\"", "true", "\"t\" + \"rue\"", "\"true\""]
+            .into_iter()
+            .map(|src| key(src).unwrap())
+            .collect();
+        assert_eq!(keys.len(), 4);
+    }
+
+    #[test]
+    fn maps_survive_gc() {
+        let src = r#"
+var map = {};
+for (var i = 0; i < 100; i = i + 1) {
+    map[i] = "value" + "!";
+    map["key" + "!"] = [i];
+}
+var last = map["key!"][0];
+var count = len(keys(map));
+"#;
+
+        let mut vm = VM::with_gc_config(GcConfig {
+            stress: true,
+            ..GcConfig::default()
+        });
+        interpret(&mut vm, src).unwrap();
+        let last = vm.get_string("last");
+        assert_eq!(vm.mem.globals.get(last), Some(Value::Number(99.0)));
+        let count = vm.get_string("count");
+        assert_eq!(vm.mem.globals.get(count), Some(Value::Number(101.0)));
+    }
+
+    #[test]
+    fn string_indexing() {
+        let src = r#"
+var s = "héllo";
+var first = s[0];
+var accented = s[1];
+var last = s[-1];
+var middle = s[1:3];
+var prefix = s[:2];
+var suffix = s[-3:];
+var whole = s[:];
+var empty = s[2:2];
+var end = s[5:];
+var interned = s[1:3] == "él";
+"#;
+
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        for (name, expected) in [
+            ("first", "h"),
+            ("accented", "é"),
+            ("last", "o"),
+            ("middle", "él"),
+            ("prefix", "hé"),
+            ("suffix", "llo"),
+            ("whole", "héllo"),
+            ("empty", ""),
+            ("end", ""),
+        ] {
+            let name_str = vm.get_string(name);
+            let value = vm.mem.globals.get(name_str).unwrap();
+            assert_eq!(value.as_str(), Some(expected), "{name}");
+        }
+
+        let interned = vm.get_string("interned");
+        assert_eq!(vm.mem.globals.get(interned), Some(Value::Bool(true)));
+
+        for src in [
+            "\"abc\"[3];",
+            "\"abc\"[-4];",
+            "\"abc\"[0.5];",
+            "\"abc\"[2:1];",
+            "\"abc\"[0:4];",
+            "\"abc\"[nil];",
+            "\"abc\"[0] = \"d\";",
+            "[1, 2][0:1];",
+        ] {
+            let err = interpret(&mut vm, src);
+            assert!(matches!(err, Err(InterpretError::RuntimeError(_))), "{src}");
+        }
+
+        let err = interpret(&mut vm, "var s = \"abc\"; s[0:1] = \"d\";");
+        assert!(matches!(err, Err(InterpretError::CompileError(_))));
+    }
+
+    #[test]
+    fn string_interpolation() {
+        let src = r#"
+var a = 1;
+var b = 2.5;
+var sum = "sum is ${a + b}";
+var several = "${a}-${b}-${nil}-${true}";
+var nested = "outer ${"inner ${a}"} done";
+var braces = "${ {"k": [1, "s"]}["k"] }";
+class Point {}
+var instance = "${Point()} and ${Point}";
+fun f() {}
+var function = "${f} ${clock}";
+var plain = "no $ {interpolation}";
+"#;
+
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        for (name, expected) in [
+            ("sum", "sum is 3.5"),
+            ("several", "1-2.5-nil-true"),
+            ("nested", "outer inner 1 done"),
+            ("braces", "[1, \"s\"]"),
+            ("instance", "Point instance and Point"),
+            ("function", "<fn f> <native fn clock>"),
+            ("plain", "no $ {interpolation}"),
+        ] {
+            let name_str = vm.get_string(name);
+            let value = vm.mem.globals.get(name_str).unwrap();
+            assert_eq!(value.as_str(), Some(expected), "{name}");
+        }
+
+        for src in ["\"${1\";", "\"${}\";", "\"${1 2}\";"] {
+            let err = interpret(&mut vm, src);
+            assert!(matches!(err, Err(InterpretError::CompileError(_))), "{src}");
+        }
+        // The rest of the string after `}` isn't an operand
+        for src in ["print \"a${1 +}b\" \"c\";", "print \"a${}b\";"] {
+            let Err(InterpretError::CompileError(errors)) = interpret(&mut vm, src) else {
+                panic!("{src} should fail to compile");
+            };
+            assert_eq!(
+                (errors[0].message.as_str(), errors[0].at.as_str()),
+                ("Expect expression", "'}b\"'"),
+                "{src}"
+            );
+        }
+    }
+
+    #[test]
+    fn lambdas() {
+        let src = r#"
+fun apply(f, a, b) { return f(a, b); }
+var sum = apply(fun (a, b) { return a + b; }, 1, 2);
+
+fun adder(n) { return fun (x) { return x + n; }; }
+var added = adder(10)(5);
+
+var called = 0;
+fun () { called = called + 1; }();
+
+class Counter {
+    init() { this.count = 0; }
+    incrementer() { return fun () { this.count = this.count + 1; }; }
+}
+var counter = Counter();
+var increment = counter.incrementer();
+increment();
+increment();
+var count = counter.count;
+
+var mapped = [];
+fun each(list, f) {
+    for (var i = 0; i < len(list); i = i + 1) f(list[i]);
+}
+each([1, 2, 3], fun (x) { push(mapped, x * 2); });
+var last = mapped[2];
+var name = "${fun () {}}";
+"#;
+
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        for (name, expected) in [
+            ("sum", Value::Number(3.0)),
+            ("added", Value::Number(15.0)),
+            ("called", Value::Number(1.0)),
+            ("count", Value::Number(2.0)),
+            ("last", Value::Number(6.0)),
+        ] {
+            let name_str = vm.get_string(name);
+            assert_eq!(vm.mem.globals.get(name_str), Some(expected), "{name}");
+        }
+
+        let name = vm.get_string("name");
+        let name = vm.mem.globals.get(name).unwrap();
+        assert_eq!(name.as_str(), Some("<fn lambda>"));
+
+        for src in [
+            "var f = fun { };",
+            "var f = fun (a) return a;",
+            "var f = fun (1) {};",
+        ] {
+            let err = interpret(&mut vm, src);
+            assert!(matches!(err, Err(InterpretError::CompileError(_))), "{src}");
+        }
+    }
+
+    #[test]
+    fn for_in() {
+        let src = r#"
+var sum = 0;
+for (x in [1, 2, 3]) sum = sum + x;
+
+var chars = "";
+for (var c in "héllo") chars = c + chars;
+
+var keys_sum = 0;
+var map = {1: "a", 2: "b", 3: "c"};
+for (k in map) {
+    keys_sum = keys_sum + k;
+    map[k + 10] = "added while iterating";
+}
+
+var skipped = 0;
+for (x in [1, 2, 3, 4, 5]) {
+    if (x == 2) continue;
+    if (x == 4) break;
+    skipped = skipped + x;
+}
+
+var closures = [];
+for (x in ["a", "b"]) push(closures, fun () { return x; });
+var captured = closures[0]() + closures[1]();
+
+var nested = 0;
+for (a in [1, 2]) for (b in [10, 20]) nested = nested + a * b;
+
+var empty = 0;
+for (x in []) empty = 1;
+
+var in_function;
+fun f() { var total = 0; for (x in [4, 5]) { var y = x; total = total + y; } return total; }
+in_function = f();
+
+var growing = [1];
+var iterations = 0;
+for (x in growing) { if (len(growing) < 3) push(growing, x); iterations = iterations + 1; }
+"#;
+
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        for (name, expected) in [
+            ("sum", Value::Number(6.0)),
+            ("keys_sum", Value::Number(6.0)),
+            ("skipped", Value::Number(4.0)),
+            ("nested", Value::Number(90.0)),
+            ("empty", Value::Number(0.0)),
+            ("in_function", Value::Number(9.0)),
+            ("iterations", Value::Number(3.0)),
+        ] {
+            let name_str = vm.get_string(name);
+            assert_eq!(vm.mem.globals.get(name_str), Some(expected), "{name}");
+        }
+
+        for (name, expected) in [("chars", "olléh"), ("captured", "ab")] {
+            let name_str = vm.get_string(name);
+            let value = vm.mem.globals.get(name_str).unwrap();
+            assert_eq!(value.as_str(), Some(expected), "{name}");
+        }
+
+        let err = interpret(&mut vm, "for (x in 1) print x;");
+        assert!(matches!(err, Err(InterpretError::RuntimeError(_))));
+        for src in [
+            "for (x in) print x;",
+            "for (x in [1] print x;",
+            "var in = 1;",
+        ] {
+            let err = interpret(&mut vm, src);
+            assert!(matches!(err, Err(InterpretError::CompileError(_))), "{src}");
+        }
+    }
+
+    #[test]
+    fn exceptions() {
+        let src = r#"
+var caught;
+try { throw "oops"; } catch (e) { caught = e; }
+
+var runtime;
+try { var x = 1 + nil; } catch (e) { runtime = e; }
+
+var native;
+try { pop([]); } catch (e) { native = e; }
+
+fun thrower(n) { if (n == 0) throw [n]; return thrower(n - 1); }
+var from_callee;
+try { thrower(10); } catch (e) { from_callee = e[0]; }
+
+var log = "";
+try { log = log + "try "; } finally { log = log + "finally"; }
+
+var rethrown;
+try {
+    try { throw 1; } catch (e) { throw e + 1; } finally { log = log + " inner"; }
+} catch (e) {
+    rethrown = e;
+}
+
+fun returns() {
+    try { return "from try"; } finally { log = log + " returned"; }
+}
+var returned = returns();
+
+fun overrides() {
+    try { return 1; } finally { return 2; }
+}
+var overridden = overrides();
+
+fun nested_finally() {
+    try {
+        try { return "value"; } finally { log = log + " first"; }
+    } finally {
+        log = log + " second";
+    }
+}
+var nested = nested_finally();
+
+var closure;
+try {
+    var local = "captured";
+    closure = fun () { return local; };
+    throw nil;
+} catch (e) {}
+var captured = closure();
+
+var after_break;
+for (var i = 0; i < 3; i = i + 1) {
+    try { if (i == 1) break; } catch (e) { after_break = "stale handler"; }
+}
+try { throw "outside"; } catch (e) { after_break = e; }
+
+var stack_ok = 0;
+for (var i = 0; i < 100; i = i + 1) {
+    try { var a = 1; var b = 2; throw a + b; } catch (e) { stack_ok = stack_ok + e; }
+}
+"#;
+
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        for (name, expected) in [
+            ("caught", "oops"),
+            ("runtime", "Operands must be two numbers or two strings."),
+            ("native", "Can't pop from an empty list."),
+            ("log", "try finally inner returned first second"),
+            ("returned", "from try"),
+            ("nested", "value"),
+            ("captured", "captured"),
+            ("after_break", "outside"),
+        ] {
+            let name_str = vm.get_string(name);
+            let value = vm.mem.globals.get(name_str).unwrap();
+            assert_eq!(value.as_str(), Some(expected), "{name}");
+        }
+
+        for (name, expected) in [
+            ("from_callee", 0.0),
+            ("rethrown", 2.0),
+            ("overridden", 2.0),
+            ("stack_ok", 300.0),
+        ] {
+            let name_str = vm.get_string(name);
+            assert_eq!(
+                vm.mem.globals.get(name_str),
+                Some(Value::Number(expected)),
+                "{name}"
+            );
+        }
+
+        let err = interpret(&mut vm, "throw \"up\";");
+        match err {
+            Err(InterpretError::RuntimeError(err)) => {
+                assert_eq!(err.message, "Uncaught exception: up")
+            }
+            other => panic!("expected a runtime error, got {other:?}"),
+        }
+        let err = interpret(&mut vm, "try { throw 1; } finally { }");
+        assert!(matches!(err, Err(InterpretError::RuntimeError(_))));
+
+        for src in [
+            "try { }",
+            "try { } catch { }",
+            "throw;",
+            "while (true) { try { break; } finally { } }",
+            "while (true) { try { continue; } catch (e) { } finally { } }",
+        ] {
+            let err = interpret(&mut vm, src);
+            assert!(matches!(err, Err(InterpretError::CompileError(_))), "{src}");
+        }
+    }
+
+    #[test]
+    fn assert_statement() {
+        let mut vm = VM::new();
+        let ok = interpret(&mut vm, "var a = 2; assert a == 2; assert \"yes\";");
+        assert!(ok.is_ok());
+
+        let err = interpret(&mut vm, "var list = [1];\nassert  len(list)  >= 2 ;");
+        match err {
+            Err(InterpretError::RuntimeError(err)) => {
+                assert_eq!(err.message, "Assertion failed at line 2: len(list)  >= 2")
+            }
+            other => panic!("expected a runtime error, got {other:?}"),
+        }
+
+        let src = "var caught; try { assert nil; } catch (e) { caught = e; }";
+        interpret(&mut vm, src).unwrap();
+        let name = vm.get_string("caught");
+        let caught = vm.mem.globals.get(name).unwrap();
+        assert_eq!(caught.as_str(), Some("Assertion failed at line 1: nil"));
+
+        let err = interpret(&mut vm, "assert;");
+        assert!(matches!(err, Err(InterpretError::CompileError(_))));
+    }
+
+    #[test]
+    fn constants() {
+        let mut vm = VM::new();
+        let src = r#"
+const a = 1;
+var b;
+{
+    const c = a + 1;
+    fun inner() { return c; }
+    var a = 10;
+    a = a + 1;
+    b = inner() + a;
+}
+"#;
+        interpret(&mut vm, src).unwrap();
+        let b = vm.get_string("b");
+        assert_eq!(vm.mem.globals.get(b), Some(Value::Number(13.0)));
+
+        for src in [
+            "a = 2;",
+            "a += 2;",
+            "a++;",
+            "--a;",
+            "var a = 2;",
+            "fun f() { a = 2; }",
+            "{ const c = 1; c = 2; }",
+            "{ const c = 1; fun f() { c = 2; } }",
+            "{ const c = 1; c -= 1; }",
+            "const d;",
+        ] {
+            let err = interpret(&mut vm, src);
+            assert!(matches!(err, Err(InterpretError::CompileError(_))), "{src}");
+        }
+
+        // Assigned in a function before the constant is declared
+        let mut vm = VM::new();
+        let src = "fun f() { g = 2; }\nvar h = 1;\nconst g = 1; h = ;\nf();";
+        let Err(InterpretError::CompileError(errors)) = interpret(&mut vm, src) else {
+            panic!("assigned a constant");
+        };
+        let errors: Vec<_> = errors
+            .iter()
+            .map(|err| (err.line, err.column, err.message.as_str()))
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                (1, 11, "Can't assign to a constant."),
+                (3, 18, "Expect expression")
+            ]
+        );
+
+        // Declared by a later script, only the VM can tell
+        let mut vm = VM::new();
+        interpret(&mut vm, "fun f() { g = 2; }").unwrap();
+        interpret(&mut vm, "const g = 1;").unwrap();
+        let Err(InterpretError::RuntimeError(err)) = interpret(&mut vm, "f();") else {
+            panic!("assigned a constant");
+        };
+        assert_eq!(err.message, "Can't assign to a constant.");
+        let g = vm.get_string("g");
+        assert_eq!(vm.mem.globals.get(g), Some(Value::Number(1.0)));
+    }
+
+    #[test]
+    fn number_literals() {
+        let mut vm = VM::new();
+        for (src, expected) in [
+            ("0xFF", 255.0),
+            ("0Xff_ff", 65535.0),
+            ("0b1010", 10.0),
+            ("0b1111_0000", 240.0),
+            ("1_000_000", 1_000_000.0),
+            ("1.5e3", 1500.0),
+            ("2E-2", 0.02),
+            ("1_0.2_5e+1_0", 10.25e10),
+            ("0", 0.0),
+            ("0.5", 0.5),
+        ] {
+            interpret(&mut vm, &format!("var n = {src};")).unwrap();
+            let n = vm.get_string("n");
+            assert_eq!(
+                vm.mem.globals.get(n),
+                Some(Value::Number(expected)),
+                "{src}"
+            );
+        }
+
+        for src in [
+            "0x;", "0xFG;", "0b;", "0b102;", "1__0;", "1_;", "1e;", "1e+;", "12abc;", "0x_1;",
+        ] {
+            let err = interpret(&mut vm, src);
+            assert!(matches!(err, Err(InterpretError::CompileError(_))), "{src}");
+        }
+    }
+
+    #[test]
+    fn number_text() {
+        let mut engine = Loxide::new();
+        for (src, expected) in [
+            ("0.1 + 0.2", "0.3"),
+            ("3", "3"),
+            ("-0", "-0"),
+            ("1 / 3", "0.333333"),
+            ("123456", "123456"),
+            ("1234567", "1.23457e+06"),
+            ("2147483647", "2.14748e+09"),
+            ("2147483648", "2.14748e+09"),
+            ("1e23", "1e+23"),
+            ("0.0001", "0.0001"),
+            ("0.00001234", "1.234e-05"),
+            ("9.9999999", "10"),
+            ("1 / 0", "inf"),
+            ("-1 / 0", "-inf"),
+            ("0 / 0", "nan"),
+        ] {
+            assert_eq!(eval_text(&mut engine, src), expected, "{src}");
+        }
+        assert_eq!(
+            eval_str(&mut engine, "\"x = \" + 1e100").unwrap(),
+            "x = 1e+100"
+        );
+    }
+
+    #[test]
+    fn math_natives() {
+        let mut vm = VM::new();
+        for (src, expected) in [
+            ("sqrt(16)", 4.0),
+            ("abs(-2.5)", 2.5),
+            ("floor(-1.5)", -2.0),
+            ("ceil(1.2)", 2.0),
+            ("sin(0)", 0.0),
+            ("cos(0)", 1.0),
+            ("pow(2, 10)", 1024.0),
+            ("min(3, 1, 2)", 1.0),
+            ("max(3)", 3.0),
+            ("floor(pi)", 3.0),
+        ] {
+            interpret(&mut vm, &format!("var n = {src};")).unwrap();
+            let n = vm.get_string("n");
+            assert_eq!(
+                vm.mem.globals.get(n),
+                Some(Value::Number(expected)),
+                "{src}"
+            );
+        }
+
+        for src in [
+            "sqrt(\"4\");",
+            "sqrt();",
+            "pow(2);",
+            "min();",
+            "max(1, nil);",
+        ] {
+            let err = interpret(&mut vm, src);
+            assert!(matches!(err, Err(InterpretError::RuntimeError(_))), "{src}");
+        }
+
+        let mut vm = VM::with_options(VmOptions {
+            math: false,
+            ..VmOptions::default()
+        });
+        let err = interpret(&mut vm, "sqrt(4);");
+        assert!(matches!(err, Err(InterpretError::RuntimeError(_))));
+        let err = interpret(&mut vm, "pi;");
+        assert!(matches!(err, Err(InterpretError::RuntimeError(_))));
+    }
+
+    #[test]
+    fn string_natives() {
+        let mut vm = VM::with_gc_config(GcConfig {
+            stress: true,
+            ..GcConfig::default()
+        });
+        let src = r#"
+var parts = split("a,bb,,ccc", ",");
+var joined = join(parts, "-") + join([1, nil, "x"], "");
+var chars = join(chars("héllo"), " ");
+var results = [
+    length("héllo"), length([1, 2]), indexOf("héllo", "llo"), indexOf("abc", "z"),
+    indexOf([1, "a", nil], nil), len(parts), len(split("ab", ""))
+];
+var text = substring("héllo", 1, -1) + "|" + substring("héllo", 2, nil) + "|" +
+    toUpper("abc") + toLower("ÄB") + trim("  x y  ") + replace("a-b-c", "-", "+");
+"#;
+        interpret(&mut vm, src).unwrap();
+
+        let global = |vm: &mut VM, name: &str| {
+            let name = vm.get_string(name);
+            vm.mem.globals.get(name).unwrap()
+        };
+        assert_eq!(global(&mut vm, "joined").as_str(), Some("a-bb--ccc1nilx"));
+        assert_eq!(global(&mut vm, "chars").as_str(), Some("h é l l o"));
+        assert_eq!(
+            global(&mut vm, "text").as_str(),
+            Some("éll|llo|ABCäbx ya+b+c")
+        );
+        let results = global(&mut vm, "results").as_list().unwrap();
+        assert_eq!(
+            results.items,
+            [5.0, 2.0, 2.0, -1.0, 2.0, 4.0, 2.0].map(Value::Number)
+        );
+
+        for src in [
+            "substring(\"abc\", 2, 1);",
+            "substring(\"abc\", 0, 4);",
+            "split(1, \",\");",
+            "join(\"abc\", \",\");",
+            "replace(\"abc\", \"\", \"x\");",
+            "toUpper(nil);",
+        ] {
+            let err = interpret(&mut vm, src);
+            assert!(matches!(err, Err(InterpretError::RuntimeError(_))), "{src}");
+        }
+    }
+
+    #[test]
+    fn io_natives() {
+        let path = std::env::temp_dir().join(format!("loxide-io-{}.txt", std::process::id()));
+        let path = path.to_str().unwrap().replace('\\', "/");
+
+        let mut vm = VM::new();
+        let src = format!(
+            r#"
+writeFile("{path}", "one
+");
+appendFile("{path}", "two");
+var contents = readFile("{path}");
+"#
+        );
+        interpret(&mut vm, &src).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let name = vm.get_string("contents");
+        let contents = vm.mem.globals.get(name).unwrap();
+        assert_eq!(contents.as_str(), Some("one\ntwo"));
+
+        let err = interpret(&mut vm, &format!("readFile(\"{path}\");"));
+        assert!(matches!(err, Err(InterpretError::RuntimeError(_))));
+        let err = interpret(&mut vm, "writeFile(1, \"\");");
+        assert!(matches!(err, Err(InterpretError::RuntimeError(_))));
+
+        let mut vm = VM::with_options(VmOptions {
+            io: false,
+            ..VmOptions::default()
+        });
+        let err = interpret(&mut vm, &format!("readFile(\"{path}\");"));
+        assert!(
+            matches!(err, Err(InterpretError::RuntimeError(err)) if err.message.contains("Undefined"))
+        );
+    }
+
+    #[test]
+    fn time_and_random_natives() {
+        let src = r#"
+var start = clock();
+sleep(1);
+var elapsed = clock() - start;
+var after_2000 = now() > 946684800000;
+var draws = [random(), random(), randomInt(1, 6), randomInt(-3, -3)];
+"#;
+        let options = || VmOptions {
+            random_seed: Some(42),
+            ..VmOptions::default()
+        };
+        let mut draws = vec![];
+        for _ in 0..2 {
+            let mut vm = VM::with_options(options());
+            interpret(&mut vm, src).unwrap();
+
+            let global = |vm: &mut VM, name: &str| {
+                let name = vm.get_string(name);
+                vm.mem.globals.get(name).unwrap()
+            };
+            let Some(elapsed) = global(&mut vm, "elapsed").as_number() else {
+                panic!("elapsed should be a number");
+            };
+            assert!(elapsed >= 0.001, "{elapsed}");
+            assert_eq!(global(&mut vm, "after_2000"), Value::Bool(true));
+
+            let items = global(&mut vm, "draws").as_list().unwrap().items.clone();
+            let numbers: Vec<_> = items.iter().map(|item| item.as_number()).collect();
+            let [Some(a), Some(b), Some(die), Some(fixed)] = numbers[..]
+            else {
+                panic!("draws should be numbers");
+            };
+            assert!((0.0..1.0).contains(&a) && (0.0..1.0).contains(&b) && a != b);
+            assert!((1.0..=6.0).contains(&die) && die.fract() == 0.0);
+            assert_eq!(fixed, -3.0);
+            draws.push(items);
+        }
+        // Same seed, same numbers
+        assert_eq!(draws[0], draws[1]);
+
+        let mut vm = VM::new();
+        for src in [
+            "randomInt(2, 1);",
+            "randomInt(0.5, 1);",
+            "sleep(-1);",
+            "sleep(\"1\");",
+            "sleep(1 / 0);",
+            "sleep(1e300);",
+        ] {
+            let err = interpret(&mut vm, src);
+            assert!(matches!(err, Err(InterpretError::RuntimeError(_))), "{src}");
+        }
+    }
+
+    #[test]
+    fn conversion_natives() {
+        let mut vm = VM::new();
+        let src = r#"
+class A {}
+var types = [
+    type(nil), type(true), type(1), type("a"), type(clock), type(type), type(fun () {}),
+    type(A), type(A()), type([]), type({})
+];
+var strings = [toString(1.5), toString(nil), toString([1, "a"]), toString("s"), toString(A)];
+var numbers = [
+    toNumber(" -1.5e3 "), toNumber("12"), toNumber(7), toNumber("1x"), toNumber("inf"),
+    toNumber(""), parseInt("ff", 16), parseInt("-101", 2), parseInt("+z", 36),
+    parseInt("12", 2), parseInt("", 10), parseInt("-", 10)
+];
+"#;
+        interpret(&mut vm, src).unwrap();
+
+        let mut list = |name: &str| {
+            let name = vm.get_string(name);
+            vm.mem
+                .globals
+                .get(name)
+                .unwrap()
+                .as_list()
+                .unwrap()
+                .items
+                .clone()
+        };
+        let types: Vec<String> = list("types")
+            .iter()
+            .map(|v| v.as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(
+            types,
+            [
+                "nil", "bool", "number", "string", "function", "function", "function", "class",
+                "instance", "list", "map"
+            ]
+        );
+        let strings: Vec<String> = list("strings")
+            .iter()
+            .map(|v| v.as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(strings, ["1.5", "nil", "[1, \"a\"]", "s", "A"]);
+        assert_eq!(
+            list("numbers"),
+            [
+                Value::Number(-1500.0),
+                Value::Number(12.0),
+                Value::Number(7.0),
+                Value::Nil,
+                Value::Nil,
+                Value::Nil,
+                Value::Number(255.0),
+                Value::Number(-5.0),
+                Value::Number(35.0),
+                Value::Nil,
+                Value::Nil,
+                Value::Nil,
+            ]
+        );
+
+        for src in [
+            "parseInt(\"1\", 1);",
+            "parseInt(\"1\", 2.5);",
+            "parseInt(1, 10);",
+            "toNumber(nil);",
+        ] {
+            let err = interpret(&mut vm, src);
+            assert!(matches!(err, Err(InterpretError::RuntimeError(_))), "{src}");
+        }
+    }
+
+    #[test]
+    fn process_natives() {
+        let mut vm = VM::new();
+        let err = interpret(&mut vm, "getenv(\"PATH\");");
+        assert!(
+            matches!(err, Err(InterpretError::RuntimeError(err)) if err.message.contains("Undefined"))
+        );
+
+        std::env::set_var("LOXIDE_PROCESS_NATIVES", "set");
+        let mut vm = VM::with_options(VmOptions {
+            allow_process: true,
+            ..VmOptions::default()
+        });
+        vm.script_args = vec!["a".to_string(), "b c".to_string()];
+        let src = r#"
+var set = getenv("LOXIDE_PROCESS_NATIVES");
+var unset = getenv("LOXIDE_PROCESS_NATIVES_UNSET");
+var args = join(argv(), "|");
+"#;
+        interpret(&mut vm, src).unwrap();
+
+        let mut global = |name: &str| {
+            let name = vm.get_string(name);
+            vm.mem.globals.get(name).unwrap()
+        };
+        assert_eq!(global("set").as_str(), Some("set"));
+        assert_eq!(global("unset"), Value::Nil);
+        assert_eq!(global("args").as_str(), Some("a|b c"));
+
+        for src in [
+            "exit(1.5);",
+            "exit(\"1\");",
+            "exit(300);",
+            "exit(-1);",
+            "getenv(1);",
+        ] {
+            let err = interpret(&mut vm, src);
+            assert!(matches!(err, Err(InterpretError::RuntimeError(_))), "{src}");
+        }
+    }
+
+    #[test]
+    fn disassemble() {
+        let mut vm = VM::new();
+        let src =
+            "var a = \"x\";\nfun f(n) {\n  while (n) n = n - 1;\n  return fun () { return n; };\n}";
+        let listing = crate::compile(&mut vm, src).unwrap().disassemble();
+
+        let expected = "\
+== <script> ==
+0000    1 Constant            1 'x'
+0002    | DefineGlobal        0 'a'
+0004    5 Closure             3 <fn f>
+0006    | DefineGlobal        2 'f'
+0008    | Nil
+0009    | Return
+
+== f ==
+0000    3 GetLocal            1
+0002    | JumpIfFalse         2 -> 17
+0005    | Pop
+0006    | GetLocal            1
+0008    | Constant            0 1
+0010    | Subtract
+0011    | SetLocal            1
+0013    | Pop
+0014    | Loop               14 -> 0
+0017    | Pop
+0018    4 Closure             1 <fn lambda>
+0020    |                     local 1
+0022    | Return
+0023    5 Nil
+0024    | Return
+
+== lambda ==
+0000    4 GetUpvalue          0
+0002    | Return
+0003    | Nil
+0004    | Return
+";
+        assert_eq!(listing, expected);
+    }
+
+    #[test]
+    fn trace_execution() {
+        let mut vm = VM::with_options(VmOptions {
+            trace_execution: true,
+            ..VmOptions::default()
+        });
+        let src = "fun f(a) { return [a, \"b\"]; } var x = f(1); try { throw x; } catch (e) { }";
+        interpret(&mut vm, src).unwrap();
+        assert!(vm.trace_execution);
+
+        vm.trace_execution = false;
+        interpret(&mut vm, "x = len(x);").unwrap();
+        let x = vm.get_string("x");
+        assert_eq!(vm.mem.globals.get(x), Some(Value::Number(2.0)));
+    }
+
+    #[test]
+    fn serialize_bytecode() {
+        use crate::chunk::serialize::{deserialize, serialize};
+
+        let src = r#"
+class Counter {
+    init(start) { this.n = start; }
+    next() { this.n = this.n + 1; return this.n; }
+}
+fun adder(x) { return fun (y) { return x + y; }; }
+var c = Counter(1.5);
+c.next();
+var result = adder(c.n)(10) + len("four") + (nil == nil and true ? 1 : 0);
+"#;
+        let bytes = {
+            let mut vm = VM::new();
+            crate::compile(&mut vm, src).unwrap().serialize()
+        };
+
+        let mut vm = VM::new();
+        let function = deserialize(&mut vm.mem, &bytes).unwrap();
+        assert_eq!(serialize(function.as_ref()), bytes);
+        crate::run_function(&mut vm, function).unwrap();
+        let result = vm.get_string("result");
+        assert_eq!(vm.mem.globals.get(result), Some(Value::Number(17.5)));
+
+        assert!(deserialize(&mut vm.mem, b"print 1;").is_err());
+        for len in 0..bytes.len() {
+            assert!(deserialize(&mut vm.mem, &bytes[..len]).is_err());
+        }
+        let mut extra = bytes.clone();
+        extra.push(0);
+        assert!(deserialize(&mut vm.mem, &extra).is_err());
+        let mut version = bytes.clone();
+        version[4] += 1;
+        assert!(deserialize(&mut vm.mem, &version).is_err());
+
+        // `print 1;` with a constant index and then a jump past the end of the code
+        let mut bad_constant = crate::compile(&mut vm, "print 1;").unwrap().serialize();
+        let code_start = 4 + 1 + 1 + 4 + 4 + 1 + 4;
+        bad_constant[code_start + 1] = 9;
+        assert!(deserialize(&mut vm.mem, &bad_constant).is_err());
+        let mut bad_jump = crate::compile(&mut vm, "if (true) 1;").unwrap().serialize();
+        bad_jump[code_start + 3] = 0xff;
+        assert!(deserialize(&mut vm.mem, &bad_jump).is_err());
+        // Into the middle of the constant after the `Pop`
+        bad_jump[code_start + 3] = 2;
+        assert!(deserialize(&mut vm.mem, &bad_jump).is_err());
+        // A local past the end of the frame
+        let mut bad_local = crate::compile(&mut vm, "{ var a = 1; print a; }")
+            .unwrap()
+            .serialize();
+        assert!(deserialize(&mut vm.mem, &bad_local).is_ok());
+        bad_local[code_start + 3] = 2;
+        assert!(deserialize(&mut vm.mem, &bad_local).is_err());
+    }
+
+    #[test]
+    fn crafted_bytecode_fails_at_runtime() {
+        use crate::chunk::{
+            serialize::{deserialize, serialize},
+            Instruction, Opcode,
+        };
+
+        // Bytecode that passes validation, but breaks what the compiler guarantees the VM
+        for (src, from, to, message) in [
+            (
+                "print 1;",
+                Opcode::Print,
+                Opcode::EndModule,
+                "Only a module's script can end the module.",
+            ),
+            (
+                "print 1;",
+                Opcode::Print,
+                Opcode::Yield,
+                "Can only yield from a generator.",
+            ),
+            (
+                "class A { static var x = 1; }",
+                Opcode::StaticField,
+                Opcode::Method,
+                "Invalid class member.",
+            ),
+            (
+                "class A { static var x = 1; }",
+                Opcode::StaticField,
+                Opcode::Setter,
+                "Invalid class member.",
+            ),
+        ] {
+            let bytes = {
+                let mut vm = VM::new();
+                let mut function = crate::compile_with(&mut vm, src, false).unwrap();
+                let chunk = &mut function.chunk;
+                let mut offset = 0;
+                while offset < chunk.len() {
+                    let start = offset;
+                    match chunk.disassemble_instruction(&mut offset).unwrap() {
+                        Instruction::Simple(op) | Instruction::Constant(op, _) if op == from => {
+                            chunk.code[start] = to as u8;
+                        }
+                        _ => (),
+                    }
+                }
+                serialize(function.as_ref())
+            };
+
+            let mut vm = VM::with_options(VmOptions {
+                stderr: Box::new(std::io::sink()),
+                ..VmOptions::default()
+            });
+            let function = deserialize(&mut vm.mem, &bytes).unwrap();
+            assert_eq!(
+                runtime_message(crate::run_function(&mut vm, function)),
+                message,
+                "{src}"
+            );
+        }
+    }
+
+    #[test]
+    fn long_constants_and_lines() {
+        let mut vm = VM::new();
+        let sum = (0..300)
+            .map(|i| i.to_string())
+            .collect::<Vec<_>>()
+            .join(" + ");
+        let src = format!("var s =\n  {sum}\n\n  + 1;");
+        let function = crate::compile_with(&mut vm, &src, false).unwrap();
+        let chunk = &function.as_ref().chunk;
+        assert_eq!(chunk.lines.len(), 2);
+        assert_eq!(chunk.get_line(0), 2);
+        assert_eq!(chunk.get_line(chunk.len() - 1), 4);
+
+        let listing = crate::chunk::disassemble::disassemble_chunk(chunk, "long");
+        assert!(listing.contains("ConstantLong      300 299\n"), "{listing}");
+        let mut offset = 0;
+        while offset < chunk.len() {
+            assert!([2, 4].contains(&chunk.get_line(offset)));
+            chunk.disassemble_instruction(&mut offset).unwrap();
+        }
+
+        interpret(&mut vm, &src).unwrap();
+        let s = vm.get_string("s");
+        assert_eq!(
+            vm.mem.globals.get(s),
+            Some(Value::Number((0..300).sum::<i32>() as f64 + 1.0))
+        );
+
+        let err = interpret(&mut vm, &format!("var u = {sum}\n\n  + -nil;")).unwrap_err();
+        let InterpretError::RuntimeError(err) = err else {
+            panic!("expected a runtime error");
+        };
+        assert_eq!(err.trace[0].line, 3);
+    }
+
+    #[test]
+    fn constant_dedup() {
+        let mut vm = VM::new();
+        let src = format!(
+            "var x = 0;\n{}\nfun f() {{ return \"x\" + \"x\"; }}\nvar s = f() + \"x\";",
+            "x = x + 1.0;\n".repeat(1000)
+        );
+        let function = crate::compile_with(&mut vm, &src, false).unwrap();
+        let constants = &function.as_ref().chunk.constants;
+        // `x`, 0, 1, `f`, <fn f>, `s`, and "x" shared with the name
+        assert_eq!(constants.len(), 6, "{constants:?}");
+        let f = constants
+            .iter()
+            .find_map(|constant| constant.as_fn())
+            .unwrap();
+        assert_eq!(f.as_ref().chunk.constants.len(), 1);
+
+        interpret(&mut vm, &src).unwrap();
+        let x = vm.get_string("x");
+        assert_eq!(vm.mem.globals.get(x), Some(Value::Number(1000.0)));
+        let s = vm.get_string("s");
+        let xxx = vm.get_string("xxx");
+        assert_eq!(vm.mem.globals.get(s), Some(Value::Obj(xxx.upcast())));
+    }
+
+    #[test]
+    fn peephole_optimizer() {
+        let src = r#"
+var log = "";
+fun f(n, flag) {
+    var r = 2 * 3 + -(4 - 1) / 2;
+    if (!!flag) r = r + 100; else r = r - 100;
+    while (!!!(n < 3)) { n = n - 1; log = log + "w"; }
+    for (var i = 0; i < 3; i = i + 1) {
+        if (i == 1) continue;
+        log = log + toString(i);
+    }
+    try { throw "t"; log = log + "dead"; } catch (e) { log = log + e; }
+    return r;
+    log = log + "dead";
+}
+var a = f(5, true);
+var b = f(1, nil);
+var c = "a" + "b" + toString(1 * 2 * 3);
+"#;
+        let globals = |optimize| {
+            let mut vm = VM::with_options(VmOptions {
+                optimize,
+                ..VmOptions::default()
+            });
+            interpret(&mut vm, src).unwrap();
+            ["log", "a", "b", "c"].map(|name| {
+                let name = vm.get_string(name);
+                format!("{:?}", vm.mem.globals.get(name).map(ValueWrapper))
+            })
+        };
+        assert_eq!(globals(true), globals(false));
+
+        let mut vm = VM::with_options(VmOptions {
+            optimize: true,
+            ..VmOptions::default()
+        });
+        let listing = crate::compile(&mut vm, "print 2 * 3 + -(4 - 1) / 2;")
+            .unwrap()
+            .disassemble();
+        assert!(
+            listing.ends_with(
+                "Constant            7 4.5\n0002    | Print\n0003    | Nil\n0004    | Return\n"
+            ),
+            "{listing}"
+        );
+
+        let function = crate::compile_with(
+            &mut vm,
+            "fun f() { return 1; print 2; } if (!!f()) print 3;",
+            false,
+        )
+        .unwrap();
+        let listing = crate::chunk::disassemble::disassemble_function(function.as_ref());
+        assert!(!listing.contains("Not"), "{listing}");
+        assert!(!listing.contains("Print               1\n"), "{listing}");
+        let f = function
+            .as_ref()
+            .chunk
+            .constants
+            .iter()
+            .find_map(|c| c.as_fn())
+            .unwrap();
+        assert_eq!(f.as_ref().chunk.len(), 3, "{listing}");
+    }
+
+    #[test]
+    fn superinstructions() {
+        let src = r#"
+fun sum(n) {
+    var total = 0;
+    for (var i = 0; i < n; i = i + 1) total = total + i;
+    return total;
+}
+fun add(a, b) { return a + b; }
+var calls = 0;
+fun count(x) { calls = calls + x; }
+for (var i = 0; i < 10; i = i + 1) count(1);
+var total = sum(100);
+var string = add("a", "b");
+"#;
+        let run = |optimize| {
+            let mut vm = VM::with_options(VmOptions {
+                optimize,
+                count_dispatches: true,
+                ..VmOptions::default()
+            });
+            interpret(&mut vm, src).unwrap();
+            let globals = ["calls", "total", "string"].map(|name| {
+                let name = vm.get_string(name);
+                format!("{:?}", vm.mem.globals.get(name).map(ValueWrapper))
+            });
+            assert!(interpret(&mut vm, "add(1, nil);").is_err());
+            (globals, vm.dispatch_count)
+        };
+        let (plain, plain_count) = run(false);
+        let (fused, fused_count) = run(true);
+        assert_eq!(plain, fused);
+        assert!(
+            fused_count < plain_count * 9 / 10,
+            "{fused_count} {plain_count}"
+        );
+
+        let mut vm = VM::with_options(VmOptions {
+            optimize: true,
+            ..VmOptions::default()
+        });
+        let listing = crate::compile(&mut vm, src).unwrap().disassemble();
+        for op in ["AddLocals", "CallConstant", "LessLocalJumpIfFalse"] {
+            assert!(listing.contains(op), "{listing}");
+        }
+    }
+
+    #[test]
+    fn value_representation() {
+        let expected_size = if cfg!(feature = "nanboxing") { 8 } else { 16 };
+        assert_eq!(std::mem::size_of::<Value>(), expected_size);
+
+        for num in [
+            0.0,
+            -0.0,
+            1.5,
+            -1e300,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+            f64::MIN_POSITIVE,
+        ] {
+            let Unpacked::Number(unpacked) = Value::Number(num).unpack() else {
+                panic!("{num} should stay a number");
+            };
+            assert_eq!(unpacked.to_bits(), num.to_bits());
+        }
+        assert!(matches!(Value::Number(f64::NAN).unpack(), Unpacked::Number(n) if n.is_nan()));
+        assert!(matches!(Value::Number(-f64::NAN).unpack(), Unpacked::Number(n) if n.is_nan()));
+        assert!(matches!(Value::Bool(true).unpack(), Unpacked::Bool(true)));
+        assert!(matches!(Value::Bool(false).unpack(), Unpacked::Bool(false)));
+        assert!(matches!(Value::Nil.unpack(), Unpacked::Nil));
+        assert_ne!(Value::Nil, Value::Bool(false));
+        assert_ne!(Value::Number(0.0), Value::Bool(false));
+
+        let mut vm = VM::new();
+        let string = vm.get_string("boxed");
+        let value = Value::Obj(string.upcast());
+        assert!(
+            matches!(value.unpack(), Unpacked::Obj(obj) if obj.as_ptr() == string.as_ptr().cast())
+        );
+        assert_eq!(value.as_str(), Some("boxed"));
+    }
+
+    #[test]
+    fn global_slots() {
+        let mut vm = VM::new();
+        let src = "var total = 0; for (var i = 0; i < 100; i = i + 1) total = total + i;";
+        let function = crate::compile_with(&mut vm, src, false).unwrap();
+        crate::run_function(&mut vm, function).unwrap();
+        let total = vm.get_string("total");
+        assert_eq!(vm.mem.globals.get(total), Some(Value::Number(4950.0)));
+        // Only the constant naming `total` is a global, its slot is cached after the first use
+        let slot = vm.mem.globals.slot(total) as u32;
+        let cached: Vec<_> = function
+            .as_ref()
+            .chunk
+            .global_slots
+            .iter()
+            .flatten()
+            .collect();
+        assert_eq!(cached, vec![&slot]);
+
+        // Later scripts and natives find the same slot
+        interpret(
+            &mut vm,
+            "total = len(\"abc\") + total; fun f() { return total; } total = f() + 1;",
+        )
+        .unwrap();
+        assert_eq!(vm.mem.globals.get(total), Some(Value::Number(4954.0)));
+
+        // Assigning an undefined global fails and leaves it undefined
+        assert!(interpret(&mut vm, "fun g() { undefined = 1; } g();").is_err());
+        let undefined = vm.get_string("undefined");
+        assert_eq!(vm.mem.globals.get(undefined), None);
+        assert!(interpret(&mut vm, "g();").is_err());
+        interpret(&mut vm, "var undefined = 2; g();").unwrap();
+        assert_eq!(vm.mem.globals.get(undefined), Some(Value::Number(1.0)));
+        assert!(vm.mem.globals.delete(undefined));
+        assert!(interpret(&mut vm, "print undefined;").is_err());
+    }
+
+    #[test]
+    fn method_caches() {
+        let src = r#"
+class A { name() { return "a"; } }
+class B < A { name() { return "b"; } }
+class C < A {}
+fun names(list) {
+    var result = "";
+    for (var i = 0; i < len(list); i = i + 1) {
+        var get = list[i].name;
+        result = result + list[i].name() + get();
+    }
+    return result;
+}
+var objects = [A(), A(), B(), C(), B()];
+var first = names(objects);
+// Classes come and go between calls, the caches must not mix them up
+class A { name() { return "new a"; } }
+objects[4].name = A().name;
+var second = names(objects);
+"#;
+
+        let mut vm = VM::with_gc_config(GcConfig {
+            stress: true,
+            ..GcConfig::default()
+        });
+        interpret(&mut vm, src).unwrap();
+        let global = |vm: &mut VM, name: &str| {
+            let name = vm.get_string(name);
+            vm.mem
+                .globals
+                .get(name)
+                .unwrap()
+                .as_str()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(global(&mut vm, "first"), "aaaabbaabb");
+        assert_eq!(global(&mut vm, "second"), "aaaabbaanew anew a");
+
+        assert!(interpret(&mut vm, "fun f(o) { return o.missing(); } f(A());").is_err());
+        assert!(interpret(&mut vm, "fun g(o) { return o.name; } g(1);").is_err());
+    }
+
+    #[test]
+    fn fuel() {
+        let mut vm = VM::with_options(VmOptions {
+            max_instructions: Some(1000),
+            ..VmOptions::default()
+        });
+        assert_eq!(
+            interpret(&mut vm, "while (true) {}"),
+            Err(InterpretError::OutOfFuel)
+        );
+        // The budget is per run, and the stack is reset after running out
+        interpret(&mut vm, "var x = 1;").unwrap();
+        assert_eq!(
+            interpret(&mut vm, "for (var i = 0; i < 1000; i = i + 1) x = x + 1;"),
+            Err(InterpretError::OutOfFuel)
+        );
+
+        // A paused script continues where it stopped
+        let src = "var total = 0; for (var i = 0; i < 100; i = i + 1) total = total + i;";
+        let mut vm = VM::new();
+        crate::compile(&mut vm, src).unwrap().start();
+        let mut runs = 1;
+        let result = loop {
+            match vm.run_fuel(7) {
+                Err(InterpretError::OutOfFuel) => runs += 1,
+                result => break result,
+            }
+        };
+        assert_eq!(result, Ok(Value::Nil));
+        assert!(runs > 100, "{runs}");
+        let total = vm.get_string("total");
+        assert_eq!(vm.mem.globals.get(total), Some(Value::Number(4950.0)));
+
+        crate::compile(&mut vm, "1 + nil;").unwrap().start();
+        assert!(matches!(
+            vm.run_fuel(100),
+            Err(InterpretError::RuntimeError(_))
+        ));
+        assert_eq!(vm.call_frame_count, 0);
+    }
+
+    #[test]
+    fn interrupt() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let interrupt = Arc::new(AtomicBool::new(false));
+        let mut vm = VM::with_options(VmOptions {
+            interrupt: Some(Arc::clone(&interrupt)),
+            ..VmOptions::default()
+        });
+        interpret(
+            &mut vm,
+            "fun f(n) { return n; } var x = 0; while (x < 10) x = x + f(1);",
+        )
+        .unwrap();
+
+        // Stops loops and calls, also in a `try`
+        interrupt.store(true, Ordering::Relaxed);
+        for src in [
+            "while (true) {}",
+            "try { for (;;) {} } catch (e) {}",
+            "f(1);",
+        ] {
+            assert_eq!(
+                interpret(&mut vm, src),
+                Err(InterpretError::Interrupted),
+                "{src}"
+            );
+        }
+        // Code without either still runs
+        interpret(&mut vm, "x = x + 1;").unwrap();
+        let x = vm.get_string("x");
+        assert_eq!(vm.mem.globals.get(x), Some(Value::Number(11.0)));
+    }
+
+    #[test]
+    fn stack_overflow() {
+        let depth = "fun depth(n) { if (n == 0) return 0; return 1 + depth(n - 1); }";
+        let mut vm = VM::new();
+        interpret(&mut vm, depth).unwrap();
+        let err = interpret(&mut vm, "try { depth(100); } catch (e) {}");
+        let Err(InterpretError::StackOverflow(err)) = err else {
+            panic!("{err:?}")
+        };
+        assert_eq!(
+            err.message,
+            "Stack overflow, 64 calls deep. Innermost calls:"
+        );
+        assert_eq!(err.trace.len(), 16);
+        interpret(&mut vm, "var shallow = depth(60);").unwrap();
+
+        let mut vm = VM::with_options(VmOptions {
+            max_frames: 1000,
+            ..VmOptions::default()
+        });
+        interpret(&mut vm, depth).unwrap();
+        interpret(&mut vm, "var deep = depth(900);").unwrap();
+        let deep = vm.get_string("deep");
+        assert_eq!(vm.mem.globals.get(deep), Some(Value::Number(900.0)));
+
+        // Each call of `depth` needs a few slots for the callee, the argument and temporaries
+        let mut vm = VM::with_options(VmOptions {
+            max_frames: 1000,
+            stack_size: 1000,
+            ..VmOptions::default()
+        });
+        interpret(&mut vm, depth).unwrap();
+        assert!(matches!(
+            interpret(&mut vm, "depth(900);"),
+            Err(InterpretError::StackOverflow(_))
+        ));
+        interpret(&mut vm, "depth(100);").unwrap();
+        // Natives calling back into the VM can't push more arguments than fit either
+        let depth = vm.get_global("depth").unwrap();
+        assert!(matches!(
+            vm.call_value_with_args(depth, &[Value::Nil; 255]),
+            Err(InterpretError::RuntimeError(_))
+        ));
+        let mut vm = VM::with_options(VmOptions {
+            stack_size: 100,
+            ..VmOptions::default()
+        });
+        interpret(&mut vm, "fun f() {}").unwrap();
+        let f = vm.get_global("f").unwrap();
+        assert!(matches!(
+            vm.call_value_with_args(f, &[Value::Nil; 255]),
+            Err(InterpretError::StackOverflow(_))
+        ));
+    }
+
+    #[test]
+    fn values_from_the_host() {
+        let mut engine = Loxide::with_options(VmOptions {
+            stderr: Box::new(std::io::sink()),
+            ..VmOptions::default()
+        });
+        engine.eval("fun id(x) { return x; }").unwrap();
+        let list = engine.eval("[1, 2]").unwrap();
+        // Natives have to be `Send`, which a value isn't without nanboxing
+        struct Stale(Value);
+        unsafe impl Send for Stale {}
+        let stale = Stale(list);
+        engine.register_native("stale", 0, move |_vm, _args| {
+            let Stale(list) = &stale;
+            Ok(*list)
+        });
+        let stale = engine.get_global("stale").unwrap();
+        let list_ref = engine.vm.value_ref(list).unwrap();
+        assert_eq!(list_ref.to_string(), "[1, 2]");
+        assert_eq!(list_ref.type_name(), "list");
+        assert_eq!(engine.vm.value_ref(Value::Nil).unwrap().to_string(), "nil");
+
+        // Nothing references the list, a collection frees it. The list allocated next gets its
+        // slot, and likely its address, but not its handle
+        engine.vm.collect_garbage();
+        let other_list = engine.eval("[3, 4]").unwrap();
+        assert_ne!(other_list, list);
+        assert!(engine.vm.value_ref(list).is_none());
+        assert_eq!(
+            engine.vm.value_ref(other_list).unwrap().to_string(),
+            "[3, 4]"
+        );
+        assert!(matches!(
+            engine.vm.call_function("id", &[list]),
+            Err(InterpretError::RuntimeError(_))
+        ));
+        assert!(matches!(
+            engine.vm.call_value_with_args(stale, &[]),
+            Err(InterpretError::RuntimeError(_))
+        ));
+
+        // Values only belong to the VM they came from
+        let mut other = Loxide::new();
+        let string = other.eval("\"abc\"").unwrap();
+        assert!(engine.vm.value_ref(string).is_none());
+        assert_eq!(other.vm.value_ref(string).unwrap().as_str(), Some("abc"));
+    }
+
+    #[test]
+    fn tail_calls() {
+        let src = r#"
+fun count(n, acc) { if (n == 0) return acc; return count(n - 1, acc + 1); }
+fun isEven(n) { if (n == 0) return true; return isOdd(n - 1); }
+fun isOdd(n) { if (n == 0) return false; return isEven(n - 1); }
+fun adders(n, fns) {
+    if (n == 0) return fns;
+    fun add(x) { return x + n; }
+    push(fns, add);
+    return adders(n - 1, fns);
+}
+class Pair { init(a, b) { this.a = a; this.b = b; } }
+fun pair(a) { return Pair(a, len("ab")); }
+fun guarded(n) { if (n == 0) return 0; try { return guarded(n - 1); } catch (e) { throw e; } }
+fun wrong(n) { return count(n); }
+"#;
+        let mut engine = Loxide::new();
+        engine.eval(src).unwrap();
+        assert_eq!(engine.eval("count(100000, 0)").unwrap(), Value::Int(100000));
+        assert_eq!(engine.eval("isEven(10001)").unwrap(), Value::Bool(false));
+        // The frame is reused only once the captured arguments are closed over
+        assert_eq!(engine.eval("adders(3, [])[2](10)").unwrap(), Value::Int(11));
+        assert_eq!(engine.eval("pair(1).b").unwrap(), Value::Int(2));
+        assert!(matches!(
+            engine.eval("guarded(100)"),
+            Err(InterpretError::StackOverflow(_))
+        ));
+        let Err(InterpretError::RuntimeError(err)) = engine.eval("wrong(1)") else {
+            panic!("the arity is still checked");
+        };
+        assert_eq!(err.message, "Expected 2 arguments but got 1.");
+        // A return value one byte long is all the chunk has before the `Return`
+        let returns =
+            "fun none() { return nil; } fun yes() { return true; } fun no() { return false; }";
+        engine.eval(returns).unwrap();
+        assert_eq!(
+            eval_text(&mut engine, "[none(), yes(), no()]"),
+            "[nil, true, false]"
+        );
+
+        let mut vm = VM::new();
+        let listing = crate::compile(&mut vm, src).unwrap().disassemble();
+        assert_eq!(listing.matches("TailCall").count(), 6, "{listing}");
+
+        let mut vm = VM::with_options(VmOptions {
+            tail_calls: false,
+            ..VmOptions::default()
+        });
+        interpret(&mut vm, src).unwrap();
+        assert!(matches!(
+            interpret(&mut vm, "count(100, 0);"),
+            Err(InterpretError::StackOverflow(_))
+        ));
+        interpret(&mut vm, "count(50, 0);").unwrap();
+    }
+
+    #[test]
+    fn default_and_rest_parameters() {
+        let src = r#"
+fun f(a, b = 10, ...rest) { return "${a} ${b} ${len(rest)}"; }
+fun twice(a, b = a * 2) { return b; }
+fun fresh(list = []) { push(list, 1); return len(list); }
+fun count(...items) { return len(items); }
+fun sum(n, acc = 0) { if (n == 0) return acc; return sum(n - 1, acc + n); }
+class Point { init(x = 1, y = x + 1) { this.x = x; this.y = y; } }
+"#;
+        let mut engine = Loxide::new();
+        engine.eval(src).unwrap();
+        let mut eval = |src| eval_text(&mut engine, src);
+        assert_eq!(eval("f(1)"), "1 10 0");
+        assert_eq!(eval("f(1, 2)"), "1 2 0");
+        assert_eq!(eval("f(1, nil)"), "1 10 0");
+        assert_eq!(eval("f(1, 2, 3, 4)"), "1 2 2");
+        assert_eq!(eval("twice(3)"), "6");
+        // Defaults are evaluated on every call
+        assert_eq!(eval("fresh() + fresh()"), "2");
+        assert_eq!(eval("count()"), "0");
+        assert_eq!(eval("count(1, 2, 3)"), "3");
+        assert_eq!(eval("sum(10000)"), "5.0005e+07");
+        assert_eq!(eval("Point().y + Point(5).y"), "8");
+
+        for (call, message) in [
+            ("f()", "Expected at least 1 arguments but got 0."),
+            ("twice(1, 2, 3)", "Expected at most 2 arguments but got 3."),
+            ("Point(1, 2, 3)", "Expected at most 2 arguments but got 3."),
+        ] {
+            let Err(InterpretError::RuntimeError(err)) = engine.eval(call) else {
+                panic!("{call} should fail");
+            };
+            assert_eq!(err.message, message);
+        }
+
+        for (src, message) in [
+            (
+                "fun f(a = 1, b) {}",
+                "Parameters with a default value must come last.",
+            ),
+            (
+                "fun f(...a, b) {}",
+                "A rest parameter must be the last parameter.",
+            ),
+            (
+                "class A { set x(value = 1) {} }",
+                "A setter takes exactly one parameter.",
+            ),
+        ] {
+            let Err(InterpretError::CompileError(errors)) = engine.eval(src) else {
+                panic!("{src} should fail to compile");
+            };
+            assert_eq!(errors[0].message, message);
+        }
+    }
+
+    #[test]
+    fn named_arguments() {
+        let src = r#"
+fun f(a, b = 10, c = 20) { return "${a} ${b} ${c}"; }
+class Point {
+    init(x, y = 0) { this.x = x; this.y = y; }
+    sum(scale = 1) { return (this.x + this.y) * scale; }
+}
+class Point3 < Point { sum(scale) { return super.sum(scale: scale * 2); } }
+var g = f;
+"#;
+        let mut engine = Loxide::new();
+        engine.eval(src).unwrap();
+        let mut eval = |src| eval_text(&mut engine, src);
+        // Through a global, so the names are matched when it's called
+        assert_eq!(eval("f(a: 1)"), "1 10 20");
+        assert_eq!(eval("f(1, c: 3)"), "1 10 3");
+        assert_eq!(eval("g(c: 3, a: 1, b: 2)"), "1 2 3");
+        assert_eq!(eval("Point(y: 2, x: 1).sum(scale: 3)"), "9");
+        assert_eq!(eval("Point3(1, 2).sum(2)"), "12");
+        // Arguments are evaluated in the order they're written
+        assert_eq!(
+            eval("var log = \"\"; fun l(s) { log = log + s; return s; } f(c: l(\"c\"), a: l(\"a\")); log"),
+            "ca"
+        );
+
+        for (call, message) in [
+            ("f(d: 1)", "No parameter named 'd'."),
+            (
+                "f(1, a: 2)",
+                "Got more than one argument for parameter 'a'.",
+            ),
+            ("f(b: 1)", "Missing argument for parameter 'a'."),
+            (
+                "len(a: 1)",
+                "Only functions and methods take named arguments.",
+            ),
+        ] {
+            let Err(InterpretError::RuntimeError(err)) = engine.eval(call) else {
+                panic!("{call} should fail");
+            };
+            assert_eq!(err.message, message);
+        }
+
+        // A local function's parameters are known, so the call is compiled like a positional one
+        let local = r#"
+{
+    fun h(a, b = 2, c = 3) { return a + b + c; }
+    print h(1, c: 10);
+    print h(c: 10, a: 1);
+}
+"#;
+        let mut vm = VM::new();
+        let listing = crate::compile(&mut vm, local).unwrap().disassemble();
+        assert_eq!(listing.matches("CallNamed").count(), 1, "{listing}");
+        interpret(&mut vm, local).unwrap();
+
+        for (src, message) in [
+            ("{ fun h(a) {} h(b: 1); }", "No parameter named 'b'."),
+            (
+                "{ fun h(a, b) {} h(b: 1); }",
+                "Missing argument for parameter 'a'.",
+            ),
+            (
+                "f(a: 1, 2);",
+                "Positional arguments must come before named ones.",
+            ),
+        ] {
+            let Err(InterpretError::CompileError(errors)) = engine.eval(src) else {
+                panic!("{src} should fail to compile");
+            };
+            assert_eq!(errors[0].message, message);
+        }
+    }
+
+    #[test]
+    fn natives_as_values() {
+        let src = r#"
+fun map(list, f) { var out = []; for (x in list) push(out, f(x)); return out; }
+fun compose(f, g) { return fun (x) { return f(g(x)); }; }
+var size = len;
+class Box { init(f) { this.f = f; } }
+"#;
+        let mut engine = Loxide::new();
+        engine.eval(src).unwrap();
+        let mut eval = |src| eval_text(&mut engine, src);
+        assert_eq!(eval("size(\"abc\")"), "3");
+        assert_eq!(eval("map([\"a\", \"bc\"], len)"), "[1, 2]");
+        assert_eq!(eval("compose(toUpper, trim)(\" a \")"), "A");
+        assert_eq!(eval("Box(len).f(\"ab\")"), "2");
+        assert_eq!(eval("[abs][0](-1)"), "1");
+        assert_eq!(eval("size == len and len != abs"), "true");
+        assert_eq!(eval("len"), "<native fn len>");
+        assert_eq!(eval("[clock]"), "[<native fn clock>]");
+        assert_eq!(eval("\"${size}\""), "<native fn len>");
+        let len = engine.eval("len").unwrap();
+        assert_eq!(
+            format!("{:?}", engine.vm.value_ref(len).unwrap()),
+            "<native fn len>"
+        );
+
+        let Err(InterpretError::RuntimeError(err)) = engine.eval("map([1], abs)(1)") else {
+            panic!("a list isn't callable");
+        };
+        assert_eq!(err.message, "Can only call functions and classes.");
+    }
+
+    #[test]
+    fn generators() {
+        let src = r#"
+fun range(n) { for (var i = 0; i < n; i++) yield i; }
+fun pairs(list) { for (x in list) { yield x; yield x * 10; } return "end"; }
+fun counter() {
+    var count = 0;
+    fun bump() { count = count + 1; }
+    yield bump;
+    yield count;
+    bump();
+    yield count;
+}
+fun guarded(log) {
+    try { yield 1; yield 2; } finally { push(log, "cleanup"); }
+}
+fun fails() { yield 1; throw "oops"; }
+fun self() { yield next(gen); }
+class Tree {
+    init(items) { this.items = items; }
+    each() { for (item in this.items) yield item; }
+}
+var gen = self();
+"#;
+        let mut engine = Loxide::new();
+        engine.eval(src).unwrap();
+        let mut eval = |src| eval_text(&mut engine, src);
+        assert_eq!(
+            eval("var out = []; for (i in range(4)) push(out, i); out"),
+            "[0, 1, 2, 3]"
+        );
+        assert_eq!(
+            eval("var p = pairs([1, 2]); [next(p), next(p), next(p)]"),
+            "[1, 10, 2]"
+        );
+        assert_eq!(
+            eval("[next(p), done(p), next(p), done(p), next(p)]"),
+            "[20, false, \"end\", true, nil]"
+        );
+        assert_eq!(eval("type(range(1))"), "generator");
+        assert_eq!(eval("range(1)"), "<generator range>");
+        // Nothing runs until the first `next`
+        assert_eq!(eval("var log = []; var g = guarded(log); len(log)"), "0");
+        assert_eq!(
+            eval("var sum = 0; for (x in g) sum = sum + x; [sum, log]"),
+            "[3, [\"cleanup\"]]"
+        );
+        // Locals captured by closures stay shared while the generator is suspended
+        assert_eq!(
+            eval("var c = counter(); var bump = next(c); bump(); bump(); next(c)"),
+            "2"
+        );
+        assert_eq!(eval("next(c)"), "3");
+        assert_eq!(
+            eval("var out = []; for (i in Tree([1, 2]).each()) push(out, i); out"),
+            "[1, 2]"
+        );
+        assert_eq!(
+            eval("var n = 0; for (x in range(3)) for (y in range(3)) n++; n"),
+            "9"
+        );
+
+        // An exception ends the generator
+        assert_eq!(
+            eval("var f = fails(); next(f); var caught; try { next(f); } catch (e) { caught = e; } [caught, done(f)]"),
+            "[\"oops\", true]"
+        );
+        for (src, message) in [
+            ("next(gen)", "Generator is already running."),
+            ("next(1)", "Expected a generator but got a number."),
+        ] {
+            let Err(InterpretError::RuntimeError(err)) = engine.eval(src) else {
+                panic!("{src} should fail");
+            };
+            assert_eq!(err.message, message);
+        }
+
+        for (src, message) in [
+            ("yield 1;", "Can't yield from top-level code."),
+            (
+                "class A { init() { yield 1; } }",
+                "Can't yield from an initializer.",
+            ),
+        ] {
+            let Err(InterpretError::CompileError(errors)) = engine.eval(src) else {
+                panic!("{src} should fail to compile");
+            };
+            assert_eq!(errors[0].message, message);
+        }
+    }
+
+    #[test]
+    fn tasks() {
+        let src = r#"
+var log = [];
+var ch = Channel();
+fun producer() {
+    for (var i = 1; i <= 3; i++) {
+        push(log, "send ${i}");
+        send(ch, i);
+        sleep(1);
+    }
+    send(ch, nil);
+}
+fun consumer() {
+    var sum = 0;
+    var item = await ch;
+    while (item != nil) {
+        push(log, "got ${item}");
+        sum = sum + item;
+        item = await ch;
+    }
+    push(log, "sum ${sum}");
+}
+fun turns(name) {
+    fun run() { for (var i = 0; i < 2; i++) { push(log, name + i); yield; } }
+    return run;
+}
+"#;
+        let mut engine = Loxide::new();
+        engine.eval(src).unwrap();
+        let mut eval = |src| eval_text(&mut engine, src);
+        // Tasks run once the script returns, until they're all done
+        assert_eq!(eval("spawn(consumer); spawn(producer); len(log)"), "0");
+        assert_eq!(
+            eval("log"),
+            "[\"send 1\", \"got 1\", \"send 2\", \"got 2\", \"send 3\", \"got 3\", \"sum 6\"]"
+        );
+        // `yield` lets the other tasks take a turn
+        assert_eq!(
+            eval("log = []; spawn(turns(\"a\")); spawn(turns(\"b\")); nil; "),
+            "nil"
+        );
+        assert_eq!(eval("log"), "[\"a0\", \"b0\", \"a1\", \"b1\"]");
+        // Sleeping tasks wake up in order
+        assert_eq!(
+            eval("log = []; spawn(fun () { sleep(20); push(log, 2); }); spawn(fun () { sleep(5); push(log, 1); }); nil"),
+            "nil"
+        );
+        assert_eq!(eval("log"), "[1, 2]");
+        // A value sent before anyone waits is kept
+        assert_eq!(eval("send(ch, 1); await ch"), "1");
+        assert_eq!(eval("type(ch)"), "channel");
+        assert_eq!(eval("ch"), "<channel>");
+        assert_eq!(eval("type(spawn(fun () {}))"), "generator");
+
+        for (src, message) in [
+            ("await ch", "Can only wait for a channel in a task."),
+            ("await 1", "Can only await channels."),
+            (
+                "spawn(fun (x) {})",
+                "A task's function can't take parameters.",
+            ),
+            ("spawn(1)", "Expected a function but got a number."),
+            ("next(spawn(fun () {}))", "Only the scheduler runs tasks."),
+            (
+                "spawn(fun () { await Channel(); })",
+                "Deadlock, every task is waiting for a channel.",
+            ),
+            (
+                "spawn(fun () { sleep(1 / 0); })",
+                "Sleep duration is too long.",
+            ),
+            (
+                "spawn(fun () { sleep(1e300); })",
+                "Sleep duration is too long.",
+            ),
+        ] {
+            let Err(InterpretError::RuntimeError(err)) = engine.eval(src) else {
+                panic!("{src} should fail");
+            };
+            assert_eq!(err.message, message);
+        }
+        // A failed run drops the tasks it left
+        let mut eval = |src| eval_text(&mut engine, src);
+        assert_eq!(eval("spawn(fun () { push(log, 3); }); len(log)"), "2");
+        assert_eq!(eval("log"), "[1, 2, 3]");
+    }
+
+    #[test]
+    fn replay() {
+        use crate::replay::Replay;
+
+        let src = "[clock(), now(), random(), randomInt(1, 100), random()]";
+        let trace = OutputBuffer::default();
+        let mut recording = Loxide::with_options(VmOptions {
+            replay: Some(Replay::Record(Box::new(trace.clone()))),
+            ..VmOptions::default()
+        });
+        let recorded = eval_text(&mut recording, src);
+        assert_eq!(trace.contents().lines().count(), 5);
+        assert!(trace.contents().starts_with("clock "));
+
+        // Without a seed, only the trace can make the numbers repeat
+        let mut replaying = Loxide::with_options(VmOptions {
+            replay: Some(Replay::parse(&trace.contents()).unwrap()),
+            ..VmOptions::default()
+        });
+        assert_eq!(eval_text(&mut replaying, src), recorded);
+
+        let mut engine = Loxide::with_options(VmOptions {
+            replay: Some(Replay::parse("readLine 5:hello\nreadLine 0:\nreadLine nil\n").unwrap()),
+            ..VmOptions::default()
+        });
+        assert_eq!(
+            eval_text(&mut engine, "[readLine(), readLine(), readLine()]"),
+            "[\"hello\", \"\", nil]"
+        );
+        let Err(InterpretError::RuntimeError(err)) = engine.eval("clock()") else {
+            panic!("the trace is over");
+        };
+        assert_eq!(err.message, "The replay trace has no more calls to clock.");
+
+        let mut engine = Loxide::with_options(VmOptions {
+            replay: Some(Replay::parse("clock 1.5\n").unwrap()),
+            ..VmOptions::default()
+        });
+        let Err(InterpretError::RuntimeError(err)) = engine.eval("random()") else {
+            panic!("the script diverged from the trace");
+        };
+        assert_eq!(
+            err.message,
+            "The replay trace has a call to clock here, not random."
+        );
+
+        for trace in ["clock", "clock x", "readLine 3:ab"] {
+            assert!(Replay::parse(trace).is_err(), "{trace}");
+        }
+    }
+
+    #[test]
+    fn stepping() {
+        let src = "var a = 1;
+fun add(x, y) {
+    return x + y;
+}
+var b = add(a, 2);
+var c = b * 2;";
+        let start = |vm: &mut VM| {
+            crate::compile(vm, src).unwrap().start();
+        };
+
+        let mut vm = VM::with_options(VmOptions {
+            count_dispatches: true,
+            ..VmOptions::default()
+        });
+        start(&mut vm);
+        let mut steps = 1;
+        while vm.step() == Ok(Status::Paused) {
+            steps += 1;
+        }
+        assert_eq!(steps, vm.dispatch_count);
+
+        start(&mut vm);
+        let mut lines = vec![vm.current_line().unwrap()];
+        while vm.run_until(Event::Line) == Ok(Status::Paused) {
+            lines.push(vm.current_line().unwrap());
+        }
+        assert_eq!(lines, [1, 4, 5, 3, 5, 6]);
+
+        // The arguments are on the stack when the call starts
+        start(&mut vm);
+        assert_eq!(vm.run_until(Event::Call), Ok(Status::Paused));
+        assert_eq!(vm.current_line(), Some(3));
+        let stack = vm.stack_values();
+        assert_eq!(
+            stack[stack.len() - 2..],
+            [Value::Number(1.0), Value::Number(2.0)]
+        );
+        assert_eq!(vm.run_until(Event::Return), Ok(Status::Paused));
+        assert_eq!(vm.stack_values().last(), Some(&Value::Number(3.0)));
+        assert_eq!(vm.run_until(Event::Breakpoint(6)), Ok(Status::Paused));
+        let b = vm.get_string("b");
+        assert_eq!(vm.mem.globals.get(b), Some(Value::Number(3.0)));
+        assert_eq!(
+            vm.run_until(Event::Breakpoint(6)),
+            Ok(Status::Finished(Value::Nil))
+        );
+
+        // Natives calling back into the VM run to completion in a single step
+        let mut vm = VM::new();
+        vm.register_native("twice", 1, |vm, args| {
+            let first = vm
+                .call_value_with_args(args[0], &[Value::Number(1.0)])
+                .map_err(|err| format!("{err:?}"))?;
+            vm.call_value_with_args(args[0], &[first])
+                .map_err(|err| format!("{err:?}"))
+        });
+        let src = "fun double(x) { return x * 2; }\nvar result = twice(double);";
+        crate::compile(&mut vm, src).unwrap().start();
+        assert_eq!(vm.run_until(Event::Call), Ok(Status::Finished(Value::Nil)));
+        let result = vm.get_string("result");
+        assert_eq!(vm.mem.globals.get(result), Some(Value::Number(4.0)));
+    }
+
+    #[test]
+    fn debug_info() {
+        let src = "fun f(a) {
+    var b = a;
+    {
+        var c = b;
+    }
+    var d = a;
+}";
+        for optimize in [false, true] {
+            let mut vm = VM::with_options(VmOptions {
+                optimize,
+                ..VmOptions::default()
+            });
+            let script = crate::compile_with(&mut vm, src, false).unwrap();
+            let f = script.chunk.constants[1].as_fn().unwrap();
+            let chunk = &f.as_ref().chunk;
+            let locals: Vec<_> = chunk
+                .debug
+                .locals
+                .iter()
+                .map(|local| (local.name.as_str(), local.slot))
+                .collect();
+            assert_eq!(locals, [("a", 1), ("b", 2), ("c", 3), ("d", 3)]);
+
+            // `c` and `d` share a slot but are never in scope at the same time
+            let names_at = |offset| {
+                let names: Vec<_> = chunk
+                    .debug
+                    .locals_at(offset)
+                    .map(|local| local.name.as_str())
+                    .collect();
+                names
+            };
+            assert_eq!(names_at(0), ["a"]);
+            let c = &chunk.debug.locals[2];
+            assert_eq!(names_at(c.start as usize), ["a", "b", "c"]);
+            assert_eq!(names_at(chunk.len() - 1), ["a", "b", "d"]);
+            assert!(chunk
+                .debug
+                .locals
+                .iter()
+                .all(|local| local.end as usize == chunk.len() || local.name == "c"));
+            assert_eq!(chunk.debug.get_column(0), 13);
+        }
+    }
+
+    #[test]
+    fn profile() {
+        let src = "fun fib(n) {
+  if (n < 2) return n; return fib(n - 1) + fib(n - 2);
+}
+var result = fib(10);";
+        let mut vm = VM::with_options(VmOptions {
+            profile: true,
+            ..VmOptions::default()
+        });
+        interpret(&mut vm, src).unwrap();
+        let profile = vm.profile.as_mut().unwrap();
+        profile.finish();
+
+        let functions = profile.functions();
+        let fib = functions
+            .iter()
+            .find(|function| function.name.as_deref() == Some("fib"))
+            .unwrap();
+        assert_eq!((fib.calls, fib.line), (177, 1));
+        let script = functions.iter().find(|function| function.name.is_none());
+        assert_eq!(script.unwrap().calls, 1);
+
+        let opcodes = profile.opcodes();
+        assert!(opcodes.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+        let calls = opcodes.iter().find(|(opcode, _)| *opcode == Opcode::Call);
+        assert_eq!(calls.unwrap().1, 177);
+        let report = profile.to_string();
+        assert!(report.contains("fib() [line 1]"), "{report}");
+        assert!(report.contains("  Call\n"), "{report}");
+    }
+
+    #[test]
+    fn coverage() {
+        let src = "fun sign(n) {
+  if (n < 0) {
+    return -1;
+  }
+  return 1;
+}
+fun unused() {
+  print 1;
+}
+for (var i = 0; i < 3; i = i + 1) sign(i);";
+        let mut vm = VM::with_options(VmOptions {
+            coverage: true,
+            ..VmOptions::default()
+        });
+        interpret(&mut vm, src).unwrap();
+        let coverage = vm.coverage.as_ref().unwrap();
+
+        assert_eq!(coverage.files(), ["<script>"]);
+        let lines = coverage.lines("<script>");
+        assert_eq!(lines[&2], 3);
+        assert_eq!(lines[&3], 0);
+        assert_eq!(lines[&5], 3);
+        assert_eq!(lines[&8], 0);
+        assert_eq!(lines[&10], 4);
+        let sign = coverage
+            .functions()
+            .iter()
+            .find(|f| f.name.as_deref() == Some("sign"));
+        assert_eq!(sign.unwrap().branches[0].taken, [0, 3]);
+
+        let lcov = coverage.lcov();
+        assert!(lcov.starts_with("TN:\nSF:<script>\n"), "{lcov}");
+        assert!(lcov.contains("FN:1,sign\nFN:7,unused\n"), "{lcov}");
+        assert!(lcov.contains("BRDA:10,0,0,3\nBRDA:10,0,1,1\n"), "{lcov}");
+        assert!(
+            lcov.contains("FNDA:3,sign\nFNDA:0,unused\nFNF:2\nFNH:1\n"),
+            "{lcov}"
+        );
+        assert!(lcov.contains("BRDA:2,1,0,0\nBRDA:2,1,1,3\n"), "{lcov}");
+        assert!(lcov.contains("DA:3,0\n"), "{lcov}");
+        assert!(lcov.ends_with("end_of_record\n"), "{lcov}");
+
+        let annotated = coverage.annotate("<script>", src);
+        let annotated: Vec<_> = annotated.lines().collect();
+        assert_eq!(annotated[1], "        3*    2:   if (n < 0) {");
+        assert_eq!(annotated[2], "    #####     3:     return -1;");
+        assert_eq!(annotated[6], "        -     7: fun unused() {");
+
+        // Nothing runs past the last line of a file that ends with a newline
+        let mut vm = VM::with_options(VmOptions {
+            coverage: true,
+            ..VmOptions::default()
+        });
+        interpret(&mut vm, &format!("{src}\n")).unwrap();
+        let coverage = vm.coverage.as_ref().unwrap();
+        assert_eq!(coverage.lines("<script>").keys().last(), Some(&10));
+        assert_eq!(coverage.summary(), (6, 8));
+    }
+
+    #[test]
+    fn coverage_of_logical_operators() {
+        let src = "fun pick(a, b) {
+  return a or b;
+}
+fun both(a, b) {
+  return a and b;
+}
+pick(1, 2);
+pick(nil, 2);
+pick(false, 3);
+both(true, 1);";
+        let mut vm = VM::with_options(VmOptions {
+            coverage: true,
+            ..VmOptions::default()
+        });
+        interpret(&mut vm, src).unwrap();
+        let coverage = vm.coverage.as_ref().unwrap();
+
+        let branches = |name| {
+            let function = coverage
+                .functions()
+                .iter()
+                .find(|f| f.name.as_deref() == Some(name))
+                .unwrap();
+            function.branches[0].taken
+        };
+        assert_eq!(branches("pick"), [1, 2]);
+        assert_eq!(branches("both"), [1, 0]);
+
+        let lcov = coverage.lcov();
+        assert!(lcov.contains("BRDA:2,0,0,1\nBRDA:2,0,1,2\n"), "{lcov}");
+        assert!(lcov.contains("BRDA:5,1,0,1\nBRDA:5,1,1,0\n"), "{lcov}");
+    }
+
+    #[test]
+    fn heap_stats() {
+        let mut vm = VM::new();
+        let before = vm.heap_stats();
+        assert_eq!(before.gc_cycles, 0);
+        assert_eq!(before.count(ObjKind::Closure), 0);
+
+        let src = "fun counter() { var n = 0; fun next() { n = n + 1; return n; } return next; }
+var a = counter();
+var b = counter();
+a();";
+        interpret(&mut vm, src).unwrap();
+        let stats = vm.heap_stats();
+        // The script, `counter` and two `next`s, which captured an upvalue each
+        assert!(stats.count(ObjKind::Closure) >= 3);
+        assert_eq!(stats.count(ObjKind::Upvalue), 2);
+        assert!(stats.count(ObjKind::Fn) >= 3);
+        assert!(stats.count(ObjKind::Str) > before.count(ObjKind::Str));
+        assert!(stats.bytes_allocated > before.bytes_allocated);
+        assert!(stats.peak_bytes >= stats.bytes_allocated);
+
+        vm.collect_garbage();
+        let collected = vm.heap_stats();
+        // The script may have collected already, e.g. with `always_gc`
+        assert_eq!(collected.gc_cycles, stats.gc_cycles + 1);
+        assert_eq!(collected.peak_bytes, stats.peak_bytes);
+        assert!(collected.bytes_allocated <= stats.bytes_allocated);
+        let report = collected.to_string();
+        assert!(report.contains("           2 upvalues\n"), "{report}");
+        let collections = format!("{:>12} collections\n", collected.gc_cycles);
+        assert!(report.contains(&collections), "{report}");
+    }
+
+    #[test]
+    fn captured_output() {
+        let stdout = OutputBuffer::default();
+        let stderr = OutputBuffer::default();
+        let mut engine = Loxide::with_options(VmOptions {
+            stdout: Box::new(stdout.clone()),
+            stderr: Box::new(stderr.clone()),
+            ..VmOptions::default()
+        });
+        engine.eval("print 1 + 2; print \"a\" + \"b\";").unwrap();
+        assert_eq!(stdout.contents(), "3\nab\n");
+        assert_eq!(stderr.contents(), "");
+
+        engine.eval("eprint(\"oops\"); print nil + 1;").unwrap_err();
+        let errors = stderr.contents();
+        assert!(errors.starts_with("oops\nOperands must be"), "{errors}");
+        assert!(errors.contains("[line 1] in script"), "{errors}");
+
+        stdout.clear();
+        stderr.clear();
+        engine.eval("print;").unwrap_err();
+        assert_eq!(stdout.contents(), "");
+        let errors = stderr.contents();
+        assert!(errors.starts_with("[line 1:6] Error"), "{errors}");
+
+        // Swapping the writer of a running VM
+        let other = OutputBuffer::default();
+        engine.vm.stdout = Box::new(other.clone());
+        engine.eval("print true;").unwrap();
+        assert_eq!(
+            (stdout.contents(), other.contents()),
+            ("".into(), "true\n".into())
+        );
+    }
+
+    #[test]
+    fn ffi() {
+        use std::ffi::{c_int, c_void, CStr};
+
+        use crate::ffi::*;
+
+        unsafe extern "C" fn sum(
+            calls: *mut c_void,
+            args: *const LoxideValue,
+            arg_count: usize,
+            result: *mut LoxideValue,
+        ) -> c_int {
+            unsafe {
+                *calls.cast::<u32>() += 1;
+                let args = std::slice::from_raw_parts(args, arg_count);
+                if let Some(arg) = args.iter().find(|arg| arg.kind != LoxideValueKind::Number) {
+                    let message = "Expected numbers.";
+                    (*result).kind = LoxideValueKind::String;
+                    (*result).string = message.as_ptr().cast();
+                    (*result).len = message.len();
+                    assert!(
+                        arg.kind == LoxideValueKind::String || arg.kind == LoxideValueKind::Object
+                    );
+                    return 1;
+                }
+                (*result).kind = LoxideValueKind::Number;
+                (*result).number = args.iter().map(|arg| arg.number).sum();
+                0
+            }
+        }
+
+        unsafe fn string(value: &LoxideValue) -> &str {
+            let bytes = unsafe { std::slice::from_raw_parts(value.string.cast(), value.len) };
+            std::str::from_utf8(bytes).unwrap()
+        }
+
+        unsafe {
+            let vm = loxide_new();
+            let mut calls = 0u32;
+            let registered = loxide_register_native(
+                vm,
+                b"sum\0".as_ptr().cast(),
+                -1,
+                sum,
+                (&mut calls as *mut u32).cast(),
+            );
+            assert!(registered);
+
+            let mut result = std::mem::zeroed::<LoxideValue>();
+            let status = loxide_eval(vm, b"var x = sum(1, 2, 3);\0".as_ptr().cast(), &mut result);
+            assert_eq!(status, LOXIDE_OK);
+            assert_eq!(result.kind, LoxideValueKind::Nil);
+            assert!(loxide_get_global(vm, b"x\0".as_ptr().cast(), &mut result));
+            assert_eq!((result.kind, result.number), (LoxideValueKind::Number, 6.0));
+            assert!(!loxide_get_global(vm, b"y\0".as_ptr().cast(), &mut result));
+
+            let status = loxide_eval(vm, b"\"a\" + \"b\"\0".as_ptr().cast(), &mut result);
+            assert_eq!(status, LOXIDE_OK);
+            assert_eq!(result.kind, LoxideValueKind::String);
+            assert_eq!(string(&result), "ab");
+
+            let status = loxide_eval(vm, b"fun f() {} f\0".as_ptr().cast(), &mut result);
+            assert_eq!(status, LOXIDE_OK);
+            assert_eq!(result.kind, LoxideValueKind::Object);
+            assert_eq!(string(&result), "<fn f>");
+
+            let status = loxide_eval(vm, b"len\0".as_ptr().cast(), &mut result);
+            assert_eq!(status, LOXIDE_OK);
+            assert_eq!(string(&result), "<native fn len>");
+
+            let status = loxide_eval(vm, b"sum(1, f);\0".as_ptr().cast(), std::ptr::null_mut());
+            assert_eq!(status, LOXIDE_RUNTIME_ERROR);
+            let error = CStr::from_ptr(loxide_error(vm)).to_str().unwrap();
+            assert!(error.starts_with("Expected numbers."), "{error}");
+            assert_eq!(calls, 2);
+
+            let status = loxide_eval(vm, b"var;\0".as_ptr().cast(), &mut result);
+            assert_eq!(status, LOXIDE_COMPILE_ERROR);
+            assert!(!CStr::from_ptr(loxide_error(vm)).to_bytes().is_empty());
+
+            loxide_free(vm);
+        }
+    }
+
+    #[test]
+    fn fuzz_entry_points() {
+        assert_eq!(crate::run_str("print 1 + 2;").unwrap(), "3\n");
+        assert!(crate::compile_str("fun f() { return 1; }").is_ok());
+        // Used to read past the end of the source
+        for src in ["print 1;/", "1.", "\"${"] {
+            assert!(matches!(
+                crate::compile_str(src),
+                Err(InterpretError::CompileError(_))
+            ));
+        }
+        assert_eq!(
+            crate::run_str("while (true) {}"),
+            Err(InterpretError::OutOfFuel)
+        );
+        assert!(crate::run_str("readFile(\"Cargo.toml\");").is_err());
+        assert!(crate::run_str("sleep(1000);").is_err());
+    }
+
+    #[test]
+    fn vm_on_another_thread() {
+        use crate::pool::{ScriptPool, Snapshot};
+
+        let output = OutputBuffer::default();
+        let mut engine = Loxide::with_options(VmOptions {
+            stdout: Box::new(output.clone()),
+            ..VmOptions::default()
+        });
+        engine.register_native("double", 1, |_vm, args| {
+            Ok(Value::Number(args[0].as_number().unwrap() * 2.0))
+        });
+        engine.eval("var list = [1, \"a\"];").unwrap();
+        let mut engine = std::thread::spawn(move || {
+            engine.eval("print double(21);").unwrap();
+            engine
+        })
+        .join()
+        .unwrap();
+        assert_eq!(output.contents(), "42\n");
+        // Printing a list that contains itself doesn't end, so the cycle is made here
+        let list = engine.get_global("list").unwrap();
+        let list = engine.vm.mem.resolve(list).unwrap();
+        list.as_list().unwrap().items.push(list);
+        assert_eq!(
+            Snapshot::of(list),
+            Snapshot::List(vec![
+                Snapshot::Number(1.0),
+                Snapshot::String("a".into()),
+                Snapshot::Object("[...]".into()),
+            ])
+        );
+
+        let pool = ScriptPool::new(2);
+        let handles: Vec<_> = [
+            "var m = {}; m[\"x\"] = double(2); m",
+            "fun f() {} f",
+            "nil + 1",
+            "var l = nil; for (var i = 0; i < 10000; i = i + 1) l = [l]; l",
+        ]
+        .into_iter()
+        .map(|src| {
+            let mut engine = Loxide::with_options(VmOptions {
+                stderr: Box::new(std::io::sink()),
+                ..VmOptions::default()
+            });
+            engine.register_native("double", 1, |_vm, args| {
+                Ok(Value::Number(args[0].as_number().unwrap() * 2.0))
+            });
+            pool.spawn_script(engine, src)
+        })
+        .collect();
+        let results: Vec<_> = handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect();
+        assert_eq!(
+            results[0],
+            Ok(Snapshot::Map(vec![(
+                Snapshot::String("x".into()),
+                Snapshot::Number(4.0)
+            )]))
+        );
+        assert_eq!(results[1], Ok(Snapshot::Object("<fn f>".into())));
+        assert!(matches!(results[2], Err(InterpretError::RuntimeError(_))));
+        // Cut off at the maximum depth
+        let mut deep = results[3].as_ref().unwrap();
+        let mut depth = 0;
+        while let Snapshot::List(items) = deep {
+            deep = &items[0];
+            depth += 1;
+        }
+        assert_eq!(depth, crate::value::display::MAX_DEPTH);
+        assert_eq!(deep, &Snapshot::Object("[...]".into()));
+
+        // Dropping one the host nested deeper doesn't overflow the stack either
+        let mut deep = Snapshot::Nil;
+        for _ in 0..1_000_000 {
+            deep = Snapshot::List(vec![deep]);
+        }
+        drop(deep);
+    }
+
+    #[test]
+    fn channels_between_vms() {
+        use crate::pool::{Channel, Snapshot};
+
+        let (jobs, results) = (Channel::new(), Channel::new());
+        let mut worker = Loxide::new();
+        worker
+            .define_channel("jobs", &jobs)
+            .define_channel("results", &results);
+        let worker = std::thread::spawn(move || {
+            // A task waits for the jobs while the other thread sends them
+            worker
+                .eval(
+                    r#"
+spawn(fun () {
+    var job = await jobs;
+    while (job != nil) {
+        push(job["items"], len(job["items"]));
+        send(results, job);
+        job = await jobs;
+    }
+    send(results, "done");
+});
+"#,
+                )
+                .unwrap();
+        });
+
+        let mut engine = Loxide::new();
+        engine
+            .define_channel("jobs", &jobs)
+            .define_channel("results", &results);
+        let mut eval = |src| eval_text(&mut engine, src);
+        assert_eq!(
+            eval("var items = [1, \"a\"]; var job = {}; job[\"items\"] = items; send(jobs, job); recv(results)[\"items\"]"),
+            "[1, \"a\", 2]"
+        );
+        // The worker changed its own copy
+        assert_eq!(eval("items"), "[1, \"a\"]");
+        // Channels can be sent through channels
+        assert_eq!(
+            eval("var reply = Channel(); job[\"items\"] = [reply]; send(jobs, job); var back = recv(results)[\"items\"][0]; send(back, 3); recv(reply)"),
+            "3"
+        );
+        assert_eq!(eval("send(jobs, nil); recv(results)"), "done");
+        worker.join().unwrap();
+
+        results.send(Snapshot::Number(1.0));
+        assert_eq!(results.try_recv(), Some(Snapshot::Number(1.0)));
+        assert_eq!(results.try_recv(), None);
+        for (src, message) in [
+            ("send(jobs, clock)", "Can't send a function to a channel."),
+            (
+                "recv(Channel())",
+                "The channel is empty and nothing else can send to it.",
+            ),
+            ("recv(1)", "Expected a channel but got a number."),
+            (
+                "var l = []; push(l, l); send(jobs, l)",
+                "Can't send a list that contains itself.",
+            ),
+            (
+                "var l = nil; for (var i = 0; i < 10000; i = i + 1) l = [l]; send(jobs, l)",
+                "Can't send a list nested more than 64 deep.",
+            ),
+        ] {
+            let Err(InterpretError::RuntimeError(err)) = engine.eval(src) else {
+                panic!("{src} should fail");
+            };
+            assert_eq!(err.message, message);
+        }
+    }
+
+    #[test]
+    fn string_pool() {
+        use std::sync::Arc;
+
+        use crate::mem::StringPool;
+
+        let pool = Arc::new(StringPool::new());
+        let options = || VmOptions {
+            string_pool: Some(pool.clone()),
+            ..VmOptions::default()
+        };
+        let mut first = Loxide::with_options(options());
+        // The names of the natives are in the pool now, other VMs reuse them
+        let natives = pool.len();
+        assert!(natives > 0);
+
+        let src = "var greeting = \"hello\"; var shout = greeting + \"!\";";
+        first.eval(src).unwrap();
+        let mut others: Vec<_> = (0..4)
+            .map(|_| {
+                let mut engine = Loxide::with_options(options());
+                std::thread::spawn(move || {
+                    engine.eval(src).unwrap();
+                    engine
+                })
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect();
+        // The constants and global names, but not "hello!" which is made at runtime
+        assert_eq!(pool.len(), natives + 4);
+        let substrings = "for (var i = 0; i < 8; i = i + 1) substring(\"runtime\", 0, i);";
+        first.eval(substrings).unwrap();
+        // Only "runtime", none of the substrings
+        assert_eq!(pool.len(), natives + 5);
+
+        let chars = |engine: &mut Loxide, name| {
+            let value = engine.get_global(name).unwrap();
+            let value = engine.vm.mem.resolve(value).unwrap();
+            value.as_obj_str().unwrap().chars
+        };
+        let hello = chars(&mut first, "greeting");
+        for other in &mut others {
+            assert_eq!(chars(other, "greeting"), hello);
+            assert_ne!(chars(other, "shout"), chars(&mut first, "shout"));
+            assert_eq!(eval_str(other, "shout").as_deref(), Some("hello!"));
+        }
+
+        // Strings of a VM without the pool are its own
+        let mut alone = Loxide::new();
+        alone.eval(src).unwrap();
+        assert_ne!(chars(&mut alone, "greeting"), hello);
+
+        // The pool outlives the VMs using it, or the other way around
+        drop(first);
+        drop(others);
+        assert_eq!(pool.len(), natives + 5);
+        let mut last = Loxide::with_options(options());
+        drop(pool);
+        assert_eq!(
+            eval_str(&mut last, "\"hello\" + \" again\"").as_deref(),
+            Some("hello again")
+        );
+    }
+
+    #[test]
+    fn imports() {
+        let dir = std::env::temp_dir().join(format!("loxide-imports-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        let files = [
+            (
+                "lib.lox",
+                "print \"loading\"; var x = 1; fun double(n) { return n * 2; } fun setx(n) { x = n; }",
+            ),
+            ("one.lox", "fun helper() { return \"one\"; } fun name() { return helper(); }"),
+            ("two.lox", "fun helper() { return len(\"two\"); } fun name() { return helper(); }"),
+            (
+                "sub/util.lox",
+                "import helper from \"helper.lox\"; var y = helper.z;",
+            ),
+            ("sub/helper.lox", "var z = 5;"),
+            ("a.lox", "import \"b.lox\";"),
+            ("b.lox", "import \"a.lox\";"),
+            ("bad.lox", "var = ;"),
+        ];
+        for (name, src) in files {
+            std::fs::write(dir.join(name), src).unwrap();
+        }
+
+        let stdout = OutputBuffer::default();
+        let mut engine = Loxide::with_options(VmOptions {
+            stdout: Box::new(stdout.clone()),
+            stderr: Box::new(std::io::sink()),
+            ..VmOptions::default()
+        });
+        engine.vm.script_path = Some(dir.join("main.lox"));
+
+        let src = "var x = \"main\"; import lib from \"lib.lox\"; import again from \"./lib.lox\"; import \"lib.lox\";";
+        engine.eval(src).unwrap();
+        // Runs once, later imports get the cached module
+        assert_eq!(stdout.contents(), "loading\n");
+        assert_eq!(engine.eval("again == lib").unwrap(), Value::Bool(true));
+        assert_eq!(
+            engine.eval("lib.double(lib.x + 20)").unwrap(),
+            Value::Number(42.0)
+        );
+        assert_eq!(
+            engine
+                .eval("import util from \"sub/util.lox\"; util.y")
+                .unwrap(),
+            Value::Number(5.0)
+        );
+        assert_eq!(
+            runtime_message(engine.eval("lib.y;")),
+            "Module \"lib.lox\" has no export 'y'."
+        );
+        // Every module has its own globals, which its exports are
+        assert_eq!(
+            engine.eval("lib.setx(5); lib.x").unwrap(),
+            Value::Number(5.0)
+        );
+        assert_eq!(eval_str(&mut engine, "x").as_deref(), Some("main"));
+        let src = "import one from \"one.lox\"; import two from \"two.lox\"; one.name()";
+        assert_eq!(eval_str(&mut engine, src).as_deref(), Some("one"));
+        // Natives are found in the VM's globals
+        assert_eq!(engine.eval("two.name()").unwrap(), Value::Number(3.0));
+
+        assert_eq!(
+            runtime_message(engine.eval("import \"a.lox\";")),
+            "Circular import: \"a.lox\" -> \"b.lox\" -> \"a.lox\"."
+        );
+        assert_eq!(
+            runtime_message(engine.eval("import \"missing.lox\";")),
+            "Could not find module \"missing.lox\"."
+        );
+        assert_eq!(
+            runtime_message(engine.eval("import \"bad.lox\";")),
+            "Could not compile module \"bad.lox\"."
+        );
+        // The failed imports are forgotten
+        assert_eq!(
+            engine.eval("import lib from \"lib.lox\"; lib.x").unwrap(),
+            Value::Number(5.0)
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let mut engine = Loxide::with_options(VmOptions {
+            io: false,
+            stderr: Box::new(std::io::sink()),
+            ..VmOptions::default()
+        });
+        assert_eq!(
+            runtime_message(engine.eval("import \"lib.lox\";")),
+            "Imports are disabled."
+        );
+
+        for (src, error) in [
+            ("import foo;", "Expect 'from' after module name."),
+            ("import x from;", "Expect module path."),
+        ] {
+            match crate::compile_str(src) {
+                Err(InterpretError::CompileError(errors)) => assert_eq!(errors[0].message, error),
+                other => panic!("expected a compile error, got {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn module_search_paths() {
+        let dir = std::env::temp_dir().join(format!("loxide-lox-path-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("app")).unwrap();
+        std::fs::create_dir_all(dir.join("lib")).unwrap();
+        let files = [
+            ("app/strings.lox", "var from = \"app\";"),
+            ("lib/strings.lox", "var from = \"lib\";"),
+            ("lib/other.lox", "var from = \"other\";"),
+        ];
+        for (name, src) in files {
+            std::fs::write(dir.join(name), src).unwrap();
+        }
+
+        let mut engine = Loxide::with_options(VmOptions {
+            module_paths: vec![dir.join("lib")],
+            stderr: Box::new(std::io::sink()),
+            ..VmOptions::default()
+        });
+        engine.vm.script_path = Some(dir.join("app/main.lox"));
+        let mut from = |src: &str| -> crate::InterpretResult<String> {
+            let value = engine.eval(src)?;
+            Ok(engine
+                .vm
+                .value_ref(value)
+                .unwrap()
+                .as_str()
+                .unwrap()
+                .to_string())
+        };
+
+        // Next to the script first, then the search path
+        assert_eq!(
+            from("import m from \"strings.lox\"; m.from").unwrap(),
+            "app"
+        );
+        assert_eq!(
+            from("import m from \"other.lox\"; m.from").unwrap(),
+            "other"
+        );
+        assert_eq!(
+            from("import m from \"../lib/strings.lox\"; m.from").unwrap(),
+            "lib"
+        );
+        assert!(from("import m from \"./other.lox\";").is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // From memory, without access to files
+        let modules = std::collections::HashMap::from([
+            (
+                "a".to_string(),
+                "import b from \"b\"; var x = b.y + 1;".to_string(),
+            ),
+            ("b".to_string(), "var y = 1;".to_string()),
+        ]);
+        let mut engine = Loxide::with_options(VmOptions {
+            io: false,
+            module_resolver: Some(Box::new(modules)),
+            ..VmOptions::default()
+        });
+        assert_eq!(
+            engine.eval("import a from \"a\"; a.x").unwrap(),
+            Value::Number(2.0)
+        );
+        assert!(engine.eval("import \"c\";").is_err());
+    }
+
+    #[test]
+    fn native_modules() {
+        use std::ffi::{c_int, c_void};
+
+        use crate::{
+            ffi::{LoxideNativeFn, LoxideValue, LoxideValueKind},
+            module::native::{natives, LoxideRegisterFn},
+        };
+
+        unsafe extern "C" fn twice(
+            _user_data: *mut c_void,
+            args: *const LoxideValue,
+            _arg_count: usize,
+            result: *mut LoxideValue,
+        ) -> c_int {
+            unsafe {
+                *result = LoxideValue {
+                    kind: LoxideValueKind::Number,
+                    number: (*args).number * 2.0,
+                    ..*args
+                };
+            }
+            0
+        }
+        unsafe extern "C" fn init(module: *mut c_void, register: LoxideRegisterFn) -> c_int {
+            let twice: LoxideNativeFn = twice;
+            unsafe {
+                assert!(!register(
+                    module,
+                    "bad\0".as_ptr().cast(),
+                    300,
+                    twice,
+                    std::ptr::null_mut()
+                ));
+                assert!(register(
+                    module,
+                    "twice\0".as_ptr().cast(),
+                    1,
+                    twice,
+                    std::ptr::null_mut()
+                ));
+            }
+            0
+        }
+        unsafe extern "C" fn failing(_module: *mut c_void, _register: LoxideRegisterFn) -> c_int {
+            7
+        }
+
+        let registered = unsafe { natives(init) }.unwrap();
+        assert_eq!(registered.len(), 1);
+        let mut engine = Loxide::new();
+        for native in registered {
+            engine.register_native(&native.name, native.arity, native.function);
+        }
+        assert_eq!(engine.eval("twice(21)").unwrap(), Value::Number(42.0));
+        assert!(unsafe { natives(failing) }.is_err());
+
+        let error = |engine: &mut Loxide, src| match engine.eval(src) {
+            Err(InterpretError::RuntimeError(error)) => error.message,
+            other => panic!("expected a runtime error, got {other:?}"),
+        };
+        engine.vm.stderr = Box::new(std::io::sink());
+        assert_eq!(
+            error(&mut engine, "import native \"json\";"),
+            "Native modules are disabled."
+        );
+        engine.vm.native_modules = true;
+        assert_eq!(
+            error(&mut engine, "import json from native \"json\";"),
+            "Could not find native module \"json\"."
+        );
+    }
+
+    #[test]
+    fn accessors_and_fields() {
+        let mut engine = Loxide::with_options(VmOptions {
+            stderr: Box::new(std::io::sink()),
+            ..VmOptions::default()
+        });
+        let src = r#"
+class Temperature {
+    var celsius = 10 * 2;
+    var unit;
+    get fahrenheit { return this.celsius * 9 / 5 + 32; }
+    set fahrenheit(value) { this.celsius = (value - 32) * 5 / 9; }
+}
+class Reading < Temperature {
+    init(place) { this.place = place; this.start = this.fahrenheit; }
+}
+var t = Temperature();
+var before = t.fahrenheit;
+var assigned = (t.fahrenheit = 212);
+var r = Reading("here");
+"#;
+        engine.eval(src).unwrap();
+        let mut eval = |src| engine.eval(src).unwrap();
+        assert_eq!(eval("before"), Value::Number(68.0));
+        assert_eq!(eval("assigned"), Value::Number(212.0));
+        assert_eq!(eval("t.celsius"), Value::Number(100.0));
+        assert_eq!(eval("t.unit"), Value::Nil);
+        // Each instance starts from the initializers
+        assert_eq!(eval("r.start"), Value::Number(68.0));
+        assert_eq!(eval("r.celsius"), Value::Number(20.0));
+        // Without a setter, assigning sets a field, which shadows the getter
+        let src = "class G { get x { return 1; } } var g = G(); var x = g.x; g.x = 2; [x, g.x]";
+        assert_eq!(eval_text(&mut engine, src), "[1, 2]");
+
+        let message = match engine.eval("t.fahrenheit();") {
+            Err(InterpretError::RuntimeError(error)) => error.message,
+            other => panic!("expected a runtime error, got {other:?}"),
+        };
+        assert!(
+            message.starts_with("Can't invoke getter 'fahrenheit'"),
+            "{message}"
+        );
+        for src in [
+            "class A { set x() {} }",
+            "class A { set x(a, b) {} }",
+            "class A { set x(v) { return v; } }",
+            "class A { var x = 1 }",
+        ] {
+            assert!(
+                matches!(engine.eval(src), Err(InterpretError::CompileError(_))),
+                "{src}"
+            );
+        }
+    }
+
+    #[test]
+    fn field_initializers_run_per_instance() {
+        let mut engine = Loxide::with_options(VmOptions {
+            stderr: Box::new(std::io::sink()),
+            ..VmOptions::default()
+        });
+        let src = r##"
+var made = 0;
+class Bag {
+    var items = [];
+    var owner = this;
+    var size = len(this.items);
+    var id = made = made + 1;
+}
+class Tagged < Bag {
+    var tag = "#" + toString(this.id);
+    var size = 10;
+}
+var a = Bag();
+var b = Bag();
+push(a.items, 1);
+var t = Tagged();
+"##;
+        engine.eval(src).unwrap();
+        let mut eval = |src| eval_text(&mut engine, src);
+        // Each instance gets its own list
+        assert_eq!(eval("[a.items, b.items]"), "[[1], []]");
+        assert_eq!(eval("a.owner == a and b.owner == b"), "true");
+        assert_eq!(eval("[a.size, a.id, b.id, made]"), "[0, 1, 2, 3]");
+        // Inherited initializers run first, redeclared fields replace them
+        assert_eq!(eval("[t.tag, t.size, t.items]"), "[\"#3\", 10, []]");
+
+        for src in [
+            "class A { static var x = this; }",
+            "class A { static var x = fun () { return this; }; }",
+            "class A {} class B < A { static var x = super.x; }",
+        ] {
+            assert!(
+                matches!(engine.eval(src), Err(InterpretError::CompileError(_))),
+                "{src}"
+            );
+        }
+    }
+
+    #[test]
+    fn static_members() {
+        let mut engine = Loxide::with_options(VmOptions {
+            stderr: Box::new(std::io::sink()),
+            ..VmOptions::default()
+        });
+        let src = r#"
+class Counter {
+    static var count = 0;
+    static var make = fun () { return 7; };
+    static create() { this.count = this.count + 1; return this(); }
+    static() { return "method"; }
+}
+class Sub < Counter {}
+var a = Counter.create();
+Counter.create();
+var create = Counter.create;
+create();
+var sub = Sub.create();
+"#;
+        engine.eval(src).unwrap();
+        let mut eval = |src| eval_resolved(&mut engine, src);
+        assert_eq!(eval("Counter.count"), Value::Number(3.0));
+        // Inherited with the values they had, `this` is the subclass
+        assert_eq!(eval("Sub.count"), Value::Number(1.0));
+        assert_eq!(ValueWrapper(eval("sub")).to_string(), "Sub instance");
+        assert_eq!(eval("Counter.make()"), Value::Number(7.0));
+        assert_eq!(eval("a.static()").as_str(), Some("method"));
+
+        assert_eq!(
+            runtime_message(engine.eval("Counter.nope;")),
+            "Undefined static property 'nope' of class Counter."
+        );
+        assert_eq!(
+            runtime_message(engine.eval("a.create();")),
+            "Undefined property 'create'."
+        );
+    }
+
+    #[test]
+    fn operator_overloading() {
+        let mut engine = Loxide::with_options(VmOptions {
+            stderr: Box::new(std::io::sink()),
+            ..VmOptions::default()
+        });
+        let src = r#"
+class Vector {
+    init(x, y) { this.x = x; this.y = y; }
+    __add(other) { return Vector(this.x + other.x, this.y + other.y); }
+    __sub(other) { return Vector(this.x - other.x, this.y - other.y); }
+    __mul(k) { return Vector(this.x * k, this.y * k); }
+    __neg() { return Vector(-this.x, -this.y); }
+    __eq(other) { return this.x == other.x and this.y == other.y; }
+    __lt(other) { return this.x < other.x; }
+    __getitem(i) { if (i == 0) return this.x; return this.y; }
+    __setitem(i, value) { if (i == 0) this.x = value; else this.y = value; return value; }
+    toString() { return "(${this.x}, ${this.y})"; }
+}
+var a = Vector(1, 2);
+var b = Vector(3, 5);
+"#;
+        engine.eval(src).unwrap();
+        let mut eval = |src| eval_resolved(&mut engine, src);
+        assert_eq!(eval("\"${a + b}\"").as_str(), Some("(4, 7)"));
+        assert_eq!(eval("\"${b - a * 2}\"").as_str(), Some("(1, 1)"));
+        assert_eq!(eval("\"${-a}\"").as_str(), Some("(-1, -2)"));
+        assert_eq!(eval("a == Vector(1, 2)"), Value::Bool(true));
+        assert_eq!(eval("a != b"), Value::Bool(true));
+        assert_eq!(eval("a < b"), Value::Bool(true));
+        assert_eq!(eval("a >= b"), Value::Bool(false));
+        assert_eq!(eval("a[1] = 10; a[0] + a[1]"), Value::Number(11.0));
+
+        // `v < limit` is fused into `LessLocalJumpIfFalse` by the optimizer
+        let mut optimized = Loxide::with_options(VmOptions {
+            optimize: true,
+            ..VmOptions::default()
+        });
+        optimized.eval(src).unwrap();
+        let count = r#"
+fun count(limit) {
+    var n = 0;
+    var v = Vector(0, 0);
+    while (v < limit) { v = v + Vector(1, 0); n = n + 1; }
+    return n;
+}
+count(Vector(3, 0))
+"#;
+        assert_eq!(optimized.eval(count), Ok(Value::Number(3.0)));
+
+        // Only the left operand is looked at
+        assert_eq!(
+            runtime_message(engine.eval("2 * a;")),
+            "Operands must be numbers."
+        );
+        assert_eq!(
+            runtime_message(engine.eval("a / 2;")),
+            "Operands must be numbers."
+        );
+        assert_eq!(
+            runtime_message(engine.eval("2 + a;")),
+            "Operands must be two numbers or two strings."
+        );
+    }
+
+    #[test]
+    fn to_string_protocol() {
+        let stdout = OutputBuffer::default();
+        let mut engine = Loxide::with_options(VmOptions {
+            stdout: Box::new(stdout.clone()),
+            stderr: Box::new(std::io::sink()),
+            ..VmOptions::default()
+        });
+        let src = r#"
+class Point {
+    init(x, y) { this.x = x; this.y = y; }
+    toString() { return "(" + this.x + ", " + this.y + ")"; }
+}
+class Id { toString() { return 42; } }
+class Plain {}
+var p = Point(1, 2.5);
+print p;
+print "p = " + p;
+print p + "!";
+print "${p} and ${Id()}";
+print "plain " + Plain();
+print [1, "a", nil] + " " + true;
+print toString(p) + " " + toString(Id()) + " " + toString(Plain());
+"#;
+        engine.eval(src).unwrap();
+        assert_eq!(
+            stdout.contents(),
+            "(1, 2.5)\np = (1, 2.5)\n(1, 2.5)!\n(1, 2.5) and 42\nplain Plain instance\n[1, \"a\", nil] true\n(1, 2.5) 42 Plain instance\n"
+        );
+
+        // Errors in `toString()` surface where the string was needed
+        engine
+            .eval("class Broken { toString() { return nil + 1; } }")
+            .unwrap();
+        assert_eq!(
+            runtime_message(engine.eval("print \"b\" + Broken();")),
+            "Operands must be two numbers or two strings."
+        );
+        assert_eq!(
+            runtime_message(engine.eval("toString(Broken());")),
+            "Operands must be two numbers or two strings."
+        );
+        assert_eq!(
+            runtime_message(engine.eval("1 + nil;")),
+            "Operands must be two numbers or two strings."
+        );
+    }
+
+    #[test]
+    fn interned_strings_are_weak() {
+        let mut mem = Mem::new();
+        let kept = mem.copy_string("kept");
+        let dead = mem.copy_string("dead");
+        let strings = mem.interned_strings.len();
+        assert_eq!(mem.copy_string("dead").as_ptr(), dead.as_ptr());
+
+        let mut greystack = vec![];
+        Obj::mark(kept.upcast(), &mut greystack);
+        mem.collect_garbage(greystack);
+        assert_eq!(mem.interned_strings.len(), strings - 1);
+        let interned =
+            |mem: &Mem, s: &str| mem.interned_strings.find_string(s, ObjHash::hash_string(s));
+        assert!(interned(&mem, "dead").is_none());
+        assert_eq!(interned(&mem, "kept").unwrap().as_ptr(), kept.as_ptr());
+
+        // Interning it again makes a new string, which is interned like any other
+        let again = mem.copy_string("dead");
+        assert_eq!(again.as_str(), "dead");
+        assert_eq!(interned(&mem, "dead").unwrap().as_ptr(), again.as_ptr());
+        assert_eq!(mem.copy_string("dead").as_ptr(), again.as_ptr());
+        assert_eq!(mem.interned_strings.len(), strings);
+
+        // Strings built at runtime die with their last reference and compare equal when built
+        // again
+        let mut engine = Loxide::with_gc_config(GcConfig {
+            stress: true,
+            ..GcConfig::default()
+        });
+        let src = r#"
+var kept = "ba" + "gel";
+for (var i = 0; i < 3; i = i + 1) { var temp = "to" + "ast"; }
+"#;
+        engine.eval(src).unwrap();
+        engine.vm.collect_garbage();
+        assert!(interned(&engine.vm.mem, "bagel").is_some());
+        assert!(interned(&engine.vm.mem, "toast").is_none());
+        let src = r#"var toast = "to" + "ast"; toast == "toa" + "st" and kept == "bag" + "el""#;
+        assert_eq!(engine.eval(src), Ok(Value::Bool(true)));
+        assert!(interned(&engine.vm.mem, "toast").is_some());
+    }
+
+    #[test]
+    fn strings_are_one_allocation() {
+        let inline = |string: Gc<ObjString>| {
+            let header = string.as_ptr() as *const u8;
+            string.chars.as_ptr() as *const u8 == header.wrapping_add(size_of::<ObjString>())
+        };
+
+        let mut engine = Loxide::new();
+        engine
+            .eval(r#"var copied = "bagel"; var concat = copied + "s"; var empty = "" + "";"#)
+            .unwrap();
+        for name in ["copied", "concat", "empty"] {
+            let string = engine.get_global(name).unwrap();
+            let string = engine.vm.mem.resolve(string).unwrap().as_obj_str().unwrap();
+            assert!(inline(string), "{name}");
+        }
+        let concat = engine.get_global("concat").unwrap();
+        assert_eq!(
+            engine.vm.value_ref(concat).unwrap().as_str(),
+            Some("bagels")
+        );
+        // Concatenating into an interned string returns that one
+        assert_eq!(
+            engine.eval(r#"concat == "bagel" + "s""#),
+            Ok(Value::Bool(true))
+        );
+
+        // Pooled strings point into the pool instead
+        let mut mem = Mem::with_string_pool(GcConfig::default(), Some(Arc::new(StringPool::new())));
+        assert!(!inline(mem.copy_constant("bagel")));
+        // Unless they're made at runtime
+        assert!(inline(mem.copy_string("bagels")));
+    }
+
+    #[test]
+    fn string_concatenation_chains() {
+        let mut vm = VM::new();
+        let listing = crate::compile(&mut vm, r#"print "a" + "b" + "c";"#)
+            .unwrap()
+            .disassemble();
+        assert!(
+            listing.contains("Constant            0 'abc'\n"),
+            "{listing}"
+        );
+        assert!(
+            !listing.contains("Add") && !listing.contains("Concat"),
+            "{listing}"
+        );
+
+        let src = r#"print "x = " + x + ", y = " + "(" + y + ")" - 1;"#;
+        let listing = crate::compile(&mut vm, src).unwrap().disassemble();
+        assert!(listing.contains("', y = ('\n"), "{listing}");
+        assert!(listing.contains("Concat              5\n"), "{listing}");
+        assert!(!listing.contains("Add"), "{listing}");
+
+        let mut engine = Loxide::new();
+        let src = r#"
+var log = "";
+fun note(value) { log = log + value; return value; }
+class Point {
+    init(x, y) { this.x = x; this.y = y; }
+    toString() { note("s"); return "(" + this.x + ", " + this.y + ")"; }
+}
+var p = Point(1, 2);
+"#;
+        engine.eval(src).unwrap();
+        let mut eval = |src| eval_str(&mut engine, src);
+        assert_eq!(eval(r#""p = " + p + "!""#).as_deref(), Some("p = (1, 2)!"));
+        assert_eq!(eval(r#""" + 1 + 2"#).as_deref(), Some("12"));
+        assert_eq!(eval(r#"1 + 2 + "a""#).as_deref(), Some("3a"));
+        assert_eq!(eval(r#""a" + "bc"[1]"#).as_deref(), Some("ac"));
+        assert_eq!(
+            eval(r#""a" + ("b" + nil) + "b""#).as_deref(),
+            Some("abnilb")
+        );
+        assert_eq!(eval(r#""a${1}b" + p"#).as_deref(), Some("a1b(1, 2)"));
+        assert_eq!(eval(r#""" + """#).as_deref(), Some(""));
+        // Operands are converted as they're evaluated, like `Add` does
+        eval(r#"log = ""; "" + note("a") + p + note("b");"#);
+        assert_eq!(eval("log").as_deref(), Some("asb"));
+        assert!(matches!(
+            engine.eval(r#""a" + 1 - 1"#),
+            Err(InterpretError::RuntimeError(_))
+        ));
+
+        // More operands than fit in one instruction
+        let src = vec![r#""a" + n"#; 300].join(" + ");
+        let src = format!("var n = 1; {src}");
+        let expected = "a1".repeat(300);
+        assert_eq!(eval_str(&mut engine, &src), Some(expected));
+    }
+
+    #[test]
+    fn int_values() {
+        for int in [0, 1, -1, i32::MAX, i32::MIN] {
+            assert!(matches!(Value::Int(int).unpack(), Unpacked::Int(n) if n == int));
+            assert_eq!(Value::Int(int), Value::Number(int.into()));
+            assert_eq!(Value::Int(int).as_number(), Some(int.into()));
+        }
+        assert!(matches!(Value::number(3.0).unpack(), Unpacked::Int(3)));
+        assert!(matches!(Value::number(0.5).unpack(), Unpacked::Number(_)));
+        assert!(
+            matches!(Value::number(-0.0).unpack(), Unpacked::Number(n) if n.is_sign_negative())
+        );
+        assert!(matches!(Value::number(3e9).unpack(), Unpacked::Number(_)));
+        assert_eq!(format!("{:?}", Value::Int(3)), "Number(3.0)");
+        assert_eq!(ValueWrapper(Value::Int(3)).to_string(), "3");
+
+        let mut engine = Loxide::new();
+        let mut eval = |src| eval_resolved(&mut engine, src);
+        // Ints and floats holding the same number are the same value
+        assert_eq!(eval("1 == 1.0"), Value::Bool(true));
+        assert_eq!(eval("0.5 + 0.5 == 1"), Value::Bool(true));
+        assert_eq!(eval("2 < 2.5 and 2.5 < 3"), Value::Bool(true));
+        assert_eq!(eval("var m = {}; m[1] = \"a\"; m[1.0]").as_str(), Some("a"));
+        assert_eq!(eval("m[0.5 * 2] = \"b\"; len(m)"), Value::Int(1));
+        // Results that don't fit are promoted to floats
+        assert_eq!(eval("2147483647 + 1"), Value::Number(2147483648.0));
+        assert_eq!(eval("-2147483647 - 2"), Value::Number(-2147483649.0));
+        assert_eq!(eval("65536 * 65536"), Value::Number(4294967296.0));
+        assert_eq!(eval("2 ** 40"), Value::Number(1099511627776.0));
+        assert_eq!(eval("7 / 2"), Value::Number(3.5));
+        assert!(matches!(eval("6 / 2").unpack(), Unpacked::Int(3)));
+        // -0 stays a float, so dividing by it still gives negative infinity
+        assert_eq!(eval("1 / -0"), Value::Number(f64::NEG_INFINITY));
+        assert_eq!(eval("1 / (0 * -1)"), Value::Number(f64::NEG_INFINITY));
+        assert_eq!(eval("-7 % 3"), Value::Int(2));
+        assert_eq!(eval("7 % -3"), Value::Int(-2));
+        assert_eq!(eval("7.5 % 2"), Value::Number(1.5));
+        assert_eq!(eval("~5 | 1 & 3"), Value::Int(-5));
+        assert_eq!(eval("1 << 31"), Value::Int(i32::MIN));
+        assert_eq!(
+            eval("var n = 0; for (var i = 0; i < 10; i++) n = n + i; n"),
+            Value::Int(45)
+        );
+        assert_eq!(
+            eval("\"\" + 3 + 1.5 + 2147483648 == \"31.52.14748e+09\""),
+            Value::Bool(true)
+        );
+    }
+
+    #[test]
+    fn invoking_fields() {
+        let src = r#"
+        class Oops {
+            init() {
+                fun f() {
+                    return 420;
+                }
+
+                this.field = f;
+            }
+        }
+
+        var oops = Oops();
+        var result = oops.field();
+        "#;
+
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        let result_str = vm.get_string("result");
+        let value = vm.mem.globals.get(result_str);
+        assert_eq!(value, Some(Value::Number(420.0)));
+    }
+
+    #[test]
+    fn misusing_this() {
+        let src = r#"
+        fun notMethod() {
+            print this;
+        }
+        "#;
+
+        let mut vm = VM::new();
+        let err = interpret(&mut vm, src);
+        if let Err(InterpretError::CompileError(_)) = err {
+        } else {
+            panic!()
+        }
+    }
+
+    #[test]
+    fn nested_this() {
+        let src = r#"
+        class Nested {
+            method() {
+              fun function() {
+                return this.lol;
+              }
+              return function();
+            }
+        }
+          
+        var nested = Nested();
+        nested.lol = 420;
+        var result = nested.method();"#;
+
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        let result_str = vm.get_string("result");
+        let value = vm.mem.globals.get(result_str);
+        assert_eq!(value, Some(Value::Number(420.0)));
+    }
+
+    #[test]
+    fn this() {
+        let src = r#"
+        class Nested {
+            method() {
+              return this.lol;
+            }
+          }
+          
+        var nested = Nested();
+        nested.lol = 420;
+        var result = nested.method();"#;
+
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        let result_str = vm.get_string("result");
+        let value = vm.mem.globals.get(result_str);
+        assert_eq!(value, Some(Value::Number(420.0)));
+    }
+
+    #[test]
+    fn methods() {
+        let src = r#"
+        class Scone {
+            topping(first, second) {
+              return "scone with " + first + " and " + second;
+            }
+          }
+          
+          var scone = Scone();
+          var result = scone.topping("berries", "cream");"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        let result_str = vm.get_string("result");
+
+        let value = vm.mem.globals.get(result_str);
+
+        let expected_str = vm.get_string("scone with berries and cream");
+        println!("VAL: {:?}", value);
+        assert_eq!(value, Some(Value::Obj(expected_str.upcast())));
+    }
+
+    #[test]
+    fn initializer() {
+        let src = r#"
+class Point {
+    init(x, y) {
+        this.x = x;
+        this.y = y;
+    }
+}
+
+var point = Point(1, 2);
+var result = point.x + point.y;"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        let result_str = vm.get_string("result");
+        let value = vm.mem.globals.get(result_str);
+        assert_eq!(value, Some(Value::Number(3.0)));
+    }
+
+    #[test]
+    fn class_call_arity() {
+        let src = r#"
+class Empty {}
+var empty = Empty(1);"#;
+        let mut vm = VM::new();
+        let err = interpret(&mut vm, src);
+        assert!(matches!(err, Err(InterpretError::RuntimeError(_))));
+    }
+
+    #[test]
+    fn bound_method() {
+        let src = r#"
+class Counter {
+    init() {
+        this.count = 0;
+    }
+
+    increment() {
+        this.count = this.count + 1;
+        return this.count;
+    }
+}
+
+var counter = Counter();
+var increment = counter.increment;
+increment();
+var result = increment();"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        let result_str = vm.get_string("result");
+        let value = vm.mem.globals.get(result_str);
+        assert_eq!(value, Some(Value::Number(2.0)));
+    }
+
+    #[test]
+    fn invoke_wrong_arity() {
+        let src = r#"
+class Scone {
+    topping(first) {
+        return first;
+    }
+}
+
+var result = Scone().topping();"#;
+        let mut vm = VM::new();
+        let err = interpret(&mut vm, src);
+        assert!(matches!(err, Err(InterpretError::RuntimeError(_))));
+    }
+
+    #[test]
+    fn instance_get_set() {
+        let src = r#"
+class Pair {}
+
+var pair = Pair();
+pair.first = 1;
+pair.second = 2;
+var result = pair.first + pair.second;"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        let result_str = vm.get_string("result");
+
+        let value = vm.mem.globals.get(result_str);
+
+        assert_eq!(value, Some(Value::Number(3.0)));
+    }
+
+    #[test]
+    fn upvalue_closed() {
+        let src = r#"
+    fun makeClosure() {
+      var a = 1;
+      fun f() {
+        a = a + 1;
+        return a;
+      }
+      return f;
+    }
+
+    var closure = makeClosure();
+    var first = closure();
+    var anotherClosure = makeClosure();
+    var second = anotherClosure();
+    var third = closure();"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        let first_str = vm.get_string("first");
+        let second_str = vm.get_string("second");
+        let third_str = vm.get_string("third");
+
+        let value1 = vm.mem.globals.get(first_str);
+        let value2 = vm.mem.globals.get(second_str);
+        let value3 = vm.mem.globals.get(third_str);
+
+        assert_eq!(value1, Some(Value::Number(2.0)));
+        assert_eq!(value2, Some(Value::Number(2.0)));
+        assert_eq!(value3, Some(Value::Number(3.0)));
+    }
+
+    #[test]
+    fn loop_variable_per_iteration() {
+        let src = r#"
+    var closures = [];
+    for (var i = 0; i < 3; i = i + 1) {
+      push(closures, fun () { return i; });
+    }
+
+    var first = closures[0]();
+    var second = closures[1]();
+    var third = closures[2]();
+
+    var skipped = [];
+    for (var j = 0; j < 3; j = j + 1) {
+      push(skipped, fun () { return j; });
+      if (j == 1) continue;
+    }
+    var after_continue = skipped[1]();
+
+    var modified_closures = [];
+    for (var m = 0; m < 3; m = m + 1) {
+      push(modified_closures, fun () { return m; });
+      m = m + 1;
+    }
+    var modified = modified_closures[0]();
+
+    var counters = [];
+    for (var k = 0; k < 2;) {
+      push(counters, fun () { k = k + 10; return k; });
+      k = k + 1;
+    }
+    var own_copy = counters[0]();
+    var own_copy_again = counters[0]();"#;
+        let mut vm = VM::with_options(VmOptions {
+            per_iteration_loops: true,
+            ..VmOptions::default()
+        });
+        interpret(&mut vm, src).unwrap();
+
+        for (name, expected) in [
+            ("first", 0.0),
+            ("second", 1.0),
+            ("third", 2.0),
+            ("after_continue", 1.0),
+            ("modified", 1.0),
+            ("own_copy", 11.0),
+            ("own_copy_again", 21.0),
+        ] {
+            let name_str = vm.get_string(name);
+            assert_eq!(
+                vm.mem.globals.get(name_str),
+                Some(Value::Number(expected)),
+                "{name}"
+            );
+        }
+
+        // By default the iterations share the variable, like in clox
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        for (name, expected) in [("first", 3.0), ("third", 3.0), ("modified", 4.0)] {
+            let name_str = vm.get_string(name);
+            assert_eq!(
+                vm.mem.globals.get(name_str),
+                Some(Value::Number(expected)),
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn set_immediate_upvalue() {
+        let src = r#"
+    fun outer() {
+      var x = 420;
+      fun inner() {
+        x = x + 1;
+        return x;
+      }
+      return inner();
+    }
+    var value = outer();"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        let value_str = vm.get_string("value");
+
+        let value = vm.mem.globals.get(value_str);
+
+        assert_eq!(value, Some(Value::Number(421.0)));
+    }
+
+    #[test]
+    fn immediate_upvalue() {
+        let src = r#"
+var result = "nothing";
+fun outer() {
+  var x = 420;
+  fun inner() {
+    result = x;
+  }
+  inner();
+}
+outer();"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        let result_str = vm.get_string("result");
+        let value = vm.mem.globals.get(result_str);
+
+        assert_eq!(value, Some(Value::Number(420.0)));
+    }
+
+    #[test]
+    fn call_native_fn() {
+        let src = r#"
+        var num = __dummy();"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        let num_str = vm.get_string("num");
+        let value = vm.mem.globals.get(num_str);
+        assert_eq!(value, Some(Value::Number(420.0)));
+    }
+
+    #[test]
+    fn call_fn() {
+        let src = r#"
+            fun add420(num) {
+              return num + 420;
+            }
+
+            fun add69(num) {
+              return num + 69;
+            }
+
+            var num = add420(1);
+            num = add69(num);
+            num = add420(num);"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        let num_str = vm.get_string("num");
+        let value = vm.mem.globals.get(num_str);
+        assert_eq!(value, Some(Value::Number(910.0)));
+    }
+
+    #[test]
+    fn print_fn() {
+        let src = r#"
+            fun bigNoob() {
+              print "OH YEAH";
+            }
+
+            print bigNoob;
+            bigNoob();"#;
+        let (mut vm, stdout) = capturing_vm();
+        interpret(&mut vm, src).unwrap();
+        let output = stdout.contents();
+        assert_eq!(output, "<fn bigNoob>\nOH YEAH\n");
+    }
+
+    #[test]
+    fn if_stmt() {
+        let src = r#"
+            var noob = 420;
+            if (420 > 69) { noob = "NICE"; } else { noob = "NOT NICE"; }
+    "#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        let noob = vm.get_string("noob");
+        let top = vm.mem.globals.get(noob);
+        assert_eq!(top.unwrap().as_str(), Some("NICE"));
+    }
+
+    #[test]
+    fn if_else_stmt() {
+        let src = r#"
+            var noob = 420;
+            if (69 > 420) { noob = "wtf"; } else { noob = "NICE"; }
+    "#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        let noob = vm.get_string("noob");
+        let top = vm.mem.globals.get(noob);
+        assert_eq!(top.unwrap().as_str(), Some("NICE"));
+    }
+
+    #[test]
+    fn while_loop() {
+        let src = r#"
+            var noob = 0;
+            while (noob < 10) {
+              noob = noob + 1;
+            }
+    "#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        let noob = vm.get_string("noob");
+        let top = vm.mem.globals.get(noob);
+        assert_eq!(top, Some(Value::Number(10.0)));
+    }
+
+    #[test]
+    fn for_loop() {
+        let src = r#"
+            var noob = 420;
+            for (var x = 0; x < 10; x = x + 1) {
+              noob = x;
+            }
+    "#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        // let noob = vm.get_string("global");
+        // let top = vm.mem.globals.get(noob);
+        // assert_eq!(top.unwrap().as_str(), Some("NICE"));
+    }
+
+    #[test]
+    fn locals() {
+        let src = r#"
+            var global = 420;
+            { var x = "HELLO"; x = "NICE"; global = x; }
+    "#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        let noob = vm.get_string("global");
+        let top = vm.mem.globals.get(noob);
+        assert_eq!(top.unwrap().as_str(), Some("NICE"));
+    }
+
+    #[test]
+    fn string() {
+        let src = r#"var noob = "hello" + " sir" + " sir";"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        let noob = vm.get_string("noob");
+        let top = vm.mem.globals.get(noob);
+        assert_eq!(top.unwrap().as_str(), Some("hello sir sir"));
+    }
+
+    /// A VM whose `print` output is kept in the returned buffer
+    fn capturing_vm() -> (VM, OutputBuffer) {
+        let stdout = OutputBuffer::default();
+        let vm = VM::with_options(VmOptions {
+            stdout: Box::new(stdout.clone()),
+            ..VmOptions::default()
+        });
+        (vm, stdout)
+    }
+
+    /// The value of `src` like the VM holds it, objects aren't handles
+    fn eval_resolved(engine: &mut Loxide, src: &str) -> Value {
+        let value = engine.eval(src).unwrap();
+        engine.vm.mem.resolve(value).unwrap()
+    }
+
+    /// What `print` shows for the value of `src`
+    fn eval_text(engine: &mut Loxide, src: &str) -> String {
+        let value = engine.eval(src).unwrap();
+        engine.vm.value_ref(value).unwrap().to_string()
+    }
+
+    /// The characters of the value of `src`, `None` if it isn't a string
+    fn eval_str(engine: &mut Loxide, src: &str) -> Option<String> {
+        let value = engine.eval(src).unwrap();
+        engine
+            .vm
+            .value_ref(value)
+            .unwrap()
+            .as_str()
+            .map(str::to_owned)
+    }
+
+    /// The message of the runtime error `result` has to be
+    fn runtime_message(result: crate::InterpretResult<Value>) -> String {
+        match result {
+            Err(InterpretError::RuntimeError(error)) => error.message,
+            other => panic!("expected a runtime error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn print() {
+        let src = r#"print 1 + 2;"#;
+        let (mut vm, stdout) = capturing_vm();
+        interpret(&mut vm, src).unwrap();
+        assert_eq!(stdout.contents(), "3\n");
+
+        let stdout = OutputBuffer::default();
+        let mut vm = VM::with_options(VmOptions {
+            stdout: Box::new(stdout.clone()),
+            debug_print: true,
+            ..VmOptions::default()
+        });
+        interpret(&mut vm, src).unwrap();
+        assert_eq!(stdout.contents(), "Number(3.0)\n");
+    }
+
+    #[test]
+    fn gc_frees_garbage() {
+        let src = r#"
+class Garbage {}
+fun makeGarbage() {
+    var garbage = Garbage();
+    garbage.field = "live";
+    return garbage;
+}
+for (var i = 0; i < 500; i = i + 1) {
+    makeGarbage();
+}
+var result = makeGarbage().field;"#;
+        let mut vm = VM::with_gc_config(GcConfig {
+            initial_threshold: 1024,
+            heap_grow_factor: 2,
+            ..Default::default()
+        });
+        interpret(&mut vm, src).unwrap();
+
+        assert!(vm.mem.obj_list.len() < 500);
+        assert!(vm.mem.bytes_allocated() <= vm.mem.next_gc);
+
+        let result_str = vm.get_string("result");
+        let value = vm.mem.globals.get(result_str);
+        assert_eq!(value.unwrap().as_str(), Some("live"));
+    }
+
+    #[test]
+    fn gc_while_compiling() {
+        let src = r#"
+fun outer() {
+    var a = "a";
+    fun middle() {
+        var b = "b";
+        fun inner() {
+            return a + b + "c";
+        }
+        return inner;
+    }
+    return middle();
+}
+var result = outer()();"#;
+        let mut vm = VM::with_gc_config(GcConfig {
+            stress: true,
+            ..Default::default()
+        });
+        interpret(&mut vm, src).unwrap();
+
+        let result_str = vm.get_string("result");
+        let value = vm.mem.globals.get(result_str);
+        assert_eq!(value.unwrap().as_str(), Some("abc"));
+    }
+
+    #[test]
+    fn state_persists_across_inputs() {
+        // Mirrors how the REPL feeds one input at a time to the same VM
+        let mut vm = VM::with_gc_config(GcConfig {
+            stress: true,
+            ..Default::default()
+        });
+        let inputs = [
+            "var greeting = \"hello\";",
+            "fun makeCounter() { var count = 0; fun counter() { count = count + 1; return count; } return counter; }",
+            "var counter = makeCounter();",
+            "counter(); counter();",
+            "class Greeter { init(name) { this.name = name; } greet() { return greeting + \" \" + this.name; } }",
+            "print undefinedVariable;",
+            "var result = Greeter(\"world\").greet();",
+            "var count = counter();",
+        ];
+        for input in inputs {
+            let _ = interpret(&mut vm, input);
+        }
+
+        let result_str = vm.get_string("result");
+        let value = vm.mem.globals.get(result_str);
+        assert_eq!(value.unwrap().as_str(), Some("hello world"));
+
+        let count_str = vm.get_string("count");
+        let value = vm.mem.globals.get(count_str);
+        assert_eq!(value, Some(Value::Number(3.0)));
+    }
+
+    #[test]
+    fn eval() {
+        let mut engine = Loxide::new();
+        assert_eq!(engine.eval("1 + 2"), Ok(Value::Number(3.0)));
+        assert_eq!(engine.eval("var x = 1;"), Ok(Value::Nil));
+        assert_eq!(engine.eval("x = x + 41;"), Ok(Value::Nil));
+        assert_eq!(engine.eval("x"), Ok(Value::Number(42.0)));
+        assert_eq!(engine.get_global("x"), Some(Value::Number(42.0)));
+        assert!(matches!(
+            engine.eval("x +"),
+            Err(InterpretError::CompileError(_))
+        ));
+        assert!(matches!(
+            engine.eval("undefined"),
+            Err(InterpretError::RuntimeError(_))
+        ));
+
+        let value = engine.eval(r#""hello" + " world""#).unwrap();
+        assert_eq!(
+            engine.vm.value_ref(value).unwrap().as_str(),
+            Some("hello world")
+        );
+    }
+
+    #[test]
+    fn register_native() {
+        let mut engine = Loxide::new();
+        engine
+            .register_native("add", 2, |_vm, args| {
+                match (args[0].as_number(), args[1].as_number()) {
+                    (Some(a), Some(b)) => Ok(Value::Number(a + b)),
+                    _ => Err("Operands must be numbers.".to_string()),
+                }
+            })
+            .register_native("sum", Arity::Variadic, |_vm, args| {
+                Ok(Value::Number(args.iter().fold(
+                    0.0,
+                    |acc, arg| match arg.as_number() {
+                        Some(num) => acc + num,
+                        None => acc,
+                    },
+                )))
+            });
+
+        let counter = std::sync::Arc::new(std::sync::atomic::AtomicU32::new(0));
+        let captured = counter.clone();
+        engine.register_native("tick", 0, move |_vm, _args| {
+            captured.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            Ok(Value::Nil)
+        });
+
+        assert_eq!(engine.eval("add(1, 2)"), Ok(Value::Number(3.0)));
+        assert_eq!(engine.eval("sum(1, 2, 3, 4)"), Ok(Value::Number(10.0)));
+        assert_eq!(engine.eval("tick(); tick();"), Ok(Value::Nil));
+        assert_eq!(counter.load(std::sync::atomic::Ordering::Relaxed), 2);
+
+        assert!(matches!(
+            engine.eval("add(1)"),
+            Err(InterpretError::RuntimeError(_))
+        ));
+        assert!(matches!(
+            engine.eval("add(1, nil)"),
+            Err(InterpretError::RuntimeError(_))
+        ));
+        // The VM is still usable after a native reported an error
+        assert_eq!(engine.eval("add(2, 2)"), Ok(Value::Number(4.0)));
+    }
+
+    #[test]
+    fn call_function() {
+        let src = r#"
+fun makeClosure(start) {
+    var count = start;
+    fun next() {
+        count = count + 1;
+        return count;
+    }
+    return next;
+}
+
+fun add(a, b) {
+    return a + b;
+}
+
+class Point {
+    init(x) {
+        this.x = x;
+    }
+}
+"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        assert_eq!(
+            vm.call_function("add", &[Value::Number(1.0), Value::Number(2.0)]),
+            Ok(Value::Number(3.0))
+        );
+
+        let next = vm
+            .call_function("makeClosure", &[Value::Number(1.0)])
+            .unwrap();
+        assert_eq!(vm.call_value_with_args(next, &[]), Ok(Value::Number(2.0)));
+        assert_eq!(vm.call_value_with_args(next, &[]), Ok(Value::Number(3.0)));
+
+        let point = vm.call_function("Point", &[Value::Number(4.0)]).unwrap();
+        assert_eq!(vm.value_ref(point).unwrap().type_name(), "instance");
+
+        assert!(matches!(
+            vm.call_function("add", &[Value::Number(1.0)]),
+            Err(InterpretError::RuntimeError(_))
+        ));
+        assert!(matches!(
+            vm.call_function("missing", &[]),
+            Err(InterpretError::RuntimeError(_))
+        ));
+        // Errors don't leave junk behind on the stack
+        assert_eq!(
+            vm.call_function("add", &[Value::Number(2.0), Value::Number(2.0)]),
+            Ok(Value::Number(4.0))
+        );
+    }
+
+    #[test]
+    fn call_function_reentrant() {
+        let mut vm = VM::new();
+        vm.register_native("twice", 1, |vm, args| {
+            let first = vm
+                .call_value_with_args(args[0], &[Value::Number(1.0)])
+                .map_err(|err| format!("{err:?}"))?;
+            let second = vm
+                .call_value_with_args(args[0], &[first])
+                .map_err(|err| format!("{err:?}"))?;
+            Ok(second)
+        });
+
+        let src = r#"
+fun double(x) {
+    return x * 2;
+}
+
+fun fail(x) {
+    return x + nil;
+}
+
+var result = twice(double);
+"#;
+        interpret(&mut vm, src).unwrap();
+        let result_str = vm.get_string("result");
+        assert_eq!(vm.mem.globals.get(result_str), Some(Value::Number(4.0)));
+
+        assert!(matches!(
+            interpret(&mut vm, "twice(fail);"),
+            Err(InterpretError::RuntimeError(_))
+        ));
+        assert_eq!(
+            interpret(&mut vm, "result = twice(double) + 1;"),
+            Ok(Value::Nil)
+        );
+        assert_eq!(vm.mem.globals.get(result_str), Some(Value::Number(5.0)));
+    }
+
+    #[test]
+    fn value_conversions() {
+        let mut vm = VM::new();
+        vm.register_native("greet", 2, |vm, args| {
+            let name = String::try_from(vm.value_ref(args[0]).unwrap())?;
+            let times = f64::try_from(vm.value_ref(args[1]).unwrap())?;
+            let greeting = format!("hello {}", name).repeat(times as usize);
+            Ok(vm.create_string(&greeting))
+        });
+
+        let src = r#"
+var result = greet("bob", 2);"#;
+        interpret(&mut vm, src).unwrap();
+
+        let result = vm.get_global("result").unwrap();
+        let result_ref = vm.value_ref(result).unwrap();
+        assert_eq!(<&str>::try_from(result_ref), Ok("hello bobhello bob"));
+        assert_eq!(
+            f64::try_from(result_ref),
+            Err(TypeError {
+                expected: "number",
+                found: "string"
+            })
+        );
+
+        assert!(matches!(
+            interpret(&mut vm, r#"greet("bob", "twice");"#),
+            Err(InterpretError::RuntimeError(_))
+        ));
+
+        // Interned strings are shared
+        let created = vm.create_string("hello bobhello bob");
+        assert_eq!(created, result);
+
+        assert_eq!(Value::from(1.5), Value::Number(1.5));
+        assert_eq!(Value::from(true), Value::Bool(true));
+        assert_eq!(Value::from(()), Value::Nil);
+        assert_eq!(Value::from(None::<f64>), Value::Nil);
+        assert_eq!(Value::from(Some(3)), Value::Number(3.0));
+        assert_eq!(
+            bool::try_from(vm.value_ref(Value::Bool(false)).unwrap()),
+            Ok(false)
+        );
+        assert_eq!(<()>::try_from(vm.value_ref(Value::Nil).unwrap()), Ok(()));
+    }
+
+    #[test]
+    fn table() {
+        let mut mem = Mem::new();
+        let mut table = Table::new();
+
+        let key = mem.copy_string("bagel");
+        assert_eq!(table.set(key, Value::Number(420.0)), true);
+        assert_eq!(table.set(key, Value::Number(69.0)), false);
+        assert_eq!(table.get(key), Some(Value::Number(69.0)));
+        assert_eq!(table.delete(key), true);
+        assert_eq!(table.delete(key), false);
+        assert_eq!(table.set(key, Value::Nil), true);
+    }
+
+    /// Random operations on a table and a `HashMap`, which have to agree. Runs against
+    /// whichever implementation the `robinhood` feature selects
+    #[test]
+    fn table_matches_hash_map() {
+        let mut mem = Mem::new();
+        let keys: Vec<Key> = (0..300)
+            .map(|i| match i % 3 {
+                0 => Key::string(mem.copy_string(&format!("key{i}"))),
+                1 => Key::new(Value::Number(i as f64)).unwrap(),
+                _ => Key::new(Value::Number(i as f64 / 7.0)).unwrap(),
+            })
+            .collect();
+
+        let mut table = Table::new();
+        let mut model = HashMap::new();
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        for step in 0..50_000 {
+            // xorshift
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            let key = keys[(state % keys.len() as u64) as usize];
+            let value = Value::Number(step as f64);
+            match (state >> 32) % 3 {
+                0 | 1 => assert_eq!(
+                    table.set_value(key, value),
+                    model.insert(key, value).is_none()
+                ),
+                _ => assert_eq!(table.delete_value(key), model.remove(&key).is_some()),
+            }
+            assert_eq!(table.len(), model.len());
+        }
+        for key in &keys {
+            assert_eq!(table.get_value(*key), model.get(key).copied(), "{key:?}");
+        }
+        assert_eq!(table.iter().count(), model.len());
+        for entry in table.iter() {
+            assert_eq!(model.get(&Key::new(entry.key).unwrap()), Some(&entry.value));
+        }
+        for (i, key) in keys.iter().enumerate().step_by(3) {
+            let string = key.value().as_obj_str().unwrap();
+            let found = table.find_string(&format!("key{i}"), string.hash);
+            assert_eq!(found.is_some(), model.contains_key(key));
+        }
+    }
+
+    #[test]
+    fn table_compacts_tombstones() {
+        let mut mem = Mem::new();
+        let mut key = |i: usize| Key::string(mem.copy_string(&format!("key{i}")));
+        let mut table = Table::new();
+        // A queue of 10 keys, each deleted 10 insertions later
+        for i in 0..10_000 {
+            table.set_value(key(i), Value::Number(i as f64));
+            if i >= 10 {
+                assert!(table.delete_value(key(i - 10)));
+            }
+        }
+        assert_eq!(table.len(), 10);
+        assert_eq!(table.iter().count(), 10);
+        assert!(table.capacity() <= 32, "{}", table.capacity());
+        assert_eq!(table.get_value(key(9_999)), Some(Value::Number(9_999.0)));
+        assert_eq!(table.get_value(key(9_989)), None);
+
+        for i in 9_990..9_999 {
+            table.delete_value(key(i));
+        }
+        table.shrink_to_fit();
+        assert_eq!(table.capacity(), 8);
+        assert_eq!(table.probe_length(key(9_999)), 1);
+        table.delete_value(key(9_999));
+        table.shrink_to_fit();
+        assert_eq!(table.capacity(), 0);
+
+        let mut sparse = Table::with_max_load(0.25);
+        for i in 0..100 {
+            sparse.set_value(key(i), Value::Nil);
+        }
+        assert_eq!(sparse.capacity(), 512);
+    }
+
+    #[test]
+    fn ohshit() {
+        // let bytes = [0, 1, 2, 3];
+
+        println!("NOOB: {:?}", std::mem::size_of::<Token>());
+
+        let values = [0, 1, 2, 3, 4, 5];
+        println!(
+            "NICE: {:?}",
+            values.iter().take(3).rev().collect::<Vec<_>>()
+        );
+
+        // 0
+        // 1
+        // 2 ---
+        // 3 ---
+        // 4
+        // 5
+        //
+        // 6
+        // -2 to adjust for the 2 bytes for the jump offset
+        let mut chunk = [0, 1, 2, 3, 4, 5];
+        let offset = 2;
+        let jump = chunk.len() as u32 - offset - 2;
+
+        chunk[offset as usize] = (jump >> 8) as u8;
+        chunk[offset as usize + 1] = jump as u8;
+
+        let val = ((chunk[offset as usize] as u16) << 8) | (chunk[offset as usize + 1] as u16);
+
+        println!("{jump} NOOB: {chunk:?} JUMP: {val} {}", 2u16);
+    }
+
+    // #[test]
+    // fn miri_test() {
+    //     let mut obj = Box::into_raw(Box::new(69));
+    //     let mut obj2 = unsafe { obj.as_mut().unwrap() };
+    //     let foo = unsafe { *obj };
+    //     *obj2 = 9999;
+    // }
+    // #[test]
+    // fn miri_test2() {
+    //     let mut obj = Box::into_raw(Box::new(69));
+    //     let mut obj2 = unsafe { obj.as_mut().unwrap() };
+    //     unsafe {
+    //         *obj = 420;
+    //     };
+    //     *obj2 = 9999;
+    // }
+}
@@ -0,0 +1,520 @@
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, Write},
+};
+
+use loxide::{
+    compile::{
+        lint,
+        symbols::{self, Definition, SymbolKind, Symbols},
+        CompileError,
+    },
+    native_fn::Arity,
+    InterpretError, Loxide,
+};
+
+mod json;
+
+use json::{object, Json};
+
+/// `loxide lsp`: a language server reading requests from `input` and writing responses and
+/// diagnostics to `out`, framed with `Content-Length` headers. Documents are synced in full,
+/// positions are converted between the UTF-16 columns of the protocol and byte columns
+pub fn run(mut input: impl BufRead, mut out: impl Write) -> io::Result<()> {
+    let mut server = Server {
+        documents: HashMap::new(),
+        natives: Loxide::new(),
+    };
+    while let Some(message) = read_message(&mut input)? {
+        let message = match Json::parse(&message) {
+            Ok(message) => message,
+            Err(err) => {
+                let error = object! { "code": -32700.0, "message": err };
+                let response = object! { "jsonrpc": "2.0", "id": Json::Null, "error": error };
+                write_message(&mut out, &response)?;
+                continue;
+            }
+        };
+        let method = message.get("method").as_str().unwrap_or_default();
+        let params = message.get("params");
+        match message.get("id") {
+            Json::Null => {
+                if method == "exit" {
+                    return Ok(());
+                }
+                for notification in server.notify(method, params) {
+                    write_message(&mut out, &notification)?;
+                }
+            }
+            id => {
+                let response = match server.request(method, params) {
+                    Ok(result) => object! { "jsonrpc": "2.0", "id": id.clone(), "result": result },
+                    Err(message) => {
+                        let error = object! { "code": -32601.0, "message": message };
+                        object! { "jsonrpc": "2.0", "id": id.clone(), "error": error }
+                    }
+                };
+                write_message(&mut out, &response)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The body of the next message, `None` at the end of the input
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        match header.split_once(':') {
+            Some((name, value)) if name.eq_ignore_ascii_case("Content-Length") => {
+                length = value.trim().parse().ok();
+            }
+            _ => (),
+        }
+    }
+    let Some(length) = length else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Missing Content-Length header.",
+        ));
+    };
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    String::from_utf8(body)
+        .map(Some)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+fn write_message(out: &mut impl Write, message: &Json) -> io::Result<()> {
+    let body = message.to_string();
+    write!(out, "Content-Length: {}\r\n\r\n{body}", body.len())?;
+    out.flush()
+}
+
+struct Server {
+    /// The text of the open documents by URI
+    documents: HashMap<String, String>,
+    /// An engine with the natives defined, for their arity
+    natives: Loxide,
+}
+
+impl Server {
+    /// Handle a notification, returning the notifications to send back
+    fn notify(&mut self, method: &str, params: &Json) -> Vec<Json> {
+        let document = params.get("textDocument");
+        let Some(uri) = document.get("uri").as_str() else {
+            return vec![];
+        };
+        let text = match method {
+            "textDocument/didOpen" => document.get("text").as_str(),
+            // Only full syncs are supported, the last change has the whole text
+            "textDocument/didChange" => params
+                .get("contentChanges")
+                .as_array()
+                .and_then(<[Json]>::last)
+                .and_then(|change| change.get("text").as_str()),
+            "textDocument/didClose" => {
+                self.documents.remove(uri);
+                let diagnostics = Json::Array(vec![]);
+                return vec![publish_diagnostics(uri, diagnostics)];
+            }
+            _ => None,
+        };
+        let Some(text) = text else {
+            return vec![];
+        };
+        self.documents.insert(uri.to_string(), text.to_string());
+        vec![publish_diagnostics(uri, diagnostics(text))]
+    }
+
+    /// Answer a request, or fail with the message of a method not found error
+    fn request(&mut self, method: &str, params: &Json) -> Result<Json, String> {
+        if method == "initialize" {
+            let capabilities = object! {
+                "textDocumentSync": 1,
+                "definitionProvider": true,
+                "hoverProvider": true,
+                "documentSymbolProvider": true,
+            };
+            let info = object! { "name": "loxide", "version": env!("CARGO_PKG_VERSION") };
+            return Ok(object! { "capabilities": capabilities, "serverInfo": info });
+        }
+        if method == "shutdown" {
+            return Ok(Json::Null);
+        }
+
+        let uri = params.get("textDocument").get("uri").as_str();
+        let document = uri.and_then(|uri| self.documents.get_key_value(uri));
+        let Some((uri, text)) = document.map(|(uri, text)| (uri.clone(), text.clone())) else {
+            return match method {
+                "textDocument/definition" | "textDocument/hover" | "textDocument/documentSymbol" => {
+                    Ok(Json::Null)
+                }
+                _ => Err(format!("Unknown method '{method}'.")),
+            };
+        };
+        let text = text.as_str();
+        let symbols = symbols::analyze(text);
+        let position = params.get("position");
+        let position = position
+            .get("line")
+            .as_u32()
+            .zip(position.get("character").as_u32())
+            .map(|(line, character)| from_utf16(text, line + 1, character));
+
+        match method {
+            "textDocument/definition" => {
+                let definition = position
+                    .and_then(|(line, column)| symbols.at(line, column))
+                    .map(|reference| &symbols.definitions[reference.definition]);
+                Ok(definition.map_or(Json::Null, |definition| {
+                    let range = name_range(text, definition);
+                    object! { "uri": uri.as_str(), "range": range }
+                }))
+            }
+            "textDocument/hover" => {
+                let Some((line, column)) = position else {
+                    return Ok(Json::Null);
+                };
+                let (range, contents) = match symbols.at(line, column) {
+                    Some(reference) => {
+                        let definition = &symbols.definitions[reference.definition];
+                        let start = (reference.line, reference.column);
+                        let end = (reference.line, reference.column + reference.len);
+                        (range(text, start, end), hover(&symbols, definition))
+                    }
+                    None => {
+                        let Some((start, name)) = word_at(text, line, column) else {
+                            return Ok(Json::Null);
+                        };
+                        let Some(contents) = self.native(name) else {
+                            return Ok(Json::Null);
+                        };
+                        let end = (line, start + name.len() as u32);
+                        (range(text, (line, start), end), contents)
+                    }
+                };
+                let contents = object! { "kind": "markdown", "value": contents };
+                Ok(object! { "contents": contents, "range": range })
+            }
+            "textDocument/documentSymbol" => {
+                let symbols = symbols
+                    .definitions
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, definition)| definition.top_level && definition.parent.is_none())
+                    .map(|(index, definition)| {
+                        let children = symbols
+                            .definitions
+                            .iter()
+                            .filter(|member| member.parent == Some(index))
+                            .map(|member| document_symbol(text, member, vec![]))
+                            .collect();
+                        document_symbol(text, definition, children)
+                    })
+                    .collect();
+                Ok(Json::Array(symbols))
+            }
+            _ => Err(format!("Unknown method '{method}'.")),
+        }
+    }
+
+    /// Hover text for the native function `name`
+    fn native(&mut self, name: &str) -> Option<String> {
+        let native = self.natives.get_global(name)?;
+        let arity = match self.natives.vm.value_ref(native)?.native_arity()? {
+            Arity::Fixed(arity) => takes(arity.into(), Some(arity.into())),
+            Arity::Variadic => "Takes any number of arguments.".to_string(),
+        };
+        Some(format!(
+            "```lox\nfun {name}(...)\n```\nNative function. {arity}"
+        ))
+    }
+}
+
+fn publish_diagnostics(uri: &str, diagnostics: Json) -> Json {
+    let params = object! { "uri": uri, "diagnostics": diagnostics };
+    object! {
+        "jsonrpc": "2.0",
+        "method": "textDocument/publishDiagnostics",
+        "params": params,
+    }
+}
+
+/// The compiler's syntax errors in `text`, or the linter's warnings if there are none
+fn diagnostics(text: &str) -> Json {
+    let errors = match loxide::compile_str(text) {
+        Err(InterpretError::CompileError(errors)) => errors,
+        _ => vec![],
+    };
+    if !errors.is_empty() {
+        return Json::Array(
+            errors
+                .iter()
+                .map(|error| error_diagnostic(text, error))
+                .collect(),
+        );
+    }
+    let warnings = lint::lint(text, &[]).unwrap_or_default();
+    let warnings = warnings.into_iter().map(|warning| {
+        let start = (warning.line, warning.column);
+        let end = (warning.line, word_end(text, warning.line, warning.column));
+        object! {
+            "range": range(text, start, end),
+            "severity": 2,
+            "code": warning.rule.code(),
+            "source": "loxide",
+            "message": warning.message,
+        }
+    });
+    Json::Array(warnings.collect())
+}
+
+fn error_diagnostic(text: &str, error: &CompileError) -> Json {
+    // Errors without a token cover their whole line
+    let start = (error.line, error.column.max(1));
+    let lexeme = error
+        .at
+        .strip_prefix('\'')
+        .and_then(|at| at.strip_suffix('\''));
+    let end = match lexeme {
+        _ if error.column == 0 => (error.line, u32::MAX),
+        Some(lexeme) => (error.line, error.column + lexeme.len() as u32),
+        None => (error.line, word_end(text, error.line, error.column)),
+    };
+    object! {
+        "range": range(text, start, end),
+        "severity": 1,
+        "source": "loxide",
+        "message": error.message.as_str(),
+    }
+}
+
+fn hover(symbols: &Symbols, definition: &Definition) -> String {
+    let name = &definition.name;
+    let params = definition.params.as_ref().map(|params| params.join(", "));
+    let qualified = match definition.parent {
+        Some(class) => format!("{}.{name}", symbols.definitions[class].name),
+        None => name.clone(),
+    };
+    let signature = match (definition.kind, &params) {
+        (SymbolKind::Variable, _) => format!("var {name}"),
+        (SymbolKind::Constant, _) => format!("const {name}"),
+        (SymbolKind::Parameter, _) => format!("(parameter) {name}"),
+        (SymbolKind::Module, _) => format!("import {name}"),
+        (SymbolKind::Field, _) => format!("(field) {qualified}"),
+        (SymbolKind::Class, Some(params)) => format!("class {name}({params})"),
+        (SymbolKind::Class, None) => format!("class {name}"),
+        (SymbolKind::Function | SymbolKind::Method, params) => {
+            format!("fun {qualified}({})", params.as_deref().unwrap_or_default())
+        }
+    };
+    let mut contents = format!("```lox\n{signature}\n```");
+    if let Some((min, max)) = definition.arity {
+        contents.push('\n');
+        contents.push_str(&takes(min, max));
+    }
+    contents
+}
+
+fn takes(min: usize, max: Option<usize>) -> String {
+    let arguments = |count| if count == 1 { "argument" } else { "arguments" };
+    match max {
+        Some(max) if max == min => format!("Takes {min} {}.", arguments(min)),
+        Some(max) => format!("Takes {min} to {max} arguments."),
+        None => format!("Takes at least {min} {}.", arguments(min)),
+    }
+}
+
+fn document_symbol(text: &str, definition: &Definition, children: Vec<Json>) -> Json {
+    let kind: u32 = match definition.kind {
+        SymbolKind::Module => 2,
+        SymbolKind::Class => 5,
+        SymbolKind::Method if definition.name == "init" => 9,
+        SymbolKind::Method => 6,
+        SymbolKind::Field => 8,
+        SymbolKind::Function => 12,
+        SymbolKind::Variable | SymbolKind::Parameter => 13,
+        SymbolKind::Constant => 14,
+    };
+    let start = (definition.line, definition.column);
+    let end = (definition.end_line, u32::MAX);
+    object! {
+        "name": definition.name.as_str(),
+        "kind": kind,
+        "range": range(text, start, end),
+        "selectionRange": name_range(text, definition),
+        "children": Json::Array(children),
+    }
+}
+
+fn name_range(text: &str, definition: &Definition) -> Json {
+    let start = (definition.line, definition.column);
+    let end = (
+        definition.line,
+        definition.column + definition.name.len() as u32,
+    );
+    range(text, start, end)
+}
+
+/// A protocol range between two 1-based lines and byte columns, `u32::MAX` for the end of the
+/// line
+fn range(text: &str, start: (u32, u32), end: (u32, u32)) -> Json {
+    object! { "start": position(text, start), "end": position(text, end) }
+}
+
+fn position(text: &str, (line, column): (u32, u32)) -> Json {
+    let source = source_line(text, line);
+    let end = (column.saturating_sub(1) as usize).min(source.len());
+    let character: usize = source[..end].chars().map(char::len_utf16).sum();
+    object! { "line": line.saturating_sub(1), "character": character as u32 }
+}
+
+/// The 1-based line and byte column of a 0-based UTF-16 `character` on 1-based `line`
+fn from_utf16(text: &str, line: u32, character: u32) -> (u32, u32) {
+    let mut units = 0;
+    let mut column = 1;
+    for c in source_line(text, line).chars() {
+        if units >= character as usize {
+            break;
+        }
+        units += c.len_utf16();
+        column += c.len_utf8() as u32;
+    }
+    (line, column)
+}
+
+fn source_line(text: &str, line: u32) -> &str {
+    let source = text.split('\n').nth(line.saturating_sub(1) as usize);
+    source.unwrap_or_default().trim_end_matches('\r')
+}
+
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// The byte column after the word starting at `column`
+fn word_end(text: &str, line: u32, column: u32) -> u32 {
+    let source = source_line(text, line);
+    let start = (column.saturating_sub(1) as usize).min(source.len());
+    let len = source[start..]
+        .find(|c| !is_word(c))
+        .unwrap_or(source.len() - start);
+    column + len.max(1) as u32
+}
+
+/// The start column and text of the word around `column`
+fn word_at(text: &str, line: u32, column: u32) -> Option<(u32, &str)> {
+    let source = source_line(text, line);
+    let at = column.checked_sub(1)? as usize;
+    if !source.get(at..)?.starts_with(is_word) {
+        return None;
+    }
+    let start = source[..at].rfind(|c| !is_word(c)).map_or(0, |i| i + 1);
+    let end = source[at..]
+        .find(|c| !is_word(c))
+        .map_or(source.len(), |i| at + i);
+    Some((start as u32 + 1, &source[start..end]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::json::Json;
+
+    /// Run a session with `messages` and return the messages sent back
+    fn session(messages: &[&str]) -> Vec<Json> {
+        let input: String = messages
+            .iter()
+            .map(|message| format!("Content-Length: {}\r\n\r\n{message}", message.len()))
+            .collect();
+        let mut out = vec![];
+        super::run(input.as_bytes(), &mut out).unwrap();
+
+        let mut out = String::from_utf8(out).unwrap();
+        let mut messages = vec![];
+        while let Some((header, rest)) = out.split_once("\r\n\r\n") {
+            let length: usize = header["Content-Length: ".len()..].parse().unwrap();
+            messages.push(Json::parse(&rest[..length]).unwrap());
+            out = rest[length..].to_string();
+        }
+        messages
+    }
+
+    #[test]
+    fn requests() {
+        let src = r#"var total = 0;\nfun add(a, b = 1) {\n  var unused;\n  return a + b;\n}\nclass Point {\n  init(x, y) { this.x = x; }\n  sum() { return this.x; }\n}\nprint add(total) + clock();\n"#;
+        let open = format!(
+            r#"{{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{{"textDocument":{{"uri":"file:///a.lox","languageId":"lox","version":1,"text":"{src}"}}}}}}"#
+        );
+        let at = |id: u32, method: &str, line: u32, character: u32| {
+            format!(
+                r#"{{"jsonrpc":"2.0","id":{id},"method":"textDocument/{method}","params":{{"textDocument":{{"uri":"file:///a.lox"}},"position":{{"line":{line},"character":{character}}}}}}}"#
+            )
+        };
+        let messages = session(&[
+            r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{"capabilities":{}}}"#,
+            r#"{"jsonrpc":"2.0","method":"initialized","params":{}}"#,
+            &open,
+            &at(2, "definition", 9, 7),
+            &at(3, "hover", 9, 7),
+            &at(4, "hover", 9, 20),
+            &at(5, "definition", 6, 24),
+            r#"{"jsonrpc":"2.0","id":6,"method":"textDocument/documentSymbol","params":{"textDocument":{"uri":"file:///a.lox"}}}"#,
+            r#"{"jsonrpc":"2.0","method":"textDocument/didChange","params":{"textDocument":{"uri":"file:///a.lox"},"contentChanges":[{"text":"print 1 +;"}]}}"#,
+            r#"{"jsonrpc":"2.0","id":8,"method":"workspace/symbol","params":{}}"#,
+            r#"{"jsonrpc":"2.0","id":9,"method":"shutdown"}"#,
+            r#"{"jsonrpc":"2.0","method":"exit"}"#,
+        ]);
+        let messages: Vec<String> = messages.iter().map(Json::to_string).collect();
+        let expected = [
+            r#"{"jsonrpc":"2.0","id":1,"result":{"capabilities":{"textDocumentSync":1,"definitionProvider":true,"hoverProvider":true,"documentSymbolProvider":true},"serverInfo":{"name":"loxide","version":"VERSION"}}}"#,
+            r#"{"jsonrpc":"2.0","method":"textDocument/publishDiagnostics","params":{"uri":"file:///a.lox","diagnostics":[{"range":{"start":{"line":2,"character":2},"end":{"line":2,"character":5}},"severity":2,"code":"unused-local","source":"loxide","message":"'unused' is never used."}]}}"#,
+            r#"{"jsonrpc":"2.0","id":2,"result":{"uri":"file:///a.lox","range":{"start":{"line":1,"character":4},"end":{"line":1,"character":7}}}}"#,
+            r#"{"jsonrpc":"2.0","id":3,"result":{"contents":{"kind":"markdown","value":"```lox\nfun add(a, b)\n```\nTakes 1 to 2 arguments."},"range":{"start":{"line":9,"character":6},"end":{"line":9,"character":9}}}}"#,
+            r#"{"jsonrpc":"2.0","id":4,"result":{"contents":{"kind":"markdown","value":"```lox\nfun clock(...)\n```\nNative function. Takes 0 arguments."},"range":{"start":{"line":9,"character":19},"end":{"line":9,"character":24}}}}"#,
+            r#"{"jsonrpc":"2.0","id":5,"result":{"uri":"file:///a.lox","range":{"start":{"line":6,"character":7},"end":{"line":6,"character":8}}}}"#,
+            r#"{"jsonrpc":"2.0","id":6,"result":[{"name":"total","kind":13,"range":{"start":{"line":0,"character":4},"end":{"line":0,"character":14}},"selectionRange":{"start":{"line":0,"character":4},"end":{"line":0,"character":9}},"children":[]},{"name":"add","kind":12,"range":{"start":{"line":1,"character":4},"end":{"line":4,"character":1}},"selectionRange":{"start":{"line":1,"character":4},"end":{"line":1,"character":7}},"children":[]},{"name":"Point","kind":5,"range":{"start":{"line":5,"character":6},"end":{"line":8,"character":1}},"selectionRange":{"start":{"line":5,"character":6},"end":{"line":5,"character":11}},"children":[{"name":"init","kind":9,"range":{"start":{"line":6,"character":2},"end":{"line":6,"character":28}},"selectionRange":{"start":{"line":6,"character":2},"end":{"line":6,"character":6}},"children":[]},{"name":"sum","kind":6,"range":{"start":{"line":7,"character":2},"end":{"line":7,"character":26}},"selectionRange":{"start":{"line":7,"character":2},"end":{"line":7,"character":5}},"children":[]}]}]}"#,
+            r#"{"jsonrpc":"2.0","method":"textDocument/publishDiagnostics","params":{"uri":"file:///a.lox","diagnostics":[{"range":{"start":{"line":0,"character":9},"end":{"line":0,"character":10}},"severity":1,"source":"loxide","message":"Expect expression"}]}}"#,
+            r#"{"jsonrpc":"2.0","id":8,"error":{"code":-32601,"message":"Unknown method 'workspace/symbol'."}}"#,
+            r#"{"jsonrpc":"2.0","id":9,"result":null}"#,
+        ];
+        let expected: Vec<String> = expected
+            .iter()
+            .map(|message| message.replace("VERSION", env!("CARGO_PKG_VERSION")))
+            .collect();
+        assert_eq!(messages, expected);
+    }
+
+    #[test]
+    fn requests_with_syntax_errors() {
+        // The statement with the error is left out, the rest of the document still answers
+        let open = r#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"textDocument":{"uri":"file:///b.lox","languageId":"lox","version":1,"text":"var total = 0;\nprint total +;\nfun f() { return total; }\n"}}}"#;
+        let at = |id: u32, method: &str, line: u32, character: u32| {
+            format!(
+                r#"{{"jsonrpc":"2.0","id":{id},"method":"textDocument/{method}","params":{{"textDocument":{{"uri":"file:///b.lox"}},"position":{{"line":{line},"character":{character}}}}}}}"#
+            )
+        };
+        let messages = session(&[
+            open,
+            &at(1, "definition", 2, 17),
+            &at(2, "hover", 2, 17),
+            r#"{"jsonrpc":"2.0","id":3,"method":"textDocument/documentSymbol","params":{"textDocument":{"uri":"file:///b.lox"}}}"#,
+        ]);
+        let messages: Vec<String> = messages.iter().map(Json::to_string).collect();
+        let expected = [
+            r#"{"jsonrpc":"2.0","method":"textDocument/publishDiagnostics","params":{"uri":"file:///b.lox","diagnostics":[{"range":{"start":{"line":1,"character":13},"end":{"line":1,"character":14}},"severity":1,"source":"loxide","message":"Expect expression"}]}}"#,
+            r#"{"jsonrpc":"2.0","id":1,"result":{"uri":"file:///b.lox","range":{"start":{"line":0,"character":4},"end":{"line":0,"character":9}}}}"#,
+            r#"{"jsonrpc":"2.0","id":2,"result":{"contents":{"kind":"markdown","value":"```lox\nvar total\n```"},"range":{"start":{"line":2,"character":17},"end":{"line":2,"character":22}}}}"#,
+            r#"{"jsonrpc":"2.0","id":3,"result":[{"name":"total","kind":13,"range":{"start":{"line":0,"character":4},"end":{"line":0,"character":14}},"selectionRange":{"start":{"line":0,"character":4},"end":{"line":0,"character":9}},"children":[]},{"name":"f","kind":12,"range":{"start":{"line":2,"character":4},"end":{"line":2,"character":25}},"selectionRange":{"start":{"line":2,"character":4},"end":{"line":2,"character":5}},"children":[]}]}"#,
+        ];
+        assert_eq!(messages, expected);
+    }
+}
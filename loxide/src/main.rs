@@ -1,550 +1,436 @@
-#![feature(ptr_sub_ptr)]
-#![feature(allocator_api)]
-#![feature(slice_ptr_get)]
-#![feature(let_chains)]
-
-pub mod chunk;
-pub mod compile;
-pub mod mem;
-pub mod native_fn;
-pub mod obj;
-pub mod table;
-pub mod value;
-pub mod vm;
-
-use std::{io::BufRead, mem::MaybeUninit, path::Path};
-
-use compile::Parser;
-use mem::Mem;
-
-use vm::{InterpretError, InterpretResult, ValueStack, STACK_MAX};
-
-use crate::vm::VM;
-
-#[macro_export]
-macro_rules! debug_println {
-    () => {
-        #[cfg(debug_assertions)]
-        $std::print!("\n")
-    };
-    ($($arg:tt)*) => {
-        #[cfg(debug_assertions)]
-        println!($($arg)*);
-    };
-}
+#![feature(is_terminal)]
+
+use std::{
+    io::{IsTerminal, Read},
+    path::{Path, PathBuf},
+};
+
+use loxide::{
+    chunk::serialize,
+    compile,
+    compile::{ast, lint},
+    interpret, load_bytecode,
+    native_fn::Arity,
+    replay::Replay,
+    report,
+    vm::{coverage::Coverage, VM},
+    InterpretError, InterpretResult, Loxide, Script, Value, VmOptions,
+};
+
+mod bench;
+mod cli;
+mod debugger;
+mod lsp;
+mod repl;
+mod script_test;
+mod watch;
+
+use cli::{AstFormat, Mode};
 
 fn main() {
-    // run_file("./test.lox")
-
-    let mut args = std::env::args();
-    let _ = args.next();
-
-    match args.len() {
-        0 => {
-            repl();
+    let options = match cli::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{err}\n\n{}", cli::USAGE);
+            std::process::exit(64);
         }
-        1 => {
-            let mut vm = VM::new();
-            run_file(&mut vm, args.next().unwrap());
-        }
-        _ => panic!(),
-    }
-}
-
-fn repl() {
-    let stdin = std::io::stdin();
-    let lines = stdin.lock().lines();
-    let mut vm = VM::new();
-
-    for line in lines {
-        let line = line.unwrap();
-        interpret(&mut vm, &line).unwrap();
-    }
-}
-
-fn run_file<P: AsRef<Path>>(vm: &mut VM, path: P) {
-    let string = std::fs::read_to_string(path).unwrap();
-    interpret(vm, &string).unwrap();
-}
-
-fn interpret(vm: &mut VM, src: &str) -> InterpretResult<()> {
-    let function = {
-        let mut parser = Parser::new(src, &mut vm.mem);
-        if !parser.compile() {
-            return Err(InterpretError::CompileError);
-        }
-        parser.compiler.function
-    };
-    vm.init(function);
-
-    vm.run()
-}
-
-#[cfg(test)]
-mod test {
-
-    use std::{cell::UnsafeCell, mem::MaybeUninit};
-
-    use crate::{
-        compile::Token,
-        interpret,
-        mem::Mem,
-        table::Table,
-        value::Value,
-        vm::{InterpretError, ValueStack, STACK_MAX, VM},
     };
 
-    #[test]
-    fn fib() {
-        let src = r#"
-fun fib(x) {
-    if (x <= 1) {
-        return x;
-    }
-    return fib(x - 1) + fib(x - 2);
-}
-
-var result = fib(2);
-"#;
-
-        let mut vm = VM::new();
-        interpret(&mut vm, src).unwrap();
-        let result_var_str = vm.get_string("result").as_non_null_ptr();
-        let value = vm.mem.globals.get(result_var_str);
-        assert_eq!(value.unwrap(), Value::Number(1.0));
-    }
-
-    #[test]
-    fn superclasses() {
-        let src = r#"
-class Doughnut {
-  cook() {
-    print "Dunk in the fryer.";
-    this.finish("sprinkles");
-  }
-
-  finish(ingredient) {
-    return "Finish with " + ingredient;
-  }
-}
-
-class Cruller < Doughnut {
-  finish(ingredient) {
-    // No sprinkles, always icing.
-    return super.finish("icing");
-  }
-}
-
-var cruller = Cruller();
-var result = cruller.finish("noice");
-"#;
-
-        let mut vm = VM::new();
-        interpret(&mut vm, src).unwrap();
-        let result_var_str = vm.get_string("result").as_non_null_ptr();
-        let value = vm.mem.globals.get(result_var_str);
-        assert_eq!(value.unwrap().as_str().unwrap(), "Finish with icing");
+    let color = options.color && std::io::stderr().is_terminal();
+    if let Mode::Watch(path) = &options.mode {
+        watch::run(path, &options, color);
     }
 
-    #[test]
-    fn invoking_fields() {
-        let src = r#"
-        class Oops {
-            init() {
-                fun f() {
-                    return 420;
-                }
-
-                this.field = f;
-            }
-        }
-
-        var oops = Oops();
-        var result = oops.field();
-        "#;
-
-        let mut vm = VM::new();
-        interpret(&mut vm, src).unwrap();
-        let result_str = vm.get_string("result").as_non_null_ptr();
-        let value = vm.mem.globals.get(result_str);
-        assert_eq!(value, Some(Value::Number(420.0)));
-    }
-
-    #[test]
-    fn misusing_this() {
-        let src = r#"
-        fun notMethod() {
-            print this;
+    let vm_options = vm_options(&options);
+    // A program on stdin runs like -e, there's no path to resolve imports from
+    let mode = match options.mode {
+        Mode::Stdin => Mode::Eval(read_stdin()),
+        Mode::Repl if !std::io::stdin().is_terminal() => Mode::Eval(read_stdin()),
+        mode => mode,
+    };
+    match mode {
+        Mode::Help => println!("{}", cli::USAGE),
+        Mode::Version => println!("loxide {}", env!("CARGO_PKG_VERSION")),
+        Mode::Repl => {
+            let vm_options = VmOptions {
+                report_errors: false,
+                ..vm_options
+            };
+            repl::run(&mut Loxide::with_options(vm_options), color);
         }
-        "#;
-
-        let mut vm = VM::new();
-        let err = interpret(&mut vm, src);
-        if let Err(InterpretError::CompileError) = err {
-        } else {
-            panic!()
+        Mode::Stdin | Mode::Watch(_) => unreachable!(),
+        Mode::Compile { input, output } => compile_file(vm_options, input, output),
+        Mode::Debug(path) => {
+            let mut vm = VM::with_options(vm_options);
+            register_args(&mut vm, options.script_args);
+            let script = load_file(&mut vm, path);
+            debugger::run(script, std::io::stdin().lock(), std::io::stdout()).unwrap_or_else(
+                |err| {
+                    eprintln!("{err}");
+                    std::process::exit(74);
+                },
+            );
         }
-    }
-
-    #[test]
-    fn nested_this() {
-        let src = r#"
-        class Nested {
-            method() {
-              fun function() {
-                return this.lol;
-              }
-              return function();
+        Mode::Bench(bench) => {
+            if let Err(err) = bench::run(&bench, options.optimize, std::io::stdout()) {
+                eprintln!("{err}");
+                std::process::exit(74);
             }
         }
-          
-        var nested = Nested();
-        nested.lol = 420;
-        var result = nested.method();"#;
-
-        let mut vm = VM::new();
-        interpret(&mut vm, src).unwrap();
-        let result_str = vm.get_string("result").as_non_null_ptr();
-        let value = vm.mem.globals.get(result_str);
-        assert_eq!(value, Some(Value::Number(420.0)));
-    }
-
-    #[test]
-    fn this() {
-        let src = r#"
-        class Nested {
-            method() {
-              return this.lol;
+        Mode::Test(paths) => match script_test::run(
+            &paths,
+            options.gc_config,
+            options.optimize,
+            std::io::stdout(),
+        ) {
+            Ok(true) => (),
+            Ok(false) => std::process::exit(1),
+            Err(err) => {
+                eprintln!("{err}");
+                std::process::exit(74);
             }
-          }
-          
-        var nested = Nested();
-        nested.lol = 420;
-        var result = nested.method();"#;
-
-        let mut vm = VM::new();
-        interpret(&mut vm, src).unwrap();
-        let result_str = vm.get_string("result").as_non_null_ptr();
-        let value = vm.mem.globals.get(result_str);
-        assert_eq!(value, Some(Value::Number(420.0)));
-    }
-
-    #[test]
-    fn methods() {
-        let src = r#"
-        class Scone {
-            topping(first, second) {
-              return "scone with " + first + " and " + second;
+        },
+        Mode::Fmt { paths, check } => match format_files(&paths, check) {
+            Ok(true) => (),
+            Ok(false) => std::process::exit(1),
+            Err(err) => {
+                eprintln!("{err}");
+                std::process::exit(74);
             }
-          }
-          
-          var scone = Scone();
-          var result = scone.topping("berries", "cream");"#;
-        let mut vm = VM::new();
-        interpret(&mut vm, src).unwrap();
-
-        let result_str = vm.get_string("result").as_non_null_ptr();
-
-        let value = vm.mem.globals.get(result_str);
-
-        let expected_str = vm.get_string("scone with berries and cream");
-        println!("VAL: {:?}", value);
-        assert_eq!(value, Some(Value::Obj(expected_str.cast())));
+        },
+        Mode::Lint { paths, allow } => match lint_files(&paths, &allow) {
+            Ok(true) => (),
+            Ok(false) => std::process::exit(1),
+            Err(err) => {
+                eprintln!("{err}");
+                std::process::exit(74);
+            }
+        },
+        Mode::Lsp => {
+            if let Err(err) = lsp::run(std::io::stdin().lock(), std::io::stdout()) {
+                eprintln!("{err}");
+                std::process::exit(74);
+            }
+        }
+        Mode::File(path) if options.dump_ast.is_some() => {
+            let string = read_source(&path);
+            print_ast(&string, options.dump_ast.unwrap());
+        }
+        Mode::Eval(code) if options.dump_ast.is_some() => {
+            print_ast(&code, options.dump_ast.unwrap())
+        }
+        Mode::File(path) if options.disassemble => {
+            let string = read_source(&path);
+            print_disassembly(vm_options, &string, color);
+        }
+        Mode::Eval(code) if options.disassemble => print_disassembly(vm_options, &code, color),
+        Mode::File(path) => {
+            let mut vm = VM::with_options(VmOptions {
+                report_errors: false,
+                ..vm_options
+            });
+            register_args(&mut vm, options.script_args);
+            let result = run_file(&mut vm, path, color);
+            print_stats(&mut vm, options.mem_stats);
+            exit_on_error(&result);
+        }
+        Mode::Eval(code) => {
+            let mut vm = VM::with_options(VmOptions {
+                report_errors: false,
+                ..vm_options
+            });
+            register_args(&mut vm, options.script_args);
+            let result = interpret(&mut vm, &code);
+            report_error(&vm, &result, Some(&code), color);
+            print_stats(&mut vm, options.mem_stats);
+            exit_on_error(&result);
+        }
     }
+}
 
-    #[test]
-    fn instance_get_set() {
-        let src = r#"
-class Pair {}
-
-var pair = Pair();
-pair.first = 1;
-pair.second = 2;
-var result = pair.first + pair.second;"#;
-        let mut vm = VM::new();
-        interpret(&mut vm, src).unwrap();
-
-        let result_str = vm.get_string("result").as_non_null_ptr();
-
-        let value = vm.mem.globals.get(result_str);
-
-        assert_eq!(value, Some(Value::Number(3.0)));
+/// The options of the VM that runs the script. Exits if the replay trace can't be opened
+fn vm_options(options: &cli::Options) -> VmOptions {
+    let replay = options.replay.as_deref().map(|path| {
+        Replay::file(path).unwrap_or_else(|err| {
+            eprintln!("Could not open the replay trace: {err}");
+            std::process::exit(74);
+        })
+    });
+    VmOptions {
+        gc_config: options.gc_config,
+        random_seed: options.random_seed,
+        replay,
+        allow_process: true,
+        trace_execution: options.trace_execution,
+        optimize: options.optimize,
+        tail_calls: options.tail_calls,
+        per_iteration_loops: options.per_iteration_loops,
+        count_dispatches: options.count_dispatches,
+        profile: options.profile,
+        coverage: options.coverage,
+        module_paths: loxide::module::lox_path(),
+        native_modules: true,
+        ..VmOptions::default()
     }
+}
 
-    #[test]
-    fn upvalue_closed() {
-        let src = r#"
-    fun makeClosure() {
-      var a = 1;
-      fun f() {
-        a = a + 1;
-        return a;
-      }
-      return f;
+/// `--count-dispatches`, `--profile`, `--coverage` and `--mem-stats`
+fn print_stats(vm: &mut VM, mem_stats: bool) {
+    if vm.count_dispatches {
+        eprintln!("{} instructions dispatched", vm.dispatch_count);
     }
-
-    var closure = makeClosure();
-    var first = closure();
-    var anotherClosure = makeClosure();
-    var second = anotherClosure();
-    var third = closure();"#;
-        let mut vm = VM::new();
-        interpret(&mut vm, src).unwrap();
-
-        let first_str = vm.get_string("first").as_non_null_ptr();
-        let second_str = vm.get_string("second").as_non_null_ptr();
-        let third_str = vm.get_string("third").as_non_null_ptr();
-
-        let value1 = vm.mem.globals.get(first_str);
-        let value2 = vm.mem.globals.get(second_str);
-        let value3 = vm.mem.globals.get(third_str);
-
-        assert_eq!(value1, Some(Value::Number(2.0)));
-        assert_eq!(value2, Some(Value::Number(2.0)));
-        assert_eq!(value3, Some(Value::Number(3.0)));
+    if let Some(profile) = &mut vm.profile {
+        profile.finish();
+        eprint!("{profile}");
     }
-
-    #[test]
-    fn set_immediate_upvalue() {
-        let src = r#"
-    fun outer() {
-      var x = 420;
-      fun inner() {
-        x = x + 1;
-        return x;
-      }
-      return inner();
+    if let Some(coverage) = &vm.coverage {
+        write_coverage(coverage);
     }
-    var value = outer();"#;
-        let mut vm = VM::new();
-        interpret(&mut vm, src).unwrap();
-        let value_str = vm.get_string("value").as_non_null_ptr();
-
-        let value = vm.mem.globals.get(value_str);
-
-        assert_eq!(value, Some(Value::Number(421.0)));
+    if mem_stats {
+        eprint!("{}", vm.heap_stats());
     }
-
-    #[test]
-    fn immediate_upvalue() {
-        let src = r#"
-var result = "nothing";
-fun outer() {
-  var x = 420;
-  fun inner() {
-    result = x;
-  }
-  inner();
 }
-outer();"#;
-        let mut vm = VM::new();
-        interpret(&mut vm, src).unwrap();
-        let result_str = vm.get_string("result").as_non_null_ptr();
-        let value = vm.mem.globals.get(result_str);
 
-        assert_eq!(value, Some(Value::Number(420.0)));
+/// Write `lcov.info` and a `.cov` file next to each script that ran, and how much of them did
+/// to stderr
+fn write_coverage(coverage: &Coverage) {
+    if let Err(err) = std::fs::write("lcov.info", coverage.lcov()) {
+        eprintln!("Could not write lcov.info: {err}");
     }
-
-    #[test]
-    fn call_native_fn() {
-        let src = r#"
-        var num = __dummy();"#;
-        let mut vm = VM::new();
-        interpret(&mut vm, src).unwrap();
-        let num_str = vm.get_string("num").as_non_null_ptr();
-        let value = vm.mem.globals.get(num_str);
-        assert_eq!(value, Some(Value::Number(420.0)));
-    }
-
-    #[test]
-    fn call_fn() {
-        let src = r#"
-            fun add420(num) {
-              return num + 420;
-            }
-
-            fun add69(num) {
-              return num + 69;
+    for file in coverage.files() {
+        let lines = coverage.lines(file);
+        let ran = lines.values().filter(|&&count| count > 0).count();
+        eprintln!("{file}: {ran} of {} lines ran", lines.len());
+        // Code from `-e` has no file to annotate
+        if let Ok(source) = std::fs::read_to_string(file) {
+            let path = format!("{file}.cov");
+            if let Err(err) = std::fs::write(&path, coverage.annotate(file, &source)) {
+                eprintln!("Could not write {path}: {err}");
             }
-
-            var num = add420(1);
-            num = add69(num);
-            num = add420(num);"#;
-        let mut vm = VM::new();
-        interpret(&mut vm, src).unwrap();
-        let num_str = vm.get_string("num").as_non_null_ptr();
-        let value = vm.mem.globals.get(num_str);
-        assert_eq!(value, Some(Value::Number(910.0)));
+        }
     }
+}
 
-    #[test]
-    fn print_fn() {
-        let src = r#"
-            fun bigNoob() {
-              print "OH YEAH";
-            }
-
-            print bigNoob;"#;
-        let mut vm = VM::new();
-        interpret(&mut vm, src).unwrap();
-    }
+/// Expose the script's arguments: `args()` returns how many there are and `args(i)` the
+/// i-th one, or nil if there is no such argument. `argv()` returns all of them as a list
+fn register_args(vm: &mut VM, script_args: Vec<String>) {
+    vm.script_args = script_args.clone();
+    vm.register_native("args", Arity::Variadic, move |vm, values| match values {
+        [] => Ok(Value::Number(script_args.len() as f64)),
+        [value] => match value.as_number() {
+            Some(i) => match script_args.get(i as usize) {
+                Some(arg) if i.fract() == 0.0 && i >= 0.0 => Ok(vm.create_string(arg)),
+                _ => Ok(Value::Nil),
+            },
+            None => Err("Argument index must be a number.".to_string()),
+        },
+        _ => Err(format!(
+            "Expected 0 or 1 arguments but got {}.",
+            values.len()
+        )),
+    });
+}
 
-    #[test]
-    fn if_stmt() {
-        let src = r#"
-            var noob = 420;
-            if (420 > 69) { noob = "NICE"; } else { noob = "NOT NICE"; }
-    "#;
-        let mut vm = VM::new();
-        interpret(&mut vm, src).unwrap();
+fn print_disassembly(vm_options: VmOptions, src: &str, color: bool) {
+    let mut vm = VM::with_options(VmOptions {
+        report_errors: false,
+        ..vm_options
+    });
+    let listing = compile(&mut vm, src).map(|script| script.disassemble());
+    report_error(&vm, &listing, Some(src), color);
+    exit_on_error(&listing);
+    print!("{}", listing.unwrap());
+}
 
-        let noob = vm.get_string("noob").as_non_null_ptr();
-        let top = vm.mem.globals.get(noob);
-        assert_eq!(top.unwrap().as_str(), Some("NICE"));
+fn print_ast(src: &str, format: AstFormat) {
+    match ast::parse(src) {
+        Ok(stmts) => match format {
+            AstFormat::Json => print!("{}", ast::to_json(&stmts)),
+            AstFormat::Sexp => print!("{}", ast::to_sexp(&stmts)),
+        },
+        Err(errors) => {
+            for error in errors {
+                eprintln!("{error}");
+            }
+            std::process::exit(65);
+        }
     }
+}
 
-    #[test]
-    fn if_else_stmt() {
-        let src = r#"
-            var noob = 420;
-            if (69 > 420) { noob = "wtf"; } else { noob = "NICE"; }
-    "#;
-        let mut vm = VM::new();
-        interpret(&mut vm, src).unwrap();
-
-        let noob = vm.get_string("noob").as_non_null_ptr();
-        let top = vm.mem.globals.get(noob);
-        assert_eq!(top.unwrap().as_str(), Some("NICE"));
+/// `loxide fmt`: rewrite the scripts in `paths` that aren't formatted, or with `check` list
+/// them. Returns whether they all were formatted already. Exits if a script doesn't parse
+fn format_files(paths: &[PathBuf], check: bool) -> std::io::Result<bool> {
+    let mut scripts = vec![];
+    for path in paths {
+        script_test::collect_scripts(path, &mut scripts)?;
     }
 
-    #[test]
-    fn while_loop() {
-        let src = r#"
-            var noob = 0;
-            while (noob < 10) {
-              noob = noob + 1;
+    let mut formatted = true;
+    for script in &scripts {
+        let source = std::fs::read_to_string(script)?;
+        let output = compile::fmt::format(&source).unwrap_or_else(|errors| {
+            for error in errors {
+                eprintln!("{}: {error}", script.display());
             }
-    "#;
-        let mut vm = VM::new();
-        interpret(&mut vm, src).unwrap();
+            std::process::exit(65);
+        });
+        if output == source {
+            continue;
+        }
+        formatted = false;
+        if check {
+            println!("{}", script.display());
+        } else {
+            std::fs::write(script, output)?;
+        }
+    }
+    Ok(formatted || !check)
+}
 
-        let noob = vm.get_string("noob").as_non_null_ptr();
-        let top = vm.mem.globals.get(noob);
-        assert_eq!(top, Some(Value::Number(10.0)));
+/// `loxide lint`: print the warnings for the scripts in `paths`, except for the `allow`ed
+/// rules. Returns whether there were none. Exits if a script doesn't parse
+fn lint_files(paths: &[PathBuf], allow: &[lint::Rule]) -> std::io::Result<bool> {
+    let mut scripts = vec![];
+    for path in paths {
+        script_test::collect_scripts(path, &mut scripts)?;
     }
 
-    #[test]
-    fn for_loop() {
-        let src = r#"
-            var noob = 420;
-            for (var x = 0; x < 10; x = x + 1) {
-              noob = x;
+    let mut count = 0;
+    for script in &scripts {
+        let source = std::fs::read_to_string(script)?;
+        let warnings = lint::lint(&source, allow).unwrap_or_else(|errors| {
+            for error in errors {
+                eprintln!("{}: {error}", script.display());
             }
-    "#;
-        let mut vm = VM::new();
-        interpret(&mut vm, src).unwrap();
-
-        // let noob = vm.get_string("global");
-        // let top = vm.mem.globals.get(noob);
-        // assert_eq!(top.unwrap().as_str(), Some("NICE"));
+            std::process::exit(65);
+        });
+        for warning in &warnings {
+            println!("{}: {warning}", script.display());
+        }
+        count += warnings.len();
     }
+    if count > 0 {
+        println!("{count} warnings");
+    }
+    Ok(count == 0)
+}
 
-    #[test]
-    fn locals() {
-        let src = r#"
-            var global = 420;
-            { var x = "HELLO"; x = "NICE"; global = x; }
-    "#;
-        let mut vm = VM::new();
-        interpret(&mut vm, src).unwrap();
-
-        let noob = vm.get_string("global").as_non_null_ptr();
-        let top = vm.mem.globals.get(noob);
-        assert_eq!(top.unwrap().as_str(), Some("NICE"));
+fn compile_file(vm_options: VmOptions, input: PathBuf, output: PathBuf) {
+    let string = read_source(&input);
+    let mut vm = VM::with_options(vm_options);
+    match compile(&mut vm, &string) {
+        Ok(script) => {
+            let bytes = script.serialize();
+            if let Err(err) = std::fs::write(&output, bytes) {
+                eprintln!("Could not write \"{}\": {err}", output.display());
+                std::process::exit(74);
+            }
+        }
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(65);
+        }
     }
+}
 
-    #[test]
-    fn string() {
-        let src = r#"var noob = "hello" + " sir" + " sir";"#;
-        let mut vm = VM::new();
-        interpret(&mut vm, src).unwrap();
+/// Compile a script, or load a `.loxc` file made by `loxide compile`. Exits if that fails
+fn load_file<P: AsRef<Path>>(vm: &mut VM, path: P) -> Script<'_> {
+    vm.script_path = Some(path.as_ref().to_path_buf());
+    let bytes = read_file(path.as_ref());
+    let script = if serialize::is_bytecode(&bytes) {
+        load_bytecode(vm, &bytes)
+    } else {
+        let src = utf8(path.as_ref(), &bytes);
+        compile(vm, src).map_err(|err| err.to_string())
+    };
+    script.unwrap_or_else(|err| {
+        eprintln!("{err}");
+        std::process::exit(65);
+    })
+}
 
-        let noob = vm.get_string("noob").as_non_null_ptr();
-        let top = vm.mem.globals.get(noob);
-        assert_eq!(top.unwrap().as_str(), Some("hello sir sir"));
+/// Run a script, or a `.loxc` file made by `loxide compile`. Errors are reported before they
+/// are returned. Exits if the file can't be read or isn't a script
+fn run_file<P: AsRef<Path>>(vm: &mut VM, path: P, color: bool) -> InterpretResult<Value> {
+    vm.script_path = Some(path.as_ref().to_path_buf());
+    let bytes = read_file(path.as_ref());
+    if !serialize::is_bytecode(&bytes) {
+        let src = utf8(path.as_ref(), &bytes);
+        let result = interpret(vm, src);
+        report_error(vm, &result, Some(src), color);
+        return result;
     }
 
-    #[test]
-    fn print() {
-        let src = r#"print 1 + 2;"#;
-        let mut vm = VM::new();
-        interpret(&mut vm, src).unwrap();
+    match load_bytecode(vm, &bytes) {
+        Ok(script) => {
+            let result = script.run();
+            report_error(vm, &result, None, color);
+            result
+        }
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(65);
+        }
     }
+}
 
-    #[test]
-    fn table() {
-        let mut mem = Mem::new();
-        let mut table = Table::new();
-
-        let key = mem.copy_string("bagel").as_non_null_ptr();
-        assert_eq!(table.set(key, Value::Number(420.0)), true);
-        assert_eq!(table.set(key, Value::Number(69.0)), false);
-        assert_eq!(table.get(key), Some(Value::Number(69.0)));
-        assert_eq!(table.delete(key), true);
-        assert_eq!(table.delete(key), false);
+/// The contents of the file at `path`. Exits with 74 if it can't be read, like clox
+fn read_file(path: &Path) -> Vec<u8> {
+    std::fs::read(path).unwrap_or_else(|err| {
+        eprintln!("Could not read \"{}\": {err}", path.display());
+        std::process::exit(74);
+    })
+}
 
-        Table::free(&mut table);
+/// The program piped to stdin. Exits with 74 if it can't be read, or 65 if it isn't UTF-8
+fn read_stdin() -> String {
+    let mut bytes = vec![];
+    if let Err(err) = std::io::stdin().lock().read_to_end(&mut bytes) {
+        eprintln!("Could not read stdin: {err}");
+        std::process::exit(74);
     }
+    utf8(Path::new("<stdin>"), &bytes).to_string()
+}
 
-    #[test]
-    fn ohshit() {
-        // let bytes = [0, 1, 2, 3];
-
-        println!("NOOB: {:?}", std::mem::size_of::<Token>());
-
-        let values = [0, 1, 2, 3, 4, 5];
-        println!(
-            "NICE: {:?}",
-            values.iter().take(3).rev().collect::<Vec<_>>()
-        );
-
-        // 0
-        // 1
-        // 2 ---
-        // 3 ---
-        // 4
-        // 5
-        //
-        // 6
-        // -2 to adjust for the 2 bytes for the jump offset
-        let mut chunk = [0, 1, 2, 3, 4, 5];
-        let offset = 2;
-        let jump = chunk.len() as u32 - offset - 2;
-
-        chunk[offset as usize] = (jump >> 8) as u8;
-        chunk[offset as usize + 1] = jump as u8;
+/// The script at `path`. Exits with 74 if it can't be read, or 65 if it isn't UTF-8
+fn read_source(path: &Path) -> String {
+    utf8(path, &read_file(path)).to_string()
+}
 
-        let val = ((chunk[offset as usize] as u16) << 8) | (chunk[offset as usize + 1] as u16);
+fn utf8<'a>(path: &Path, bytes: &'a [u8]) -> &'a str {
+    std::str::from_utf8(bytes).unwrap_or_else(|err| {
+        eprintln!("\"{}\" is not valid UTF-8: {err}", path.display());
+        std::process::exit(65);
+    })
+}
 
-        println!("{jump} NOOB: {chunk:?} JUMP: {val} {}", 2u16);
+/// Exit with the status clox uses for the error of `result`, if there is one: 65 for compile
+/// errors and 70 for runtime errors. The error should have been reported already
+fn exit_on_error<T>(result: &InterpretResult<T>) {
+    match result {
+        Ok(_) => (),
+        Err(InterpretError::CompileError(_)) => std::process::exit(65),
+        Err(_) => std::process::exit(70),
     }
+}
 
-    // #[test]
-    // fn miri_test() {
-    //     let mut obj = Box::into_raw(Box::new(69));
-    //     let mut obj2 = unsafe { obj.as_mut().unwrap() };
-    //     let foo = unsafe { *obj };
-    //     *obj2 = 9999;
-    // }
-    // #[test]
-    // fn miri_test2() {
-    //     let mut obj = Box::into_raw(Box::new(69));
-    //     let mut obj2 = unsafe { obj.as_mut().unwrap() };
-    //     unsafe {
-    //         *obj = 420;
-    //     };
-    //     *obj2 = 9999;
-    // }
+/// Print the error of `result` on stderr, quoting the script's source `src` for runtime errors
+/// unless they may have happened in an imported module. Functions don't know which file they
+/// come from, only the top-level code of the script surely is in `src`
+fn report_error<T>(vm: &VM, result: &InterpretResult<T>, src: Option<&str>, color: bool) {
+    let Err(error) = result else {
+        return;
+    };
+    let in_script = match error {
+        InterpretError::RuntimeError(error) | InterpretError::StackOverflow(error) => {
+            !vm.has_modules() || error.trace.len() == 1
+        }
+        _ => true,
+    };
+    eprint!(
+        "{}",
+        report::render(error, src.filter(|_| in_script), color)
+    );
 }
 
 fn _f(_a: i32, _b: i32) -> i32 {
@@ -1,17 +1,52 @@
 use std::{
-    alloc::{self, handle_alloc_error, Layout},
+    collections::{HashMap, HashSet},
     ops::{Deref, DerefMut},
-    ptr::{self, NonNull},
+    ptr::NonNull,
+    sync::{Arc, Mutex},
 };
 
 use crate::{
-    obj::{Obj, ObjList, ObjPunnable, ObjString},
+    globals::Globals,
+    obj::{AllocList, Obj, ObjKind, ObjPtrWrapper, ObjPunnable, ObjString, Operator, NO_SLOT},
     table::{ObjHash, Table},
-    value::Value,
+    value::{Unpacked, Value},
 };
 
 pub type Greystack = Vec<NonNull<Obj>>;
 
+/// Memory usage reported by [`Mem::heap_stats`], sizes are in bytes and only count the objects'
+/// headers like [`Mem::bytes_allocated`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeapStats {
+    pub bytes_allocated: usize,
+    /// Most bytes allocated at once so far
+    pub peak_bytes: usize,
+    pub gc_cycles: u64,
+    /// The heap size the next collection runs at
+    pub next_gc: usize,
+    /// Number of objects of each kind, in the order of [`ObjKind::ALL`]
+    pub objects: [(ObjKind, usize); ObjKind::ALL.len()],
+}
+
+impl HeapStats {
+    pub fn count(&self, kind: ObjKind) -> usize {
+        self.objects[kind as usize].1
+    }
+}
+
+impl std::fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{:>12} bytes allocated", self.bytes_allocated)?;
+        writeln!(f, "{:>12} bytes at peak", self.peak_bytes)?;
+        writeln!(f, "{:>12} bytes start the next collection", self.next_gc)?;
+        writeln!(f, "{:>12} collections", self.gc_cycles)?;
+        for (kind, count) in self.objects {
+            writeln!(f, "{count:>12} {}", kind.plural_name())?;
+        }
+        Ok(())
+    }
+}
+
 // Borrowed from https://github.com/ceronman/loxido/blob/a605c17e4d35bc75022e65387c200201704ec37c/src/gc.rs#L286
 // pub struct GlobalAllocator {
 //     bytes_allocated: usize,
@@ -43,35 +78,259 @@ pub type Greystack = Vec<NonNull<Obj>>;
 // #[global_allocator]
 // pub static GLOBAL: GlobalAllocator = GlobalAllocator { bytes_allocated: 0 };
 
+/// Controls when the garbage collector runs.
+#[derive(Debug, Clone, Copy)]
+pub struct GcConfig {
+    /// Number of bytes that can be allocated before the first collection
+    pub initial_threshold: usize,
+    /// After a collection, the next one is scheduled once the heap has grown
+    /// to `bytes_allocated * heap_grow_factor`
+    pub heap_grow_factor: usize,
+    /// Collect on every allocation, useful to shake out objects that aren't rooted
+    pub stress: bool,
+    /// Log allocations, marked objects, frees and collection summaries to stderr
+    pub log: bool,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        Self {
+            initial_threshold: 1024 * 1024,
+            heap_grow_factor: 2,
+            stress: cfg!(feature = "always_gc"),
+            log: cfg!(feature = "debug_gc"),
+        }
+    }
+}
+
+/// The characters of strings, shared by VMs so each of them doesn't keep its own copy of the
+/// same constants, see [`VmOptions::string_pool`](crate::VmOptions::string_pool).
+///
+/// Strings are only ever added, a VM's string objects point into the pool for as long as the VM
+/// holds on to it. That's why only the strings of compiled code (its constants and names) and
+/// the names of the natives go into the pool, see [`Mem::copy_constant`], the strings a script
+/// makes while it runs are the VM's own.
+#[derive(Debug, Default)]
+pub struct StringPool {
+    strings: Mutex<HashSet<Box<str>>>,
+}
+
+impl StringPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of distinct strings in the pool
+    pub fn len(&self) -> usize {
+        self.strings.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The pool's copy of `string`, which lives as long as the pool
+    fn chars(&self, string: &str) -> NonNull<u8> {
+        let mut strings = self.strings.lock().unwrap();
+        if let Some(pooled) = strings.get(string) {
+            return NonNull::from(pooled.as_bytes()).cast();
+        }
+        // Moving the box into the set doesn't move the characters
+        let pooled = Box::<str>::from(string);
+        let chars = NonNull::from(pooled.as_bytes()).cast();
+        strings.insert(pooled);
+        chars
+    }
+}
+
 pub struct Mem {
-    pub obj_list: ObjList,
-    pub globals: Table,
+    pub(crate) obj_list: AllocList,
+    pub globals: Globals,
+    /// Names of the globals declared with `const`, kept across compilations so later
+    /// scripts can't assign to them either
+    pub const_globals: Table,
+    /// Every string, so equal strings are the same object. Holds its strings weakly: a sweep
+    /// removes the ones nothing else reaches before freeing them
     pub interned_strings: Table,
     pub next_gc: usize,
     pub bytes_allocated: usize,
+    /// Most bytes allocated at the start of a collection, see [`Mem::heap_stats`]
+    pub peak_bytes: usize,
+    /// Collections run so far
+    pub gc_cycles: u64,
+    pub gc_config: GcConfig,
+    pub(crate) grey_stack: Greystack,
+
+    /// Interned "init" string, used to look up initializers. Lives here instead of the VM
+    /// so collections triggered by the compiler don't free it
+    pub(crate) init_string: Gc<ObjString>,
+    /// Interned method names of the [`Operator`]s, indexed by the operator
+    pub(crate) operator_names: Vec<Gc<ObjString>>,
+    /// Modules imported so far by the canonical path of their file, each file only runs once
+    pub modules: Table,
+    /// Where `copy_string` gets the characters of new strings from, instead of copying them
+    string_pool: Option<Arc<StringPool>>,
+    /// The objects handed to the host by their handle's slot, see [`Mem::export`]
+    handles: HashMap<u32, Gc<Obj>>,
+}
+
+/// How the host refers to an object: by its slot in the handle table and the generation of the
+/// slot when the object got it. Once the object is freed its slot goes to the next object with
+/// the next generation, so an old handle doesn't find the new object
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Handle {
+    pub slot: u32,
+    pub generation: u16,
+}
+
+/// The handle table's slots. They're shared by all heaps, so a handle from another heap never
+/// finds an object in this one: the slot isn't this heap's, or it was given up since
+static SLOTS: Mutex<Slots> = Mutex::new(Slots {
+    generations: Vec::new(),
+    free: Vec::new(),
+});
+
+struct Slots {
+    /// The generation of the object in each slot, or the one the next object gets if it's free
+    generations: Vec<u16>,
+    /// Slots whose object was freed
+    free: Vec<u32>,
 }
 
 impl Mem {
     pub fn new() -> Self {
-        Self {
+        Self::with_gc_config(GcConfig::default())
+    }
+
+    pub fn with_gc_config(gc_config: GcConfig) -> Self {
+        Self::with_string_pool(gc_config, None)
+    }
+
+    /// A heap whose string constants share their characters with other heaps using `pool`
+    pub fn with_string_pool(gc_config: GcConfig, string_pool: Option<Arc<StringPool>>) -> Self {
+        let mut mem = Self {
             obj_list: Default::default(),
-            globals: Table::new(),
+            globals: Globals::new(),
+            const_globals: Table::new(),
             interned_strings: Table::new(),
-            next_gc: 1024 * 1024,
+            next_gc: gc_config.initial_threshold,
             bytes_allocated: 0,
+            peak_bytes: 0,
+            gc_cycles: 0,
+            gc_config,
+            grey_stack: vec![],
+            // Set right below, we need `Mem` to exist to intern it. Never dereferenced
+            init_string: unsafe { Gc::new(NonNull::dangling()) },
+            operator_names: vec![],
+            modules: Table::new(),
+            string_pool,
+            handles: HashMap::new(),
+        };
+        mem.init_string = mem.copy_string("init");
+        mem.operator_names = Operator::ALL
+            .iter()
+            .map(|operator| mem.copy_string(operator.method_name()))
+            .collect();
+        mem
+    }
+
+    /// Run a full mark-and-sweep collection.
+    ///
+    /// `greystack` must already contain the roots owned by the caller (the VM's stack and
+    /// call frames, or the compiler's functions). Roots owned by `Mem` itself are marked here.
+    pub(crate) fn collect_garbage(&mut self, mut greystack: Greystack) {
+        let log = self.gc_config.log;
+        let before = self.bytes_allocated();
+        // The heap only grows between collections, so it's at its peak right now
+        self.peak_bytes = self.peak_bytes.max(before);
+        self.gc_cycles += 1;
+        if log {
+            eprintln!("-- gc begin");
+        }
+
+        self.globals.mark(&mut greystack);
+        self.const_globals.mark(&mut greystack);
+        self.modules.mark(&mut greystack);
+        Obj::mark(self.init_string.upcast(), &mut greystack);
+        for name in &self.operator_names {
+            Obj::mark(name.upcast(), &mut greystack);
+        }
+
+        Self::trace_references(&mut greystack, log);
+        self.sweep();
+
+        self.grey_stack = greystack;
+        self.next_gc = self.bytes_allocated() * self.gc_config.heap_grow_factor;
+
+        if log {
+            eprintln!("-- gc end");
+            eprintln!(
+                "   collected {} bytes (from {} to {}) next at {}",
+                before - self.bytes_allocated(),
+                before,
+                self.bytes_allocated(),
+                self.next_gc
+            );
         }
     }
 
-    #[cfg(feature = "always_gc")]
-    #[inline]
-    pub fn should_run_gc<T: Sized>(&self) -> bool {
-        true
+    fn trace_references(greystack: &mut Greystack, log: bool) {
+        // Every marked object passes through the greystack exactly once, so this is where
+        // marks get logged
+        while let Some(obj) = greystack.pop() {
+            if log {
+                let kind = unsafe { obj.as_ref().kind };
+                eprintln!(
+                    "{:?} mark {:?} {:?}",
+                    obj.as_ptr(),
+                    kind,
+                    ObjPtrWrapper(obj.as_ptr())
+                );
+            }
+            unsafe { Obj::blacken(obj, greystack) };
+        }
+    }
+
+    fn sweep(&mut self) {
+        let log = self.gc_config.log;
+
+        // Clear references to unmarked strings
+        self.interned_strings.remove_white();
+
+        // Now free all unmarked objects
+        let mut freed = 0;
+        let mut released = vec![];
+        self.obj_list.retain_mut(|obj| {
+            if obj.as_ref().is_marked {
+                obj.as_mut().is_marked = false;
+                return true;
+            }
+
+            if obj.as_ref().slot != NO_SLOT {
+                released.push(obj.as_ref().slot);
+            }
+            // Not the contents, the objects they refer to may have been freed already
+            if log {
+                let kind = obj.as_ref().kind;
+                let (ptr, size) = (obj.as_ptr(), kind.size());
+                eprintln!("{ptr:?} free {kind:?} ({size} bytes)");
+            }
+            // Safety:
+            // Unmarked objects aren't reachable, and the object leaves the list right below
+            freed += unsafe { Obj::free(obj.as_non_null_ptr()) };
+            false
+        });
+
+        self.bytes_allocated -= freed;
+        for slot in &released {
+            self.handles.remove(slot);
+        }
+        Slots::release(&released);
     }
 
-    #[cfg(not(feature = "always_gc"))]
     #[inline]
     pub fn should_run_gc<T: Sized>(&self) -> bool {
-        self.bytes_allocated() + std::mem::size_of::<T>() > self.next_gc
+        self.gc_config.stress || self.bytes_allocated() + std::mem::size_of::<T>() > self.next_gc
     }
 
     #[inline]
@@ -79,85 +338,208 @@ impl Mem {
         self.bytes_allocated
     }
 
+    /// `value` as the host gets it: objects are replaced by their [`Handle`], which
+    /// [`Mem::resolve`] turns back into the object as long as it isn't freed
+    pub(crate) fn export(&mut self, value: Value) -> Value {
+        let Unpacked::Obj(mut obj) = value.unpack() else {
+            return value;
+        };
+        if obj.slot == NO_SLOT {
+            let (slot, generation) = Slots::claim();
+            obj.slot = slot;
+            obj.generation = generation;
+            self.handles.insert(slot, obj);
+        }
+        Value::Handle(Handle {
+            slot: obj.slot,
+            generation: obj.generation,
+        })
+    }
+
+    /// The value the host handed back, `None` if its object was freed in the meantime. Objects
+    /// that aren't handles, or whose handle is from another heap, aren't found either
+    pub(crate) fn resolve(&self, value: Value) -> Option<Value> {
+        match value.unpack() {
+            Unpacked::Handle(handle) => match self.handles.get(&handle.slot) {
+                Some(&obj) if obj.generation == handle.generation => Some(Value::Obj(obj)),
+                _ => None,
+            },
+            Unpacked::Obj(_) => None,
+            _ => Some(value),
+        }
+    }
+
+    /// How big the heap is and what's on it. The objects are the ones that weren't freed yet,
+    /// including garbage the next collection frees
+    pub fn heap_stats(&self) -> HeapStats {
+        let mut objects = ObjKind::ALL.map(|kind| (kind, 0));
+        for obj in &self.obj_list {
+            objects[obj.as_ref().kind as usize].1 += 1;
+        }
+        HeapStats {
+            bytes_allocated: self.bytes_allocated,
+            peak_bytes: self.peak_bytes.max(self.bytes_allocated),
+            gc_cycles: self.gc_cycles,
+            next_gc: self.next_gc,
+            objects,
+        }
+    }
+
+    /// Add a string allocated by [`ObjString`] to the heap and intern it
+    ///
+    /// # Safety
+    ///
+    /// The string must not be on the heap yet, and nobody else may free it
     #[inline]
-    pub fn alloc_obj_string(&mut self, obj_string: ObjString) -> Gc<ObjString> {
-        let obj_string = self.alloc_obj(obj_string);
-        self.intern_string(obj_string.as_non_null_ptr());
+    unsafe fn adopt_string(&mut self, obj_string: NonNull<ObjString>) -> Gc<ObjString> {
+        // Safety: the string is alive until `Obj::free` is called on it in `sweep`
+        let obj_string = self.adopt(unsafe { Gc::new(obj_string) });
+        self.intern_string(obj_string);
         obj_string
     }
 
     #[inline]
-    pub fn alloc_obj<T: ObjPunnable>(&mut self, obj: T) -> Gc<T> {
-        let val = Gc::new(unsafe { NonNull::new_unchecked(Box::into_raw(Box::new(obj))) });
-        self.obj_list.push_front(val.cast());
+    pub(crate) fn alloc_obj<T: ObjPunnable>(&mut self, obj: T) -> Gc<T> {
+        // Safety:
+        // The box is a live `T` until `Obj::free` is called on it in `sweep`
+        let val = unsafe { Gc::new(NonNull::new_unchecked(Box::into_raw(Box::new(obj)))) };
+        self.adopt(val)
+    }
+
+    /// Put a new object on the list the sweep phase frees objects from
+    #[inline]
+    fn adopt<T: ObjPunnable>(&mut self, val: Gc<T>) -> Gc<T> {
+        self.obj_list.push_front(val.upcast());
 
         self.bytes_allocated += std::mem::size_of::<T>();
 
-        #[cfg(feature = "debug_gc")]
-        println!(
-            "{:?}: allocating {:?} bytes for {:?}",
-            val,
-            std::mem::size_of::<T>(),
-            unsafe { (*val.as_ptr()).kind() }
-        );
+        if self.gc_config.log {
+            eprintln!(
+                "{:?} allocate {} bytes for {:?}",
+                val.as_ptr(),
+                std::mem::size_of::<T>(),
+                unsafe { (*val.as_ptr()).kind() }
+            );
+        }
 
         val
     }
 
     #[inline]
-    pub fn intern_string(&mut self, obj_string: NonNull<ObjString>) {
+    pub(crate) fn intern_string(&mut self, obj_string: Gc<ObjString>) {
         self.interned_strings.set(obj_string, Value::Nil);
     }
 
-    pub fn copy_string(&mut self, string: &str) -> Gc<ObjString> {
+    pub(crate) fn copy_string(&mut self, string: &str) -> Gc<ObjString> {
+        self.copy_string_with(string, false)
+    }
+
+    /// Like [`Mem::copy_string`], but the characters go into the [`StringPool`] if there is
+    /// one. For the constants and names of compiled code, which other VMs are likely to use too
+    pub(crate) fn copy_constant(&mut self, string: &str) -> Gc<ObjString> {
+        self.copy_string_with(string, true)
+    }
+
+    fn copy_string_with(&mut self, string: &str, pool: bool) -> Gc<ObjString> {
         let hash = ObjHash::hash_string(string);
         match self.interned_strings.find_string(string, hash) {
             Some(interned) => return interned,
             None => (),
         };
 
-        // Allocating layout for zero length data is not allowed,
-        // documentation of `std::slice::from_raw_parts` says NonNull::dangling() is allowed
-        // for zero-length data
-        if string.is_empty() {
-            let empty_string = ObjString::new(NonNull::dangling(), 0, hash);
-            return self.alloc_obj_string(empty_string);
+        let obj_str = match &self.string_pool {
+            // Safety: the pool outlives the string, `self` holds on to it until all the objects
+            // are freed
+            Some(string_pool) if pool => unsafe {
+                ObjString::pooled(string_pool.chars(string), string.len() as u32, hash)
+            },
+            _ => ObjString::copy(string, hash),
+        };
+        // Safety: the string is new
+        unsafe { self.adopt_string(obj_str) }
+    }
+
+    /// Intern a string made with [`ObjString::concat`], unless there already is an equal one:
+    /// then `obj_string` is freed and the interned one is returned instead
+    ///
+    /// # Safety
+    ///
+    /// The string must not be on the heap yet, and nobody else may use it afterwards
+    pub(crate) unsafe fn take_string(&mut self, obj_string: NonNull<ObjString>) -> Gc<ObjString> {
+        // Safety: the string is alive until it's freed below
+        let (string, hash) = unsafe { (obj_string.as_ref().as_str(), obj_string.as_ref().hash) };
+        if let Some(interned) = self.interned_strings.find_string(string, hash) {
+            // Safety: the caller gave the string up
+            unsafe { ObjString::dealloc(obj_string) };
+            return interned;
         }
 
-        let layout = Layout::for_value(string.as_bytes());
-        let chars = unsafe { alloc::alloc(layout) };
-        unsafe {
-            ptr::copy_nonoverlapping(string.as_ptr(), chars, string.len());
+        // Safety: the string is new
+        unsafe { self.adopt_string(obj_string) }
+    }
+}
+
+impl Slots {
+    /// A free slot and its generation
+    fn claim() -> (u32, u16) {
+        let mut slots = SLOTS.lock().unwrap();
+        if let Some(slot) = slots.free.pop() {
+            return (slot, slots.generations[slot as usize]);
         }
-        let chars = match NonNull::new(chars) {
-            Some(ptr) => ptr,
-            None => handle_alloc_error(layout),
-        };
+        // `NO_SLOT` isn't a slot
+        let slot = u32::try_from(slots.generations.len())
+            .ok()
+            .filter(|&slot| slot != NO_SLOT)
+            .expect("fewer than 4 billion objects are handed to the host at once");
+        slots.generations.push(0);
+        (slot, 0)
+    }
 
-        let obj_str = ObjString::new(chars, string.len() as u32, hash);
-        self.alloc_obj_string(obj_str)
+    /// Give up `released`, their objects were freed. A slot that went through every generation
+    /// isn't used again
+    fn release(released: &[u32]) {
+        if released.is_empty() {
+            return;
+        }
+        let mut slots = SLOTS.lock().unwrap();
+        for &slot in released {
+            let generation = &mut slots.generations[slot as usize];
+            if let Some(next) = generation.checked_add(1) {
+                *generation = next;
+                slots.free.push(slot);
+            }
+        }
     }
 }
 
 impl Drop for Mem {
     fn drop(&mut self) {
-        // free obj list
+        // Safety:
+        // The objects are only freed here and in `sweep`, which removes them from the list
         for obj in self.obj_list.iter_mut() {
-            Obj::free(obj.as_non_null_ptr())
+            unsafe { Obj::free(obj.as_non_null_ptr()) };
         }
-
-        Table::free(&mut self.interned_strings);
-        Table::free(&mut self.globals);
+        Slots::release(&self.handles.keys().copied().collect::<Vec<_>>());
     }
 }
 
+/// Handle to an object on the heap of a [`Mem`].
+///
+/// Handles are only made by [`Mem`] when it allocates, so a `Gc` always points to an object of
+/// type `T`. It doesn't keep the object alive though: a handle that isn't reachable from the
+/// VM's roots (the stack, globals, ...) dangles after the next collection. That's why a `Gc`
+/// never leaves the crate, its code makes sure they're alive when it dereferences them. The
+/// host gets a [`Handle`] instead.
 #[repr(transparent)]
-pub struct Gc<T> {
+pub(crate) struct Gc<T> {
     inner: NonNull<T>,
 }
 
 impl<T> Gc<T> {
-    pub fn new(inner: NonNull<T>) -> Self {
+    /// # Safety
+    ///
+    /// `inner` must point to a live `T` allocated by [`Mem::alloc_obj`]
+    pub unsafe fn new(inner: NonNull<T>) -> Self {
         Self { inner }
     }
 
@@ -171,9 +553,24 @@ impl<T> Gc<T> {
         self.inner
     }
 
+    /// # Safety
+    ///
+    /// The object must be a `K`, or `K` must be [`Obj`], the header of every object
+    #[inline]
+    pub unsafe fn cast<K>(self) -> Gc<K> {
+        Gc {
+            inner: self.inner.cast(),
+        }
+    }
+}
+
+impl<T: ObjPunnable> Gc<T> {
+    /// The header of the object, for code that works on any kind of object
     #[inline]
-    pub fn cast<K>(self) -> Gc<K> {
-        Gc::new(self.inner.cast())
+    pub fn upcast(self) -> Gc<Obj> {
+        // Safety:
+        // Every `ObjPunnable` is `repr(C)` and starts with an `Obj`
+        unsafe { self.cast() }
     }
 }
 
@@ -0,0 +1,104 @@
+//! Finding and reading the modules `import` loads.
+//!
+//! The VM goes through a [`ModuleResolver`]: [`FileResolver`] reads files, relative to the
+//! importing file and then from a search path. Embedders can supply their own, e.g. to load
+//! modules from memory or an archive:
+//!
+//! ```
+//! use std::collections::HashMap;
+//!
+//! use loxide::{Loxide, Value, VmOptions};
+//!
+//! let modules = HashMap::from([("math.lox".to_string(), "var tau = 6.28;".to_string())]);
+//! let mut engine = Loxide::with_options(VmOptions {
+//!     module_resolver: Some(Box::new(modules)),
+//!     ..Default::default()
+//! });
+//! let tau = engine.eval("import math from \"math.lox\"; math.tau").unwrap();
+//! assert_eq!(tau, Value::Number(6.28));
+//! ```
+//!
+//! Modules can also be written in C, see [`native`].
+
+pub mod native;
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+/// Where the source of a module comes from.
+///
+/// Modules are identified by the id `resolve` returns, a module is only loaded once per id
+pub trait ModuleResolver: Send {
+    /// The id of the module `name` refers to in an `import`, or `None` if there is no such
+    /// module. `importer` is the id of the importing module, or the path of the script (see
+    /// [`VM::script_path`](crate::VM::script_path)) if it has one
+    fn resolve(&self, importer: Option<&str>, name: &str) -> Option<String>;
+
+    /// The source of the module `id`, the error message otherwise
+    fn load(&self, id: &str) -> Result<String, String>;
+}
+
+/// Modules from the file system, their ids are canonical paths.
+///
+/// A path is looked up relative to the directory of the importing file, or the working
+/// directory without one, and then in each of the search paths. Paths starting with `./` or
+/// `../` are only looked up relative to the importing file
+#[derive(Debug, Clone, Default)]
+pub struct FileResolver {
+    pub search_paths: Vec<PathBuf>,
+}
+
+impl FileResolver {
+    pub fn new(search_paths: Vec<PathBuf>) -> Self {
+        Self { search_paths }
+    }
+}
+
+impl ModuleResolver for FileResolver {
+    fn resolve(&self, importer: Option<&str>, name: &str) -> Option<String> {
+        find_file(importer, name, &self.search_paths)
+    }
+
+    fn load(&self, id: &str) -> Result<String, String> {
+        std::fs::read_to_string(id).map_err(|err| err.to_string())
+    }
+}
+
+/// Canonical path of the file `name`, looked up like [`FileResolver`] does
+fn find_file(importer: Option<&str>, name: &str, search_paths: &[PathBuf]) -> Option<String> {
+    let dir = importer
+        .and_then(|importer| Path::new(importer).parent())
+        .unwrap_or_else(|| Path::new(""));
+    let relative = name.starts_with("./") || name.starts_with("../");
+    let search_paths = if relative { &[] } else { search_paths };
+
+    std::iter::once(dir)
+        .chain(search_paths.iter().map(PathBuf::as_path))
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())?
+        .canonicalize()
+        .ok()
+        .map(|path| path.to_string_lossy().into_owned())
+}
+
+/// Modules in memory, by the exact name they are imported with
+impl ModuleResolver for HashMap<String, String> {
+    fn resolve(&self, _importer: Option<&str>, name: &str) -> Option<String> {
+        self.contains_key(name).then(|| name.to_string())
+    }
+
+    fn load(&self, id: &str) -> Result<String, String> {
+        self.get(id)
+            .cloned()
+            .ok_or_else(|| "no such module".to_string())
+    }
+}
+
+/// The directories in the `LOX_PATH` environment variable, separated like those in `PATH`
+pub fn lox_path() -> Vec<PathBuf> {
+    std::env::var_os("LOX_PATH")
+        .map(|paths| std::env::split_paths(&paths).collect())
+        .unwrap_or_default()
+}
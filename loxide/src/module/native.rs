@@ -0,0 +1,151 @@
+//! Modules implemented in shared libraries, imported with `import native "name";`.
+//!
+//! `name` is a path like `"./libjson.so"`, or a bare name like `"json"` for the platform's file
+//! name of that library (`libjson.so`, `libjson.dylib`, `json.dll`). It is looked up like the
+//! files of Lox modules, next to the importing file and then in the search paths.
+//!
+//! The library exports a [`LoxideModuleInit`] called `loxide_module_init`, declared in
+//! `include/loxide.h`, which registers the module's natives through the function it is passed.
+//! They become globals and the exports of the module:
+//!
+//! ```c
+//! #include "loxide.h"
+//!
+//! static int twice(void *user_data, const LoxideValue *args, size_t arg_count,
+//!                  LoxideValue *result) {
+//!     result->kind = LOXIDE_NUMBER;
+//!     result->number = args[0].number * 2;
+//!     return 0;
+//! }
+//!
+//! int loxide_module_init(void *module, LoxideRegisterFn register_native) {
+//!     return register_native(module, "twice", 1, twice, NULL) ? 0 : 1;
+//! }
+//! ```
+//!
+//! Loading a library runs arbitrary code, so the VM only does it with
+//! [`VmOptions::native_modules`](crate::VmOptions::native_modules). Libraries stay loaded until
+//! the process exits, natives can't outlive their code.
+
+use std::{
+    ffi::{c_char, c_int, c_void},
+    path::PathBuf,
+};
+
+use crate::{
+    ffi::{self, LoxideNativeFn},
+    native_fn::{Arity, HostFn},
+};
+
+/// Registers a native of the module being loaded, passed to `loxide_module_init`. Returns
+/// whether the name and arity were valid, see [`ffi::loxide_register_native`]
+pub type LoxideRegisterFn = unsafe extern "C" fn(
+    module: *mut c_void,
+    name: *const c_char,
+    arity: c_int,
+    native: LoxideNativeFn,
+    user_data: *mut c_void,
+) -> bool;
+
+/// The function a native module exports as `loxide_module_init`. It returns 0 after registering
+/// its natives, anything else fails the import
+pub type LoxideModuleInit =
+    unsafe extern "C" fn(module: *mut c_void, register_native: LoxideRegisterFn) -> c_int;
+
+/// A native registered by a module
+pub(crate) struct Native {
+    pub name: String,
+    pub arity: Arity,
+    pub function: Box<HostFn>,
+}
+
+unsafe extern "C" fn register(
+    module: *mut c_void,
+    name: *const c_char,
+    arity: c_int,
+    native: LoxideNativeFn,
+    user_data: *mut c_void,
+) -> bool {
+    // Safety: `natives` passes its list as `module`, and the module vouches for the rest
+    let natives = unsafe { &mut *module.cast::<Vec<Native>>() };
+    let Some((name, arity)) = (unsafe { ffi::signature(name, arity) }) else {
+        return false;
+    };
+    let function = unsafe { ffi::host_fn(native, user_data) };
+    natives.push(Native {
+        name: name.to_string(),
+        arity,
+        function: Box::new(function),
+    });
+    true
+}
+
+/// Call `init` and collect the natives it registers
+///
+/// # Safety
+///
+/// `init` has to behave like `loxide_module_init` is documented to, and its natives have to
+/// stay callable for as long as the result lives
+pub(crate) unsafe fn natives(init: LoxideModuleInit) -> Result<Vec<Native>, String> {
+    let mut natives: Vec<Native> = vec![];
+    let status = unsafe { init((&mut natives as *mut Vec<Native>).cast(), register) };
+    if status != 0 {
+        return Err(format!("loxide_module_init failed with {status}"));
+    }
+    Ok(natives)
+}
+
+/// Canonical path of the library `name`, see the [module docs](self)
+pub(crate) fn find(importer: Option<&str>, name: &str, search_paths: &[PathBuf]) -> Option<String> {
+    use std::env::consts::{DLL_PREFIX, DLL_SUFFIX};
+
+    if name.contains('/') || name.ends_with(DLL_SUFFIX) {
+        return super::find_file(importer, name, search_paths);
+    }
+    super::find_file(
+        importer,
+        &format!("{DLL_PREFIX}{name}{DLL_SUFFIX}"),
+        search_paths,
+    )
+}
+
+/// Load the library at `path` and look up its `loxide_module_init`
+#[cfg(unix)]
+pub(crate) fn open(path: &str) -> Result<LoxideModuleInit, String> {
+    use std::ffi::{CStr, CString};
+
+    const RTLD_NOW: c_int = 2;
+
+    #[cfg_attr(target_os = "linux", link(name = "dl"))]
+    extern "C" {
+        fn dlopen(filename: *const c_char, flags: c_int) -> *mut c_void;
+        fn dlsym(handle: *mut c_void, symbol: *const c_char) -> *mut c_void;
+        fn dlerror() -> *mut c_char;
+    }
+
+    let path = CString::new(path).map_err(|_| "the path contains NUL".to_string())?;
+    // Safety: both strings are NUL-terminated. `dlerror` returns null or a string that is valid
+    // until the next call, which is copied right away
+    unsafe {
+        let handle = dlopen(path.as_ptr(), RTLD_NOW);
+        if handle.is_null() {
+            let error = dlerror();
+            if error.is_null() {
+                return Err("dlopen failed".to_string());
+            }
+            return Err(CStr::from_ptr(error).to_string_lossy().into_owned());
+        }
+
+        let init = dlsym(handle, b"loxide_module_init\0".as_ptr().cast());
+        if init.is_null() {
+            return Err("it doesn't export loxide_module_init".to_string());
+        }
+        // The library is never closed, so the function stays valid
+        Ok(std::mem::transmute::<*mut c_void, LoxideModuleInit>(init))
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn open(_path: &str) -> Result<LoxideModuleInit, String> {
+    Err("native modules are only supported on Unix".to_string())
+}
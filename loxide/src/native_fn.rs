@@ -1,14 +1,56 @@
-use std::fmt::Debug;
+use std::{
+    env,
+    fmt::Debug,
+    fs,
+    io::{self, BufRead, Write},
+    process, thread,
+    time::Duration,
+};
 
-use crate::value::Value;
+use crate::{
+    mem::Gc,
+    obj::{GeneratorState, ObjChannel, ObjList, ObjMap},
+    pool::{Channel, Snapshot},
+    table::Key,
+    value::{display::ValueWrapper, TypeError, Unpacked, Value},
+    vm::{Wait, VM},
+};
 
 pub type NativeFn = fn(&[Value]) -> Value;
 
-#[derive(Clone, Copy)]
+/// Native function installed by the host with [`VM::register_native`].
+///
+/// Returning `Err` raises a runtime error with the given message.
+pub type HostFn = dyn Fn(&mut VM, &[Value]) -> Result<Value, String> + Send;
+
+/// Native function that is part of the standard library, same signature as [`HostFn`] but
+/// without the boxing
+pub type BuiltinFn = fn(&mut VM, &[Value]) -> Result<Value, String>;
+
+/// Number of arguments a native function accepts, checked by the VM before calling it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Arity {
+    Fixed(u8),
+    Variadic,
+}
+
+impl From<u8> for Arity {
+    fn from(arity: u8) -> Self {
+        Arity::Fixed(arity)
+    }
+}
+
 pub enum NativeFnKind {
     Clock,
     Dummy,
+    /// `next(generator)`, the VM runs it since it resumes the generator's frame
+    Next,
+    /// `toString(value)`, the value as `print` shows it. The VM converts it since that can call
+    /// the value's `toString()` method
+    ToString,
     Custom(NativeFn),
+    Host(Box<HostFn>),
+    Builtin(BuiltinFn),
 }
 
 impl Debug for NativeFnKind {
@@ -16,28 +58,767 @@ impl Debug for NativeFnKind {
         match self {
             Self::Clock => write!(f, "Clock"),
             Self::Dummy => write!(f, "Dummy"),
+            Self::Next => write!(f, "Next"),
+            Self::ToString => write!(f, "ToString"),
             Self::Custom(arg0) => {
                 let fn_pointer: *const NativeFn = arg0;
                 f.debug_tuple("Custom").field(&fn_pointer).finish()
             }
+            Self::Host(host_fn) => {
+                let fn_pointer: *const HostFn = &**host_fn;
+                f.debug_tuple("Host").field(&fn_pointer).finish()
+            }
+            Self::Builtin(builtin) => {
+                let fn_pointer: *const BuiltinFn = builtin;
+                f.debug_tuple("Builtin").field(&fn_pointer).finish()
+            }
         }
     }
 }
 
 impl NativeFnKind {
-    pub fn call(&self, values: &[Value]) -> Value {
+    pub(crate) fn call(&self, vm: &mut VM, values: &[Value]) -> Result<Value, String> {
         match self {
-            NativeFnKind::Clock => Self::call_clock(values),
-            NativeFnKind::Dummy => Self::call_dummy(values),
-            NativeFnKind::Custom(native_fn) => native_fn(values),
+            NativeFnKind::Clock => Self::call_clock(vm),
+            NativeFnKind::Dummy => Ok(Self::call_dummy(values)),
+            NativeFnKind::Next => unreachable!("the VM resumes generators itself"),
+            NativeFnKind::ToString => unreachable!("the VM converts values itself"),
+            NativeFnKind::Custom(native_fn) => Ok(native_fn(values)),
+            NativeFnKind::Host(host_fn) => {
+                // The host can hold on to its arguments past a collection, so it gets handles
+                let args: Vec<_> = values.iter().map(|&value| vm.mem.export(value)).collect();
+                let value = host_fn(vm, &args)?;
+                vm.mem.resolve(value).ok_or_else(|| {
+                    "Native function returned an object that was collected.".to_string()
+                })
+            }
+            NativeFnKind::Builtin(builtin) => builtin(vm, values),
         }
     }
 
-    fn call_clock(_values: &[Value]) -> Value {
-        Value::Number(420.0)
+    /// Seconds since the VM was created
+    fn call_clock(vm: &mut VM) -> Result<Value, String> {
+        vm.replayed("clock", |vm| {
+            Ok(Snapshot::Number(vm.start.elapsed().as_secs_f64()))
+        })
     }
 
     fn call_dummy(_values: &[Value]) -> Value {
         Value::Number(420.0)
     }
 }
+
+/// Natives defined in every VM
+pub const BUILTINS: &[(&str, Arity, BuiltinFn)] = &[
+    ("len", Arity::Fixed(1), len),
+    ("push", Arity::Fixed(2), push),
+    ("pop", Arity::Fixed(1), pop),
+    ("insert", Arity::Fixed(3), insert),
+    ("remove", Arity::Fixed(2), remove),
+    ("keys", Arity::Fixed(1), keys),
+    ("values", Arity::Fixed(1), values),
+    ("has", Arity::Fixed(2), has),
+    ("type", Arity::Fixed(1), type_of),
+    ("toNumber", Arity::Fixed(1), to_number),
+    ("parseInt", Arity::Fixed(2), parse_int),
+    ("done", Arity::Fixed(1), done),
+    ("spawn", Arity::Fixed(1), spawn),
+    ("Channel", Arity::Fixed(0), channel),
+    ("send", Arity::Fixed(2), send),
+    ("recv", Arity::Fixed(1), recv),
+];
+
+/// Math natives, only defined with [`VmOptions::math`](crate::vm::VmOptions::math)
+pub const MATH: &[(&str, Arity, BuiltinFn)] = &[
+    ("sqrt", Arity::Fixed(1), sqrt),
+    ("abs", Arity::Fixed(1), abs),
+    ("floor", Arity::Fixed(1), floor),
+    ("ceil", Arity::Fixed(1), ceil),
+    ("sin", Arity::Fixed(1), sin),
+    ("cos", Arity::Fixed(1), cos),
+    ("pow", Arity::Fixed(2), pow),
+    ("min", Arity::Variadic, min),
+    ("max", Arity::Variadic, max),
+];
+
+/// String natives, only defined with [`VmOptions::strings`](crate::vm::VmOptions::strings).
+/// Indices count characters like `len` and indexing do
+pub const STRINGS: &[(&str, Arity, BuiltinFn)] = &[
+    ("length", Arity::Fixed(1), len),
+    ("substring", Arity::Fixed(3), substring),
+    ("indexOf", Arity::Fixed(2), index_of),
+    ("split", Arity::Fixed(2), split),
+    ("join", Arity::Fixed(2), join),
+    ("toUpper", Arity::Fixed(1), to_upper),
+    ("toLower", Arity::Fixed(1), to_lower),
+    ("trim", Arity::Fixed(1), trim),
+    ("replace", Arity::Fixed(3), replace),
+    ("chars", Arity::Fixed(1), chars),
+];
+
+/// Console and file natives, only defined with [`VmOptions::io`](crate::vm::VmOptions::io)
+pub const IO: &[(&str, Arity, BuiltinFn)] = &[
+    ("readLine", Arity::Fixed(0), read_line),
+    ("readFile", Arity::Fixed(1), read_file),
+    ("writeFile", Arity::Fixed(2), write_file),
+    ("appendFile", Arity::Fixed(2), append_file),
+    ("eprint", Arity::Fixed(1), eprint),
+];
+
+/// Time and random number natives, only defined with
+/// [`VmOptions::time`](crate::vm::VmOptions::time). `clock` is always defined
+pub const TIME: &[(&str, Arity, BuiltinFn)] = &[
+    ("now", Arity::Fixed(0), now),
+    ("sleep", Arity::Fixed(1), sleep),
+    ("random", Arity::Fixed(0), random),
+    ("randomInt", Arity::Fixed(2), random_int),
+];
+
+/// Natives reaching out of the VM into the process, only defined with
+/// [`VmOptions::allow_process`](crate::vm::VmOptions::allow_process)
+pub const PROCESS: &[(&str, Arity, BuiltinFn)] = &[
+    ("getenv", Arity::Fixed(1), getenv),
+    ("exit", Arity::Fixed(1), exit),
+    ("argv", Arity::Fixed(0), argv),
+];
+
+/// Globals defined along with [`MATH`]
+pub const MATH_CONSTANTS: &[(&str, f64)] = &[("pi", std::f64::consts::PI)];
+
+fn as_list(value: Value) -> Result<Gc<ObjList>, TypeError> {
+    value.as_list().ok_or_else(|| value.type_error("list"))
+}
+
+/// Check that `index` is an integer in `0..len`
+pub fn list_index(index: Value, len: usize) -> Result<usize, String> {
+    if let Unpacked::Int(index) = index.unpack() {
+        return usize::try_from(index)
+            .ok()
+            .filter(|&index| index < len)
+            .ok_or_else(|| "List index out of range.".to_string());
+    }
+    let Some(index) = index.as_number() else {
+        return Err("List index must be a number.".to_string());
+    };
+
+    if index.fract() != 0.0 {
+        return Err("List index must be an integer.".to_string());
+    }
+    if index < 0.0 || index >= len as f64 {
+        return Err("List index out of range.".to_string());
+    }
+
+    Ok(index as usize)
+}
+
+/// The part of `string` from the character at `start` to the one before `end`, either of which
+/// can be `nil` for the start or the end of the string
+pub fn slice_str(string: &str, start: Value, end: Value) -> Result<&str, String> {
+    let len = string.chars().count();
+    let bound = |index: Value, default: usize| match index.unpack() {
+        Unpacked::Nil => Ok(default),
+        _ => string_index(index, len, true),
+    };
+    let (start, end) = (bound(start, 0)?, bound(end, len)?);
+    if start > end {
+        return Err("Slice start must not be after its end.".to_string());
+    }
+
+    // Character indices to byte offsets
+    let offsets: Vec<usize> = string
+        .char_indices()
+        .map(|(offset, _)| offset)
+        .chain([string.len()])
+        .collect();
+    Ok(&string[offsets[start]..offsets[end]])
+}
+
+fn as_map(value: Value) -> Result<Gc<ObjMap>, TypeError> {
+    value.as_map().ok_or_else(|| value.type_error("map"))
+}
+
+fn as_channel(value: Value) -> Result<Gc<ObjChannel>, TypeError> {
+    value
+        .as_channel()
+        .ok_or_else(|| value.type_error("channel"))
+}
+
+/// Check that `key` can be stored in a map. `nil` marks empty table slots and NaN is never
+/// equal to itself, so neither could be found again
+pub(crate) fn map_key(key: Value) -> Result<Key, String> {
+    Key::new(key).ok_or_else(|| match key.is_nil() {
+        true => "Map key can't be nil.".to_string(),
+        false => "Map key can't be NaN.".to_string(),
+    })
+}
+
+/// Check that `index` is an integer in `-len..len`, negative indices count from the end. With
+/// `inclusive_end`, `len` itself is allowed too, for the end of a slice
+pub fn string_index(index: Value, len: usize, inclusive_end: bool) -> Result<usize, String> {
+    let Some(index) = index.as_number() else {
+        return Err("String index must be a number.".to_string());
+    };
+
+    if index.fract() != 0.0 {
+        return Err("String index must be an integer.".to_string());
+    }
+
+    let index = if index < 0.0 {
+        index + len as f64
+    } else {
+        index
+    };
+    let end = if inclusive_end { len + 1 } else { len };
+    if index < 0.0 || index >= end as f64 {
+        return Err("String index out of range.".to_string());
+    }
+
+    Ok(index as usize)
+}
+
+/// `len(value)`, number of items in a list or map, or characters in a string
+fn len(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    if let Some(list) = args[0].as_list() {
+        return Ok(Value::number(list.items.len() as f64));
+    }
+    if let Some(map) = args[0].as_map() {
+        return Ok(Value::number(map.entries.len() as f64));
+    }
+
+    let string: &str = args[0].try_as_str()?;
+    Ok(Value::number(string.chars().count() as f64))
+}
+
+/// `push(list, value)`, append to the end of the list
+fn push(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let mut list = as_list(args[0])?;
+    list.items.push(args[1]);
+    Ok(Value::Nil)
+}
+
+/// `pop(list)`, remove and return the last item
+fn pop(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let mut list = as_list(args[0])?;
+    list.items
+        .pop()
+        .ok_or_else(|| "Can't pop from an empty list.".to_string())
+}
+
+/// `insert(list, index, value)`, insert before `index`, which may be the length of the list
+fn insert(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let mut list = as_list(args[0])?;
+    let len = list.items.len();
+    let index = list_index(args[1], len + 1)?;
+    list.items.insert(index, args[2]);
+    Ok(Value::Nil)
+}
+
+/// `remove(list, index)`, remove and return the item at `index`
+fn remove(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let mut list = as_list(args[0])?;
+    let index = list_index(args[1], list.items.len())?;
+    Ok(list.items.remove(index))
+}
+
+/// `keys(map)`, list of the keys in unspecified order
+fn keys(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let map = as_map(args[0])?;
+    let keys = map.entries.iter().map(|entry| entry.key).collect();
+    // The keys are still rooted through the map, which is an argument
+    Ok(Value::Obj(vm.alloc_obj(ObjList::new(keys)).upcast()))
+}
+
+/// `values(map)`, list of the values in the same order as `keys(map)`
+fn values(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let map = as_map(args[0])?;
+    let values = map.entries.iter().map(|entry| entry.value).collect();
+    Ok(Value::Obj(vm.alloc_obj(ObjList::new(values)).upcast()))
+}
+
+/// `has(map, key)`
+fn has(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let map = as_map(args[0])?;
+    let value = Key::new(args[1]).and_then(|key| map.entries.get_value(key));
+    Ok(Value::Bool(value.is_some()))
+}
+
+/// `sqrt(x)`
+fn sqrt(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Number(args[0].try_as_number()?.sqrt()))
+}
+
+/// `abs(x)`
+fn abs(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Number(args[0].try_as_number()?.abs()))
+}
+
+/// `floor(x)`
+fn floor(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Number(args[0].try_as_number()?.floor()))
+}
+
+/// `ceil(x)`
+fn ceil(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Number(args[0].try_as_number()?.ceil()))
+}
+
+/// `sin(x)`, in radians
+fn sin(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Number(args[0].try_as_number()?.sin()))
+}
+
+/// `cos(x)`, in radians
+fn cos(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Number(args[0].try_as_number()?.cos()))
+}
+
+/// `pow(base, exponent)`
+fn pow(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let base = args[0].try_as_number()?;
+    let exponent = args[1].try_as_number()?;
+    Ok(Value::Number(base.powf(exponent)))
+}
+
+/// `min(x, ...)`, smallest of one or more numbers
+fn min(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    fold_numbers(args, f64::min)
+}
+
+/// `max(x, ...)`, largest of one or more numbers
+fn max(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    fold_numbers(args, f64::max)
+}
+
+fn fold_numbers(args: &[Value], f: fn(f64, f64) -> f64) -> Result<Value, String> {
+    let Some((&first, rest)) = args.split_first() else {
+        return Err("Expected at least 1 argument but got 0.".to_string());
+    };
+
+    let mut acc = first.try_as_number()?;
+    for &arg in rest {
+        acc = f(acc, arg.try_as_number()?);
+    }
+    Ok(Value::Number(acc))
+}
+
+/// `type(value)`, name of the value's type: "nil", "bool", "number", "string", "function",
+/// "class", "instance", "list" or "map"
+fn type_of(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    Ok(vm.alloc_string(args[0].type_name()))
+}
+
+/// `toNumber(string)`, a decimal number like `-1.5e3`, `nil` if the string isn't one. Numbers
+/// are returned as they are
+fn to_number(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    if args[0].is_number() {
+        return Ok(args[0]);
+    }
+
+    let string: &str = args[0].try_as_str()?;
+    let string = string.trim();
+    // `f64::from_str` also accepts things like "inf" and "NaN"
+    let is_decimal = string
+        .bytes()
+        .all(|c| c.is_ascii_digit() || matches!(c, b'+' | b'-' | b'.' | b'e' | b'E'));
+    match string.parse() {
+        Ok(number) if is_decimal => Ok(Value::Number(number)),
+        _ => Ok(Value::Nil),
+    }
+}
+
+/// `parseInt(string, radix)`, an integer with an optional sign in base 2 to 36, `nil` if the
+/// string isn't one
+fn parse_int(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let string: &str = args[0].try_as_str()?;
+    let radix = args[1].try_as_number()?;
+    if radix.fract() != 0.0 || !(2.0..=36.0).contains(&radix) {
+        return Err("Radix must be an integer from 2 to 36.".to_string());
+    }
+    let radix = radix as u32;
+
+    let string = string.trim();
+    let (sign, digits) = match string.as_bytes().first() {
+        Some(b'-') => (-1.0, &string[1..]),
+        Some(b'+') => (1.0, &string[1..]),
+        _ => (1.0, string),
+    };
+    if digits.is_empty() {
+        return Ok(Value::Nil);
+    }
+
+    // Folding into a float instead of parsing an integer, so big numbers don't overflow
+    let mut value = 0.0;
+    for c in digits.chars() {
+        let Some(digit) = c.to_digit(radix) else {
+            return Ok(Value::Nil);
+        };
+        value = value * radix as f64 + digit as f64;
+    }
+    Ok(Value::Number(sign * value))
+}
+
+/// `done(generator)`, whether it returned, `next` gives `nil` from then on
+fn done(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let generator = args[0]
+        .as_generator()
+        .ok_or_else(|| args[0].type_error("generator"))?;
+    Ok(Value::Bool(generator.state == GeneratorState::Done))
+}
+
+/// `spawn(fn)`, start a task that runs `fn` once the script returns
+fn spawn(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    vm.spawn(args[0])
+}
+
+/// `Channel()`, a new channel to `send` to and `await` or `recv` from
+fn channel(vm: &mut VM, _args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Obj(
+        vm.alloc_obj(ObjChannel::new(Channel::new())).upcast(),
+    ))
+}
+
+/// `send(channel, value)`, a copy of `value`. Never waits
+fn send(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let channel = as_channel(args[0])?;
+    channel.channel.send(Snapshot::transfer(args[1])?);
+    Ok(Value::Nil)
+}
+
+/// `recv(channel)`, the next value sent to it. Blocks the thread until there is one, unlike
+/// `await`
+fn recv(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let channel = as_channel(args[0])?;
+    let snapshot = match channel.channel.try_recv() {
+        Some(snapshot) => snapshot,
+        // Only this VM could send, and it's waiting
+        None if !channel.channel.is_shared() => {
+            return Err("The channel is empty and nothing else can send to it.".to_string())
+        }
+        None if cfg!(target_arch = "wasm32") => {
+            return Err("Can't wait for a channel in WebAssembly.".to_string())
+        }
+        None => channel.channel.recv(),
+    };
+    Ok(snapshot.rebuild(vm))
+}
+
+/// `substring(string, start, end)`, like `string[start:end]`
+fn substring(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let string: &str = args[0].try_as_str()?;
+    let substring = slice_str(string, args[1], args[2])?;
+    Ok(vm.alloc_string(substring))
+}
+
+/// `indexOf(string, substring)` or `indexOf(list, value)`, index of the first occurrence or -1
+fn index_of(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let index = if let Some(list) = args[0].as_list() {
+        list.items.iter().position(|&item| item == args[1])
+    } else {
+        let string: &str = args[0].try_as_str()?;
+        let needle: &str = args[1].try_as_str()?;
+        string
+            .find(needle)
+            .map(|offset| string[..offset].chars().count())
+    };
+
+    Ok(Value::number(index.map_or(-1.0, |index| index as f64)))
+}
+
+/// `split(string, separator)`, list of the parts between separators. An empty separator
+/// splits into characters
+fn split(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let string: &str = args[0].try_as_str()?;
+    let separator: &str = args[1].try_as_str()?;
+    if separator.is_empty() {
+        return chars(vm, args);
+    }
+
+    Ok(string_list(vm, string.split(separator)))
+}
+
+/// `join(list, separator)`, items that aren't strings are converted like `toString` does
+fn join(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let list = as_list(args[0])?;
+    let separator: &str = args[1].try_as_str()?;
+
+    let mut joined = String::new();
+    for (i, item) in list.items.iter().enumerate() {
+        if i > 0 {
+            joined.push_str(separator);
+        }
+        joined.push_str(&ValueWrapper(*item).to_string());
+    }
+    Ok(vm.alloc_string(&joined))
+}
+
+/// `toUpper(string)`
+fn to_upper(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let string: &str = args[0].try_as_str()?;
+    Ok(vm.alloc_string(&string.to_uppercase()))
+}
+
+/// `toLower(string)`
+fn to_lower(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let string: &str = args[0].try_as_str()?;
+    Ok(vm.alloc_string(&string.to_lowercase()))
+}
+
+/// `trim(string)`, without leading and trailing whitespace
+fn trim(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let string: &str = args[0].try_as_str()?;
+    Ok(vm.alloc_string(string.trim()))
+}
+
+/// `replace(string, from, to)`, replaces every occurrence of `from`
+fn replace(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let string: &str = args[0].try_as_str()?;
+    let from: &str = args[1].try_as_str()?;
+    let to: &str = args[2].try_as_str()?;
+    if from.is_empty() {
+        return Err("Can't replace an empty string.".to_string());
+    }
+    Ok(vm.alloc_string(&string.replace(from, to)))
+}
+
+/// `chars(string)`, list of the characters as strings
+fn chars(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let string: &str = args[0].try_as_str()?;
+    let chars = string
+        .char_indices()
+        .map(|(offset, c)| &string[offset..offset + c.len_utf8()]);
+    Ok(string_list(vm, chars))
+}
+
+/// Allocate a list of new strings
+fn string_list<'a>(vm: &mut VM, strings: impl Iterator<Item = &'a str>) -> Value {
+    let mut list = vm.alloc_obj(ObjList::new(vec![]));
+    // Keep the list rooted while its strings are allocated
+    let list_value = Value::Obj(list.upcast());
+    vm.push(list_value);
+    for string in strings {
+        let string = vm.alloc_string(string);
+        list.items.push(string);
+    }
+    vm.pop();
+    list_value
+}
+
+/// `readLine()`, a line from stdin without the line ending, `nil` at the end of the input
+fn read_line(vm: &mut VM, _args: &[Value]) -> Result<Value, String> {
+    vm.replayed("readLine", |_vm| {
+        let mut line = String::new();
+        let read = io::stdin()
+            .lock()
+            .read_line(&mut line)
+            .map_err(|err| format!("Could not read line: {err}."))?;
+        if read == 0 {
+            return Ok(Snapshot::Nil);
+        }
+
+        let line = line.strip_suffix('\n').unwrap_or(&line);
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        Ok(Snapshot::String(line.to_string()))
+    })
+}
+
+/// `readFile(path)`, the whole file as a string
+fn read_file(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let path: &str = args[0].try_as_str()?;
+    let contents =
+        fs::read_to_string(path).map_err(|err| format!("Could not read file '{path}': {err}."))?;
+    Ok(vm.alloc_string(&contents))
+}
+
+/// `writeFile(path, text)`, creates or truncates the file
+fn write_file(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let path: &str = args[0].try_as_str()?;
+    let text: &str = args[1].try_as_str()?;
+    fs::write(path, text).map_err(|err| format!("Could not write file '{path}': {err}."))?;
+    Ok(Value::Nil)
+}
+
+/// `appendFile(path, text)`, creates the file if it doesn't exist
+fn append_file(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let path: &str = args[0].try_as_str()?;
+    let text: &str = args[1].try_as_str()?;
+    fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(text.as_bytes()))
+        .map_err(|err| format!("Could not append to file '{path}': {err}."))?;
+    Ok(Value::Nil)
+}
+
+/// `eprint(value)`, like `print` but to stderr
+fn eprint(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    writeln!(vm.stderr, "{}", ValueWrapper(args[0]))
+        .map_err(|err| format!("Could not print: {err}."))?;
+    Ok(Value::Nil)
+}
+
+/// `now()`, milliseconds since the unix epoch
+fn now(vm: &mut VM, _args: &[Value]) -> Result<Value, String> {
+    vm.replayed("now", |_vm| {
+        let since_epoch =
+            unix_time().ok_or_else(|| "System clock is before the unix epoch.".to_string())?;
+        Ok(Snapshot::Number(since_epoch.as_millis() as f64))
+    })
+}
+
+/// `sleep(ms)`. A task lets the others run meanwhile, anything else blocks the thread
+fn sleep(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let ms = args[0].try_as_number()?;
+    if ms.is_nan() || ms < 0.0 {
+        return Err("Sleep duration must not be negative.".to_string());
+    }
+    let too_long = || "Sleep duration is too long.".to_string();
+    let duration = Duration::try_from_secs_f64(ms / 1000.0).map_err(|_| too_long())?;
+    if vm.in_task() {
+        let wake = vm
+            .start
+            .elapsed()
+            .checked_add(duration)
+            .ok_or_else(too_long)?;
+        vm.block(Wait::Sleep(wake));
+        return Ok(Value::Nil);
+    }
+    if cfg!(target_arch = "wasm32") {
+        return Err("Can't sleep in WebAssembly.".to_string());
+    }
+    thread::sleep(duration);
+    Ok(Value::Nil)
+}
+
+/// `random()`, a number in `[0, 1)` from the VM's generator, see [`VM::seed_random`]
+fn random(vm: &mut VM, _args: &[Value]) -> Result<Value, String> {
+    vm.replayed("random", |vm| Ok(Snapshot::Number(vm.rng.next_f64())))
+}
+
+/// `randomInt(lo, hi)`, an integer in `[lo, hi]`
+fn random_int(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let lo = args[0].try_as_number()?;
+    let hi = args[1].try_as_number()?;
+    if lo.fract() != 0.0 || hi.fract() != 0.0 {
+        return Err("Bounds must be integers.".to_string());
+    }
+    if lo > hi {
+        return Err("Lower bound must not be greater than the upper bound.".to_string());
+    }
+
+    // The bounds are checked first, a replay fails on the same calls
+    vm.replayed("randomInt", |vm| {
+        let offset = (vm.rng.next_f64() * (hi - lo + 1.0)).floor();
+        Ok(Snapshot::Number(lo + offset))
+    })
+}
+
+/// SplitMix64, small and fast with good enough output for scripts. Not cryptographically secure
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    /// Seeded from the current time
+    pub fn from_time() -> Self {
+        let nanos = unix_time().map_or(0, |since_epoch| since_epoch.as_nanos() as u64);
+        Self::new(nanos)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `[0, 1)`, using the top 53 bits
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// `getenv(name)`, value of the environment variable or `nil` if it isn't set
+fn getenv(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let name: &str = args[0].try_as_str()?;
+    match env::var(name) {
+        Ok(value) => Ok(vm.alloc_string(&value)),
+        Err(_) => Ok(Value::Nil),
+    }
+}
+
+/// `exit(code)`, ends the process right away
+fn exit(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let code = args[0].try_as_number()?;
+    if code.fract() != 0.0 {
+        return Err("Exit code must be an integer.".to_string());
+    }
+    // The OS only keeps the lowest byte of the code
+    if !(0.0..=255.0).contains(&code) {
+        return Err("Exit code must be between 0 and 255.".to_string());
+    }
+
+    let _ = vm.stdout.flush();
+    let _ = vm.stderr.flush();
+    process::exit(code as i32)
+}
+
+/// `argv()`, list of the arguments passed to the script, see [`VM::script_args`]
+fn argv(vm: &mut VM, _args: &[Value]) -> Result<Value, String> {
+    let script_args = std::mem::take(&mut vm.script_args);
+    let list = string_list(vm, script_args.iter().map(String::as_str));
+    vm.script_args = script_args;
+    Ok(list)
+}
+
+/// Time since the unix epoch, `None` if the system clock is set before it
+#[cfg(not(target_arch = "wasm32"))]
+pub fn unix_time() -> Option<Duration> {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    SystemTime::now().duration_since(UNIX_EPOCH).ok()
+}
+
+/// `SystemTime` panics in browsers, ask JavaScript instead
+#[cfg(target_arch = "wasm32")]
+pub fn unix_time() -> Option<Duration> {
+    #[cfg(feature = "wasm")]
+    let millis = crate::wasm::date_now();
+    // Without JavaScript to ask time stands still
+    #[cfg(not(feature = "wasm"))]
+    let millis = 0.0;
+    Some(Duration::from_secs_f64(millis / 1000.0))
+}
+
+/// Where `clock()` counts from. An `Instant`, except in browsers where there is none and the
+/// wall clock has to do
+#[derive(Debug, Clone, Copy)]
+pub struct Clock {
+    #[cfg(not(target_arch = "wasm32"))]
+    start: std::time::Instant,
+    #[cfg(target_arch = "wasm32")]
+    start: Duration,
+}
+
+impl Clock {
+    pub fn start() -> Self {
+        Self {
+            #[cfg(not(target_arch = "wasm32"))]
+            start: std::time::Instant::now(),
+            #[cfg(target_arch = "wasm32")]
+            start: unix_time().unwrap_or_default(),
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        #[cfg(not(target_arch = "wasm32"))]
+        return self.start.elapsed();
+        #[cfg(target_arch = "wasm32")]
+        return unix_time().unwrap_or_default().saturating_sub(self.start);
+    }
+}
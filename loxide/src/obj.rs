@@ -1,19 +1,26 @@
 use std::{
-    alloc::{self, Layout},
+    alloc::{self, handle_alloc_error, Layout},
     collections::VecDeque,
-    ptr::NonNull,
+    ptr::{self, null_mut, NonNull},
     slice,
 };
 
 use crate::{
     chunk::Chunk,
+    globals::Globals,
     mem::{Gc, Greystack},
-    native_fn::NativeFnKind,
+    native_fn::{Arity, NativeFnKind},
+    pool::Channel,
     table::{ObjHash, Table},
-    value::Value,
+    value::{
+        display::{fmt_container, ValueWrapper},
+        Value,
+    },
+    vm::Handler,
 };
 
-pub type ObjList = VecDeque<Gc<Obj>>;
+/// Every object allocated by `Mem`, walked by the sweep phase
+pub(crate) type AllocList = VecDeque<Gc<Obj>>;
 
 /// This is to enable type-safe functions generic over types that are type punnable to Obj
 pub trait ObjPunnable: Sized {
@@ -65,6 +72,31 @@ impl ObjPunnable for ObjBoundMethod {
         ObjKind::BoundMethod
     }
 }
+impl ObjPunnable for ObjList {
+    fn kind(&self) -> ObjKind {
+        ObjKind::List
+    }
+}
+impl ObjPunnable for ObjMap {
+    fn kind(&self) -> ObjKind {
+        ObjKind::Map
+    }
+}
+impl ObjPunnable for ObjModule {
+    fn kind(&self) -> ObjKind {
+        ObjKind::Module
+    }
+}
+impl ObjPunnable for ObjGenerator {
+    fn kind(&self) -> ObjKind {
+        ObjKind::Generator
+    }
+}
+impl ObjPunnable for ObjChannel {
+    fn kind(&self) -> ObjKind {
+        ObjKind::Channel
+    }
+}
 
 #[repr(C)]
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -77,178 +109,421 @@ pub enum ObjKind {
     Class,
     Instance,
     BoundMethod,
+    List,
+    Map,
+    Module,
+    Generator,
+    Channel,
+}
+
+impl ObjKind {
+    pub const ALL: [ObjKind; 13] = [
+        ObjKind::Str,
+        ObjKind::Fn,
+        ObjKind::Native,
+        ObjKind::Closure,
+        ObjKind::Upvalue,
+        ObjKind::Class,
+        ObjKind::Instance,
+        ObjKind::BoundMethod,
+        ObjKind::List,
+        ObjKind::Map,
+        ObjKind::Module,
+        ObjKind::Generator,
+        ObjKind::Channel,
+    ];
+
+    /// What objects of this kind are called in reports, e.g. [`HeapStats`](crate::mem::HeapStats)
+    pub fn plural_name(self) -> &'static str {
+        match self {
+            ObjKind::Str => "strings",
+            ObjKind::Fn => "functions",
+            ObjKind::Native => "natives",
+            ObjKind::Closure => "closures",
+            ObjKind::Upvalue => "upvalues",
+            ObjKind::Class => "classes",
+            ObjKind::Instance => "instances",
+            ObjKind::BoundMethod => "bound methods",
+            ObjKind::List => "lists",
+            ObjKind::Map => "maps",
+            ObjKind::Module => "modules",
+            ObjKind::Generator => "generators",
+            ObjKind::Channel => "channels",
+        }
+    }
+
+    /// Size of the object's header struct, this is what `Mem::bytes_allocated` accounts for
+    pub fn size(self) -> usize {
+        match self {
+            ObjKind::Str => std::mem::size_of::<ObjString>(),
+            ObjKind::Fn => std::mem::size_of::<ObjFunction>(),
+            ObjKind::Native => std::mem::size_of::<ObjNative>(),
+            ObjKind::Closure => std::mem::size_of::<ObjClosure>(),
+            ObjKind::Upvalue => std::mem::size_of::<ObjUpvalue>(),
+            ObjKind::Class => std::mem::size_of::<ObjClass>(),
+            ObjKind::Instance => std::mem::size_of::<ObjInstance>(),
+            ObjKind::BoundMethod => std::mem::size_of::<ObjBoundMethod>(),
+            ObjKind::List => std::mem::size_of::<ObjList>(),
+            ObjKind::Map => std::mem::size_of::<ObjMap>(),
+            ObjKind::Module => std::mem::size_of::<ObjModule>(),
+            ObjKind::Generator => std::mem::size_of::<ObjGenerator>(),
+            ObjKind::Channel => std::mem::size_of::<ObjChannel>(),
+        }
+    }
 }
 
 #[repr(C)]
 pub struct Obj {
     pub kind: ObjKind,
     pub is_marked: bool,
+    /// Generation of the handle slot the object is in, see [`Handle`](crate::mem::Handle)
+    pub(crate) generation: u16,
+    /// The object's slot in the heap's handle table, [`NO_SLOT`] until it's handed to the host
+    pub(crate) slot: u32,
 }
 
-pub struct ObjPtrWrapper(pub *mut Obj);
+/// [`Obj::slot`] of objects the host was never handed
+pub(crate) const NO_SLOT: u32 = u32::MAX;
+
+pub(crate) struct ObjPtrWrapper(pub *mut Obj);
 
 #[repr(C)]
 pub struct ObjNative {
     pub obj: Obj,
+    /// The global it was defined as, for printing
+    pub(crate) name: Gc<ObjString>,
     pub function: NativeFnKind,
+    pub arity: Arity,
 }
 
 #[repr(C)]
 pub struct ObjUpvalue {
     pub obj: Obj,
-    pub location: NonNull<Value>,
+    pub(crate) location: NonNull<Value>,
     // open upvalues are in a linkedlist so we can traverse that to reuse upvalues
-    pub next: *mut ObjUpvalue,
+    pub(crate) next: *mut ObjUpvalue,
     pub closed: Value,
 }
 
 #[repr(C)]
 pub struct ObjClosure {
     pub obj: Obj,
-    pub function: Gc<ObjFunction>,
-    pub upvalues: NonNull<*mut ObjUpvalue>,
+    pub(crate) function: Gc<ObjFunction>,
+    pub(crate) upvalues: NonNull<*mut ObjUpvalue>,
     pub upvalue_count: u8,
 }
 
 #[repr(C)]
 pub struct ObjClass {
     pub obj: Obj,
-    pub name: NonNull<ObjString>,
+    pub(crate) name: Gc<ObjString>,
     pub methods: Table,
+    /// `get` accessors by property name, called when an instance has no such field
+    pub getters: Table,
+    /// `set` accessors by property name, called instead of setting the field
+    pub setters: Table,
+    /// Field declarations in order, with the closure computing the field's initial value or
+    /// `nil`. The closures run for each new instance, before `init`
+    pub(crate) fields: Vec<(Gc<ObjString>, Value)>,
+    /// `static` methods, called on the class with `this` bound to it
+    pub static_methods: Table,
+    /// `static var` fields of the class itself
+    pub static_fields: Table,
 }
 
 #[repr(C)]
 pub struct ObjBoundMethod {
     pub obj: Obj,
     pub receiver: Value,
-    pub method: Gc<ObjClosure>,
+    pub(crate) method: Gc<ObjClosure>,
 }
 
 #[repr(C)]
 pub struct ObjInstance {
     pub obj: Obj,
-    pub class: Gc<ObjClass>,
+    pub(crate) class: Gc<ObjClass>,
     pub fields: Table,
 }
 
+/// Operators a class can overload by defining a method named after them, e.g. `__add(other)`
+/// for `+`. The method is looked up on the left operand, or the indexed value for `[]`, when it
+/// is an instance; its result is the result of the operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+    Negate,
+    Equal,
+    Less,
+    Greater,
+    /// `target[index]`
+    GetIndex,
+    /// `target[index] = value`, which evaluates to what the method returns
+    SetIndex,
+    /// Converting the instance to a string, see [`value::display`](crate::value::display)
+    ToString,
+}
+
+impl Operator {
+    pub const ALL: [Operator; 12] = [
+        Operator::Add,
+        Operator::Subtract,
+        Operator::Multiply,
+        Operator::Divide,
+        Operator::Modulo,
+        Operator::Negate,
+        Operator::Equal,
+        Operator::Less,
+        Operator::Greater,
+        Operator::GetIndex,
+        Operator::SetIndex,
+        Operator::ToString,
+    ];
+
+    pub fn method_name(self) -> &'static str {
+        match self {
+            Operator::Add => "__add",
+            Operator::Subtract => "__sub",
+            Operator::Multiply => "__mul",
+            Operator::Divide => "__div",
+            Operator::Modulo => "__mod",
+            Operator::Negate => "__neg",
+            Operator::Equal => "__eq",
+            Operator::Less => "__lt",
+            Operator::Greater => "__gt",
+            Operator::GetIndex => "__getitem",
+            Operator::SetIndex => "__setitem",
+            Operator::ToString => "toString",
+        }
+    }
+}
+
+/// Lox list value, `[1, 2, 3]`
+#[repr(C)]
+pub struct ObjList {
+    pub obj: Obj,
+    pub items: Vec<Value>,
+}
+
+/// Lox map value, `{"key": value}`. Keys can be any value but `nil` or NaN
+#[repr(C)]
+pub struct ObjMap {
+    pub obj: Obj,
+    pub entries: Table,
+}
+
+/// The namespace of an imported file, see [`Opcode::Import`](crate::chunk::Opcode::Import)
+#[repr(C)]
+pub struct ObjModule {
+    pub obj: Obj,
+    /// The path the module was first imported with
+    pub(crate) name: Gc<ObjString>,
+    /// The globals the module's code defines, which are its exports. Names it doesn't define
+    /// are looked up in the VM's globals, where the natives are
+    pub(crate) globals: Globals,
+}
+
+/// A call of a generator function. `next` or a `for` loop resume its frame until the next
+/// `yield`, in between the frame is kept here
+#[repr(C)]
+pub struct ObjGenerator {
+    pub obj: Obj,
+    pub(crate) closure: Gc<ObjClosure>,
+    pub state: GeneratorState,
+    /// Started by `spawn`, only the scheduler resumes it
+    pub task: bool,
+    /// Where the frame continues when it's resumed
+    pub(crate) instr_offset: u32,
+    /// The frame's slots while it's suspended, from the callee to the top of the stack
+    pub(crate) slots: Vec<Value>,
+    /// Upvalues of the frame's locals, closed while it's suspended, with the slot they're
+    /// reopened at
+    pub(crate) upvalues: Vec<(Gc<ObjUpvalue>, usize)>,
+    /// The frame's `try` handlers while it's suspended, outermost first, with the slot of
+    /// their stack top
+    pub(crate) handlers: Vec<(usize, Handler)>,
+}
+
+/// A handle to a [`Channel`], which tasks and VMs on other threads send values through
+#[repr(C)]
+pub struct ObjChannel {
+    pub obj: Obj,
+    pub channel: Channel,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GeneratorState {
+    /// Not started yet or stopped at a `yield`
+    Suspended,
+    Running,
+    /// Returned, or unwound by an exception
+    Done,
+}
+
 #[repr(C)]
 pub struct ObjFunction {
     pub obj: Obj,
+    /// Parameters without a default value, which every call has to pass
     pub arity: u8,
+    /// Parameters with a default value, after the required ones
+    pub optional: u8,
+    /// The last parameter collects the arguments past the others into a list
+    pub rest: bool,
+    /// The function contains `yield`, calling it returns an [`ObjGenerator`] instead of
+    /// running it
+    pub generator: bool,
+    /// Names of the parameters, in order, to match named arguments to
+    pub(crate) params: Vec<Gc<ObjString>>,
     pub chunk: Chunk,
-    pub name: *mut ObjString,
+    /// `None` for the top level script
+    pub(crate) name: Option<Gc<ObjString>>,
+    /// Line of the declaration, where reports place the function. 0 for the top level script
+    pub line: u32,
     pub upvalue_count: u8,
+    /// The module whose globals the function uses, `None` for the VM's
+    pub(crate) module: Option<Gc<ObjModule>>,
 }
 
+/// Header of a string, its characters follow it in the same allocation unless they're pooled
 #[repr(C)]
 pub struct ObjString {
     pub obj: Obj,
+    /// The characters belong to a [`StringPool`](crate::mem::StringPool), not to the string
+    pub(crate) pooled: bool,
     pub len: u32,
     pub hash: ObjHash,
-    pub chars: NonNull<u8>,
+    /// Right behind the header, or into the pool
+    pub(crate) chars: NonNull<u8>,
 }
 
 impl Obj {
+    /// Mark everything `obj` refers to
+    ///
+    /// # Safety
+    ///
+    /// `obj` must be a live object, whose kind matches the type it was allocated as
     pub unsafe fn blacken(obj: NonNull<Obj>, greystack: &mut Greystack) {
-        #[cfg(feature = "debug_gc")]
-        println!("{:?} blacken {:?}", obj.as_ptr(), Value::Obj(obj));
-
-        let kind = obj.as_ref().kind;
-        match kind {
-            ObjKind::Fn => {
-                let function = obj.cast::<ObjFunction>().as_ref();
-                Obj::mark(function.name.cast(), greystack);
-                for val in function.chunk.constants.iter() {
-                    val.mark(greystack)
+        // Safety: the casts follow the kind, and the fields of a live object are live objects
+        unsafe {
+            let kind = obj.as_ref().kind;
+            match kind {
+                ObjKind::Fn => {
+                    let function = obj.cast::<ObjFunction>().as_ref();
+                    if let Some(name) = function.name {
+                        Obj::mark(name.upcast(), greystack);
+                    }
+                    if let Some(module) = function.module {
+                        Obj::mark(module.upcast(), greystack);
+                    }
+                    for param in &function.params {
+                        Obj::mark(param.upcast(), greystack);
+                    }
+                    for val in function.chunk.constants.iter() {
+                        val.mark(greystack)
+                    }
+                    // Keeping cached classes alive means their addresses can't be reused by another
+                    // class while the cache still refers to them
+                    for cache in function.chunk.method_caches.iter().flatten() {
+                        Obj::mark(cache.class.upcast(), greystack);
+                        Obj::mark(cache.method.upcast(), greystack);
+                    }
                 }
-            }
-            ObjKind::Closure => {
-                let closure = obj.cast::<ObjClosure>().as_ref();
-                Obj::mark(closure.function.as_ptr().cast(), greystack);
-                let upvalue_slice = std::slice::from_raw_parts(
-                    closure.upvalues.as_ptr(),
-                    closure.upvalue_count as usize,
-                );
-                for obj in upvalue_slice {
-                    Obj::mark(obj.cast(), greystack);
+                ObjKind::Closure => {
+                    let closure = obj.cast::<ObjClosure>().as_ref();
+                    Obj::mark(closure.function.upcast(), greystack);
+                    let upvalue_slice = std::slice::from_raw_parts(
+                        closure.upvalues.as_ptr(),
+                        closure.upvalue_count as usize,
+                    );
+                    // Slots that weren't filled in yet are null
+                    for upvalue in upvalue_slice
+                        .iter()
+                        .filter_map(|upvalue| NonNull::new(*upvalue))
+                    {
+                        Obj::mark(Gc::new(upvalue).upcast(), greystack);
+                    }
                 }
-            }
-            ObjKind::Upvalue => obj.cast::<ObjUpvalue>().as_ref().closed.mark(greystack),
-            ObjKind::Native | ObjKind::Str => (),
-            ObjKind::Class => {
-                Obj::mark(
-                    obj.cast::<ObjClass>().as_ref().name.cast().as_ptr(),
-                    greystack,
-                );
-                (*obj.cast::<ObjClass>().as_ref()).methods.mark(greystack);
-            }
-            ObjKind::Instance => {
-                let instance_ptr = obj.cast::<ObjInstance>().as_ptr();
-                Obj::mark((*instance_ptr).class.as_ptr() as *mut _, greystack);
-                (*instance_ptr).fields.mark(greystack);
-            }
-            ObjKind::BoundMethod => {
-                let bound = obj.cast::<ObjBoundMethod>().as_ptr();
-                (*bound).receiver.mark(greystack);
-                Obj::mark((*bound).method.as_ptr() as *mut _, greystack);
+                ObjKind::Upvalue => obj.cast::<ObjUpvalue>().as_ref().closed.mark(greystack),
+                ObjKind::Native => {
+                    Obj::mark(obj.cast::<ObjNative>().as_ref().name.upcast(), greystack)
+                }
+                ObjKind::Str => (),
+                ObjKind::Class => {
+                    let class = obj.cast::<ObjClass>().as_ref();
+                    Obj::mark(class.name.upcast(), greystack);
+                    class.methods.mark(greystack);
+                    class.getters.mark(greystack);
+                    class.setters.mark(greystack);
+                    for (name, initializer) in &class.fields {
+                        Obj::mark(name.upcast(), greystack);
+                        initializer.mark(greystack);
+                    }
+                    class.static_methods.mark(greystack);
+                    class.static_fields.mark(greystack);
+                }
+                ObjKind::Instance => {
+                    let instance_ptr = obj.cast::<ObjInstance>().as_ptr();
+                    Obj::mark((*instance_ptr).class.upcast(), greystack);
+                    (*instance_ptr).fields.mark(greystack);
+                }
+                ObjKind::BoundMethod => {
+                    let bound = obj.cast::<ObjBoundMethod>().as_ptr();
+                    (*bound).receiver.mark(greystack);
+                    Obj::mark((*bound).method.upcast(), greystack);
+                }
+                ObjKind::List => {
+                    for item in obj.cast::<ObjList>().as_ref().items.iter() {
+                        item.mark(greystack);
+                    }
+                }
+                ObjKind::Map => obj.cast::<ObjMap>().as_ref().entries.mark(greystack),
+                ObjKind::Module => {
+                    let module = obj.cast::<ObjModule>().as_ref();
+                    Obj::mark(module.name.upcast(), greystack);
+                    module.globals.mark(greystack);
+                }
+                ObjKind::Generator => {
+                    let generator = obj.cast::<ObjGenerator>().as_ref();
+                    Obj::mark(generator.closure.upcast(), greystack);
+                    for slot in &generator.slots {
+                        slot.mark(greystack);
+                    }
+                    for (upvalue, _) in &generator.upvalues {
+                        Obj::mark(upvalue.upcast(), greystack);
+                    }
+                }
+                // The values in the channel are copies, outside of the heap
+                ObjKind::Channel => {}
             }
         }
     }
 
-    pub fn mark(obj: *mut Obj, greystack: &mut Greystack) {
-        if obj.is_null() {
-            return;
-        }
-
-        if unsafe { obj.as_ref() }.unwrap().is_marked {
+    pub(crate) fn mark(mut obj: Gc<Obj>, greystack: &mut Greystack) {
+        if obj.is_marked {
             return;
         }
 
-        #[cfg(feature = "debug_gc")]
-        println!(
-            "{:?} mark {:?}",
-            obj,
-            Value::Obj(NonNull::new(obj).unwrap())
-        );
-
-        // let kind = (*obj).kind;
-        unsafe {
-            (*obj).is_marked = true;
-        }
-
-        // Safety:
-        // We checked that obj is non null above
-        greystack.push(unsafe { NonNull::new_unchecked(obj.cast()) });
+        obj.is_marked = true;
+        greystack.push(obj.as_non_null_ptr());
     }
 
-    pub fn free(obj_nonnull: NonNull<Obj>) {
+    /// Free the object and return the number of bytes it was accounted for in
+    /// `Mem::bytes_allocated`
+    ///
+    /// # Safety
+    ///
+    /// `obj_nonnull` must be an object allocated by [`Mem::alloc_obj`](crate::mem::Mem::alloc_obj)
+    /// that wasn't freed yet, and nothing may use it afterwards
+    pub(crate) unsafe fn free(obj_nonnull: NonNull<Obj>) -> usize {
         unsafe {
             let obj = obj_nonnull.as_ptr();
             let kind = (*obj).kind;
 
-            #[cfg(feature = "debug_gc")]
-            println!(
-                "{:?} free type {:?} ({:?})",
-                obj,
-                kind,
-                Value::Obj(obj_nonnull)
-            );
-
             match kind {
-                ObjKind::Str => {
-                    let obj_str = obj as *mut ObjString;
-                    let len = (*obj_str).len;
-                    if len != 0 {
-                        let layout = {
-                            let bytes =
-                                slice::from_raw_parts((*obj_str).chars.as_ptr(), len as usize);
-                            Layout::for_value(bytes)
-                        };
-                        alloc::dealloc((*obj_str).chars.as_ptr(), layout);
-                    }
-
-                    let _ = Box::from_raw(obj as *mut ObjString);
-                }
+                ObjKind::Str => ObjString::dealloc(obj_nonnull.cast()),
                 ObjKind::Fn => {
                     let _ = Box::from_raw(obj as *mut ObjFunction);
                 }
@@ -274,18 +549,32 @@ impl Obj {
                     let _ = Box::from_raw(obj as *mut ObjUpvalue);
                 }
                 ObjKind::Class => {
-                    let mut obj = Box::from_raw(obj as *mut ObjClass);
-                    Table::free(&mut obj.methods);
+                    let _ = Box::from_raw(obj as *mut ObjClass);
                 }
                 ObjKind::Instance => {
-                    Table::free(&mut (*(obj as *mut ObjInstance)).fields);
-
                     let _ = Box::from_raw(obj as *mut ObjInstance);
                 }
                 ObjKind::BoundMethod => {
                     let _ = Box::from_raw(obj as *mut ObjBoundMethod);
                 }
+                ObjKind::List => {
+                    let _ = Box::from_raw(obj as *mut ObjList);
+                }
+                ObjKind::Map => {
+                    let _ = Box::from_raw(obj as *mut ObjMap);
+                }
+                ObjKind::Module => {
+                    let _ = Box::from_raw(obj as *mut ObjModule);
+                }
+                ObjKind::Generator => {
+                    let _ = Box::from_raw(obj as *mut ObjGenerator);
+                }
+                ObjKind::Channel => {
+                    let _ = Box::from_raw(obj as *mut ObjChannel);
+                }
             }
+
+            kind.size()
         }
     }
 }
@@ -306,14 +595,21 @@ impl std::fmt::Debug for ObjPtrWrapper {
             ObjKind::Fn => unsafe {
                 let obj_fn = ptr.cast::<ObjFunction>().as_ref();
                 f.debug_struct("Function")
-                    .field("name", &ObjPtrWrapper(obj_fn.name as *mut Obj))
+                    .field(
+                        "name",
+                        &ObjPtrWrapper(
+                            obj_fn
+                                .name
+                                .map_or(null_mut(), |name| name.upcast().as_ptr()),
+                        ),
+                    )
                     .field("arity", &obj_fn.arity)
                     // .field("chunk", &obj_fn.chunk)
                     .finish()
             },
             ObjKind::Native => {
-                let function = unsafe { ptr.cast::<ObjNative>().as_ref().function };
-                write!(f, "{function:?}")
+                let name = unsafe { ptr.cast::<ObjNative>().as_ref().name.as_str() };
+                write!(f, "<native fn {name}>")
             }
             ObjKind::Closure => unsafe {
                 let ptr: NonNull<ObjClosure> = ptr.cast();
@@ -335,26 +631,28 @@ impl std::fmt::Debug for ObjPtrWrapper {
                 let loc = *ptr.location.as_ptr();
 
                 f.debug_struct("ObjUpvalue")
-                    .field("location", &(loc_ptr, loc))
+                    .field("location", &(loc_ptr, ValueWrapper(loc)))
                     .finish()
             },
             ObjKind::Class => unsafe {
                 f.debug_struct("Class")
                     .field(
                         "name",
-                        &ObjPtrWrapper(ptr.cast::<ObjClass>().as_ref().name.cast::<Obj>().as_ptr()),
+                        &ObjPtrWrapper(ptr.cast::<ObjClass>().as_ref().name.upcast().as_ptr()),
                     )
                     .finish()
             },
             ObjKind::Instance => unsafe {
                 let instance = ptr.cast::<ObjInstance>().as_ref();
-                f.debug_struct("Instance")
-                    .field(
-                        "class",
-                        &ObjPtrWrapper(instance.class.cast::<Obj>().as_ptr()),
-                    )
-                    .field("fields", &instance.fields)
-                    .finish()
+                fmt_container(self.0, "Instance { ... }", f, |f| {
+                    f.debug_struct("Instance")
+                        .field(
+                            "class",
+                            &ObjPtrWrapper(instance.class.cast::<Obj>().as_ptr()),
+                        )
+                        .field("fields", &instance.fields)
+                        .finish()
+                })
             },
             ObjKind::BoundMethod => unsafe {
                 let name = (*(*(*ptr.cast::<ObjBoundMethod>().as_ptr()).method.as_ptr())
@@ -363,31 +661,85 @@ impl std::fmt::Debug for ObjPtrWrapper {
                 .name;
 
                 f.debug_struct("BoundMethod")
-                    .field("name", &ObjPtrWrapper(name.cast::<Obj>()))
+                    .field(
+                        "name",
+                        &ObjPtrWrapper(name.map_or(null_mut(), |name| name.upcast().as_ptr())),
+                    )
                     .finish()
             },
+            ObjKind::List => {
+                let list = unsafe { ptr.cast::<ObjList>().as_ref() };
+                fmt_container(self.0, "[...]", f, |f| {
+                    f.debug_list()
+                        .entries(list.items.iter().map(|&item| ValueWrapper(item)))
+                        .finish()
+                })
+            }
+            ObjKind::Map => {
+                let map = unsafe { ptr.cast::<ObjMap>().as_ref() };
+                fmt_container(self.0, "{...}", f, |f| {
+                    f.debug_map()
+                        .entries(
+                            map.entries
+                                .iter()
+                                .map(|entry| (ValueWrapper(entry.key), ValueWrapper(entry.value))),
+                        )
+                        .finish()
+                })
+            }
+            ObjKind::Module => {
+                let module = unsafe { ptr.cast::<ObjModule>().as_ref() };
+                fmt_container(self.0, "Module { ... }", f, |f| {
+                    f.debug_struct("Module")
+                        .field("name", &module.name.as_str())
+                        .field("exports", &module.globals)
+                        .finish()
+                })
+            }
+            ObjKind::Generator => {
+                let generator = unsafe { ptr.cast::<ObjGenerator>().as_ref() };
+                f.debug_struct("Generator")
+                    .field(
+                        "closure",
+                        &ObjPtrWrapper(generator.closure.as_ptr() as *mut Obj),
+                    )
+                    .field("state", &generator.state)
+                    .finish()
+            }
+            ObjKind::Channel => {
+                let channel = unsafe { ptr.cast::<ObjChannel>().as_ref() };
+                f.debug_struct("Channel")
+                    .field("channel", &channel.channel)
+                    .finish()
+            }
         }
     }
 }
 
 impl ObjNative {
-    pub fn new(kind: NativeFnKind) -> Self {
+    pub(crate) fn new(name: Gc<ObjString>, kind: NativeFnKind, arity: Arity) -> Self {
         Self {
             obj: Obj {
                 kind: ObjKind::Native,
                 is_marked: false,
+                generation: 0,
+                slot: NO_SLOT,
             },
+            name,
             function: kind,
+            arity,
         }
     }
 }
 
 impl ObjUpvalue {
-    pub fn new(location: NonNull<Value>, next: *mut ObjUpvalue) -> Self {
+    pub(crate) fn new(location: NonNull<Value>, next: *mut ObjUpvalue) -> Self {
         Self {
             obj: Obj {
                 kind: ObjKind::Upvalue,
                 is_marked: false,
+                generation: 0,
+                slot: NO_SLOT,
             },
             location,
             next,
@@ -397,7 +749,7 @@ impl ObjUpvalue {
 }
 
 impl ObjClosure {
-    pub fn new(function: Gc<ObjFunction>) -> Self {
+    pub(crate) fn new(function: Gc<ObjFunction>) -> Self {
         let (upvalues, upvalue_count) = unsafe {
             let upvalue_count = (*function.as_ptr()).upvalue_count;
             let upvalues = if upvalue_count == 0 {
@@ -414,6 +766,8 @@ impl ObjClosure {
             obj: Obj {
                 kind: ObjKind::Closure,
                 is_marked: false,
+                generation: 0,
+                slot: NO_SLOT,
             },
             function,
             upvalues,
@@ -435,24 +789,33 @@ impl ObjClosure {
 }
 
 impl ObjClass {
-    pub fn new(name: NonNull<ObjString>) -> Self {
+    pub(crate) fn new(name: Gc<ObjString>) -> Self {
         Self {
             obj: Obj {
                 kind: ObjKind::Class,
                 is_marked: false,
+                generation: 0,
+                slot: NO_SLOT,
             },
             name,
             methods: Table::new(),
+            getters: Table::new(),
+            setters: Table::new(),
+            fields: Vec::new(),
+            static_methods: Table::new(),
+            static_fields: Table::new(),
         }
     }
 }
 
 impl ObjInstance {
-    pub fn new(class: Gc<ObjClass>) -> Self {
+    pub(crate) fn new(class: Gc<ObjClass>) -> Self {
         Self {
             obj: Obj {
                 kind: ObjKind::Instance,
                 is_marked: false,
+                generation: 0,
+                slot: NO_SLOT,
             },
             class,
             fields: Table::new(),
@@ -461,11 +824,13 @@ impl ObjInstance {
 }
 
 impl ObjBoundMethod {
-    pub fn new(receiver: Value, method: Gc<ObjClosure>) -> Self {
+    pub(crate) fn new(receiver: Value, method: Gc<ObjClosure>) -> Self {
         Self {
             obj: Obj {
                 kind: ObjKind::BoundMethod,
                 is_marked: false,
+                generation: 0,
+                slot: NO_SLOT,
             },
             receiver,
             method,
@@ -473,19 +838,133 @@ impl ObjBoundMethod {
     }
 }
 
+impl ObjList {
+    pub fn new(items: Vec<Value>) -> Self {
+        Self {
+            obj: Obj {
+                kind: ObjKind::List,
+                is_marked: false,
+                generation: 0,
+                slot: NO_SLOT,
+            },
+            items,
+        }
+    }
+}
+
+impl ObjMap {
+    pub fn new() -> Self {
+        Self {
+            obj: Obj {
+                kind: ObjKind::Map,
+                is_marked: false,
+                generation: 0,
+                slot: NO_SLOT,
+            },
+            entries: Table::new(),
+        }
+    }
+}
+
+impl ObjModule {
+    pub(crate) fn new(name: Gc<ObjString>) -> Self {
+        Self {
+            obj: Obj {
+                kind: ObjKind::Module,
+                is_marked: false,
+                generation: 0,
+                slot: NO_SLOT,
+            },
+            name,
+            globals: Globals::new(),
+        }
+    }
+}
+
+impl ObjGenerator {
+    /// A generator that starts `closure` with `slots`, the callee and its arguments
+    pub(crate) fn new(closure: Gc<ObjClosure>, slots: Vec<Value>) -> Self {
+        Self {
+            obj: Obj {
+                kind: ObjKind::Generator,
+                is_marked: false,
+                generation: 0,
+                slot: NO_SLOT,
+            },
+            closure,
+            state: GeneratorState::Suspended,
+            task: false,
+            instr_offset: 0,
+            slots,
+            upvalues: vec![],
+            handlers: vec![],
+        }
+    }
+
+    /// Stop it for good, `next` only returns `nil` afterwards
+    pub(crate) fn finish(&mut self) {
+        self.state = GeneratorState::Done;
+        self.slots = vec![];
+        self.upvalues = vec![];
+        self.handlers = vec![];
+    }
+}
+
+impl ObjChannel {
+    pub fn new(channel: Channel) -> Self {
+        Self {
+            obj: Obj {
+                kind: ObjKind::Channel,
+                is_marked: false,
+                generation: 0,
+                slot: NO_SLOT,
+            },
+            channel,
+        }
+    }
+}
+
+impl Default for ObjMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ObjFunction {
-    pub fn new(name: *mut ObjString) -> Self {
+    pub(crate) fn new(name: Option<Gc<ObjString>>) -> Self {
         Self {
             obj: Obj {
                 kind: ObjKind::Fn,
                 is_marked: false,
+                generation: 0,
+                slot: NO_SLOT,
             },
             arity: 0,
+            optional: 0,
+            rest: false,
+            generator: false,
+            params: vec![],
             chunk: Chunk::new(),
             name,
+            line: 0,
             upvalue_count: 0,
+            module: None,
         }
     }
+
+    /// Number of parameters, the slots after the callee that hold the arguments
+    pub fn param_count(&self) -> usize {
+        self.arity as usize + self.optional as usize + self.rest as usize
+    }
+
+    /// Index of the parameter called `name` that can be passed by name, which all but a rest
+    /// parameter can
+    pub fn named_param(&self, name: &str) -> Option<usize> {
+        let named = self.arity as usize + self.optional as usize;
+        self.params[..named]
+            .iter()
+            .position(|param| param.as_str() == name)
+    }
 }
 
 impl ObjString {
@@ -496,15 +975,100 @@ impl ObjString {
         }
     }
 
-    pub fn new(chars: NonNull<u8>, len: u32, hash: ObjHash) -> ObjString {
-        Self {
-            obj: Obj {
-                kind: ObjKind::Str,
-                is_marked: false,
-            },
-            len,
-            hash,
-            chars,
+    /// The header followed by `len` characters
+    fn layout(len: u32) -> Layout {
+        let chars = Layout::array::<u8>(len as usize).unwrap();
+        let (layout, _) = Layout::new::<ObjString>().extend(chars).unwrap();
+        layout.pad_to_align()
+    }
+
+    /// A copy of `string`, whose hash is `hash`. It belongs to the caller until it's handed to
+    /// a [`Mem`](crate::mem::Mem)
+    pub(crate) fn copy(string: &str, hash: ObjHash) -> NonNull<ObjString> {
+        let mut copy = Self::alloc(std::iter::once(string));
+        // Safety: the string was just allocated, nobody else has it
+        unsafe { copy.as_mut().hash = hash };
+        copy
+    }
+
+    /// The strings in `parts` one after another, see [`ObjString::copy`]
+    pub(crate) fn concat(
+        parts: impl Iterator<Item = impl AsRef<str>> + Clone,
+    ) -> NonNull<ObjString> {
+        let mut concat = Self::alloc(parts);
+        // Safety: the string was just allocated, nobody else has it
+        let concat_ref = unsafe { concat.as_mut() };
+        concat_ref.hash = ObjHash::hash_string(concat_ref.as_str());
+        concat
+    }
+
+    /// Allocate a string with the characters of `parts` behind the header, it isn't hashed yet
+    fn alloc(parts: impl Iterator<Item = impl AsRef<str>> + Clone) -> NonNull<ObjString> {
+        let len = parts.clone().map(|part| part.as_ref().len()).sum::<usize>();
+        let len = u32::try_from(len).expect("strings are shorter than 4 GiB");
+        let layout = Self::layout(len);
+        // Safety: the layout isn't zero-sized, it has at least the header
+        let Some(string) = NonNull::new(unsafe { alloc::alloc(layout) }.cast::<ObjString>()) else {
+            handle_alloc_error(layout);
+        };
+        // Safety: the allocation has room for the header followed by `len` bytes, bytes need no
+        // alignment so they start right after the header
+        unsafe {
+            let chars = string.as_ptr().add(1).cast::<u8>();
+            let mut offset = 0;
+            for part in parts {
+                let part = part.as_ref();
+                ptr::copy_nonoverlapping(part.as_ptr(), chars.add(offset), part.len());
+                offset += part.len();
+            }
+            string.as_ptr().write(Self {
+                obj: Obj {
+                    kind: ObjKind::Str,
+                    is_marked: false,
+                    generation: 0,
+                    slot: NO_SLOT,
+                },
+                pooled: false,
+                len,
+                hash: ObjHash::EMPTY_STR_HASH,
+                chars: NonNull::new_unchecked(chars),
+            });
         }
+        string
+    }
+
+    /// A string whose characters are owned by a [`StringPool`](crate::mem::StringPool)
+    ///
+    /// # Safety
+    ///
+    /// `chars` must point to `len` bytes of UTF-8 that outlive the string
+    pub(crate) unsafe fn pooled(chars: NonNull<u8>, len: u32, hash: ObjHash) -> NonNull<ObjString> {
+        let mut string = Self::alloc(std::iter::empty::<&str>());
+        // Safety: the string was just allocated, nobody else has it
+        let header = unsafe { string.as_mut() };
+        header.pooled = true;
+        header.len = len;
+        header.hash = hash;
+        header.chars = chars;
+        string
+    }
+
+    /// Free a string made by one of the functions above
+    ///
+    /// # Safety
+    ///
+    /// Nothing may use the string afterwards
+    pub(crate) unsafe fn dealloc(string: NonNull<ObjString>) {
+        // Safety: the string is still alive here
+        let inline_len = unsafe {
+            let header = string.as_ref();
+            if header.pooled {
+                0
+            } else {
+                header.len
+            }
+        };
+        // Safety: it was allocated with the same layout in `alloc`
+        unsafe { alloc::dealloc(string.as_ptr().cast(), Self::layout(inline_len)) };
     }
 }
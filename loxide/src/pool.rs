@@ -0,0 +1,392 @@
+//! Running scripts on worker threads.
+//!
+//! A [`VM`](crate::VM) can be moved to another thread, but the [`Value`]s it returns point into
+//! its heap and can't. [`ScriptPool::spawn_script`] runs a script on one of a fixed number of
+//! worker threads and hands back a [`Snapshot`] of the result, a copy that owns its data:
+//!
+//! ```
+//! use loxide::{pool::{ScriptPool, Snapshot}, Loxide};
+//!
+//! let pool = ScriptPool::new(2);
+//! let handles: Vec<_> = (1..=3)
+//!     .map(|n| pool.spawn_script(Loxide::new(), format!("var n = {n}; [n, n * n]")))
+//!     .collect();
+//! let results: Vec<_> = handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+//! assert_eq!(
+//!     results[2],
+//!     Ok(Snapshot::List(vec![Snapshot::Number(3.0), Snapshot::Number(9.0)]))
+//! );
+//! ```
+//!
+//! Scripts on different threads talk through a [`Channel`]: `send(channel, value)` copies the
+//! value into it, `recv(channel)` waits for the next one and rebuilds it in the receiving VM.
+//!
+//! ```
+//! use loxide::{pool::Channel, Loxide};
+//!
+//! let (jobs, results) = (Channel::new(), Channel::new());
+//! let mut worker = Loxide::new();
+//! worker.define_channel("jobs", &jobs).define_channel("results", &results);
+//! let worker = std::thread::spawn(move || {
+//!     worker.eval("var job = recv(jobs); send(results, job * 2);").unwrap();
+//! });
+//!
+//! let mut engine = Loxide::new();
+//! engine.define_channel("jobs", &jobs).define_channel("results", &results);
+//! let answer = engine.eval("send(jobs, 21); recv(results)").unwrap();
+//! assert_eq!(answer, loxide::Value::Number(42.0));
+//! worker.join().unwrap();
+//! ```
+
+use std::{
+    collections::{HashSet, VecDeque},
+    panic::{self, AssertUnwindSafe},
+    sync::{mpsc, Arc, Condvar, Mutex},
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    obj::{Obj, ObjChannel, ObjList, ObjMap},
+    table::Key,
+    value::display::{ValueWrapper, MAX_DEPTH},
+    InterpretResult, Loxide, Unpacked, Value, VM,
+};
+
+/// A value copied out of a VM, which can outlive it and be sent to other threads
+#[derive(Debug, Clone, PartialEq)]
+pub enum Snapshot {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    List(Vec<Snapshot>),
+    /// Entries in the map's iteration order
+    Map(Vec<(Snapshot, Snapshot)>),
+    Channel(Channel),
+    /// Any other object, as `print` shows it. A list or map that contains itself shows up here
+    /// as `[...]` or `{...}` where it repeats, and so do the ones nested deeper than
+    /// [`MAX_DEPTH`]
+    Object(String),
+}
+
+impl Snapshot {
+    /// Copy `value`, which has to be alive
+    pub(crate) fn of(value: Value) -> Self {
+        Self::copy(value, &mut HashSet::new(), false).expect("only transfers fail")
+    }
+
+    /// Copy `value` to rebuild it in another VM with [`Snapshot::into_value`]. Unlike
+    /// [`Snapshot::of`] this fails for values that can't be rebuilt: objects other than strings,
+    /// lists, maps and channels, lists or maps that contain themselves, and ones nested deeper
+    /// than [`MAX_DEPTH`]
+    pub(crate) fn transfer(value: Value) -> Result<Self, String> {
+        Self::copy(value, &mut HashSet::new(), true)
+    }
+
+    /// `path` holds the lists and maps `value` is nested in. It's at most [`MAX_DEPTH`] long, so
+    /// a long chain of them can't overflow the stack
+    fn copy(value: Value, path: &mut HashSet<*mut Obj>, transfer: bool) -> Result<Self, String> {
+        let obj = match value.unpack() {
+            Unpacked::Nil => return Ok(Snapshot::Nil),
+            Unpacked::Bool(boolean) => return Ok(Snapshot::Bool(boolean)),
+            Unpacked::Number(number) => return Ok(Snapshot::Number(number)),
+            Unpacked::Int(int) => return Ok(Snapshot::Number(int.into())),
+            Unpacked::Obj(obj) => obj.as_ptr(),
+            Unpacked::Handle(_) => unreachable!("handles are resolved before they're copied"),
+        };
+
+        if let Some(string) = value.as_str() {
+            return Ok(Snapshot::String(string.to_string()));
+        }
+        if let Some(channel) = value.as_channel() {
+            return Ok(Snapshot::Channel(channel.channel.clone()));
+        }
+        let (list, map) = (value.as_list(), value.as_map());
+        if list.is_none() && map.is_none() {
+            if transfer {
+                return Err(format!("Can't send a {} to a channel.", value.type_name()));
+            }
+            return Ok(Snapshot::Object(ValueWrapper(value).to_string()));
+        }
+        let repeats = path.contains(&obj);
+        if repeats || path.len() >= MAX_DEPTH {
+            if transfer {
+                let why = if repeats {
+                    "that contains itself".to_string()
+                } else {
+                    format!("nested more than {MAX_DEPTH} deep")
+                };
+                return Err(format!("Can't send a {} {why}.", value.type_name()));
+            }
+            let repeated = if list.is_some() { "[...]" } else { "{...}" };
+            return Ok(Snapshot::Object(repeated.to_string()));
+        }
+
+        path.insert(obj);
+        let snapshot = match (list, map) {
+            (Some(list), _) => Snapshot::List(
+                list.items
+                    .iter()
+                    .map(|item| Self::copy(*item, path, transfer))
+                    .collect::<Result<_, _>>()?,
+            ),
+            (_, Some(map)) => Snapshot::Map(
+                map.entries
+                    .iter()
+                    .map(|entry| {
+                        Ok((
+                            Self::copy(entry.key, path, transfer)?,
+                            Self::copy(entry.value, path, transfer)?,
+                        ))
+                    })
+                    .collect::<Result<_, String>>()?,
+            ),
+            (None, None) => unreachable!(),
+        };
+        path.remove(&obj);
+        Ok(snapshot)
+    }
+
+    /// Rebuild the value in `vm`'s heap. An [`Snapshot::Object`] becomes its string
+    pub fn into_value(self, vm: &mut VM) -> Value {
+        let value = self.rebuild(vm);
+        vm.mem.export(value)
+    }
+
+    /// [`Snapshot::into_value`] for the VM's own use, objects aren't handles
+    pub(crate) fn rebuild(self, vm: &mut VM) -> Value {
+        let value = self.shell(vm);
+        // The value keeps what's rebuilt inside it reachable
+        vm.push(value);
+        self.fill(vm, value);
+        vm.pop()
+    }
+
+    /// The value itself, lists and maps still empty
+    fn shell(&self, vm: &mut VM) -> Value {
+        match self {
+            Snapshot::Nil => Value::Nil,
+            Snapshot::Bool(boolean) => Value::Bool(*boolean),
+            Snapshot::Number(number) => Value::number(*number),
+            Snapshot::String(string) | Snapshot::Object(string) => vm.alloc_string(string),
+            Snapshot::List(items) => {
+                let list = ObjList::new(Vec::with_capacity(items.len()));
+                Value::Obj(vm.alloc_obj(list).upcast())
+            }
+            Snapshot::Map(_) => Value::Obj(vm.alloc_obj(ObjMap::new()).upcast()),
+            Snapshot::Channel(channel) => {
+                Value::Obj(vm.alloc_obj(ObjChannel::new(channel.clone())).upcast())
+            }
+        }
+    }
+
+    /// Rebuild the items of `value`, the shell of this snapshot. Each is put in its place as
+    /// soon as it's allocated, so it's reachable while the next is
+    fn fill(mut self, vm: &mut VM, value: Value) {
+        match &mut self {
+            Snapshot::List(items) => {
+                let items = std::mem::take(items);
+                let mut list = value.as_list().unwrap();
+                for item in items {
+                    let shell = item.shell(vm);
+                    list.items.push(shell);
+                    item.fill(vm, shell);
+                }
+            }
+            Snapshot::Map(entries) => {
+                let entries = std::mem::take(entries);
+                let mut map = value.as_map().unwrap();
+                for (key, item) in entries {
+                    let key_shell = key.shell(vm);
+                    // Keys taken from a map are valid keys
+                    let Some(map_key) = Key::new(key_shell) else {
+                        continue;
+                    };
+                    map.entries.set_value(map_key, Value::Nil);
+                    key.fill(vm, key_shell);
+                    let shell = item.shell(vm);
+                    map.entries.set_value(map_key, shell);
+                    item.fill(vm, shell);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Snapshots made by the host can be nested arbitrarily deep, so their items are dropped one at
+/// a time instead of recursively
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        fn take_items(snapshot: &mut Snapshot, items: &mut Vec<Snapshot>) {
+            match snapshot {
+                Snapshot::List(list) => items.append(list),
+                Snapshot::Map(entries) => {
+                    for (key, value) in entries.drain(..) {
+                        items.extend([key, value]);
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        let mut items = vec![];
+        take_items(self, &mut items);
+        // Each item is empty by the time it's dropped
+        while let Some(mut item) = items.pop() {
+            take_items(&mut item, &mut items);
+        }
+    }
+}
+
+/// A queue of values that VMs on any thread can send to and receive from, `Channel()` in a
+/// script. The values are copied with [`Snapshot::transfer`], the VMs share nothing else.
+/// Clones are handles to the same channel, see [`Loxide::define_channel`]
+#[derive(Debug, Clone, Default)]
+pub struct Channel(Arc<Shared>);
+
+#[derive(Debug, Default)]
+struct Shared {
+    queue: Mutex<VecDeque<Snapshot>>,
+    sent: Condvar,
+}
+
+impl Channel {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn send(&self, value: Snapshot) {
+        self.0.queue.lock().unwrap().push_back(value);
+        self.0.sent.notify_all();
+    }
+
+    /// The next value, without waiting for one
+    pub fn try_recv(&self) -> Option<Snapshot> {
+        self.0.queue.lock().unwrap().pop_front()
+    }
+
+    /// Wait for the next value
+    pub fn recv(&self) -> Snapshot {
+        let mut queue = self.0.queue.lock().unwrap();
+        loop {
+            if let Some(value) = queue.pop_front() {
+                return value;
+            }
+            queue = self.0.sent.wait(queue).unwrap();
+        }
+    }
+
+    /// Wait at most `timeout` for a value to be sent, leaving it in the channel. Whether there
+    /// is one
+    pub(crate) fn wait(&self, timeout: Duration) -> bool {
+        let queue = self.0.queue.lock().unwrap();
+        let (queue, _) = self
+            .0
+            .sent
+            .wait_timeout_while(queue, timeout, |queue| queue.is_empty())
+            .unwrap();
+        !queue.is_empty()
+    }
+
+    /// Whether there are other handles to the channel, which may send to it
+    pub(crate) fn is_shared(&self) -> bool {
+        Arc::strong_count(&self.0) > 1
+    }
+}
+
+/// Handles to the same channel are equal
+impl PartialEq for Channel {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A fixed number of threads that run scripts, see [`ScriptPool::spawn_script`]. Dropping the
+/// pool waits for the scripts that were spawned to finish
+pub struct ScriptPool {
+    jobs: Option<mpsc::Sender<Job>>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl ScriptPool {
+    /// A pool with `threads` workers, at least one
+    pub fn new(threads: usize) -> Self {
+        let (jobs, queue) = mpsc::channel::<Job>();
+        let queue = Arc::new(Mutex::new(queue));
+        let workers = (0..threads.max(1))
+            .map(|i| {
+                let queue = queue.clone();
+                thread::Builder::new()
+                    .name(format!("loxide-worker-{i}"))
+                    .spawn(move || loop {
+                        // The lock is released before the job runs
+                        let job = queue.lock().unwrap().recv();
+                        match job {
+                            Ok(job) => job(),
+                            Err(_) => break,
+                        }
+                    })
+                    .expect("failed to spawn a worker thread")
+            })
+            .collect();
+
+        Self {
+            jobs: Some(jobs),
+            workers,
+        }
+    }
+
+    /// Run `src` on `engine` like [`Loxide::eval`] on the next free worker. The engine is
+    /// dropped on the worker once the script is done
+    pub fn spawn_script(&self, mut engine: Loxide, src: impl Into<String>) -> ScriptHandle {
+        let src = src.into();
+        let (sender, result) = mpsc::channel();
+        let job = Box::new(move || {
+            let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+                let value = engine.eval(&src)?;
+                let value = engine
+                    .vm
+                    .value_ref(value)
+                    .expect("the result was just returned");
+                Ok(value.snapshot())
+            }));
+            // The handle may have been dropped already
+            let _ = sender.send(outcome);
+        });
+        self.jobs
+            .as_ref()
+            .unwrap()
+            .send(job)
+            .expect("the workers are gone");
+        ScriptHandle { result }
+    }
+}
+
+impl Drop for ScriptPool {
+    fn drop(&mut self) {
+        // Closing the queue stops the workers once it's empty
+        drop(self.jobs.take());
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// The result of a script spawned on a [`ScriptPool`]
+pub struct ScriptHandle {
+    result: mpsc::Receiver<thread::Result<InterpretResult<Snapshot>>>,
+}
+
+impl ScriptHandle {
+    /// Wait for the script to finish. Like [`thread::JoinHandle::join`], `Err` holds the payload
+    /// of a panic in the VM
+    pub fn join(self) -> thread::Result<InterpretResult<Snapshot>> {
+        self.result
+            .recv()
+            .expect("the worker stopped without sending a result")
+    }
+}
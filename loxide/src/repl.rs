@@ -0,0 +1,223 @@
+use loxide::{compile::is_incomplete, report, InterpretError, Loxide};
+
+const PROMPT: &str = "> ";
+const CONTINUATION_PROMPT: &str = "... ";
+
+/// Read-eval-print loop on a single engine, so globals and objects persist between inputs.
+///
+/// Errors are reported and the loop keeps going. Input that stops inside a block, a grouping
+/// or a string is continued on the next line, an empty line submits it as is.
+///
+/// A trailing expression without a semicolon is echoed, unless it is `nil`. Errors are printed
+/// with [`report`], in color if `color` is set.
+pub fn run(engine: &mut Loxide, color: bool) {
+    let mut reader = LineReader::new();
+    let mut input = String::new();
+
+    loop {
+        let prompt = if input.is_empty() {
+            PROMPT
+        } else {
+            CONTINUATION_PROMPT
+        };
+
+        let line = match reader.read_line(prompt) {
+            ReadLine::Line(line) => line,
+            ReadLine::Interrupted => {
+                input.clear();
+                continue;
+            }
+            ReadLine::Eof => break,
+        };
+
+        let submit = line.trim().is_empty();
+        if !input.is_empty() {
+            input.push('\n');
+        }
+        input.push_str(&line);
+
+        if input.trim().is_empty() || (!submit && is_incomplete(&input)) {
+            continue;
+        }
+
+        reader.add_history(&input);
+        match engine.eval(&input) {
+            Ok(value) if value.is_nil() => (),
+            Err(error) => {
+                // Runtime errors in functions from earlier inputs are on lines of those
+                let in_input = match &error {
+                    InterpretError::RuntimeError(error) | InterpretError::StackOverflow(error) => {
+                        error.trace.len() == 1
+                    }
+                    _ => true,
+                };
+                let src = Some(input.as_str()).filter(|_| in_input);
+                eprint!("{}", report::render(&error, src, color));
+            }
+            // Same format as `print`
+            Ok(value) => {
+                let value = engine
+                    .vm
+                    .value_ref(value)
+                    .expect("the result was just returned");
+                println!("{value}");
+            }
+        }
+        input.clear();
+    }
+
+    reader.save_history();
+}
+
+enum ReadLine {
+    Line(String),
+    /// Ctrl-C, discards the current input
+    Interrupted,
+    Eof,
+}
+
+/// Line editing, highlighting and history, backed by rustyline
+#[cfg(feature = "readline")]
+struct LineReader {
+    editor: Option<rustyline::Editor<Highlight>>,
+    history_path: Option<std::path::PathBuf>,
+}
+
+#[cfg(feature = "readline")]
+impl LineReader {
+    fn new() -> Self {
+        let history_path = std::env::var_os("HOME")
+            .map(|home| std::path::Path::new(&home).join(".loxide_history"));
+
+        // Fall back to plain stdin if the terminal can't be set up
+        let editor = rustyline::Editor::<Highlight>::new()
+            .ok()
+            .map(|mut editor| {
+                editor.set_helper(Some(Highlight));
+                if let Some(path) = &history_path {
+                    let _ = editor.load_history(path);
+                }
+                editor
+            });
+
+        Self {
+            editor,
+            history_path,
+        }
+    }
+
+    fn read_line(&mut self, prompt: &str) -> ReadLine {
+        use rustyline::error::ReadlineError;
+
+        let Some(editor) = &mut self.editor else {
+            return read_stdin_line(prompt);
+        };
+
+        match editor.readline(prompt) {
+            Ok(line) => ReadLine::Line(line),
+            Err(ReadlineError::Interrupted) => ReadLine::Interrupted,
+            Err(_) => ReadLine::Eof,
+        }
+    }
+
+    fn add_history(&mut self, entry: &str) {
+        if let Some(editor) = &mut self.editor {
+            editor.add_history_entry(entry);
+        }
+    }
+
+    fn save_history(&mut self) {
+        if let (Some(editor), Some(path)) = (&mut self.editor, &self.history_path) {
+            let _ = editor.save_history(path);
+        }
+    }
+}
+
+/// Colors the line being typed by its tokens
+#[cfg(feature = "readline")]
+struct Highlight;
+
+#[cfg(feature = "readline")]
+impl rustyline::highlight::Highlighter for Highlight {
+    fn highlight<'l>(&self, line: &'l str, _pos: usize) -> std::borrow::Cow<'l, str> {
+        use loxide::compile::lexer::{Category, Lexer};
+
+        let mut highlighted = String::with_capacity(line.len());
+        let mut end = 0;
+        for lexeme in Lexer::new(line).tokens() {
+            highlighted.push_str(&line[end..lexeme.span.start]);
+            let color = match lexeme.category {
+                Category::Keyword => "35",
+                Category::Number => "33",
+                Category::String => "32",
+                Category::Comment => "90",
+                Category::Error => "31",
+                Category::Identifier | Category::Operator | Category::Punctuation => {
+                    highlighted.push_str(lexeme.text);
+                    end = lexeme.span.end;
+                    continue;
+                }
+            };
+            highlighted.push_str(&format!("\x1b[{color}m{}\x1b[0m", lexeme.text));
+            end = lexeme.span.end;
+        }
+        highlighted.push_str(&line[end..]);
+        highlighted.into()
+    }
+
+    fn highlight_char(&self, _line: &str, _pos: usize) -> bool {
+        true
+    }
+}
+
+#[cfg(feature = "readline")]
+impl rustyline::completion::Completer for Highlight {
+    type Candidate = String;
+}
+
+#[cfg(feature = "readline")]
+impl rustyline::hint::Hinter for Highlight {
+    type Hint = String;
+}
+
+#[cfg(feature = "readline")]
+impl rustyline::validate::Validator for Highlight {}
+
+#[cfg(feature = "readline")]
+impl rustyline::Helper for Highlight {}
+
+/// Plain stdin, without editing or history
+#[cfg(not(feature = "readline"))]
+struct LineReader;
+
+#[cfg(not(feature = "readline"))]
+impl LineReader {
+    fn new() -> Self {
+        Self
+    }
+
+    fn read_line(&mut self, prompt: &str) -> ReadLine {
+        read_stdin_line(prompt)
+    }
+
+    fn add_history(&mut self, _entry: &str) {}
+
+    fn save_history(&mut self) {}
+}
+
+fn read_stdin_line(prompt: &str) -> ReadLine {
+    use std::io::Write;
+
+    print!("{prompt}");
+    let _ = std::io::stdout().flush();
+
+    let mut line = String::new();
+    match std::io::stdin().read_line(&mut line) {
+        Ok(0) | Err(_) => ReadLine::Eof,
+        Ok(_) => {
+            let len = line.trim_end_matches(['\n', '\r']).len();
+            line.truncate(len);
+            ReadLine::Line(line)
+        }
+    }
+}
@@ -0,0 +1,119 @@
+//! Recording and replaying what the nondeterministic natives return.
+//!
+//! `clock`, `now`, `random`, `randomInt` and `readLine` are the only way a script sees
+//! anything but its own source, so a run that replays their results does exactly what the
+//! recorded one did. The trace is a text file with a line per call, the native's name and its
+//! result:
+//!
+//! ```text
+//! clock 0.000153
+//! random 0.8231
+//! readLine 5:hello
+//! readLine nil
+//! ```
+//!
+//! Strings are prefixed with their length in bytes, so they can hold anything but line breaks,
+//! which `readLine` strips.
+
+use std::{
+    collections::VecDeque,
+    fs::{self, File},
+    io::{self, Write},
+    path::Path,
+};
+
+use crate::{pool::Snapshot, Value, VM};
+
+/// See [`VmOptions::replay`](crate::VmOptions::replay)
+pub enum Replay {
+    /// Run the natives and write their results to the trace
+    Record(Box<dyn Write + Send>),
+    /// Return the results from a trace instead of running the natives, in the order they were
+    /// recorded
+    Replay(VecDeque<(String, Snapshot)>),
+}
+
+impl Replay {
+    /// Replay the trace at `path` if there is one, otherwise record a new one there
+    pub fn file(path: &Path) -> io::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(trace) => Self::parse(&trace).map_err(|err| {
+                let message = format!("{}: {err}", path.display());
+                io::Error::new(io::ErrorKind::InvalidData, message)
+            }),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                Ok(Replay::Record(Box::new(File::create(path)?)))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Replay a trace written while recording
+    pub fn parse(trace: &str) -> Result<Self, String> {
+        let calls = trace
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.is_empty())
+            .map(|(i, line)| {
+                parse_call(line).ok_or_else(|| format!("Invalid call on line {}.", i + 1))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Replay::Replay(calls))
+    }
+}
+
+fn parse_call(line: &str) -> Option<(String, Snapshot)> {
+    let (native, result) = line.split_once(' ')?;
+    let result = match result {
+        "nil" => Snapshot::Nil,
+        _ => match result.split_once(':') {
+            Some((len, string)) if string.len() == len.parse().ok()? => {
+                Snapshot::String(string.to_string())
+            }
+            Some(_) => return None,
+            None => Snapshot::Number(result.parse().ok()?),
+        },
+    };
+    Some((native.to_string(), result))
+}
+
+fn write_call(out: &mut dyn Write, native: &str, result: &Snapshot) -> io::Result<()> {
+    match result {
+        Snapshot::Number(number) => writeln!(out, "{native} {number}"),
+        Snapshot::String(string) => writeln!(out, "{native} {}:{string}", string.len()),
+        _ => writeln!(out, "{native} nil"),
+    }
+}
+
+impl VM {
+    /// Call the nondeterministic native `native` by running `run`, recording its result while
+    /// recording. While replaying, its recorded result instead
+    pub(crate) fn replayed(
+        &mut self,
+        native: &str,
+        run: impl FnOnce(&mut VM) -> Result<Snapshot, String>,
+    ) -> Result<Value, String> {
+        let result = match &mut self.replay {
+            None => run(self)?,
+            Some(Replay::Replay(calls)) => match calls.pop_front() {
+                Some((recorded, result)) if recorded == native => result,
+                Some((recorded, _)) => {
+                    return Err(format!(
+                        "The replay trace has a call to {recorded} here, not {native}."
+                    ))
+                }
+                None => return Err(format!("The replay trace has no more calls to {native}.")),
+            },
+            Some(Replay::Record(_)) => {
+                let result = run(self)?;
+                let Some(Replay::Record(out)) = &mut self.replay else {
+                    unreachable!()
+                };
+                write_call(out, native, &result)
+                    .map_err(|err| format!("Could not write the replay trace: {err}."))?;
+                result
+            }
+        };
+        Ok(result.rebuild(self))
+    }
+}
@@ -0,0 +1,174 @@
+//! Errors as the command line interpreter shows them: the source line the error is on with the
+//! offending token underlined, a suggestion for a misspelled name, and colors on a terminal.
+//!
+//! ```text
+//! Error: Undefined variable 'conut'.
+//!   |
+//! 3 |   return a + conut;
+//!   |              ^^^^^
+//! Did you mean 'count'?
+//! [line 3] in f(1)
+//! [line 5] in script
+//! ```
+//!
+//! The `Display` of the errors stays plain, for embedders and tests.
+
+use std::fmt::Write;
+
+use crate::{
+    compile::{lexer::Lexer, CompileError},
+    vm::{InterpretError, RuntimeError},
+};
+
+const RED: &str = "1;31";
+const BOLD: &str = "1";
+const BLUE: &str = "1;34";
+const CYAN: &str = "36";
+const DIM: &str = "2";
+
+/// `error` as a report ending in a newline. `src` is the source it's in, runtime errors only
+/// quote it if it's given
+pub fn render(error: &InterpretError, src: Option<&str>, color: bool) -> String {
+    let mut out = String::new();
+    match error {
+        InterpretError::CompileError(errors) => {
+            for error in errors {
+                compile_error(&mut out, error, color);
+            }
+        }
+        InterpretError::RuntimeError(error) | InterpretError::StackOverflow(error) => {
+            runtime_error(&mut out, error, src, color);
+        }
+        InterpretError::OutOfFuel | InterpretError::Interrupted => {
+            let _ = writeln!(out, "{}: {error}", paint("Error", RED, color));
+        }
+    }
+    out
+}
+
+fn compile_error(out: &mut String, error: &CompileError, color: bool) {
+    let _ = write!(
+        out,
+        "[line {}:{}] {}",
+        error.line,
+        error.column,
+        paint("Error", RED, color)
+    );
+    if !error.at.is_empty() {
+        let _ = write!(out, " at {}", error.at);
+    }
+    let _ = writeln!(out, ": {}", paint(&error.message, BOLD, color));
+
+    if !error.snippet.is_empty() && error.column > 0 {
+        let lexeme = error
+            .at
+            .strip_prefix('\'')
+            .and_then(|at| at.strip_suffix('\''));
+        let width = lexeme.map_or(1, |lexeme| lexeme.chars().count());
+        excerpt(out, error.line, &error.snippet, error.column, width, color);
+    }
+}
+
+fn runtime_error(out: &mut String, error: &RuntimeError, src: Option<&str>, color: bool) {
+    let _ = writeln!(
+        out,
+        "{}: {}",
+        paint("Error", RED, color),
+        paint(&error.message, BOLD, color)
+    );
+
+    let innermost = error.trace.first().filter(|frame| frame.column > 0);
+    let line = innermost.zip(src).and_then(|(frame, src)| {
+        let text = src.lines().nth(frame.line.checked_sub(1)? as usize)?;
+        Some((frame, text))
+    });
+    if let Some((frame, text)) = line {
+        // Underline the token the failing instruction was compiled from
+        let width = Lexer::new(text)
+            .tokens()
+            .find(|lexeme| lexeme.span.column == frame.column)
+            .map_or(1, |lexeme| lexeme.text.chars().count());
+        excerpt(out, frame.line, text, frame.column, width, color);
+    }
+
+    if let Some(name) = &error.suggestion {
+        let help = format!("Did you mean '{name}'?");
+        let _ = writeln!(out, "{}", paint(&help, CYAN, color));
+    }
+    for frame in &error.trace {
+        let _ = writeln!(out, "{}", paint(&frame.to_string(), DIM, color));
+    }
+}
+
+/// Line `line` of the source, `text`, with `width` characters underlined from the byte
+/// `column`
+fn excerpt(out: &mut String, line: u32, text: &str, column: u32, width: usize, color: bool) {
+    let number = line.to_string();
+    let gutter = " ".repeat(number.len());
+    let bar = paint("|", BLUE, color);
+    // Tabs stay tabs so the carets line up with the text above
+    let end = (column as usize - 1).min(text.len());
+    let indent: String = text
+        .get(..end)
+        .unwrap_or_default()
+        .chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let carets = paint(&"^".repeat(width.max(1)), RED, color);
+
+    let _ = writeln!(out, "{gutter} {bar}");
+    let _ = writeln!(out, "{} {bar} {text}", paint(&number, BLUE, color));
+    let _ = writeln!(out, "{gutter} {bar} {indent}{carets}");
+}
+
+fn paint(text: &str, style: &str, color: bool) -> String {
+    if color {
+        format!("\x1b[{style}m{text}\x1b[0m")
+    } else {
+        text.to_string()
+    }
+}
+
+/// The candidate closest to `name` if it's close enough to be a typo of it: at most one edit
+/// (an insertion, deletion, substitution or swap of neighbours) for every three characters.
+/// Ties go to the first candidate
+pub fn suggest<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    let limit = (name.chars().count() / 3).max(1);
+    let mut best = None;
+    for candidate in candidates {
+        let distance = edit_distance(name, candidate);
+        if distance == 0 || distance > limit {
+            continue;
+        }
+        if best.map_or(true, |(best, _)| distance < best) {
+            best = Some((distance, candidate));
+        }
+    }
+    best.map(|(_, candidate)| candidate)
+}
+
+/// Optimal string alignment distance, Levenshtein distance where swapping two neighbouring
+/// characters counts as one edit
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    // Rows for the prefixes of `a` two, one and zero characters shorter
+    let mut before: Vec<usize> = vec![0; b.len() + 1];
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+    for i in 1..=a.len() {
+        current[0] = i;
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            current[j] = (previous[j] + 1)
+                .min(current[j - 1] + 1)
+                .min(previous[j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                current[j] = current[j].min(before[j - 2] + 1);
+            }
+        }
+        std::mem::swap(&mut before, &mut previous);
+        std::mem::swap(&mut previous, &mut current);
+    }
+    previous[b.len()]
+}
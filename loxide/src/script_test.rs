@@ -0,0 +1,247 @@
+use std::{
+    io::{self, Write},
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+};
+
+use loxide::{interpret, mem::GcConfig, vm::OutputBuffer, InterpretError, VmOptions, VM};
+
+/// What a script expects, from comments in the format of the Crafting Interpreters test suite:
+///
+/// ```lox
+/// print 1 + 2; // expect: 3
+/// var a = nil + 1; // expect runtime error: Operands must be two numbers or two strings.
+/// print; // Error at ';': Expect expression.
+/// // [line 7] Error at end: Expect '}' after block.
+/// ```
+#[derive(Debug, Default, PartialEq)]
+struct Expectations {
+    /// Lines printed, with the line of their comment
+    output: Vec<(u32, String)>,
+    /// As `[line N] Error at 'x': message`
+    compile_errors: Vec<String>,
+    /// The message and line of the error
+    runtime_error: Option<(u32, String)>,
+}
+
+impl Expectations {
+    fn parse(source: &str) -> Self {
+        let mut expectations = Self::default();
+        for (line, text) in (1..).zip(source.lines()) {
+            let Some((_, comment)) = text.split_once("// ") else {
+                continue;
+            };
+
+            if let Some(output) = comment.strip_prefix("expect: ") {
+                expectations.output.push((line, output.to_string()));
+            } else if let Some(message) = comment.strip_prefix("expect runtime error: ") {
+                expectations.runtime_error = Some((line, message.to_string()));
+            } else if comment.starts_with("Error") {
+                let error = format!("[line {line}] {comment}");
+                expectations.compile_errors.push(error);
+            } else if let Some(error) = comment
+                .strip_prefix("[line ")
+                .or_else(|| comment.strip_prefix("[c line "))
+            {
+                // Errors for other implementations, i.e. `[java line 3]`, don't apply
+                expectations.compile_errors.push(format!("[line {error}"));
+            }
+        }
+        expectations
+    }
+}
+
+/// `loxide test`: run every script in `paths` and check it against its expectation comments.
+/// Failures and a summary go to `out`, returns whether all the scripts passed
+pub fn run(
+    paths: &[PathBuf],
+    gc_config: GcConfig,
+    optimize: bool,
+    mut out: impl Write,
+) -> io::Result<bool> {
+    let mut scripts = vec![];
+    for path in paths {
+        collect_scripts(path, &mut scripts)?;
+    }
+
+    let mut failed = 0;
+    for script in &scripts {
+        let source = std::fs::read_to_string(script)?;
+        let failures = check(&source, gc_config, optimize);
+        if !failures.is_empty() {
+            failed += 1;
+            writeln!(out, "FAIL {}", script.display())?;
+            for failure in failures {
+                writeln!(out, "     {failure}")?;
+            }
+        }
+    }
+
+    writeln!(out, "{} passed, {failed} failed", scripts.len() - failed)?;
+    Ok(failed == 0)
+}
+
+/// `path` if it's a file, otherwise the `.lox` files under it, sorted by name
+pub fn collect_scripts(path: &Path, scripts: &mut Vec<PathBuf>) -> io::Result<()> {
+    if !path.is_dir() {
+        scripts.push(path.to_owned());
+        return Ok(());
+    }
+
+    let mut entries = std::fs::read_dir(path)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+    for entry in entries {
+        if entry.is_dir() {
+            collect_scripts(&entry, scripts)?;
+        } else if entry
+            .extension()
+            .map_or(false, |extension| extension == "lox")
+        {
+            scripts.push(entry);
+        }
+    }
+    Ok(())
+}
+
+/// Run `source` and compare what happens with its expectations, empty if they all hold
+fn check(source: &str, gc_config: GcConfig, optimize: bool) -> Vec<String> {
+    let expectations = Expectations::parse(source);
+    let stdout = OutputBuffer::default();
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut vm = VM::with_options(VmOptions {
+            gc_config,
+            optimize,
+            stdout: Box::new(stdout.clone()),
+            stderr: Box::new(io::sink()),
+            ..VmOptions::default()
+        });
+        interpret(&mut vm, source).map(|_| ())
+    }));
+
+    let mut failures = vec![];
+    match result {
+        Err(_) => failures.push("The interpreter panicked.".to_string()),
+        Ok(Ok(())) => {
+            if let Some((line, message)) = &expectations.runtime_error {
+                failures.push(format!(
+                    "Expected runtime error '{message}' on line {line}."
+                ));
+            }
+            for error in &expectations.compile_errors {
+                failures.push(format!("Expected compile error '{error}'."));
+            }
+        }
+        Ok(Err(InterpretError::CompileError(errors))) => {
+            let errors: Vec<_> = errors
+                .iter()
+                .map(|error| {
+                    let at = if error.at.is_empty() {
+                        String::new()
+                    } else {
+                        format!(" at {}", error.at)
+                    };
+                    format!("[line {}] Error{at}: {}", error.line, error.message)
+                })
+                .collect();
+            for error in &expectations.compile_errors {
+                if !errors.contains(error) {
+                    failures.push(format!("Expected compile error '{error}'."));
+                }
+            }
+            for error in errors {
+                if !expectations.compile_errors.contains(&error) {
+                    failures.push(format!("Unexpected compile error '{error}'."));
+                }
+            }
+        }
+        Ok(Err(InterpretError::RuntimeError(error) | InterpretError::StackOverflow(error))) => {
+            let message = error.message.lines().next().unwrap_or_default();
+            let line = error.trace.first().map_or(0, |frame| frame.line);
+            match &expectations.runtime_error {
+                Some(expected) if *expected == (line, message.to_string()) => (),
+                Some((expected_line, expected)) => failures.push(format!(
+                    "Expected runtime error '{expected}' on line {expected_line}, got '{message}' on line {line}."
+                )),
+                None => failures.push(format!(
+                    "Unexpected runtime error '{message}' on line {line}."
+                )),
+            }
+        }
+        Ok(Err(InterpretError::OutOfFuel)) => unreachable!("the fuel isn't limited"),
+        Ok(Err(InterpretError::Interrupted)) => unreachable!("nothing interrupts the tests"),
+    }
+
+    let output = stdout.contents();
+    let mut printed = output.lines();
+    for (line, expected) in &expectations.output {
+        match printed.next() {
+            Some(actual) if actual == expected => (),
+            Some(actual) => failures.push(format!(
+                "Expected output '{expected}' on line {line}, got '{actual}'."
+            )),
+            None => failures.push(format!("Missing output '{expected}' on line {line}.")),
+        }
+    }
+    for actual in printed {
+        failures.push(format!("Unexpected output '{actual}'."));
+    }
+
+    failures
+}
+
+#[cfg(test)]
+mod test {
+    use loxide::mem::GcConfig;
+
+    use super::{check, Expectations};
+
+    #[test]
+    fn expectations() {
+        let source = "\
+print 1 + 2; // expect: 3
+print \"a\"; // expect: a
+print; // Error at ';': Expect expression
+// [java line 4] Error at end: Expect '}' after block.
+// [c line 5] Error at end: Expect '}' after block.
+nil + 1; // expect runtime error: Operands must be two numbers or two strings.
+";
+        assert_eq!(
+            Expectations::parse(source),
+            Expectations {
+                output: vec![(1, "3".into()), (2, "a".into())],
+                compile_errors: vec![
+                    "[line 3] Error at ';': Expect expression".into(),
+                    "[line 5] Error at end: Expect '}' after block.".into(),
+                ],
+                runtime_error: Some((6, "Operands must be two numbers or two strings.".into())),
+            }
+        );
+
+        let check = |source| check(source, GcConfig::default(), false);
+        assert_eq!(
+            check("print 1 + 2; // expect: 3\nprint \"a\"; // expect: a"),
+            [""; 0]
+        );
+        assert_eq!(
+            check("print \"a\";\nprint nil; // expect: nil\n\nnil + 1; // expect runtime error: Operands must be two numbers or two strings."),
+            ["Expected output 'nil' on line 2, got 'a'.", "Unexpected output 'nil'."]
+        );
+        assert_eq!(
+            check("var a = 1;\nprint a; // expect: 2\nprint a + nil;"),
+            [
+                "Unexpected runtime error 'Operands must be two numbers or two strings.' on line 3.",
+                "Expected output '2' on line 2, got '1'."
+            ]
+        );
+        assert_eq!(check("print; // Error at ';': Expect expression"), [""; 0]);
+        assert_eq!(
+            check("print 1; // expect: 1\nprint;"),
+            [
+                "Unexpected compile error '[line 2] Error at ';': Expect expression'.",
+                "Missing output '1' on line 1."
+            ]
+        );
+    }
+}
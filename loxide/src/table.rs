@@ -1,9 +1,14 @@
-use std::ptr::{null_mut, NonNull};
+#[cfg(test)]
+mod bench;
+#[cfg(feature = "robinhood")]
+mod robin_hood;
+
+use std::ptr::null_mut;
 
 use crate::{
     mem::{Gc, Greystack},
     obj::{Obj, ObjString},
-    value::Value,
+    value::{Unpacked, Value},
 };
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -28,6 +33,90 @@ impl ObjHash {
 
         ObjHash(hash)
     }
+
+    pub fn hash_bool(b: bool) -> ObjHash {
+        if b {
+            ObjHash(5)
+        } else {
+            ObjHash(3)
+        }
+    }
+
+    pub fn hash_number(num: f64) -> ObjHash {
+        // `0.0 == -0.0`, so they need the same hash
+        let bits = if num == 0.0 { 0 } else { num.to_bits() };
+        // Small integers only differ in the top bits, which the table's mask would cut off.
+        // Multiplying spreads them over the upper half of the product
+        let mixed = (bits ^ (bits >> 32)).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        ObjHash((mixed >> 32) as u32)
+    }
+
+    /// Objects other than strings compare by identity, so they hash their address
+    pub fn hash_obj(obj: *mut Obj) -> ObjHash {
+        ObjHash(((obj as usize) >> 3) as u32)
+    }
+
+    /// Hash of any value, consistent with `==`: values that are equal hash the same. Strings
+    /// are interned, so their hash is computed once from their characters
+    pub(crate) fn hash_value(value: Value) -> ObjHash {
+        match value.unpack() {
+            Unpacked::Bool(b) => Self::hash_bool(b),
+            Unpacked::Nil => ObjHash(7),
+            Unpacked::Number(num) => Self::hash_number(num),
+            // Ints are equal to the same `Number`
+            Unpacked::Int(int) => Self::hash_number(int.into()),
+            Unpacked::Obj(obj) => match value.as_obj_str() {
+                Some(string) => string.hash,
+                None => Self::hash_obj(obj.as_ptr()),
+            },
+            Unpacked::Handle(_) => unreachable!("handles are resolved before they're used as keys"),
+        }
+    }
+}
+
+/// A value that can be a table key: anything but `nil`, which marks empty slots, and NaN,
+/// which isn't equal to itself and could never be looked up again.
+///
+/// Keys compare like `==` and hash with [`ObjHash::hash_value`], so they can also be used with
+/// Rust's own collections
+#[derive(Clone, Copy, Debug)]
+pub struct Key(Value);
+
+impl Key {
+    pub(crate) fn new(value: Value) -> Option<Key> {
+        match value.unpack() {
+            Unpacked::Nil => None,
+            Unpacked::Number(num) if num.is_nan() => None,
+            _ => Some(Key(value)),
+        }
+    }
+
+    pub(crate) fn string(string: Gc<ObjString>) -> Key {
+        Key(Value::Obj(string.upcast()))
+    }
+
+    pub fn value(self) -> Value {
+        self.0
+    }
+
+    pub(crate) fn hash(self) -> ObjHash {
+        ObjHash::hash_value(self.0)
+    }
+}
+
+/// Like `==`, which only calls values equal that also hash the same
+impl PartialEq for Key {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl Eq for Key {}
+
+impl std::hash::Hash for Key {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        state.write_u32(Key::hash(*self).0);
+    }
 }
 
 pub struct TableIter<'a> {
@@ -53,7 +142,7 @@ impl<'a> Iterator for TableIter<'a> {
             self.index += 1;
 
             // Skip uninitialized and tombstone entries
-            if !entry.key.is_null() {
+            if !entry.key.is_nil() {
                 return Some(entry);
             }
         }
@@ -83,23 +172,33 @@ impl<'a> Iterator for TableIterMut<'a> {
             self.index += 1;
 
             // Skip uninitialized and tombstone entries
-            if !entry.key.is_null() {
+            if !entry.key.is_nil() {
                 return Some(entry);
             }
         }
     }
 }
 
-#[derive(Clone)]
+/// Open addressing hash table, owns its buffer of entries.
+///
+/// Deleting an entry leaves a tombstone so lookups keep probing past it. Tombstones count
+/// towards the load like entries do, and growing rehashes without them: when they make up most
+/// of the load, the table is rebuilt at the same capacity instead of doubling it, so tables
+/// with many deletions don't grow forever
 pub struct Table {
-    pub len: u32,
-    pub cap: u32,
-    pub entries: *mut Entry,
+    /// Live entries
+    len: u32,
+    tombstones: u32,
+    cap: u32,
+    /// Grow once `len + tombstones` would exceed this fraction of `cap`
+    max_load: f32,
+    entries: *mut Entry,
 }
 
+/// Any value but `nil` can be a key, a `nil` key marks an empty slot or a tombstone
 #[derive(Copy, Clone)]
 pub struct Entry {
-    pub key: *mut ObjString,
+    pub key: Value,
     pub value: Value,
 }
 
@@ -107,6 +206,7 @@ impl std::fmt::Debug for Table {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Table")
             .field("len", &self.len)
+            .field("tombstones", &self.tombstones)
             .field("cap", &self.cap)
             .field("entries", &self.entries)
             .finish()
@@ -115,19 +215,10 @@ impl std::fmt::Debug for Table {
 
 impl std::fmt::Debug for Entry {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        unsafe {
-            f.debug_struct("Entry")
-                .field(
-                    "key",
-                    &match self.key.as_ref() {
-                        Some(obj_str) => obj_str.as_str(),
-                        None => "null_key",
-                    },
-                )
-                .field("(key_ptr)", &self.key)
-                .field("value", &self.value)
-                .finish()
-        }
+        f.debug_struct("Entry")
+            .field("key", &self.key)
+            .field("value", &self.value)
+            .finish()
     }
 }
 
@@ -135,13 +226,43 @@ impl Table {
     pub const TABLE_MAX_LOAD: f32 = 0.75;
 
     pub fn new() -> Self {
+        Self::with_max_load(Self::TABLE_MAX_LOAD)
+    }
+
+    /// A table that grows once more than `max_load` of its slots are in use, which trades
+    /// memory for shorter probes. Defaults to [`TABLE_MAX_LOAD`](Self::TABLE_MAX_LOAD)
+    ///
+    /// # Panics
+    ///
+    /// If `max_load` isn't in `0.0..1.0`: lookups stop at an empty slot, so there has to be one
+    pub fn with_max_load(max_load: f32) -> Self {
+        assert!(
+            max_load > 0.0 && max_load < 1.0,
+            "max load must be in 0.0..1.0, got {max_load}"
+        );
         Self {
             len: 0,
+            tombstones: 0,
             cap: 0,
+            max_load,
             entries: null_mut(),
         }
     }
 
+    /// Number of entries
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Number of slots, including empty ones and tombstones
+    pub fn capacity(&self) -> usize {
+        self.cap as usize
+    }
+
     pub fn iter(&self) -> TableIter {
         TableIter {
             table: self,
@@ -170,11 +291,8 @@ impl Table {
         match self.entries_slice() {
             Some(entries) => {
                 for entry in entries.iter() {
-                    match NonNull::new(entry.key) {
-                        Some(key) => {
-                            to.set(key, entry.value);
-                        }
-                        None => (),
+                    if !entry.key.is_nil() {
+                        to.set_value(Key(entry.key), entry.value);
                     }
                 }
             }
@@ -182,7 +300,10 @@ impl Table {
         }
     }
 
+    /// Rehash the entries into `new_cap` slots, dropping the tombstones. `new_cap` must be a
+    /// power of two with room for the entries
     pub fn adjust_capacity(&mut self, new_cap: u32) {
+        debug_assert!(new_cap.is_power_of_two() && new_cap > self.len);
         let mut entries = vec![Entry::uninitialized(); new_cap as usize];
 
         if !self.entries.is_null() {
@@ -195,78 +316,179 @@ impl Table {
                 unsafe { Vec::from_raw_parts(self.entries, self.cap as usize, self.cap as usize) };
 
             for entry in old_entries.iter() {
-                unsafe {
-                    let key = match NonNull::new(entry.key) {
-                        Some(key) => key,
-                        None => continue,
-                    };
-
-                    let dest = Self::find_entry_from_ptr(entries.as_mut_ptr(), new_cap, key);
-                    (*dest).key = key.as_ptr();
-                    (*dest).value = entry.value;
+                if entry.key.is_nil() {
+                    continue;
                 }
+
+                Self::place(entries.as_mut_ptr(), new_cap, *entry);
                 new_len += 1;
             }
 
             self.len = new_len;
         }
 
+        self.tombstones = 0;
         self.entries = entries.as_mut_ptr();
         self.cap = new_cap;
 
         let _ = entries.leak();
     }
 
-    pub fn set(&mut self, key: NonNull<ObjString>, val: Value) -> bool {
-        if self.len as f32 + 1.0 > self.cap as f32 * Self::TABLE_MAX_LOAD {
-            let new_cap = if self.cap < 8 { 8 } else { self.cap * 2 };
+    pub(crate) fn set(&mut self, key: Gc<ObjString>, val: Value) -> bool {
+        self.set_value(Key::string(key), val)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn delete(&mut self, key: Gc<ObjString>) -> bool {
+        self.delete_value(Key::string(key))
+    }
+
+    pub(crate) fn get(&self, key: Gc<ObjString>) -> Option<Value> {
+        self.get_value(Key::string(key))
+    }
+
+    /// Insert or overwrite `key`. Returns whether the key is new
+    pub fn set_value(&mut self, key: Key, val: Value) -> bool {
+        if (self.len + self.tombstones + 1) as f32 > self.cap as f32 * self.max_load {
+            self.grow();
+        }
+        self.insert(key.0, val)
+    }
+
+    /// Make room for another entry: rehash at the same capacity if that drops enough
+    /// tombstones to get the load down to half of the maximum, double the capacity otherwise
+    fn grow(&mut self) {
+        let new_cap = if (self.len + 1) as f32 <= self.cap as f32 * self.max_load / 2.0 {
+            self.cap
+        } else {
+            Self::capacity_for(self.len + 1, self.max_load).max(self.cap * 2)
+        };
+        self.adjust_capacity(new_cap);
+    }
+
+    /// Smallest capacity that holds `len` entries without going over `max_load`
+    fn capacity_for(len: u32, max_load: f32) -> u32 {
+        let mut cap = 8;
+        while len as f32 > cap as f32 * max_load {
+            cap *= 2;
+        }
+        cap
+    }
+
+    /// Rehash into the smallest capacity that holds the entries, dropping the tombstones, or
+    /// free the slots if there are no entries
+    pub fn shrink_to_fit(&mut self) {
+        if self.len == 0 {
+            *self = Self::with_max_load(self.max_load);
+            return;
+        }
+        let new_cap = Self::capacity_for(self.len, self.max_load);
+        if new_cap < self.cap || self.tombstones > 0 {
             self.adjust_capacity(new_cap);
         }
+    }
+
+    pub fn delete_value(&mut self, key: Key) -> bool {
+        if self.len == 0 {
+            return false;
+        }
+        self.remove(key.0)
+    }
+
+    pub fn get_value(&self, key: Key) -> Option<Value> {
+        if self.len == 0 {
+            return None;
+        }
+        self.find(key.0).map(|entry| entry.value)
+    }
+
+    pub(crate) fn mark(&self, greystack: &mut Greystack) {
+        for entry in self.iter() {
+            entry.key.mark(greystack);
+            entry.value.mark(greystack);
+        }
+    }
+}
+
+impl Drop for Table {
+    fn drop(&mut self) {
+        if self.entries.is_null() {
+            return;
+        }
+
+        // Safety:
+        // `entries` came from a `Vec` of `cap` entries in `adjust_capacity`, and entries don't
+        // need to be dropped
+        let _entries =
+            unsafe { Vec::from_raw_parts(self.entries, self.cap as usize, self.cap as usize) };
+    }
+}
+
+impl Entry {
+    fn uninitialized() -> Self {
+        Self {
+            key: Value::Nil,
+            value: Value::Nil,
+        }
+    }
 
+    fn is_uninitialized(&self) -> bool {
+        self.key.is_nil() && self.value.is_nil()
+    }
+}
+
+/// Linear probing: a key goes into the first free slot after the one its hash points to, and
+/// deleting it leaves a tombstone
+#[cfg(not(feature = "robinhood"))]
+impl Table {
+    /// Insert or overwrite `key`, there has to be room for it. Returns whether it's new
+    fn insert(&mut self, key: Value, val: Value) -> bool {
         let entry = self.find_entry_mut(key);
 
-        let is_new_key = entry.is_uninitialized();
-        let should_increment_len = entry.is_uninitialized();
+        let is_new_key = entry.key.is_nil();
+        let reuses_tombstone = entry.is_tombstone();
 
-        entry.key = key.as_ptr();
+        entry.key = key;
         entry.value = val;
 
-        if should_increment_len {
+        if is_new_key {
             self.len += 1;
         }
+        if reuses_tombstone {
+            self.tombstones -= 1;
+        }
 
         is_new_key
     }
 
-    pub fn delete(&mut self, key: NonNull<ObjString>) -> bool {
-        if self.len == 0 {
-            return false;
-        }
+    /// The entry of `key`, the table mustn't be empty
+    fn find(&self, key: Value) -> Option<&Entry> {
+        let entry = self.find_entry(key);
+        (!entry.key.is_nil()).then_some(entry)
+    }
 
+    fn remove(&mut self, key: Value) -> bool {
         let entry = self.find_entry_mut(key);
-        if entry.key.is_null() {
+        if entry.key.is_nil() {
             return false;
         }
 
         // place tombstone
         entry.delete();
+        self.len -= 1;
+        self.tombstones += 1;
         true
     }
 
-    pub fn get(&self, key: NonNull<ObjString>) -> Option<Value> {
-        if self.len == 0 {
-            return None;
-        }
-
-        let entry = self.find_entry(key);
-        if entry.key.is_null() {
-            return None;
+    /// Put `entry` in a table being rehashed, which doesn't have its key yet
+    fn place(entries: *mut Entry, cap: u32, entry: Entry) {
+        unsafe {
+            let dest = Self::find_entry_from_ptr(entries, cap, entry.key);
+            *dest = entry;
         }
-
-        Some(entry.value)
     }
 
-    pub fn find_entry(&self, key: NonNull<ObjString>) -> &Entry {
+    fn find_entry(&self, key: Value) -> &Entry {
         unsafe {
             Self::find_entry_from_ptr(self.entries, self.cap, key)
                 .as_ref()
@@ -274,7 +496,7 @@ impl Table {
         }
     }
 
-    pub fn find_entry_mut(&mut self, key: NonNull<ObjString>) -> &mut Entry {
+    fn find_entry_mut(&mut self, key: Value) -> &mut Entry {
         unsafe {
             Self::find_entry_from_ptr(self.entries, self.cap, key)
                 .as_mut()
@@ -282,7 +504,7 @@ impl Table {
         }
     }
 
-    pub fn find_string(&self, string: &str, hash: ObjHash) -> Option<Gc<ObjString>> {
+    pub(crate) fn find_string(&self, string: &str, hash: ObjHash) -> Option<Gc<ObjString>> {
         if self.len == 0 {
             return None;
         }
@@ -294,35 +516,34 @@ impl Table {
 
         let mut index = hash.0 & (self.cap - 1);
 
-        unsafe {
-            loop {
-                let entry = entries[index as usize];
-                if entry.key.is_null() {
-                    if matches!(entry.value, Value::Nil) {
-                        return None;
-                    }
-                } else if (*entry.key).len == string.len() as u32
-                    && (*entry.key).hash == hash
-                    && (*entry.key).as_str() == string
-                {
-                    return Some(Gc::new(NonNull::new_unchecked(entry.key)));
+        loop {
+            let entry = entries[index as usize];
+            if entry.key.is_nil() {
+                if entry.value.is_nil() {
+                    return None;
                 }
-
-                index = (index + 1) & (self.cap - 1);
+            } else if let Some(key) = entry.key.as_obj_str()
+                && key.len == string.len() as u32
+                && key.hash == hash
+                && key.as_str() == string
+            {
+                return Some(key);
             }
+
+            index = (index + 1) & (self.cap - 1);
         }
     }
 
-    fn find_entry_from_ptr(entries: *mut Entry, cap: u32, key: NonNull<ObjString>) -> *mut Entry {
-        let mut index = unsafe { (*key.as_ptr()).hash.0 } & (cap - 1);
+    fn find_entry_from_ptr(entries: *mut Entry, cap: u32, key: Value) -> *mut Entry {
+        let mut index = ObjHash::hash_value(key).0 & (cap - 1);
         let mut tombstone: *mut Entry = null_mut();
 
         loop {
             unsafe {
                 let entry = entries.offset(index as isize);
-                if (*entry).key.is_null() {
+                if (*entry).key.is_nil() {
                     // It's a tombstone
-                    if matches!((*entry).value, Value::Nil) {
+                    if (*entry).value.is_nil() {
                         return if !tombstone.is_null() {
                             tombstone
                         } else {
@@ -330,7 +551,7 @@ impl Table {
                         };
                     }
                     tombstone = entry;
-                } else if (*entry).key == key.as_ptr() {
+                } else if (*entry).key == key {
                     return entry;
                 }
 
@@ -339,66 +560,52 @@ impl Table {
         }
     }
 
-    pub fn free(table: &mut Table) {
-        if table.entries.is_null() {
-            return;
+    /// How many slots a lookup of `key` probes, for measuring how tombstones and the load
+    /// factor affect lookups
+    pub fn probe_length(&self, key: Key) -> usize {
+        if self.cap == 0 {
+            return 0;
         }
-
-        // free the buckets
-        let _entries =
-            unsafe { Vec::from_raw_parts(table.entries, table.len as usize, table.cap as usize) };
-
-        table.len = 0;
-        table.cap = 0;
-        table.entries = null_mut();
-    }
-
-    pub fn mark(&self, greystack: &mut Greystack) {
-        for entry in self.iter() {
-            Obj::mark(entry.key.cast(), greystack);
-            entry.value.mark(greystack);
+        let mut index = key.hash().0 & (self.cap - 1);
+        let mut probes = 1;
+        loop {
+            // Safety: `index` is masked to the capacity
+            let entry = unsafe { &*self.entries.add(index as usize) };
+            if entry.key == key.0 || entry.is_uninitialized() {
+                return probes;
+            }
+            probes += 1;
+            index = (index + 1) & (self.cap - 1);
         }
     }
 
     pub fn remove_white(&mut self) {
+        let mut removed = 0;
         for entry in self.iter_mut() {
-            let is_marked = unsafe {
-                // Safety:
-                // TableIterMut skips over entries with null keys (unintialized and tombstoned entries)
-                !entry
-                    .key
-                    .cast::<Obj>()
-                    .as_ref()
-                    .unwrap_unchecked()
-                    .is_marked
+            let is_white = match entry.key.unpack() {
+                Unpacked::Obj(key) => !key.is_marked,
+                _ => false,
             };
 
-            if is_marked {
+            if is_white {
                 entry.delete();
+                removed += 1;
             }
         }
+        self.len -= removed;
+        self.tombstones += removed;
     }
 }
 
+#[cfg(not(feature = "robinhood"))]
 impl Entry {
     fn delete(&mut self) {
-        self.key = null_mut();
+        self.key = Value::Nil;
         // place tombstone
         self.value = Value::Bool(true);
     }
 
-    fn uninitialized() -> Self {
-        Self {
-            key: null_mut(),
-            value: Value::Nil,
-        }
-    }
-
-    fn is_uninitialized(&self) -> bool {
-        self.key.is_null() && self.value.is_nil()
-    }
-
     fn is_tombstone(&self) -> bool {
-        self.key.is_null() && matches!(self.value, Value::Bool(true))
+        self.key.is_nil() && self.value == Value::Bool(true)
     }
 }
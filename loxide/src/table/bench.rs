@@ -0,0 +1,95 @@
+//! Micro-benchmarks of `Table`, to compare linear probing with Robin Hood hashing:
+//!
+//! ```bash
+//! cargo bench --lib table::bench
+//! cargo bench --lib table::bench --features robinhood
+//! ```
+
+extern crate test;
+
+use test::{black_box, Bencher};
+
+use crate::{
+    mem::Mem,
+    table::{Key, Table},
+    value::Value,
+};
+
+const KEYS: usize = 1_000;
+
+/// `n` string keys, interned in `mem`
+fn string_keys(mem: &mut Mem, prefix: &str, n: usize) -> Vec<Key> {
+    (0..n)
+        .map(|i| Key::string(mem.copy_string(&format!("{prefix}{i}"))))
+        .collect()
+}
+
+fn filled(keys: &[Key]) -> Table {
+    let mut table = Table::new();
+    for (i, key) in keys.iter().enumerate() {
+        table.set_value(*key, Value::Number(i as f64));
+    }
+    table
+}
+
+#[bench]
+fn insert(b: &mut Bencher) {
+    let mut mem = Mem::new();
+    let keys = string_keys(&mut mem, "key", KEYS);
+    b.iter(|| filled(black_box(&keys)));
+}
+
+#[bench]
+fn lookup_hit(b: &mut Bencher) {
+    let mut mem = Mem::new();
+    let keys = string_keys(&mut mem, "key", KEYS);
+    let table = filled(&keys);
+    b.iter(|| {
+        for key in &keys {
+            black_box(table.get_value(*key));
+        }
+    });
+}
+
+#[bench]
+fn lookup_miss(b: &mut Bencher) {
+    let mut mem = Mem::new();
+    let keys = string_keys(&mut mem, "key", KEYS);
+    let missing = string_keys(&mut mem, "missing", KEYS);
+    let table = filled(&keys);
+    b.iter(|| {
+        for key in &missing {
+            black_box(table.get_value(*key));
+        }
+    });
+}
+
+#[bench]
+fn lookup_number(b: &mut Bencher) {
+    let keys: Vec<Key> = (0..KEYS)
+        .map(|i| Key::new(Value::Number(i as f64)).unwrap())
+        .collect();
+    let table = filled(&keys);
+    b.iter(|| {
+        for key in &keys {
+            black_box(table.get_value(*key));
+        }
+    });
+}
+
+/// A queue of 100 keys, each deleted 100 insertions later
+#[bench]
+fn churn(b: &mut Bencher) {
+    let mut mem = Mem::new();
+    let keys = string_keys(&mut mem, "key", KEYS);
+    b.iter(|| {
+        let mut table = Table::new();
+        for (i, key) in keys.iter().enumerate() {
+            table.set_value(*key, Value::Nil);
+            if i >= 100 {
+                table.delete_value(keys[i - 100]);
+            }
+        }
+        table
+    });
+}
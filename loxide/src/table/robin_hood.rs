@@ -0,0 +1,209 @@
+//! Robin Hood hashing for [`Table`], enabled with the `robinhood` feature.
+//!
+//! Like linear probing, a key goes into the first free slot after the one its hash points to,
+//! but on the way it takes the slot of any entry that is closer to its own: entries that
+//! probed far are "poor" and get to displace "rich" ones. That evens out probe lengths, and a
+//! lookup can stop as soon as it reaches an entry closer to its slot than the key would be,
+//! which makes misses cheap. Deleting shifts the following entries back by one instead of
+//! leaving a tombstone, so the table never has any.
+//!
+//! The distance of an entry from its slot isn't stored, it's recomputed from the hash of its
+//! key, which for strings is cached in the string
+
+use super::{Entry, Key, ObjHash, Table};
+use crate::{
+    mem::Gc,
+    obj::ObjString,
+    value::{Unpacked, Value},
+};
+
+impl Table {
+    /// Insert or overwrite `key`, there has to be room for it. Returns whether it's new
+    pub(super) fn insert(&mut self, key: Value, val: Value) -> bool {
+        let mask = self.cap - 1;
+        let mut index = ObjHash::hash_value(key).0 & mask;
+        let mut distance = 0;
+        loop {
+            // Safety: `index` is masked to the capacity
+            let slot = unsafe { &mut *self.entries.add(index as usize) };
+            if slot.key.is_nil() {
+                *slot = Entry { key, value: val };
+                self.len += 1;
+                return true;
+            }
+            if slot.key == key {
+                slot.value = val;
+                return false;
+            }
+            // An entry closer to its slot means `key` isn't in the table, it goes here and the
+            // entry moves on
+            let slot_distance = Self::distance(slot.key, index, mask);
+            if slot_distance < distance {
+                let displaced = std::mem::replace(slot, Entry { key, value: val });
+                let next = (index + 1) & mask;
+                Self::shift_in(self.entries, mask, displaced, next, slot_distance + 1);
+                self.len += 1;
+                return true;
+            }
+            index = (index + 1) & mask;
+            distance += 1;
+        }
+    }
+
+    /// The entry of `key`, the table mustn't be empty
+    pub(super) fn find(&self, key: Value) -> Option<&Entry> {
+        let index = self.index_of(key)?;
+        // Safety: `index_of` returns an index in the table
+        Some(unsafe { &*self.entries.add(index as usize) })
+    }
+
+    pub(super) fn remove(&mut self, key: Value) -> bool {
+        let Some(mut index) = self.index_of(key) else {
+            return false;
+        };
+        let mask = self.cap - 1;
+        // Shift the entries after it back until one is already in its own slot
+        loop {
+            let next = (index + 1) & mask;
+            // Safety: both indices are masked to the capacity
+            unsafe {
+                let next_entry = *self.entries.add(next as usize);
+                if next_entry.key.is_nil() || Self::distance(next_entry.key, next, mask) == 0 {
+                    *self.entries.add(index as usize) = Entry::uninitialized();
+                    break;
+                }
+                *self.entries.add(index as usize) = next_entry;
+            }
+            index = next;
+        }
+        self.len -= 1;
+        true
+    }
+
+    /// Put `entry` in a table that doesn't have its key yet and has room for it
+    pub(super) fn place(entries: *mut Entry, cap: u32, entry: Entry) {
+        let mask = cap - 1;
+        let index = ObjHash::hash_value(entry.key).0 & mask;
+        Self::shift_in(entries, mask, entry, index, 0);
+    }
+
+    /// Put `entry` in the first free slot from `index`, which is `distance` from its own,
+    /// displacing entries that are closer to their slot on the way
+    fn shift_in(
+        entries: *mut Entry,
+        mask: u32,
+        mut entry: Entry,
+        mut index: u32,
+        mut distance: u32,
+    ) {
+        loop {
+            // Safety: `index` is masked to the capacity
+            let slot = unsafe { &mut *entries.add(index as usize) };
+            if slot.key.is_nil() {
+                *slot = entry;
+                return;
+            }
+            let slot_distance = Self::distance(slot.key, index, mask);
+            if slot_distance < distance {
+                entry = std::mem::replace(slot, entry);
+                distance = slot_distance;
+            }
+            index = (index + 1) & mask;
+            distance += 1;
+        }
+    }
+
+    /// Index of the entry of `key`
+    fn index_of(&self, key: Value) -> Option<u32> {
+        let mask = self.cap - 1;
+        let mut index = ObjHash::hash_value(key).0 & mask;
+        let mut distance = 0;
+        loop {
+            // Safety: `index` is masked to the capacity
+            let entry = unsafe { &*self.entries.add(index as usize) };
+            if entry.key.is_nil() || Self::distance(entry.key, index, mask) < distance {
+                return None;
+            }
+            if entry.key == key {
+                return Some(index);
+            }
+            index = (index + 1) & mask;
+            distance += 1;
+        }
+    }
+
+    /// How far the entry of `key` at `index` is from the slot its hash points to
+    fn distance(key: Value, index: u32, mask: u32) -> u32 {
+        index.wrapping_sub(ObjHash::hash_value(key).0) & mask
+    }
+
+    pub(crate) fn find_string(&self, string: &str, hash: ObjHash) -> Option<Gc<ObjString>> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let mask = self.cap - 1;
+        let mut index = hash.0 & mask;
+        let mut distance = 0;
+        loop {
+            // Safety: `index` is masked to the capacity
+            let entry = unsafe { &*self.entries.add(index as usize) };
+            if entry.key.is_nil() || Self::distance(entry.key, index, mask) < distance {
+                return None;
+            }
+            if let Some(key) = entry.key.as_obj_str()
+                && key.len == string.len() as u32
+                && key.hash == hash
+                && key.as_str() == string
+            {
+                return Some(key);
+            }
+            index = (index + 1) & mask;
+            distance += 1;
+        }
+    }
+
+    /// How many slots a lookup of `key` probes, for measuring how the load factor affects
+    /// lookups
+    pub fn probe_length(&self, key: Key) -> usize {
+        if self.cap == 0 {
+            return 0;
+        }
+        let mask = self.cap - 1;
+        let mut index = key.hash().0 & mask;
+        let mut distance = 0;
+        loop {
+            // Safety: `index` is masked to the capacity
+            let entry = unsafe { &*self.entries.add(index as usize) };
+            if entry.key.is_nil()
+                || entry.key == key.value()
+                || Self::distance(entry.key, index, mask) < distance
+            {
+                return distance as usize + 1;
+            }
+            index = (index + 1) & mask;
+            distance += 1;
+        }
+    }
+
+    pub fn remove_white(&mut self) {
+        let mut removed = 0;
+        for entry in self.iter_mut() {
+            let is_white = match entry.key.unpack() {
+                Unpacked::Obj(key) => !key.is_marked,
+                _ => false,
+            };
+
+            if is_white {
+                *entry = Entry::uninitialized();
+                removed += 1;
+            }
+        }
+
+        // The entries behind the removed ones may not be reachable from their slot anymore,
+        // rehashing puts them back in place
+        if removed > 0 {
+            self.adjust_capacity(self.cap);
+        }
+    }
+}
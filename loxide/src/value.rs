@@ -1,129 +1,240 @@
 use std::{
-    fmt::Debug,
+    fmt::{Debug, Display},
+    marker::PhantomData,
     ops::{Add, Div, Mul, Neg, Sub},
-    ptr::NonNull,
 };
 
 use crate::{
-    mem::{Gc, Greystack},
+    mem::{Gc, Greystack, Handle},
+    native_fn::Arity,
     obj::{
-        Obj, ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjKind, ObjNative,
-        ObjPtrWrapper, ObjString,
+        Obj, ObjBoundMethod, ObjChannel, ObjClass, ObjClosure, ObjFunction, ObjGenerator,
+        ObjInstance, ObjKind, ObjList, ObjMap, ObjModule, ObjNative, ObjString,
     },
+    pool::Snapshot,
+    VM,
 };
 
+use self::display::ValueWrapper;
+
+pub mod display;
+#[cfg(feature = "nanboxing")]
+mod nanbox;
+
+#[cfg(feature = "nanboxing")]
+pub(crate) use nanbox::Unpacked;
+#[cfg(feature = "nanboxing")]
+pub use nanbox::Value;
+
 pub type ValueArray = Vec<Value>;
 
+/// Build values with `Value::Number(1.0)`, `Value::Nil`, ..., inside the crate they're taken
+/// apart by matching on `Value::unpack`. With the `nanboxing` feature the same API is backed by a NaN-boxed
+/// `u64` instead, which halves its size
+///
+/// Lox has a single number type, but whole numbers that fit in 32 bits are usually stored as
+/// an `Int` so counting and indexing don't go through floats: integer literals are ints, and
+/// so are the results of integer arithmetic unless it overflows or a division leaves a rest.
+/// An `Int` behaves exactly like the equal `Number`, they compare and hash the same and print
+/// the same
+///
+/// A value holding an object doesn't keep it alive, and outside the crate its object can only be
+/// looked at through the VM it came from, see [`VM::value_ref`](crate::VM::value_ref). The VM
+/// hands objects out as a [`Handle`] instead of their address, so a value whose object was
+/// collected can't find another object that took its place
+#[cfg(not(feature = "nanboxing"))]
+#[derive(Copy, Clone)]
+pub struct Value(Unpacked);
+
+/// A [`Value`] taken apart, for matching on its type
+#[cfg(not(feature = "nanboxing"))]
 #[derive(Copy, Clone)]
-pub enum Value {
+pub(crate) enum Unpacked {
     Bool(bool),
     Number(f64),
+    Int(i32),
     Nil,
     Obj(Gc<Obj>),
+    /// An object as the host holds it, see [`Mem::export`](crate::mem::Mem::export)
+    Handle(Handle),
+}
+
+#[cfg(not(feature = "nanboxing"))]
+#[allow(non_snake_case, non_upper_case_globals)]
+impl Value {
+    pub const Nil: Value = Value(Unpacked::Nil);
+
+    #[inline(always)]
+    pub fn Bool(b: bool) -> Self {
+        Value(Unpacked::Bool(b))
+    }
+
+    #[inline(always)]
+    pub fn Number(num: f64) -> Self {
+        Value(Unpacked::Number(num))
+    }
+
+    #[inline(always)]
+    pub fn Int(int: i32) -> Self {
+        Value(Unpacked::Int(int))
+    }
+
+    #[inline(always)]
+    pub(crate) fn Obj(obj: Gc<Obj>) -> Self {
+        Value(Unpacked::Obj(obj))
+    }
+
+    #[inline(always)]
+    pub(crate) fn Handle(handle: Handle) -> Self {
+        Value(Unpacked::Handle(handle))
+    }
+
+    #[inline(always)]
+    pub(crate) fn unpack(self) -> Unpacked {
+        self.0
+    }
 }
 
 impl Value {
-    // pub fn from_num(num: f64) -> Self {
-    //     let bits = num.to_bits();
-    //     Self(bits)
-    // }
-
-    // pub fn to_num(self) -> f64 {
-    //     f64::from_bits(self.0)
-    // }
-
-    // #[cfg(not(feature = "nanboxing"))]
-    pub fn mark(&self, greystack: &mut Greystack) {
-        match *self {
-            Value::Obj(obj) => Obj::mark(obj.as_ptr(), greystack),
+    pub(crate) fn mark(&self, greystack: &mut Greystack) {
+        match self.unpack() {
+            Unpacked::Obj(obj) => Obj::mark(obj, greystack),
             _ => (),
         }
     }
 
-    pub fn is_str(&self) -> bool {
-        match *self {
-            Value::Obj(obj) => obj.kind == ObjKind::Str,
+    pub(crate) fn is_str(&self) -> bool {
+        match self.unpack() {
+            Unpacked::Obj(obj) => obj.kind == ObjKind::Str,
             _ => false,
         }
     }
 
-    pub fn is_fn(&self) -> bool {
-        match self {
-            Value::Obj(obj) => obj.kind == ObjKind::Fn,
-            _ => false,
+    pub(crate) fn as_bound_method(&self) -> Option<Gc<ObjBoundMethod>> {
+        match self.unpack() {
+            Unpacked::Obj(obj) if obj.kind == ObjKind::BoundMethod => Some(unsafe { obj.cast() }),
+            _ => None,
         }
     }
 
-    pub fn is_native(&self) -> bool {
-        match self {
-            Value::Obj(obj) => obj.kind == ObjKind::Native,
-            _ => false,
+    pub(crate) fn as_instance_fn(&self) -> Option<Gc<ObjInstance>> {
+        match self.unpack() {
+            Unpacked::Obj(obj) if obj.kind == ObjKind::Instance => Some(unsafe { obj.cast() }),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_class(&self) -> Option<Gc<ObjClass>> {
+        match self.unpack() {
+            Unpacked::Obj(obj) if obj.kind == ObjKind::Class => Some(unsafe { obj.cast() }),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_fn(&self) -> Option<Gc<ObjFunction>> {
+        match self.unpack() {
+            Unpacked::Obj(obj) if obj.kind == ObjKind::Fn => Some(unsafe { obj.cast() }),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_obj_native(&self) -> Option<Gc<ObjNative>> {
+        match self.unpack() {
+            Unpacked::Obj(obj) if obj.kind == ObjKind::Native => Some(unsafe { obj.cast() }),
+            _ => None,
         }
     }
 
-    pub fn as_bound_method(&self) -> Option<Gc<ObjBoundMethod>> {
-        match self {
-            Value::Obj(obj) if obj.kind == ObjKind::BoundMethod => Some(obj.cast()),
+    pub(crate) fn as_obj_closure(&self) -> Option<Gc<ObjClosure>> {
+        match self.unpack() {
+            Unpacked::Obj(obj) if obj.kind == ObjKind::Closure => Some(unsafe { obj.cast() }),
             _ => None,
         }
     }
 
-    pub fn as_instance_fn(&self) -> Option<Gc<ObjInstance>> {
-        match *self {
-            Value::Obj(obj) if obj.kind == ObjKind::Instance => Some(obj.cast()),
+    pub(crate) fn as_list(&self) -> Option<Gc<ObjList>> {
+        match self.unpack() {
+            Unpacked::Obj(obj) if obj.kind == ObjKind::List => Some(unsafe { obj.cast() }),
             _ => None,
         }
     }
 
-    pub fn as_class(&self) -> Option<Gc<ObjClass>> {
-        match *self {
-            Value::Obj(obj) if obj.kind == ObjKind::Class => Some(obj.cast()),
+    pub(crate) fn as_map(&self) -> Option<Gc<ObjMap>> {
+        match self.unpack() {
+            Unpacked::Obj(obj) if obj.kind == ObjKind::Map => Some(unsafe { obj.cast() }),
             _ => None,
         }
     }
 
-    pub fn as_fn(&self) -> Option<Gc<ObjFunction>> {
-        match *self {
-            Value::Obj(obj) if obj.kind == ObjKind::Fn => Some(obj.cast()),
+    pub(crate) fn as_module(&self) -> Option<Gc<ObjModule>> {
+        match self.unpack() {
+            Unpacked::Obj(obj) if obj.kind == ObjKind::Module => Some(unsafe { obj.cast() }),
             _ => None,
         }
     }
 
-    pub fn as_obj_native(&self) -> Option<Gc<ObjNative>> {
-        match *self {
-            Value::Obj(obj) if obj.kind == ObjKind::Native => Some(obj.cast()),
+    pub(crate) fn as_generator(&self) -> Option<Gc<ObjGenerator>> {
+        match self.unpack() {
+            Unpacked::Obj(obj) if obj.kind == ObjKind::Generator => Some(unsafe { obj.cast() }),
             _ => None,
         }
     }
 
-    pub fn as_obj_closure(&self) -> Option<Gc<ObjClosure>> {
-        match self {
-            Value::Obj(obj) if obj.kind == ObjKind::Closure => Some(obj.cast()),
+    pub(crate) fn as_channel(&self) -> Option<Gc<ObjChannel>> {
+        match self.unpack() {
+            Unpacked::Obj(obj) if obj.kind == ObjKind::Channel => Some(unsafe { obj.cast() }),
             _ => None,
         }
     }
 
-    pub fn as_obj_str(&self) -> Option<Gc<ObjString>> {
-        match *self {
-            Value::Obj(obj) if obj.kind == ObjKind::Str => Some(obj.cast()),
+    pub(crate) fn as_obj_str(&self) -> Option<Gc<ObjString>> {
+        match self.unpack() {
+            Unpacked::Obj(obj) if obj.kind == ObjKind::Str => Some(unsafe { obj.cast() }),
             _ => None,
         }
     }
 
-    pub fn as_str<'a>(&'a self) -> Option<&'a str> {
+    pub(crate) fn as_str<'a>(&'a self) -> Option<&'a str> {
         let noob = self.as_obj_str()?;
         Some(unsafe { (*noob.as_ptr()).as_str() })
     }
 
+    #[inline]
+    pub fn is_number(self) -> bool {
+        matches!(self.unpack(), Unpacked::Number(_) | Unpacked::Int(_))
+    }
+
+    /// The number, whether it's stored as an `Int` or not
+    #[inline]
+    pub fn as_number(self) -> Option<f64> {
+        match self.unpack() {
+            Unpacked::Number(num) => Some(num),
+            Unpacked::Int(int) => Some(int.into()),
+            _ => None,
+        }
+    }
+
+    /// `num` as an `Int` if it's a whole number that fits, otherwise as a `Number`. `-0.0` is
+    /// kept as it is, `1 / -0` is negative infinity
+    #[inline]
+    pub fn number(num: f64) -> Value {
+        let int = num as i32;
+        if int as f64 == num && (int != 0 || num.is_sign_positive()) {
+            Value::Int(int)
+        } else {
+            Value::Number(num)
+        }
+    }
+
     #[inline]
     pub fn is_nil(self) -> bool {
-        matches!(self, Value::Nil)
+        matches!(self.unpack(), Unpacked::Nil)
     }
 
     pub fn is_falsey(self) -> bool {
-        match self {
-            Value::Bool(b) => !b,
-            Value::Nil => true,
+        match self.unpack() {
+            Unpacked::Bool(b) => !b,
+            Unpacked::Nil => true,
             _ => false,
         }
     }
@@ -136,6 +247,59 @@ impl Value {
         self.lt(&other).into()
     }
 
+    /// Both operands as numbers, the operators only get here after checking for that
+    #[inline]
+    fn numbers(self, other: Self) -> (f64, f64) {
+        match (self.as_number(), other.as_number()) {
+            (Some(a), Some(b)) => (a, b),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Floored modulo, the result has the sign of the divisor: `-7 % 3 == 2`
+    pub fn modulo_owned(self, other: Self) -> Value {
+        if let (Unpacked::Int(a), Unpacked::Int(b)) = (self.unpack(), other.unpack()) && b != 0 {
+            let rem = a.wrapping_rem(b);
+            return match rem != 0 && (rem < 0) != (b < 0) {
+                true => Value::Int(rem + b),
+                false => Value::Int(rem),
+            };
+        }
+        let (a, b) = self.numbers(other);
+        Value::Number(a - b * (a / b).floor())
+    }
+
+    pub fn floor_div_owned(self, other: Self) -> Value {
+        let (a, b) = self.numbers(other);
+        Value::number((a / b).floor())
+    }
+
+    /// Apply a bitwise operation to both operands truncated to 32-bit integers, like JS.
+    /// Shift amounts are taken modulo 32
+    pub fn bitwise(self, other: Self, f: impl FnOnce(i32, i32) -> i32) -> Value {
+        Value::Int(f(self.to_int32(), other.to_int32()))
+    }
+
+    /// The number truncated to a 32-bit integer, see [`to_int32`]
+    pub fn to_int32(self) -> i32 {
+        match self.unpack() {
+            Unpacked::Int(int) => int,
+            Unpacked::Number(num) => to_int32(num),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn pow_owned(self, other: Self) -> Value {
+        if let (Unpacked::Int(a), Unpacked::Int(b)) = (self.unpack(), other.unpack())
+            && let Ok(b) = u32::try_from(b)
+            && let Some(pow) = a.checked_pow(b)
+        {
+            return Value::Int(pow);
+        }
+        let (a, b) = self.numbers(other);
+        Value::Number(a.powf(b))
+    }
+
     fn objs_eq(a: *mut Obj, b: *mut Obj) -> bool {
         a == b
         // unsafe {
@@ -157,37 +321,257 @@ impl Value {
 
 impl Debug for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::Bool(arg0) => f.debug_tuple("Bool").field(arg0).finish(),
-            Self::Number(arg0) => f.debug_tuple("Number").field(arg0).finish(),
-            Self::Nil => write!(f, "Nil"),
-            Self::Obj(arg0) => {
-                write!(f, "{:?}", ObjPtrWrapper(arg0.as_ptr()))
-            }
+        match self.unpack() {
+            Unpacked::Bool(arg0) => f.debug_tuple("Bool").field(&arg0).finish(),
+            Unpacked::Number(arg0) => f.debug_tuple("Number").field(&arg0).finish(),
+            // Looks like the equal `Number`, which it is as far as Lox is concerned
+            Unpacked::Int(arg0) => f.debug_tuple("Number").field(&f64::from(arg0)).finish(),
+            Unpacked::Nil => write!(f, "Nil"),
+            // Only the address or handle, the object may have been collected. See `ValueWrapper`
+            // for what it contains
+            Unpacked::Obj(arg0) => f.debug_tuple("Obj").field(&arg0.as_ptr()).finish(),
+            Unpacked::Handle(arg0) => f
+                .debug_tuple("Handle")
+                .field(&arg0.slot)
+                .field(&arg0.generation)
+                .finish(),
         }
     }
 }
 
 impl PartialOrd for Value {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        match (self, other) {
-            (Value::Number(a), Value::Number(b)) => a.partial_cmp(b),
-            _ => None,
+        match (self.unpack(), other.unpack()) {
+            (Unpacked::Int(a), Unpacked::Int(b)) => a.partial_cmp(&b),
+            _ => self.as_number()?.partial_cmp(&other.as_number()?),
         }
     }
 }
 
 impl PartialEq for Value {
     fn eq(&self, other: &Self) -> bool {
-        match (*self, *other) {
-            (Self::Bool(l0), Self::Bool(r0)) => l0 == r0,
-            (Self::Number(l0), Self::Number(r0)) => l0 == r0,
-            (Self::Obj(a), Self::Obj(b)) => Self::objs_eq(a.as_ptr(), b.as_ptr()),
-            _ => core::mem::discriminant(self) == core::mem::discriminant(other),
+        match (self.unpack(), other.unpack()) {
+            (Unpacked::Bool(l0), Unpacked::Bool(r0)) => l0 == r0,
+            (Unpacked::Number(l0), Unpacked::Number(r0)) => l0 == r0,
+            (Unpacked::Int(l0), Unpacked::Int(r0)) => l0 == r0,
+            (Unpacked::Int(int), Unpacked::Number(num))
+            | (Unpacked::Number(num), Unpacked::Int(int)) => f64::from(int) == num,
+            (Unpacked::Obj(a), Unpacked::Obj(b)) => Self::objs_eq(a.as_ptr(), b.as_ptr()),
+            // An object only ever has one handle
+            (Unpacked::Handle(a), Unpacked::Handle(b)) => a == b,
+            (Unpacked::Nil, Unpacked::Nil) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Returned when converting a [`ValueRef`] into a Rust type fails, and by natives that get an
+/// argument of the wrong type
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TypeError {
+    pub expected: &'static str,
+    pub found: &'static str,
+}
+
+impl std::fmt::Display for TypeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Expected a {} but got a {}.", self.expected, self.found)
+    }
+}
+
+impl std::error::Error for TypeError {}
+
+/// Lets natives use `?` on conversions, the message becomes the runtime error
+impl From<TypeError> for String {
+    fn from(err: TypeError) -> Self {
+        err.to_string()
+    }
+}
+
+impl Value {
+    /// Name of the value's type as seen from Lox
+    pub(crate) fn type_name(&self) -> &'static str {
+        match self.unpack() {
+            Unpacked::Bool(_) => "bool",
+            Unpacked::Number(_) | Unpacked::Int(_) => "number",
+            Unpacked::Nil => "nil",
+            Unpacked::Obj(obj) => match obj.kind {
+                ObjKind::Str => "string",
+                ObjKind::Fn | ObjKind::Closure | ObjKind::BoundMethod | ObjKind::Native => {
+                    "function"
+                }
+                ObjKind::Upvalue => "upvalue",
+                ObjKind::Class => "class",
+                ObjKind::Instance => "instance",
+                ObjKind::List => "list",
+                ObjKind::Map => "map",
+                ObjKind::Module => "module",
+                ObjKind::Generator => "generator",
+                ObjKind::Channel => "channel",
+            },
+            Unpacked::Handle(_) => unreachable!("handles are resolved before they're looked into"),
+        }
+    }
+
+    pub(crate) fn type_error(&self, expected: &'static str) -> TypeError {
+        TypeError {
+            expected,
+            found: self.type_name(),
+        }
+    }
+}
+
+impl Value {
+    /// The number, or the error a native reports for another type
+    pub(crate) fn try_as_number(&self) -> Result<f64, TypeError> {
+        self.as_number().ok_or_else(|| self.type_error("number"))
+    }
+
+    /// The string, or the error a native reports for another type
+    pub(crate) fn try_as_str(&self) -> Result<&str, TypeError> {
+        self.as_str().ok_or_else(|| self.type_error("string"))
+    }
+}
+
+/// A [`Value`] of a [`VM`], which can be looked into for as long as the VM is borrowed: the VM
+/// can't run, so its objects are neither collected nor changed. Made by [`VM::value_ref`], it
+/// prints like `print` does and converts to Rust types
+#[derive(Clone, Copy)]
+pub struct ValueRef<'vm> {
+    /// What the host holds, objects as their handle
+    handle: Value,
+    /// The same value for the VM to look into
+    value: Value,
+    vm: PhantomData<&'vm VM>,
+}
+
+impl<'vm> ValueRef<'vm> {
+    /// # Safety
+    ///
+    /// `value` is `handle` resolved, its object, if any, has to stay alive and unchanged for
+    /// `'vm`
+    pub(crate) unsafe fn new(handle: Value, value: Value) -> Self {
+        Self {
+            handle,
+            value,
+            vm: PhantomData,
+        }
+    }
+
+    pub fn value(self) -> Value {
+        self.handle
+    }
+
+    /// Name of the value's type as seen from Lox, like `"number"` or `"list"`
+    pub fn type_name(self) -> &'static str {
+        self.value.type_name()
+    }
+
+    pub fn as_str(self) -> Option<&'vm str> {
+        let string = self.value.as_obj_str()?;
+        // Safety: the string lives as long as the VM is borrowed
+        Some(unsafe { (*string.as_ptr()).as_str() })
+    }
+
+    /// How many arguments the value takes if it's a native function
+    pub fn native_arity(self) -> Option<Arity> {
+        Some(self.value.as_obj_native()?.arity)
+    }
+
+    /// A copy of the value that doesn't need the VM anymore, see [`Snapshot`]
+    pub fn snapshot(self) -> Snapshot {
+        Snapshot::of(self.value)
+    }
+}
+
+/// Like `print` shows the value
+impl Display for ValueRef<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&ValueWrapper(self.value), f)
+    }
+}
+
+impl Debug for ValueRef<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(&ValueWrapper(self.value), f)
+    }
+}
+
+impl TryFrom<ValueRef<'_>> for f64 {
+    type Error = TypeError;
+
+    fn try_from(value: ValueRef<'_>) -> Result<Self, Self::Error> {
+        value.value.try_as_number()
+    }
+}
+
+impl TryFrom<ValueRef<'_>> for bool {
+    type Error = TypeError;
+
+    fn try_from(value: ValueRef<'_>) -> Result<Self, Self::Error> {
+        match value.value.unpack() {
+            Unpacked::Bool(b) => Ok(b),
+            _ => Err(value.value.type_error("bool")),
+        }
+    }
+}
+
+impl TryFrom<ValueRef<'_>> for () {
+    type Error = TypeError;
+
+    fn try_from(value: ValueRef<'_>) -> Result<Self, Self::Error> {
+        match value.value.unpack() {
+            Unpacked::Nil => Ok(()),
+            _ => Err(value.value.type_error("nil")),
+        }
+    }
+}
+
+impl<'vm> TryFrom<ValueRef<'vm>> for &'vm str {
+    type Error = TypeError;
+
+    fn try_from(value: ValueRef<'vm>) -> Result<Self, Self::Error> {
+        value
+            .as_str()
+            .ok_or_else(|| value.value.type_error("string"))
+    }
+}
+
+impl TryFrom<ValueRef<'_>> for String {
+    type Error = TypeError;
+
+    fn try_from(value: ValueRef<'_>) -> Result<Self, Self::Error> {
+        <&str>::try_from(value).map(str::to_string)
+    }
+}
+
+impl From<()> for Value {
+    fn from(_: ()) -> Self {
+        Self::Nil
+    }
+}
+
+impl From<i32> for Value {
+    fn from(val: i32) -> Self {
+        Self::Int(val)
+    }
+}
+
+impl From<u32> for Value {
+    fn from(val: u32) -> Self {
+        match i32::try_from(val) {
+            Ok(int) => Self::Int(int),
+            Err(_) => Self::Number(val.into()),
         }
     }
 }
 
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(val: Option<T>) -> Self {
+        val.map_or(Value::Nil, Into::into)
+    }
+}
+
 impl From<f64> for Value {
     fn from(val: f64) -> Self {
         Self::Number(val)
@@ -200,13 +584,29 @@ impl From<bool> for Value {
     }
 }
 
+/// Convert to a 32-bit integer the way JS's `ToInt32` does: truncate and wrap around,
+/// non-finite numbers become 0
+pub fn to_int32(n: f64) -> i32 {
+    if !n.is_finite() {
+        return 0;
+    }
+
+    n.trunc().rem_euclid(4294967296.0) as u32 as i32
+}
+
 impl Neg for Value {
     type Output = Value;
 
     fn neg(self) -> Self::Output {
-        match self {
-            Value::Bool(b) => Value::Bool(!b),
-            Value::Number(num) => Value::Number(num),
+        match self.unpack() {
+            Unpacked::Bool(b) => Value::Bool(!b),
+            Unpacked::Number(num) => Value::Number(-num),
+            // `-0` is `-0.0`
+            Unpacked::Int(int) if int != 0 => match int.checked_neg() {
+                Some(neg) => Value::Int(neg),
+                None => Value::Number(-f64::from(int)),
+            },
+            Unpacked::Int(_) => Value::Number(-0.0),
             _ => unreachable!(),
         }
     }
@@ -216,10 +616,13 @@ impl Add for Value {
     type Output = Self;
 
     fn add(self, rhs: Self) -> Self::Output {
-        match (self, rhs) {
-            (Value::Number(a), Value::Number(b)) => Value::Number(a + b),
-            _ => unreachable!(),
+        if let (Unpacked::Int(a), Unpacked::Int(b)) = (self.unpack(), rhs.unpack())
+            && let Some(result) = a.checked_add(b)
+        {
+            return Value::Int(result);
         }
+        let (a, b) = self.numbers(rhs);
+        Value::Number(a + b)
     }
 }
 
@@ -227,10 +630,13 @@ impl Sub for Value {
     type Output = Self;
 
     fn sub(self, rhs: Self) -> Self::Output {
-        match (self, rhs) {
-            (Value::Number(a), Value::Number(b)) => Value::Number(a - b),
-            _ => unreachable!(),
+        if let (Unpacked::Int(a), Unpacked::Int(b)) = (self.unpack(), rhs.unpack())
+            && let Some(result) = a.checked_sub(b)
+        {
+            return Value::Int(result);
         }
+        let (a, b) = self.numbers(rhs);
+        Value::Number(a - b)
     }
 }
 
@@ -238,20 +644,27 @@ impl Mul for Value {
     type Output = Self;
 
     fn mul(self, rhs: Self) -> Self::Output {
-        match (self, rhs) {
-            (Value::Number(a), Value::Number(b)) => Value::Number(a * b),
-            _ => unreachable!(),
+        // A zero times a negative number is `-0.0`, which only a `Number` can be
+        if let (Unpacked::Int(a), Unpacked::Int(b)) = (self.unpack(), rhs.unpack())
+            && let Some(result) = a.checked_mul(b)
+            && (result != 0 || (a >= 0 && b >= 0))
+        {
+            return Value::Int(result);
         }
+        let (a, b) = self.numbers(rhs);
+        Value::Number(a * b)
     }
 }
 
 impl Div for Value {
     type Output = Self;
 
+    /// Dividing ints gives an int if there's no rest
     fn div(self, rhs: Self) -> Self::Output {
-        match (self, rhs) {
-            (Value::Number(a), Value::Number(b)) => Value::Number(a / b),
-            _ => unreachable!(),
+        let (a, b) = self.numbers(rhs);
+        match (self.unpack(), rhs.unpack()) {
+            (Unpacked::Int(_), Unpacked::Int(_)) => Value::number(a / b),
+            _ => Value::Number(a / b),
         }
     }
 }
@@ -0,0 +1,216 @@
+//! How values convert to strings, for `print`, string interpolation and concatenating a string
+//! with another value (`"x = " + x`).
+//!
+//! The [`Display`] of `ValueWrapper` gives the text of any value: numbers the way clox's `%g`
+//! prints them (`3`, `0.3`, `1e+23`), `nil`, `true`, strings as they are, functions as
+//! `<fn name>`, lists and maps with their elements (strings quoted, see
+//! [`Value::fmt_nested`]), and instances as `Name instance`. Instances can override that with a
+//! `toString()` method, which the VM calls instead, see
+//! [`Operator::ToString`](crate::obj::Operator::ToString). Elements of lists and maps always
+//! use their [`Display`] text.
+//!
+//! Outside the crate the text comes from [`ValueRef`](super::ValueRef), which makes sure the
+//! objects are alive.
+//!
+//! A list or map that contains itself prints as `[...]` or `{...}` where it repeats, and so do
+//! the ones nested deeper than [`MAX_DEPTH`].
+
+use std::{
+    cell::RefCell,
+    fmt::{Debug, Display},
+    ptr::NonNull,
+};
+
+use super::{Unpacked, Value};
+use crate::obj::{
+    Obj, ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjGenerator, ObjInstance, ObjKind,
+    ObjList, ObjMap, ObjModule, ObjNative, ObjPtrWrapper, ObjString,
+};
+
+/// How deep lists and maps are printed, so a long chain of them can't overflow the stack
+pub const MAX_DEPTH: usize = 64;
+
+thread_local! {
+    /// The lists, maps and instances being printed, outermost first
+    static PRINTING: RefCell<Vec<*mut Obj>> = RefCell::new(vec![]);
+}
+
+/// Print the contents of the container `obj` with `contents`, or `elided` instead if it's
+/// already being printed further out, or nested more than [`MAX_DEPTH`] deep. For `Display` as
+/// well as `Debug`
+pub(crate) fn fmt_container(
+    obj: *mut Obj,
+    elided: &str,
+    f: &mut std::fmt::Formatter<'_>,
+    contents: impl FnOnce(&mut std::fmt::Formatter<'_>) -> std::fmt::Result,
+) -> std::fmt::Result {
+    let entered = PRINTING.with(|printing| {
+        let mut printing = printing.borrow_mut();
+        if printing.len() >= MAX_DEPTH || printing.contains(&obj) {
+            return false;
+        }
+        printing.push(obj);
+        true
+    });
+    if !entered {
+        return f.write_str(elided);
+    }
+    let result = contents(f);
+    PRINTING.with(|printing| printing.borrow_mut().pop());
+    result
+}
+
+/// Significant digits of a printed number, `%g`'s default
+const PRECISION: i32 = 6;
+
+/// Print `num` like C's `%g`: rounded to [`PRECISION`] significant digits without trailing
+/// zeros, in exponent form if the exponent is below -4 or not below the precision
+fn fmt_number(num: f64, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    if num.is_nan() {
+        return f.write_str("nan");
+    }
+    if num.is_infinite() {
+        return f.write_str(if num < 0.0 { "-inf" } else { "inf" });
+    }
+    // The exponent after rounding, 9.999999 becomes 1e+01
+    let precision = (PRECISION - 1) as usize;
+    let scientific = format!("{num:.precision$e}");
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+    if !(-4..PRECISION).contains(&exponent) {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        write!(
+            f,
+            "{}e{sign}{:02}",
+            trim_zeros(mantissa),
+            exponent.unsigned_abs()
+        )
+    } else {
+        let decimals = (PRECISION - 1 - exponent) as usize;
+        let fixed = format!("{num:.decimals$}");
+        f.write_str(trim_zeros(&fixed))
+    }
+}
+
+/// Drop the trailing zeros of the fraction of `digits`, and the point if nothing is left
+fn trim_zeros(digits: &str) -> &str {
+    if digits.contains('.') {
+        digits.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        digits
+    }
+}
+
+/// Formats a value with what its objects contain, which have to be alive. [`Display`] gives
+/// its text, [`Debug`] its debug form (`Number(3.0)`, `"hello"`). `Value`'s own `Debug` only
+/// shows the address of an object
+#[derive(Clone, Copy)]
+pub(crate) struct ValueWrapper(pub Value);
+
+/// Text of a value as seen from Lox, what string interpolation produces
+impl Display for ValueWrapper {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0.unpack() {
+            Unpacked::Bool(b) => write!(f, "{b}"),
+            Unpacked::Number(num) => fmt_number(num, f),
+            Unpacked::Int(int) => fmt_number(int.into(), f),
+            Unpacked::Nil => write!(f, "nil"),
+            Unpacked::Obj(obj) => write!(f, "{}", ObjPtrWrapper(obj.as_ptr())),
+            Unpacked::Handle(_) => unreachable!("handles are resolved before they're printed"),
+        }
+    }
+}
+
+impl Value {
+    /// Like [`Display`](std::fmt::Display), but quotes strings so they stand out inside lists
+    /// and maps
+    pub(crate) fn fmt_nested(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.as_str() {
+            Some(string) => write!(f, "{string:?}"),
+            None => write!(f, "{}", ValueWrapper(*self)),
+        }
+    }
+}
+
+impl Debug for ValueWrapper {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0.unpack() {
+            Unpacked::Obj(obj) => write!(f, "{:?}", ObjPtrWrapper(obj.as_ptr())),
+            _ => write!(f, "{:?}", self.0),
+        }
+    }
+}
+
+/// User facing text of an object, used when converting values to strings
+impl Display for ObjPtrWrapper {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ptr = match NonNull::new(self.0) {
+            Some(ptr) => ptr,
+            None => return write!(f, "nil"),
+        };
+
+        unsafe {
+            match ptr.as_ref().kind {
+                ObjKind::Str => write!(f, "{}", ptr.cast::<ObjString>().as_ref().as_str()),
+                ObjKind::Fn => match ptr.cast::<ObjFunction>().as_ref().name {
+                    Some(name) => write!(f, "<fn {}>", name.as_str()),
+                    None => write!(f, "<script>"),
+                },
+                ObjKind::Native => {
+                    let name = ptr.cast::<ObjNative>().as_ref().name;
+                    write!(f, "<native fn {}>", name.as_str())
+                }
+                ObjKind::Closure => {
+                    let function = ptr.cast::<ObjClosure>().as_ref().function;
+                    write!(f, "{}", ObjPtrWrapper(function.as_ptr().cast()))
+                }
+                ObjKind::Upvalue => write!(f, "upvalue"),
+                ObjKind::Class => {
+                    write!(f, "{}", ptr.cast::<ObjClass>().as_ref().name.as_str())
+                }
+                ObjKind::Instance => {
+                    let class = ptr.cast::<ObjInstance>().as_ref().class;
+                    write!(f, "{} instance", class.name.as_str())
+                }
+                ObjKind::BoundMethod => {
+                    let method = ptr.cast::<ObjBoundMethod>().as_ref().method;
+                    write!(f, "{}", ObjPtrWrapper(method.as_ptr().cast()))
+                }
+                ObjKind::List => fmt_container(self.0, "[...]", f, |f| {
+                    write!(f, "[")?;
+                    for (i, item) in ptr.cast::<ObjList>().as_ref().items.iter().enumerate() {
+                        if i > 0 {
+                            write!(f, ", ")?;
+                        }
+                        item.fmt_nested(f)?;
+                    }
+                    write!(f, "]")
+                }),
+                ObjKind::Map => fmt_container(self.0, "{...}", f, |f| {
+                    write!(f, "{{")?;
+                    for (i, entry) in ptr.cast::<ObjMap>().as_ref().entries.iter().enumerate() {
+                        if i > 0 {
+                            write!(f, ", ")?;
+                        }
+                        entry.key.fmt_nested(f)?;
+                        write!(f, ": ")?;
+                        entry.value.fmt_nested(f)?;
+                    }
+                    write!(f, "}}")
+                }),
+                ObjKind::Module => {
+                    let module = ptr.cast::<ObjModule>().as_ref();
+                    write!(f, "<module {}>", module.name.as_str())
+                }
+                ObjKind::Generator => {
+                    let function = ptr.cast::<ObjGenerator>().as_ref().closure.function;
+                    match function.name {
+                        Some(name) => write!(f, "<generator {}>", name.as_str()),
+                        None => write!(f, "<generator>"),
+                    }
+                }
+                ObjKind::Channel => write!(f, "<channel>"),
+            }
+        }
+    }
+}
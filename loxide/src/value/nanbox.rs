@@ -0,0 +1,100 @@
+//! NaN-boxed [`Value`], enabled with the `nanboxing` feature.
+//!
+//! Numbers are stored as their bits. Anything else hides in the payload of a quiet NaN that
+//! arithmetic never produces: nil and the booleans as small tags, ints in the low 32 bits with
+//! [`TAG_INT`] set, objects as their pointer with the sign bit set. Pointers have to fit in 48
+//! bits, which holds on x86-64 and aarch64. The [`Handle`]s the host gets for objects have
+//! [`TAG_HANDLE`] set, their slot in the low 32 bits and the generation above the tag.
+
+use std::ptr::NonNull;
+
+use crate::{
+    mem::{Gc, Handle},
+    obj::Obj,
+};
+
+const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+const QNAN: u64 = 0x7ffc_0000_0000_0000;
+
+const TAG_NIL: u64 = 1;
+const TAG_FALSE: u64 = 2;
+const TAG_TRUE: u64 = 3;
+const TAG_INT: u64 = 1 << 32;
+const TAG_HANDLE: u64 = 1 << 33;
+const GENERATION_SHIFT: u32 = 34;
+
+#[derive(Copy, Clone)]
+#[repr(transparent)]
+pub struct Value(u64);
+
+/// A [`Value`] taken apart, for matching on its type
+#[derive(Copy, Clone)]
+pub(crate) enum Unpacked {
+    Bool(bool),
+    Number(f64),
+    Int(i32),
+    Nil,
+    Obj(Gc<Obj>),
+    Handle(Handle),
+}
+
+#[allow(non_snake_case, non_upper_case_globals)]
+impl Value {
+    pub const Nil: Value = Value(QNAN | TAG_NIL);
+
+    #[inline(always)]
+    pub fn Bool(b: bool) -> Self {
+        Value(QNAN | if b { TAG_TRUE } else { TAG_FALSE })
+    }
+
+    /// NaNs are stored as the canonical NaN, so their payload can't be mistaken for a tag
+    #[inline(always)]
+    pub fn Number(num: f64) -> Self {
+        if num.is_nan() {
+            Value(f64::NAN.to_bits())
+        } else {
+            Value(num.to_bits())
+        }
+    }
+
+    #[inline(always)]
+    pub fn Int(int: i32) -> Self {
+        Value(QNAN | TAG_INT | int as u32 as u64)
+    }
+
+    #[inline(always)]
+    pub(crate) fn Obj(obj: Gc<Obj>) -> Self {
+        let ptr = obj.as_ptr() as u64;
+        debug_assert_eq!(ptr & (SIGN_BIT | QNAN), 0, "pointer doesn't fit in 48 bits");
+        Value(SIGN_BIT | QNAN | ptr)
+    }
+
+    #[inline(always)]
+    pub(crate) fn Handle(handle: Handle) -> Self {
+        let generation = u64::from(handle.generation) << GENERATION_SHIFT;
+        Value(QNAN | TAG_HANDLE | generation | u64::from(handle.slot))
+    }
+
+    #[inline(always)]
+    pub(crate) fn unpack(self) -> Unpacked {
+        if self.0 & QNAN != QNAN {
+            Unpacked::Number(f64::from_bits(self.0))
+        } else if self.0 & SIGN_BIT != 0 {
+            let ptr = (self.0 & !(SIGN_BIT | QNAN)) as *mut Obj;
+            Unpacked::Obj(unsafe { Gc::new(NonNull::new_unchecked(ptr)) })
+        } else if self.0 & TAG_INT != 0 {
+            Unpacked::Int(self.0 as u32 as i32)
+        } else if self.0 & TAG_HANDLE != 0 {
+            Unpacked::Handle(Handle {
+                slot: self.0 as u32,
+                generation: (self.0 >> GENERATION_SHIFT) as u16,
+            })
+        } else {
+            match self.0 & 0b11 {
+                TAG_NIL => Unpacked::Nil,
+                TAG_FALSE => Unpacked::Bool(false),
+                _ => Unpacked::Bool(true),
+            }
+        }
+    }
+}
@@ -1,31 +1,137 @@
+pub mod coverage;
+mod dispatch;
+pub mod profile;
+mod scheduler;
+
 use std::{
-    alloc::{self, handle_alloc_error, Layout},
     borrow::Cow,
+    io::{self, Write},
     mem::{transmute, MaybeUninit},
     num::NonZeroUsize,
+    path::PathBuf,
     ptr::{self, addr_of_mut, null_mut, NonNull},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
 };
 
 use crate::{
-    chunk::{InstructionDebug, Opcode},
-    mem::{Gc, Greystack, Mem},
-    native_fn::NativeFnKind,
+    chunk::{
+        disassemble::{disassemble_instruction, format_value},
+        optimize, stack, MethodCache, Opcode, FINALLY_RETURN, FINALLY_THROW,
+    },
+    compile::{CompileError, Parser},
+    globals::Globals,
+    mem::{Gc, GcConfig, Greystack, HeapStats, Mem, StringPool},
+    module::{native, FileResolver, ModuleResolver},
+    native_fn::{
+        Arity, Clock, NativeFnKind, Rng, BUILTINS, IO, MATH, MATH_CONSTANTS, PROCESS, STRINGS, TIME,
+    },
     obj::{
-        Obj, ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjKind, ObjNative,
-        ObjPtrWrapper, ObjPunnable, ObjString, ObjUpvalue,
+        GeneratorState, Obj, ObjBoundMethod, ObjChannel, ObjClass, ObjClosure, ObjFunction,
+        ObjGenerator, ObjInstance, ObjKind, ObjList, ObjModule, ObjNative, ObjPunnable, ObjString,
+        ObjUpvalue, Operator,
     },
-    table::ObjHash,
-    value::Value,
+    pool::Channel,
+    replay::Replay,
+    report,
+    value::{display::ValueWrapper, Unpacked, Value, ValueRef},
 };
 
-const GC_HEAP_GROW_FACTOR: usize = 2;
+use coverage::Coverage;
+use dispatch::*;
+use profile::Profile;
+use scheduler::Scheduler;
+pub(crate) use scheduler::Wait;
 
 pub type InterpretResult<T> = Result<T, InterpretError>;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum InterpretError {
-    RuntimeError,
-    CompileError,
+    RuntimeError(RuntimeError),
+    CompileError(Vec<CompileError>),
+    /// The script ran more instructions than it was allowed to, see
+    /// [`VmOptions::max_instructions`] and [`VM::run_fuel`]
+    OutOfFuel,
+    /// [`VmOptions::interrupt`] was set while the script ran
+    Interrupted,
+    /// The calls went deeper than [`VmOptions::max_frames`] or used more than
+    /// [`VmOptions::stack_size`] values. Unlike other runtime errors this can't be caught, and
+    /// the trace only has the innermost frames
+    StackOverflow(RuntimeError),
+}
+
+impl std::fmt::Display for InterpretError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InterpretError::RuntimeError(err) => write!(f, "{err}"),
+            InterpretError::CompileError(errors) => {
+                for (i, err) in errors.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{err}")?;
+                }
+                Ok(())
+            }
+            InterpretError::OutOfFuel => write!(f, "Script ran out of instructions."),
+            InterpretError::Interrupted => write!(f, "Script was interrupted."),
+            InterpretError::StackOverflow(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for InterpretError {}
+
+/// An error raised while running a script, with the call stack at the time of the error
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub message: String,
+    /// Innermost frame first
+    pub trace: Vec<TraceFrame>,
+    /// A name in scope that's close to the undefined one the error is about
+    pub suggestion: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceFrame {
+    pub line: u32,
+    /// 1-based, 0 for bytecode without columns
+    pub column: u32,
+    /// `None` for top-level code
+    pub function: Option<String>,
+    /// The call's arguments as the debugger prints them, they may have been assigned to since
+    /// the call
+    pub args: Vec<String>,
+}
+
+impl std::fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)?;
+        if let Some(name) = &self.suggestion {
+            write!(f, "\nDid you mean '{name}'?")?;
+        }
+        for frame in &self.trace {
+            write!(f, "\n{frame}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.function {
+            Some(name) => write!(
+                f,
+                "[line {}] in {}({})",
+                self.line,
+                name,
+                self.args.join(", ")
+            ),
+            None => write!(f, "[line {}] in script", self.line),
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -38,7 +144,7 @@ pub struct CallFrame {
     /// ptr into VM's value stack at the first slot this function can use
     pub slots_ptr: *mut Value,
 
-    pub closure: Gc<ObjClosure>,
+    pub(crate) closure: Gc<ObjClosure>,
 }
 
 impl CallFrame {
@@ -63,6 +169,20 @@ impl CallFrame {
             *self.slots_ptr.add(index) = value;
         }
     }
+
+    /// Where the call is at when it's running the instruction at `offset`
+    fn trace(&self, offset: u32) -> TraceFrame {
+        let function = self.function();
+        let chunk = &function.chunk;
+        TraceFrame {
+            line: chunk.get_line(offset as usize),
+            column: chunk.debug.get_column(offset as usize),
+            function: function.name.map(|name| name.as_str().to_string()),
+            args: (1..=function.param_count())
+                .map(|slot| format_value(self.index(slot)))
+                .collect(),
+        }
+    }
 }
 
 pub struct StackIter {
@@ -87,6 +207,10 @@ impl Iterator for StackIter {
 pub struct Stack {
     pub stack: *mut Value,
     pub top: *mut Value,
+    /// End of the stack for scripts, a call only starts if everything its chunk pushes fits
+    /// below this (see [`stack::max_height`]). The [`STACK_SLACK`] slots after it hold what
+    /// instructions push while they run
+    pub limit: *mut Value,
 }
 
 impl Stack {
@@ -130,31 +254,338 @@ impl Stack {
     }
 }
 
+impl Drop for Stack {
+    fn drop(&mut self) {
+        // Safety: `VM::with_options` allocated the slots up to the limit and the slack as a box
+        unsafe {
+            let len = self.limit.offset_from(self.stack) as usize + STACK_SLACK;
+            let slots =
+                std::ptr::slice_from_raw_parts_mut(self.stack.cast::<MaybeUninit<Value>>(), len);
+            drop(Box::from_raw(slots));
+        }
+    }
+}
+
 pub const U8_COUNT: usize = (u8::MAX) as usize + 1; // 256
-const FRAMES_MAX: usize = 64;
+/// Default for [`VmOptions::max_frames`]
+pub const FRAMES_MAX: usize = 64;
+/// Default for [`VmOptions::stack_size`]
 pub const STACK_MAX: usize = 64 * U8_COUNT;
-pub static mut STACK: [MaybeUninit<Value>; STACK_MAX] = [MaybeUninit::uninit(); STACK_MAX];
+/// Slots allocated past the stack's limit, more than a single instruction pushes and pops again
+const STACK_SLACK: usize = 8;
+/// Frames kept in the trace of a [`InterpretError::StackOverflow`]
+const STACK_OVERFLOW_TRACE: usize = 16;
 pub type ValueStack = [MaybeUninit<Value>; STACK_MAX];
 
+/// Configuration of a [`VM`]
+pub struct VmOptions {
+    pub gc_config: GcConfig,
+    /// Share the characters of string constants and names with the other VMs using this pool,
+    /// e.g. when running many small scripts that use the same names. The pool only grows, so
+    /// strings made at runtime aren't pooled
+    pub string_pool: Option<Arc<StringPool>>,
+    /// Define the math natives (`sqrt`, `floor`, `min`, ...) and `pi`
+    pub math: bool,
+    /// Define the string natives (`substring`, `split`, `join`, ...)
+    pub strings: bool,
+    /// Define the console and file natives (`readLine`, `readFile`, `writeFile`, ...) and
+    /// import modules from files, turn this off to keep scripts away from the file system. Off
+    /// by default in WebAssembly, which has neither
+    pub io: bool,
+    /// Directories `import` looks for files in when they aren't next to the importing file,
+    /// `loxide` takes them from `LOX_PATH`
+    pub module_paths: Vec<PathBuf>,
+    /// Load modules through this instead of from files, also when `io` is off
+    pub module_resolver: Option<Box<dyn ModuleResolver>>,
+    /// Allow `import native`, which loads shared libraries and runs their code, see
+    /// [`crate::module::native`]. Off by default, the command line interpreter turns it on
+    pub native_modules: bool,
+    /// Define `now`, `sleep`, `random` and `randomInt`
+    pub time: bool,
+    /// Seed for `random` and `randomInt`, to make runs repeatable. Seeded from the current time
+    /// if `None`
+    pub random_seed: Option<u64>,
+    /// See [`VM::replay`]
+    pub replay: Option<Replay>,
+    /// Define `getenv`, `exit` and `argv`. Off by default so an embedded VM can't reach
+    /// outside the host, the command line interpreter turns it on
+    pub allow_process: bool,
+    /// See [`VM::trace_execution`]
+    pub trace_execution: bool,
+    /// See [`VM::optimize`]
+    pub optimize: bool,
+    /// See [`VM::tail_calls`]
+    pub tail_calls: bool,
+    /// See [`VM::per_iteration_loops`]
+    pub per_iteration_loops: bool,
+    /// See [`VM::count_dispatches`]
+    pub count_dispatches: bool,
+    /// Collect a [`Profile`] of the script in [`VM::profile`]
+    pub profile: bool,
+    /// Record which lines and branches of the script ran in [`VM::coverage`]
+    pub coverage: bool,
+    /// Stop `run()` with [`InterpretError::OutOfFuel`] after this many instructions, so an
+    /// untrusted script can't loop forever
+    pub max_instructions: Option<u64>,
+    /// Stop the script with [`InterpretError::Interrupted`] once this is set, e.g. from another
+    /// thread. Checked on each loop iteration and call, a native that blocks (like `readLine`)
+    /// returns first
+    pub interrupt: Option<Arc<AtomicBool>>,
+    /// How many values the stack holds, the locals and temporaries of all the active calls
+    pub stack_size: usize,
+    /// How deep calls can nest
+    pub max_frames: usize,
+    /// See [`VM::debug_print`]
+    pub debug_print: bool,
+    /// See [`VM::stdout`]
+    pub stdout: Box<dyn Write + Send>,
+    /// See [`VM::stderr`]
+    pub stderr: Box<dyn Write + Send>,
+    /// See [`VM::report_errors`]
+    pub report_errors: bool,
+}
+
+impl Default for VmOptions {
+    fn default() -> Self {
+        Self {
+            gc_config: GcConfig::default(),
+            string_pool: None,
+            math: true,
+            strings: true,
+            io: !cfg!(target_arch = "wasm32"),
+            module_paths: vec![],
+            module_resolver: None,
+            native_modules: false,
+            time: true,
+            random_seed: None,
+            replay: None,
+            allow_process: false,
+            trace_execution: false,
+            optimize: false,
+            tail_calls: true,
+            per_iteration_loops: false,
+            count_dispatches: false,
+            profile: false,
+            coverage: false,
+            max_instructions: None,
+            interrupt: None,
+            stack_size: STACK_MAX,
+            max_frames: FRAMES_MAX,
+            debug_print: false,
+            stdout: Box::new(io::stdout()),
+            stderr: Box::new(io::stderr()),
+            report_errors: true,
+        }
+    }
+}
+
+/// A writer that keeps everything written to it, to capture the output of a VM. Clones share
+/// the same buffer, also across threads:
+///
+/// ```
+/// use loxide::{vm::OutputBuffer, Loxide, VmOptions};
+///
+/// let output = OutputBuffer::default();
+/// let mut engine = Loxide::with_options(VmOptions {
+///     stdout: Box::new(output.clone()),
+///     ..Default::default()
+/// });
+/// engine.eval("print 1 + 2;").unwrap();
+/// assert_eq!(output.contents(), "3\n");
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct OutputBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl OutputBuffer {
+    /// Everything written so far, invalid UTF-8 is replaced
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+    }
+
+    /// Empty the buffer
+    pub fn clear(&self) {
+        self.0.lock().unwrap().clear();
+    }
+}
+
+impl Write for OutputBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
 pub struct VM {
-    pub stack: Stack,
+    pub(crate) stack: Stack,
 
     /// linked list of open upvalues for the top-most stack,
     /// this points to the top-most open upvalue
     /// (so last in this list is the first open upvalue on the stack)
-    pub open_upvalues: *mut ObjUpvalue,
+    pub(crate) open_upvalues: *mut ObjUpvalue,
+
+    /// As many as [`VmOptions::max_frames`]
+    pub(crate) call_frames: Box<[MaybeUninit<CallFrame>]>,
+    pub(crate) call_frame_count: u32,
+
+    /// Exception handlers of the `try` statements being executed, innermost last
+    pub(crate) handlers: Vec<Handler>,
+
+    /// When the VM was created, `clock` counts from here
+    pub start: Clock,
+    /// Generator behind `random` and `randomInt`
+    pub rng: Rng,
+    /// Record what `clock`, `now`, `random`, `randomInt` and `readLine` return, or return what
+    /// they returned in a recorded run, see [`crate::replay`]
+    pub replay: Option<Replay>,
+    /// Print the stack and each instruction to stderr before running it, can be toggled
+    /// while running (e.g. from a native)
+    pub trace_execution: bool,
+    /// Run the peephole optimizer over compiled code, see [`crate::chunk::optimize`]
+    pub optimize: bool,
+    /// Let `return f(...)` reuse the frame of the returning function when `f` is a closure, so
+    /// recursion in tail position doesn't run into [`VmOptions::max_frames`]. On by default,
+    /// turning it off keeps every call in stack traces
+    pub tail_calls: bool,
+    /// Compile `for (var i = ...; ...)` loops so each iteration gets its own `i`, and closures
+    /// created in the body keep the value of their iteration. Off by default, like in clox all
+    /// iterations share one variable
+    pub per_iteration_loops: bool,
+    /// Count the instructions run in `dispatch_count`, to measure what the optimizer saves
+    pub count_dispatches: bool,
+    pub dispatch_count: u64,
+    /// Make `print` show the debug form of values (`Number(3.0)`, `"hello"`) instead of
+    /// printing them like clox does (`3`, `hello`)
+    pub debug_print: bool,
+    /// Where `print` writes, stdout by default. Can be swapped out while running, e.g. for an
+    /// [`OutputBuffer`] to capture the output of a script
+    pub stdout: Box<dyn Write + Send>,
+    /// Where `eprint`, runtime errors, compile errors and `trace_execution` write, stderr by
+    /// default
+    pub stderr: Box<dyn Write + Send>,
+    /// Write compile errors and uncaught runtime errors to [`VM::stderr`]. Off when the host
+    /// reports the errors it gets back itself, like the command line interpreter does
+    pub report_errors: bool,
+    /// Opcode counts and time per function, for `--profile`
+    pub profile: Option<Box<Profile>>,
+    /// Hit counts of lines and branches, for `--coverage`
+    pub coverage: Option<Box<Coverage>>,
+    /// See [`VmOptions::max_instructions`]
+    pub max_instructions: Option<u64>,
+    /// Instructions left to run before stopping with [`InterpretError::OutOfFuel`]
+    pub fuel: u64,
+    /// See [`VmOptions::interrupt`]
+    interrupt: Option<Arc<AtomicBool>>,
+    /// Set while [`VM::run_until`] runs, which gets one instruction of fuel at a time
+    watch: Option<Watch>,
+    /// Arguments for the script, returned by `argv`
+    pub script_args: Vec<String>,
+    /// File the script was read from, its imports are relative to its directory. Imports are
+    /// relative to the working directory without one
+    pub script_path: Option<PathBuf>,
+    /// Where `import` gets modules from, see [`VmOptions::module_resolver`]. `None` disables
+    /// imports
+    pub module_resolver: Option<Box<dyn ModuleResolver>>,
+    /// See [`VmOptions::native_modules`]
+    pub native_modules: bool,
+    /// Where `import native` looks for libraries, see [`VmOptions::module_paths`]
+    pub module_paths: Vec<PathBuf>,
+    /// The modules whose scripts are running, innermost last
+    loading: Vec<Loading>,
+    /// The generators whose frames are running, innermost last
+    resumed: Vec<Resumed>,
+    /// The tasks started with `spawn` that aren't running
+    scheduler: Scheduler,
+    /// Set when the running task has to stop for `sleep` or `await`, with the fuel it had
+    blocked: Option<(Wait, u64)>,
+    /// How many natives are calling back into the VM, see [`VM::call_value_with_args`]
+    callbacks: u32,
+
+    pub(crate) mem: Mem,
+}
+
+/// A module being imported, from the `Import` until its script reaches `EndModule`
+struct Loading {
+    /// The id the resolver gave the module, its key in [`Mem::modules`]
+    key: Gc<ObjString>,
+    /// The path as the `import` wrote it
+    name: Gc<ObjString>,
+    /// Whose globals the script defines, cached once the script is done
+    module: Gc<ObjModule>,
+    /// The frame running the module's script, as a `call_frame_count`
+    frame_count: u32,
+}
+
+/// A generator running on the stack, from being resumed until its frame yields or returns
+struct Resumed {
+    generator: Gc<ObjGenerator>,
+    /// Its frame, as a `call_frame_count`
+    frame_count: u32,
+    /// Where the `ForIter` that resumed it continues once it returns, `None` for `next`
+    exit: Option<u32>,
+}
+
+/// What [`VM::run_until`] pauses the script at. It always pauses before an instruction, so the
+/// stack and the frames can be inspected between runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// The next instruction, see [`VM::step`]
+    Instruction,
+    /// The next instruction from another line, or from the same line in another call
+    Line,
+    /// The start of a call, before the callee's first instruction
+    Call,
+    /// The return of the call that was running, back in its caller
+    Return,
+    /// An instruction from this line, coming from another line or call
+    Breakpoint(u32),
+}
 
-    pub call_frames: [MaybeUninit<CallFrame>; FRAMES_MAX],
-    pub call_frame_count: u32,
+/// Where [`VM::step`] and [`VM::run_until`] stopped
+#[derive(Debug, Clone, PartialEq)]
+pub enum Status {
+    /// Before an instruction, running again continues from there
+    Paused,
+    /// The script returned this value
+    Finished(Value),
+}
 
-    pub mem: Mem,
-    pub grey_stack: Greystack,
+/// The [`Event`] that `run_until` waits for, checked before every instruction
+#[derive(Debug, Clone, Copy)]
+struct Watch {
+    event: Event,
+    /// `call_frame_count` when the run started
+    depth: u32,
+    /// `call_frame_count` and line of the last instruction
+    last: (u32, Option<u32>),
+}
 
-    pub init_string: Gc<ObjString>,
+/// Where to continue when an exception is thrown, pushed by `PushCatch` and `PushFinally`
+#[derive(Debug, Clone, Copy)]
+pub struct Handler {
+    /// The frame the handler belongs to, as a `call_frame_count`
+    pub frame_count: u32,
+    /// Stack top to unwind to, the locals declared in the `try` block are discarded
+    pub stack_top: *mut Value,
+    /// Instruction offset of the `catch` or `finally` block
+    pub target: u32,
+    /// `finally` blocks also run when returning through them
+    pub is_finally: bool,
 }
 
+// Safety: every pointer in the VM (the stack, frames, open upvalues and the heap) points into
+// memory the VM owns and frees, no other VM shares it. What it doesn't own, the natives and the
+// writers, has to be `Send`. Values handed out by the VM may point into its heap too, and may
+// even be sent along with it (with nanboxing a `Value` is a plain `u64`), but outside the crate
+// their objects can only be reached through `&VM` with `VM::value_ref`, or checked by `&mut VM`
+// before they're used, so moving the VM moves every access to its heap with it
+unsafe impl Send for VM {}
+
 impl VM {
-    pub fn init(&mut self, function: Gc<ObjFunction>) {
+    pub(crate) fn init(&mut self, function: Gc<ObjFunction>) {
         let closure = self.mem.alloc_obj(ObjClosure::new(function));
 
         self.call_frames[0] = MaybeUninit::new(CallFrame {
@@ -164,44 +595,117 @@ impl VM {
         });
 
         unsafe {
-            *self.stack.stack = Value::Obj(closure.cast());
+            *self.stack.stack = Value::Obj(closure.upcast());
             self.stack.top = self.stack.stack.add(1);
         }
-        self.define_native("clock", NativeFnKind::Clock);
-        self.define_native("__dummy", NativeFnKind::Dummy);
-
         self.call_frame_count = 1;
-
-        #[cfg(debug_assertions)]
-        {
-            println!("OBJECT LIST!");
-            for obj in &self.mem.obj_list {
-                println!("{:?}", ObjPtrWrapper(obj.as_ptr()));
-            }
-            println!("END OBJECT LIST");
-        }
     }
 
     pub fn new() -> Self {
-        let mut mem = Mem::new();
-        let mut stack = Vec::<Value>::with_capacity(STACK_MAX);
-        // let raw = Box::into_raw(stack.into_boxed_slice());
+        Self::with_options(VmOptions::default())
+    }
 
-        let raw = stack.as_mut_ptr();
-        stack.leak();
+    pub fn with_gc_config(gc_config: GcConfig) -> Self {
+        Self::with_options(VmOptions {
+            gc_config,
+            ..VmOptions::default()
+        })
+    }
 
-        Self {
-            init_string: mem.copy_string("init"),
+    pub fn with_options(options: VmOptions) -> Self {
+        let mem = Mem::with_string_pool(options.gc_config, options.string_pool);
+        // The script's frame needs at least its closure
+        let stack_size = options.stack_size.max(1);
+        // Freed by `Stack::drop`
+        let stack = vec![MaybeUninit::<Value>::uninit(); stack_size + STACK_SLACK];
+        let raw = Box::into_raw(stack.into_boxed_slice()).cast::<Value>();
+
+        let mut vm = Self {
             stack: Stack {
                 stack: raw,
-                top: null_mut(),
+                top: raw,
+                limit: unsafe { raw.add(stack_size) },
             },
             open_upvalues: null_mut(),
-            call_frames: [MaybeUninit::uninit(); FRAMES_MAX],
+            call_frames: vec![MaybeUninit::uninit(); options.max_frames.max(1)].into_boxed_slice(),
             call_frame_count: 0,
+            handlers: vec![],
+            start: Clock::start(),
+            rng: options.random_seed.map_or_else(Rng::from_time, Rng::new),
+            replay: options.replay,
+            trace_execution: options.trace_execution,
+            optimize: options.optimize,
+            tail_calls: options.tail_calls,
+            per_iteration_loops: options.per_iteration_loops,
+            count_dispatches: options.count_dispatches,
+            dispatch_count: 0,
+            profile: options.profile.then(Box::default),
+            coverage: options.coverage.then(Box::default),
+            debug_print: options.debug_print,
+            stdout: options.stdout,
+            stderr: options.stderr,
+            report_errors: options.report_errors,
+            max_instructions: options.max_instructions,
+            fuel: u64::MAX,
+            interrupt: options.interrupt,
+            watch: None,
+            script_args: vec![],
+            script_path: None,
+            native_modules: options.native_modules,
+            module_paths: options.module_paths.clone(),
+            module_resolver: options.module_resolver.or_else(|| {
+                let files = FileResolver::new(options.module_paths);
+                options
+                    .io
+                    .then(|| Box::new(files) as Box<dyn ModuleResolver>)
+            }),
+            loading: vec![],
+            resumed: vec![],
+            scheduler: Scheduler::default(),
+            blocked: None,
+            callbacks: 0,
             mem,
-            grey_stack: vec![],
+        };
+
+        // Defined once so scripts can shadow them, the REPL reuses the VM
+        vm.define_native("clock", NativeFnKind::Clock, Arity::Fixed(0));
+        vm.define_native("__dummy", NativeFnKind::Dummy, Arity::Variadic);
+        vm.define_native("next", NativeFnKind::Next, Arity::Fixed(1));
+        vm.define_native("toString", NativeFnKind::ToString, Arity::Fixed(1));
+        for &(name, arity, builtin) in BUILTINS {
+            vm.define_native(name, NativeFnKind::Builtin(builtin), arity);
+        }
+        if options.math {
+            for &(name, arity, builtin) in MATH {
+                vm.define_native(name, NativeFnKind::Builtin(builtin), arity);
+            }
+            for &(name, value) in MATH_CONSTANTS {
+                let name = vm.mem.copy_constant(name);
+                vm.mem.globals.set(name, Value::Number(value));
+            }
+        }
+        if options.strings {
+            for &(name, arity, builtin) in STRINGS {
+                vm.define_native(name, NativeFnKind::Builtin(builtin), arity);
+            }
+        }
+        if options.io {
+            for &(name, arity, builtin) in IO {
+                vm.define_native(name, NativeFnKind::Builtin(builtin), arity);
+            }
         }
+        if options.time {
+            for &(name, arity, builtin) in TIME {
+                vm.define_native(name, NativeFnKind::Builtin(builtin), arity);
+            }
+        }
+        if options.allow_process {
+            for &(name, arity, builtin) in PROCESS {
+                vm.define_native(name, NativeFnKind::Builtin(builtin), arity);
+            }
+        }
+
+        vm
     }
 
     fn iter_stack(&self) -> StackIter {
@@ -220,139 +724,127 @@ impl VM {
             .map(|val| unsafe { val.assume_init_read() })
     }
 
-    fn trace_references(&mut self, greystack: &mut Greystack) {
-        loop {
-            if greystack.is_empty() {
-                break;
-            }
-
-            // Safety:
-            // We checked that the greystack is non-empty
-            let obj = unsafe { greystack.pop().unwrap_unchecked() };
-            unsafe { Obj::blacken(obj, greystack) };
-        }
-    }
-
-    fn sweep(&mut self) {
-        // Clear references to unmarked strings
-        self.mem.interned_strings.remove_white();
+    /// The objects the VM reaches without going through the heap's tables: the stack, the
+    /// frames' closures, the open upvalues, the modules being imported and the generators
+    /// running
+    fn roots(&self) -> Vec<Gc<Obj>> {
+        let mut roots: Vec<Gc<Obj>> = self
+            .iter_stack()
+            .filter_map(|val| match val.unpack() {
+                Unpacked::Obj(obj) => Some(obj),
+                _ => None,
+            })
+            .collect();
 
-        // Now free all unmarked objects
-        let mut i = 0;
-        loop {
-            let mut obj_ptr = match self.mem.obj_list.get(i) {
-                Some(obj) => *obj,
-                None => break,
-            };
+        roots.extend(self.iter_frames().map(|frame| frame.closure.upcast()));
 
-            if obj_ptr.as_ref().is_marked {
-                obj_ptr.as_mut().is_marked = false;
-                i += 1;
-                continue;
+        let mut upvalue = self.open_upvalues;
+        while !upvalue.is_null() {
+            unsafe {
+                roots.push(Gc::new(NonNull::new_unchecked(upvalue)).upcast());
+                upvalue = (*upvalue).next;
             }
+        }
 
-            self.mem.obj_list.remove(i);
-            Obj::free(obj_ptr.as_non_null_ptr())
+        for module in &self.loading {
+            roots.push(module.key.upcast());
+            roots.push(module.name.upcast());
+            roots.push(module.module.upcast());
         }
+        roots.extend(
+            self.resumed
+                .iter()
+                .map(|resumed| resumed.generator.upcast()),
+        );
+        roots
     }
 
     fn mark_roots(&mut self, greystack: &mut Greystack) {
-        for val in self.iter_stack() {
-            val.mark(greystack);
+        for root in self.roots() {
+            Obj::mark(root, greystack);
         }
+        self.scheduler.mark(greystack);
+    }
 
-        for frame in self.iter_frames() {
-            Obj::mark(frame.closure.cast::<Obj>().as_ptr(), greystack)
-        }
+    pub(crate) fn collect_garbage(&mut self) {
+        let mut greystack = std::mem::take(&mut self.mem.grey_stack);
+        self.mark_roots(&mut greystack);
+        self.mem.collect_garbage(greystack);
+    }
 
-        let mut upvalue = self.open_upvalues;
-        while !upvalue.is_null() {
-            unsafe {
-                Obj::mark(upvalue.cast(), greystack);
-                upvalue = (*upvalue).next;
+    pub(crate) fn alloc_obj<T: ObjPunnable>(&mut self, obj: T) -> Gc<T> {
+        if self.mem.should_run_gc::<T>() {
+            if self.mem.gc_config.log {
+                eprintln!("Allocating a {:?}, now collecting garbage", obj.kind());
             }
+            self.collect_garbage();
         }
 
-        self.mem.globals.mark(greystack);
-
-        Obj::mark(self.init_string.as_ptr().cast(), greystack);
+        self.mem.alloc_obj(obj)
     }
 
-    fn collect_garbage(&mut self) {
-        #[cfg(feature = "debug_gc")]
-        println!("-- gc begin");
-        #[cfg(feature = "debug_gc")]
-        let before = self.mem.bytes_allocated();
-
-        let mut greystack = std::mem::take(&mut self.grey_stack);
-
-        self.mark_roots(&mut greystack);
-        self.trace_references(&mut greystack);
-        self.sweep();
+    /// See [`Mem::take_string`]
+    fn take_string(&mut self, obj_string: NonNull<ObjString>) -> Gc<ObjString> {
+        if self.mem.should_run_gc::<ObjString>() {
+            // The string isn't on the heap yet, so it's safe from the collection
+            self.collect_garbage();
+        }
 
-        self.mem.next_gc = self.mem.bytes_allocated() * GC_HEAP_GROW_FACTOR;
+        // Safety: `ObjString::concat` strings are only ever taken once
+        unsafe { self.mem.take_string(obj_string) }
+    }
 
-        #[cfg(feature = "debug_gc")]
-        {
-            println!("-- gc end");
-            println!(
-                "   collected {} bytes (from {} to {}) next at {}",
-                before - self.mem.bytes_allocated(),
-                before,
-                self.mem.bytes_allocated(),
-                self.mem.next_gc
-            );
-        }
+    /// Create (or reuse) an interned string value.
+    ///
+    /// Strings can't be created with `From<&str>` because they have to be interned in the VM.
+    /// Like any other object, the string can be collected once it's no longer reachable from
+    /// the VM (e.g. stored in a global or on the stack)
+    pub fn create_string(&mut self, string: &str) -> Value {
+        let string = self.alloc_string(string);
+        self.mem.export(string)
     }
 
-    fn alloc_obj<T: ObjPunnable>(&mut self, obj: T) -> Gc<T> {
-        if self.mem.should_run_gc::<T>() {
-            #[cfg(feature = "debug_gc")]
-            println!("Allocated a {:?}, now collecting garbage", obj.kind());
+    /// [`VM::create_string`] for the VM's own use, the string isn't a handle
+    pub(crate) fn alloc_string(&mut self, string: &str) -> Value {
+        if self.mem.should_run_gc::<ObjString>() {
             self.collect_garbage();
         }
 
-        self.mem.alloc_obj(obj)
+        Value::Obj(self.mem.copy_string(string).upcast())
     }
 
-    fn alloc_obj_string(&mut self, obj_string: ObjString) -> Gc<ObjString> {
-        let ptr = self.alloc_obj(obj_string);
-        self.mem.intern_string(ptr.as_non_null_ptr());
-        ptr
+    /// Look into `value`, `None` if it's an object that was collected or belongs to another VM
+    pub fn value_ref(&self, value: Value) -> Option<ValueRef<'_>> {
+        let object = self.mem.resolve(value)?;
+        // Safety: the object is alive, and the VM can't run while it's borrowed
+        Some(unsafe { ValueRef::new(value, object) })
     }
 
-    fn take_string(&mut self, chars: NonNull<u8>, len: u32) -> Gc<ObjString> {
-        let hash = ObjHash::hash_string(unsafe {
-            std::str::from_utf8_unchecked(std::slice::from_raw_parts(chars.as_ptr(), len as usize))
-        });
+    /// Look up a global variable by name
+    pub fn get_global(&mut self, name: &str) -> Option<Value> {
+        let name = self.mem.copy_string(name);
+        let value = self.mem.globals.get(name)?;
+        Some(self.mem.export(value))
+    }
 
-        match self.mem.interned_strings.find_string(
-            unsafe {
-                std::str::from_utf8_unchecked(std::slice::from_raw_parts(
-                    chars.as_ptr(),
-                    len as usize,
-                ))
-            },
-            hash,
-        ) {
-            Some(interned) => {
-                return interned;
-            }
-            None => (),
-        }
+    /// Whether any modules were imported, by this script or an earlier one
+    pub fn has_modules(&self) -> bool {
+        !self.mem.modules.is_empty()
+    }
 
-        let obj_string = ObjString::new(chars, len, hash);
-        self.alloc_obj_string(obj_string)
+    /// Memory usage of the VM's heap, see [`Mem::heap_stats`]
+    pub fn heap_stats(&self) -> HeapStats {
+        self.mem.heap_stats()
     }
 
-    #[cfg(debug_assertions)]
+    #[cfg(test)]
     /// Only to be used for debugging purposes
-    pub fn get_string(&mut self, string: &str) -> Gc<ObjString> {
+    pub(crate) fn get_string(&mut self, string: &str) -> Gc<ObjString> {
         self.mem.copy_string(string)
     }
 
     #[inline]
-    fn push(&mut self, val: Value) {
+    pub(crate) fn push(&mut self, val: Value) {
         // unsafe {
         //     *self.stack.as_mut_ptr().add(self.stack_top as usize) = MaybeUninit::new(val);
         // }
@@ -363,16 +855,107 @@ impl VM {
     }
 
     #[inline]
-    fn pop(&mut self) -> Value {
+    pub(crate) fn pop(&mut self) -> Value {
         self.stack.sub(1);
         unsafe { *self.stack.top }
     }
 
+    #[inline]
+    fn add(&mut self) -> InterpretResult<()> {
+        // Counting loops add small ints, which skips checking for strings and instances
+        if let (Unpacked::Int(a), Unpacked::Int(b)) = (self.peek(1).unpack(), self.peek(0).unpack())
+            && let Some(sum) = a.checked_add(b)
+        {
+            self.pop();
+            self.stack.set(0, Value::Int(sum));
+            Ok(())
+        } else if self.peek(0).is_str() && self.peek(1).is_str() {
+            self.concatenate();
+            Ok(())
+        } else if !self.peek(1).is_number() && self.call_operator(Operator::Add, 1)? {
+            Ok(())
+        } else if self.peek(0).is_str() || self.peek(1).is_str() {
+            // The other operand is converted, and stays on the stack while that allocates
+            for distance in [1, 0] {
+                let string = self.stringify(self.peek(distance))?;
+                self.stack.set(distance, string);
+            }
+            self.concatenate();
+            Ok(())
+        } else if !self.peek(0).is_number() || !self.peek(1).is_number() {
+            Err(self.runtime_error("Operands must be two numbers or two strings.".into()))
+        } else {
+            self.binary_op(std::ops::Add::add)
+        }
+    }
+
+    /// `value` converted to a string, by its `toString()` method if it's an instance that has
+    /// one, see [`value::display`](crate::value::display)
+    fn stringify(&mut self, value: Value) -> InterpretResult<Value> {
+        if value.is_str() {
+            return Ok(value);
+        }
+        self.push(value);
+        let value = match self.operator_result(Operator::ToString, 0)? {
+            Some(string) if string.is_str() => return Ok(string),
+            Some(value) => {
+                self.push(value);
+                value
+            }
+            None => value,
+        };
+        let string = self.alloc_string(&ValueWrapper(value).to_string());
+        self.pop();
+        Ok(string)
+    }
+
+    /// [`binary_op`](Self::binary_op), unless the left operand is an instance overloading
+    /// `operator`
+    #[inline]
+    fn overloadable_op<F: FnOnce(Value, Value) -> Value>(
+        &mut self,
+        operator: Operator,
+        f: F,
+    ) -> InterpretResult<()> {
+        if !self.peek(1).is_number() && self.call_operator(operator, 1)? {
+            return Ok(());
+        }
+        self.binary_op(f)
+    }
+
+    /// Call the method overloading `operator` if the value `arg_count` slots below the top of
+    /// the stack is an instance whose class has one, with the values above it as the arguments.
+    /// Returns whether it did, the result replaces the operands once the method returns
+    fn call_operator(&mut self, operator: Operator, arg_count: u8) -> InterpretResult<bool> {
+        let Some(instance) = self.peek(arg_count as u32).as_instance_fn() else {
+            return Ok(false);
+        };
+        let name = self.mem.operator_names[operator as usize];
+        let Some(method) = instance.class.methods.get(name) else {
+            return Ok(false);
+        };
+        self.call(method.as_obj_closure().unwrap(), arg_count)?;
+        Ok(true)
+    }
+
+    /// Like [`call_operator`](Self::call_operator), but runs the method to completion and
+    /// returns its result, for instructions that need it before they end
+    fn operator_result(
+        &mut self,
+        operator: Operator,
+        arg_count: u8,
+    ) -> InterpretResult<Option<Value>> {
+        if !self.call_operator(operator, arg_count)? {
+            return Ok(None);
+        }
+        self.execute_until_error(self.call_frame_count - 1)
+            .map(Some)
+    }
+
     #[inline]
     fn binary_op<F: FnOnce(Value, Value) -> Value>(&mut self, f: F) -> InterpretResult<()> {
-        if !matches!(self.peek(0), Value::Number(_)) || !matches!(self.peek(1), Value::Number(_)) {
-            self.runtime_error("Operands must be two numbers or two strings.".into());
-            return Err(InterpretError::RuntimeError);
+        if !self.peek(0).is_number() || !self.peek(1).is_number() {
+            return Err(self.runtime_error("Operands must be numbers.".into()));
         }
 
         let b = self.pop();
@@ -387,39 +970,265 @@ impl VM {
         self.stack.top = self.stack.stack;
         self.call_frame_count = 0;
         self.open_upvalues = null_mut();
+        self.handlers.clear();
+        self.loading.clear();
+        self.abandon_generators(0);
+        self.scheduler.clear();
+        self.blocked = None;
     }
 
-    fn runtime_error<'a>(&mut self, err: Cow<'a, str>) {
-        eprintln!("{err}");
+    /// Build a runtime error with a stack trace.
+    ///
+    /// This leaves the stack as is, errors raised while executing can still be caught by a
+    /// `try` statement. Otherwise it's up to the entry point (`run()`, `call_function()`) to
+    /// unwind the stack and `report` the error
+    fn runtime_error<'a>(&mut self, err: Cow<'a, str>) -> InterpretError {
+        let trace = self
+            .iter_frames()
+            .rev()
+            // `instr_offset` already points past the failing instruction
+            .map(|frame| frame.trace(frame.instr_offset.saturating_sub(1)))
+            .collect();
+
+        InterpretError::RuntimeError(RuntimeError {
+            message: err.into_owned(),
+            trace,
+            suggestion: None,
+        })
+    }
 
-        let frame = self.top_call_frame();
-        let instr_idx = frame.instr_offset;
-        let line = frame.function().chunk.lines[instr_idx as usize];
+    fn stack_overflow(&mut self) -> InterpretError {
+        let InterpretError::RuntimeError(mut error) = self.runtime_error("Stack overflow.".into())
+        else {
+            unreachable!()
+        };
+        if error.trace.len() > STACK_OVERFLOW_TRACE {
+            error.message = format!(
+                "Stack overflow, {} calls deep. Innermost calls:",
+                error.trace.len()
+            );
+            error.trace.truncate(STACK_OVERFLOW_TRACE);
+        }
+        InterpretError::StackOverflow(error)
+    }
 
-        eprintln!("[line {line}] in script");
+    /// Report an error that isn't caught on [`VM::stderr`]
+    fn report(&mut self, error: InterpretError) -> InterpretError {
+        if let InterpretError::RuntimeError(error) | InterpretError::StackOverflow(error) = &error
+            && self.report_errors
+        {
+            let _ = writeln!(self.stderr, "{error}");
+        }
+        error
+    }
 
-        for frame in self
-            .call_frames
-            .iter()
-            .take(self.call_frame_count as usize - 1)
+    /// Unwind to the innermost handler pushed since `base_frame_count` and continue at its
+    /// block with `exception`. Returns false if there is no such handler
+    fn throw(&mut self, exception: Value, base_frame_count: u32) -> bool {
+        let Some(handler) = self.handlers.last().copied() else {
+            return false;
+        };
+        if handler.frame_count <= base_frame_count {
+            return false;
+        }
+
+        self.handlers.pop();
+        self.close_upvalues(handler.stack_top);
+        self.stack.top = handler.stack_top;
+        self.call_frame_count = handler.frame_count;
+        // Imports that were unwound can be tried again
+        self.loading
+            .retain(|module| module.frame_count <= handler.frame_count);
+        self.abandon_generators(handler.frame_count);
+        self.top_call_frame_mut().instr_offset = handler.target;
+
+        self.push(exception);
+        if handler.is_finally {
+            self.push(Value::Number(FINALLY_THROW));
+        }
+        true
+    }
+
+    fn uncaught(&mut self, exception: Value) -> InterpretError {
+        self.runtime_error(format!("Uncaught exception: {}", ValueWrapper(exception)).into())
+    }
+
+    /// Return `result` from the top frame, after running the `finally` blocks it returns
+    /// through. Returns the result if this was the last frame to execute
+    fn return_from_frame(&mut self, result: Value, base_frame_count: u32) -> Option<Value> {
+        // Handlers of this frame are gone once it returns, `finally` blocks run first
+        while let Some(handler) = self.handlers.last().copied() {
+            if handler.frame_count != self.call_frame_count {
+                break;
+            }
+
+            self.handlers.pop();
+            if handler.is_finally {
+                self.close_upvalues(handler.stack_top);
+                self.stack.top = handler.stack_top;
+                self.top_call_frame_mut().instr_offset = handler.target;
+                self.push(result);
+                self.push(Value::Number(FINALLY_RETURN));
+                return None;
+            }
+        }
+
+        // A generator that returns is done, a `for` loop over it ends
+        let exit = match self.resumed.last() {
+            Some(resumed) if resumed.frame_count == self.call_frame_count => {
+                let mut resumed = self.resumed.pop().unwrap();
+                resumed.generator.finish();
+                resumed.exit
+            }
+            _ => None,
+        };
+
+        self.close_upvalues(self.top_call_frame().slots_ptr);
+
+        self.stack.top = self.top_call_frame().slots_ptr;
+        self.call_frame_count -= 1;
+
+        if self.call_frame_count == base_frame_count {
+            return Some(result);
+        }
+
+        match exit {
+            Some(exit) => self.top_call_frame_mut().instr_offset = exit,
+            None => self.push(result),
+        }
+        None
+    }
+
+    /// Replace the callee and its `arg_count` arguments on top of the stack by a generator that
+    /// runs the call once it's resumed
+    fn create_generator(&mut self, closure: Gc<ObjClosure>, arg_count: u8) {
+        let count = arg_count as u32 + 1;
+        let slots = (0..count)
             .rev()
+            .map(|distance| self.peek(distance))
+            .collect();
+        // The slots stay on the stack until the generator exists, so they're rooted
+        let generator = self.alloc_obj(ObjGenerator::new(closure, slots));
+        self.stack.sub(count);
+        self.push(Value::Obj(generator.upcast()));
+    }
+
+    /// Put the frame of `generator` back on top of the stack and continue running it. `exit` is
+    /// where the `ForIter` resuming it continues if it returns
+    fn resume(
+        &mut self,
+        mut generator: Gc<ObjGenerator>,
+        exit: Option<u32>,
+    ) -> InterpretResult<()> {
+        if generator.state == GeneratorState::Running {
+            return Err(self.runtime_error("Generator is already running.".into()));
+        }
+        let function = generator.closure.function;
+        let max_stack = match function.chunk.max_stack {
+            Some(max_stack) => max_stack,
+            None => Self::find_max_stack(function),
+        };
+        let room = unsafe { self.stack.limit.offset_from(self.stack.top) };
+        if self.call_frame_count as usize == self.call_frames.len()
+            || room < (generator.slots.len() + max_stack as usize) as isize
         {
+            return Err(self.stack_overflow());
+        }
+
+        let slots = self.stack.top;
+        for slot in std::mem::take(&mut generator.slots) {
+            self.push(slot);
+        }
+
+        // The frame is above every other open upvalue, in the list they go first. Closures may
+        // have assigned to them while the generator was suspended
+        for (mut upvalue, slot) in std::mem::take(&mut generator.upvalues).into_iter().rev() {
             unsafe {
-                let frame = frame.assume_init();
-                let function = frame.function();
-                let instruction = frame.instr_offset;
-                eprintln!(
-                    "[line {}] in {}",
-                    function.chunk.lines[instruction as usize],
-                    match function.name.as_ref() {
-                        Some(name) => name.as_str(),
-                        None => "script",
-                    }
-                )
+                let location = slots.add(slot);
+                *location = upvalue.closed;
+                upvalue.location = NonNull::new_unchecked(location);
+            }
+            upvalue.next = self.open_upvalues;
+            self.open_upvalues = upvalue.as_ptr();
+        }
+
+        let frame = self.call_frames[self.call_frame_count as usize].as_mut_ptr();
+        self.call_frame_count += 1;
+        unsafe {
+            frame.write(CallFrame {
+                instr_offset: generator.instr_offset,
+                slots_ptr: slots,
+                closure: generator.closure,
+            });
+        }
+        for (slot, handler) in std::mem::take(&mut generator.handlers) {
+            self.handlers.push(Handler {
+                frame_count: self.call_frame_count,
+                stack_top: unsafe { slots.add(slot) },
+                ..handler
+            });
+        }
+
+        generator.state = GeneratorState::Running;
+        self.resumed.push(Resumed {
+            generator,
+            frame_count: self.call_frame_count,
+            exit,
+        });
+        Ok(())
+    }
+
+    /// `yield value`: move the top frame into its generator and give `value` to whatever
+    /// resumed it. Returns the value if the frame was the last to execute
+    fn suspend(&mut self, value: Value, base_frame_count: u32) -> Option<Value> {
+        // Only generator functions yield, and their frames only run resumed
+        let mut generator = self.resumed.pop().unwrap().generator;
+        let frame = *self.top_call_frame();
+        let slot = |ptr: *mut Value| unsafe { ptr.offset_from(frame.slots_ptr) as usize };
+
+        let mut upvalue = self.open_upvalues;
+        while let Some(open) = NonNull::new(upvalue) {
+            // Safety: the open upvalues are live, the list holds on to them
+            let open: Gc<ObjUpvalue> = unsafe { Gc::new(open) };
+            if open.location.as_ptr() < frame.slots_ptr {
+                break;
             }
+            generator
+                .upvalues
+                .push((open, slot(open.location.as_ptr())));
+            upvalue = open.next;
+        }
+        self.close_upvalues(frame.slots_ptr);
+
+        while let Some(&handler) = self.handlers.last()
+            && handler.frame_count == self.call_frame_count
+        {
+            self.handlers.pop();
+            generator.handlers.insert(0, (slot(handler.stack_top), handler));
         }
 
-        self.reset_stack();
+        let count = slot(self.stack.top);
+        generator.slots = unsafe { std::slice::from_raw_parts(frame.slots_ptr, count) }.to_vec();
+        generator.instr_offset = frame.instr_offset;
+        generator.state = GeneratorState::Suspended;
+
+        self.stack.top = frame.slots_ptr;
+        self.call_frame_count -= 1;
+        if self.call_frame_count == base_frame_count {
+            return Some(value);
+        }
+
+        self.push(value);
+        None
+    }
+
+    /// Mark the generators whose frames are above `frame_count` done, the frames were unwound
+    fn abandon_generators(&mut self, frame_count: u32) {
+        while let Some(resumed) = self.resumed.last()
+            && resumed.frame_count > frame_count
+        {
+            self.resumed.pop().unwrap().generator.finish();
+        }
     }
 
     fn peek(&self, distance: u32) -> Value {
@@ -433,130 +1242,290 @@ impl VM {
         let b = b.as_obj_str().unwrap();
         let a = a.as_obj_str().unwrap();
 
-        let new_len = a.len + b.len;
-
-        let obj_str = if new_len == 0 {
-            self.alloc_obj_string(ObjString::new(
-                NonNull::dangling(),
-                0,
-                ObjHash::EMPTY_STR_HASH,
-            ))
-        } else {
-            let layout = Layout::array::<u8>(new_len as usize).unwrap();
-            let chars = unsafe {
-                match NonNull::new(alloc::alloc(layout)) {
-                    Some(ptr) => ptr,
-                    None => handle_alloc_error(layout),
-                }
-            };
-
-            unsafe {
-                ptr::copy_nonoverlapping(a.chars.as_ptr(), chars.as_ptr(), a.len as usize);
-                ptr::copy_nonoverlapping(
-                    b.chars.as_ptr(),
-                    chars.as_ptr().offset(a.len as isize),
-                    b.len as usize,
-                );
-            }
+        let obj_str = self.take_string(ObjString::concat([a.as_str(), b.as_str()].into_iter()));
+        self.push(Value::Obj(obj_str.upcast()))
+    }
 
-            self.take_string(chars, new_len)
-        };
+    /// Replace the top `count` values by their concatenation, converting the ones that aren't
+    /// strings first. The result is allocated once, instead of once per `+`
+    fn concatenate_n(&mut self, count: u32) -> InterpretResult<()> {
+        // Converted in place, so the strings stay rooted
+        for distance in (0..count).rev() {
+            let string = self.stringify(self.peek(distance))?;
+            self.stack.set(distance, string);
+        }
 
-        self.push(Value::Obj(obj_str.cast()))
+        let parts = (0..count)
+            .rev()
+            .map(|distance| self.peek(distance).as_obj_str().unwrap());
+        let concat = ObjString::concat(parts);
+        for _ in 0..count {
+            self.pop();
+        }
+        let obj_str = self.take_string(concat);
+        self.push(Value::Obj(obj_str.upcast()));
+        Ok(())
     }
 
-    fn call(&mut self, closure: Gc<ObjClosure>, arg_count: u8) -> bool {
-        let arity = closure.as_ref().function.as_ref().arity;
-        if arg_count != arity {
-            self.runtime_error(format!("Expected {arg_count} arguments but got {arity}.").into());
-            return false;
+    fn call(&mut self, closure: Gc<ObjClosure>, arg_count: u8) -> InterpretResult<()> {
+        self.check_interrupt()?;
+        let arg_count = self.bind_args(closure.function, arg_count)?;
+        if closure.function.generator {
+            self.create_generator(closure, arg_count);
+            return Ok(());
         }
-
-        if self.call_frame_count as usize == FRAMES_MAX {
-            self.runtime_error("Stack overflow.".into());
-            return false;
+        if self.call_frame_count as usize == self.call_frames.len()
+            || !self.stack_fits(closure.function)
+        {
+            return Err(self.stack_overflow());
         }
 
         self.next_call_frame(closure, arg_count);
 
-        true
+        Ok(())
     }
 
-    fn define_native(&mut self, name: &str, native_fn_kind: NativeFnKind) {
-        // We don't want/need to trigger GC here so directly call allocation
-        // functions on `self.mem`
+    /// Fit the `arg_count` arguments on top of the stack to the parameters of `function`:
+    /// parameters with a default value that got no argument are `nil` until the function's code
+    /// sets them, a rest parameter gets the arguments past the others as a list. Returns the
+    /// number of arguments the function's frame starts with
+    #[inline]
+    fn bind_args(&mut self, function: Gc<ObjFunction>, arg_count: u8) -> InterpretResult<u8> {
+        let (arity, optional) = (function.arity, function.optional);
+        if arg_count == arity && optional == 0 && !function.rest {
+            return Ok(arg_count);
+        }
 
-        let name = Value::Obj(self.mem.copy_string(name).cast());
+        let max = arity + optional;
+        if arg_count < arity || (arg_count > max && !function.rest) {
+            let expected = match (optional, function.rest) {
+                (0, false) => arity.to_string(),
+                _ if arg_count < arity => format!("at least {arity}"),
+                _ => format!("at most {max}"),
+            };
+            return Err(self.runtime_error(
+                format!("Expected {expected} arguments but got {arg_count}.").into(),
+            ));
+        }
 
-        let native_fn = Value::Obj(self.mem.alloc_obj(ObjNative::new(native_fn_kind)).cast());
+        let missing = max.saturating_sub(arg_count) as isize + function.rest as isize;
+        if unsafe { self.stack.limit.offset_from(self.stack.top) } < missing {
+            return Err(self.stack_overflow());
+        }
+        for _ in arg_count..max {
+            self.push(Value::Nil);
+        }
+        if function.rest {
+            let extra = arg_count.saturating_sub(max) as u32;
+            let items = (0..extra).rev().map(|i| self.peek(i)).collect();
+            // The arguments stay on the stack until the list exists, so they're rooted
+            let list = self.alloc_obj(ObjList::new(items));
+            self.stack.sub(extra);
+            self.push(Value::Obj(list.upcast()));
+        }
+        Ok(function.param_count() as u8)
+    }
 
-        self.push(name);
-        self.push(native_fn);
+    /// Turn the `named` name and value pairs above `arg_count` positional arguments into
+    /// positional arguments, by the parameter names of the function the call runs. Parameters
+    /// with a default value that are skipped get `nil`, like when they're left out at the end.
+    /// Returns the number of arguments
+    fn bind_named_args(&mut self, arg_count: u8, named: u8) -> InterpretResult<u8> {
+        let callee = self.peek(arg_count as u32 + 2 * named as u32);
+        let Some(function) = self.callee_function(callee) else {
+            return Err(self.runtime_error("Only functions and methods take named arguments.".into()));
+        };
 
-        self.mem.globals.set(
-            // self.stack[self.stack_top as usize - 2]
-            //     .assume_init()
-            //     .as_obj_str()
-            //     .unwrap()
-            //     .as_non_null_ptr(),
-            // self.stack[self.stack_top as usize - 1].assume_init(),
-            self.stack.peek(1).as_obj_str().unwrap().as_non_null_ptr(),
-            self.stack.peek(0),
-        );
+        let mut args = vec![None; function.arity as usize + function.optional as usize];
+        for i in (0..named as u32).rev() {
+            // The compiler always passes the names as strings
+            let Some(name) = self.peek(2 * i + 1).as_obj_str() else {
+                return Err(self.runtime_error("Invalid argument name.".into()));
+            };
+            let Some(index) = function.named_param(name.as_str()) else {
+                return Err(self.runtime_error(format!("No parameter named '{}'.", name.as_str()).into()));
+            };
+            if index < arg_count as usize || args[index].is_some() {
+                return Err(self.runtime_error(
+                    format!(
+                        "Got more than one argument for parameter '{}'.",
+                        name.as_str()
+                    )
+                    .into(),
+                ));
+            }
+            args[index] = Some(self.peek(2 * i));
+        }
+        let missing = (arg_count as usize..function.arity as usize).find(|&i| args[i].is_none());
+        if let Some(index) = missing {
+            let name = function.params[index].as_str();
+            return Err(
+                self.runtime_error(format!("Missing argument for parameter '{name}'.").into())
+            );
+        }
 
-        self.pop();
-        self.pop();
+        self.stack.sub(2 * named as u32);
+        let count = args
+            .iter()
+            .rposition(Option::is_some)
+            .map_or(arg_count as usize, |last| last + 1);
+        if unsafe { self.stack.limit.offset_from(self.stack.top) } < count as isize {
+            return Err(self.stack_overflow());
+        }
+        for arg in &args[arg_count as usize..count] {
+            self.push(arg.unwrap_or(Value::Nil));
+        }
+        Ok(count as u8)
+    }
+
+    /// The function a call of `callee` runs, `None` if it isn't a Lox function
+    fn callee_function(&self, callee: Value) -> Option<Gc<ObjFunction>> {
+        if let Some(closure) = callee.as_obj_closure() {
+            Some(closure.function)
+        } else if let Some(bound) = callee.as_bound_method() {
+            Some(bound.method.function)
+        } else {
+            let class = callee.as_class()?;
+            let initializer = class.methods.get(self.mem.init_string)?;
+            Some(initializer.as_obj_closure()?.function)
+        }
+    }
+
+    /// Whether everything a call of `function` pushes fits on the stack, with the callee and its
+    /// arguments already on top
+    #[inline]
+    fn stack_fits(&self, function: Gc<ObjFunction>) -> bool {
+        let max_stack = match function.chunk.max_stack {
+            Some(max_stack) => max_stack,
+            None => Self::find_max_stack(function),
+        };
+        unsafe { self.stack.limit.offset_from(self.stack.top) >= max_stack as isize }
+    }
+
+    #[cold]
+    fn find_max_stack(mut function: Gc<ObjFunction>) -> u32 {
+        let max_stack = stack::max_height(&function.chunk) as u32;
+        function.chunk.max_stack = Some(max_stack);
+        max_stack
+    }
+
+    fn define_native(&mut self, name: &str, native_fn_kind: NativeFnKind, arity: Arity) {
+        // We don't want/need to trigger GC here so directly call allocation
+        // functions on `self.mem`, this also means we don't have to root the
+        // objects on the stack, which might not be set up yet
+        let name = self.mem.copy_constant(name);
+        let native_fn = self
+            .mem
+            .alloc_obj(ObjNative::new(name, native_fn_kind, arity));
+
+        self.mem.globals.set(name, Value::Obj(native_fn.upcast()));
+    }
+
+    /// Define the global `name` as `channel`. Scripts in VMs given clones of it can send values
+    /// to each other, see [`Channel`]
+    pub fn define_channel(&mut self, name: &str, channel: Channel) {
+        // Like `define_native`, without collecting garbage
+        let name = self.mem.copy_string(name);
+        let channel = self.mem.alloc_obj(ObjChannel::new(channel));
+        self.mem.globals.set(name, Value::Obj(channel.upcast()));
+    }
+
+    /// Restart the generator behind `random` and `randomInt` from `seed`
+    pub fn seed_random(&mut self, seed: u64) {
+        self.rng = Rng::new(seed);
     }
 
-    fn call_value(&mut self, callee: Value, arg_count: u8) -> bool {
-        match callee {
-            Value::Obj(obj) => {
+    /// Install a host function as a global native function called `name`.
+    ///
+    /// The VM checks the number of arguments against `arity` before calling `f`,
+    /// returning `Err` from `f` raises a runtime error with that message.
+    ///
+    /// ```
+    /// use loxide::Value;
+    ///
+    /// let mut vm = loxide::VM::new();
+    /// vm.register_native("add", 2, |_vm, args| match (args[0].as_number(), args[1].as_number()) {
+    ///     (Some(a), Some(b)) => Ok(Value::number(a + b)),
+    ///     _ => Err("Operands must be numbers.".to_string()),
+    /// });
+    /// ```
+    pub fn register_native<F>(&mut self, name: &str, arity: impl Into<Arity>, f: F)
+    where
+        F: Fn(&mut VM, &[Value]) -> Result<Value, String> + Send + 'static,
+    {
+        self.define_native(name, NativeFnKind::Host(Box::new(f)), arity.into());
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: u8) -> InterpretResult<()> {
+        match callee.unpack() {
+            Unpacked::Obj(obj) => {
                 let kind = obj.as_ref().kind;
                 match kind {
                     ObjKind::Class => {
-                        let class: Gc<ObjClass> = obj.cast();
+                        let class: Gc<ObjClass> = unsafe { obj.cast() };
                         let instance = self.alloc_obj(ObjInstance::new(class));
                         self.stack
-                            .set(arg_count as u32, Value::Obj(instance.cast()));
+                            .set(arg_count as u32, Value::Obj(instance.upcast()));
+                        self.initialize_fields(instance)?;
 
-                        if let Some(initializer) = class
-                            .as_ref()
-                            .methods
-                            .get(self.init_string.as_non_null_ptr())
+                        if let Some(initializer) = class.as_ref().methods.get(self.mem.init_string)
                         {
                             return self.call(initializer.as_obj_closure().unwrap(), arg_count);
                         }
 
                         if arg_count != 0 {
-                            self.runtime_error(
-                                format!("Expected 0 arguments but got {:?}", arg_count).into(),
-                            );
-                            return false;
+                            return Err(self.runtime_error(
+                                format!("Expected 0 arguments but got {arg_count}.").into(),
+                            ));
                         }
 
-                        return true;
+                        return Ok(());
                     }
-                    ObjKind::Closure => return self.call(obj.cast(), arg_count),
+                    ObjKind::Closure => return self.call(unsafe { obj.cast() }, arg_count),
                     ObjKind::Native => {
-                        let native: Gc<ObjNative> = obj.cast();
+                        let native: Gc<ObjNative> = unsafe { obj.cast() };
+                        let native = unsafe { &*native.as_ptr() };
+
+                        if let Arity::Fixed(arity) = native.arity && arity != arg_count {
+                            return Err(self.runtime_error(
+                                format!("Expected {arity} arguments but got {arg_count}.").into(),
+                            ));
+                        }
+                        if let NativeFnKind::Next = native.function {
+                            return self.call_next();
+                        }
+                        if let NativeFnKind::ToString = native.function {
+                            let string = self.stringify(self.peek(0))?;
+                            self.stack.sub(2);
+                            self.push(string);
+                            return Ok(());
+                        }
+
+                        // Safety:
+                        // The arguments stay on the stack (and rooted) for the duration of the
+                        // call, anything the native pushes goes above them
                         let values = unsafe {
                             std::slice::from_raw_parts(
                                 self.stack.top.sub(arg_count as usize),
                                 arg_count as usize,
                             )
                         };
-                        let result =
-                            unsafe { native.as_ref().function.call(std::mem::transmute(values)) };
+                        let result = native.function.call(self, values);
 
-                        self.stack.sub(arg_count as u32 + 1);
-
-                        self.push(result);
-                        return true;
+                        match result {
+                            Ok(result) => {
+                                self.stack.sub(arg_count as u32 + 1);
+                                self.push(result);
+                                return Ok(());
+                            }
+                            Err(err) => {
+                                return Err(self.runtime_error(err.into()));
+                            }
+                        }
                     }
                     ObjKind::BoundMethod => {
-                        let bound: Gc<ObjBoundMethod> = obj.cast();
-                        self.stack
-                            .set(arg_count as u32 + 1, bound.as_ref().receiver);
+                        let bound: Gc<ObjBoundMethod> = unsafe { obj.cast() };
+                        self.stack.set(arg_count as u32, bound.as_ref().receiver);
                         return self.call(bound.method, arg_count);
                     }
                     _ => (),
@@ -565,8 +1534,86 @@ impl VM {
             _ => {}
         }
 
-        self.runtime_error("Can only call functions and classes.".into());
-        false
+        Err(self.runtime_error("Can only call functions and classes.".into()))
+    }
+
+    /// Set the declared fields of a new `instance`, running their initializers with `this`
+    /// bound to it
+    fn initialize_fields(&mut self, mut instance: Gc<ObjInstance>) -> InterpretResult<()> {
+        let class = instance.class;
+        for i in 0..class.fields.len() {
+            let (name, initializer) = class.fields[i];
+            let value = match initializer.as_obj_closure() {
+                Some(closure) => {
+                    self.push(Value::Obj(instance.upcast()));
+                    self.call(closure, 0)?;
+                    self.execute_until_error(self.call_frame_count - 1)?
+                }
+                None => Value::Nil,
+            };
+            instance.fields.set(name, value);
+        }
+        Ok(())
+    }
+
+    /// `next(generator)`: its frame takes the place of the call, what it yields or returns is
+    /// the result. Once it's done the result is `nil`
+    fn call_next(&mut self) -> InterpretResult<()> {
+        let Some(generator) = self.peek(0).as_generator() else {
+            let error = self.peek(0).type_error("generator").to_string();
+            return Err(self.runtime_error(error.into()));
+        };
+        if generator.task {
+            return Err(self.runtime_error("Only the scheduler runs tasks.".into()));
+        }
+        if generator.state == GeneratorState::Done {
+            self.stack.sub(2);
+            self.push(Value::Nil);
+            return Ok(());
+        }
+
+        self.stack.sub(2);
+        self.resume(generator, None)
+    }
+
+    /// `return callee(...)`: a closure takes over the current frame, its arguments moved down to
+    /// where the caller's slots start. Anything else is called like `Call` does, the `Return`
+    /// that follows returns the result
+    fn tail_call_value(&mut self, callee: Value, arg_count: u8) -> InterpretResult<()> {
+        let Some(closure) = callee.as_obj_closure() else {
+            return self.call_value(callee, arg_count);
+        };
+        // A generator's frame has to return to whatever resumed it. The compiler doesn't emit
+        // `TailCall` in a `try` statement, but loaded bytecode could, and the handler jumps back
+        // into this frame's code
+        let in_try = self.handlers.last().map_or(false, |handler| {
+            handler.frame_count == self.call_frame_count
+        });
+        if !self.tail_calls
+            || closure.function.generator
+            || self.top_call_frame().function().generator
+            || in_try
+        {
+            return self.call(closure, arg_count);
+        }
+        self.check_interrupt()?;
+        let arg_count = self.bind_args(closure.function, arg_count)?;
+
+        let slots = self.top_call_frame().slots_ptr;
+        self.close_upvalues(slots);
+        unsafe {
+            let count = arg_count as usize + 1;
+            std::ptr::copy(self.stack.top.sub(count), slots, count);
+            self.stack.top = slots.add(count);
+        }
+        if !self.stack_fits(closure.function) {
+            return Err(self.stack_overflow());
+        }
+
+        let frame = self.top_call_frame_mut();
+        frame.closure = closure;
+        frame.instr_offset = 0;
+        Ok(())
     }
 
     fn capture_upvalue(&mut self, local: NonNull<Value>) -> Gc<ObjUpvalue> {
@@ -607,7 +1654,7 @@ impl VM {
 
     fn new_closure(&mut self, function: Gc<ObjFunction>) {
         let closure = self.alloc_obj(ObjClosure::new(function));
-        self.push(Value::Obj(closure.cast()));
+        self.push(Value::Obj(closure.upcast()));
         unsafe {
             for i in 0..(*closure.as_ptr()).upvalue_count {
                 let byte = self.read_byte();
@@ -664,51 +1711,142 @@ impl VM {
         }
     }
 
-    fn define_method(&mut self, name: Gc<ObjString>) {
+    fn define_method(&mut self, name: Gc<ObjString>) -> InterpretResult<()> {
         let method = self.peek(0);
-        let class_value = self.peek(1);
-        let mut class = class_value.as_class().unwrap();
+        let mut class = self.member_class(true)?;
         let class = class.as_mut();
-        class.methods.set(name.as_non_null_ptr(), method);
+        class.methods.set(name, method);
         self.pop();
+        Ok(())
     }
 
-    fn bind_method(&mut self, class: Gc<ObjClass>, name: Gc<ObjString>) -> bool {
-        let method = match class.methods.get(name.as_non_null_ptr()) {
+    /// The class a class body instruction adds the member on top of the stack to. Methods and
+    /// accessors have to be closures, the calls that find them later rely on it. The compiler
+    /// always emits them that way, bytecode loaded from a file might not
+    fn member_class(&mut self, closure: bool) -> InterpretResult<Gc<ObjClass>> {
+        match self.peek(1).as_class() {
+            Some(class) if !closure || self.peek(0).as_obj_closure().is_some() => Ok(class),
+            _ => Err(self.runtime_error("Invalid class member.".into())),
+        }
+    }
+
+    fn bind_method(&mut self, class: Gc<ObjClass>, name: Gc<ObjString>) -> InterpretResult<()> {
+        let method = match class.methods.get(name) {
             Some(method) => method,
             None => {
-                self.runtime_error(format!("Undefined property {}", name.as_str()).into());
-
-                return false;
+                return Err(
+                    self.runtime_error(format!("Undefined property '{}'.", name.as_str()).into())
+                );
             }
         };
 
+        self.bind(method.as_obj_closure().unwrap());
+        Ok(())
+    }
+
+    /// Replace the receiver on top of the stack with `method` bound to it
+    fn bind(&mut self, method: Gc<ObjClosure>) {
         let receiver = self.peek(0);
-        let bound = ObjBoundMethod::new(receiver, method.as_obj_closure().unwrap());
+        let bound = ObjBoundMethod::new(receiver, method);
         let bound = self.alloc_obj(bound);
 
         self.pop();
-        self.push(Value::Obj(bound.cast()));
+        self.push(Value::Obj(bound.upcast()));
+    }
 
-        true
+    /// Replace the class on top of the stack with its static field `name`, or its static method
+    /// bound to it
+    fn get_static(&mut self, class: Gc<ObjClass>, name: Gc<ObjString>) -> InterpretResult<()> {
+        if let Some(method) = class.static_methods.get(name) {
+            self.bind(method.as_obj_closure().unwrap());
+            return Ok(());
+        }
+        let field = self.static_field(class, name)?;
+        self.pop();
+        self.push(field);
+        Ok(())
+    }
+
+    fn static_field(&mut self, class: Gc<ObjClass>, name: Gc<ObjString>) -> InterpretResult<Value> {
+        match class.static_fields.get(name) {
+            Some(value) => Ok(value),
+            None => Err(self.runtime_error(
+                format!(
+                    "Undefined static property '{}' of class {}.",
+                    name.as_str(),
+                    class.name.as_str()
+                )
+                .into(),
+            )),
+        }
     }
 
-    fn invoke(&mut self, name: Gc<ObjString>, arg_count: u8) -> bool {
+    /// Invoke the method named by the constant at `index` in the current chunk
+    fn invoke(&mut self, index: usize, arg_count: u8) -> InterpretResult<()> {
         let receiver = self.peek(arg_count as u32);
+        let name = self.top_call_frame().function().chunk.constants[index]
+            .as_obj_str()
+            .unwrap();
         let instance = match receiver.as_instance_fn() {
             Some(inst) => inst,
             None => {
-                self.runtime_error("Only instances have methods.".into());
-                return false;
+                if let Some(module) = receiver.as_module() {
+                    let export = self.export(module, name)?;
+                    self.stack.set(arg_count as u32, export);
+                    return self.call_value(export, arg_count);
+                }
+                if let Some(class) = receiver.as_class() {
+                    // The class stays in slot zero as `this`
+                    if let Some(method) = class.static_methods.get(name) {
+                        return self.call(method.as_obj_closure().unwrap(), arg_count);
+                    }
+                    let field = self.static_field(class, name)?;
+                    self.stack.set(arg_count as u32, field);
+                    return self.call_value(field, arg_count);
+                }
+                return Err(self.runtime_error("Only instances have methods.".into()));
             }
         };
 
-        if let Some(field) = instance.fields.get(name.as_non_null_ptr()) {
+        if let Some(field) = instance.fields.get(name) {
             self.stack.set(arg_count as u32, field);
             return self.call_value(field, arg_count);
         }
 
-        self.invoke_from_class(instance.class, name, arg_count)
+        match self.find_method(instance.class, index) {
+            Some(method) => self.call(method, arg_count),
+            None if instance.class.getters.get(name).is_some() => Err(self.runtime_error(
+                format!(
+                    "Can't invoke getter '{0}', call its value with '(object.{0})()'.",
+                    name.as_str()
+                )
+                .into(),
+            )),
+            None => {
+                Err(self.runtime_error(format!("Undefined property '{}'.", name.as_str()).into()))
+            }
+        }
+    }
+
+    /// Method of `class` named by the constant at `index` in the current chunk, going through
+    /// the chunk's [`MethodCache`] for that name
+    #[inline]
+    fn find_method(&mut self, class: Gc<ObjClass>, index: usize) -> Option<Gc<ObjClosure>> {
+        let mut function = self.top_call_frame().closure().function;
+        if let Some(Some(cache)) = function.chunk.method_caches.get(index)
+            && cache.class.as_ptr() == class.as_ptr()
+        {
+            return Some(cache.method);
+        }
+
+        let name = function.chunk.constants[index].as_obj_str().unwrap();
+        let method = class.methods.get(name)?.as_obj_closure().unwrap();
+        let chunk = &mut function.chunk;
+        if chunk.method_caches.len() <= index {
+            chunk.method_caches.resize(chunk.constants.len(), None);
+        }
+        chunk.method_caches[index] = Some(MethodCache { class, method });
+        Some(method)
     }
 
     fn invoke_from_class(
@@ -716,352 +1854,656 @@ impl VM {
         class: Gc<ObjClass>,
         name: Gc<ObjString>,
         arg_count: u8,
-    ) -> bool {
-        let method = class.methods.get(name.as_non_null_ptr());
+    ) -> InterpretResult<()> {
+        let method = class.methods.get(name);
         match method {
-            Some(method) => {
-                self.call(method.as_obj_closure().unwrap(), arg_count);
-                true
-            }
+            Some(method) => self.call(method.as_obj_closure().unwrap(), arg_count),
             None => {
-                self.runtime_error(format!("Undefined property {}", name.as_str()).into());
-                false
+                Err(self.runtime_error(format!("Undefined property '{}'.", name.as_str()).into()))
             }
         }
     }
 
-    pub fn run(&mut self) -> InterpretResult<()> {
-        loop {
-            #[cfg(debug_assertions)]
-            {
-                // Debug frame window
-                let slot_addr = self.top_call_frame().slots_ptr as usize;
-                println!("          Frame slot addr: {}", slot_addr);
-                // Debug stack
-                let take_amount =
-                    (self.stack.top as usize - slot_addr) / std::mem::size_of::<Value>();
-                for (i, slot) in self.stack.iter().take(take_amount).enumerate() {
-                    let value = slot;
-                    println!("          {i}: {value:?}");
-                }
+    /// The value `module` exports as `name`
+    fn export(&mut self, module: Gc<ObjModule>, name: Gc<ObjString>) -> InterpretResult<Value> {
+        match module.globals.get(name) {
+            Some(value) => Ok(value),
+            None => Err(self.runtime_error(
+                format!(
+                    "Module \"{}\" has no export '{}'.",
+                    module.name.as_str(),
+                    name.as_str()
+                )
+                .into(),
+            )),
+        }
+    }
+
+    /// Push the module at `name`, relative to the importing module or script. A module that
+    /// wasn't imported before is compiled and its script called, it pushes the module when it
+    /// reaches `EndModule`
+    fn import(&mut self, name: Gc<ObjString>) -> InterpretResult<()> {
+        let Some(resolver) = &self.module_resolver else {
+            return Err(self.runtime_error("Imports are disabled.".into()));
+        };
+
+        let importer = self.importer();
+        let Some(id) = resolver.resolve(importer.as_deref(), name.as_str()) else {
+            let error = format!("Could not find module \"{}\".", name.as_str());
+            return Err(self.runtime_error(error.into()));
+        };
+
+        let key = self.mem.copy_string(&id);
+        if let Some(module) = self.mem.modules.get(key) {
+            self.push(module);
+            return Ok(());
+        }
+        if let Some(start) = self
+            .loading
+            .iter()
+            .position(|module| module.key.as_ptr() == key.as_ptr())
+        {
+            let cycle: Vec<_> = self.loading[start..]
+                .iter()
+                .map(|module| format!("\"{}\"", module.name.as_str()))
+                .chain([format!("\"{}\"", name.as_str())])
+                .collect();
+            let error = format!("Circular import: {}.", cycle.join(" -> "));
+            return Err(self.runtime_error(error.into()));
+        }
 
-                // Debug instruction
-                let frame = self.top_call_frame();
-                let mut duplicate_instruction_index = frame.instr_offset as usize;
-                let line = frame.function().chunk.lines[duplicate_instruction_index];
-                let inner = frame
-                    .function()
-                    .chunk
-                    .disassemble_instruction(&mut duplicate_instruction_index);
-                println!("{:?}", inner.map(|inner| InstructionDebug { line, inner }));
+        let src = match resolver.load(&id) {
+            Ok(src) => src,
+            Err(err) => {
+                let error = format!("Could not read module \"{}\": {err}.", name.as_str());
+                return Err(self.runtime_error(error.into()));
             }
+        };
 
-            let byte = self.read_byte();
+        let mut roots = self.roots();
+        roots.push(key.upcast());
+        let mut parser = Parser::new(&src, &mut self.mem)
+            .module(true)
+            .per_iteration_loops(self.per_iteration_loops)
+            .roots(roots);
+        if let Err(errors) = parser.compile() {
+            for error in &errors {
+                let _ = writeln!(self.stderr, "{error}");
+            }
+            let error = format!("Could not compile module \"{}\".", name.as_str());
+            return Err(self.runtime_error(error.into()));
+        }
+        let mut function = parser.compiler.function;
+        if self.optimize {
+            optimize::optimize(&mut function);
+        }
 
-            match Opcode::from_u8(byte) {
-                Some(Opcode::SuperInvoke) => {
-                    let method = self.read_constant().as_obj_str().unwrap();
-                    let arg_count = self.read_byte();
-                    let superclass = self.pop().as_class().unwrap();
+        // Not collecting here, nothing roots the function yet
+        let module = self.mem.alloc_obj(ObjModule::new(name));
+        set_module(&mut function, module);
+        let closure = self.mem.alloc_obj(ObjClosure::new(function));
+        self.push(Value::Obj(closure.upcast()));
+        self.loading.push(Loading {
+            key,
+            name,
+            module,
+            frame_count: self.call_frame_count + 1,
+        });
+        let result = self.call(closure, 0);
+        if result.is_err() {
+            self.loading.pop();
+        }
+        result
+    }
 
-                    if !self.invoke_from_class(superclass, method, arg_count) {
-                        return Err(InterpretError::RuntimeError);
-                    }
-                }
-                Some(Opcode::GetSuper) => {
-                    // The name of the class
-                    let name = self.read_constant().as_obj_str().unwrap();
+    /// The id of the module running an `import`, or the path of the script
+    fn importer(&self) -> Option<String> {
+        match self.loading.last() {
+            Some(module) => Some(module.key.as_str().to_string()),
+            None => self
+                .script_path
+                .as_ref()
+                .map(|path| path.to_string_lossy().into_owned()),
+        }
+    }
 
-                    let superclass = self.pop().as_class().unwrap();
+    /// Push the module of the shared library `name`, loading it if it wasn't imported before.
+    /// Its natives are defined as globals as well
+    fn import_native(&mut self, name: Gc<ObjString>) -> InterpretResult<()> {
+        if !self.native_modules {
+            return Err(self.runtime_error("Native modules are disabled.".into()));
+        }
 
-                    if !self.bind_method(superclass, name) {
-                        return Err(InterpretError::RuntimeError);
-                    }
-                }
-                Some(Opcode::Inherit) => {
-                    let superclass = self.peek(1);
-                    let superclass = match superclass.as_class() {
-                        Some(class) => class,
-                        None => {
-                            self.runtime_error("Superclass must be a class.".into());
-                            return Err(InterpretError::RuntimeError);
-                        }
-                    };
+        let importer = self.importer();
+        let Some(path) = native::find(importer.as_deref(), name.as_str(), &self.module_paths) else {
+            let error = format!("Could not find native module \"{}\".", name.as_str());
+            return Err(self.runtime_error(error.into()));
+        };
+        let key = self.mem.copy_string(&path);
+        if let Some(module) = self.mem.modules.get(key) {
+            self.push(module);
+            return Ok(());
+        }
 
-                    let mut subclass = self.peek(0);
-                    let mut subclass = subclass.as_class().unwrap();
+        // Safety: the library is never unloaded, see `native::open`
+        let natives = native::open(&path).and_then(|init| unsafe { native::natives(init) });
+        let natives = match natives {
+            Ok(natives) => natives,
+            Err(err) => {
+                let error = format!("Could not load native module \"{}\": {err}.", name.as_str());
+                return Err(self.runtime_error(error.into()));
+            }
+        };
 
-                    superclass.methods.add_all(&mut subclass.methods);
+        self.push(Value::Obj(key.upcast()));
+        let mut module = self.alloc_obj(ObjModule::new(name));
+        self.pop();
+        self.push(Value::Obj(module.upcast()));
+        for native in natives {
+            self.define_native(
+                &native.name,
+                NativeFnKind::Host(native.function),
+                native.arity,
+            );
+            let name = self.mem.copy_string(&native.name);
+            module
+                .globals
+                .set(name, self.mem.globals.get(name).unwrap());
+        }
+        self.mem.modules.set(key, Value::Obj(module.upcast()));
+        Ok(())
+    }
 
-                    self.pop();
-                }
-                Some(Opcode::Invoke) => {
-                    let method = self.read_constant().as_obj_str().unwrap();
-                    let arg_count = self.read_byte();
-                    if !self.invoke(method, arg_count) {
-                        return Err(InterpretError::RuntimeError);
-                    }
-                }
-                Some(Opcode::Method) => {
-                    let obj_str = self.read_constant().as_obj_str().unwrap();
-                    self.define_method(obj_str)
-                }
-                Some(Opcode::GetProperty) => {
-                    let top = self.peek(0);
-                    let instance = match top.as_instance_fn() {
-                        Some(instance) => instance,
-                        None => {
-                            self.runtime_error("Only instances have properties.".into());
-                            return Err(InterpretError::RuntimeError);
-                        }
-                    };
+    /// Push the module being imported, its script is done, and cache it
+    fn end_module(&mut self) -> InterpretResult<()> {
+        // Only bytecode that didn't come from the compiler ends anything else
+        let frame_count = self.loading.last().map(|loading| loading.frame_count);
+        if frame_count != Some(self.call_frame_count) {
+            return Err(self.runtime_error("Only a module's script can end the module.".into()));
+        }
+        let loading = self.loading.pop().unwrap();
+        let module = Value::Obj(loading.module.upcast());
+        self.mem.modules.set(loading.key, module);
+        self.push(module);
+        Ok(())
+    }
+
+    /// Run until the script returns, then the tasks it spawned until they're done. Yields the
+    /// script's return value
+    pub fn run(&mut self) -> InterpretResult<Value> {
+        self.fuel = self.max_instructions.unwrap_or(u64::MAX);
+        let result = self
+            .check_script_stack()
+            .and_then(|()| self.execute(0))
+            .and_then(|value| self.run_tasks().map(|()| value));
+        self.fuel = u64::MAX;
+        if result.is_err() {
+            self.reset_stack();
+        }
+        result.map(|value| self.mem.export(value))
+    }
 
-                    let name = self
-                        .read_constant()
-                        .as_obj_str()
-                        .expect("Expect to read a string constant.");
+    /// Run at most `fuel` more instructions of the script. If it doesn't return by then, this
+    /// fails with [`InterpretError::OutOfFuel`] and the script is paused, the next call to
+    /// `run_fuel` continues where it stopped.
+    ///
+    /// Running out while a native is calling back into the VM (see [`VM::call_function`])
+    /// can't be resumed, the native gets the error instead.
+    pub fn run_fuel(&mut self, fuel: u64) -> InterpretResult<Value> {
+        self.fuel = fuel;
+        let result = self.check_script_stack().and_then(|()| self.execute(0));
+        self.fuel = u64::MAX;
+        if result.is_err() && result != Err(InterpretError::OutOfFuel) {
+            self.reset_stack();
+        }
+        result.map(|value| self.mem.export(value))
+    }
 
-                    match instance.fields.get(name.as_non_null_ptr()) {
-                        Some(val) => {
-                            self.pop();
-                            self.push(val);
-                        }
-                        None => {
-                            if !self.bind_method(unsafe { &*instance.as_ptr() }.class, name) {
-                                return Err(InterpretError::RuntimeError);
-                            }
-                        }
-                    }
-                }
-                Some(Opcode::SetProperty) => {
-                    let top = self.peek(1);
-                    let mut instance = match top.as_instance_fn() {
-                        Some(instance) => instance,
-                        None => {
-                            self.runtime_error("Only instances have fields.".into());
-                            return Err(InterpretError::RuntimeError);
-                        }
-                    };
+    /// Run the script until `event`, or until it returns. Returns [`Status::Paused`] before the
+    /// first instruction the event applies to, and the script continues from there with the
+    /// next `run_until`, `step` or `run`. [`VmOptions::max_instructions`] doesn't apply.
+    ///
+    /// Calls from natives back into the VM (see [`VM::call_function`]) run to completion, their
+    /// instructions don't count as events.
+    pub fn run_until(&mut self, event: Event) -> InterpretResult<Status> {
+        self.watch = Some(Watch {
+            event,
+            depth: self.call_frame_count,
+            last: (self.call_frame_count, self.current_line()),
+        });
+        // Ran out after each instruction, see `out_of_fuel`
+        let result = self.run_fuel(1);
+        self.watch = None;
+        match result {
+            Ok(value) => Ok(Status::Finished(value)),
+            Err(InterpretError::OutOfFuel) => Ok(Status::Paused),
+            Err(error) => Err(error),
+        }
+    }
 
-                    let field_name = self
-                        .read_constant()
-                        .as_obj_str()
-                        .expect("Expect to string constant");
+    /// Run a single instruction of the script
+    pub fn step(&mut self) -> InterpretResult<Status> {
+        self.run_until(Event::Instruction)
+    }
 
-                    instance
-                        .fields
-                        .set(field_name.as_non_null_ptr(), self.peek(0));
+    /// How many calls are running, the script's own counts as one
+    pub fn call_depth(&self) -> u32 {
+        self.call_frame_count
+    }
 
-                    let value = self.pop();
-                    self.pop();
-                    self.push(value);
-                }
-                Some(Opcode::Class) => {
-                    let name = self
-                        .read_constant()
-                        .as_obj_str()
-                        .expect("Opcode::Class instruction should be followed by string constant");
+    /// Source line of the instruction that runs next
+    pub fn current_line(&self) -> Option<u32> {
+        if self.call_frame_count == 0 {
+            return None;
+        }
+        let frame = self.top_call_frame();
+        Some(frame.function().chunk.get_line(frame.instr_offset as usize))
+    }
 
-                    let class = ObjClass::new(name.as_non_null_ptr());
-                    let class = self.alloc_obj(class);
+    /// The values on the stack, from the script's closure at the bottom to the top. Objects are
+    /// handles like the other values the host gets
+    pub fn stack_values(&mut self) -> Vec<Value> {
+        let values = unsafe {
+            let len = self.stack.top.offset_from(self.stack.stack) as usize;
+            std::slice::from_raw_parts(self.stack.stack, len)
+        };
+        values.iter().map(|&value| self.mem.export(value)).collect()
+    }
 
-                    self.push(Value::Obj(class.cast()));
-                }
-                Some(Opcode::CloseUpvalue) => {
-                    self.close_upvalues(unsafe { self.stack.top.sub(1) });
-                    self.pop();
-                }
-                Some(Opcode::GetUpvalue) => {
-                    let slot = self.read_byte();
-                    let val = unsafe {
-                        *(self
-                            .top_call_frame()
-                            .closure
-                            .as_ref()
-                            .upvalue_at_slot(slot as usize)
-                            .unwrap()
-                            .as_ref()
-                            .location
-                            .as_ptr())
-                    };
-
-                    self.push(val);
-                }
-                Some(Opcode::SetUpvalue) => {
-                    let slot = self.read_byte();
-                    let val = self.peek(0);
-                    unsafe {
-                        let loc_ptr = self
-                            .top_call_frame()
-                            .closure()
-                            .upvalue_at_slot(slot as usize)
-                            .unwrap()
-                            .as_mut()
-                            .location
-                            .as_ptr();
-
-                        (*loc_ptr) = val;
-                    }
-                }
-                Some(Opcode::Closure) => {
-                    let function = self.read_constant().as_fn().unwrap();
-                    self.new_closure(function);
-                    // TODO: investigate
-                    // let closure = self.alloc_obj();
-                    // let noob = Value::Obj(closure.cast());
-                    // println!("did the closure thing");
-                    // self.push(noob);
-                }
-                Some(Opcode::Call) => {
-                    let arg_count = self.read_byte();
-                    if !self.call_value(self.peek(arg_count as u32), arg_count) {
-                        return Err(InterpretError::RuntimeError);
-                    }
-                }
-                Some(Opcode::Loop) => {
-                    let offset = self.read_u16();
-                    self.top_call_frame_mut().instr_offset -= offset as u32;
-                }
-                Some(Opcode::Jump) => {
-                    let offset = self.read_u16();
-                    self.top_call_frame_mut().instr_offset += offset as u32;
-                }
-                Some(Opcode::JumpIfFalse) => {
-                    let offset = self.read_u16();
-                    if self.peek(0).is_falsey() {
-                        self.top_call_frame_mut().instr_offset += offset as u32;
-                    }
-                }
-                Some(Opcode::GetLocal) => {
-                    let slot = self.read_byte();
-                    let val = self.top_call_frame().index(slot as usize);
+    /// The values of the innermost call: the callee (or `this`) in slot zero, then its
+    /// arguments, locals and temporaries
+    pub fn frame_slots(&mut self) -> Vec<Value> {
+        if self.call_frame_count == 0 {
+            return vec![];
+        }
+        let slots = self.top_call_frame().slots_ptr;
+        let values = unsafe {
+            let len = self.stack.top.offset_from(slots) as usize;
+            std::slice::from_raw_parts(slots, len)
+        };
+        values.iter().map(|&value| self.mem.export(value)).collect()
+    }
 
-                    self.push(val);
-                }
-                Some(Opcode::SetLocal) => {
-                    let slot = self.read_byte();
-                    let val = self.peek(0);
-                    self.top_call_frame_mut().set(slot as usize, val);
+    /// Names of the locals in scope in the innermost call, by slot, see [`VM::frame_slots`]
+    pub fn local_names(&self) -> Vec<(u8, &str)> {
+        if self.call_frame_count == 0 {
+            return vec![];
+        }
+        let frame = self.top_call_frame();
+        let offset = frame.instr_offset as usize;
+        frame
+            .function()
+            .chunk
+            .debug
+            .locals_at(offset)
+            .map(|local| (local.slot, local.name.as_str()))
+            .collect()
+    }
+
+    /// The calls being run, innermost first, with the line each one is at
+    pub fn backtrace(&self) -> Vec<TraceFrame> {
+        let innermost = self.call_frame_count as usize;
+        self.iter_frames()
+            .enumerate()
+            .rev()
+            .map(|(i, frame)| {
+                // The callers are past their call instruction
+                let mut offset = frame.instr_offset;
+                if i + 1 < innermost {
+                    offset = offset.saturating_sub(1);
                 }
-                Some(Opcode::SetGlobal) => {
-                    let name = self
-                        .read_constant()
-                        .as_obj_str()
-                        .expect("Expect string constant for global variable name.");
+                frame.trace(offset)
+            })
+            .collect()
+    }
 
-                    let new_val = self.peek(0);
-                    // println!("{} = {:?}", unsafe { name.as_ref() }.as_str(), self.peek(0));
+    /// The instruction that runs next, disassembled
+    pub fn current_instruction(&self) -> Option<String> {
+        if self.call_frame_count == 0 {
+            return None;
+        }
+        let frame = self.top_call_frame();
+        let chunk = &frame.function().chunk;
+        Some(disassemble_instruction(chunk, frame.instr_offset as usize))
+    }
 
-                    if self.mem.globals.set(name.as_non_null_ptr(), new_val) {
-                        self.mem.globals.delete(name.as_non_null_ptr());
-                        self.runtime_error(format!("Undefined variable: {}", name.as_str()).into());
+    /// The fuel ran out before the next instruction. Stop with [`InterpretError::OutOfFuel`],
+    /// unless `run_until` is waiting for an event that isn't there yet
+    #[cold]
+    fn out_of_fuel(&mut self, base_frame_count: u32) -> InterpretResult<()> {
+        let Some(watch) = self.watch else {
+            return Err(InterpretError::OutOfFuel);
+        };
+        // Natives calling back into the VM can't be paused
+        if base_frame_count != 0 {
+            self.fuel = 1;
+            return Ok(());
+        }
 
-                        return Err(InterpretError::RuntimeError);
-                    }
-                }
-                Some(Opcode::GetGlobal) => {
-                    let name = self
-                        .read_constant()
-                        .as_obj_str()
-                        .expect("Expect string constant for global variable name.");
-
-                    let val = match self.mem.globals.get(name.as_non_null_ptr()) {
-                        Some(global) => global,
-                        None => {
-                            self.runtime_error(
-                                format!("Undefined variable: {}", name.as_str()).into(),
-                            );
-
-                            return Err(InterpretError::RuntimeError);
-                        }
-                    };
+        let depth = self.call_frame_count;
+        let here = (depth, self.current_line());
+        let reached = match watch.event {
+            Event::Instruction => true,
+            Event::Line => here != watch.last,
+            Event::Call => depth > watch.depth,
+            Event::Return => depth < watch.depth,
+            Event::Breakpoint(line) => here.1 == Some(line) && here != watch.last,
+        };
+        if reached {
+            return Err(InterpretError::OutOfFuel);
+        }
+        self.watch = Some(Watch {
+            last: here,
+            ..watch
+        });
+        self.fuel = 1;
+        Ok(())
+    }
 
-                    self.push(val);
-                }
-                Some(Opcode::DefineGlobal) => {
-                    let name = self
-                        .read_constant()
-                        .as_obj_str()
-                        .expect("Expect string constant for global variable name.");
-
-                    self.mem.globals.set(name.as_non_null_ptr(), self.peek(0));
-                    self.pop();
-                }
-                Some(Opcode::Nil) => {
-                    self.push(Value::Nil);
-                }
-                Some(Opcode::True) => {
-                    self.push(Value::Bool(true));
-                }
-                Some(Opcode::False) => {
-                    self.push(Value::Bool(false));
-                }
-                Some(Opcode::Pop) => {
-                    self.pop();
-                }
-                Some(Opcode::Print) => {
-                    let value = self.pop();
-                    println!("{value:?}");
-                }
-                Some(Opcode::Equal) => {
-                    let b = self.pop();
-                    let a = self.pop();
+    /// Stop with [`InterpretError::Interrupted`] if [`VmOptions::interrupt`] is set. Loops and
+    /// calls check it, a script can't run for long without doing either
+    fn check_interrupt(&self) -> InterpretResult<()> {
+        match &self.interrupt {
+            Some(interrupt) if interrupt.load(Ordering::Relaxed) => {
+                Err(InterpretError::Interrupted)
+            }
+            _ => Ok(()),
+        }
+    }
 
-                    self.push(Value::Bool(a == b))
-                }
-                Some(Opcode::Not) => {
-                    let top = self.pop();
-                    self.push(Value::Bool(top.is_falsey()))
+    /// Calls check that they fit on the stack before they start, the script has to be checked
+    /// before it runs
+    fn check_script_stack(&mut self) -> InterpretResult<()> {
+        if self.call_frame_count == 1
+            && self.top_call_frame().instr_offset == 0
+            && !self.stack_fits(self.top_call_frame().closure().function)
+        {
+            let error = self.stack_overflow();
+            return Err(self.report(error));
+        }
+        Ok(())
+    }
+
+    /// Call a global function (or anything else callable, like a class) by name.
+    ///
+    /// This can be used after `run()` has completed, or re-entrantly from inside a native function.
+    pub fn call_function(&mut self, name: &str, args: &[Value]) -> InterpretResult<Value> {
+        let name_str = self.mem.copy_string(name);
+        let callee = match self.mem.globals.get(name_str) {
+            Some(callee) => callee,
+            None => {
+                let error = self.undefined_variable(name);
+                return Err(self.report(error));
+            }
+        };
+
+        let callee = self.mem.export(callee);
+        self.call_value_with_args(callee, args)
+    }
+
+    /// Call a callable value (closure, bound method, class or native) with `args`. Fails if one
+    /// of them is an object that was collected since the VM handed it out
+    pub fn call_value_with_args(
+        &mut self,
+        callee: Value,
+        args: &[Value],
+    ) -> InterpretResult<Value> {
+        let resolved: Option<Vec<_>> = args.iter().map(|&arg| self.mem.resolve(arg)).collect();
+        let (Some(callee), Some(args)) = (self.mem.resolve(callee), resolved) else {
+            let error = self.runtime_error("Can't use an object that was collected.".into());
+            return Err(self.report(error));
+        };
+
+        let arg_count: u8 = match args.len().try_into() {
+            Ok(arg_count) => arg_count,
+            Err(_) => {
+                let error = self.runtime_error("Can't have more than 255 arguments".into());
+                return Err(self.report(error));
+            }
+        };
+
+        if unsafe { self.stack.limit.offset_from(self.stack.top) } < args.len() as isize + 1 {
+            let error = self.stack_overflow();
+            return Err(self.report(error));
+        }
+
+        let base_frame_count = self.call_frame_count;
+        let base_top = self.stack.top;
+
+        self.push(callee);
+        for arg in args {
+            self.push(arg);
+        }
+
+        self.callbacks += 1;
+        let result = match self.call_value(callee, arg_count) {
+            Ok(()) if self.call_frame_count > base_frame_count => self.execute(base_frame_count),
+            // Natives (and classes without initializers) complete immediately
+            Ok(()) => Ok(self.pop()),
+            Err(error) => Err(self.report(error)),
+        };
+        self.callbacks -= 1;
+
+        if result.is_err() {
+            // Unwind everything this call pushed, leaving the caller's frames intact
+            self.close_upvalues(base_top);
+            self.call_frame_count = base_frame_count;
+            self.handlers
+                .retain(|handler| handler.frame_count <= base_frame_count);
+            self.loading
+                .retain(|module| module.frame_count <= base_frame_count);
+            self.abandon_generators(base_frame_count);
+        }
+        self.stack.top = base_top;
+
+        result.map(|value| self.mem.export(value))
+    }
+
+    /// Execute instructions until the call frame count drops back to `base_frame_count`,
+    /// returning the value returned by the last frame.
+    ///
+    /// Runtime errors are thrown as exceptions, with the message as the value, so a `try`
+    /// statement can catch them. Uncaught errors are reported
+    fn execute(&mut self, base_frame_count: u32) -> InterpretResult<Value> {
+        loop {
+            match self.execute_until_error(base_frame_count) {
+                Err(InterpretError::RuntimeError(error))
+                    if self
+                        .handlers
+                        .last()
+                        .map_or(false, |handler| handler.frame_count > base_frame_count) =>
+                {
+                    let exception = self.alloc_string(&error.message);
+                    self.throw(exception, base_frame_count);
                 }
-                Some(Opcode::Negate) => {
-                    if !matches!(self.peek(0), Value::Bool(_) | Value::Number(_)) {
-                        self.runtime_error("Operand must be a number.".into());
-                        return Err(InterpretError::RuntimeError);
+                Err(error) => return Err(self.report(error)),
+                Ok(value) => return Ok(value),
+            }
+        }
+    }
+
+    /// Print the whole stack and the instruction about to run, like clox's
+    /// `DEBUG_TRACE_EXECUTION`
+    fn trace_instruction(&mut self) {
+        let mut stack = String::from("          ");
+        for value in self.iter_stack() {
+            stack.push_str(&format!("[ {} ]", format_value(value)));
+        }
+        let instruction = self.current_instruction().unwrap();
+        let _ = writeln!(self.stderr, "{stack}\n{instruction}");
+    }
+
+    /// `--trace`, `--count-dispatches`, `--profile` and `--coverage`, before each instruction
+    #[cold]
+    #[inline(never)]
+    fn instrument(&mut self) {
+        if self.trace_execution {
+            self.trace_instruction();
+        }
+        if self.count_dispatches {
+            self.dispatch_count += 1;
+        }
+        if let Some(mut profile) = self.profile.take() {
+            let frame = self.top_call_frame();
+            let function = frame.function();
+            let opcode = function.chunk.code[frame.instr_offset as usize];
+            profile.instruction(function, self.call_frame_count, opcode);
+            self.profile = Some(profile);
+        }
+        if let Some(mut coverage) = self.coverage.take() {
+            let frame = self.top_call_frame();
+            let offset = frame.instr_offset;
+            coverage.instruction(frame.function(), self.call_frame_count, offset, || {
+                self.script_file()
+            });
+            self.coverage = Some(coverage);
+        }
+    }
+
+    /// Where the top frame's code comes from if it's a script's: the module being loaded, or
+    /// the script the VM runs
+    fn script_file(&self) -> String {
+        match self.loading.last() {
+            Some(loading) if loading.frame_count == self.call_frame_count => {
+                loading.key.as_str().to_string()
+            }
+            _ => self
+                .script_path
+                .as_ref()
+                .map_or_else(|| "<script>".to_string(), |path| path.display().to_string()),
+        }
+    }
+
+    /// The dispatch loop, the instructions are implemented in [`dispatch`]
+    fn execute_until_error(&mut self, base_frame_count: u32) -> InterpretResult<Value> {
+        loop {
+            // Checked before reading the instruction, so `run_fuel` can resume with it
+            if self.fuel == 0 {
+                if self.blocked.is_some() {
+                    // Blocking only happens in the task's own frame, see `in_task`
+                    if let Some(value) = self.suspend_blocked(base_frame_count) {
+                        return Ok(value);
                     }
+                    // Unless bytecode that didn't come from the compiler resumed the task itself
+                    self.blocked = None;
+                    return Err(self.runtime_error("Only the scheduler runs tasks.".into()));
+                }
+                self.out_of_fuel(base_frame_count)?;
+            }
+            self.fuel -= 1;
+            if self.trace_execution
+                || self.count_dispatches
+                || self.profile.is_some()
+                || self.coverage.is_some()
+            {
+                self.instrument();
+            }
 
-                    let negated = -self.pop();
-                    self.push(negated)
+            let byte = self.read_byte();
+            match Opcode::from_u8(byte) {
+                Some(Opcode::SuperInvoke) => op_super_invoke(self)?,
+                Some(Opcode::GetSuper) => op_get_super(self)?,
+                Some(Opcode::Inherit) => op_inherit(self)?,
+                Some(Opcode::Invoke) => op_invoke(self)?,
+                Some(Opcode::Method) => op_method(self)?,
+                Some(Opcode::GetProperty) => op_get_property(self)?,
+                Some(Opcode::SetProperty) => op_set_property(self)?,
+                Some(Opcode::Class) => op_class(self)?,
+                Some(Opcode::CloseUpvalue) => op_close_upvalue(self)?,
+                Some(Opcode::GetIter) => op_get_iter(self)?,
+                Some(Opcode::ForIter) => op_for_iter(self)?,
+                Some(Opcode::CloseLocal) => op_close_local(self)?,
+                Some(Opcode::GetUpvalue) => op_get_upvalue(self)?,
+                Some(Opcode::SetUpvalue) => op_set_upvalue(self)?,
+                Some(Opcode::Closure) => op_closure(self)?,
+                Some(Opcode::Call) => op_call(self)?,
+                Some(Opcode::TailCall) => op_tail_call(self)?,
+                Some(Opcode::CallNamed) => op_call_named(self)?,
+                Some(Opcode::Await) => op_await(self)?,
+                Some(Opcode::Yield) => {
+                    if let Some(result) = op_yield(self, base_frame_count)? {
+                        return Ok(result);
+                    }
                 }
+                Some(Opcode::Loop) => op_loop(self)?,
+                Some(Opcode::Jump) => op_jump(self)?,
+                Some(Opcode::JumpIfFalse) => op_jump_if_false(self)?,
+                Some(Opcode::GetLocal) => op_get_local(self)?,
+                Some(Opcode::SetLocal) => op_set_local(self)?,
+                Some(Opcode::SetGlobal) => op_set_global(self)?,
+                Some(Opcode::GetGlobal) => op_get_global(self)?,
+                Some(Opcode::DefineGlobal) => op_define_global(self)?,
+                Some(Opcode::Nil) => op_nil(self)?,
+                Some(Opcode::True) => op_true(self)?,
+                Some(Opcode::False) => op_false(self)?,
+                Some(Opcode::Pop) => op_pop(self)?,
+                Some(Opcode::Print) => op_print(self)?,
+                Some(Opcode::Equal) => op_equal(self)?,
+                Some(Opcode::Not) => op_not(self)?,
+                Some(Opcode::Negate) => op_negate(self)?,
                 Some(Opcode::Return) => {
-                    if self.call_frame_count == 1 {
-                        self.pop();
-                        return Ok(());
+                    if let Some(result) = op_return(self, base_frame_count)? {
+                        return Ok(result);
                     }
-
-                    let result = self.pop();
-                    self.close_upvalues(self.top_call_frame().slots_ptr);
-
-                    self.stack.top = self.top_call_frame().slots_ptr;
-                    self.call_frame_count -= 1;
-                    self.push(result);
                 }
-                Some(Opcode::Constant) => {
-                    let constant = self.read_constant();
-                    self.push(constant);
-                }
-                Some(Opcode::Subtract) => self.binary_op(std::ops::Sub::sub)?,
-                Some(Opcode::Multiply) => self.binary_op(std::ops::Mul::mul)?,
-                Some(Opcode::Divide) => self.binary_op(std::ops::Div::div)?,
-                Some(Opcode::Greater) => {
-                    self.binary_op(Value::gt_owned)?;
+                Some(Opcode::Throw) => {
+                    if let Some(result) = op_throw(self, base_frame_count)? {
+                        return Ok(result);
+                    }
                 }
-                Some(Opcode::Less) => self.binary_op(Value::lt_owned)?,
-                Some(Opcode::Add) => {
-                    if self.peek(0).is_str() && self.peek(1).is_str() {
-                        self.concatenate();
-                    } else {
-                        self.binary_op(std::ops::Add::add)?
+                Some(Opcode::PushCatch) => op_push_catch(self)?,
+                Some(Opcode::PushFinally) => op_push_finally(self)?,
+                Some(Opcode::Assert) => op_assert(self)?,
+                Some(Opcode::PopHandler) => op_pop_handler(self)?,
+                Some(Opcode::EndFinally) => {
+                    if let Some(result) = op_end_finally(self, base_frame_count)? {
+                        return Ok(result);
                     }
                 }
-                otherwise => panic!("Unknown opcode {otherwise:?}"),
+                Some(Opcode::Constant) => op_constant(self)?,
+                Some(Opcode::ConstantLong) => op_constant_long(self)?,
+                Some(Opcode::Subtract) => op_subtract(self)?,
+                Some(Opcode::Multiply) => op_multiply(self)?,
+                Some(Opcode::Divide) => op_divide(self)?,
+                Some(Opcode::Modulo) => op_modulo(self)?,
+                Some(Opcode::FloorDivide) => op_floor_divide(self)?,
+                Some(Opcode::Power) => op_power(self)?,
+                Some(Opcode::Dup) => op_dup(self)?,
+                Some(Opcode::BuildList) => op_build_list(self)?,
+                Some(Opcode::BuildMap) => op_build_map(self)?,
+                Some(Opcode::GetIndex) => op_get_index(self)?,
+                Some(Opcode::ToString) => op_to_string(self)?,
+                Some(Opcode::Concat) => op_concat(self)?,
+                Some(Opcode::Slice) => op_slice(self)?,
+                Some(Opcode::SetIndex) => op_set_index(self)?,
+                Some(Opcode::BitAnd) => op_bit_and(self)?,
+                Some(Opcode::BitOr) => op_bit_or(self)?,
+                Some(Opcode::BitXor) => op_bit_xor(self)?,
+                Some(Opcode::ShiftLeft) => op_shift_left(self)?,
+                Some(Opcode::ShiftRight) => op_shift_right(self)?,
+                Some(Opcode::BitNot) => op_bit_not(self)?,
+                Some(Opcode::Greater) => op_greater(self)?,
+                Some(Opcode::Less) => op_less(self)?,
+                Some(Opcode::Add) => op_add(self)?,
+                Some(Opcode::AddLocals) => op_add_locals(self)?,
+                Some(Opcode::CallConstant) => op_call_constant(self)?,
+                Some(Opcode::LessLocalJumpIfFalse) => op_less_local_jump_if_false(self)?,
+                Some(Opcode::Import) => op_import(self)?,
+                Some(Opcode::EndModule) => op_end_module(self)?,
+                Some(Opcode::ImportNative) => op_import_native(self)?,
+                Some(Opcode::Field) => op_field(self)?,
+                Some(Opcode::Getter) => op_getter(self)?,
+                Some(Opcode::Setter) => op_setter(self)?,
+                Some(Opcode::StaticMethod) => op_static_method(self)?,
+                Some(Opcode::StaticField) => op_static_field(self)?,
+                None => panic!("Unknown opcode {byte}"),
             }
         }
     }
 
     #[inline]
     fn read_byte(&mut self) -> u8 {
-        let frame =
-            unsafe { self.call_frames[self.call_frame_count as usize - 1].assume_init_mut() };
+        let frame = self.top_call_frame_mut();
 
         let ret = frame.function().chunk.code[frame.instr_offset as usize];
         frame.instr_offset += 1;
@@ -1082,19 +2524,24 @@ impl VM {
 
     #[inline]
     fn top_call_frame_ptr(&mut self) -> NonNull<CallFrame> {
-        unsafe {
-            NonNull::new_unchecked(
-                self.call_frames[self.call_frame_count as usize - 1].as_mut_ptr(),
-            )
-        }
+        NonNull::from(self.top_call_frame_mut())
     }
     #[inline]
     fn top_call_frame_mut(&mut self) -> &mut CallFrame {
-        unsafe { self.call_frames[self.call_frame_count as usize - 1].assume_init_mut() }
+        // `call` never lets the count past the frames
+        unsafe {
+            self.call_frames
+                .get_unchecked_mut(self.call_frame_count as usize - 1)
+                .assume_init_mut()
+        }
     }
     #[inline]
     fn top_call_frame(&self) -> &CallFrame {
-        unsafe { self.call_frames[self.call_frame_count as usize - 1].assume_init_ref() }
+        unsafe {
+            self.call_frames
+                .get_unchecked(self.call_frame_count as usize - 1)
+                .assume_init_ref()
+        }
     }
 
     #[inline]
@@ -1111,9 +2558,90 @@ impl VM {
         ((byte1 as u16) << 8) | (byte2 as u16)
     }
 
+    /// Slot of the global named by the constant operand. It's cached in the chunk, so only the
+    /// first time an instruction runs looks the name up
+    #[inline]
+    fn read_global_slot(&mut self) -> usize {
+        let index = self.read_byte() as usize;
+        let mut function = self.top_call_frame().closure().function;
+        if let Some(&Some(slot)) = function.chunk.global_slots.get(index) {
+            return slot as usize;
+        }
+
+        let name = function.chunk.constants[index]
+            .as_obj_str()
+            .expect("Expect string constant for global variable name.");
+        let slot = self.globals().slot(name);
+        let chunk = &mut function.chunk;
+        if chunk.global_slots.len() <= index {
+            chunk.global_slots.resize(chunk.constants.len(), None);
+        }
+        chunk.global_slots[index] = Some(slot as u32);
+        slot
+    }
+
+    fn undefined_global(&mut self, slot: usize) -> InterpretError {
+        let name = self.globals().name(slot);
+        self.undefined_variable(name.as_str())
+    }
+
+    /// The globals of the top frame's module, or the VM's for the script's code
+    #[inline]
+    pub(super) fn globals(&mut self) -> &mut Globals {
+        match self.top_call_frame().function().module {
+            // Safety: the frame's closure holds on to its function, which holds on to the module
+            Some(module) => unsafe { &mut (*module.as_ptr()).globals },
+            None => &mut self.mem.globals,
+        }
+    }
+
+    /// The value of the global in `slot` of the top frame's globals. Code in a module falls
+    /// back to the VM's globals for names the module doesn't define, like the natives
+    #[inline]
+    pub(super) fn get_global_slot(&mut self, slot: usize) -> Option<Value> {
+        let globals = self.globals();
+        match globals.get_slot(slot) {
+            Some(value) => Some(value),
+            None if self.top_call_frame().function().module.is_some() => {
+                let name = self.globals().name(slot);
+                self.mem.globals.get(name)
+            }
+            None => None,
+        }
+    }
+
+    /// An error for the undefined `name`, suggesting a local of the innermost call or a global
+    /// spelled almost the same
+    fn undefined_variable(&mut self, name: &str) -> InterpretError {
+        let module = match self.call_frame_count {
+            0 => None,
+            _ => self.top_call_frame().function().module,
+        };
+        let globals = module.iter().flat_map(|module| module.globals.defined());
+        let globals: Vec<_> = globals.chain(self.mem.globals.defined()).collect();
+        let locals = self.local_names().into_iter().map(|(_, local)| local);
+        let names = locals.chain(globals.iter().map(|global| global.as_str()));
+        let suggestion = report::suggest(name, names).map(str::to_string);
+        let mut error = self.runtime_error(format!("Undefined variable '{name}'.").into());
+        if let InterpretError::RuntimeError(error) = &mut error {
+            error.suggestion = suggestion;
+        }
+        error
+    }
+
     #[inline]
     fn read_constant(&mut self) -> Value {
         let idx = self.read_byte();
         self.top_call_frame().function().chunk.constants[idx as usize]
     }
 }
+
+/// Make `function` and the functions declared in it use the globals of `module`
+fn set_module(function: &mut ObjFunction, module: Gc<ObjModule>) {
+    function.module = Some(module);
+    for constant in &function.chunk.constants {
+        if let Some(mut nested) = constant.as_fn() {
+            set_module(&mut nested, module);
+        }
+    }
+}
@@ -0,0 +1,285 @@
+//! `--coverage`: which lines and branches of a script ran, and how often.
+//!
+//! Like [`Profile`](super::profile::Profile) the VM reports every instruction it's about to
+//! run. A function is registered the first time one of its instructions runs, along with the
+//! functions declared in it, so the ones that never ran show up too. Lines are taken from the
+//! chunk's line table, a line ran as often as its most frequent instruction.
+//!
+//! A conditional jump (`JumpIfFalse`, `LessLocalJumpIfFalse` and `ForIter`) is a branch with
+//! two ways to go, the next instruction the same frame runs tells which one it went.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+};
+
+use crate::{
+    chunk::{Instruction, Opcode},
+    obj::ObjFunction,
+};
+
+#[derive(Debug, Default)]
+pub struct Coverage {
+    functions: Vec<FunctionCoverage>,
+    /// Index into `functions`. Functions stay alive while the script runs, they're constants
+    /// of the script or of other functions
+    indices: HashMap<*const ObjFunction, usize>,
+    /// Branches waiting for the next instruction of their frame: the function's index, the
+    /// call depth and the branch's index. Innermost last
+    pending: Vec<(usize, u32, usize)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FunctionCoverage {
+    /// `None` for top-level code
+    pub name: Option<String>,
+    /// The script or module the function was declared in
+    pub file: String,
+    /// Line the function is declared on
+    pub line: u32,
+    /// Offset and line of each instruction, with how often it ran
+    pub instructions: Vec<(u32, u32, u64)>,
+    pub branches: Vec<BranchCoverage>,
+}
+
+#[derive(Debug, Clone)]
+pub struct BranchCoverage {
+    pub offset: u32,
+    pub line: u32,
+    /// Where the code continues without jumping
+    next: u32,
+    /// How often the branch didn't jump and how often it did
+    pub taken: [u64; 2],
+}
+
+impl Coverage {
+    /// Count the instruction at `offset` in `function`, running at call `depth`. `file` names
+    /// where the function comes from if it's new
+    pub fn instruction(
+        &mut self,
+        function: &ObjFunction,
+        depth: u32,
+        offset: u32,
+        file: impl FnOnce() -> String,
+    ) {
+        let index = match self.indices.get(&(function as *const ObjFunction)) {
+            Some(&index) => index,
+            None => self.register(function, &file()),
+        };
+
+        // Branches of frames that returned never find out
+        while let Some(&(_, branch_depth, _)) = self.pending.last()
+            && branch_depth > depth
+        {
+            self.pending.pop();
+        }
+        if let Some(&(branch_function, branch_depth, branch)) = self.pending.last()
+            && branch_depth == depth
+        {
+            self.pending.pop();
+            // Unless a tail call replaced the frame
+            if branch_function == index {
+                let branch = &mut self.functions[index].branches[branch];
+                branch.taken[(offset != branch.next) as usize] += 1;
+            }
+        }
+
+        let coverage = &mut self.functions[index];
+        if let Ok(i) = coverage
+            .instructions
+            .binary_search_by_key(&offset, |&(start, ..)| start)
+        {
+            coverage.instructions[i].2 += 1;
+        }
+        if let Ok(branch) = coverage
+            .branches
+            .binary_search_by_key(&offset, |branch| branch.offset)
+        {
+            self.pending.push((index, depth, branch));
+        }
+    }
+
+    /// Add `function` and the functions declared in it
+    fn register(&mut self, function: &ObjFunction, file: &str) -> usize {
+        let chunk = &function.chunk;
+        let mut instructions = vec![];
+        let mut branches = vec![];
+        let mut offset = 0;
+        while offset < chunk.code.len() {
+            let start = offset as u32;
+            let line = chunk.get_line(offset);
+            let Some(instruction) = chunk.disassemble_instruction(&mut offset) else {
+                break;
+            };
+            instructions.push((start, line, 0));
+            if let Instruction::Jump(Opcode::JumpIfFalse, _)
+            | Instruction::SlotJump(Opcode::LessLocalJumpIfFalse | Opcode::ForIter, ..) =
+                instruction
+            {
+                branches.push(BranchCoverage {
+                    offset: start,
+                    line,
+                    next: offset as u32,
+                    taken: [0; 2],
+                });
+            }
+        }
+
+        // The script's implicit return is on the line of the end of the file, which is past the
+        // last line when the file ends with a newline. Leave it out unless there's code there
+        if function.name.is_none()
+            && let [.., (_, before, _), (_, value, _), (_, end, _)] = instructions[..]
+            && before < value
+            && value == end
+        {
+            instructions.truncate(instructions.len() - 2);
+        }
+
+        let index = self.functions.len();
+        self.functions.push(FunctionCoverage {
+            name: function.name.map(|name| name.as_str().to_string()),
+            file: file.to_string(),
+            line: function.line,
+            instructions,
+            branches,
+        });
+        self.indices.insert(function, index);
+
+        for constant in chunk.constants.iter() {
+            if let Some(nested) = constant.as_fn()
+                && !self.indices.contains_key(&nested.as_ptr().cast_const())
+            {
+                self.register(&nested, file);
+            }
+        }
+        index
+    }
+
+    pub fn functions(&self) -> &[FunctionCoverage] {
+        &self.functions
+    }
+
+    /// The files functions ran from, in the order they first did
+    pub fn files(&self) -> Vec<&str> {
+        let mut files: Vec<&str> = vec![];
+        for function in &self.functions {
+            if !files.contains(&function.file.as_str()) {
+                files.push(&function.file);
+            }
+        }
+        files
+    }
+
+    /// How often each line of `file` with code on it ran
+    pub fn lines(&self, file: &str) -> BTreeMap<u32, u64> {
+        let mut lines = BTreeMap::new();
+        for function in self
+            .functions
+            .iter()
+            .filter(|function| function.file == file)
+        {
+            for &(_, line, count) in &function.instructions {
+                let hits = lines.entry(line).or_insert(0);
+                *hits = count.max(*hits);
+            }
+        }
+        lines
+    }
+
+    fn branches<'a>(&'a self, file: &'a str) -> impl Iterator<Item = &'a BranchCoverage> + 'a {
+        self.functions
+            .iter()
+            .filter(move |function| function.file == file)
+            .flat_map(|function| &function.branches)
+    }
+
+    /// The report in the lcov tracefile format, which `genhtml` and editors read
+    pub fn lcov(&self) -> String {
+        let mut out = String::from("TN:\n");
+        for file in self.files() {
+            let _ = writeln!(out, "SF:{file}");
+            let functions: Vec<_> = self
+                .functions
+                .iter()
+                .filter(|function| function.file == file && function.name.is_some())
+                .collect();
+            for function in &functions {
+                let name = function.name.as_deref().unwrap();
+                let _ = writeln!(out, "FN:{},{name}", function.line);
+            }
+            for function in &functions {
+                let name = function.name.as_deref().unwrap();
+                // The first instruction runs once per call
+                let calls = function
+                    .instructions
+                    .first()
+                    .map_or(0, |&(.., count)| count);
+                let _ = writeln!(out, "FNDA:{calls},{name}");
+            }
+            let called = functions
+                .iter()
+                .filter(|function| function.instructions.first().map_or(false, |i| i.2 > 0))
+                .count();
+            let _ = writeln!(out, "FNF:{}\nFNH:{called}", functions.len());
+
+            let (mut found, mut hit) = (0, 0);
+            for (block, branch) in self.branches(file).enumerate() {
+                let ran = branch.taken != [0; 2];
+                for (way, count) in branch.taken.iter().enumerate() {
+                    let count = if ran {
+                        count.to_string()
+                    } else {
+                        "-".to_string()
+                    };
+                    let _ = writeln!(out, "BRDA:{},{block},{way},{count}", branch.line);
+                }
+                found += 2;
+                hit += branch.taken.iter().filter(|&&count| count > 0).count();
+            }
+            let _ = writeln!(out, "BRF:{found}\nBRH:{hit}");
+
+            let lines = self.lines(file);
+            for (line, count) in &lines {
+                let _ = writeln!(out, "DA:{line},{count}");
+            }
+            let hit = lines.values().filter(|&&count| count > 0).count();
+            let _ = writeln!(out, "LF:{}\nLH:{hit}\nend_of_record", lines.len());
+        }
+        out
+    }
+
+    /// `source`, the contents of `file`, with how often each line ran in front of it like gcov
+    /// shows it: `-` for lines without code, `#####` for lines that never ran. Lines with a
+    /// branch that only went one way are marked with `*`
+    pub fn annotate(&self, file: &str, source: &str) -> String {
+        let lines = self.lines(file);
+        let partial: Vec<u32> = self
+            .branches(file)
+            .filter(|branch| branch.taken.iter().any(|&count| count == 0))
+            .map(|branch| branch.line)
+            .collect();
+        let mut out = String::new();
+        for (i, text) in source.lines().enumerate() {
+            let line = i as u32 + 1;
+            let count = match lines.get(&line) {
+                None => "-".to_string(),
+                Some(0) => "#####".to_string(),
+                Some(count) => count.to_string(),
+            };
+            let mark = if partial.contains(&line) { '*' } else { ' ' };
+            let _ = writeln!(out, "{count:>9}{mark}{line:>5}: {text}");
+        }
+        out
+    }
+
+    /// Lines that ran and lines with code, over all files
+    pub fn summary(&self) -> (usize, usize) {
+        self.files()
+            .into_iter()
+            .map(|file| self.lines(file))
+            .fold((0, 0), |(hit, found), lines| {
+                let ran = lines.values().filter(|&&count| count > 0).count();
+                (hit + ran, found + lines.len())
+            })
+    }
+}
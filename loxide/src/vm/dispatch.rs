@@ -0,0 +1,962 @@
+//! The instruction handlers, one function per opcode.
+//!
+//! [`VM::execute_until_error`] only decodes the opcode and calls its handler. The handlers are
+//! `#[inline(always)]`, so the optimizer still sees the whole loop as one function: each arm of
+//! the dispatch `match` is the handler's code, and LLVM is free to duplicate the dispatch into
+//! the end of the handlers, i.e. to turn the loop into threaded code. Handlers that can finish
+//! the current `execute` (`Return`, `Throw`, `EndFinally` and `Yield`) return the final value,
+//! the others only errors.
+
+use std::io::Write;
+
+use super::{Handler, InterpretResult, Wait, VM};
+use crate::{
+    chunk::{read_u24, FINALLY_NORMAL, FINALLY_RETURN, FINALLY_THROW},
+    native_fn::{list_index, map_key, slice_str, string_index},
+    obj::{GeneratorState, ObjClass, ObjList, ObjMap, Operator},
+    table::Key,
+    value::{display::ValueWrapper, Unpacked, Value},
+};
+
+/// `Some` when the frame `execute` started with returned
+type Step = InterpretResult<Option<Value>>;
+
+#[inline(always)]
+pub(super) fn op_super_invoke(vm: &mut VM) -> InterpretResult<()> {
+    let method = vm.read_constant().as_obj_str().unwrap();
+    let arg_count = vm.read_byte();
+    let Some(superclass) = vm.pop().as_class() else {
+        return Err(vm.runtime_error("Superclass must be a class.".into()));
+    };
+
+    vm.invoke_from_class(superclass, method, arg_count)?;
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_get_super(vm: &mut VM) -> InterpretResult<()> {
+    // The name of the class
+    let name = vm.read_constant().as_obj_str().unwrap();
+
+    let Some(superclass) = vm.pop().as_class() else {
+        return Err(vm.runtime_error("Superclass must be a class.".into()));
+    };
+
+    vm.bind_method(superclass, name)?;
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_inherit(vm: &mut VM) -> InterpretResult<()> {
+    let superclass = vm.peek(1);
+    let superclass = match superclass.as_class() {
+        Some(class) => class,
+        None => {
+            return Err(vm.runtime_error("Superclass must be a class.".into()));
+        }
+    };
+
+    let Some(mut subclass) = vm.peek(0).as_class() else {
+        return Err(vm.runtime_error("Invalid class member.".into()));
+    };
+
+    superclass.methods.add_all(&mut subclass.methods);
+    superclass.getters.add_all(&mut subclass.getters);
+    superclass.setters.add_all(&mut subclass.setters);
+    subclass.fields = superclass.fields.clone();
+    superclass
+        .static_methods
+        .add_all(&mut subclass.static_methods);
+    superclass
+        .static_fields
+        .add_all(&mut subclass.static_fields);
+
+    vm.pop();
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_invoke(vm: &mut VM) -> InterpretResult<()> {
+    let index = vm.read_byte() as usize;
+    let arg_count = vm.read_byte();
+    vm.invoke(index, arg_count)?;
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_method(vm: &mut VM) -> InterpretResult<()> {
+    let obj_str = vm.read_constant().as_obj_str().unwrap();
+    vm.define_method(obj_str)
+}
+
+#[inline(always)]
+pub(super) fn op_field(vm: &mut VM) -> InterpretResult<()> {
+    let name = vm.read_constant().as_obj_str().unwrap();
+    let mut class = vm.member_class(false)?;
+    let initializer = vm.peek(0);
+    // Redeclaring an inherited field keeps its place
+    let declared = class
+        .fields
+        .iter_mut()
+        .find(|(field, _)| field.as_ptr() == name.as_ptr());
+    match declared {
+        Some(field) => field.1 = initializer,
+        None => class.fields.push((name, initializer)),
+    }
+    vm.pop();
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_static_method(vm: &mut VM) -> InterpretResult<()> {
+    let name = vm.read_constant().as_obj_str().unwrap();
+    let mut class = vm.member_class(true)?;
+    class.static_methods.set(name, vm.peek(0));
+    vm.pop();
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_static_field(vm: &mut VM) -> InterpretResult<()> {
+    let name = vm.read_constant().as_obj_str().unwrap();
+    let mut class = vm.member_class(false)?;
+    class.static_fields.set(name, vm.peek(0));
+    vm.pop();
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_getter(vm: &mut VM) -> InterpretResult<()> {
+    let name = vm.read_constant().as_obj_str().unwrap();
+    let mut class = vm.member_class(true)?;
+    class.getters.set(name, vm.peek(0));
+    vm.pop();
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_setter(vm: &mut VM) -> InterpretResult<()> {
+    let name = vm.read_constant().as_obj_str().unwrap();
+    let mut class = vm.member_class(true)?;
+    class.setters.set(name, vm.peek(0));
+    vm.pop();
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_get_property(vm: &mut VM) -> InterpretResult<()> {
+    let top = vm.peek(0);
+    let index = vm.read_byte() as usize;
+    let name = vm.top_call_frame().function().chunk.constants[index]
+        .as_obj_str()
+        .expect("Expect to read a string constant.");
+    let instance = match top.as_instance_fn() {
+        Some(instance) => instance,
+        None => {
+            if let Some(module) = top.as_module() {
+                let export = vm.export(module, name)?;
+                vm.pop();
+                vm.push(export);
+                return Ok(());
+            }
+            if let Some(class) = top.as_class() {
+                return vm.get_static(class, name);
+            }
+            return Err(vm.runtime_error("Only instances have properties.".into()));
+        }
+    };
+
+    match instance.fields.get(name) {
+        Some(val) => {
+            vm.pop();
+            vm.push(val);
+        }
+        None => match vm.find_method(instance.class, index) {
+            Some(method) => vm.bind(method),
+            // The getter replaces the instance with what it returns
+            None => match instance.class.getters.get(name) {
+                Some(getter) => vm.call(getter.as_obj_closure().unwrap(), 0)?,
+                None => {
+                    return Err(
+                        vm.runtime_error(format!("Undefined property '{}'.", name.as_str()).into())
+                    );
+                }
+            },
+        },
+    }
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_set_property(vm: &mut VM) -> InterpretResult<()> {
+    let top = vm.peek(1);
+    let field_name = vm
+        .read_constant()
+        .as_obj_str()
+        .expect("Expect to string constant");
+    let mut instance = match top.as_instance_fn() {
+        Some(instance) => instance,
+        None => {
+            if let Some(mut class) = top.as_class() {
+                class.static_fields.set(field_name, vm.peek(0));
+                let value = vm.pop();
+                vm.pop();
+                vm.push(value);
+                return Ok(());
+            }
+            return Err(vm.runtime_error("Only instances have fields.".into()));
+        }
+    };
+
+    // The setter gets the instance and the value, and returns the value
+    if let Some(setter) = instance.class.setters.get(field_name) {
+        return vm.call(setter.as_obj_closure().unwrap(), 1);
+    }
+    instance.fields.set(field_name, vm.peek(0));
+
+    let value = vm.pop();
+    vm.pop();
+    vm.push(value);
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_class(vm: &mut VM) -> InterpretResult<()> {
+    let name = vm
+        .read_constant()
+        .as_obj_str()
+        .expect("Opcode::Class instruction should be followed by string constant");
+
+    let class = ObjClass::new(name);
+    let class = vm.alloc_obj(class);
+
+    vm.push(Value::Obj(class.upcast()));
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_close_upvalue(vm: &mut VM) -> InterpretResult<()> {
+    vm.close_upvalues(unsafe { vm.stack.top.sub(1) });
+    vm.pop();
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_get_iter(vm: &mut VM) -> InterpretResult<()> {
+    let collection = vm.peek(0);
+    if let Some(map) = collection.as_map() {
+        // Iterate over a snapshot of the keys, so the map can change in the loop
+        let keys = map.entries.iter().map(|entry| entry.key).collect();
+        let keys = vm.alloc_obj(ObjList::new(keys));
+        vm.pop();
+        vm.push(Value::Obj(keys.upcast()));
+    } else if let Some(generator) = collection.as_generator()
+        && generator.task
+    {
+        return Err(vm.runtime_error("Only the scheduler runs tasks.".into()));
+    } else if collection.as_list().is_none()
+        && !collection.is_str()
+        && collection.as_generator().is_none()
+    {
+        return Err(
+            vm.runtime_error("Can only iterate over lists, maps, strings and generators.".into())
+        );
+    }
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_for_iter(vm: &mut VM) -> InterpretResult<()> {
+    let slot = vm.read_byte() as usize;
+    let exit = vm.read_u16();
+    let collection = vm.top_call_frame().index(slot);
+    // The generator pushes the item when it yields, or jumps to the exit when it returns
+    if let Some(generator) = collection.as_generator() {
+        let exit = vm.top_call_frame().instr_offset + exit as u32;
+        if generator.state == GeneratorState::Done {
+            vm.top_call_frame_mut().instr_offset = exit;
+            return Ok(());
+        }
+        return vm.resume(generator, Some(exit));
+    }
+    // Only bytecode that didn't come from the compiler loops without a cursor
+    let Some(cursor) = vm.top_call_frame().index(slot + 1).as_number() else {
+        return Err(vm.runtime_error("Invalid loop cursor.".into()));
+    };
+    let cursor = cursor as usize;
+
+    // Lists use an index, strings a byte offset
+    let next = if let Some(list) = collection.as_list() {
+        list.items.get(cursor).map(|item| (*item, cursor + 1))
+    } else if let Some(string) = collection.as_str() {
+        let next = string.get(cursor..).and_then(|rest| rest.chars().next());
+        next.map(|char| {
+            let item = vm.alloc_string(char.encode_utf8(&mut [0; 4]));
+            (item, cursor + char.len_utf8())
+        })
+    } else {
+        return Err(
+            vm.runtime_error("Can only iterate over lists, maps, strings and generators.".into())
+        );
+    };
+
+    match next {
+        Some((item, cursor)) => {
+            vm.top_call_frame_mut()
+                .set(slot + 1, Value::number(cursor as f64));
+            vm.push(item);
+        }
+        None => vm.top_call_frame_mut().instr_offset += exit as u32,
+    }
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_yield(vm: &mut VM, base_frame_count: u32) -> Step {
+    // Only bytecode that didn't come from the compiler yields outside of a generator
+    let resumed = vm.resumed.last().map(|resumed| resumed.frame_count);
+    if resumed != Some(vm.call_frame_count) {
+        return Err(vm.runtime_error("Can only yield from a generator.".into()));
+    }
+    let value = vm.pop();
+    Ok(vm.suspend(value, base_frame_count))
+}
+
+#[inline(always)]
+pub(super) fn op_await(vm: &mut VM) -> InterpretResult<()> {
+    let Some(channel) = vm.peek(0).as_channel() else {
+        return Err(vm.runtime_error("Can only await channels.".into()));
+    };
+    if let Some(snapshot) = channel.channel.try_recv() {
+        vm.pop();
+        let value = snapshot.rebuild(vm);
+        vm.push(value);
+        return Ok(());
+    }
+    if !vm.in_task() {
+        return Err(vm.runtime_error("Can only wait for a channel in a task.".into()));
+    }
+    // Replaced by the value once one is sent
+    vm.pop();
+    vm.push(Value::Nil);
+    vm.block(Wait::Recv(channel));
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_close_local(vm: &mut VM) -> InterpretResult<()> {
+    // Unlike `CloseUpvalue`, the local stays on the stack
+    let slot = vm.read_byte();
+    let local = vm.top_call_frame().index_ptr(slot as usize);
+    vm.close_upvalues(local);
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_get_upvalue(vm: &mut VM) -> InterpretResult<()> {
+    let slot = vm.read_byte();
+    let val = unsafe {
+        *(vm.top_call_frame()
+            .closure
+            .as_ref()
+            .upvalue_at_slot(slot as usize)
+            .unwrap()
+            .as_ref()
+            .location
+            .as_ptr())
+    };
+
+    vm.push(val);
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_set_upvalue(vm: &mut VM) -> InterpretResult<()> {
+    let slot = vm.read_byte();
+    let val = vm.peek(0);
+    unsafe {
+        let loc_ptr = vm
+            .top_call_frame()
+            .closure()
+            .upvalue_at_slot(slot as usize)
+            .unwrap()
+            .as_mut()
+            .location
+            .as_ptr();
+
+        (*loc_ptr) = val;
+    }
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_closure(vm: &mut VM) -> InterpretResult<()> {
+    let function = vm.read_constant().as_fn().unwrap();
+    vm.new_closure(function);
+    // TODO: investigate
+    // let closure = vm.alloc_obj();
+    // let noob = Value::Obj(closure.upcast());
+    // println!("did the closure thing");
+    // vm.push(noob);
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_call(vm: &mut VM) -> InterpretResult<()> {
+    let arg_count = vm.read_byte();
+    vm.call_value(vm.peek(arg_count as u32), arg_count)?;
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_tail_call(vm: &mut VM) -> InterpretResult<()> {
+    let arg_count = vm.read_byte();
+    vm.tail_call_value(vm.peek(arg_count as u32), arg_count)?;
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_call_named(vm: &mut VM) -> InterpretResult<()> {
+    let arg_count = vm.read_byte();
+    let named = vm.read_byte();
+    let arg_count = vm.bind_named_args(arg_count, named)?;
+    vm.call_value(vm.peek(arg_count as u32), arg_count)?;
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_loop(vm: &mut VM) -> InterpretResult<()> {
+    let offset = vm.read_u16();
+    vm.top_call_frame_mut().instr_offset -= offset as u32;
+    vm.check_interrupt()
+}
+
+#[inline(always)]
+pub(super) fn op_jump(vm: &mut VM) -> InterpretResult<()> {
+    let offset = vm.read_u16();
+    vm.top_call_frame_mut().instr_offset += offset as u32;
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_jump_if_false(vm: &mut VM) -> InterpretResult<()> {
+    let offset = vm.read_u16();
+    if vm.peek(0).is_falsey() {
+        vm.top_call_frame_mut().instr_offset += offset as u32;
+    }
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_get_local(vm: &mut VM) -> InterpretResult<()> {
+    let slot = vm.read_byte();
+    let val = vm.top_call_frame().index(slot as usize);
+
+    vm.push(val);
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_set_local(vm: &mut VM) -> InterpretResult<()> {
+    let slot = vm.read_byte();
+    let val = vm.peek(0);
+    vm.top_call_frame_mut().set(slot as usize, val);
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_set_global(vm: &mut VM) -> InterpretResult<()> {
+    let slot = vm.read_global_slot();
+    let value = vm.peek(0);
+    // A module can only assign its own globals
+    let globals = vm.globals();
+    if globals.get_slot(slot).is_none() {
+        return Err(vm.undefined_global(slot));
+    }
+    // The compiler catches assignments to constants declared later in the same script, but not
+    // to ones declared by a later script, e.g. the next REPL input or a module
+    if !vm.mem.const_globals.is_empty() {
+        let name = vm.globals().name(slot);
+        if vm.mem.const_globals.get(name).is_some() {
+            return Err(vm.runtime_error("Can't assign to a constant.".into()));
+        }
+    }
+    vm.globals().set_slot(slot, value);
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_get_global(vm: &mut VM) -> InterpretResult<()> {
+    let slot = vm.read_global_slot();
+    let Some(val) = vm.get_global_slot(slot) else {
+        return Err(vm.undefined_global(slot));
+    };
+
+    vm.push(val);
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_define_global(vm: &mut VM) -> InterpretResult<()> {
+    let slot = vm.read_global_slot();
+    let value = vm.peek(0);
+    vm.globals().set_slot(slot, value);
+    vm.pop();
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_nil(vm: &mut VM) -> InterpretResult<()> {
+    vm.push(Value::Nil);
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_true(vm: &mut VM) -> InterpretResult<()> {
+    vm.push(Value::Bool(true));
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_false(vm: &mut VM) -> InterpretResult<()> {
+    vm.push(Value::Bool(false));
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_pop(vm: &mut VM) -> InterpretResult<()> {
+    vm.pop();
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_print(vm: &mut VM) -> InterpretResult<()> {
+    let value = match vm.operator_result(Operator::ToString, 0)? {
+        Some(string) => string,
+        None => vm.pop(),
+    };
+    let written = if vm.debug_print {
+        writeln!(vm.stdout, "{:?}", ValueWrapper(value))
+    } else {
+        writeln!(vm.stdout, "{}", ValueWrapper(value))
+    };
+    if let Err(err) = written {
+        return Err(vm.runtime_error(format!("Could not print: {err}.").into()));
+    }
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_equal(vm: &mut VM) -> InterpretResult<()> {
+    if vm.call_operator(Operator::Equal, 1)? {
+        return Ok(());
+    }
+    let b = vm.pop();
+    let a = vm.pop();
+
+    vm.push(Value::Bool(a == b));
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_not(vm: &mut VM) -> InterpretResult<()> {
+    let top = vm.pop();
+    vm.push(Value::Bool(top.is_falsey()));
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_negate(vm: &mut VM) -> InterpretResult<()> {
+    if !vm.peek(0).is_number() {
+        if vm.call_operator(Operator::Negate, 0)? {
+            return Ok(());
+        }
+        return Err(vm.runtime_error("Operand must be a number.".into()));
+    }
+
+    let negated = -vm.pop();
+    vm.push(negated);
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_return(vm: &mut VM, base_frame_count: u32) -> Step {
+    let result = vm.pop();
+    Ok(vm.return_from_frame(result, base_frame_count))
+}
+
+#[inline(always)]
+pub(super) fn op_throw(vm: &mut VM, base_frame_count: u32) -> Step {
+    let exception = vm.pop();
+    if !vm.throw(exception, base_frame_count) {
+        return Err(vm.uncaught(exception));
+    }
+    Ok(None)
+}
+
+#[inline(always)]
+pub(super) fn op_push_catch(vm: &mut VM) -> InterpretResult<()> {
+    let offset = vm.read_u16();
+    let target = vm.top_call_frame().instr_offset + offset as u32;
+    vm.handlers.push(Handler {
+        frame_count: vm.call_frame_count,
+        stack_top: vm.stack.top,
+        target,
+        is_finally: false,
+    });
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_push_finally(vm: &mut VM) -> InterpretResult<()> {
+    let offset = vm.read_u16();
+    let target = vm.top_call_frame().instr_offset + offset as u32;
+    vm.handlers.push(Handler {
+        frame_count: vm.call_frame_count,
+        stack_top: vm.stack.top,
+        target,
+        is_finally: true,
+    });
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_assert(vm: &mut VM) -> InterpretResult<()> {
+    let message = vm.read_constant();
+    if vm.pop().is_falsey() {
+        return Err(vm.runtime_error(ValueWrapper(message).to_string().into()));
+    }
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_pop_handler(vm: &mut VM) -> InterpretResult<()> {
+    vm.handlers.pop();
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_end_finally(vm: &mut VM, base_frame_count: u32) -> Step {
+    // Only bytecode that didn't come from the compiler gets this wrong
+    let completion = match vm.pop().unpack() {
+        Unpacked::Number(completion)
+            if [FINALLY_NORMAL, FINALLY_THROW, FINALLY_RETURN].contains(&completion) =>
+        {
+            completion
+        }
+        _ => return Err(vm.runtime_error("Invalid finally completion.".into())),
+    };
+    let value = vm.pop();
+
+    if completion == FINALLY_THROW {
+        if !vm.throw(value, base_frame_count) {
+            return Err(vm.uncaught(value));
+        }
+    } else if completion == FINALLY_RETURN {
+        if let Some(result) = vm.return_from_frame(value, base_frame_count) {
+            return Ok(Some(result));
+        }
+    }
+    Ok(None)
+}
+
+#[inline(always)]
+pub(super) fn op_constant(vm: &mut VM) -> InterpretResult<()> {
+    let constant = vm.read_constant();
+    vm.push(constant);
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_constant_long(vm: &mut VM) -> InterpretResult<()> {
+    let bytes = [vm.read_byte(), vm.read_byte(), vm.read_byte()];
+    let constant = vm.top_call_frame().function().chunk.constants[read_u24(&bytes)];
+    vm.push(constant);
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_subtract(vm: &mut VM) -> InterpretResult<()> {
+    vm.overloadable_op(Operator::Subtract, std::ops::Sub::sub)?;
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_multiply(vm: &mut VM) -> InterpretResult<()> {
+    vm.overloadable_op(Operator::Multiply, std::ops::Mul::mul)?;
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_divide(vm: &mut VM) -> InterpretResult<()> {
+    vm.overloadable_op(Operator::Divide, std::ops::Div::div)?;
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_modulo(vm: &mut VM) -> InterpretResult<()> {
+    vm.overloadable_op(Operator::Modulo, Value::modulo_owned)?;
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_floor_divide(vm: &mut VM) -> InterpretResult<()> {
+    vm.binary_op(Value::floor_div_owned)?;
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_power(vm: &mut VM) -> InterpretResult<()> {
+    vm.binary_op(Value::pow_owned)?;
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_dup(vm: &mut VM) -> InterpretResult<()> {
+    vm.push(vm.peek(0));
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_build_list(vm: &mut VM) -> InterpretResult<()> {
+    let count = vm.read_byte() as u32;
+    let items = (0..count).rev().map(|i| vm.peek(i)).collect();
+    // The items stay on the stack until the list exists, so they're rooted
+    let list = vm.alloc_obj(ObjList::new(items));
+    for _ in 0..count {
+        vm.pop();
+    }
+    vm.push(Value::Obj(list.upcast()));
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_build_map(vm: &mut VM) -> InterpretResult<()> {
+    let count = vm.read_byte() as u32;
+    // Allocated before popping the entries so they stay rooted
+    let mut map = vm.alloc_obj(ObjMap::new());
+    for i in (0..count).rev() {
+        let key = map_key(vm.peek(i * 2 + 1)).map_err(|err| vm.runtime_error(err.into()))?;
+        map.entries.set_value(key, vm.peek(i * 2));
+    }
+    for _ in 0..count * 2 {
+        vm.pop();
+    }
+    vm.push(Value::Obj(map.upcast()));
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_get_index(vm: &mut VM) -> InterpretResult<()> {
+    let index = vm.peek(0);
+    let target = vm.peek(1);
+    let value = if let Some(list) = target.as_list() {
+        list_index(index, list.items.len())
+            .map(|index| list.items[index])
+            .map_err(|err| vm.runtime_error(err.into()))?
+    } else if let Some(map) = target.as_map() {
+        let Some(value) = Key::new(index).and_then(|key| map.entries.get_value(key)) else {
+            return Err(vm.runtime_error(format!("Undefined key {}.", ValueWrapper(index)).into()));
+        };
+        value
+    } else if let Some(string) = target.as_str() {
+        let len = string.chars().count();
+        let index = string_index(index, len, false).map_err(|err| vm.runtime_error(err.into()))?;
+        let char = string.chars().nth(index).unwrap();
+        // The string is still on the stack if this collects garbage
+        vm.alloc_string(char.encode_utf8(&mut [0; 4]))
+    } else if vm.call_operator(Operator::GetIndex, 1)? {
+        return Ok(());
+    } else {
+        return Err(vm.runtime_error("Only lists, maps and strings can be indexed.".into()));
+    };
+
+    vm.pop();
+    vm.pop();
+    vm.push(value);
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_to_string(vm: &mut VM) -> InterpretResult<()> {
+    let value = vm.peek(0);
+    // Instances with a `toString()` method are replaced by what it returns, which the `Add`
+    // after this converts in turn if it isn't a string
+    if !value.is_str() && !vm.call_operator(Operator::ToString, 0)? {
+        // Still on the stack while the string is allocated
+        let string = vm.alloc_string(&ValueWrapper(value).to_string());
+        vm.pop();
+        vm.push(string);
+    }
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_concat(vm: &mut VM) -> InterpretResult<()> {
+    let count = vm.read_byte() as u32;
+    vm.concatenate_n(count)
+}
+
+#[inline(always)]
+pub(super) fn op_slice(vm: &mut VM) -> InterpretResult<()> {
+    let end = vm.peek(0);
+    let start = vm.peek(1);
+    let target = vm.peek(2);
+    let Some(string) = target.as_str() else {
+        return Err(vm.runtime_error("Only strings can be sliced.".into()));
+    };
+
+    let slice = slice_str(string, start, end).map_err(|err| vm.runtime_error(err.into()))?;
+    let slice = vm.alloc_string(slice);
+
+    vm.pop();
+    vm.pop();
+    vm.pop();
+    vm.push(slice);
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_set_index(vm: &mut VM) -> InterpretResult<()> {
+    let value = vm.peek(0);
+    let index = vm.peek(1);
+    let target = vm.peek(2);
+    if let Some(mut list) = target.as_list() {
+        let index =
+            list_index(index, list.items.len()).map_err(|err| vm.runtime_error(err.into()))?;
+        list.items[index] = value;
+    } else if let Some(mut map) = target.as_map() {
+        let key = map_key(index).map_err(|err| vm.runtime_error(err.into()))?;
+        map.entries.set_value(key, value);
+    } else if vm.call_operator(Operator::SetIndex, 2)? {
+        return Ok(());
+    } else {
+        return Err(vm.runtime_error("Only lists and maps can be indexed.".into()));
+    }
+
+    vm.pop();
+    vm.pop();
+    vm.pop();
+    vm.push(value);
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_bit_and(vm: &mut VM) -> InterpretResult<()> {
+    vm.binary_op(|a, b| Value::bitwise(a, b, |a, b| a & b))?;
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_bit_or(vm: &mut VM) -> InterpretResult<()> {
+    vm.binary_op(|a, b| Value::bitwise(a, b, |a, b| a | b))?;
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_bit_xor(vm: &mut VM) -> InterpretResult<()> {
+    vm.binary_op(|a, b| Value::bitwise(a, b, |a, b| a ^ b))?;
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_shift_left(vm: &mut VM) -> InterpretResult<()> {
+    vm.binary_op(|a, b| Value::bitwise(a, b, |a, b| a.wrapping_shl(b as u32)))?;
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_shift_right(vm: &mut VM) -> InterpretResult<()> {
+    vm.binary_op(|a, b| Value::bitwise(a, b, |a, b| a.wrapping_shr(b as u32)))?;
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_bit_not(vm: &mut VM) -> InterpretResult<()> {
+    let n = vm.peek(0);
+    if !n.is_number() {
+        return Err(vm.runtime_error("Operand must be a number.".into()));
+    }
+
+    vm.pop();
+    vm.push(Value::Int(!n.to_int32()));
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_greater(vm: &mut VM) -> InterpretResult<()> {
+    vm.overloadable_op(Operator::Greater, Value::gt_owned)?;
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_less(vm: &mut VM) -> InterpretResult<()> {
+    vm.overloadable_op(Operator::Less, Value::lt_owned)?;
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_add(vm: &mut VM) -> InterpretResult<()> {
+    vm.add()?;
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_add_locals(vm: &mut VM) -> InterpretResult<()> {
+    let a = vm.read_byte();
+    let b = vm.read_byte();
+    let frame = vm.top_call_frame();
+    let (a, b) = (frame.index(a as usize), frame.index(b as usize));
+    vm.push(a);
+    vm.push(b);
+    vm.add()?;
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_call_constant(vm: &mut VM) -> InterpretResult<()> {
+    let constant = vm.read_constant();
+    let arg_count = vm.read_byte();
+    vm.push(constant);
+    vm.call_value(vm.peek(arg_count as u32), arg_count)?;
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_less_local_jump_if_false(vm: &mut VM) -> InterpretResult<()> {
+    let slot = vm.read_byte();
+    let offset = vm.read_u16();
+    vm.push(vm.top_call_frame().index(slot as usize));
+    let overloaded = match vm.peek(1).is_number() {
+        true => None,
+        false => vm.operator_result(Operator::Less, 1)?,
+    };
+    if let Some(result) = overloaded {
+        vm.push(result);
+    } else {
+        vm.binary_op(Value::lt_owned)?;
+    }
+    if vm.peek(0).is_falsey() {
+        vm.top_call_frame_mut().instr_offset += offset as u32;
+    }
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_import(vm: &mut VM) -> InterpretResult<()> {
+    let name = vm.read_constant().as_obj_str().unwrap();
+    vm.import(name)?;
+    Ok(())
+}
+
+#[inline(always)]
+pub(super) fn op_end_module(vm: &mut VM) -> InterpretResult<()> {
+    vm.end_module()
+}
+
+#[inline(always)]
+pub(super) fn op_import_native(vm: &mut VM) -> InterpretResult<()> {
+    let name = vm.read_constant().as_obj_str().unwrap();
+    vm.import_native(name)?;
+    Ok(())
+}
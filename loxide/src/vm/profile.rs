@@ -0,0 +1,150 @@
+//! `--profile`: how often each opcode runs and where the time goes, by Lox function.
+//!
+//! The VM reports every instruction it's about to run. The time between two instructions is
+//! charged to the function the first one is in, so a function's time is its self time: calls
+//! to other Lox functions are charged to them, natives to the function calling them.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{Duration, Instant},
+};
+
+use crate::{chunk::Opcode, obj::ObjFunction};
+
+#[derive(Debug)]
+pub struct Profile {
+    opcodes: [u64; 256],
+    functions: Vec<FunctionStats>,
+    /// Index into `functions`. Functions stay alive while the script runs, they're constants
+    /// of the script or of other functions
+    indices: HashMap<*const ObjFunction, usize>,
+    /// The function and call depth of the last instruction, and the function's index
+    current: Option<(*const ObjFunction, u32, usize)>,
+    /// When the current function started running
+    since: Instant,
+}
+
+#[derive(Debug, Clone)]
+pub struct FunctionStats {
+    /// `None` for top-level code
+    pub name: Option<String>,
+    /// Line the function is declared on
+    pub line: u32,
+    pub calls: u64,
+    pub instructions: u64,
+    pub time: Duration,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self {
+            opcodes: [0; 256],
+            functions: vec![],
+            indices: HashMap::new(),
+            current: None,
+            since: Instant::now(),
+        }
+    }
+}
+
+impl Profile {
+    /// Count the instruction starting with `opcode` in `function`, running at call `depth`
+    pub fn instruction(&mut self, function: &ObjFunction, depth: u32, opcode: u8) {
+        self.opcodes[opcode as usize] += 1;
+
+        let key = function as *const ObjFunction;
+        let index = match self.current {
+            Some((current, current_depth, index)) if current == key && current_depth == depth => {
+                index
+            }
+            current => {
+                let now = Instant::now();
+                if let Some((_, _, index)) = current {
+                    self.functions[index].time += now - self.since;
+                }
+                self.since = now;
+
+                let functions = &mut self.functions;
+                let index = *self.indices.entry(key).or_insert_with(|| {
+                    functions.push(FunctionStats {
+                        name: function.name.map(|name| name.as_str().to_string()),
+                        line: function.line,
+                        calls: 0,
+                        instructions: 0,
+                        time: Duration::ZERO,
+                    });
+                    functions.len() - 1
+                });
+                // Deeper than before, the function was just called
+                if current.map_or(true, |(_, current_depth, _)| depth > current_depth) {
+                    self.functions[index].calls += 1;
+                }
+                self.current = Some((key, depth, index));
+                index
+            }
+        };
+        self.functions[index].instructions += 1;
+    }
+
+    /// Charge the time since the last instruction, e.g. when the script is done
+    pub fn finish(&mut self) {
+        let now = Instant::now();
+        if let Some((_, _, index)) = self.current.take() {
+            self.functions[index].time += now - self.since;
+        }
+        self.since = now;
+    }
+
+    /// How often each opcode ran, most common first
+    pub fn opcodes(&self) -> Vec<(Opcode, u64)> {
+        let mut opcodes: Vec<_> = (0..=u8::MAX)
+            .filter_map(|byte| Some((Opcode::from_u8(byte)?, self.opcodes[byte as usize])))
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        opcodes.sort_by(|a, b| b.1.cmp(&a.1));
+        opcodes
+    }
+
+    /// The functions that ran, slowest first
+    pub fn functions(&self) -> Vec<&FunctionStats> {
+        let mut functions: Vec<_> = self.functions.iter().collect();
+        functions.sort_by(|a, b| b.time.cmp(&a.time));
+        functions
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total_time: Duration = self.functions.iter().map(|function| function.time).sum();
+        let total_time = total_time.as_secs_f64().max(f64::MIN_POSITIVE);
+        writeln!(
+            f,
+            "{:>12} {:>7} {:>10} {:>14}  function",
+            "self time", "%", "calls", "instructions"
+        )?;
+        for function in self.functions() {
+            let name = match &function.name {
+                Some(name) => format!("{name}() [line {}]", function.line),
+                None => "script".to_string(),
+            };
+            let time = function.time.as_secs_f64();
+            writeln!(
+                f,
+                "{:>10.3}ms {:>6.1}% {:>10} {:>14}  {name}",
+                time * 1000.0,
+                time / total_time * 100.0,
+                function.calls,
+                function.instructions,
+            )?;
+        }
+
+        let total: u64 = self.opcodes.iter().sum();
+        writeln!(f, "\n{:>12} {:>7}  opcode", "count", "%")?;
+        for (opcode, count) in self.opcodes() {
+            let share = count as f64 / total as f64 * 100.0;
+            writeln!(f, "{count:>12} {share:>6.1}%  {opcode:?}")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,193 @@
+//! Tasks started with `spawn`, taking turns on the VM's stack.
+//!
+//! A task is a generator the scheduler resumes, so it's suspended the same way: when it yields,
+//! sleeps or waits for a channel its frame is moved off the stack and the next task runs. The
+//! tasks only run once the script returns, until every one of them is done.
+//!
+//! Values can be sent to a channel from another thread, so the scheduler checks the channels
+//! tasks wait for instead of being told. With nothing else to do it waits for one of them, see
+//! [`POLL_INTERVAL`].
+
+use std::{collections::VecDeque, thread, time::Duration};
+
+use super::VM;
+use crate::{
+    mem::{Gc, Greystack},
+    obj::{GeneratorState, Obj, ObjChannel, ObjGenerator},
+    value::Value,
+    InterpretResult,
+};
+
+/// How long the scheduler waits for a value on one channel another VM can send to before it
+/// checks the other channels and the sleeping tasks again
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// What a task stopped running for
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Wait {
+    /// `sleep`, until `VM::start` has been running for this long
+    Sleep(Duration),
+    /// `await` on an empty channel
+    Recv(Gc<ObjChannel>),
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Scheduler {
+    /// Tasks that can run, with the value to push when they continue. `None` for tasks that
+    /// haven't started or yielded, which expect nothing
+    ready: VecDeque<(Gc<ObjGenerator>, Option<Value>)>,
+    /// Tasks in `sleep`, with when they wake up
+    sleeping: Vec<(Duration, Gc<ObjGenerator>)>,
+    /// Tasks in `await`, with the channel they wait for
+    receiving: Vec<(Gc<ObjChannel>, Gc<ObjGenerator>)>,
+}
+
+impl Scheduler {
+    pub(crate) fn mark(&self, greystack: &mut Greystack) {
+        for (task, value) in &self.ready {
+            Obj::mark(task.upcast(), greystack);
+            if let Some(value) = value {
+                value.mark(greystack);
+            }
+        }
+        for (_, task) in &self.sleeping {
+            Obj::mark(task.upcast(), greystack);
+        }
+        for (channel, task) in &self.receiving {
+            Obj::mark(channel.upcast(), greystack);
+            Obj::mark(task.upcast(), greystack);
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        *self = Self::default();
+    }
+}
+
+impl VM {
+    /// `spawn(fn)`: start `callee`, a function without parameters, as a task
+    pub(crate) fn spawn(&mut self, callee: Value) -> Result<Value, String> {
+        let (closure, receiver) = if let Some(closure) = callee.as_obj_closure() {
+            (closure, callee)
+        } else if let Some(bound) = callee.as_bound_method() {
+            (bound.method, bound.receiver)
+        } else {
+            return Err(callee.type_error("function").to_string());
+        };
+        if closure.function.param_count() != 0 {
+            return Err("A task's function can't take parameters.".to_string());
+        }
+
+        let mut task = self.alloc_obj(ObjGenerator::new(closure, vec![receiver]));
+        task.task = true;
+        self.scheduler.ready.push_back((task, None));
+        Ok(Value::Obj(task.upcast()))
+    }
+
+    /// Whether the top frame is a task's, which can stop to let the others run. Frames it
+    /// calls and natives calling back into the VM can't, `sleep` blocks the thread in them
+    pub(crate) fn in_task(&self) -> bool {
+        self.callbacks == 0
+            && self.resumed.last().map_or(false, |resumed| {
+                resumed.frame_count == self.call_frame_count && resumed.generator.task
+            })
+    }
+
+    /// Stop the running task once the current instruction is done. Takes the fuel away, so the
+    /// dispatch loop checks [`VM::blocked`] before the next instruction
+    pub(crate) fn block(&mut self, wait: Wait) {
+        self.blocked = Some((wait, self.fuel));
+        self.fuel = 0;
+    }
+
+    /// The task blocked with the result of the instruction that blocked it on top of the stack,
+    /// a placeholder for what it gets once it continues. Suspend its frame, returning to
+    /// [`VM::run_task`]
+    pub(super) fn suspend_blocked(&mut self, base_frame_count: u32) -> Option<Value> {
+        let (_, fuel) = self.blocked.unwrap();
+        self.fuel = fuel;
+        self.pop();
+        self.suspend(Value::Nil, base_frame_count)
+    }
+
+    /// Run the tasks until they're all done
+    pub(super) fn run_tasks(&mut self) -> InterpretResult<()> {
+        loop {
+            self.wake_receivers();
+            let now = self.start.elapsed();
+            let sleeping = std::mem::take(&mut self.scheduler.sleeping);
+            let (mut woken, sleeping): (Vec<_>, Vec<_>) =
+                sleeping.into_iter().partition(|&(wake, _)| wake <= now);
+            woken.sort_by_key(|&(wake, _)| wake);
+            self.scheduler.sleeping = sleeping;
+            let woken = woken.into_iter().map(|(_, task)| (task, Some(Value::Nil)));
+            self.scheduler.ready.extend(woken);
+
+            if let Some((task, value)) = self.scheduler.ready.pop_front() {
+                self.run_task(task, value)?;
+                continue;
+            }
+
+            let wake = self.scheduler.sleeping.iter().map(|&(wake, _)| wake).min();
+            let wake = wake.map(|wake| wake.saturating_sub(now));
+            let shared = self
+                .scheduler
+                .receiving
+                .iter()
+                .find(|(channel, _)| channel.channel.is_shared());
+            match (wake, shared) {
+                (_, Some((channel, _))) => {
+                    let timeout = wake.map_or(POLL_INTERVAL, |wake| wake.min(POLL_INTERVAL));
+                    channel.channel.clone().wait(timeout);
+                }
+                (Some(wake), None) => thread::sleep(wake),
+                (None, None) if !self.scheduler.receiving.is_empty() => {
+                    let error = "Deadlock, every task is waiting for a channel.";
+                    let error = self.runtime_error(error.into());
+                    self.scheduler.clear();
+                    return Err(self.report(error));
+                }
+                (None, None) => return Ok(()),
+            }
+        }
+    }
+
+    /// Move the tasks waiting for a channel that has a value to `ready`, the first to wait gets
+    /// the first value
+    fn wake_receivers(&mut self) {
+        let mut i = 0;
+        while i < self.scheduler.receiving.len() {
+            let (channel, task) = self.scheduler.receiving[i];
+            let Some(snapshot) = channel.channel.try_recv() else {
+                i += 1;
+                continue;
+            };
+            // The task is still rooted through `receiving`
+            let value = snapshot.rebuild(self);
+            self.scheduler.receiving.remove(i);
+            self.scheduler.ready.push_back((task, Some(value)));
+        }
+    }
+
+    /// Continue `task` until it stops, pushing `value` for it first
+    fn run_task(&mut self, task: Gc<ObjGenerator>, value: Option<Value>) -> InterpretResult<()> {
+        if task.state == GeneratorState::Done {
+            return Ok(());
+        }
+        let base_frame_count = self.call_frame_count;
+        self.resume(task, None)
+            .map_err(|error| self.report(error))?;
+        if let Some(value) = value {
+            self.push(value);
+        }
+        self.execute(base_frame_count)?;
+
+        match self.blocked.take() {
+            _ if task.state == GeneratorState::Done => {}
+            None => self.scheduler.ready.push_back((task, None)),
+            Some((Wait::Sleep(wake), _)) => self.scheduler.sleeping.push((wake, task)),
+            Some((Wait::Recv(channel), _)) => self.scheduler.receiving.push((channel, task)),
+        }
+        Ok(())
+    }
+}
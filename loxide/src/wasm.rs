@@ -0,0 +1,121 @@
+//! JavaScript API for running loxide in a browser, built with the `wasm` feature for
+//! `wasm32-unknown-unknown`:
+//!
+//! ```js
+//! import init_wasm, { init, eval, setPrintCallback } from "./pkg/loxide.js";
+//!
+//! await init_wasm();
+//! init();
+//! setPrintCallback((text) => console.log(text));
+//! eval("var x = 1; print x + 1;");
+//! eval("x * 10"); // "10"
+//! ```
+//!
+//! There is one interpreter per page, its globals persist between calls to `eval`. Scripts can't
+//! reach the file system or stdin, see [`VmOptions::io`].
+
+use std::{
+    cell::RefCell,
+    io::{self, Write},
+};
+
+use wasm_bindgen::prelude::*;
+
+use crate::{Loxide, VmOptions};
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = Date, js_name = now)]
+    fn js_date_now() -> f64;
+
+    /// A JavaScript function taking the printed text
+    pub type PrintCallback;
+
+    #[wasm_bindgen(method, js_name = call)]
+    fn call(this: &PrintCallback, this_arg: &JsValue, text: &str);
+}
+
+/// Milliseconds since the unix epoch, for `clock()`, `now()` and seeding `random()`
+pub(crate) fn date_now() -> f64 {
+    js_date_now()
+}
+
+thread_local! {
+    static ENGINE: RefCell<Option<Loxide>> = RefCell::new(None);
+}
+
+/// Start a new interpreter, forgetting the globals of the old one. The print callback is
+/// cleared too
+#[wasm_bindgen]
+pub fn init() {
+    ENGINE.with(|engine| {
+        *engine.borrow_mut() = Some(Loxide::with_options(VmOptions::default()));
+    });
+}
+
+/// Run `source` like a line in the REPL: the value of a trailing expression without a
+/// semicolon is returned in the same format as `print`, an empty string otherwise. Errors are
+/// returned as their message, they don't throw
+#[wasm_bindgen]
+pub fn eval(source: &str) -> String {
+    with_engine(|engine| match engine.eval(source) {
+        Ok(value) if value.is_nil() => String::new(),
+        Ok(value) => {
+            let value = engine
+                .vm
+                .value_ref(value)
+                .expect("the result was just returned");
+            format!("{value:?}")
+        }
+        Err(err) => err.to_string(),
+    })
+}
+
+/// Call `callback` with the text of each `print` instead of dropping it, WebAssembly has no
+/// stdout
+#[wasm_bindgen(js_name = setPrintCallback)]
+pub fn set_print_callback(callback: PrintCallback) {
+    with_engine(|engine| {
+        engine.vm.stdout = Box::new(CallbackWriter {
+            callback,
+            line: vec![],
+        });
+    });
+}
+
+/// Calls the callback once per line written, without the newline
+struct CallbackWriter {
+    callback: PrintCallback,
+    line: Vec<u8>,
+}
+
+// Safety: wasm32-unknown-unknown has no threads, the writer never leaves the one there is
+unsafe impl Send for CallbackWriter {}
+
+impl Write for CallbackWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            if byte == b'\n' {
+                let text = String::from_utf8_lossy(&self.line);
+                self.callback.call(&JsValue::NULL, &text);
+                self.line.clear();
+            } else {
+                self.line.push(byte);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Run `f` on the interpreter, starting one if `init` wasn't called yet
+fn with_engine<T>(f: impl FnOnce(&mut Loxide) -> T) -> T {
+    ENGINE.with(|engine| {
+        let mut engine = engine.borrow_mut();
+        let engine = engine.get_or_insert_with(|| Loxide::with_options(VmOptions::default()));
+        f(engine)
+    })
+}
@@ -0,0 +1,245 @@
+//! `loxide run --watch`: run a script again whenever it or a module it imports changes
+
+use std::{
+    io::{IsTerminal, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use loxide::{
+    interpret,
+    module::{FileResolver, ModuleResolver},
+    vm::VM,
+    InterpretError, VmOptions,
+};
+
+use crate::cli::Options;
+
+/// How often the files are checked for changes
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Run the script at `path`, then wait until it or one of the modules it imported changes and
+/// start over with a fresh VM, until interrupted. The screen is cleared before each run if
+/// stdout is a terminal. Errors are reported like for a script run once, but don't stop the
+/// watching, and a file that can't be read is picked up again once it's back. A change while
+/// the script is still running stops it and starts over.
+pub fn run(path: &Path, options: &Options, color: bool) -> ! {
+    let mut stdout = std::io::stdout();
+    loop {
+        if stdout.is_terminal() {
+            let _ = write!(stdout, "\x1b[2J\x1b[3J\x1b[H");
+            let _ = stdout.flush();
+        }
+
+        let started = SystemTime::now();
+        let files = run_once(
+            path,
+            crate::vm_options(options),
+            &options.script_args,
+            options.mem_stats,
+            color,
+        );
+        let _ = stdout.flush();
+        let modified = last_modified(&files);
+        // Saved while the script was running
+        if modified.iter().flatten().any(|&time| time >= started) {
+            continue;
+        }
+
+        let count = match files.len() {
+            1 => "1 file".to_string(),
+            count => format!("{count} files"),
+        };
+        eprintln!("\n[Watching {count} for changes, press Ctrl-C to stop]");
+        while last_modified(&files) == modified {
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+/// Run the script at `path` on a new VM and report its errors, stopping it early if one of the
+/// files changes. Returns the files to watch: the script and the modules it tried to import,
+/// also those that failed to compile
+fn run_once(
+    path: &Path,
+    options: VmOptions,
+    script_args: &[String],
+    mem_stats: bool,
+    color: bool,
+) -> Vec<PathBuf> {
+    let imported = Arc::default();
+    let resolver = Recorder {
+        files: FileResolver::new(options.module_paths.clone()),
+        imported: Arc::clone(&imported),
+    };
+    let interrupt = Arc::new(AtomicBool::new(false));
+    let done = Arc::new(AtomicBool::new(false));
+    let watcher = {
+        let path = path.to_path_buf();
+        let imported = Arc::clone(&imported);
+        let interrupt = Arc::clone(&interrupt);
+        let done = Arc::clone(&done);
+        thread::spawn(move || interrupt_on_change(path, &imported, &interrupt, &done))
+    };
+    let mut vm = VM::with_options(VmOptions {
+        module_resolver: Some(Box::new(resolver)),
+        report_errors: false,
+        interrupt: Some(interrupt),
+        ..options
+    });
+    crate::register_args(&mut vm, script_args.to_vec());
+    vm.script_path = Some(path.to_path_buf());
+
+    match std::fs::read_to_string(path) {
+        Ok(src) => {
+            let result = interpret(&mut vm, &src);
+            // It runs again right away
+            if result != Err(InterpretError::Interrupted) {
+                crate::report_error(&vm, &result, Some(&src), color);
+                crate::print_stats(&mut vm, mem_stats);
+            }
+        }
+        Err(err) => eprintln!("Could not read \"{}\": {err}", path.display()),
+    }
+    done.store(true, Ordering::Relaxed);
+    let _ = watcher.join();
+
+    let mut files = vec![path.to_path_buf()];
+    for module in imported.lock().unwrap().drain(..) {
+        if !files.contains(&module) {
+            files.push(module);
+        }
+    }
+    files
+}
+
+/// Set `interrupt` once the script at `path` or one of the modules it imported so far is modified,
+/// checking every [`POLL_INTERVAL`] until `done` is set
+fn interrupt_on_change(
+    path: PathBuf,
+    imported: &Mutex<Vec<PathBuf>>,
+    interrupt: &AtomicBool,
+    done: &AtomicBool,
+) {
+    let started = SystemTime::now();
+    while !done.load(Ordering::Relaxed) {
+        thread::sleep(POLL_INTERVAL);
+        let mut files = vec![path.clone()];
+        files.extend(imported.lock().unwrap().iter().cloned());
+        if last_modified(&files)
+            .iter()
+            .flatten()
+            .any(|&time| time >= started)
+        {
+            interrupt.store(true, Ordering::Relaxed);
+            return;
+        }
+    }
+}
+
+/// When each of `files` was last modified, `None` for those that can't be read
+fn last_modified(files: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    files
+        .iter()
+        .map(|file| {
+            file.metadata()
+                .and_then(|metadata| metadata.modified())
+                .ok()
+        })
+        .collect()
+}
+
+/// Loads modules from files and keeps the paths of the ones that were imported
+struct Recorder {
+    files: FileResolver,
+    imported: Arc<Mutex<Vec<PathBuf>>>,
+}
+
+impl ModuleResolver for Recorder {
+    fn resolve(&self, importer: Option<&str>, name: &str) -> Option<String> {
+        let id = self.files.resolve(importer, name)?;
+        self.imported.lock().unwrap().push(PathBuf::from(&id));
+        Some(id)
+    }
+
+    fn load(&self, id: &str) -> Result<String, String> {
+        self.files.load(id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use loxide::{vm::OutputBuffer, VmOptions};
+
+    use super::{last_modified, run_once, Duration};
+
+    #[test]
+    fn imported_files() {
+        let dir = std::env::temp_dir().join(format!("loxide-watch-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        let files = [
+            (
+                "main.lox",
+                "import lib from \"lib.lox\"; import \"sub/util.lox\"; print lib.x;",
+            ),
+            ("lib.lox", "import \"sub/util.lox\"; var x = 1;"),
+            ("sub/util.lox", "var y = 2;"),
+        ];
+        for (name, src) in files {
+            std::fs::write(dir.join(name), src).unwrap();
+        }
+
+        let stdout = OutputBuffer::default();
+        let options = VmOptions {
+            stdout: Box::new(stdout.clone()),
+            ..VmOptions::default()
+        };
+        let main = dir.join("main.lox");
+        let watched = run_once(&main, options, &[], false, false);
+        assert_eq!(stdout.contents(), "1\n");
+        let canonical = |name| dir.join(name).canonicalize().unwrap();
+        assert_eq!(
+            watched,
+            [main, canonical("lib.lox"), canonical("sub/util.lox")]
+        );
+
+        let missing = [dir.join("missing.lox")];
+        assert_eq!(last_modified(&missing), [None]);
+        assert!(last_modified(&watched).iter().all(Option::is_some));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn change_while_running() {
+        let dir = std::env::temp_dir().join(format!("loxide-watch-loop-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let main = dir.join("main.lox");
+        std::fs::write(&main, "print 1; while (true) {}").unwrap();
+
+        let save = {
+            let main = main.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(500));
+                std::fs::write(main, "print 2;").unwrap();
+            })
+        };
+        let stdout = OutputBuffer::default();
+        let options = VmOptions {
+            stdout: Box::new(stdout.clone()),
+            ..VmOptions::default()
+        };
+        // Stops the loop instead of running forever
+        let watched = run_once(&main, options, &[], false, false);
+        save.join().unwrap();
+        assert_eq!(stdout.contents(), "1\n");
+        assert_eq!(watched, [main]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
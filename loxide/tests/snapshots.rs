@@ -0,0 +1,107 @@
+//! Golden tests: every `.lox` program in `tests/snapshots` is disassembled (as compiled and
+//! optimized) and run, and the result has to match the `.snap` file next to it. Changes to code
+//! generation show up as a diff of the listing.
+//!
+//! Run with `UPDATE_SNAPSHOTS=1` to rewrite the snapshots after an intended change, and review
+//! the diff before committing them.
+
+use std::{
+    fmt::Write,
+    path::{Path, PathBuf},
+};
+
+use loxide::{compile, interpret, vm::OutputBuffer, VmOptions, VM};
+
+#[test]
+fn snapshots() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/snapshots");
+    let update = std::env::var_os("UPDATE_SNAPSHOTS").is_some();
+
+    let mut programs: Vec<PathBuf> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            path.extension()
+                .map_or(false, |extension| extension == "lox")
+        })
+        .collect();
+    programs.sort();
+    assert!(!programs.is_empty(), "no programs in {}", dir.display());
+
+    let mut failures = vec![];
+    for program in programs {
+        let source = std::fs::read_to_string(&program).unwrap();
+        let actual = snapshot(&source);
+        let path = program.with_extension("snap");
+        match std::fs::read_to_string(&path) {
+            Ok(expected) if expected == actual => (),
+            _ if update => std::fs::write(&path, actual).unwrap(),
+            Ok(expected) => {
+                failures.push(format!("{}\n{}", path.display(), diff(&expected, &actual)))
+            }
+            Err(_) => failures.push(format!(
+                "{} is missing, run with UPDATE_SNAPSHOTS=1 to create it",
+                path.display()
+            )),
+        }
+    }
+
+    assert!(
+        failures.is_empty(),
+        "snapshots don't match, run with UPDATE_SNAPSHOTS=1 if the changes are intended:\n\n{}",
+        failures.join("\n\n")
+    );
+}
+
+/// The bytecode of `source` as compiled and optimized, and what it prints when it runs
+fn snapshot(source: &str) -> String {
+    let mut out = String::new();
+    for optimize in [false, true] {
+        let mut vm = VM::with_options(VmOptions {
+            optimize,
+            stderr: Box::new(std::io::sink()),
+            ..VmOptions::default()
+        });
+        let listing = match compile(&mut vm, source) {
+            Ok(script) => script.disassemble(),
+            Err(err) => format!("{err}\n"),
+        };
+        let title = if optimize { "optimized" } else { "bytecode" };
+        let _ = write!(out, "---- {title} ----\n{listing}\n");
+    }
+
+    let stdout = OutputBuffer::default();
+    let stderr = OutputBuffer::default();
+    let mut vm = VM::with_options(VmOptions {
+        stdout: Box::new(stdout.clone()),
+        stderr: Box::new(stderr.clone()),
+        ..VmOptions::default()
+    });
+    let _ = interpret(&mut vm, source);
+    let _ = write!(out, "---- stdout ----\n{}", stdout.contents());
+    let errors = stderr.contents();
+    if !errors.is_empty() {
+        let _ = write!(out, "\n---- stderr ----\n{errors}");
+    }
+    out
+}
+
+/// The lines that differ, `-` for the expected and `+` for the actual ones
+fn diff(expected: &str, actual: &str) -> String {
+    let expected: Vec<_> = expected.lines().collect();
+    let actual: Vec<_> = actual.lines().collect();
+    let mut out = String::new();
+    for i in 0..expected.len().max(actual.len()) {
+        let (old, new) = (expected.get(i), actual.get(i));
+        if old == new {
+            continue;
+        }
+        if let Some(old) = old {
+            let _ = writeln!(out, "{:4} - {old}", i + 1);
+        }
+        if let Some(new) = new {
+            let _ = writeln!(out, "{:4} + {new}", i + 1);
+        }
+    }
+    out
+}